@@ -1,32 +1,60 @@
 pub mod noop;
+pub mod remote;
 
 #[cfg(target_os = "linux")]
 pub mod qemu;
 #[cfg(target_os = "linux")]
+pub mod qga;
+#[cfg(target_os = "linux")]
 pub mod qmp;
 
 #[cfg(target_os = "illumos")]
 pub mod propolis;
 
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
+use async_trait::async_trait;
+use tokio_util::sync::CancellationToken;
+#[cfg(target_os = "linux")]
+use tracing::{debug, warn};
+
+use crate::config::{Config, ConfigSource};
 use crate::error::{Result, VmError};
+use crate::events::{EventBus, VmEvent};
 use crate::traits::{ConsoleEndpoint, Hypervisor};
-use crate::types::{BackendTag, VmHandle, VmSpec, VmState};
+use crate::types::{BackendTag, DryRun, VmHandle, VmIpInfo, VmSpec, VmState};
+
+/// Settings that only the QEMU backend uses; `None` keeps the backend's default.
+#[derive(Default)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct QemuOptions {
+    binary: Option<std::path::PathBuf>,
+    firmware_dir: Option<std::path::PathBuf>,
+    networks_dir: Option<std::path::PathBuf>,
+    lease_sources: Option<Vec<crate::leases::LeaseSource>>,
+    cancel: CancellationToken,
+}
 
 /// Platform-aware router that delegates to the appropriate backend.
+///
+/// Backends are looked up by the [`BackendTag`] recorded in each `VmHandle`. New VMs are
+/// prepared by the default backend, or on the remote host if one is configured.
 pub struct RouterHypervisor {
-    pub noop: noop::NoopBackend,
-    #[cfg(target_os = "linux")]
-    pub qemu: Option<qemu::QemuBackend>,
-    #[cfg(target_os = "illumos")]
-    pub propolis: Option<propolis::PropolisBackend>,
+    backends: HashMap<BackendTag, Arc<dyn Hypervisor>>,
+    default_backend: BackendTag,
+    /// When set, new VMs are prepared on this remote host instead of locally.
+    pub remote: Option<remote::RemoteBackend>,
+    events: EventBus,
 }
 
 impl RouterHypervisor {
     /// Build a router with platform defaults.
     ///
-    /// On Linux, creates a QemuBackend with the given bridge.
+    /// On Linux, creates a QemuBackend with the given bridge. If [`probe`](crate::capabilities::probe)
+    /// finds no QEMU or KVM, it still manages existing VMs, but preparing new ones fails with
+    /// [`VmError::QemuUnavailable`].
     /// On illumos, creates a PropolisBackend with the given ZFS pool.
     #[allow(unused_variables)]
     pub fn new(bridge: Option<String>, zfs_pool: Option<String>) -> Self {
@@ -34,258 +62,562 @@ impl RouterHypervisor {
     }
 
     /// Build a router with a custom data directory for VM work files.
-    #[allow(unused_variables)]
     pub fn with_data_dir(
         bridge: Option<String>,
         zfs_pool: Option<String>,
         data_dir: Option<std::path::PathBuf>,
     ) -> Self {
+        Self::build(QemuOptions::default(), bridge, zfs_pool, data_dir)
+    }
+
+    /// Build a router from layered vmctl configuration: QEMU binary and firmware directory,
+    /// default bridge, data directory and default backend.
+    ///
+    /// Fails if the configured backend is not available on this host.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Self::from_config_with_cancel(config, CancellationToken::new())
+    }
+
+    /// Like [`from_config`](Self::from_config), with backends that abandon `prepare` and
+    /// `start` and clean up after themselves once `cancel` fires.
+    pub fn from_config_with_cancel(config: &Config, cancel: CancellationToken) -> Result<Self> {
+        // Work directories always depend on the namespace. Leave the backends' other defaults
+        // alone unless a data directory was configured.
+        let custom = config.data_dir.source != ConfigSource::Default;
+        let qemu = QemuOptions {
+            binary: config.qemu_binary.value.clone(),
+            firmware_dir: config.firmware_dir.value.clone(),
+            networks_dir: custom.then(|| config.networks_dir()),
+            lease_sources: Some(config.lease_sources.value.clone()),
+            cancel,
+        };
+        let mut router = Self::build(
+            qemu,
+            config.bridge.value.clone(),
+            None,
+            Some(config.vms_dir()),
+        );
+        if let Some(ref tag) = config.backend.value {
+            router.set_default_backend(tag.clone())?;
+        }
+        Ok(router)
+    }
+
+    #[allow(unused_variables)]
+    fn build(
+        qemu: QemuOptions,
+        bridge: Option<String>,
+        zfs_pool: Option<String>,
+        data_dir: Option<std::path::PathBuf>,
+    ) -> Self {
+        #[allow(unused_mut)]
+        let mut router = Self::noop_only();
+
+        // QEMU is registered even when it can't run VMs, so the VMs it already has stay
+        // manageable; only preparing new ones fails.
         #[cfg(target_os = "linux")]
         {
-            RouterHypervisor {
-                noop: noop::NoopBackend,
-                qemu: Some(qemu::QemuBackend::new(None, data_dir, bridge)),
+            let caps = crate::capabilities::probe_cached();
+            let reason = match qemu.binary {
+                Some(ref bin) if !bin.exists() => Some(format!(
+                    "configured QEMU binary {} does not exist",
+                    bin.display()
+                )),
+                Some(_) if !caps.kvm => Some("/dev/kvm is missing or not accessible".into()),
+                Some(_) => None,
+                None => caps.qemu_unavailable_reason(),
+            };
+            let binary = qemu
+                .binary
+                .or_else(|| caps.qemu.as_ref().map(|q| q.path.clone()));
+            let mut backend =
+                qemu::QemuBackend::new(binary.clone(), data_dir, bridge).with_cancel(qemu.cancel);
+            if let Some(dir) = qemu.networks_dir {
+                backend = backend.with_networks_dir(dir);
+            }
+            if let Some(sources) = qemu.lease_sources {
+                backend = backend.with_lease_sources(sources);
             }
+            if let Some(dir) = qemu.firmware_dir {
+                backend = backend.with_firmware_dir(dir);
+            }
+            match reason {
+                None => debug!(binary = ?binary, "selected qemu backend"),
+                Some(reason) => {
+                    warn!(%reason, "QEMU is not usable on this host; new VMs will fail to prepare");
+                    backend = backend.with_unavailable(reason);
+                }
+            }
+            router.register_backend(BackendTag::Qemu, Arc::new(backend));
+            router.default_backend = BackendTag::Qemu;
         }
         #[cfg(target_os = "illumos")]
         {
-            RouterHypervisor {
-                noop: noop::NoopBackend,
-                propolis: Some(propolis::PropolisBackend::new(
-                    None,
+            router.register_backend(
+                BackendTag::Propolis,
+                Arc::new(propolis::PropolisBackend::new(
+                    data_dir,
                     zfs_pool.unwrap_or_else(|| "rpool".into()),
                 )),
-            }
-        }
-        #[cfg(not(any(target_os = "linux", target_os = "illumos")))]
-        {
-            RouterHypervisor {
-                noop: noop::NoopBackend,
-            }
+            );
+            router.default_backend = BackendTag::Propolis;
         }
+
+        router
     }
 
     /// Build a router that only has the noop backend (for dev/testing).
     pub fn noop_only() -> Self {
-        #[cfg(target_os = "linux")]
-        {
-            RouterHypervisor {
-                noop: noop::NoopBackend,
-                qemu: None,
-            }
+        let mut backends: HashMap<BackendTag, Arc<dyn Hypervisor>> = HashMap::new();
+        backends.insert(BackendTag::Noop, Arc::new(noop::NoopBackend));
+        RouterHypervisor {
+            backends,
+            default_backend: BackendTag::Noop,
+            remote: None,
+            events: EventBus::new(),
         }
-        #[cfg(target_os = "illumos")]
-        {
-            RouterHypervisor {
-                noop: noop::NoopBackend,
-                propolis: None,
-            }
+    }
+
+    /// Register (or replace) the backend that handles VMs tagged with `tag`.
+    ///
+    /// Out-of-tree backends use [`BackendTag::Custom`] and must set the same tag on the handles
+    /// they return from `prepare`.
+    pub fn register_backend(&mut self, tag: BackendTag, backend: Arc<dyn Hypervisor>) {
+        self.backends.insert(tag, backend);
+    }
+
+    /// Prepare new VMs with the backend registered under `tag`.
+    pub fn set_default_backend(&mut self, tag: BackendTag) -> Result<()> {
+        if !self.backends.contains_key(&tag) {
+            return Err(VmError::BackendNotAvailable {
+                backend: tag.to_string(),
+            });
         }
-        #[cfg(not(any(target_os = "linux", target_os = "illumos")))]
-        {
-            RouterHypervisor {
-                noop: noop::NoopBackend,
+        self.default_backend = tag;
+        Ok(())
+    }
+
+    /// The backend used to prepare new local VMs.
+    pub fn default_backend(&self) -> &BackendTag {
+        &self.default_backend
+    }
+
+    /// Prepare new VMs on a remote host reached over SSH.
+    ///
+    /// VMs whose handles carry a `remote_host` are always routed to that host, whether or not a
+    /// remote is configured here.
+    pub fn with_remote(mut self, remote: remote::RemoteBackend) -> Self {
+        self.remote = Some(remote);
+        self
+    }
+
+    /// Whether new VMs are prepared on a remote host.
+    pub fn has_remote(&self) -> bool {
+        self.remote.is_some()
+    }
+
+    /// Publish lifecycle events into `events` instead of a bus of the router's own.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// The bus lifecycle events are published into, for handing to an
+    /// [`ImageManager`](crate::image::ImageManager) or [`run_provisions`](crate::provision::run_provisions).
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Receive the events of every operation from now on.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<VmEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish `event(&value)` on success and an `Error` event on failure, then pass `result` on.
+    fn report<T>(
+        &self,
+        vm: &str,
+        result: Result<T>,
+        event: impl FnOnce(&T) -> Option<VmEvent>,
+    ) -> Result<T> {
+        match result {
+            Ok(ref value) => {
+                if let Some(e) = event(value) {
+                    self.events.publish(e);
+                }
             }
+            Err(ref e) => self.events.publish(VmEvent::Error {
+                vm: Some(vm.to_string()),
+                message: e.to_string(),
+            }),
         }
+        result
     }
-}
 
-impl Hypervisor for RouterHypervisor {
-    async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
-        #[cfg(target_os = "linux")]
-        if let Some(ref qemu) = self.qemu {
-            return qemu.prepare(spec).await;
+    /// Backend for the remote host recorded in a VM handle.
+    fn remote_for(&self, host: &str) -> Result<remote::RemoteBackend> {
+        match self.remote {
+            Some(ref r) if r.host().to_string() == host => Ok(r.clone()),
+            _ => Ok(remote::RemoteBackend::new(remote::RemoteHost::parse(host)?)),
         }
-        #[cfg(target_os = "illumos")]
-        if let Some(ref propolis) = self.propolis {
-            return propolis.prepare(spec).await;
+    }
+
+    /// Backend that manages `vm`: its remote host, or the local backend for its tag.
+    fn backend_for(&self, vm: &VmHandle) -> Result<Arc<dyn Hypervisor>> {
+        if let Some(ref host) = vm.remote_host {
+            return Ok(Arc::new(self.remote_for(host)?));
         }
-        self.noop.prepare(spec).await
+        self.backends
+            .get(&vm.backend)
+            .cloned()
+            .ok_or_else(|| VmError::BackendNotAvailable {
+                backend: vm.backend.to_string(),
+            })
     }
+}
 
-    async fn start(&self, vm: &VmHandle) -> Result<VmHandle> {
-        match vm.backend {
-            #[cfg(target_os = "linux")]
-            BackendTag::Qemu => match self.qemu {
-                Some(ref q) => q.start(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "qemu".into(),
-                }),
-            },
-            #[cfg(target_os = "illumos")]
-            BackendTag::Propolis => match self.propolis {
-                Some(ref p) => p.start(vm).await,
+#[async_trait]
+impl Hypervisor for RouterHypervisor {
+    async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
+        let result = match self.remote {
+            Some(ref remote) => remote.prepare(spec).await,
+            None => match self.backends.get(&self.default_backend) {
+                Some(backend) => backend.prepare(spec).await,
                 None => Err(VmError::BackendNotAvailable {
-                    backend: "propolis".into(),
+                    backend: self.default_backend.to_string(),
                 }),
             },
-            BackendTag::Noop => self.noop.start(vm).await,
-            #[allow(unreachable_patterns)]
-            _ => Err(VmError::BackendNotAvailable {
-                backend: vm.backend.to_string(),
-            }),
-        }
+        };
+        self.report(&spec.name, result, |vm| {
+            Some(VmEvent::VmPrepared {
+                vm: vm.name.clone(),
+            })
+        })
+    }
+
+    async fn start(&self, vm: &VmHandle) -> Result<VmHandle> {
+        let result = match self.backend_for(vm) {
+            Ok(backend) => backend.start(vm).await,
+            Err(e) => Err(e),
+        };
+        self.report(&vm.name, result, |vm| {
+            Some(VmEvent::VmStarted {
+                vm: vm.name.clone(),
+                pid: vm.pid,
+            })
+        })
     }
 
     async fn stop(&self, vm: &VmHandle, timeout: Duration) -> Result<VmHandle> {
-        match vm.backend {
-            #[cfg(target_os = "linux")]
-            BackendTag::Qemu => match self.qemu {
-                Some(ref q) => q.stop(vm, timeout).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "qemu".into(),
-                }),
-            },
-            #[cfg(target_os = "illumos")]
-            BackendTag::Propolis => match self.propolis {
-                Some(ref p) => p.stop(vm, timeout).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "propolis".into(),
-                }),
-            },
-            BackendTag::Noop => self.noop.stop(vm, timeout).await,
-            #[allow(unreachable_patterns)]
-            _ => Err(VmError::BackendNotAvailable {
-                backend: vm.backend.to_string(),
-            }),
-        }
+        let result = match self.backend_for(vm) {
+            Ok(backend) => backend.stop(vm, timeout).await,
+            Err(e) => Err(e),
+        };
+        self.report(&vm.name, result, |vm| {
+            Some(VmEvent::VmStopped {
+                vm: vm.name.clone(),
+            })
+        })
     }
 
     async fn suspend(&self, vm: &VmHandle) -> Result<VmHandle> {
-        match vm.backend {
-            #[cfg(target_os = "linux")]
-            BackendTag::Qemu => match self.qemu {
-                Some(ref q) => q.suspend(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "qemu".into(),
-                }),
-            },
-            #[cfg(target_os = "illumos")]
-            BackendTag::Propolis => match self.propolis {
-                Some(ref p) => p.suspend(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "propolis".into(),
-                }),
-            },
-            BackendTag::Noop => self.noop.suspend(vm).await,
-            #[allow(unreachable_patterns)]
-            _ => Err(VmError::BackendNotAvailable {
-                backend: vm.backend.to_string(),
-            }),
-        }
+        self.backend_for(vm)?.suspend(vm).await
     }
 
     async fn resume(&self, vm: &VmHandle) -> Result<VmHandle> {
-        match vm.backend {
-            #[cfg(target_os = "linux")]
-            BackendTag::Qemu => match self.qemu {
-                Some(ref q) => q.resume(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "qemu".into(),
-                }),
-            },
-            #[cfg(target_os = "illumos")]
-            BackendTag::Propolis => match self.propolis {
-                Some(ref p) => p.resume(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "propolis".into(),
-                }),
-            },
-            BackendTag::Noop => self.noop.resume(vm).await,
-            #[allow(unreachable_patterns)]
-            _ => Err(VmError::BackendNotAvailable {
-                backend: vm.backend.to_string(),
-            }),
-        }
+        self.backend_for(vm)?.resume(vm).await
     }
 
     async fn destroy(&self, vm: VmHandle) -> Result<()> {
-        match vm.backend {
-            #[cfg(target_os = "linux")]
-            BackendTag::Qemu => match self.qemu {
-                Some(ref q) => q.destroy(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "qemu".into(),
-                }),
-            },
-            #[cfg(target_os = "illumos")]
-            BackendTag::Propolis => match self.propolis {
-                Some(ref p) => p.destroy(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "propolis".into(),
-                }),
-            },
-            BackendTag::Noop => self.noop.destroy(vm).await,
-            #[allow(unreachable_patterns)]
-            _ => Err(VmError::BackendNotAvailable {
-                backend: vm.backend.to_string(),
-            }),
-        }
+        let name = vm.name.clone();
+        let result = match self.backend_for(&vm) {
+            Ok(backend) => backend.destroy(vm).await,
+            Err(e) => Err(e),
+        };
+        self.report(&name, result, |_| {
+            Some(VmEvent::VmDestroyed { vm: name.clone() })
+        })
     }
 
     async fn state(&self, vm: &VmHandle) -> Result<VmState> {
-        match vm.backend {
-            #[cfg(target_os = "linux")]
-            BackendTag::Qemu => match self.qemu {
-                Some(ref q) => q.state(vm).await,
-                None => Ok(VmState::Destroyed),
-            },
-            #[cfg(target_os = "illumos")]
-            BackendTag::Propolis => match self.propolis {
-                Some(ref p) => p.state(vm).await,
-                None => Ok(VmState::Destroyed),
-            },
-            BackendTag::Noop => self.noop.state(vm).await,
-            #[allow(unreachable_patterns)]
-            _ => Ok(VmState::Destroyed),
-        }
+        self.backend_for(vm)?.state(vm).await
     }
 
-    async fn guest_ip(&self, vm: &VmHandle) -> Result<String> {
-        match vm.backend {
-            #[cfg(target_os = "linux")]
-            BackendTag::Qemu => match self.qemu {
-                Some(ref q) => q.guest_ip(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "qemu".into(),
-                }),
-            },
-            #[cfg(target_os = "illumos")]
-            BackendTag::Propolis => match self.propolis {
-                Some(ref p) => p.guest_ip(vm).await,
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "propolis".into(),
-                }),
-            },
-            BackendTag::Noop => self.noop.guest_ip(vm).await,
-            #[allow(unreachable_patterns)]
-            _ => Err(VmError::BackendNotAvailable {
-                backend: vm.backend.to_string(),
-            }),
-        }
+    async fn guest_ips(&self, vm: &VmHandle, timeout: Duration) -> Result<VmIpInfo> {
+        let result = match self.backend_for(vm) {
+            Ok(backend) => backend.guest_ips(vm, timeout).await,
+            Err(e) => Err(e),
+        };
+        self.report(&vm.name, result, |ips| {
+            ips.preferred(false).map(|ip| VmEvent::IpDiscovered {
+                vm: vm.name.clone(),
+                ip: ip.to_string(),
+            })
+        })
     }
 
     fn console_endpoint(&self, vm: &VmHandle) -> Result<ConsoleEndpoint> {
-        match vm.backend {
-            #[cfg(target_os = "linux")]
-            BackendTag::Qemu => match self.qemu {
-                Some(ref q) => q.console_endpoint(vm),
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "qemu".into(),
-                }),
-            },
-            #[cfg(target_os = "illumos")]
-            BackendTag::Propolis => match self.propolis {
-                Some(ref p) => p.console_endpoint(vm),
-                None => Err(VmError::BackendNotAvailable {
-                    backend: "propolis".into(),
-                }),
+        self.backend_for(vm)?.console_endpoint(vm)
+    }
+
+    /// Fails with `DryRunUnsupported` rather than returning `None`.
+    async fn dry_run_create(&self, spec: &VmSpec) -> Result<Option<DryRun>> {
+        let (dry_run, backend) = match self.remote {
+            Some(ref remote) => (
+                remote.dry_run_create(spec).await?,
+                remote.host().to_string(),
+            ),
+            None => match self.backends.get(&self.default_backend) {
+                Some(backend) => (
+                    backend.dry_run_create(spec).await?,
+                    self.default_backend.to_string(),
+                ),
+                None => {
+                    return Err(VmError::BackendNotAvailable {
+                        backend: self.default_backend.to_string(),
+                    });
+                }
             },
-            BackendTag::Noop => self.noop.console_endpoint(vm),
-            #[allow(unreachable_patterns)]
-            _ => Err(VmError::BackendNotAvailable {
-                backend: vm.backend.to_string(),
-            }),
+        };
+        dry_run
+            .map(Some)
+            .ok_or(VmError::DryRunUnsupported { backend })
+    }
+
+    /// The VMs of every registered local backend, by name. Remote hosts are not asked.
+    async fn list_vms(&self) -> Result<Vec<VmHandle>> {
+        let mut vms = Vec::new();
+        for backend in self.backends.values() {
+            vms.extend(backend.list_vms().await?);
+        }
+        vms.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(vms)
+    }
+
+    /// Fails with `DryRunUnsupported` rather than returning `None`.
+    async fn dry_run_start(&self, vm: &VmHandle) -> Result<Option<DryRun>> {
+        let backend = vm
+            .remote_host
+            .clone()
+            .unwrap_or_else(|| vm.backend.to_string());
+        self.backend_for(vm)?
+            .dry_run_start(vm)
+            .await?
+            .map(Some)
+            .ok_or(VmError::DryRunUnsupported { backend })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{MachineType, NetworkConfig};
+
+    /// A third-party backend: the noop backend under a custom tag.
+    struct CustomBackend;
+
+    #[async_trait]
+    impl Hypervisor for CustomBackend {
+        async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
+            let mut vm = noop::NoopBackend.prepare(spec).await?;
+            vm.backend = BackendTag::Custom("custom".into());
+            Ok(vm)
+        }
+        async fn start(&self, vm: &VmHandle) -> Result<VmHandle> {
+            Ok(vm.clone())
+        }
+        async fn stop(&self, vm: &VmHandle, _timeout: Duration) -> Result<VmHandle> {
+            Ok(vm.clone())
+        }
+        async fn suspend(&self, vm: &VmHandle) -> Result<VmHandle> {
+            Ok(vm.clone())
+        }
+        async fn resume(&self, vm: &VmHandle) -> Result<VmHandle> {
+            Ok(vm.clone())
+        }
+        async fn destroy(&self, vm: VmHandle) -> Result<()> {
+            noop::NoopBackend.destroy(vm).await
         }
+        async fn state(&self, _vm: &VmHandle) -> Result<VmState> {
+            Ok(VmState::Running)
+        }
+        async fn guest_ips(&self, _vm: &VmHandle, _timeout: Duration) -> Result<VmIpInfo> {
+            Ok(VmIpInfo {
+                v4: vec!["192.0.2.1".parse().unwrap()],
+                v6: vec!["2001:db8::1".parse().unwrap()],
+            })
+        }
+        fn console_endpoint(&self, _vm: &VmHandle) -> Result<ConsoleEndpoint> {
+            Ok(ConsoleEndpoint::None)
+        }
+        async fn list_vms(&self) -> Result<Vec<VmHandle>> {
+            let vm = serde_json::json!({
+                "id": "custom-1",
+                "name": "adopted",
+                "backend": "custom",
+                "work_dir": "/tmp/adopted",
+            });
+            Ok(vec![serde_json::from_value(vm).unwrap()])
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_vms_of_local_backends() {
+        let mut router = RouterHypervisor::noop_only();
+        assert!(router.list_vms().await.unwrap().is_empty());
+
+        router.register_backend(BackendTag::Custom("custom".into()), Arc::new(CustomBackend));
+        let vms = router.list_vms().await.unwrap();
+        assert_eq!(vms.len(), 1);
+        assert_eq!(vms[0].name, "adopted");
+        assert_eq!(vms[0].backend, BackendTag::Custom("custom".into()));
+    }
+
+    #[tokio::test]
+    async fn routes_to_registered_backend() {
+        let mut router = RouterHypervisor::noop_only();
+        let custom = BackendTag::Custom("custom".into());
+        assert!(router.set_default_backend(custom.clone()).is_err());
+
+        router.register_backend(custom.clone(), Arc::new(CustomBackend));
+        router.set_default_backend(custom.clone()).unwrap();
+
+        let spec = VmSpec {
+            name: "router-custom".into(),
+            image_path: "/tmp/test.qcow2".into(),
+            vcpus: 1,
+            memory_mb: 512,
+            disk_gb: None,
+            overlay: Default::default(),
+            network: NetworkConfig::None,
+            cloud_init: None,
+            ssh: None,
+            uefi: false,
+            mac_addr: None,
+            existing_macs: Vec::new(),
+            static_ip: None,
+            ssh_host_port: None,
+            private_networks: Vec::new(),
+            serial_ports: Vec::new(),
+            machine: MachineType::Q35,
+            kernel: None,
+            cdrom: None,
+            boot_order: Vec::new(),
+            random_seed: Default::default(),
+            qemu_args: Vec::new(),
+            restart_policy: Default::default(),
+            guest_os: Default::default(),
+            display: Default::default(),
+            audio: Default::default(),
+            smbios: Default::default(),
+            disk_bus: None,
+            drivers_iso: None,
+            secure_boot: false,
+            secure_boot_vars: None,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
+        };
+        let vm = router.prepare(&spec).await.unwrap();
+        assert_eq!(vm.backend, custom);
+        assert_eq!(router.state(&vm).await.unwrap(), VmState::Running);
+        assert_eq!(
+            router.guest_ip(&vm, Duration::ZERO).await.unwrap(),
+            "192.0.2.1"
+        );
+        let ips = router.guest_ips(&vm, Duration::ZERO).await.unwrap();
+        assert_eq!(ips.preferred(true), Some("2001:db8::1".parse().unwrap()));
+
+        // The tag round-trips through the state file as a plain string.
+        let json = serde_json::to_string(&vm).unwrap();
+        assert!(json.contains(r#""backend":"custom""#));
+        let parsed: VmHandle = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.backend, custom);
+
+        // Without the backend registered, the VM's state is unknown and every call fails.
+        let bare = RouterHypervisor::noop_only();
+        assert!(matches!(
+            bare.state(&vm).await,
+            Err(VmError::BackendNotAvailable { .. })
+        ));
+        assert!(bare.start(&vm).await.is_err());
+
+        router.destroy(vm).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn publishes_lifecycle_events() {
+        let events = EventBus::new();
+        let router = RouterHypervisor::noop_only().with_events(events.clone());
+        let mut rx = events.subscribe();
+
+        let vm = router
+            .prepare(&VmSpec {
+                name: "events".into(),
+                image_path: "/tmp/test.qcow2".into(),
+                vcpus: 1,
+                memory_mb: 512,
+                disk_gb: None,
+                overlay: Default::default(),
+                network: NetworkConfig::None,
+                cloud_init: None,
+                ssh: None,
+                uefi: false,
+                mac_addr: None,
+                existing_macs: Vec::new(),
+                static_ip: None,
+                ssh_host_port: None,
+                private_networks: Vec::new(),
+                serial_ports: Vec::new(),
+                machine: MachineType::Q35,
+                kernel: None,
+                cdrom: None,
+                boot_order: Vec::new(),
+                random_seed: Default::default(),
+                qemu_args: Vec::new(),
+                restart_policy: Default::default(),
+                guest_os: Default::default(),
+                display: Default::default(),
+                audio: Default::default(),
+                smbios: Default::default(),
+                disk_bus: None,
+                drivers_iso: None,
+                secure_boot: false,
+                secure_boot_vars: None,
+                confidential: None,
+                cpu_pinning: None,
+                numa: Vec::new(),
+                hugepages: false,
+                usb_devices: Vec::new(),
+            })
+            .await
+            .unwrap();
+        let vm = router.start(&vm).await.unwrap();
+        router.destroy(vm.clone()).await.unwrap();
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            VmEvent::VmPrepared {
+                vm: "events".into()
+            }
+        );
+        assert!(matches!(rx.try_recv().unwrap(), VmEvent::VmStarted { vm, .. } if vm == "events"));
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            VmEvent::VmDestroyed {
+                vm: "events".into()
+            }
+        );
+
+        // Failures are published too, tagged with the VM.
+        let mut orphan = vm;
+        orphan.backend = BackendTag::Custom("custom".into());
+        assert!(router.start(&orphan).await.is_err());
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            VmEvent::Error { vm: Some(vm), .. } if vm == "events"
+        ));
     }
 }
@@ -1,15 +1,19 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
 use std::time::Duration;
 
+use async_trait::async_trait;
 use tracing::info;
 
 use crate::error::Result;
 use crate::traits::{ConsoleEndpoint, Hypervisor};
-use crate::types::{BackendTag, VmHandle, VmSpec, VmState};
+use crate::types::{BackendTag, DryRun, VmHandle, VmIpInfo, VmSpec, VmState};
 
 /// No-op hypervisor for development and testing on hosts without VM capabilities.
 #[derive(Debug, Clone, Default)]
 pub struct NoopBackend;
 
+#[async_trait]
 impl Hypervisor for NoopBackend {
     async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
         let id = format!("noop-{}", uuid::Uuid::new_v4());
@@ -30,10 +34,41 @@ impl Hypervisor for NoopBackend {
             vcpus: spec.vcpus,
             memory_mb: spec.memory_mb,
             disk_gb: spec.disk_gb,
+            overlay: spec.overlay.clone(),
             network: spec.network.clone(),
+            macvtap: None,
             ssh_host_port: None,
-            mac_addr: None,
+            ssh_host_port_fixed: false,
+            mac_addr: spec.mac_addr.clone(),
             uefi: false,
+            static_ip: spec.static_ip.clone(),
+            private_networks: spec.private_networks.clone(),
+            serial_ports: spec.serial_ports.clone(),
+            machine: spec.machine,
+            kernel: spec.kernel.clone(),
+            cdrom: spec.cdrom.clone(),
+            boot_order: spec.boot_order.clone(),
+            boot_once: None,
+            random_seed: spec.random_seed,
+            qemu_args: spec.qemu_args.clone(),
+            restart_policy: spec.restart_policy,
+            guest_os: spec.guest_os,
+            display: spec.display,
+            audio: spec.audio,
+            smbios: spec.smbios.clone(),
+            disk_bus: spec.disk_bus(),
+            drivers_iso: spec.drivers_iso.clone(),
+            rdp_host_port: None,
+            secure_boot: false,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
+            remote_host: None,
+            labels: HashMap::new(),
+            annotations: HashMap::new(),
+            hostnames: Vec::new(),
         })
     }
 
@@ -67,13 +102,21 @@ impl Hypervisor for NoopBackend {
         Ok(VmState::Prepared)
     }
 
-    async fn guest_ip(&self, _vm: &VmHandle) -> Result<String> {
-        Ok("127.0.0.1".to_string())
+    async fn guest_ips(&self, _vm: &VmHandle, _timeout: Duration) -> Result<VmIpInfo> {
+        Ok(VmIpInfo::from(IpAddr::V4(Ipv4Addr::LOCALHOST)))
     }
 
     fn console_endpoint(&self, _vm: &VmHandle) -> Result<ConsoleEndpoint> {
         Ok(ConsoleEndpoint::None)
     }
+
+    async fn dry_run_create(&self, _spec: &VmSpec) -> Result<Option<DryRun>> {
+        Ok(Some(DryRun::default()))
+    }
+
+    async fn dry_run_start(&self, _vm: &VmHandle) -> Result<Option<DryRun>> {
+        Ok(Some(DryRun::default()))
+    }
 }
 
 #[cfg(test)]
@@ -81,7 +124,7 @@ mod tests {
     use super::*;
     use std::path::PathBuf;
 
-    use crate::types::NetworkConfig;
+    use crate::types::{MachineType, NetworkConfig, SerialBackend, SerialPort};
 
     fn test_spec() -> VmSpec {
         VmSpec {
@@ -90,10 +133,37 @@ mod tests {
             vcpus: 1,
             memory_mb: 512,
             disk_gb: None,
+            overlay: Default::default(),
             network: NetworkConfig::None,
             cloud_init: None,
             ssh: None,
             uefi: false,
+            mac_addr: None,
+            existing_macs: Vec::new(),
+            static_ip: None,
+            ssh_host_port: None,
+            private_networks: Vec::new(),
+            serial_ports: Vec::new(),
+            machine: MachineType::Q35,
+            kernel: None,
+            cdrom: None,
+            boot_order: Vec::new(),
+            random_seed: Default::default(),
+            qemu_args: Vec::new(),
+            restart_policy: Default::default(),
+            guest_os: Default::default(),
+            display: Default::default(),
+            audio: Default::default(),
+            smbios: Default::default(),
+            disk_bus: None,
+            drivers_iso: None,
+            secure_boot: false,
+            secure_boot_vars: None,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
         }
     }
 
@@ -112,7 +182,7 @@ mod tests {
         let handle = backend.suspend(&handle).await.unwrap();
         let handle = backend.resume(&handle).await.unwrap();
 
-        let ip = backend.guest_ip(&handle).await.unwrap();
+        let ip = backend.guest_ip(&handle, Duration::ZERO).await.unwrap();
         assert_eq!(ip, "127.0.0.1");
 
         let endpoint = backend.console_endpoint(&handle).unwrap();
@@ -132,6 +202,9 @@ mod tests {
             NetworkConfig::Vnic {
                 name: "vnic0".into(),
             },
+            NetworkConfig::Macvtap {
+                parent: "eth0".into(),
+            },
             NetworkConfig::None,
         ];
         for cfg in configs {
@@ -157,10 +230,48 @@ mod tests {
             vcpus: 4,
             memory_mb: 2048,
             disk_gb: Some(20),
+            overlay: Default::default(),
             network: NetworkConfig::User,
+            macvtap: None,
             ssh_host_port: Some(10022),
+            ssh_host_port_fixed: false,
             mac_addr: Some("52:54:00:ab:cd:ef".into()),
             uefi: false,
+            static_ip: None,
+            private_networks: Vec::new(),
+            serial_ports: vec![
+                SerialPort {
+                    backend: SerialBackend::Socket("/tmp/test/uart1.sock".into()),
+                },
+                SerialPort {
+                    backend: SerialBackend::Null,
+                },
+            ],
+            machine: MachineType::Q35,
+            kernel: None,
+            cdrom: None,
+            boot_order: Vec::new(),
+            boot_once: None,
+            random_seed: Default::default(),
+            qemu_args: Vec::new(),
+            restart_policy: Default::default(),
+            guest_os: Default::default(),
+            display: Default::default(),
+            audio: Default::default(),
+            smbios: Default::default(),
+            disk_bus: Default::default(),
+            drivers_iso: None,
+            rdp_host_port: None,
+            secure_boot: false,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
+            remote_host: None,
+            labels: HashMap::from([("team".into(), "infra".into())]),
+            annotations: HashMap::from([("build".into(), "aHR0cHM6Ly9jaS8xMjM=".into())]),
+            hostnames: vec!["my-vm.local".into()],
         };
         let json = serde_json::to_string_pretty(&handle).unwrap();
         let parsed: VmHandle = serde_json::from_str(&json).unwrap();
@@ -169,6 +280,10 @@ mod tests {
         assert_eq!(handle.memory_mb, parsed.memory_mb);
         assert_eq!(handle.ssh_host_port, parsed.ssh_host_port);
         assert_eq!(handle.mac_addr, parsed.mac_addr);
+        assert_eq!(handle.serial_ports, parsed.serial_ports);
+        assert_eq!(handle.labels, parsed.labels);
+        assert_eq!(handle.annotations, parsed.annotations);
+        assert_eq!(handle.hostnames, parsed.hostnames);
     }
 
     #[test]
@@ -192,5 +307,8 @@ mod tests {
         assert_eq!(handle.disk_gb, None);
         assert!(handle.ssh_host_port.is_none());
         assert!(handle.mac_addr.is_none());
+        assert!(handle.labels.is_empty());
+        assert!(handle.annotations.is_empty());
+        assert!(handle.hostnames.is_empty());
     }
 }
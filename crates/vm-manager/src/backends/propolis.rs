@@ -2,14 +2,19 @@
 //!
 //! Manages VMs inside `nebula-vm` branded zones with propolis-server.
 
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::time::Duration;
 
-use tracing::{info, warn};
+use async_trait::async_trait;
+use tracing::{debug, info, warn};
 
 use crate::error::{Result, VmError};
-use crate::traits::{ConsoleEndpoint, Hypervisor};
-use crate::types::{BackendTag, NetworkConfig, VmHandle, VmSpec, VmState};
+use crate::traits::{ConsoleEndpoint, Hypervisor, IP_POLL_INTERVAL};
+use crate::types::{
+    BackendTag, CloudInitConfig, NetworkConfig, VmHandle, VmIpInfo, VmSpec, VmState, is_global_v6,
+};
 
 /// Propolis backend for illumos zones.
 pub struct PropolisBackend {
@@ -28,6 +33,31 @@ impl PropolisBackend {
     }
 
     /// Run a shell command and return (success, stdout, stderr).
+    /// Addresses configured inside the zone, from one `ipadm show-addr` pass.
+    async fn zone_ips(zone: &str) -> Result<VmIpInfo> {
+        let (ok, stdout, _) =
+            Self::run_cmd("zlogin", &[zone, "ipadm", "show-addr", "-p", "-o", "ADDR"]).await?;
+        let mut info = VmIpInfo::default();
+        if !ok {
+            return Ok(info);
+        }
+        for line in stdout.lines() {
+            // Parseable output escapes the colons of IPv6 addresses (`fd00\:1\:\:5/64`).
+            let addr = line
+                .split('/')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .replace('\\', "");
+            match addr.parse::<IpAddr>() {
+                Ok(IpAddr::V4(v4)) if !v4.is_loopback() => info.push(IpAddr::V4(v4)),
+                Ok(IpAddr::V6(v6)) if is_global_v6(&v6) => info.push(IpAddr::V6(v6)),
+                _ => {}
+            }
+        }
+        Ok(info)
+    }
+
     async fn run_cmd(cmd: &str, args: &[&str]) -> Result<(bool, String, String)> {
         let output = tokio::process::Command::new(cmd)
             .args(args)
@@ -66,11 +96,25 @@ impl PropolisBackend {
     }
 }
 
+#[async_trait]
 impl Hypervisor for PropolisBackend {
     async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
         let work_dir = self.work_dir(&spec.name);
         tokio::fs::create_dir_all(&work_dir).await?;
 
+        if !spec.serial_ports.is_empty() {
+            warn!(
+                name = %spec.name,
+                "propolis: additional serial ports are not supported and will be ignored"
+            );
+        }
+        if !spec.private_networks.is_empty() {
+            warn!(
+                name = %spec.name,
+                "propolis: private networks are not supported and will be ignored"
+            );
+        }
+
         // Clone ZFS dataset for the VM disk
         let base_dataset = format!("{}/images/{}", self.zfs_pool, spec.name);
         let vm_dataset = format!("{}/vms/{}", self.zfs_pool, spec.name);
@@ -86,14 +130,21 @@ impl Hypervisor for PropolisBackend {
 
         // Create cloud-init seed ISO if configured
         let mut seed_iso_path = None;
-        if let Some(ref ci) = spec.cloud_init {
+        if spec.cloud_init.is_some() || spec.static_ip.is_some() {
             let iso_path = work_dir.join("seed.iso");
-            let instance_id = ci.instance_id.as_deref().unwrap_or(&spec.name);
-            let hostname = ci.hostname.as_deref().unwrap_or(&spec.name);
-            let meta_data = format!("instance-id: {instance_id}\nlocal-hostname: {hostname}\n");
-            crate::cloudinit::create_nocloud_iso_raw(
-                &ci.user_data,
-                meta_data.as_bytes(),
+            let ci = spec.cloud_init.as_ref();
+            let meta_data = CloudInitConfig::meta_data_for(ci, &spec.name);
+            let user_data = ci
+                .map(|c| c.user_data.clone())
+                .unwrap_or_else(|| b"#cloud-config\n".to_vec());
+            let network_config = spec
+                .static_ip
+                .as_ref()
+                .map(|ip| crate::cloudinit::build_network_config(spec.mac_addr.as_deref(), ip));
+            crate::cloudinit::create_nocloud_iso_with_network(
+                &user_data,
+                &meta_data,
+                network_config.as_deref(),
                 &iso_path,
             )?;
             seed_iso_path = Some(iso_path);
@@ -136,10 +187,41 @@ impl Hypervisor for PropolisBackend {
             vcpus: spec.vcpus,
             memory_mb: spec.memory_mb,
             disk_gb: spec.disk_gb,
+            overlay: spec.overlay.clone(),
             network: spec.network.clone(),
+            macvtap: None,
             ssh_host_port: None,
-            mac_addr: None,
+            ssh_host_port_fixed: false,
+            mac_addr: spec.mac_addr.clone(),
             uefi: false,
+            static_ip: spec.static_ip.clone(),
+            private_networks: Vec::new(),
+            serial_ports: spec.serial_ports.clone(),
+            machine: spec.machine,
+            kernel: spec.kernel.clone(),
+            cdrom: spec.cdrom.clone(),
+            boot_order: Vec::new(),
+            boot_once: None,
+            random_seed: spec.random_seed,
+            qemu_args: spec.qemu_args.clone(),
+            restart_policy: spec.restart_policy,
+            guest_os: spec.guest_os,
+            display: spec.display,
+            audio: spec.audio,
+            smbios: spec.smbios.clone(),
+            disk_bus: spec.disk_bus(),
+            drivers_iso: spec.drivers_iso.clone(),
+            rdp_host_port: None,
+            secure_boot: false,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
+            remote_host: None,
+            labels: HashMap::new(),
+            annotations: HashMap::new(),
+            hostnames: Vec::new(),
         };
 
         info!(name = %spec.name, id = %handle.id, "Propolis: prepared");
@@ -152,6 +234,7 @@ impl Hypervisor for PropolisBackend {
         if !ok {
             return Err(VmError::QemuSpawnFailed {
                 source: std::io::Error::other(format!("zone boot failed: {stderr}")),
+                stderr: None,
             });
         }
 
@@ -268,30 +351,33 @@ impl Hypervisor for PropolisBackend {
         })
     }
 
-    async fn guest_ip(&self, vm: &VmHandle) -> Result<String> {
-        // For exclusive-IP zones, the IP is configured inside the zone.
-        // Try to query it via zlogin.
-        let (ok, stdout, _) = Self::run_cmd(
-            "zlogin",
-            &[&vm.name, "ipadm", "show-addr", "-p", "-o", "ADDR"],
-        )
-        .await?;
+    async fn guest_ips(&self, vm: &VmHandle, timeout: Duration) -> Result<VmIpInfo> {
+        if let Some(ip) = vm
+            .static_ip
+            .as_ref()
+            .and_then(|s| s.ip().parse::<IpAddr>().ok())
+        {
+            return Ok(VmIpInfo::from(ip));
+        }
 
-        if ok {
-            for line in stdout.lines() {
-                let addr = line
-                    .trim()
-                    .trim_end_matches(|c: char| c == '/' || c.is_ascii_digit());
-                let addr = line.split('/').next().unwrap_or("").trim();
-                if !addr.is_empty() && addr != "127.0.0.1" && addr.contains('.') {
-                    return Ok(addr.to_string());
-                }
+        // For exclusive-IP zones, the IP is configured inside the zone.
+        // Query it via zlogin until the zone has brought up its interface.
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let info = Self::zone_ips(&vm.name).await?;
+            if !info.is_empty() {
+                return Ok(info);
             }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Err(VmError::IpDiscoveryTimeout {
+                    name: vm.name.clone(),
+                    mac: vm.mac_addr.clone().unwrap_or_else(|| "unknown".into()),
+                });
+            }
+            debug!(name = %vm.name, "waiting for zone IP");
+            tokio::time::sleep(IP_POLL_INTERVAL.min(deadline - now)).await;
         }
-
-        Err(VmError::IpDiscoveryTimeout {
-            name: vm.name.clone(),
-        })
     }
 
     fn console_endpoint(&self, vm: &VmHandle) -> Result<ConsoleEndpoint> {
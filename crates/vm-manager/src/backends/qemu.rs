@@ -1,15 +1,167 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn};
 
+use crate::capabilities::which;
 use crate::cloudinit;
 use crate::error::{Result, VmError};
 use crate::image;
-use crate::traits::{ConsoleEndpoint, Hypervisor};
-use crate::types::{BackendTag, NetworkConfig, VmHandle, VmSpec, VmState};
+use crate::leases::{self, LeaseFormat, LeaseSource};
+use crate::network::{self, NetworkManager};
+use crate::pinning;
+use crate::traits::{ConsoleEndpoint, Hypervisor, IP_POLL_INTERVAL};
+use crate::types::{
+    AudioBackend, BackendTag, BootDevice, CloudInitConfig, ConfidentialMode, DiskBus, DisplayMode,
+    DryRun, DryRunFile, GuestOs, MachineType, NetworkConfig, NumaNode, PrivateNic, RngConfig,
+    SerialBackend, UsbDevice, VcpuAffinity, VmExit, VmHandle, VmIpInfo, VmSpec, VmState,
+    check_boot_order, check_numa_layout, is_global_v6, managed_qemu_option,
+};
+use crate::usb;
 
-use super::qmp::QmpClient;
+use super::qga;
+use super::qmp::{QmpClient, QmpPool, SevInfo};
+
+/// NIC MAC address for handles that predate per-VM MACs.
+const DEFAULT_MAC: &str = "52:54:00:00:00:01";
+
+/// File descriptor QEMU finds the VM's open macvtap device on: the first one after stdio.
+const MACVTAP_FD: i32 = 3;
+
+/// How long to keep waiting for an IPv4 address once only IPv6 ones have shown up, so dual-stack
+/// guests (whose SLAAC addresses usually appear before the DHCP lease) still report both.
+const V4_GRACE: Duration = Duration::from_secs(5);
+
+/// QEMU's id for a VM's root disk, for QMP commands such as `block_resize`.
+pub const ROOT_DRIVE: &str = "drive0";
+
+/// Index of the root disk, which [`disk_serial`] and the device ids of NVMe and SCSI disks are
+/// derived from.
+const ROOT_DISK_INDEX: usize = 0;
+
+/// Second QMP socket in the work directory, kept free for [`QemuBackend::wait_for_exit`]: QEMU
+/// serves one client per monitor, and the main one is used for commands.
+const EVENTS_SOCKET: &str = "qmp-events.sock";
+
+/// Guest clock settings unless `qemu_args` has its own `-rtc`: UTC, with ticks missed while the
+/// VM was descheduled replayed faster rather than lost.
+const DEFAULT_RTC: &str = "base=utc,driftfix=slew";
+
+/// Guest clock settings for Windows guests, whose clock keeps local time.
+const WINDOWS_RTC: &str = "base=localtime,driftfix=slew";
+
+/// Where distributions install the virtio-win drivers ISO, attached to Windows guests that don't
+/// name one so the installer can load the virtio drivers.
+const VIRTIO_WIN_ISOS: &[&str] = &[
+    "/usr/share/virtio-win/virtio-win.iso",
+    "/usr/share/virtio-win.iso",
+    "/usr/share/drivers/windows/virtio-win.iso",
+];
+
+/// `kvm_amd` parameter that reads `Y` (or `1` on older kernels) when the host runs SEV guests.
+const SEV_PARAM: &str = "/sys/module/kvm_amd/parameters/sev";
+
+/// The AMD Secure Processor device QEMU launches SEV guests through.
+const SEV_DEVICE: &str = "/dev/sev";
+
+/// Launch policy of SEV guests: debugging off, so the host can't have the secure processor
+/// decrypt guest memory.
+const SEV_POLICY: u32 = 0x1;
+
+/// Options in `qemu_args` that need the host to read or change guest memory, which it can't for
+/// confidential guests, with what they are for.
+const CONFIDENTIAL_CONFLICTS: &[(&str, &str)] = &[
+    ("virtio-balloon", "memory ballooning"),
+    ("virtio-mem", "memory hotplug"),
+    ("pc-dimm", "memory hotplug"),
+    ("maxmem=", "memory hotplug"),
+];
+
+/// Where hugetlbfs is mounted, for VMs whose memory is backed by huge pages.
+const HUGEPAGES_PATH: &str = "/dev/hugepages";
+
+/// Id of the memory backend of a VM with huge pages but no NUMA nodes.
+const MEMORY_BACKEND: &str = "ram0";
+
+/// How long `resume` waits for the guest agent to set the clock.
+const RESUME_TIME_SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Written to the work directory by `stop`, so that the exit it causes is not taken for a crash.
+const STOP_MARKER: &str = "stop-requested";
+
+/// How often [`QemuBackend::wait_for_exit`] checks whether the QEMU process is still there.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Id of the `-audiodev` the sound card plays through.
+const AUDIODEV: &str = "audio0";
+
+/// Id of the virtio-scsi controller SCSI disks are attached to.
+const SCSI_CONTROLLER: &str = "scsi0";
+
+/// Bus of the XHCI controller q35 VMs get, which passed-through USB devices are attached to.
+const USB_BUS: &str = "xhci.0";
+
+/// Output of a QEMU that runs in the foreground, in its work directory.
+const QEMU_LOG: &str = "qemu.log";
+
+/// Output of the last daemonizing QEMU launch, in its work directory.
+const QEMU_LAUNCH_LOG: &str = "qemu-launch.log";
+
+/// How many of the last lines of QEMU's output a failed start reports.
+const SPAWN_OUTPUT_LINES: usize = 40;
+
+/// Cache of [`probe`] results in the data directory.
+const QEMU_INFO_CACHE: &str = ".qemu-info.json";
+
+/// How long a QEMU run by [`probe`] may take to answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How `start` runs a VM's QEMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProcessMode {
+    /// `-daemonize`: QEMU forks once it has initialized, and its pidfile has the pid. It
+    /// outlives vmctl.
+    Daemonized,
+    /// A child of the vmctl process, for displays that need QEMU in the foreground. A
+    /// supervisor task reaps it when it exits, so that `stop` doesn't wait on a zombie; it
+    /// keeps running when vmctl exits.
+    Supervised,
+}
+
+impl ProcessMode {
+    fn for_vm(vm: &VmHandle) -> Self {
+        if vm.display.is_local() {
+            Self::Supervised
+        } else {
+            Self::Daemonized
+        }
+    }
+}
+
+/// The arguments after the program name in the contents of a `/proc/<pid>/cmdline` file.
+pub fn split_cmdline(raw: &[u8]) -> Vec<String> {
+    raw.split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .skip(1)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
+
+/// Whether `args` are those of the QEMU of the VM in `work_dir`, which is started with
+/// `-pidfile <work_dir>/qemu.pid`.
+pub fn is_qemu_of(args: &[String], work_dir: &Path) -> bool {
+    let pidfile = work_dir.join("qemu.pid").display().to_string();
+    args.windows(2)
+        .any(|w| w[0] == "-pidfile" && w[1] == pidfile)
+}
 
 /// QEMU-KVM backend for Linux.
 ///
@@ -18,6 +170,11 @@ pub struct QemuBackend {
     qemu_binary: PathBuf,
     data_dir: PathBuf,
     default_bridge: Option<String>,
+    networks_dir: PathBuf,
+    lease_sources: Vec<LeaseSource>,
+    firmware_dir: Option<PathBuf>,
+    cancel: CancellationToken,
+    unavailable: Option<String>,
 }
 
 impl QemuBackend {
@@ -36,213 +193,387 @@ impl QemuBackend {
             qemu_binary: qemu_binary.unwrap_or_else(|| "qemu-system-x86_64".into()),
             data_dir,
             default_bridge,
+            networks_dir: dirs::data_dir()
+                .unwrap_or_else(|| PathBuf::from("/tmp"))
+                .join("vmctl")
+                .join("networks"),
+            lease_sources: LeaseSource::defaults(),
+            firmware_dir: None,
+            cancel: CancellationToken::new(),
+            unavailable: None,
         }
     }
 
-    fn work_dir(&self, name: &str) -> PathBuf {
-        self.data_dir.join(name)
+    /// Refuse to prepare new VMs, failing with [`VmError::QemuUnavailable`] and `reason`.
+    /// Existing VMs can still be stopped, destroyed and inspected.
+    pub fn with_unavailable(mut self, reason: String) -> Self {
+        self.unavailable = Some(reason);
+        self
     }
 
-    /// Generate a random locally-administered MAC address.
-    pub fn generate_mac() -> String {
-        let bytes: [u8; 6] = rand_mac();
-        format!(
-            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
-            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]
-        )
+    /// Directory searched for OVMF firmware before the well-known [`FIRMWARE_DIRS`].
+    pub fn with_firmware_dir(mut self, dir: PathBuf) -> Self {
+        self.firmware_dir = Some(dir);
+        self
     }
 
-    /// Read PID from the pidfile in the work directory.
-    async fn read_pid(work_dir: &Path) -> Option<u32> {
-        let pid_path = work_dir.join("qemu.pid");
-        tokio::fs::read_to_string(&pid_path)
-            .await
-            .ok()
-            .and_then(|s| s.trim().parse().ok())
+    /// OVMF firmware for UEFI guests, built with Secure Boot if `secure_boot` is set.
+    pub fn firmware(&self, secure_boot: bool) -> Result<Firmware> {
+        let dirs: Vec<PathBuf> = self
+            .firmware_dir
+            .iter()
+            .cloned()
+            .chain(FIRMWARE_DIRS.iter().map(PathBuf::from))
+            .collect();
+        find_firmware(&dirs, secure_boot)
     }
 
-    /// Check if a process with the given PID is alive.
-    fn pid_alive(pid: u32) -> bool {
-        // Signal 0 checks if process exists without sending a signal
-        unsafe { libc::kill(pid as i32, 0) == 0 }
+    /// Abandon `prepare` and `start` once `cancel` fires: a half-prepared work directory is
+    /// removed and a QEMU process that was just spawned is killed.
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
     }
 
-    /// Pick a free TCP host port for SSH forwarding.
-    ///
-    /// Previously this hashed the VM name into a 100-port range
-    /// (10022..10121). With N concurrent VMs the birthday-paradox collision
-    /// probability there is ≈ N*(N-1)/200, so 8 concurrent jobs collide
-    /// ~28% of the time and QEMU exits with
-    ///   "Could not set up host forwarding rule 'tcp::PORT-:22'".
-    ///
-    /// Replace with: bind to an OS-assigned ephemeral port, read the bound
-    /// port back, close the listener, and hand that port to QEMU. There is
-    /// a tiny race between close and QEMU's bind, but in practice QEMU
-    /// binds within milliseconds and the OS won't immediately reuse the
-    /// port for anyone else. `name` is unused now but kept in the signature
-    /// to minimise the diff at call sites.
-    fn ssh_port_for_name(_name: &str) -> u16 {
-        use std::net::TcpListener;
-        // 0 = let the OS pick any free ephemeral port on loopback.
-        match TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()) {
-            Ok(addr) => addr.port(),
-            Err(_) => 10022, // last-resort fallback; better than panic
+    fn cancelled(operation: &str, name: &str) -> VmError {
+        VmError::Cancelled {
+            operation: format!("{operation} of VM {name}"),
         }
     }
-}
 
-/// Generate a locally-administered unicast MAC address using random bytes.
-fn rand_mac() -> [u8; 6] {
-    use std::collections::hash_map::RandomState;
-    use std::hash::{BuildHasher, Hasher};
-
-    let s = RandomState::new();
-    let mut h = s.build_hasher();
-    h.write_u64(
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_nanos() as u64,
-    );
-    let v = h.finish();
-
-    let mut mac = [0u8; 6];
-    mac[0] = 0x52; // locally administered, unicast
-    mac[1] = 0x54;
-    mac[2] = (v >> 24) as u8;
-    mac[3] = (v >> 16) as u8;
-    mac[4] = (v >> 8) as u8;
-    mac[5] = v as u8;
-    mac
-}
+    /// Remove the work directory of a VM whose `prepare` was cancelled.
+    async fn abandon_prepare(work_dir: &Path, name: &str) -> VmError {
+        info!(name = %name, "QEMU: prepare cancelled, removing work directory");
+        let _ = tokio::fs::remove_dir_all(work_dir).await;
+        Self::cancelled("prepare", name)
+    }
 
-impl Hypervisor for QemuBackend {
-    async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
-        let work_dir = self.work_dir(&spec.name);
-        tokio::fs::create_dir_all(&work_dir).await?;
+    /// Kill a QEMU process that `start` spawned but did not hand over to the caller.
+    async fn kill_spawned(vm: &VmHandle) {
+        if let Some(pid) = Self::read_pid(&vm.work_dir).await {
+            if Self::pid_alive(pid) {
+                warn!(name = %vm.name, pid, "QEMU: start cancelled, killing the new process");
+                unsafe {
+                    libc::kill(pid as i32, libc::SIGKILL);
+                }
+            }
+        }
+        let _ = tokio::fs::remove_file(vm.work_dir.join("qemu.pid")).await;
+    }
 
-        // Create QCOW2 overlay
-        let overlay = work_dir.join("overlay.qcow2");
-        image::create_overlay(&spec.image_path, &overlay, spec.disk_gb).await?;
+    /// Check that this QEMU build has the host audio backend `vm` plays through. QEMU would
+    /// only fail on it after `-daemonize`, with a message that names no alternative.
+    async fn check_audio(&self, vm: &VmHandle) -> Result<()> {
+        let Some(driver) = vm.audio.driver() else {
+            return Ok(());
+        };
+        let output = tokio::process::Command::new(&self.qemu_binary)
+            .args(["-audiodev", "help"])
+            .output()
+            .await
+            .map_err(|e| VmError::QemuSpawnFailed {
+                source: e,
+                stderr: None,
+            })?;
+        let available = help_list(&String::from_utf8_lossy(&output.stdout));
+        if available.iter().any(|d| d == driver) {
+            return Ok(());
+        }
+        Err(VmError::AudioBackendUnavailable {
+            vm: vm.name.clone(),
+            backend: driver.into(),
+            available: available.join(", "),
+        })
+    }
 
-        // Generate cloud-init seed ISO if configured
-        let mut seed_iso_path = None;
-        if let Some(ref ci) = spec.cloud_init {
-            let iso_path = work_dir.join("seed.iso");
-            let instance_id = ci.instance_id.as_deref().unwrap_or(&spec.name);
-            let hostname = ci.hostname.as_deref().unwrap_or(&spec.name);
-            let meta_data = format!("instance-id: {instance_id}\nlocal-hostname: {hostname}\n");
+    /// Run QEMU with `args` in [`ProcessMode::Daemonized`]: wait for the parent to exit once
+    /// QEMU has initialized, then read the pid from the pidfile in `work_dir`. `tap` is passed
+    /// on as [`MACVTAP_FD`]. What QEMU printed goes to `qemu-launch.log` in `work_dir`, and the
+    /// end of its stderr into the error if it fails.
+    async fn spawn_daemonized(
+        &self,
+        work_dir: &Path,
+        args: &[String],
+        tap: Option<&std::fs::File>,
+    ) -> Result<Option<u32>> {
+        let mut command = tokio::process::Command::new(&self.qemu_binary);
+        command.args(args);
+        if let Some(tap) = tap {
+            pass_tap_fd(&mut command, tap);
+        }
+        // The daemon reopens its stdio on /dev/null once it has initialized, so the pipes close
+        // when the parent exits
+        let output = command
+            .output()
+            .await
+            .map_err(|e| VmError::QemuSpawnFailed {
+                source: e,
+                stderr: None,
+            })?;
+        let log = [output.stdout.as_slice(), output.stderr.as_slice()].concat();
+        if let Err(e) = tokio::fs::write(work_dir.join(QEMU_LAUNCH_LOG), log).await {
+            warn!(error = %e, "QEMU: cannot write {QEMU_LAUNCH_LOG}");
+        }
 
-            cloudinit::create_nocloud_iso_raw(&ci.user_data, meta_data.as_bytes(), &iso_path)?;
-            seed_iso_path = Some(iso_path);
+        if !output.status.success() {
+            return Err(VmError::QemuSpawnFailed {
+                source: std::io::Error::other(format!("QEMU exited with status {}", output.status)),
+                stderr: output_tail(&String::from_utf8_lossy(&output.stderr), QEMU_LAUNCH_LOG),
+            });
         }
+        Ok(Self::read_pid(work_dir).await)
+    }
 
-        let qmp_socket = work_dir.join("qmp.sock");
-        let console_socket = work_dir.join("console.sock");
+    /// Run QEMU with `args` in [`ProcessMode::Supervised`], its output going to `qemu.log` in
+    /// the work directory of `vm`, and `tap` passed on as [`MACVTAP_FD`]. Returns the pid and a
+    /// receiver that gets QEMU's exit status from the supervisor task.
+    async fn spawn_supervised(
+        &self,
+        vm: &VmHandle,
+        args: &[String],
+        tap: Option<&std::fs::File>,
+    ) -> Result<(u32, oneshot::Receiver<std::process::ExitStatus>)> {
+        let log = std::fs::File::create(vm.work_dir.join(QEMU_LOG))?;
+        let mut command = tokio::process::Command::new(&self.qemu_binary);
+        if let Some(tap) = tap {
+            pass_tap_fd(&mut command, tap);
+        }
+        let mut child = command
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            // A process group of its own, so Ctrl-C in vmctl's terminal doesn't reach it
+            .process_group(0)
+            .spawn()
+            .map_err(|e| VmError::QemuSpawnFailed {
+                source: e,
+                stderr: None,
+            })?;
+        let pid = child.id().ok_or_else(|| VmError::QemuSpawnFailed {
+            source: std::io::Error::other("QEMU exited as it started"),
+            stderr: None,
+        })?;
 
-        let mac_addr = Self::generate_mac();
+        let (exited, exit_status) = oneshot::channel();
+        let name = vm.name.clone();
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) => {
+                    debug!(name = %name, pid, %status, "QEMU: supervised process exited");
+                    let _ = exited.send(status);
+                }
+                Err(e) => warn!(name = %name, pid, error = %e, "QEMU: cannot wait for process"),
+            }
+        });
+        Ok((pid, exit_status))
+    }
 
-        // For user-mode networking, allocate an SSH host port based on the VM name
-        let ssh_host_port = match &spec.network {
-            NetworkConfig::User => Some(Self::ssh_port_for_name(&spec.name)),
-            _ => None,
+    /// The error for a supervised QEMU that exited with `status` before it was ready, with the
+    /// last lines it logged (e.g. `gtk initialization failed`).
+    async fn exited_early(work_dir: &Path, status: Option<std::process::ExitStatus>) -> VmError {
+        let log = tokio::fs::read_to_string(work_dir.join(QEMU_LOG))
+            .await
+            .unwrap_or_default();
+        let message = match status {
+            Some(status) => format!("QEMU exited with status {status}"),
+            None => "QEMU exited".to_string(),
         };
+        VmError::QemuSpawnFailed {
+            source: std::io::Error::other(message),
+            stderr: output_tail(&log, QEMU_LOG),
+        }
+    }
 
-        // Copy OVMF_VARS to the VM's work directory when UEFI is requested
-        if spec.uefi {
-            if let Some(ovmf_vars) = find_ovmf_vars() {
-                let vars_dest = work_dir.join("efivars.fd");
-                tokio::fs::copy(&ovmf_vars, &vars_dest)
-                    .await
-                    .map_err(|e| VmError::InvalidState {
-                        name: spec.name.clone(),
-                        state: format!("failed to copy OVMF_VARS: {e}"),
-                    })?;
-            }
+    /// Step the guest's clock after it was paused, through the guest agent if it answers. The
+    /// emulated RTC kept running on host time, but the guest only rereads it on its own schedule
+    /// (or on an RTC_CHANGE it never sees here), so without the agent it stays behind by the
+    /// paused time until NTP catches up.
+    async fn resync_clock(vm: &VmHandle) {
+        let handle = vm.clone();
+        let result =
+            tokio::task::spawn_blocking(move || qga::sync_time(&handle, RESUME_TIME_SYNC_TIMEOUT))
+                .await;
+        match result {
+            Ok(Ok(())) => info!(name = %vm.name, "QEMU: guest clock set after resume"),
+            Ok(Err(e)) => warn!(
+                name = %vm.name,
+                error = %e,
+                "QEMU: could not set the guest clock after resume; it is behind by the time \
+                 the VM was paused until NTP or `vmctl time-sync` corrects it"
+            ),
+            Err(e) => warn!(name = %vm.name, error = %e, "QEMU: guest clock resync panicked"),
         }
+    }
 
-        let handle = VmHandle {
-            id: format!("qemu-{}", uuid::Uuid::new_v4()),
-            name: spec.name.clone(),
-            backend: BackendTag::Qemu,
-            work_dir,
-            overlay_path: Some(overlay),
-            seed_iso_path,
-            pid: None,
-            qmp_socket: Some(qmp_socket),
-            console_socket: Some(console_socket),
-            vnc_addr: None,
-            vcpus: spec.vcpus,
-            memory_mb: spec.memory_mb,
-            disk_gb: spec.disk_gb,
-            network: spec.network.clone(),
-            ssh_host_port,
-            mac_addr: Some(mac_addr),
-            uefi: spec.uefi,
-        };
+    /// DHCP lease files searched, in order, for bridged guests that are not in the neighbour
+    /// table yet (default [`LeaseSource::defaults`]).
+    pub fn with_lease_sources(mut self, sources: Vec<LeaseSource>) -> Self {
+        self.lease_sources = sources;
+        self
+    }
 
-        info!(
-            name = %spec.name,
-            id = %handle.id,
-            vcpus = handle.vcpus,
-            memory_mb = handle.memory_mb,
-            overlay = ?handle.overlay_path,
-            seed = ?handle.seed_iso_path,
-            "QEMU: prepared"
-        );
+    /// Where managed networks keep their state; their dnsmasq lease files are searched first for
+    /// VMs on a managed bridge.
+    pub fn with_networks_dir(mut self, dir: PathBuf) -> Self {
+        self.networks_dir = dir;
+        self
+    }
 
-        Ok(handle)
+    fn work_dir(&self, name: &str) -> PathBuf {
+        self.data_dir.join(name)
     }
 
-    async fn start(&self, vm: &VmHandle) -> Result<VmHandle> {
+    /// Build the QEMU command line for `vm`.
+    ///
+    /// `microvm` guests get virtio-mmio devices, no firmware and no VNC, and must boot a kernel
+    /// directly. UEFI guests boot `firmware`; SEV guests are set up for the host CPU `sev`. With
+    /// the `qemu` that will run it, the VM is checked against what that QEMU supports, and
+    /// devices older releases lack are swapped for ones they have; without it, the command
+    /// line is for a current QEMU.
+    fn build_args(
+        vm: &VmHandle,
+        firmware: Option<&Firmware>,
+        sev: Option<&SevHost>,
+        qemu: Option<&QemuInfo>,
+    ) -> Result<Vec<String>> {
+        let invalid = |state: &str| VmError::InvalidState {
+            name: vm.name.clone(),
+            state: state.into(),
+        };
+        if let Some(qemu) = qemu {
+            check_qemu_support(vm, qemu)?;
+        }
+        let qemu_at_least = |major, minor| qemu.is_none_or(|q| q.at_least(major, minor));
         let overlay = vm
             .overlay_path
             .as_ref()
-            .ok_or_else(|| VmError::InvalidState {
-                name: vm.name.clone(),
-                state: "no overlay path".into(),
-            })?;
-
+            .ok_or_else(|| invalid("no overlay path"))?;
         let qmp_sock = vm
             .qmp_socket
             .as_ref()
-            .ok_or_else(|| VmError::InvalidState {
-                name: vm.name.clone(),
-                state: "no QMP socket path".into(),
-            })?;
+            .ok_or_else(|| invalid("no QMP socket path"))?;
         let console_sock = vm
             .console_socket
             .as_ref()
-            .ok_or_else(|| VmError::InvalidState {
-                name: vm.name.clone(),
-                state: "no console socket path".into(),
-            })?;
+            .ok_or_else(|| invalid("no console socket path"))?;
 
-        // Clean up stale socket files from a previous run
-        for sock in [qmp_sock, console_sock] {
-            if sock.exists() {
-                let _ = tokio::fs::remove_file(sock).await;
+        let microvm = vm.machine == MachineType::Microvm;
+        if microvm {
+            if vm.kernel.is_none() {
+                return Err(invalid(
+                    "the microvm machine type requires direct kernel boot (set a kernel)",
+                ));
+            }
+            if vm.uefi || vm.secure_boot {
+                return Err(invalid("the microvm machine type does not support UEFI"));
+            }
+            if vm.disk_bus == DiskBus::Sata {
+                return Err(invalid("the microvm machine type has no SATA controller"));
+            }
+            if vm.disk_bus == DiskBus::Nvme {
+                return Err(invalid("the microvm machine type has no PCI bus for NVMe"));
             }
         }
+        let boot_order = vm.start_boot_order();
+        check_boot(
+            &vm.name,
+            &boot_order,
+            vm.machine,
+            vm.kernel.is_some(),
+            vm.cdrom.is_some(),
+            &vm.network,
+        )?;
+        // `bootindex` of a device the firmware boots from, and the id it is changed by over QMP
+        // unless the device has one already
+        let boot =
+            |device: BootDevice, has_id: bool| match boot_order.iter().position(|&d| d == device) {
+                Some(i) if has_id => format!(",bootindex={i}"),
+                Some(i) => format!(",bootindex={i},id={}", boot_device_id(device)),
+                None => String::new(),
+            };
+        let windows = vm.guest_os == GuestOs::Windows;
+        // virtio-mmio on microvm, PCI everywhere else
+        let virtio = |dev: &str| {
+            if microvm {
+                format!("virtio-{dev}-device")
+            } else {
+                format!("virtio-{dev}-pci")
+            }
+        };
 
-        let mac = vm.mac_addr.as_deref().unwrap_or("52:54:00:00:00:01");
+        let mac = vm.mac_addr.as_deref().unwrap_or(DEFAULT_MAC);
 
-        let mut args: Vec<String> = vec![
-            "-enable-kvm".into(),
-            "-machine".into(),
-            "q35,accel=kvm".into(),
+        let mut args: Vec<String> = vec!["-enable-kvm".into()];
+        if microvm {
+            // microvm has no RTC to configure, so `-rtc` is left out
+            args.extend([
+                "-machine".into(),
+                "microvm,x-option-roms=off,rtc=off".into(),
+            ]);
+        } else {
+            let mut machine = String::from("q35,accel=kvm");
+            if vm.secure_boot {
+                if !vm.uefi {
+                    return Err(invalid("Secure Boot requested without UEFI"));
+                }
+                // Only SMM code may write the variable store, so the guest can't change the keys
+                machine.push_str(",smm=on");
+            }
+            if vm.confidential.is_some() {
+                if !vm.uefi {
+                    return Err(invalid("a confidential guest requested without UEFI"));
+                }
+                if vm.secure_boot {
+                    return Err(invalid(
+                        "Secure Boot is not supported for confidential guests",
+                    ));
+                }
+                // QEMU also turns on iommu_platform for every virtio device, so they use the
+                // guest's unencrypted bounce buffers
+                machine.push_str(",confidential-guest-support=sev0");
+            }
+            args.extend(["-machine".into(), machine]);
+            if vm.secure_boot {
+                args.extend([
+                    "-global".into(),
+                    "driver=cfi.pflash01,property=secure,value=on".into(),
+                ]);
+            }
+            if let Some(ConfidentialMode::Sev) = vm.confidential {
+                let sev = sev.ok_or_else(|| invalid("SEV requested without the host's C-bit"))?;
+                args.extend([
+                    "-object".into(),
+                    format!(
+                        "sev-guest,id=sev0,cbitpos={},reduced-phys-bits={},policy={SEV_POLICY:#x}",
+                        sev.cbitpos, sev.reduced_phys_bits
+                    ),
+                ]);
+            }
+            // An `-rtc` among the VM's extra QEMU arguments replaces the default
+            if !vm.qemu_args.iter().any(|arg| arg == "-rtc") {
+                let rtc = if windows { WINDOWS_RTC } else { DEFAULT_RTC };
+                args.extend(["-rtc".into(), rtc.into()]);
+            }
+        }
+        args.extend([
             "-cpu".into(),
             "host".into(),
             "-nodefaults".into(),
             // vCPUs
             "-smp".into(),
-            vm.vcpus.to_string(),
+            smp_option(vm),
             // Memory
             "-m".into(),
             format!("{}M", vm.memory_mb),
-            // QMP socket
+            // QMP socket, and a second one for watching events
             "-qmp".into(),
             format!("unix:{},server,nowait", qmp_sock.display()),
+            "-qmp".into(),
+            format!(
+                "unix:{},server,nowait",
+                vm.work_dir.join(EVENTS_SOCKET).display()
+            ),
             // Serial console: Unix socket (interactive) + log file for post-mortem review
             "-chardev".into(),
             format!(
@@ -252,356 +583,3911 @@ impl Hypervisor for QemuBackend {
             ),
             "-serial".into(),
             "chardev:serial0".into(),
-            // VNC on localhost, auto-select a free display.
-            // `127.0.0.1:0,to=99` tells QEMU to try display 0 (TCP 5900) and
-            // fall back through 5901..=5999 if occupied. Without `to=`, QEMU
-            // binds display 0 exactly and the second concurrent VM fails with
-            // "Address already in use".
-            "-vnc".into(),
-            "127.0.0.1:0,to=99".into(),
-            // Virtio RNG
-            "-device".into(),
-            "virtio-rng-pci".into(),
-            // Main disk
-            "-drive".into(),
+            // Guest agent channel, answered if the guest runs qemu-guest-agent
+            "-chardev".into(),
             format!(
-                "file={},format=qcow2,if=none,id=drive0,discard=unmap",
-                overlay.display()
+                "socket,id=qga0,path={},server=on,wait=off",
+                vm.work_dir.join(qga::AGENT_SOCKET).display()
             ),
             "-device".into(),
-            "virtio-blk-pci,drive=drive0".into(),
-        ];
-
-        // UEFI firmware (OVMF pflash drives)
-        if vm.uefi {
-            if let Some(ovmf_code) = find_ovmf_code() {
-                let efivars = vm.work_dir.join("efivars.fd");
+            virtio("serial"),
+            "-device".into(),
+            format!("virtserialport,chardev=qga0,name={}", qga::AGENT_CHANNEL),
+        ]);
+        args.extend(memory_args(vm));
+        match vm.display {
+            DisplayMode::Gtk | DisplayMode::Sdl if microvm => {
+                return Err(invalid(
+                    "the microvm machine type has no display adapter for a local window",
+                ));
+            }
+            DisplayMode::Vnc | DisplayMode::None if microvm => {
+                args.extend(["-display".into(), "none".into()]);
+            }
+            DisplayMode::None => args.extend(["-display".into(), "none".into()]),
+            DisplayMode::Vnc => {
+                // VNC on localhost, auto-select a free display.
+                // `127.0.0.1:0,to=99` tells QEMU to try display 0 (TCP 5900) and
+                // fall back through 5901..=5999 if occupied. Without `to=`, QEMU
+                // binds display 0 exactly and the second concurrent VM fails with
+                // "Address already in use".
+                args.extend(["-vnc".into(), "127.0.0.1:0,to=99".into()]);
+                // -nodefaults drops the display adapter, and Windows has no serial console to
+                // fall back on
+                if windows {
+                    args.extend(["-device".into(), "VGA".into()]);
+                }
+            }
+            DisplayMode::Gtk | DisplayMode::Sdl => {
+                // Windows has no virtio-gpu driver out of the box
+                let adapter = if windows { "VGA" } else { "virtio-gpu-pci" };
                 args.extend([
-                    "-drive".into(),
-                    format!(
-                        "if=pflash,format=raw,readonly=on,file={}",
-                        ovmf_code.display()
-                    ),
-                    "-drive".into(),
-                    format!("if=pflash,format=raw,file={}", efivars.display()),
+                    "-device".into(),
+                    adapter.into(),
+                    "-display".into(),
+                    vm.display.to_string(),
                 ]);
-            } else {
-                warn!("UEFI requested but OVMF firmware not found — falling back to BIOS boot");
             }
         }
-
-        // Networking
-        match &vm.network {
-            NetworkConfig::Tap { bridge } => {
+        // A sound card playing through the host's audio server
+        if let Some(driver) = vm.audio.driver() {
+            if microvm {
+                return Err(invalid("the microvm machine type has no sound card"));
+            }
+            args.extend([
+                "-audiodev".into(),
+                format!("{driver},id={AUDIODEV}"),
+                "-device".into(),
+            ]);
+            // Windows has no virtio-sound driver out of the box, and QEMU only has one since 8.2
+            if windows || !qemu_at_least(8, 2) {
                 args.extend([
-                    "-netdev".into(),
-                    format!("tap,id=net0,br={bridge},script=no,downscript=no"),
+                    "intel-hda".into(),
                     "-device".into(),
-                    format!("virtio-net-pci,netdev=net0,mac={mac}"),
+                    format!("hda-duplex,audiodev={AUDIODEV}"),
                 ]);
+            } else {
+                args.push(format!("virtio-sound-pci,audiodev={AUDIODEV}"));
             }
-            NetworkConfig::User => {
-                let port = vm.ssh_host_port.unwrap_or(10022);
+        }
+        // SMBIOS system information and OEM strings
+        if !vm.smbios.is_empty() {
+            if microvm {
+                return Err(invalid("the microvm machine type has no SMBIOS tables"));
+            }
+            let fields = vm.smbios.system_fields();
+            if !fields.is_empty() {
+                let mut system = String::from("type=1");
+                for (key, value) in fields {
+                    system.push_str(&format!(",{key}={}", qemu_opt_escape(value)));
+                }
+                args.extend(["-smbios".into(), system]);
+            }
+            for value in vm.smbios.oem_strings_for(&vm.name, &vm.id) {
                 args.extend([
-                    "-netdev".into(),
-                    format!("user,id=net0,hostfwd=tcp::{port}-:22"),
-                    "-device".into(),
-                    format!("virtio-net-pci,netdev=net0,mac={mac}"),
+                    "-smbios".into(),
+                    format!("type=11,value={}", qemu_opt_escape(&value)),
                 ]);
             }
-            NetworkConfig::Vnic { .. } | NetworkConfig::None => {
-                // No network args for Vnic (illumos only) or None
+        }
+        // A USB 3 controller on every q35 VM, so that devices can be attached while it runs
+        if microvm {
+            if !vm.usb_devices.is_empty() {
+                return Err(invalid("the microvm machine type has no USB controller"));
             }
+        } else {
+            args.extend(["-device".into(), "qemu-xhci,id=xhci".into()]);
         }
-
-        // Seed ISO (cloud-init) — use IDE CDROM so it doesn't interfere with
-        // the root disk's virtio-blk device ordering (Ubuntu cloud images use
-        // LABEL=cloudimg-rootfs which expects the root disk as the first virtio device)
+        for usb in &vm.usb_devices {
+            let selector = match *usb {
+                UsbDevice::Id { vendor, product } => {
+                    format!("vendorid={vendor:#06x},productid={product:#06x}")
+                }
+                UsbDevice::Address { bus, device } => format!("hostbus={bus},hostaddr={device}"),
+            };
+            args.extend([
+                "-device".into(),
+                format!(
+                    "usb-host,bus={USB_BUS},{selector},id={}",
+                    usb_device_id(usb)
+                ),
+            ]);
+        }
+        if let RngConfig::VirtioRng {
+            max_bytes,
+            period_ms,
+        } = vm.random_seed
+        {
+            let mut device = format!("{},rng=rng0", virtio("rng"));
+            if let Some(n) = max_bytes {
+                device.push_str(&format!(",max-bytes={n}"));
+            }
+            if let Some(ms) = period_ms {
+                device.push_str(&format!(",period={ms}"));
+            }
+            args.extend([
+                "-object".into(),
+                "rng-random,filename=/dev/urandom,id=rng0".into(),
+                "-device".into(),
+                device,
+            ]);
+        }
+        // One virtio-scsi controller, shared by every SCSI disk
+        if vm.disk_bus == DiskBus::Scsi {
+            args.extend([
+                "-device".into(),
+                format!("{},id={SCSI_CONTROLLER}", virtio("scsi")),
+            ]);
+        }
+        // Ids and serials follow the disk's index, so the guest names it the same every boot
+        let disk = ROOT_DISK_INDEX;
+        let serial = disk_serial(disk);
+        args.extend([
+            // Main disk
+            "-drive".into(),
+            format!(
+                "file={},format=qcow2,if=none,id={ROOT_DRIVE},discard=unmap",
+                overlay.display()
+            ),
+            "-device".into(),
+            match vm.disk_bus {
+                DiskBus::Virtio => format!(
+                    "{},drive={ROOT_DRIVE}{}",
+                    virtio("blk"),
+                    boot(BootDevice::Disk, false)
+                ),
+                // on the q35 AHCI controller; the seed and extra ISOs have ide.0 and ide.1
+                DiskBus::Sata => format!(
+                    "ide-hd,drive={ROOT_DRIVE},bus=ide.2{}",
+                    boot(BootDevice::Disk, false)
+                ),
+                DiskBus::Nvme => format!(
+                    "nvme,drive={ROOT_DRIVE},serial={serial},id=disk{disk}{}",
+                    boot(BootDevice::Disk, true)
+                ),
+                DiskBus::Scsi => format!(
+                    "scsi-hd,drive={ROOT_DRIVE},bus={SCSI_CONTROLLER}.0,scsi-id={disk},serial={serial},id=disk{disk}{}",
+                    boot(BootDevice::Disk, true)
+                ),
+            },
+        ]);
+
+        // Direct kernel boot
+        if let Some(ref kernel) = vm.kernel {
+            args.extend(["-kernel".into(), kernel.kernel.display().to_string()]);
+            if let Some(ref initrd) = kernel.initrd {
+                args.extend(["-initrd".into(), initrd.display().to_string()]);
+            }
+            args.extend(["-append".into(), kernel.cmdline().to_string()]);
+        }
+
+        // UEFI firmware (OVMF pflash drives)
+        if vm.uefi {
+            let firmware = firmware.ok_or_else(|| invalid("UEFI requested without firmware"))?;
+            let efivars = vm.work_dir.join("efivars.fd");
+            args.extend([
+                "-drive".into(),
+                format!(
+                    "if=pflash,format=raw,readonly=on,file={}",
+                    firmware.code.display()
+                ),
+                "-drive".into(),
+                format!("if=pflash,format=raw,file={}", efivars.display()),
+            ]);
+        }
+
+        // Additional serial ports (ttyS1, ttyS2, ...) after the console on ttyS0
+        for port in &vm.serial_ports {
+            if port.backend == SerialBackend::Stdio {
+                return Err(invalid(
+                    "stdio serial ports are not supported by the QEMU backend",
+                ));
+            }
+            args.extend(["-serial".into(), serial_spec(&port.backend)]);
+        }
+
+        // Networking
+        let nic = format!(
+            "{},netdev=net0,mac={mac}{}",
+            virtio("net"),
+            boot(BootDevice::Network, false)
+        );
+        match &vm.network {
+            NetworkConfig::Tap { bridge } => {
+                args.extend([
+                    "-netdev".into(),
+                    format!("tap,id=net0,br={bridge},script=no,downscript=no"),
+                    "-device".into(),
+                    nic,
+                ]);
+            }
+            NetworkConfig::Macvtap { .. } => {
+                args.extend([
+                    "-netdev".into(),
+                    format!("tap,id=net0,fd={MACVTAP_FD}"),
+                    "-device".into(),
+                    nic,
+                ]);
+            }
+            NetworkConfig::User => {
+                let port = vm.ssh_host_port.ok_or_else(|| {
+                    invalid("no SSH host port allocated for user-mode networking")
+                })?;
+                let mut netdev = format!("user,id=net0,hostfwd=tcp::{port}-:22");
+                if let Some(rdp) = vm.rdp_host_port {
+                    netdev.push_str(&format!(",hostfwd=tcp:127.0.0.1:{rdp}-:3389"));
+                }
+                args.extend(["-netdev".into(), netdev, "-device".into(), nic]);
+            }
+            NetworkConfig::Vnic { .. } | NetworkConfig::None => {
+                // No network args for Vnic (illumos only) or None
+            }
+        }
+
+        // Private networks: one NIC each, on the host bridge or a multicast socket
+        for (i, nic) in vm.private_networks.iter().enumerate() {
+            let netdev = match nic.bridge {
+                Some(_) => format!(
+                    "tap,id=pnet{i},script={},downscript=no",
+                    private_ifup_script_path(vm, nic).display()
+                ),
+                None => format!(
+                    "socket,id=pnet{i},mcast={},localaddr=127.0.0.1",
+                    nic.multicast_group()
+                ),
+            };
+            let mac = nic
+                .mac
+                .as_deref()
+                .ok_or_else(|| invalid("private network NIC has no MAC address"))?;
+            args.extend([
+                "-netdev".into(),
+                netdev,
+                "-device".into(),
+                format!("{},netdev=pnet{i},mac={mac}", virtio("net")),
+            ]);
+        }
+
+        // Seed ISO (cloud-init) — use IDE CDROM so it doesn't interfere with
+        // the root disk's virtio-blk device ordering (Ubuntu cloud images use
+        // LABEL=cloudimg-rootfs which expects the root disk as the first virtio device).
+        // microvm has no IDE bus, so there it is a second virtio disk after the root disk.
         if let Some(ref iso) = vm.seed_iso_path {
+            if microvm {
+                args.extend([
+                    "-drive".into(),
+                    format!(
+                        "file={},format=raw,if=none,id=seed,readonly=on",
+                        iso.display()
+                    ),
+                    "-device".into(),
+                    "virtio-blk-device,drive=seed".into(),
+                ]);
+            } else {
+                args.extend([
+                    "-drive".into(),
+                    format!(
+                        "file={},format=raw,if=ide,media=cdrom,readonly=on",
+                        iso.display()
+                    ),
+                ]);
+            }
+        }
+
+        // Extra ISO: a real CD-ROM on its own IDE bus, so installers recognise it and the seed
+        // keeps ide.0. microvm has no IDE, so it gets another virtio disk.
+        if let Some(ref iso) = vm.cdrom {
+            args.extend([
+                "-drive".into(),
+                format!(
+                    "file={},format=raw,if=none,id=cdrom0,media=cdrom,readonly=on",
+                    iso.display()
+                ),
+                "-device".into(),
+                if microvm {
+                    "virtio-blk-device,drive=cdrom0".into()
+                } else {
+                    format!(
+                        "ide-cd,drive=cdrom0,bus=ide.1{}",
+                        boot(BootDevice::Cdrom, false)
+                    )
+                },
+            ]);
+        }
+
+        // virtio-win drivers for Windows installers, on the last IDE bus
+        if let Some(ref iso) = vm.drivers_iso {
+            if microvm {
+                return Err(invalid(
+                    "the microvm machine type cannot attach a drivers ISO",
+                ));
+            }
             args.extend([
                 "-drive".into(),
                 format!(
-                    "file={},format=raw,if=ide,media=cdrom,readonly=on",
+                    "file={},format=raw,if=none,id=drivers0,media=cdrom,readonly=on",
                     iso.display()
                 ),
+                "-device".into(),
+                "ide-cd,drive=drivers0,bus=ide.3".into(),
             ]);
         }
 
-        // Daemonize and pidfile
-        args.extend([
-            "-daemonize".into(),
-            "-pidfile".into(),
-            vm.work_dir.join("qemu.pid").display().to_string(),
-        ]);
+        // Pidfile, and daemonize unless QEMU stays in the foreground for a local display
+        if ProcessMode::for_vm(vm) == ProcessMode::Daemonized {
+            args.push("-daemonize".into());
+        }
+        args.extend([
+            "-pidfile".into(),
+            vm.work_dir.join("qemu.pid").display().to_string(),
+        ]);
+
+        // Extra arguments go last so they can add to (but not replace) the managed ones
+        check_qemu_args(&vm.qemu_args)?;
+        args.extend(vm.qemu_args.iter().cloned());
+
+        Ok(args)
+    }
+
+    /// Generate a random locally-administered MAC address.
+    pub fn generate_mac() -> String {
+        let bytes: [u8; 6] = rand_mac();
+        format!(
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]
+        )
+    }
+
+    /// Generate a random MAC address that is not in `existing_macs` (compared case-insensitively).
+    pub fn generate_unique_mac(existing_macs: &[String]) -> String {
+        Self::generate_unique_mac_with(existing_macs, Self::generate_mac)
+    }
+
+    /// Draw MAC addresses from `generate` until one is not in `existing_macs` (compared
+    /// case-insensitively).
+    fn generate_unique_mac_with(
+        existing_macs: &[String],
+        mut generate: impl FnMut() -> String,
+    ) -> String {
+        loop {
+            let mac = generate();
+            if !existing_macs.iter().any(|m| m.eq_ignore_ascii_case(&mac)) {
+                return mac;
+            }
+        }
+    }
+
+    /// Read PID from the pidfile in the work directory.
+    async fn read_pid(work_dir: &Path) -> Option<u32> {
+        let pid_path = work_dir.join("qemu.pid");
+        tokio::fs::read_to_string(&pid_path)
+            .await
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+    }
+
+    /// Check if a process with the given PID is alive.
+    fn pid_alive(pid: u32) -> bool {
+        // Signal 0 checks if process exists without sending a signal
+        unsafe { libc::kill(pid as i32, 0) == 0 }
+    }
+
+    /// Rebuild the handle of the VM in `work_dir`, named after the directory, for a VM the
+    /// caller has lost track of. While its QEMU runs the handle is filled in from the process's
+    /// command line; otherwise only the files in `work_dir` are known and the rest is defaulted.
+    pub async fn inspect(work_dir: PathBuf) -> VmHandle {
+        let name = work_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let running = Self::read_pid(&work_dir)
+            .await
+            .filter(|&pid| Self::pid_alive(pid))
+            .and_then(|pid| Some((pid, Self::read_command_line(pid, &work_dir)?)));
+        match running {
+            Some((pid, args)) => {
+                let mut vm = handle_from_command_line(&name, work_dir, &args);
+                vm.pid = Some(pid);
+                vm
+            }
+            None => handle_from_command_line(&name, work_dir, &[]),
+        }
+    }
+
+    /// The arguments of process `pid`, if it is the QEMU of the VM in `work_dir`: the pid may
+    /// have been reused since QEMU wrote it.
+    pub fn read_command_line(pid: u32, work_dir: &Path) -> Option<Vec<String>> {
+        let raw = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+        let args = split_cmdline(&raw);
+        is_qemu_of(&args, work_dir).then_some(args)
+    }
+
+    /// Look up the addresses currently associated with `mac`: the neighbour table (IPv4 and
+    /// global IPv6), then, when that has no IPv4 address, `managed_leases` (the lease file of a
+    /// managed network) and the configured lease sources.
+    async fn find_ips_by_mac(
+        &self,
+        mac: &str,
+        bridge: Option<&str>,
+        managed_leases: Option<&Path>,
+    ) -> VmIpInfo {
+        let mut info = VmIpInfo::default();
+        if let Ok(output) = tokio::process::Command::new("ip")
+            .args(["neigh", "show"])
+            .output()
+            .await
+        {
+            for ip in neigh_ips(&String::from_utf8_lossy(&output.stdout), mac, bridge) {
+                info.push(ip);
+            }
+        }
+        if !info.v4.is_empty() {
+            return info;
+        }
+
+        let managed = managed_leases.map(|path| LeaseSource {
+            format: LeaseFormat::Dnsmasq,
+            path: path.to_path_buf(),
+        });
+        let sources: Vec<LeaseSource> = managed
+            .into_iter()
+            .chain(self.lease_sources.iter().cloned())
+            .collect();
+        if let Some(ip) = leases::find_ip(&sources, mac).await {
+            match ip.parse::<IpAddr>() {
+                Ok(IpAddr::V6(v6)) if !is_global_v6(&v6) => {}
+                Ok(ip) => info.push(ip),
+                Err(_) => warn!(ip = %ip, mac = %mac, "ignoring unparseable lease address"),
+            }
+        }
+        info
+    }
+
+    /// Pick a free TCP host port for SSH forwarding.
+    ///
+    /// Binds to an OS-assigned ephemeral port on loopback, reads the bound port back, closes the
+    /// listener, and hands that port to QEMU. There is a tiny race between close and QEMU's bind,
+    /// but in practice QEMU binds within milliseconds and the OS won't immediately reuse the port
+    /// for anyone else. (Hashing the VM name into a fixed range collided often with several
+    /// concurrent VMs.)
+    pub fn find_free_port() -> std::io::Result<u16> {
+        use std::net::TcpListener;
+        TcpListener::bind("127.0.0.1:0")?
+            .local_addr()
+            .map(|addr| addr.port())
+    }
+
+    /// Take internal snapshot `snapshot` of `vm`'s disk. A running VM is snapshotted live with
+    /// `savevm`, which also saves its memory and device state; a stopped one with
+    /// `qemu-img snapshot -c`.
+    pub async fn save_snapshot(vm: &VmHandle, snapshot: &str) -> Result<()> {
+        if !Self::live_snapshot_command(vm, "savevm", snapshot).await? {
+            image::create_snapshot(Self::overlay(vm)?, snapshot).await?;
+        }
+        info!(name = %vm.name, snapshot, "QEMU: snapshot saved");
+        Ok(())
+    }
+
+    /// Return `vm` to internal snapshot `snapshot`. A running VM carries on from the snapshot's
+    /// saved state with `loadvm`, which needs a snapshot taken while it ran; a stopped VM's disk
+    /// is reverted with `qemu-img snapshot -a`. Everything written since is lost.
+    pub async fn load_snapshot(vm: &VmHandle, snapshot: &str) -> Result<()> {
+        if !Self::live_snapshot_command(vm, "loadvm", snapshot).await? {
+            image::revert_snapshot(Self::overlay(vm)?, snapshot).await?;
+        }
+        info!(name = %vm.name, snapshot, "QEMU: snapshot loaded");
+        Ok(())
+    }
+
+    /// Delete internal snapshot `snapshot` of `vm`'s disk, with `delvm` while the VM runs.
+    pub async fn delete_snapshot(vm: &VmHandle, snapshot: &str) -> Result<()> {
+        if !Self::live_snapshot_command(vm, "delvm", snapshot).await? {
+            image::delete_snapshot(Self::overlay(vm)?, snapshot).await?;
+        }
+        info!(name = %vm.name, snapshot, "QEMU: snapshot deleted");
+        Ok(())
+    }
+
+    fn overlay(vm: &VmHandle) -> Result<&Path> {
+        vm.overlay_path
+            .as_deref()
+            .ok_or_else(|| VmError::InvalidState {
+                name: vm.name.clone(),
+                state: "no disk image".into(),
+            })
+    }
+
+    /// Run the snapshot monitor command `command` (`savevm`, `loadvm` or `delvm`) if `vm`'s QEMU
+    /// process is alive. Returns `false` without doing anything when it is not running.
+    async fn live_snapshot_command(vm: &VmHandle, command: &str, snapshot: &str) -> Result<bool> {
+        let Some(ref qmp_sock) = vm.qmp_socket else {
+            return Ok(false);
+        };
+        if !Self::read_pid(&vm.work_dir)
+            .await
+            .is_some_and(Self::pid_alive)
+        {
+            return Ok(false);
+        }
+        // savevm and loadvm move the guest's memory, which is encrypted
+        if vm.confidential.is_some() && command != "delvm" {
+            return Err(VmError::ConfidentialConflict {
+                vm: vm.name.clone(),
+                detail: "live snapshots (stop the VM to snapshot its disk)".into(),
+            });
+        }
+        let line = format!("{command} {snapshot}");
+        let output = QmpPool::shared()
+            .run(qmp_sock, Duration::from_secs(5), async |qmp| {
+                qmp.human_monitor_command(&line).await
+            })
+            .await?;
+        // These commands print nothing unless they fail
+        let output = output.trim();
+        if !output.is_empty() {
+            return Err(VmError::SnapshotFailed {
+                snapshot: snapshot.into(),
+                path: vm.overlay_path.clone().unwrap_or_default(),
+                detail: output.trim_start_matches("Error: ").to_string(),
+            });
+        }
+        Ok(true)
+    }
+
+    /// The SEV launch measurement of confidential VM `vm` (base64, as QEMU reports it) and the
+    /// guest's SEV state. A guest owner compares the measurement with the one expected for the
+    /// firmware and launch policy before trusting the guest with secrets.
+    pub async fn launch_measurement(vm: &VmHandle) -> Result<(String, SevInfo)> {
+        if vm.confidential.is_none() {
+            return Err(VmError::InvalidState {
+                name: vm.name.clone(),
+                state: "not a confidential VM".into(),
+            });
+        }
+        let qmp_sock = vm
+            .qmp_socket
+            .as_ref()
+            .ok_or_else(|| VmError::InvalidState {
+                name: vm.name.clone(),
+                state: "no QMP socket path".into(),
+            })?;
+        QmpPool::shared()
+            .run(qmp_sock, Duration::from_secs(5), async |qmp| {
+                let measurement = qmp.query_sev_launch_measure().await?;
+                let info = qmp.query_sev().await?;
+                Ok((measurement, info))
+            })
+            .await
+    }
+
+    /// The host CPUs each vCPU thread of running VM `vm` may run on, as the kernel reports
+    /// them; shows whether the VM's `cpu_pinning` is in effect.
+    pub async fn vcpu_affinity(vm: &VmHandle) -> Result<Vec<VcpuAffinity>> {
+        let qmp_sock = vm
+            .qmp_socket
+            .as_ref()
+            .ok_or_else(|| VmError::InvalidState {
+                name: vm.name.clone(),
+                state: "no QMP socket path".into(),
+            })?;
+        let threads = QmpPool::shared()
+            .run(qmp_sock, Duration::from_secs(2), async |qmp| {
+                qmp.query_vcpu_threads().await
+            })
+            .await?;
+        (0u16..)
+            .zip(threads)
+            .map(|(vcpu, thread_id)| -> Result<VcpuAffinity> {
+                let cpus = pinning::thread_affinity(thread_id)?;
+                Ok(VcpuAffinity {
+                    vcpu,
+                    thread_id,
+                    cpus,
+                })
+            })
+            .collect()
+    }
+
+    /// Pass host USB device `device` through to running VM `vm` until it stops or the device is
+    /// detached. The VM must have been started with a USB controller, as q35 VMs are.
+    pub async fn usb_attach(vm: &VmHandle, device: &UsbDevice) -> Result<()> {
+        usb::check_access(&vm.name, device)?;
+        let mut properties = serde_json::json!({
+            "driver": "usb-host",
+            "bus": USB_BUS,
+            "id": usb_device_id(device),
+        });
+        for (key, value) in usb_host_properties(device) {
+            properties[key] = value.into();
+        }
+        let result = QmpPool::shared()
+            .run(Self::qmp_socket(vm)?, Duration::from_secs(5), async |qmp| {
+                qmp.device_add(properties.clone()).await
+            })
+            .await;
+        match result {
+            Err(VmError::QmpError { desc, .. }) if desc.contains(USB_BUS) => {
+                Err(VmError::InvalidState {
+                    name: vm.name.clone(),
+                    state: "running without a USB controller (restart it to add one)".into(),
+                })
+            }
+            Err(VmError::QmpError { desc, .. }) if desc.contains("Duplicate") => {
+                Err(VmError::InvalidState {
+                    name: vm.name.clone(),
+                    state: format!("already using USB device {device}"),
+                })
+            }
+            other => other,
+        }
+    }
+
+    /// Unplug host USB device `device` from running VM `vm`.
+    pub async fn usb_detach(vm: &VmHandle, device: &UsbDevice) -> Result<()> {
+        let id = usb_device_id(device);
+        let result = QmpPool::shared()
+            .run(Self::qmp_socket(vm)?, Duration::from_secs(5), async |qmp| {
+                qmp.device_del(&id).await
+            })
+            .await;
+        match result {
+            Err(VmError::QmpError { class, .. }) if class == "DeviceNotFound" => {
+                Err(VmError::InvalidState {
+                    name: vm.name.clone(),
+                    state: format!("not using USB device {device}"),
+                })
+            }
+            other => other,
+        }
+    }
+
+    /// The host USB devices running VM `vm` uses, from its spec and attached since it started.
+    pub async fn usb_devices(vm: &VmHandle) -> Result<Vec<UsbDevice>> {
+        let children = QmpPool::shared()
+            .run(Self::qmp_socket(vm)?, Duration::from_secs(2), async |qmp| {
+                qmp.qom_list("/machine/peripheral").await
+            })
+            .await?;
+        Ok(children
+            .iter()
+            .filter(|(_, kind)| kind == "child<usb-host>")
+            .filter_map(|(id, _)| usb_device_from_id(id))
+            .collect())
+    }
+
+    fn qmp_socket(vm: &VmHandle) -> Result<&Path> {
+        vm.qmp_socket
+            .as_deref()
+            .ok_or_else(|| VmError::InvalidState {
+                name: vm.name.clone(),
+                state: "no QMP socket path".into(),
+            })
+    }
+
+    /// Wait until `vm`'s QEMU process exits, and tell how it ended.
+    ///
+    /// Follows the QMP events on the VM's events socket: `SHUTDOWN` gives QEMU's reason, and a
+    /// `GUEST_PANICKED` (reported when the guest has a `pvpanic` device) makes QEMU quit. VMs
+    /// started before the events socket existed are only polled for their pid, so any exit
+    /// that vmctl did not request counts as a crash.
+    pub async fn wait_for_exit(vm: &VmHandle) -> Result<VmExit> {
+        let mut reason = None;
+        let events = vm.work_dir.join(EVENTS_SOCKET);
+        match QmpClient::connect(&events, Duration::from_secs(2)).await {
+            Ok(mut qmp) => {
+                // QEMU closes the connection when it exits
+                while let Ok(event) = qmp.next_event().await {
+                    let data = &event["data"];
+                    match event["event"].as_str() {
+                        Some("SHUTDOWN") => {
+                            let shutdown = data["reason"].as_str().unwrap_or("unknown");
+                            reason.get_or_insert_with(|| shutdown.to_string());
+                        }
+                        Some("GUEST_PANICKED") => {
+                            warn!(name = %vm.name, "QEMU: guest panicked, quitting");
+                            reason.get_or_insert_with(|| "guest-panic".to_string());
+                            let _ = qmp.quit().await;
+                        }
+                        Some("STOP") => debug!(name = %vm.name, "QEMU: VM paused"),
+                        _ => {}
+                    }
+                }
+            }
+            Err(e) => {
+                debug!(name = %vm.name, error = %e, "QEMU: no events socket, polling the pid");
+            }
+        }
+
+        while Self::read_pid(&vm.work_dir)
+            .await
+            .is_some_and(Self::pid_alive)
+        {
+            tokio::time::sleep(EXIT_POLL_INTERVAL).await;
+        }
+        // `destroy` removes the work directory right after stopping the VM
+        let requested = vm.work_dir.join(STOP_MARKER).exists() || !vm.work_dir.exists();
+        let exit = exit_kind(requested, reason.as_deref());
+        info!(name = %vm.name, reason = ?reason, exit = ?exit, "QEMU: process exited");
+        Ok(exit)
+    }
+
+    /// Create a new VM in `work_dir` named `name` whose disk is internal snapshot `snapshot` of
+    /// `vm`'s overlay. `vm` must not be running and is left untouched.
+    ///
+    /// The new disk is standalone (the base image is merged in). The VM gets a new id, MAC and
+    /// SSH port, and a seed ISO with its own instance-id and hostname, so cloud-init brings up
+    /// networking for the new MAC on first boot. Its static IP and private networks are dropped:
+    /// the addresses belong to `vm`. If branching fails, `work_dir` is removed again.
+    pub async fn branch(
+        vm: &VmHandle,
+        snapshot: &str,
+        name: &str,
+        work_dir: PathBuf,
+        existing_macs: &[String],
+    ) -> Result<VmHandle> {
+        let result = Self::branch_into(vm, snapshot, name, work_dir.clone(), existing_macs).await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_dir_all(&work_dir).await;
+        }
+        result
+    }
+
+    async fn branch_into(
+        vm: &VmHandle,
+        snapshot: &str,
+        name: &str,
+        work_dir: PathBuf,
+        existing_macs: &[String],
+    ) -> Result<VmHandle> {
+        let invalid = |detail: &str| VmError::InvalidState {
+            name: vm.name.clone(),
+            state: detail.into(),
+        };
+        let source = vm
+            .overlay_path
+            .as_deref()
+            .ok_or_else(|| invalid("no overlay path"))?;
+
+        tokio::fs::create_dir_all(&work_dir).await?;
+        let overlay = work_dir.join("overlay.qcow2");
+        image::export_snapshot(source, snapshot, &overlay, "qcow2", &vm.overlay).await?;
+
+        // UEFI variables (boot entries) belong with the disk.
+        let vars = vm.work_dir.join("efivars.fd");
+        if vars.exists() {
+            tokio::fs::copy(&vars, work_dir.join("efivars.fd")).await?;
+        }
+
+        let seed_iso_path = match vm.seed_iso_path {
+            Some(_) => {
+                let iso_path = work_dir.join("seed.iso");
+                let meta_data = format!("instance-id: {name}\nlocal-hostname: {name}\n");
+                cloudinit::create_nocloud_iso_with_network(
+                    b"#cloud-config\n",
+                    meta_data.as_bytes(),
+                    None,
+                    &iso_path,
+                )?;
+                Some(iso_path)
+            }
+            None => None,
+        };
+
+        let ssh_host_port = match vm.network {
+            NetworkConfig::User => Some(Self::find_free_port()?),
+            _ => None,
+        };
+
+        let id = format!("qemu-{}", uuid::Uuid::new_v4());
+        let handle = VmHandle {
+            macvtap: vm.macvtap.as_ref().map(|_| network::macvtap_name(&id)),
+            id,
+            name: name.into(),
+            overlay_path: Some(overlay),
+            seed_iso_path,
+            pid: None,
+            qmp_socket: Some(work_dir.join("qmp.sock")),
+            console_socket: Some(work_dir.join("console.sock")),
+            vnc_addr: None,
+            ssh_host_port,
+            ssh_host_port_fixed: false,
+            rdp_host_port: vm
+                .rdp_host_port
+                .map(|_| Self::find_free_port())
+                .transpose()?,
+            mac_addr: Some(Self::generate_unique_mac(existing_macs)),
+            static_ip: None,
+            private_networks: Vec::new(),
+            annotations: HashMap::new(),
+            hostnames: Vec::new(),
+            work_dir,
+            ..vm.clone()
+        };
+        info!(
+            from = %vm.name,
+            snapshot,
+            name = %handle.name,
+            id = %handle.id,
+            "QEMU: branched VM from snapshot"
+        );
+        Ok(handle)
+    }
+
+    /// Work out the handle `prepare` returns for `spec` and the seed ISO it writes, without
+    /// touching the disk. The handle's `seed_iso_path` is left for `prepare` to fill in.
+    fn plan(&self, spec: &VmSpec) -> Result<Plan> {
+        if let Some(ref reason) = self.unavailable {
+            return Err(VmError::QemuUnavailable {
+                reason: reason.clone(),
+            });
+        }
+        check_qemu_args(&spec.qemu_args)?;
+        check_confidential(spec)?;
+        if spec.confidential.is_some() {
+            SevHost::probe()?;
+        }
+        check_numa(spec)?;
+        check_boot(
+            &spec.name,
+            &spec.boot_order,
+            spec.machine,
+            spec.kernel.is_some(),
+            spec.cdrom.is_some(),
+            &spec.network,
+        )?;
+        spec.smbios
+            .check()
+            .map_err(|detail| VmError::SmbiosInvalid {
+                vm: spec.name.clone(),
+                detail,
+            })?;
+        if spec.hugepages {
+            check_hugepages(&spec.name, spec.memory_mb, &spec.numa)?;
+        }
+        if let Some(ref cpu_pinning) = spec.cpu_pinning {
+            pinning::check(cpu_pinning, spec.vcpus).map_err(|detail| {
+                VmError::CpuPinningFailed {
+                    vm: spec.name.clone(),
+                    detail,
+                }
+            })?;
+        }
+        // Secure Boot is a UEFI feature, and SEV guests are launched by OVMF, so both bring UEFI
+        // along
+        let uefi = spec.uefi || spec.secure_boot || spec.confidential.is_some();
+        let mut firmware = uefi.then(|| self.firmware(spec.secure_boot)).transpose()?;
+        if let (Some(firmware), Some(vars)) = (firmware.as_mut(), &spec.secure_boot_vars) {
+            firmware.vars = vars.clone();
+        }
+        let work_dir = self.work_dir(&spec.name);
+
+        let mac_addr = spec
+            .mac_addr
+            .clone()
+            .unwrap_or_else(|| Self::generate_unique_mac(&spec.existing_macs));
+
+        // Private networks get a host bridge when we may create one, otherwise a multicast
+        // socket between the QEMU processes.
+        let is_root = unsafe { libc::geteuid() } == 0;
+        let mut taken_macs = spec.existing_macs.clone();
+        taken_macs.push(mac_addr.clone());
+        let mut private_networks = spec.private_networks.clone();
+        for nic in &mut private_networks {
+            let mac = nic
+                .mac
+                .get_or_insert_with(|| Self::generate_unique_mac(&taken_macs));
+            taken_macs.push(mac.clone());
+            nic.bridge = is_root.then(|| network::private_bridge_name(&nic.network));
+        }
+
+        // A cloud-init seed ISO if configured. A static IP or private network alone also needs a
+        // seed ISO to carry the network-config.
+        let seed =
+            (spec.cloud_init.is_some() || spec.static_ip.is_some() || !private_networks.is_empty())
+                .then(|| {
+                    let ci = spec.cloud_init.as_ref();
+                    let network_config = if private_networks.is_empty() {
+                        spec.static_ip
+                            .as_ref()
+                            .map(|ip| cloudinit::build_network_config(Some(&mac_addr), ip))
+                    } else {
+                        let primary = (!matches!(spec.network, NetworkConfig::None))
+                            .then_some(mac_addr.as_str());
+                        Some(cloudinit::build_private_network_config(
+                            primary,
+                            spec.static_ip.as_ref(),
+                            &private_networks,
+                        ))
+                    };
+                    Seed {
+                        user_data: ci
+                            .map(|c| c.user_data.clone())
+                            .unwrap_or_else(|| b"#cloud-config\n".to_vec()),
+                        meta_data: CloudInitConfig::meta_data_for(ci, &spec.name),
+                        network_config,
+                    }
+                });
+
+        // For user-mode networking, forward the configured host port or a free one to the guest's SSH
+        let ssh_host_port = match &spec.network {
+            NetworkConfig::User => Some(match spec.ssh_host_port {
+                Some(port) => port,
+                None => Self::find_free_port()?,
+            }),
+            _ => None,
+        };
+
+        let windows = spec.guest_os == GuestOs::Windows;
+        // Windows guests also forward RDP, which is how most of them are used
+        let rdp_host_port = match &spec.network {
+            NetworkConfig::User if windows => Some(Self::find_free_port()?),
+            _ => None,
+        };
+        let drivers_iso = spec.drivers_iso.clone().or_else(|| {
+            if !windows {
+                return None;
+            }
+            let found = VIRTIO_WIN_ISOS.iter().map(PathBuf::from).find(|p| p.exists());
+            if found.is_none() {
+                warn!(name = %spec.name, "no virtio-win drivers ISO found; set drivers-iso if the installer cannot see the disk");
+            }
+            found
+        });
+
+        let id = format!("qemu-{}", uuid::Uuid::new_v4());
+        let macvtap = matches!(spec.network, NetworkConfig::Macvtap { .. })
+            .then(|| network::macvtap_name(&id));
+        let handle = VmHandle {
+            id,
+            name: spec.name.clone(),
+            backend: BackendTag::Qemu,
+            overlay_path: Some(work_dir.join("overlay.qcow2")),
+            seed_iso_path: None,
+            pid: None,
+            qmp_socket: Some(work_dir.join("qmp.sock")),
+            console_socket: Some(work_dir.join("console.sock")),
+            work_dir,
+            vnc_addr: None,
+            vcpus: spec.vcpus,
+            memory_mb: spec.memory_mb,
+            disk_gb: spec.disk_gb,
+            overlay: spec.overlay.clone(),
+            network: spec.network.clone(),
+            macvtap,
+            ssh_host_port,
+            ssh_host_port_fixed: ssh_host_port.is_some() && spec.ssh_host_port.is_some(),
+            mac_addr: Some(mac_addr),
+            uefi,
+            static_ip: spec.static_ip.clone(),
+            private_networks,
+            serial_ports: spec.serial_ports.clone(),
+            machine: spec.machine,
+            kernel: spec.kernel.clone(),
+            cdrom: spec.cdrom.clone(),
+            boot_order: spec.boot_order.clone(),
+            boot_once: None,
+            random_seed: spec.random_seed,
+            qemu_args: spec.qemu_args.clone(),
+            restart_policy: spec.restart_policy,
+            guest_os: spec.guest_os,
+            display: spec.display,
+            audio: spec.audio,
+            smbios: spec.smbios.clone(),
+            disk_bus: spec.disk_bus(),
+            drivers_iso,
+            rdp_host_port,
+            secure_boot: spec.secure_boot,
+            confidential: spec.confidential,
+            cpu_pinning: spec.cpu_pinning.clone(),
+            numa: spec.numa.clone(),
+            hugepages: spec.hugepages,
+            usb_devices: spec.usb_devices.clone(),
+            remote_host: None,
+            labels: HashMap::new(),
+            annotations: HashMap::new(),
+            hostnames: Vec::new(),
+        };
+        Ok(Plan {
+            handle,
+            seed,
+            firmware,
+        })
+    }
+
+    /// The full command line `start` runs for `vm`. Without a QEMU to probe, it is the one for a
+    /// current QEMU.
+    async fn command_line(
+        &self,
+        vm: &VmHandle,
+        firmware: Option<&Firmware>,
+    ) -> Result<Vec<String>> {
+        let sev = vm.confidential.map(|_| SevHost::probe()).transpose()?;
+        let qemu = self.qemu_info().await.ok();
+        let mut argv = vec![self.qemu_binary.to_string_lossy().into_owned()];
+        argv.extend(Self::build_args(vm, firmware, sev.as_ref(), qemu.as_ref())?);
+        Ok(argv)
+    }
+
+    /// What the QEMU binary this backend runs supports; see [`probe`].
+    pub async fn qemu_info(&self) -> Result<QemuInfo> {
+        probe(&self.qemu_binary, &self.data_dir).await
+    }
+
+    /// Give the boot devices of `vm`, which was started with `boot_once`, the `bootindex` of its
+    /// own boot order. QEMU hands the firmware the new order on the next reset.
+    async fn restore_boot_order(vm: &VmHandle, qmp_sock: &Path) -> Result<()> {
+        QmpPool::shared()
+            .run(qmp_sock, Duration::from_secs(5), async |qmp| {
+                // Clear every index first, as two devices may not share one
+                for device in vm.start_boot_order() {
+                    let path = format!("/machine/peripheral/{}", boot_device_id(device));
+                    qmp.qom_set(&path, "bootindex", serde_json::json!(-1))
+                        .await?;
+                }
+                for (i, &device) in vm.boot_order.iter().enumerate() {
+                    let path = format!("/machine/peripheral/{}", boot_device_id(device));
+                    qmp.qom_set(&path, "bootindex", serde_json::json!(i))
+                        .await?;
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// Make sure the SSH host port is free before QEMU tries to forward it.
+    ///
+    /// A port taken since `prepare` (by another VM or any other process) would otherwise only
+    /// show up as QEMU exiting with status 1. A picked port is swapped for a new one; a
+    /// configured port fails with `HostPortInUse`, naming the process that holds it.
+    fn claim_ssh_host_port(vm: &VmHandle) -> Result<VmHandle> {
+        let mut vm = vm.clone();
+        let Some(port) = vm
+            .ssh_host_port
+            .filter(|_| matches!(vm.network, NetworkConfig::User))
+        else {
+            return Ok(vm);
+        };
+        if !port_in_use(port) {
+            return Ok(vm);
+        }
+        let holder = port_holder(port).unwrap_or_else(|| "another process".into());
+        if vm.ssh_host_port_fixed {
+            return Err(VmError::HostPortInUse {
+                vm: vm.name,
+                port,
+                holder,
+            });
+        }
+        let new_port = Self::find_free_port()?;
+        warn!(name = %vm.name, port, holder = %holder, new_port, "SSH host port is in use, forwarding another");
+        vm.ssh_host_port = Some(new_port);
+        Ok(vm)
+    }
+
+    /// Swap a forwarded RDP port taken since `prepare` for a free one.
+    fn claim_rdp_host_port(vm: &VmHandle) -> Result<VmHandle> {
+        let mut vm = vm.clone();
+        if let Some(port) = vm.rdp_host_port.filter(|&port| port_in_use(port)) {
+            let new_port = Self::find_free_port()?;
+            warn!(name = %vm.name, port, new_port, "RDP host port is in use, forwarding another");
+            vm.rdp_host_port = Some(new_port);
+        }
+        Ok(vm)
+    }
+}
+
+/// Whether binding `port` on all IPv4 addresses, as QEMU's `hostfwd` does, would fail.
+fn port_in_use(port: u16) -> bool {
+    std::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+        .is_err_and(|e| e.kind() == std::io::ErrorKind::AddrInUse)
+}
+
+/// Describe the process listening on TCP `port`, e.g. `qemu-system-x86 (pid 4242)`.
+///
+/// Matches the socket inodes in `/proc/net/tcp{,6}` against every process's open files. Without
+/// root, other users' processes can't be inspected and this returns `None`.
+fn port_holder(port: u16) -> Option<String> {
+    let inodes: Vec<u64> = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|table| listening_inodes(&table, port))
+        .collect();
+    if inodes.is_empty() {
+        return None;
+    }
+    let sockets: Vec<String> = inodes.iter().map(|i| format!("socket:[{i}]")).collect();
+    for proc_entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Ok(pid) = proc_entry.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        let Ok(fds) = std::fs::read_dir(proc_entry.path().join("fd")) else {
+            continue;
+        };
+        let holds = fds.flatten().any(|fd| {
+            std::fs::read_link(fd.path())
+                .is_ok_and(|target| sockets.iter().any(|s| target.as_os_str() == s.as_str()))
+        });
+        if holds {
+            let comm = std::fs::read_to_string(proc_entry.path().join("comm")).unwrap_or_default();
+            return Some(format!("{} (pid {pid})", comm.trim()));
+        }
+    }
+    None
+}
+
+/// Inodes of the listening sockets on `port` in a `/proc/net/tcp` style table.
+fn listening_inodes(table: &str, port: u16) -> Vec<u64> {
+    const LISTEN: &str = "0A";
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let local_port = fields.get(1)?.rsplit(':').next()?;
+            (u16::from_str_radix(local_port, 16).ok()? == port && *fields.get(3)? == LISTEN)
+                .then(|| fields.get(9)?.parse().ok())?
+        })
+        .collect()
+}
+
+/// Generate a locally-administered unicast MAC address using random bytes.
+fn rand_mac() -> [u8; 6] {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let s = RandomState::new();
+    let mut h = s.build_hasher();
+    h.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64,
+    );
+    let v = h.finish();
+
+    let mut mac = [0u8; 6];
+    mac[0] = 0x52; // locally administered, unicast
+    mac[1] = 0x54;
+    mac[2] = (v >> 24) as u8;
+    mac[3] = (v >> 16) as u8;
+    mac[4] = (v >> 8) as u8;
+    mac[5] = v as u8;
+    mac
+}
+
+#[async_trait]
+impl Hypervisor for QemuBackend {
+    async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
+        let Plan {
+            mut handle,
+            seed,
+            firmware,
+        } = self.plan(spec)?;
+        let work_dir = handle.work_dir.clone();
+        tokio::fs::create_dir_all(&work_dir).await?;
+
+        // Create QCOW2 overlay
+        let overlay = work_dir.join("overlay.qcow2");
+        let created = image::create_overlay(
+            &spec.image_path,
+            &overlay,
+            spec.disk_gb,
+            &spec.overlay,
+            &self.cancel,
+        );
+        match created.await {
+            Err(VmError::Cancelled { .. }) => {
+                return Err(Self::abandon_prepare(&work_dir, &spec.name).await);
+            }
+            result => result?,
+        }
+
+        if let Some(seed) = seed {
+            let iso_path = work_dir.join("seed.iso");
+            cloudinit::create_nocloud_iso_with_network(
+                &seed.user_data,
+                &seed.meta_data,
+                seed.network_config.as_deref(),
+                &iso_path,
+            )?;
+            handle.seed_iso_path = Some(iso_path);
+        }
+
+        // Copy OVMF_VARS to the VM's work directory when UEFI is requested
+        if let Some(ref firmware) = firmware {
+            let vars_dest = work_dir.join("efivars.fd");
+            tokio::fs::copy(&firmware.vars, &vars_dest)
+                .await
+                .map_err(|e| VmError::InvalidState {
+                    name: spec.name.clone(),
+                    state: format!("failed to copy OVMF_VARS: {e}"),
+                })?;
+        }
+
+        info!(
+            name = %spec.name,
+            id = %handle.id,
+            vcpus = handle.vcpus,
+            memory_mb = handle.memory_mb,
+            overlay = ?handle.overlay_path,
+            seed = ?handle.seed_iso_path,
+            "QEMU: prepared"
+        );
+
+        if self.cancel.is_cancelled() {
+            return Err(Self::abandon_prepare(&handle.work_dir, &spec.name).await);
+        }
+        Ok(handle)
+    }
+
+    async fn start(&self, vm: &VmHandle) -> Result<VmHandle> {
+        let vm = &Self::claim_rdp_host_port(&Self::claim_ssh_host_port(vm)?)?;
+        let firmware = vm.uefi.then(|| self.firmware(vm.secure_boot)).transpose()?;
+        // The host may have lost SEV since the VM was created, e.g. to a firmware update
+        let sev = vm.confidential.map(|_| SevHost::probe()).transpose()?;
+        // Other VMs may have taken the free huge pages since this one was created
+        if vm.hugepages {
+            check_hugepages(&vm.name, vm.memory_mb, &vm.numa)?;
+        }
+        // An old QEMU fails on what it doesn't know with a message that doesn't say what's missing
+        let qemu = self.qemu_info().await?;
+        let args = Self::build_args(vm, firmware.as_ref(), sev.as_ref(), Some(&qemu))?;
+        self.check_audio(vm).await?;
+        // QEMU only warns about a USB device it can't open, and starts without it
+        for device in &vm.usb_devices {
+            usb::check_access(&vm.name, device)?;
+        }
+
+        let qmp_sock = vm
+            .qmp_socket
+            .as_ref()
+            .ok_or_else(|| VmError::InvalidState {
+                name: vm.name.clone(),
+                state: "no QMP socket path".into(),
+            })?;
+        let console_sock = vm
+            .console_socket
+            .as_ref()
+            .ok_or_else(|| VmError::InvalidState {
+                name: vm.name.clone(),
+                state: "no console socket path".into(),
+            })?;
+
+        // Clean up stale socket files from a previous run
+        let serial_socks = vm.serial_ports.iter().filter_map(|p| match p.backend {
+            SerialBackend::Socket(ref path) => Some(path),
+            _ => None,
+        });
+        let events_sock = vm.work_dir.join(EVENTS_SOCKET);
+        let agent_sock = vm.work_dir.join(qga::AGENT_SOCKET);
+        for sock in [qmp_sock, &events_sock, &agent_sock, console_sock]
+            .into_iter()
+            .chain(serial_socks)
+        {
+            if sock.exists() {
+                let _ = tokio::fs::remove_file(sock).await;
+            }
+        }
+        let _ = tokio::fs::remove_file(vm.work_dir.join(STOP_MARKER)).await;
+
+        // Bridges of private networks don't survive a host reboot, so make sure they exist
+        for nic in vm.private_networks.iter().filter(|n| n.bridge.is_some()) {
+            let bridge = network::ensure_private_bridge(&nic.network).await?;
+            let script = private_ifup_script_path(vm, nic);
+            tokio::fs::write(&script, network::private_ifup_script(&bridge)).await?;
+            tokio::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).await?;
+        }
+        // Neither does a macvtap device. QEMU gets it as an open file
+        let tap = match (&vm.network, &vm.macvtap) {
+            (NetworkConfig::Macvtap { parent }, Some(device)) => {
+                let mac = vm.mac_addr.as_deref().unwrap_or(DEFAULT_MAC);
+                Some(network::open_macvtap(device, parent, mac).await?)
+            }
+            _ => None,
+        };
+
+        info!(
+            name = %vm.name,
+            vcpus = vm.vcpus,
+            memory_mb = vm.memory_mb,
+            binary = %self.qemu_binary.display(),
+            extra_args = ?vm.qemu_args,
+            "QEMU: starting"
+        );
+        debug!(args = ?args, "QEMU command line");
+
+        if self.cancel.is_cancelled() {
+            return Err(Self::cancelled("start", &vm.name));
+        }
+        let (pid, exit_status) = match ProcessMode::for_vm(vm) {
+            ProcessMode::Daemonized => (
+                self.spawn_daemonized(&vm.work_dir, &args, tap.as_ref())
+                    .await?,
+                None,
+            ),
+            ProcessMode::Supervised => {
+                let (pid, exit_status) = self.spawn_supervised(vm, &args, tap.as_ref()).await?;
+                (Some(pid), Some(exit_status))
+            }
+        };
+        // A supervised QEMU reports errors such as a missing desktop session by exiting
+        let exited = async {
+            match exit_status {
+                Some(exit_status) => exit_status.await.ok(),
+                None => std::future::pending().await,
+            }
+        };
+
+        // Wait for QMP socket and verify + query VNC
+        let ready = QmpPool::shared().run(
+            qmp_sock,
+            Duration::from_secs(10),
+            async |qmp: &mut QmpClient| {
+                let qmp_status = qmp.query_status().await?;
+                let vnc_addr = qmp.query_vnc().await.unwrap_or(None);
+                let vcpu_threads = match vm.cpu_pinning {
+                    Some(_) => qmp.query_vcpu_threads().await?,
+                    None => Vec::new(),
+                };
+                Ok((qmp_status, vnc_addr, vcpu_threads))
+            },
+        );
+        let (qmp_status, vnc_addr, vcpu_threads) = tokio::select! {
+            ready = ready => ready?,
+            status = exited => return Err(Self::exited_early(&vm.work_dir, status).await),
+            _ = self.cancel.cancelled() => {
+                Self::kill_spawned(vm).await;
+                return Err(Self::cancelled("start", &vm.name));
+            }
+        };
+
+        // Affinity belongs to the threads, so it is set again on every start
+        if let Some(ref cpu_pinning) = vm.cpu_pinning {
+            let applied = match pid {
+                Some(pid) => {
+                    pinning::apply(pid, &vcpu_threads, cpu_pinning).map_err(|e| e.to_string())
+                }
+                None => Err("QEMU wrote no pidfile".to_string()),
+            };
+            if let Err(detail) = applied {
+                Self::kill_spawned(vm).await;
+                return Err(VmError::CpuPinningFailed {
+                    vm: vm.name.clone(),
+                    detail,
+                });
+            }
+            info!(name = %vm.name, pinning = %cpu_pinning, "QEMU: pinned threads");
+        }
+
+        // The one-time boot device only counts for this boot; a reboot of the guest goes by
+        // the boot order again
+        if vm.boot_once.is_some() {
+            if let Err(e) = Self::restore_boot_order(vm, qmp_sock).await {
+                warn!(name = %vm.name, error = %e, "QEMU: cannot restore the boot order");
+            }
+        }
+
+        info!(
+            name = %vm.name,
+            status = %qmp_status,
+            pid = ?pid,
+            vnc = ?vnc_addr,
+            display = %vm.display,
+            "QEMU: started"
+        );
+
+        let mut updated = vm.clone();
+        updated.pid = pid;
+        updated.vnc_addr = vnc_addr;
+
+        Ok(updated)
+    }
+
+    async fn stop(&self, vm: &VmHandle, timeout: Duration) -> Result<VmHandle> {
+        // Tell `wait_for_exit` that this exit is expected
+        if vm.work_dir.exists() {
+            tokio::fs::write(vm.work_dir.join(STOP_MARKER), b"").await?;
+        }
+
+        // Try ACPI shutdown via QMP first
+        if let Some(ref qmp_sock) = vm.qmp_socket {
+            if qmp_sock.exists() {
+                let pool = QmpPool::shared();
+                let _ = pool
+                    .run(qmp_sock, Duration::from_secs(2), async |qmp| {
+                        qmp.system_powerdown().await
+                    })
+                    .await;
+                pool.forget(qmp_sock).await;
+            }
+        }
+
+        // Wait for process to exit
+        let start = tokio::time::Instant::now();
+        loop {
+            if let Some(pid) = Self::read_pid(&vm.work_dir).await {
+                if !Self::pid_alive(pid) {
+                    info!(name = %vm.name, "QEMU: process exited after ACPI shutdown");
+                    let mut updated = vm.clone();
+                    updated.pid = None;
+                    updated.vnc_addr = None;
+                    return Ok(updated);
+                }
+            } else {
+                // No PID file, process likely already gone
+                let mut updated = vm.clone();
+                updated.pid = None;
+                updated.vnc_addr = None;
+                return Ok(updated);
+            }
+
+            if start.elapsed() >= timeout {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+
+        // SIGTERM fallback
+        if let Some(pid) = Self::read_pid(&vm.work_dir).await {
+            if Self::pid_alive(pid) {
+                warn!(name = %vm.name, pid, "QEMU: ACPI shutdown timed out, sending SIGTERM");
+                unsafe {
+                    libc::kill(pid as i32, libc::SIGTERM);
+                }
+                tokio::time::sleep(Duration::from_secs(3)).await;
+            }
+
+            // SIGKILL if still alive
+            if Self::pid_alive(pid) {
+                warn!(name = %vm.name, pid, "QEMU: SIGTERM failed, sending SIGKILL");
+                unsafe {
+                    libc::kill(pid as i32, libc::SIGKILL);
+                }
+            }
+        }
+
+        let mut updated = vm.clone();
+        updated.pid = None;
+        updated.vnc_addr = None;
+        Ok(updated)
+    }
+
+    async fn suspend(&self, vm: &VmHandle) -> Result<VmHandle> {
+        if let Some(ref qmp_sock) = vm.qmp_socket {
+            QmpPool::shared()
+                .run(qmp_sock, Duration::from_secs(5), async |qmp| {
+                    qmp.stop().await
+                })
+                .await?;
+        }
+        Ok(vm.clone())
+    }
+
+    async fn resume(&self, vm: &VmHandle) -> Result<VmHandle> {
+        if let Some(ref qmp_sock) = vm.qmp_socket {
+            QmpPool::shared()
+                .run(qmp_sock, Duration::from_secs(5), async |qmp| {
+                    qmp.cont().await
+                })
+                .await?;
+            Self::resync_clock(vm).await;
+        }
+        Ok(vm.clone())
+    }
+
+    async fn destroy(&self, vm: VmHandle) -> Result<()> {
+        // Stop if running
+        self.stop(&vm, Duration::from_secs(5)).await?;
+
+        // QMP quit to ensure cleanup
+        if let Some(ref qmp_sock) = vm.qmp_socket {
+            if qmp_sock.exists() {
+                let pool = QmpPool::shared();
+                let _ = pool
+                    .run(qmp_sock, Duration::from_secs(2), async |qmp| {
+                        qmp.quit().await
+                    })
+                    .await;
+                pool.forget(qmp_sock).await;
+            }
+        }
+
+        if let Some(ref device) = vm.macvtap {
+            if let Err(e) = network::delete_macvtap(device).await {
+                warn!(name = %vm.name, %device, error = %e, "failed to remove macvtap device");
+            }
+        }
+
+        // The last member of a private network takes its bridge with it
+        for nic in vm.private_networks.iter().filter(|n| n.bridge.is_some()) {
+            if let Err(e) = network::remove_private_bridge_if_unused(&nic.network).await {
+                warn!(
+                    name = %vm.name,
+                    network = %nic.network,
+                    error = %e,
+                    "failed to remove private network bridge"
+                );
+            }
+        }
+
+        // Remove work directory
+        let _ = tokio::fs::remove_dir_all(&vm.work_dir).await;
+        info!(name = %vm.name, "QEMU: destroyed");
+        Ok(())
+    }
+
+    async fn state(&self, vm: &VmHandle) -> Result<VmState> {
+        // Check if process is alive
+        if let Some(pid) = Self::read_pid(&vm.work_dir).await {
+            if Self::pid_alive(pid) {
+                // Try QMP for detailed state
+                if let Some(ref qmp_sock) = vm.qmp_socket {
+                    let status = QmpPool::shared()
+                        .run(qmp_sock, Duration::from_secs(2), async |qmp| {
+                            qmp.query_status().await
+                        })
+                        .await;
+                    if let Ok(status) = status {
+                        return Ok(match status.as_str() {
+                            "running" => VmState::Running,
+                            "paused" | "suspended" => VmState::Suspended,
+                            _ => VmState::Running,
+                        });
+                    }
+                }
+                return Ok(VmState::Running);
+            }
+        }
+
+        // Check if work dir exists (prepared but not running)
+        if vm.work_dir.exists() {
+            Ok(VmState::Stopped)
+        } else {
+            Ok(VmState::Destroyed)
+        }
+    }
+
+    async fn guest_ips(&self, vm: &VmHandle, timeout: Duration) -> Result<VmIpInfo> {
+        // For user-mode networking, the guest is reachable via localhost
+        // (SSH uses the forwarded host port). QEMU's hostfwd only listens on IPv4.
+        if matches!(vm.network, NetworkConfig::User) {
+            return Ok(VmIpInfo::from(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        }
+
+        // A statically configured guest is reachable at its configured address
+        if let Some(ip) = vm
+            .static_ip
+            .as_ref()
+            .and_then(|s| s.ip().parse::<IpAddr>().ok())
+        {
+            return Ok(VmIpInfo::from(ip));
+        }
+
+        // Otherwise look for the guest's MAC in the neighbour table and DHCP leases, waiting for
+        // it to appear while the guest boots.
+        let mac = vm
+            .mac_addr
+            .as_deref()
+            .unwrap_or(DEFAULT_MAC)
+            .to_ascii_lowercase();
+        let bridge = match &vm.network {
+            NetworkConfig::Tap { bridge } => Some(bridge.as_str()),
+            // The guest is on the parent NIC's network, whatever its interface on this host
+            NetworkConfig::Macvtap { .. } => None,
+            _ => self.default_bridge.as_deref(),
+        };
+        let networks = NetworkManager::new(&self.networks_dir);
+        let managed_leases = match bridge {
+            Some(br) => networks
+                .find_by_bridge(br)
+                .await
+                .map(|n| networks.lease_file(&n.name)),
+            None => None,
+        };
+        let started = tokio::time::Instant::now();
+        let deadline = started + timeout;
+        let mut v6_seen = None;
+        loop {
+            let info = self
+                .find_ips_by_mac(&mac, bridge, managed_leases.as_deref())
+                .await;
+            let now = tokio::time::Instant::now();
+            if !info.v6.is_empty() {
+                v6_seen.get_or_insert(now);
+            }
+            let grace_over = v6_seen.is_some_and(|t| now >= t + V4_GRACE);
+            if !info.v4.is_empty() || (!info.v6.is_empty() && (grace_over || now >= deadline)) {
+                debug!(name = %vm.name, mac = %mac, ips = ?info, "guest IP discovered");
+                return Ok(info);
+            }
+            if now >= deadline {
+                return Err(VmError::IpDiscoveryTimeout {
+                    name: vm.name.clone(),
+                    mac,
+                });
+            }
+            debug!(
+                name = %vm.name,
+                mac = %mac,
+                elapsed_secs = (now - started).as_secs(),
+                timeout_secs = timeout.as_secs(),
+                "waiting for guest IP"
+            );
+            tokio::time::sleep(IP_POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    fn console_endpoint(&self, vm: &VmHandle) -> Result<ConsoleEndpoint> {
+        match vm.console_socket {
+            Some(ref path) => Ok(ConsoleEndpoint::UnixSocket(path.clone())),
+            None => Ok(ConsoleEndpoint::None),
+        }
+    }
+
+    async fn dry_run_create(&self, spec: &VmSpec) -> Result<Option<DryRun>> {
+        let Plan {
+            mut handle,
+            seed,
+            firmware,
+        } = self.plan(spec)?;
+        let overlay = handle.work_dir.join("overlay.qcow2");
+        let mut qemu_img = vec!["qemu-img".to_string()];
+        qemu_img.extend(
+            image::overlay_args(&spec.image_path, &overlay, spec.disk_gb, &spec.overlay).await?,
+        );
+        let mut dry_run = DryRun {
+            commands: vec![qemu_img],
+            files: Vec::new(),
+            mac_addr: handle.mac_addr.clone(),
+        };
+        if let Some(seed) = seed {
+            let iso_path = handle.work_dir.join("seed.iso");
+            let iso = iso_path.display();
+            let text = |data: &[u8]| String::from_utf8_lossy(data).into_owned();
+            dry_run.files.push(DryRunFile {
+                location: format!("{iso}:user-data"),
+                contents: cloudinit::redact_private_keys(&text(&seed.user_data)),
+            });
+            dry_run.files.push(DryRunFile {
+                location: format!("{iso}:meta-data"),
+                contents: text(&seed.meta_data),
+            });
+            if let Some(ref network_config) = seed.network_config {
+                dry_run.files.push(DryRunFile {
+                    location: format!("{iso}:network-config"),
+                    contents: text(network_config),
+                });
+            }
+            handle.seed_iso_path = Some(iso_path);
+        }
+        if let Some(ref firmware) = firmware {
+            dry_run.commands.push(vec![
+                "cp".into(),
+                firmware.vars.to_string_lossy().into_owned(),
+                handle
+                    .work_dir
+                    .join("efivars.fd")
+                    .to_string_lossy()
+                    .into_owned(),
+            ]);
+        }
+        dry_run
+            .commands
+            .push(self.command_line(&handle, firmware.as_ref()).await?);
+        Ok(Some(dry_run))
+    }
+
+    async fn dry_run_start(&self, vm: &VmHandle) -> Result<Option<DryRun>> {
+        let vm = Self::claim_ssh_host_port(vm)?;
+        let firmware = vm.uefi.then(|| self.firmware(vm.secure_boot)).transpose()?;
+        Ok(Some(DryRun {
+            commands: vec![self.command_line(&vm, firmware.as_ref()).await?],
+            files: Vec::new(),
+            mac_addr: vm.mac_addr.clone(),
+        }))
+    }
+
+    /// The work directories in the data directory that hold a `qemu.pid`. VMs still running are
+    /// described from their QEMU command line, the others only from the files left behind.
+    async fn list_vms(&self) -> Result<Vec<VmHandle>> {
+        let mut entries = match tokio::fs::read_dir(&self.data_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut vms = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let work_dir = entry.path();
+            if !work_dir.join("qemu.pid").is_file() {
+                continue;
+            }
+            let vm = Self::inspect(work_dir).await;
+            debug!(name = %vm.name, pid = ?vm.pid, "QEMU: found VM in the data directory");
+            vms.push(vm);
+        }
+        vms.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(vms)
+    }
+}
+
+/// Addresses of `mac` in `ip neigh show` output
+/// (`192.168.122.45 dev virbr0 lladdr 52:54:00:ab:cd:ef REACHABLE`), optionally only on `bridge`.
+/// Link-local IPv6 neighbours are skipped.
+fn neigh_ips(output: &str, mac: &str, bridge: Option<&str>) -> Vec<IpAddr> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let field = |key: &str| {
+                fields
+                    .iter()
+                    .position(|f| *f == key)
+                    .and_then(|i| fields.get(i + 1))
+                    .copied()
+            };
+            let usable = !line.contains("FAILED") && !line.contains("INCOMPLETE");
+            let on_bridge = bridge.is_none_or(|br| field("dev") == Some(br));
+            let matches = field("lladdr").is_some_and(|l| l.eq_ignore_ascii_case(mac));
+            if !(usable && on_bridge && matches) {
+                return None;
+            }
+            match fields[0].parse().ok()? {
+                IpAddr::V6(v6) if !is_global_v6(&v6) => None,
+                ip => Some(ip),
+            }
+        })
+        .collect()
+}
+
+/// Where the ifup script attaching `nic`'s tap device to its bridge lives.
+fn private_ifup_script_path(vm: &VmHandle, nic: &PrivateNic) -> PathBuf {
+    vm.work_dir.join(format!("ifup-{}.sh", nic.network))
+}
+
+/// QEMU `-serial` argument for an additional serial port.
+fn serial_spec(backend: &SerialBackend) -> String {
+    match backend {
+        SerialBackend::Socket(path) => format!(
+            "unix:{},server=on,wait=off",
+            qemu_opt_escape(&path.to_string_lossy())
+        ),
+        SerialBackend::File(path) => format!("file:{}", qemu_opt_escape(&path.to_string_lossy())),
+        SerialBackend::Stdio => "stdio".into(),
+        SerialBackend::Null => "null".into(),
+    }
+}
+
+/// Where distributions install OVMF, searched in order after the configured firmware directory.
+pub const FIRMWARE_DIRS: &[&str] = &[
+    "/usr/share/OVMF",
+    "/usr/share/qemu",
+    "/usr/share/edk2/x64",
+    "/usr/local/share/qemu",
+    "/usr/share/ovmf",
+    "/usr/share/edk2/ovmf",
+    "/usr/share/edk2-ovmf/x64",
+];
+
+/// OVMF code images and the variable-store templates built with them, in order of preference.
+/// Code and vars must come from the same build, so they are only used in these pairs.
+const FIRMWARE_FILES: &[(&str, &str)] = &[
+    ("OVMF_CODE.fd", "OVMF_VARS.fd"),
+    ("OVMF_CODE_4M.fd", "OVMF_VARS_4M.fd"),
+    ("OVMF_CODE.4m.fd", "OVMF_VARS.4m.fd"),
+    ("edk2-x86_64-code.fd", "edk2-i386-vars.fd"),
+];
+
+/// Like [`FIRMWARE_FILES`], for Secure Boot: code images built with it and the templates that
+/// have the Microsoft and distribution keys enrolled. Templates without keys would boot with
+/// Secure Boot off, so they are not listed.
+const SECURE_BOOT_FIRMWARE_FILES: &[(&str, &str)] = &[
+    // Fedora, RHEL
+    ("OVMF_CODE.secboot.fd", "OVMF_VARS.secboot.fd"),
+    // Debian, Ubuntu
+    ("OVMF_CODE_4M.secboot.fd", "OVMF_VARS_4M.ms.fd"),
+    ("OVMF_CODE.secboot.fd", "OVMF_VARS.ms.fd"),
+];
+
+/// Classify a QEMU exit from whether vmctl asked for it and the `SHUTDOWN` reason, if any.
+fn exit_kind(requested: bool, reason: Option<&str>) -> VmExit {
+    match reason {
+        _ if requested => VmExit::Requested,
+        // The user closed the window of a GTK or SDL display
+        Some("host-ui") => VmExit::Requested,
+        Some("guest-shutdown") => VmExit::GuestShutdown,
+        Some(reason) => VmExit::Crashed {
+            reason: reason.to_string(),
+        },
+        None => VmExit::Crashed {
+            reason: "exited without a shutdown event".into(),
+        },
+    }
+}
+
+/// Rebuild the handle of VM `name` in `work_dir` from the arguments `build_args` gave its QEMU:
+/// vCPUs, memory, NUMA nodes, huge pages, machine type, display, UEFI, the entropy device and the
+/// primary NIC. Everything else takes the same defaults as a deserialized handle, as does all of it when
+/// `args` is empty.
+fn handle_from_command_line(name: &str, work_dir: PathBuf, args: &[String]) -> VmHandle {
+    let mut vm = VmHandle {
+        id: format!("qemu-{}", uuid::Uuid::new_v4()),
+        name: name.into(),
+        backend: BackendTag::Qemu,
+        overlay_path: Some(work_dir.join("overlay.qcow2")).filter(|p| p.exists()),
+        seed_iso_path: Some(work_dir.join("seed.iso")).filter(|p| p.exists()),
+        pid: None,
+        qmp_socket: Some(work_dir.join("qmp.sock")),
+        console_socket: Some(work_dir.join("console.sock")),
+        work_dir,
+        vnc_addr: None,
+        vcpus: 1,
+        memory_mb: 1024,
+        disk_gb: None,
+        overlay: Default::default(),
+        network: NetworkConfig::None,
+        macvtap: None,
+        ssh_host_port: None,
+        ssh_host_port_fixed: false,
+        mac_addr: None,
+        uefi: false,
+        static_ip: None,
+        private_networks: Vec::new(),
+        serial_ports: Vec::new(),
+        machine: Default::default(),
+        kernel: None,
+        cdrom: None,
+        boot_order: Vec::new(),
+        boot_once: None,
+        random_seed: Default::default(),
+        qemu_args: Vec::new(),
+        restart_policy: Default::default(),
+        guest_os: Default::default(),
+        display: Default::default(),
+        audio: Default::default(),
+        smbios: Default::default(),
+        disk_bus: Default::default(),
+        drivers_iso: None,
+        rdp_host_port: None,
+        secure_boot: false,
+        confidential: None,
+        cpu_pinning: None,
+        numa: Vec::new(),
+        hugepages: false,
+        usb_devices: Vec::new(),
+        remote_host: None,
+        labels: HashMap::new(),
+        annotations: HashMap::new(),
+        hostnames: Vec::new(),
+    };
+    // `key=value` options of a comma-separated QEMU argument
+    let option = |arg: &str, key: &str| {
+        arg.split(',')
+            .find_map(|opt| opt.strip_prefix(key)?.strip_prefix('='))
+            .map(str::to_string)
+    };
+    if !args.is_empty() {
+        vm.random_seed = RngConfig::None;
+    }
+    // NUMA nodes: memory from their backends, vCPUs from the sockets mapped to them
+    let mut numa_memory: std::collections::BTreeMap<usize, u64> = Default::default();
+    let mut numa_sockets: Vec<(usize, u16)> = Vec::new();
+    let mut cores: u16 = 1;
+    for pair in args.windows(2) {
+        let (flag, value) = (pair[0].as_str(), pair[1].as_str());
+        match flag {
+            "-smp" => {
+                if let Ok(n) = value.split(',').next().unwrap_or_default().parse() {
+                    vm.vcpus = n;
+                }
+                if let Some(n) = option(value, "cores").and_then(|n| n.parse().ok()) {
+                    cores = n;
+                }
+            }
+            "-object" if value.starts_with("memory-backend-") => {
+                vm.hugepages |= option(value, "mem-path").as_deref() == Some(HUGEPAGES_PATH);
+                let node = option(value, "id").and_then(|id| id.strip_prefix("numa")?.parse().ok());
+                let size = option(value, "size").and_then(|s| s.strip_suffix('M')?.parse().ok());
+                if let (Some(node), Some(size)) = (node, size) {
+                    numa_memory.insert(node, size);
+                }
+            }
+            "-numa" if value.starts_with("cpu,") => {
+                let node = option(value, "node-id").and_then(|n| n.parse().ok());
+                let socket = option(value, "socket-id").and_then(|n| n.parse().ok());
+                if let (Some(node), Some(socket)) = (node, socket) {
+                    numa_sockets.push((node, socket));
+                }
+            }
+            "-m" => {
+                let size = value.split(',').next().unwrap_or_default();
+                let (digits, gib) = match size.strip_suffix(['G', 'g']) {
+                    Some(digits) => (digits, true),
+                    None => (size.trim_end_matches(['M', 'm']), false),
+                };
+                if let Ok(n) = digits.parse::<u64>() {
+                    vm.memory_mb = if gib { n * 1024 } else { n };
+                }
+            }
+            "-machine" if value.starts_with("microvm") => vm.machine = MachineType::Microvm,
+            "-display" => {
+                if let Ok(display) = value.split(',').next().unwrap_or_default().parse() {
+                    vm.display = display;
+                }
+            }
+            "-audiodev" => {
+                if let Ok(audio) = value.split(',').next().unwrap_or_default().parse() {
+                    vm.audio = audio;
+                }
+            }
+            "-drive" if option(value, "if").as_deref() == Some("pflash") => vm.uefi = true,
+            "-global" if value == "driver=cfi.pflash01,property=secure,value=on" => {
+                vm.secure_boot = true;
+            }
+            "-object" if value.starts_with("sev-guest,") => {
+                vm.confidential = Some(ConfidentialMode::Sev);
+            }
+            "-netdev" if option(value, "id").as_deref() == Some("net0") => {
+                if value.starts_with("tap,") {
+                    if let Some(bridge) = option(value, "br") {
+                        vm.network = NetworkConfig::Tap { bridge };
+                    }
+                } else if value.starts_with("user,") {
+                    vm.network = NetworkConfig::User;
+                    vm.ssh_host_port = option(value, "hostfwd")
+                        .and_then(|fwd| fwd.strip_prefix("tcp::")?.split('-').next()?.parse().ok());
+                    vm.rdp_host_port = value
+                        .split(',')
+                        .filter_map(|opt| opt.strip_prefix("hostfwd=tcp:127.0.0.1:"))
+                        .find_map(|fwd| fwd.strip_suffix("-:3389")?.parse().ok());
+                    if vm.rdp_host_port.is_some() {
+                        vm.guest_os = GuestOs::Windows;
+                    }
+                }
+            }
+            "-device" if option(value, "drive").as_deref() == Some(ROOT_DRIVE) => {
+                let driver = value.split(',').next().unwrap_or_default();
+                match driver {
+                    "ide-hd" => vm.disk_bus = DiskBus::Sata,
+                    "nvme" => vm.disk_bus = DiskBus::Nvme,
+                    "scsi-hd" => vm.disk_bus = DiskBus::Scsi,
+                    _ => {}
+                }
+            }
+            "-drive" if option(value, "id").as_deref() == Some("drivers0") => {
+                vm.drivers_iso = option(value, "file").map(PathBuf::from);
+            }
+            "-device" if value.starts_with("usb-host,") => {
+                if let Some(usb) = option(value, "id").and_then(|id| usb_device_from_id(&id)) {
+                    vm.usb_devices.push(usb);
+                }
+            }
+            "-device" if option(value, "netdev").as_deref() == Some("net0") => {
+                vm.mac_addr = option(value, "mac");
+            }
+            "-device" if option(value, "rng").as_deref() == Some("rng0") => {
+                vm.random_seed = RngConfig::VirtioRng {
+                    max_bytes: option(value, "max-bytes").and_then(|n| n.parse().ok()),
+                    period_ms: option(value, "period").and_then(|n| n.parse().ok()),
+                };
+            }
+            _ => {}
+        }
+    }
+    vm.numa = numa_memory
+        .into_iter()
+        .map(|(node, memory_mb)| {
+            let mut cpus: Vec<u16> = numa_sockets
+                .iter()
+                .filter(|&&(n, _)| n == node)
+                .flat_map(|&(_, socket)| socket * cores..(socket + 1) * cores)
+                .collect();
+            cpus.sort_unstable();
+            NumaNode { cpus, memory_mb }
+        })
+        .collect();
+    // Boot order from the `bootindex` of the devices
+    let mut boot: Vec<(u32, BootDevice)> = args
+        .windows(2)
+        .filter(|pair| pair[0] == "-device")
+        .filter_map(|pair| {
+            let index = option(pair[1].as_str(), "bootindex")?.parse().ok()?;
+            let device = match (
+                option(pair[1].as_str(), "drive"),
+                option(pair[1].as_str(), "netdev"),
+            ) {
+                (Some(drive), _) if drive == ROOT_DRIVE => BootDevice::Disk,
+                (Some(drive), _) if drive == "cdrom0" => BootDevice::Cdrom,
+                (_, Some(netdev)) if netdev == "net0" => BootDevice::Network,
+                _ => return None,
+            };
+            Some((index, device))
+        })
+        .collect();
+    boot.sort_unstable_by_key(|&(index, _)| index);
+    vm.boot_order = boot.into_iter().map(|(_, device)| device).collect();
+    vm
+}
+
+/// QEMU device id of the `usb-host` device passing `usb` through: `usb-1050-0407` or
+/// `usb-3.7`, so that it can be detached by the same name.
+fn usb_device_id(usb: &UsbDevice) -> String {
+    match usb {
+        UsbDevice::Id { vendor, product } => format!("usb-{vendor:04x}-{product:04x}"),
+        UsbDevice::Address { bus, device } => format!("usb-{bus}.{device}"),
+    }
+}
+
+/// The device a [`usb_device_id`] refers to.
+fn usb_device_from_id(id: &str) -> Option<UsbDevice> {
+    id.strip_prefix("usb-")?.replacen('-', ":", 1).parse().ok()
+}
+
+/// `usb-host` properties that select `usb` on the host, for `device_add`.
+fn usb_host_properties(usb: &UsbDevice) -> [(&'static str, u16); 2] {
+    match *usb {
+        UsbDevice::Id { vendor, product } => [("vendorid", vendor), ("productid", product)],
+        UsbDevice::Address { bus, device } => {
+            [("hostbus", bus.into()), ("hostaddr", device.into())]
+        }
+    }
+}
+
+/// The `-smp` value for `vm`. With NUMA nodes the topology follows them, so that no socket
+/// spans two nodes: one socket per node when the nodes hold equal runs of consecutive vCPUs in
+/// order, otherwise one socket per vCPU.
+fn smp_option(vm: &VmHandle) -> String {
+    match cores_per_socket(&vm.numa, vm.vcpus) {
+        Some(cores) => format!(
+            "{},sockets={},cores={cores},threads=1",
+            vm.vcpus,
+            vm.vcpus / cores
+        ),
+        None => vm.vcpus.to_string(),
+    }
+}
+
+/// Cores per socket of the topology [`smp_option`] picks for `nodes`; `None` without NUMA.
+fn cores_per_socket(nodes: &[NumaNode], vcpus: u16) -> Option<u16> {
+    let count = u16::try_from(nodes.len()).ok().filter(|&n| n > 0)?;
+    let per_node = vcpus / count;
+    let even = vcpus % count == 0
+        && (0u16..).zip(nodes).all(|(i, node)| {
+            let first = i * per_node;
+            node.cpus.iter().copied().eq(first..first + per_node)
+        });
+    Some(if even { per_node } else { 1 })
+}
+
+/// Memory backends and `-numa` options for `vm`: a backend per NUMA node with the node's
+/// vCPU sockets mapped to it, or a single backend for huge pages without NUMA. Huge page
+/// backends are files on hugetlbfs, allocated up front.
+fn memory_args(vm: &VmHandle) -> Vec<String> {
+    let backend = |id: &str, size_mb: u64| {
+        if vm.hugepages {
+            format!(
+                "memory-backend-file,id={id},size={size_mb}M,mem-path={HUGEPAGES_PATH},prealloc=on"
+            )
+        } else {
+            format!("memory-backend-ram,id={id},size={size_mb}M")
+        }
+    };
+    let mut args = Vec::new();
+    let Some(cores) = cores_per_socket(&vm.numa, vm.vcpus) else {
+        if vm.hugepages {
+            args.extend([
+                "-object".into(),
+                backend(MEMORY_BACKEND, vm.memory_mb),
+                "-machine".into(),
+                format!("memory-backend={MEMORY_BACKEND}"),
+            ]);
+        }
+        return args;
+    };
+    for (i, node) in vm.numa.iter().enumerate() {
+        let id = format!("numa{i}");
+        args.extend([
+            "-object".into(),
+            backend(&id, node.memory_mb),
+            "-numa".into(),
+            format!("node,nodeid={i},memdev={id}"),
+        ]);
+    }
+    for (i, node) in vm.numa.iter().enumerate() {
+        let mut sockets: Vec<u16> = node.cpus.iter().map(|cpu| cpu / cores).collect();
+        sockets.dedup();
+        for socket in sockets {
+            args.extend([
+                "-numa".into(),
+                format!("cpu,node-id={i},socket-id={socket}"),
+            ]);
+        }
+    }
+    args
+}
+
+/// Check boot order `order` of VM `name`: see [`check_boot_order`]. microvm and direct kernel boot
+/// bypass the firmware's boot devices altogether.
+fn check_boot(
+    name: &str,
+    order: &[BootDevice],
+    machine: MachineType,
+    kernel: bool,
+    cdrom: bool,
+    network: &NetworkConfig,
+) -> Result<()> {
+    let invalid = |detail: String| VmError::BootOrderInvalid {
+        vm: name.into(),
+        detail,
+    };
+    if order.is_empty() {
+        return Ok(());
+    }
+    if machine == MachineType::Microvm {
+        return Err(invalid(
+            "the microvm machine type boots its kernel directly".into(),
+        ));
+    }
+    if kernel {
+        return Err(invalid("direct kernel boot ignores the boot order".into()));
+    }
+    // Vnic networking is for illumos zones and adds no NIC here
+    let nic = !matches!(network, NetworkConfig::None | NetworkConfig::Vnic { .. });
+    check_boot_order(order, cdrom, nic).map_err(invalid)
+}
+
+/// QEMU id of boot device `device`, whose `bootindex` [`QemuBackend::restore_boot_order`] sets.
+fn boot_device_id(device: BootDevice) -> String {
+    match device {
+        BootDevice::Disk => format!("disk{ROOT_DISK_INDEX}"),
+        BootDevice::Cdrom => "cd0".into(),
+        BootDevice::Network => "nic0".into(),
+    }
+}
+
+/// Check that `qemu` has the accelerator, machine type and features the command line of `vm`
+/// uses, so that an old or cut-down QEMU is refused with what it lacks instead of failing at
+/// startup with an error of its own.
+fn check_qemu_support(vm: &VmHandle, qemu: &QemuInfo) -> Result<()> {
+    let unsupported = |detail: String| VmError::QemuUnsupported {
+        vm: vm.name.clone(),
+        detail,
+    };
+    // Lists QEMU couldn't give are taken to have everything
+    if !qemu.accels.is_empty() && !qemu.accels.iter().any(|a| a == "kvm") {
+        return Err(unsupported(format!(
+            "QEMU {} was built without KVM (accelerators: {})",
+            qemu.version,
+            qemu.accels.join(", ")
+        )));
+    }
+    let machine = vm.machine.to_string();
+    if !qemu.machine_types.is_empty() && !qemu.machine_types.contains(&machine) {
+        return Err(unsupported(format!(
+            "QEMU {} has no {machine} machine type",
+            qemu.version
+        )));
+    }
+    for (feature, required) in qemu_requirements(vm) {
+        if qemu.version < required {
+            return Err(unsupported(format!(
+                "{feature} requires QEMU ≥ {required}, found {}",
+                qemu.version
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// What the command line of `vm` uses that older QEMU releases lack, with the release that
+/// added it.
+fn qemu_requirements(vm: &VmHandle) -> Vec<(&'static str, QemuVersion)> {
+    let mut required = Vec::new();
+    if vm.machine == MachineType::Microvm {
+        required.push((
+            "the microvm machine type with rtc=off",
+            QemuVersion::new(5, 0, 0),
+        ));
+    }
+    if vm.hugepages && vm.numa.is_empty() {
+        required.push((
+            "huge pages (-machine memory-backend)",
+            QemuVersion::new(5, 0, 0),
+        ));
+    }
+    if vm.confidential.is_some() {
+        required.push((
+            "a confidential guest (confidential-guest-support)",
+            QemuVersion::new(6, 0, 0),
+        ));
+    }
+    match vm.audio {
+        AudioBackend::None => {}
+        AudioBackend::Pa => required.push(("audio (-audiodev)", QemuVersion::new(4, 0, 0))),
+        AudioBackend::Pipewire => {
+            required.push(("the pipewire audio backend", QemuVersion::new(8, 1, 0)));
+        }
+    }
+    required
+}
+
+/// Check that the NUMA nodes of `spec`, if any, divide its vCPUs and memory.
+fn check_numa(spec: &VmSpec) -> Result<()> {
+    if spec.numa.is_empty() {
+        return Ok(());
+    }
+    let invalid = |detail: String| VmError::NumaLayoutInvalid {
+        vm: spec.name.clone(),
+        detail,
+    };
+    if spec.machine == MachineType::Microvm {
+        return Err(invalid(
+            "the microvm machine type has no NUMA support".into(),
+        ));
+    }
+    check_numa_layout(&spec.numa, spec.vcpus, spec.memory_mb).map_err(invalid)
+}
+
+/// Check that the host can back the memory of VM `name` with huge pages: hugetlbfs is mounted,
+/// each memory backend is a whole number of pages, and enough pages are free.
+fn check_hugepages(name: &str, memory_mb: u64, numa: &[NumaNode]) -> Result<()> {
+    let unavailable = |detail: String| VmError::HugepagesUnavailable {
+        vm: name.into(),
+        detail,
+    };
+    if !Path::new(HUGEPAGES_PATH).is_dir() {
+        return Err(unavailable(format!("{HUGEPAGES_PATH} does not exist")));
+    }
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    let (page_kb, free) = hugepages_info(&meminfo)
+        .ok_or_else(|| unavailable("the kernel reports no huge page size".into()))?;
+    let sizes: Vec<u64> = match numa {
+        [] => vec![memory_mb],
+        nodes => nodes.iter().map(|n| n.memory_mb).collect(),
+    };
+    if let Some(size) = sizes.iter().find(|&&mb| mb * 1024 % page_kb != 0) {
+        return Err(unavailable(format!(
+            "{size} MB is not a whole number of {page_kb} kB huge pages"
+        )));
+    }
+    let needed = memory_mb * 1024 / page_kb;
+    if needed > free {
+        return Err(unavailable(format!(
+            "{needed} huge pages of {page_kb} kB are needed, but {free} are free"
+        )));
+    }
+    Ok(())
+}
+
+/// Make `tap` file descriptor [`MACVTAP_FD`] of the process `command` runs, where `-netdev
+/// tap,fd=` finds it.
+fn pass_tap_fd(command: &mut tokio::process::Command, tap: &std::fs::File) {
+    let fd = tap.as_raw_fd();
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 onto itself would keep close-on-exec set
+            let result = if fd == MACVTAP_FD {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, MACVTAP_FD)
+            };
+            if result == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Serial number of disk `index`: what `/dev/disk/by-id` names NVMe and SCSI disks after. NVMe
+/// allows 20 characters.
+fn disk_serial(index: usize) -> String {
+    format!("vmctl-disk{index}")
+}
+
+/// `value` for a QEMU option list, where a comma is written as two.
+fn qemu_opt_escape(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+/// The last [`SPAWN_OUTPUT_LINES`] lines of QEMU's `output`, saying how many more are in `log`;
+/// `None` if it printed nothing.
+fn output_tail(output: &str, log: &str) -> Option<String> {
+    let lines: Vec<&str> = output.trim_end().lines().collect();
+    if lines.iter().all(|l| l.trim().is_empty()) {
+        return None;
+    }
+    let skipped = lines.len().saturating_sub(SPAWN_OUTPUT_LINES);
+    let mut tail = lines[skipped..].join("\n");
+    if skipped > 0 {
+        tail.insert_str(0, &format!("[{skipped} earlier lines in {log}]\n"));
+    }
+    Some(tail)
+}
+
+/// The names in the output of a QEMU `help` option such as `-audiodev help`: a heading, then
+/// one name per line.
+fn help_list(help: &str) -> Vec<String> {
+    help.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.contains(' ') && !line.ends_with(':'))
+        .map(String::from)
+        .collect()
+}
+
+/// The machine types in `-machine help` output: a heading, then a line per machine type that
+/// starts with its name (`q35   Standard PC (Q35 + ICH9, 2009) (alias of pc-q35-8.2)`).
+fn machine_types(help: &str) -> Vec<String> {
+    help.lines()
+        .filter(|line| !line.ends_with(':'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+/// The default huge page size in kB and the number of free huge pages, from `/proc/meminfo`.
+fn hugepages_info(meminfo: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| {
+        meminfo.lines().find_map(|line| {
+            line.strip_prefix(name)?
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+    };
+    let page_kb = field("Hugepagesize:").filter(|&kb| kb > 0)?;
+    Some((page_kb, field("HugePages_Free:")?))
+}
+
+/// Reject extra arguments that would fight the options the backend manages.
+fn check_qemu_args(args: &[String]) -> Result<()> {
+    match managed_qemu_option(args) {
+        Some(arg) => Err(VmError::QemuArgManaged { arg: arg.into() }),
+        None => Ok(()),
+    }
+}
+
+/// Reject options a confidential guest can't have, because they need the host to get at its
+/// memory or firmware.
+fn check_confidential(spec: &VmSpec) -> Result<()> {
+    if spec.confidential.is_none() {
+        return Ok(());
+    }
+    let conflict = |detail: &str| {
+        Err(VmError::ConfidentialConflict {
+            vm: spec.name.clone(),
+            detail: detail.into(),
+        })
+    };
+    if spec.machine == MachineType::Microvm {
+        return conflict("the microvm machine type, which has no firmware to launch it");
+    }
+    if spec.secure_boot {
+        return conflict("Secure Boot, whose variable store needs SMM");
+    }
+    let found = CONFIDENTIAL_CONFLICTS
+        .iter()
+        .find(|(option, _)| spec.qemu_args.iter().any(|arg| arg.contains(option)));
+    match found {
+        Some((_, what)) => conflict(what),
+        None => Ok(()),
+    }
+}
+
+/// Memory encryption details of an AMD host that SEV guests are launched with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SevHost {
+    /// Bit of a page-table entry that marks the page encrypted (the C-bit).
+    pub cbitpos: u32,
+    /// Physical address bits lost to memory encryption.
+    pub reduced_phys_bits: u32,
+}
+
+impl SevHost {
+    /// Check that this host can run SEV guests, and read its C-bit from CPUID.
+    pub fn probe() -> Result<Self> {
+        let unavailable = |detail: &str| VmError::SevUnavailable {
+            detail: detail.into(),
+        };
+        let enabled = std::fs::read_to_string(SEV_PARAM)
+            .map_err(|_| unavailable("kvm_amd is not loaded, or this is not an AMD host"))?;
+        if !matches!(enabled.trim(), "Y" | "1") {
+            return Err(unavailable("kvm_amd was loaded with SEV off"));
+        }
+        if !Path::new(SEV_DEVICE).exists() {
+            return Err(unavailable(
+                "/dev/sev is missing (is the ccp module loaded?)",
+            ));
+        }
+        Self::from_cpuid().ok_or_else(|| unavailable("the CPU does not report SEV"))
+    }
+
+    /// The C-bit and reduced physical address bits from CPUID leaf 0x8000001F, if the CPU has
+    /// SEV.
+    #[cfg(target_arch = "x86_64")]
+    fn from_cpuid() -> Option<Self> {
+        use std::arch::x86_64::__cpuid;
+        // __cpuid is only unsafe on older toolchains
+        #[allow(unused_unsafe)]
+        let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+        if max_leaf < 0x8000_001f {
+            return None;
+        }
+        #[allow(unused_unsafe)]
+        let leaf = unsafe { __cpuid(0x8000_001f) };
+        // EAX bit 1: SEV
+        (leaf.eax & 0b10 != 0).then_some(Self {
+            cbitpos: leaf.ebx & 0x3f,
+            reduced_phys_bits: (leaf.ebx >> 6) & 0x3f,
+        })
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn from_cpuid() -> Option<Self> {
+        None
+    }
+}
+
+/// A QEMU release, from `qemu-system-x86_64 --version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct QemuVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+}
+
+impl QemuVersion {
+    pub const fn new(major: u32, minor: u32, micro: u32) -> Self {
+        Self {
+            major,
+            minor,
+            micro,
+        }
+    }
+
+    /// The version in `--version` output: `QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)`.
+    fn parse(output: &str) -> Option<Self> {
+        let number = output
+            .lines()
+            .next()?
+            .split("version ")
+            .nth(1)?
+            .split_whitespace()
+            .next()?;
+        // Release candidates and git builds end in e.g. `0-rc1`, which counts as 0
+        let mut parts = number.split('.').map_while(|part| part.parse().ok());
+        Some(Self::new(
+            parts.next()?,
+            parts.next().unwrap_or(0),
+            parts.next().unwrap_or(0),
+        ))
+    }
+}
+
+impl std::fmt::Display for QemuVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.micro != 0 {
+            write!(f, ".{}", self.micro)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a QEMU binary supports; see [`probe`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QemuInfo {
+    pub version: QemuVersion,
+    /// Accelerators from `-accel help`, e.g. `kvm` and `tcg`; empty for QEMU older than 4.0,
+    /// which can't list them.
+    pub accels: Vec<String>,
+    /// Machine types and their aliases from `-machine help`, e.g. `q35` and `pc-q35-8.2`.
+    pub machine_types: Vec<String>,
+}
+
+impl QemuInfo {
+    /// Whether this is QEMU `major.minor` or newer.
+    pub fn at_least(&self, major: u32, minor: u32) -> bool {
+        self.version >= QemuVersion::new(major, minor, 0)
+    }
+}
+
+/// A [`QemuInfo`] in the probe cache, valid while the binary was last modified at `modified`.
+#[derive(Serialize, Deserialize)]
+struct CachedQemuInfo {
+    modified: SystemTime,
+    info: QemuInfo,
+}
+
+/// Find out what QEMU binary `binary` (a path, or a name looked up in `PATH`) supports, by
+/// running it with `--version`, `-machine help` and `-accel help`. The result is cached in
+/// `data_dir` by the binary's path and modification time, so QEMU only runs again once it has
+/// been upgraded.
+pub async fn probe(binary: &Path, data_dir: &Path) -> Result<QemuInfo> {
+    let path = if binary.components().count() > 1 {
+        binary.to_path_buf()
+    } else {
+        which(&binary.to_string_lossy()).ok_or_else(|| VmError::QemuSpawnFailed {
+            source: std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} not found in PATH", binary.display()),
+            ),
+            stderr: None,
+        })?
+    };
+    let modified = tokio::fs::metadata(&path)
+        .await
+        .and_then(|m| m.modified())
+        .map_err(|e| VmError::QemuSpawnFailed {
+            source: e,
+            stderr: None,
+        })?;
+
+    let cache_path = data_dir.join(QEMU_INFO_CACHE);
+    let mut cache: HashMap<PathBuf, CachedQemuInfo> = match tokio::fs::read(&cache_path).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    };
+    if let Some(cached) = cache.get(&path).filter(|c| c.modified == modified) {
+        return Ok(cached.info.clone());
+    }
+
+    let version_output = qemu_output(&path, &["--version"])
+        .await?
+        .unwrap_or_default();
+    let version = QemuVersion::parse(&version_output).ok_or_else(|| VmError::QemuSpawnFailed {
+        source: std::io::Error::other(format!(
+            "cannot read the QEMU version from `{} --version`",
+            path.display()
+        )),
+        stderr: None,
+    })?;
+    let machine_types = qemu_output(&path, &["-machine", "help"])
+        .await?
+        .map(|help| machine_types(&help))
+        .unwrap_or_default();
+    // Older releases don't know `help` here and would start a VM instead
+    let accels = if version >= QemuVersion::new(4, 0, 0) {
+        qemu_output(&path, &["-accel", "help"])
+            .await?
+            .map(|help| help_list(&help))
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let info = QemuInfo {
+        version,
+        accels,
+        machine_types,
+    };
+    debug!(binary = %path.display(), version = %info.version, "QEMU: probed");
+
+    cache.insert(
+        path,
+        CachedQemuInfo {
+            modified,
+            info: info.clone(),
+        },
+    );
+    // The cache only saves a few milliseconds; a data directory that can't be written to is
+    // no reason to fail
+    let written = async {
+        tokio::fs::create_dir_all(data_dir).await?;
+        tokio::fs::write(&cache_path, serde_json::to_vec_pretty(&cache)?).await
+    };
+    if let Err(e) = written.await {
+        debug!(path = %cache_path.display(), error = %e, "QEMU: cannot cache the probe");
+    }
+    Ok(info)
+}
+
+/// The standard output of QEMU `binary` run with `args`, if it exits successfully within
+/// [`PROBE_TIMEOUT`].
+async fn qemu_output(binary: &Path, args: &[&str]) -> Result<Option<String>> {
+    let output = tokio::process::Command::new(binary)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(PROBE_TIMEOUT, output)
+        .await
+        .map_err(|_| VmError::QemuSpawnFailed {
+            source: std::io::Error::other(format!(
+                "`{} {}` did not exit within {}s",
+                binary.display(),
+                args.join(" "),
+                PROBE_TIMEOUT.as_secs()
+            )),
+            stderr: None,
+        })?
+        .map_err(|e| VmError::QemuSpawnFailed {
+            source: e,
+            stderr: None,
+        })?;
+    Ok(output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// What `prepare` will create for a spec; see [`QemuBackend::plan`].
+struct Plan {
+    handle: VmHandle,
+    seed: Option<Seed>,
+    firmware: Option<Firmware>,
+}
+
+/// Contents of a cloud-init NoCloud seed ISO.
+struct Seed {
+    user_data: Vec<u8>,
+    meta_data: Vec<u8>,
+    network_config: Option<Vec<u8>>,
+}
+
+/// OVMF firmware for a UEFI guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Firmware {
+    /// Read-only code image, attached as the first pflash drive.
+    pub code: PathBuf,
+    /// Variable-store template, copied to each VM's `efivars.fd`.
+    pub vars: PathBuf,
+}
+
+/// The first directory in `dirs` that holds a matching OVMF code and vars pair, built with
+/// Secure Boot if `secure_boot` is set.
+fn find_firmware(dirs: &[PathBuf], secure_boot: bool) -> Result<Firmware> {
+    let files = if secure_boot {
+        SECURE_BOOT_FIRMWARE_FILES
+    } else {
+        FIRMWARE_FILES
+    };
+    for dir in dirs {
+        for (code, vars) in files {
+            let (code, vars) = (dir.join(code), dir.join(vars));
+            if code.is_file() && vars.is_file() {
+                debug!(code = %code.display(), vars = %vars.display(), "found OVMF firmware");
+                return Ok(Firmware { code, vars });
+            }
+        }
+    }
+    let searched = dirs
+        .iter()
+        .map(|d| d.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(if secure_boot {
+        VmError::SecureBootFirmwareNotFound { searched }
+    } else {
+        VmError::FirmwareNotFound { searched }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{KernelBoot, SerialPort, SmbiosConfig, StaticIpConfig};
+
+    fn test_handle(machine: MachineType) -> VmHandle {
+        VmHandle {
+            id: "qemu-test".into(),
+            name: "test-vm".into(),
+            backend: BackendTag::Qemu,
+            work_dir: "/tmp/vm".into(),
+            overlay_path: Some("/tmp/vm/overlay.qcow2".into()),
+            seed_iso_path: Some("/tmp/vm/seed.iso".into()),
+            pid: None,
+            qmp_socket: Some("/tmp/vm/qmp.sock".into()),
+            console_socket: Some("/tmp/vm/console.sock".into()),
+            vnc_addr: None,
+            vcpus: 2,
+            memory_mb: 512,
+            disk_gb: None,
+            overlay: Default::default(),
+            network: NetworkConfig::User,
+            macvtap: None,
+            ssh_host_port: Some(10022),
+            ssh_host_port_fixed: false,
+            mac_addr: Some("52:54:00:ab:cd:ef".into()),
+            uefi: false,
+            static_ip: None,
+            private_networks: Vec::new(),
+            serial_ports: Vec::new(),
+            machine,
+            kernel: None,
+            cdrom: None,
+            boot_order: Vec::new(),
+            boot_once: None,
+            random_seed: Default::default(),
+            qemu_args: Vec::new(),
+            restart_policy: Default::default(),
+            guest_os: Default::default(),
+            display: Default::default(),
+            audio: Default::default(),
+            smbios: Default::default(),
+            disk_bus: Default::default(),
+            drivers_iso: None,
+            rdp_host_port: None,
+            secure_boot: false,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
+            remote_host: None,
+            labels: HashMap::new(),
+            annotations: HashMap::new(),
+            hostnames: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_branch_removes_work_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vm = test_handle(MachineType::Q35);
+        vm.overlay_path = Some(dir.path().join("missing.qcow2"));
+        let work_dir = dir.path().join("branch");
+        assert!(
+            QemuBackend::branch(&vm, "nope", "branch", work_dir.clone(), &[])
+                .await
+                .is_err()
+        );
+        assert!(!work_dir.exists());
+    }
+
+    #[test]
+    fn unique_mac_avoids_existing() {
+        let existing = vec![
+            "52:54:00:00:00:01".to_string(),
+            "52:54:00:AB:CD:EF".to_string(),
+        ];
+        let mut candidates = [
+            "52:54:00:00:00:01",
+            // taken, only in a different case
+            "52:54:00:ab:cd:ef",
+            "52:54:00:00:00:02",
+        ]
+        .into_iter();
+        let mut drawn = 0;
+        let mac = QemuBackend::generate_unique_mac_with(&existing, || {
+            drawn += 1;
+            candidates.next().unwrap().to_string()
+        });
+        assert_eq!(mac, "52:54:00:00:00:02");
+        assert_eq!(drawn, 3);
+
+        let mac = QemuBackend::generate_unique_mac(&existing);
+        assert!(mac.starts_with("52:54:"), "{mac}");
+        assert!(!existing.iter().any(|m| m.eq_ignore_ascii_case(&mac)));
+    }
+
+    #[test]
+    fn neigh_matches_mac() {
+        let mac = "52:54:00:ab:cd:ef";
+
+        let neigh = "192.168.1.1 dev eth0 lladdr 00:11:22:33:44:55 REACHABLE\n\
+                     192.168.122.9 dev virbr0 lladdr 52:54:00:AB:CD:EF FAILED\n\
+                     192.168.122.45 dev virbr0 lladdr 52:54:00:ab:cd:ef STALE\n\
+                     fe80::5054:ff:feab:cdef dev virbr0 lladdr 52:54:00:ab:cd:ef STALE\n\
+                     fd00:122::45 dev virbr0 lladdr 52:54:00:ab:cd:ef REACHABLE\n";
+        let expected: Vec<IpAddr> = vec![
+            "192.168.122.45".parse().unwrap(),
+            "fd00:122::45".parse().unwrap(),
+        ];
+        assert_eq!(neigh_ips(neigh, mac, None), expected);
+        assert_eq!(neigh_ips(neigh, mac, Some("virbr0")), expected);
+        assert!(neigh_ips(neigh, mac, Some("br0")).is_empty());
+        assert!(neigh_ips(neigh, "52:54:00:00:00:99", None).is_empty());
+    }
+
+    fn has_pair(args: &[String], flag: &str, value: &str) -> bool {
+        args.windows(2).any(|w| w[0] == flag && w[1] == value)
+    }
+
+    #[test]
+    fn q35_args() {
+        let args =
+            QemuBackend::build_args(&test_handle(MachineType::Q35), None, None, None).unwrap();
+        assert!(has_pair(&args, "-machine", "q35,accel=kvm"));
+        assert!(has_pair(&args, "-rtc", "base=utc,driftfix=slew"));
+        assert!(has_pair(&args, "-vnc", "127.0.0.1:0,to=99"));
+        assert!(has_pair(
+            &args,
+            "-qmp",
+            "unix:/tmp/vm/qmp-events.sock,server,nowait"
+        ));
+        assert!(has_pair(&args, "-device", "virtio-blk-pci,drive=drive0"));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-net-pci,netdev=net0,mac=52:54:00:ab:cd:ef"
+        ));
+        assert!(has_pair(
+            &args,
+            "-chardev",
+            "socket,id=qga0,path=/tmp/vm/qga.sock,server=on,wait=off"
+        ));
+        assert!(has_pair(&args, "-device", "virtio-serial-pci"));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtserialport,chardev=qga0,name=org.qemu.guest_agent.0"
+        ));
+        assert!(has_pair(
+            &args,
+            "-drive",
+            "file=/tmp/vm/seed.iso,format=raw,if=ide,media=cdrom,readonly=on"
+        ));
+        assert!(!args.iter().any(|a| a == "-kernel"));
+        assert!(!args.iter().any(|a| a.contains("cdrom0")));
+        assert!(has_pair(
+            &args,
+            "-object",
+            "rng-random,filename=/dev/urandom,id=rng0"
+        ));
+        assert!(has_pair(&args, "-device", "virtio-rng-pci,rng=rng0"));
+
+        let mut vm = test_handle(MachineType::Q35);
+        vm.cdrom = Some("/isos/installer.iso".into());
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-drive",
+            "file=/isos/installer.iso,format=raw,if=none,id=cdrom0,media=cdrom,readonly=on"
+        ));
+        assert!(has_pair(&args, "-device", "ide-cd,drive=cdrom0,bus=ide.1"));
+        // the seed ISO is still attached separately
+        assert!(has_pair(
+            &args,
+            "-drive",
+            "file=/tmp/vm/seed.iso,format=raw,if=ide,media=cdrom,readonly=on"
+        ));
+
+        // a VM without cloud-init has no seed drive
+        vm.seed_iso_path = None;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(!args.iter().any(|a| a.contains("seed.iso")));
+        assert!(has_pair(&args, "-device", "ide-cd,drive=cdrom0,bus=ide.1"));
+    }
+
+    #[test]
+    fn rng_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.random_seed = RngConfig::VirtioRng {
+            max_bytes: Some(1024),
+            period_ms: Some(2000),
+        };
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-rng-pci,rng=rng0,max-bytes=1024,period=2000"
+        ));
+
+        vm.random_seed = RngConfig::None;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(!args.iter().any(|a| a.contains("rng")));
+    }
+
+    #[test]
+    fn serial_port_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.serial_ports = [
+            SerialBackend::Socket("/tmp/vm/ttyS1.sock".into()),
+            SerialBackend::File("/tmp/vm,logs/ttyS2.log".into()),
+            SerialBackend::Null,
+        ]
+        .into_iter()
+        .map(|backend| SerialPort { backend })
+        .collect();
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-serial",
+            "unix:/tmp/vm/ttyS1.sock,server=on,wait=off"
+        ));
+        // commas in paths are doubled so QEMU doesn't read them as option separators
+        assert!(has_pair(&args, "-serial", "file:/tmp/vm,,logs/ttyS2.log"));
+        assert!(has_pair(&args, "-serial", "null"));
+
+        vm.serial_ports = vec![SerialPort {
+            backend: SerialBackend::Stdio,
+        }];
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+    }
 
-        info!(
-            name = %vm.name,
-            vcpus = vm.vcpus,
-            memory_mb = vm.memory_mb,
-            binary = %self.qemu_binary.display(),
-            "QEMU: starting"
-        );
-        debug!(args = ?args, "QEMU command line");
+    #[test]
+    fn microvm_args() {
+        let mut vm = test_handle(MachineType::Microvm);
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
 
-        let status = tokio::process::Command::new(&self.qemu_binary)
-            .args(&args)
-            .status()
-            .await
-            .map_err(|e| VmError::QemuSpawnFailed { source: e })?;
+        vm.kernel = Some(KernelBoot {
+            kernel: "/boot/vmlinuz".into(),
+            initrd: Some("/boot/initrd.img".into()),
+            cmdline: None,
+        });
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-machine",
+            "microvm,x-option-roms=off,rtc=off"
+        ));
+        assert!(has_pair(&args, "-display", "none"));
+        assert!(!args.iter().any(|a| a == "-vnc" || a.contains("-pci")));
+        assert!(has_pair(&args, "-device", "virtio-blk-device,drive=drive0"));
+        assert!(has_pair(&args, "-device", "virtio-blk-device,drive=seed"));
+        assert!(has_pair(&args, "-kernel", "/boot/vmlinuz"));
+        assert!(has_pair(&args, "-initrd", "/boot/initrd.img"));
+        assert!(has_pair(&args, "-append", "console=ttyS0 root=/dev/vda rw"));
 
-        if !status.success() {
-            return Err(VmError::QemuSpawnFailed {
-                source: std::io::Error::other(format!("QEMU exited with status {}", status)),
-            });
-        }
+        vm.uefi = true;
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+    }
 
-        // Read PID from pidfile
-        let pid = Self::read_pid(&vm.work_dir).await;
+    #[test]
+    fn windows_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.guest_os = GuestOs::Windows;
+        vm.disk_bus = DiskBus::Sata;
+        vm.drivers_iso = Some("/isos/virtio-win.iso".into());
+        vm.rdp_host_port = Some(13389);
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-rtc", "base=localtime,driftfix=slew"));
+        assert!(has_pair(&args, "-device", "VGA"));
+        assert!(has_pair(&args, "-device", "ide-hd,drive=drive0,bus=ide.2"));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "ide-cd,drive=drivers0,bus=ide.3"
+        ));
+        assert!(has_pair(
+            &args,
+            "-netdev",
+            "user,id=net0,hostfwd=tcp::10022-:22,hostfwd=tcp:127.0.0.1:13389-:3389"
+        ));
 
-        // Wait for QMP socket and verify + query VNC
-        let mut qmp = QmpClient::connect(qmp_sock, Duration::from_secs(10)).await?;
-        let qmp_status = qmp.query_status().await?;
-        let vnc_addr = qmp.query_vnc().await.unwrap_or(None);
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.guest_os, GuestOs::Windows);
+        assert_eq!(found.disk_bus, DiskBus::Sata);
+        assert_eq!(found.rdp_host_port, Some(13389));
+        assert_eq!(found.ssh_host_port, Some(10022));
+        assert_eq!(found.drivers_iso, vm.drivers_iso);
 
-        info!(
-            name = %vm.name,
-            status = %qmp_status,
-            pid = ?pid,
-            vnc = ?vnc_addr,
-            "QEMU: started"
+        vm.machine = MachineType::Microvm;
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+    }
+
+    #[test]
+    fn firmware_search() {
+        let empty = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        // A code image without its vars template is not usable.
+        std::fs::write(dir.path().join("OVMF_CODE.fd"), b"").unwrap();
+        std::fs::write(dir.path().join("OVMF_CODE_4M.fd"), b"").unwrap();
+        std::fs::write(dir.path().join("OVMF_VARS_4M.fd"), b"").unwrap();
+
+        let dirs = [empty.path().to_path_buf(), dir.path().to_path_buf()];
+        let firmware = find_firmware(&dirs, false).unwrap();
+        assert_eq!(firmware.code, dir.path().join("OVMF_CODE_4M.fd"));
+        assert_eq!(firmware.vars, dir.path().join("OVMF_VARS_4M.fd"));
+
+        let err = find_firmware(&dirs[..1], false).unwrap_err();
+        assert!(matches!(err, VmError::FirmwareNotFound { .. }));
+        assert!(
+            err.to_string()
+                .contains(&empty.path().display().to_string())
         );
 
-        let mut updated = vm.clone();
-        updated.pid = pid;
-        updated.vnc_addr = vnc_addr;
+        let mut vm = test_handle(MachineType::Q35);
+        vm.uefi = true;
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+        let args = QemuBackend::build_args(&vm, Some(&firmware), None, None).unwrap();
+        let code = format!(
+            "if=pflash,format=raw,readonly=on,file={}",
+            firmware.code.display()
+        );
+        assert!(has_pair(&args, "-drive", &code));
+    }
 
-        Ok(updated)
+    #[test]
+    fn secure_boot_firmware_and_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirs = [dir.path().to_path_buf()];
+        // Plain firmware and a Secure Boot build without enrolled keys don't count
+        for file in ["OVMF_CODE.fd", "OVMF_VARS.fd", "OVMF_CODE_4M.secboot.fd"] {
+            std::fs::write(dir.path().join(file), b"").unwrap();
+        }
+        let err = find_firmware(&dirs, true).unwrap_err();
+        assert!(matches!(err, VmError::SecureBootFirmwareNotFound { .. }));
+
+        std::fs::write(dir.path().join("OVMF_VARS_4M.ms.fd"), b"").unwrap();
+        let firmware = find_firmware(&dirs, true).unwrap();
+        assert_eq!(firmware.code, dir.path().join("OVMF_CODE_4M.secboot.fd"));
+        assert_eq!(firmware.vars, dir.path().join("OVMF_VARS_4M.ms.fd"));
+
+        let mut vm = test_handle(MachineType::Q35);
+        vm.secure_boot = true;
+        assert!(QemuBackend::build_args(&vm, Some(&firmware), None, None).is_err());
+        vm.uefi = true;
+        let args = QemuBackend::build_args(&vm, Some(&firmware), None, None).unwrap();
+        assert!(has_pair(&args, "-machine", "q35,accel=kvm,smm=on"));
+        assert!(has_pair(
+            &args,
+            "-global",
+            "driver=cfi.pflash01,property=secure,value=on"
+        ));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert!(found.uefi && found.secure_boot);
     }
 
-    async fn stop(&self, vm: &VmHandle, timeout: Duration) -> Result<VmHandle> {
-        // Try ACPI shutdown via QMP first
-        if let Some(ref qmp_sock) = vm.qmp_socket {
-            if qmp_sock.exists() {
-                if let Ok(mut qmp) = QmpClient::connect(qmp_sock, Duration::from_secs(2)).await {
-                    let _ = qmp.system_powerdown().await;
-                }
+    #[test]
+    fn sev_args_and_conflicts() {
+        let firmware = Firmware {
+            code: "/usr/share/OVMF/OVMF_CODE.fd".into(),
+            vars: "/usr/share/OVMF/OVMF_VARS.fd".into(),
+        };
+        let host = SevHost {
+            cbitpos: 51,
+            reduced_phys_bits: 1,
+        };
+        let mut vm = test_handle(MachineType::Q35);
+        vm.confidential = Some(ConfidentialMode::Sev);
+        vm.uefi = true;
+        assert!(QemuBackend::build_args(&vm, Some(&firmware), None, None).is_err());
+        let args = QemuBackend::build_args(&vm, Some(&firmware), Some(&host), None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-machine",
+            "q35,accel=kvm,confidential-guest-support=sev0"
+        ));
+        assert!(has_pair(
+            &args,
+            "-object",
+            "sev-guest,id=sev0,cbitpos=51,reduced-phys-bits=1,policy=0x1"
+        ));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.confidential, Some(ConfidentialMode::Sev));
+        vm.secure_boot = true;
+        assert!(QemuBackend::build_args(&vm, Some(&firmware), Some(&host), None).is_err());
+
+        let mut spec = VmSpec::new("test-vm", "/images/base.qcow2");
+        spec.confidential = Some(ConfidentialMode::Sev);
+        assert!(check_confidential(&spec).is_ok());
+        spec.qemu_args = vec!["-device".into(), "virtio-balloon-pci".into()];
+        match check_confidential(&spec) {
+            Err(VmError::ConfidentialConflict { detail, .. }) => {
+                assert_eq!(detail, "memory ballooning")
             }
+            other => panic!("expected ConfidentialConflict, got {other:?}"),
         }
+        spec.qemu_args.clear();
+        spec.secure_boot = true;
+        assert!(check_confidential(&spec).is_err());
+    }
 
-        // Wait for process to exit
-        let start = tokio::time::Instant::now();
-        loop {
-            if let Some(pid) = Self::read_pid(&vm.work_dir).await {
-                if !Self::pid_alive(pid) {
-                    info!(name = %vm.name, "QEMU: process exited after ACPI shutdown");
-                    let mut updated = vm.clone();
-                    updated.pid = None;
-                    updated.vnc_addr = None;
-                    return Ok(updated);
-                }
-            } else {
-                // No PID file, process likely already gone
-                let mut updated = vm.clone();
-                updated.pid = None;
-                updated.vnc_addr = None;
-                return Ok(updated);
-            }
+    #[test]
+    fn ssh_host_port_conflict() {
+        let listener = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut vm = test_handle(MachineType::Q35);
+        vm.ssh_host_port = Some(port);
 
-            if start.elapsed() >= timeout {
-                break;
+        let claimed = QemuBackend::claim_ssh_host_port(&vm).unwrap();
+        assert_ne!(claimed.ssh_host_port, Some(port));
+
+        vm.ssh_host_port_fixed = true;
+        match QemuBackend::claim_ssh_host_port(&vm) {
+            Err(VmError::HostPortInUse {
+                port: p, holder, ..
+            }) => {
+                assert_eq!(p, port);
+                assert!(
+                    holder.ends_with(&format!("(pid {})", std::process::id())),
+                    "{holder}"
+                );
             }
-            tokio::time::sleep(Duration::from_millis(500)).await;
+            other => panic!("expected HostPortInUse, got {other:?}"),
         }
+    }
 
-        // SIGTERM fallback
-        if let Some(pid) = Self::read_pid(&vm.work_dir).await {
-            if Self::pid_alive(pid) {
-                warn!(name = %vm.name, pid, "QEMU: ACPI shutdown timed out, sending SIGTERM");
-                unsafe {
-                    libc::kill(pid as i32, libc::SIGTERM);
-                }
-                tokio::time::sleep(Duration::from_secs(3)).await;
-            }
+    #[test]
+    fn private_network_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        let nic = |network: &str, mac: &str, bridge: Option<&str>| PrivateNic {
+            network: network.into(),
+            address: "10.99.0.2/24".into(),
+            mac: Some(mac.into()),
+            bridge: bridge.map(String::from),
+        };
+        vm.private_networks = vec![
+            nic("cluster", "52:54:00:00:00:02", Some("vmp-cluster")),
+            nic("storage", "52:54:00:00:00:03", None),
+        ];
+        let group = vm.private_networks[1].multicast_group();
+        assert!(group.starts_with("239.192."), "{group}");
 
-            // SIGKILL if still alive
-            if Self::pid_alive(pid) {
-                warn!(name = %vm.name, pid, "QEMU: SIGTERM failed, sending SIGKILL");
-                unsafe {
-                    libc::kill(pid as i32, libc::SIGKILL);
-                }
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-netdev",
+            "tap,id=pnet0,script=/tmp/vm/ifup-cluster.sh,downscript=no"
+        ));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-net-pci,netdev=pnet0,mac=52:54:00:00:00:02"
+        ));
+        assert!(has_pair(
+            &args,
+            "-netdev",
+            &format!("socket,id=pnet1,mcast={group},localaddr=127.0.0.1")
+        ));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-net-pci,netdev=pnet1,mac=52:54:00:00:00:03"
+        ));
+    }
+
+    #[test]
+    fn exits_and_restart_policies() {
+        use crate::types::RestartPolicy;
+
+        let crash = exit_kind(false, None);
+        assert!(matches!(crash, VmExit::Crashed { ref reason } if reason.contains("without")));
+        assert_eq!(
+            exit_kind(false, Some("guest-panic")),
+            VmExit::Crashed {
+                reason: "guest-panic".into()
             }
+        );
+        let poweroff = exit_kind(false, Some("guest-shutdown"));
+        assert_eq!(poweroff, VmExit::GuestShutdown);
+        let stopped = exit_kind(true, Some("guest-shutdown"));
+        assert_eq!(stopped, VmExit::Requested);
+        assert_eq!(exit_kind(false, Some("host-ui")), VmExit::Requested);
+
+        for (policy, restarts) in [
+            (RestartPolicy::No, [false, false, false]),
+            (RestartPolicy::OnFailure, [true, false, false]),
+            (RestartPolicy::Always, [true, true, false]),
+        ] {
+            let got = [&crash, &poweroff, &stopped].map(|e| policy.restarts_after(e));
+            assert_eq!(got, restarts, "{policy}");
         }
+    }
 
-        let mut updated = vm.clone();
-        updated.pid = None;
-        updated.vnc_addr = None;
-        Ok(updated)
+    #[tokio::test]
+    async fn wait_for_exit_follows_qmp_events() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut vm = test_handle(MachineType::Q35);
+        vm.work_dir = dir.path().to_path_buf();
+        let listener = tokio::net::UnixListener::bind(dir.path().join(EVENTS_SOCKET)).unwrap();
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let (read, mut write) = tokio::io::split(conn);
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"{\"QMP\": {}}\n").await.unwrap();
+            lines.next_line().await.unwrap();
+            write.write_all(b"{\"return\": {}}\n").await.unwrap();
+            let shutdown =
+                r#"{"event": "SHUTDOWN", "data": {"guest": false, "reason": "host-signal"}}"#;
+            write
+                .write_all(format!("{shutdown}\n").as_bytes())
+                .await
+                .unwrap();
+            // QEMU exits: the connection closes
+        });
+
+        let exit = QemuBackend::wait_for_exit(&vm).await.unwrap();
+        assert_eq!(
+            exit,
+            VmExit::Crashed {
+                reason: "host-signal".into()
+            }
+        );
+
+        // No events socket and a stop marker: an exit vmctl asked for.
+        std::fs::remove_file(dir.path().join(EVENTS_SOCKET)).unwrap();
+        std::fs::write(dir.path().join(STOP_MARKER), b"").unwrap();
+        let exit = QemuBackend::wait_for_exit(&vm).await.unwrap();
+        assert_eq!(exit, VmExit::Requested);
     }
 
-    async fn suspend(&self, vm: &VmHandle) -> Result<VmHandle> {
-        if let Some(ref qmp_sock) = vm.qmp_socket {
-            let mut qmp = QmpClient::connect(qmp_sock, Duration::from_secs(5)).await?;
-            qmp.stop().await?;
-        }
-        Ok(vm.clone())
+    #[test]
+    fn plan_touches_nothing_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = QemuBackend::new(None, Some(dir.path().to_path_buf()), None);
+        let mut spec = VmSpec::new("web", "/images/base.qcow2");
+        spec.ssh_host_port = Some(2222);
+        let plan = backend.plan(&spec).unwrap();
+
+        assert!(!dir.path().join("web").exists());
+        assert!(plan.seed.is_none() && plan.firmware.is_none());
+        assert_eq!(plan.handle.work_dir, dir.path().join("web"));
+        assert_eq!(plan.handle.ssh_host_port, Some(2222));
+        assert!(plan.handle.ssh_host_port_fixed);
+
+        let argv = backend.command_line(&plan.handle, None).unwrap();
+        assert_eq!(argv[0], "qemu-system-x86_64");
+        assert!(has_pair(
+            &argv,
+            "-qmp",
+            &format!(
+                "unix:{},server,nowait",
+                dir.path().join("web/qmp.sock").display()
+            )
+        ));
     }
 
-    async fn resume(&self, vm: &VmHandle) -> Result<VmHandle> {
-        if let Some(ref qmp_sock) = vm.qmp_socket {
-            let mut qmp = QmpClient::connect(qmp_sock, Duration::from_secs(5)).await?;
-            qmp.cont().await?;
-        }
-        Ok(vm.clone())
+    #[tokio::test]
+    async fn unavailable_backend_refuses_new_vms_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = QemuBackend::new(None, Some(dir.path().to_path_buf()), None)
+            .with_unavailable("/dev/kvm is missing or not accessible".into());
+        let spec = VmSpec::new("web", "/images/base.qcow2");
+        assert!(matches!(
+            backend.prepare(&spec).await,
+            Err(VmError::QemuUnavailable { .. })
+        ));
+        assert!(!dir.path().join("web").exists());
+
+        let vm = QemuBackend::new(None, Some(dir.path().to_path_buf()), None)
+            .plan(&spec)
+            .unwrap()
+            .handle;
+        std::fs::create_dir(&vm.work_dir).unwrap();
+        assert_eq!(backend.state(&vm).await.unwrap(), VmState::Stopped);
     }
 
-    async fn destroy(&self, vm: VmHandle) -> Result<()> {
-        // Stop if running
-        self.stop(&vm, Duration::from_secs(5)).await?;
+    #[test]
+    fn plan_seed_contents() {
+        let backend = QemuBackend::new(None, Some("/vms".into()), None);
+        let mut spec = VmSpec::new("db", "/images/base.qcow2");
+        spec.mac_addr = Some("52:54:00:12:34:56".into());
+        spec.cloud_init = Some(CloudInitConfig {
+            user_data: b"#cloud-config\nusers: []\n".to_vec(),
+            instance_id: None,
+            hostname: Some("db.example".into()),
+            meta_data: None,
+        });
+        spec.static_ip = Some(StaticIpConfig {
+            address: "10.0.0.5/24".into(),
+            gateway: None,
+            nameservers: Vec::new(),
+        });
+        let seed = backend.plan(&spec).unwrap().seed.unwrap();
 
-        // QMP quit to ensure cleanup
-        if let Some(ref qmp_sock) = vm.qmp_socket {
-            if qmp_sock.exists() {
-                if let Ok(mut qmp) = QmpClient::connect(qmp_sock, Duration::from_secs(2)).await {
-                    let _ = qmp.quit().await;
-                }
+        assert_eq!(seed.user_data, b"#cloud-config\nusers: []\n");
+        assert_eq!(
+            seed.meta_data,
+            b"instance-id: db\nlocal-hostname: db.example\n"
+        );
+        let network_config = String::from_utf8(seed.network_config.unwrap()).unwrap();
+        assert!(
+            network_config.contains("52:54:00:12:34:56"),
+            "{network_config}"
+        );
+        assert!(network_config.contains("10.0.0.5/24"), "{network_config}");
+    }
+
+    #[test]
+    fn extra_args_are_appended_last() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.qemu_args = vec!["-device".into(), "virtio-balloon-pci".into()];
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert_eq!(args[args.len() - 2..], ["-device", "virtio-balloon-pci"]);
+
+        for managed in ["-qmp", "--pidfile", "-daemonize"] {
+            vm.qemu_args = vec![managed.into(), "x".into()];
+            match QemuBackend::build_args(&vm, None, None, None) {
+                Err(VmError::QemuArgManaged { arg }) => assert_eq!(arg, managed),
+                other => panic!("expected QemuArgManaged, got {other:?}"),
             }
         }
+    }
 
-        // Remove work directory
-        let _ = tokio::fs::remove_dir_all(&vm.work_dir).await;
-        info!(name = %vm.name, "QEMU: destroyed");
-        Ok(())
+    #[test]
+    fn extra_rtc_replaces_default() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.qemu_args = vec!["-rtc".into(), "base=localtime".into()];
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert_eq!(args.iter().filter(|a| *a == "-rtc").count(), 1);
+        assert!(has_pair(&args, "-rtc", "base=localtime"));
     }
 
-    async fn state(&self, vm: &VmHandle) -> Result<VmState> {
-        // Check if process is alive
-        if let Some(pid) = Self::read_pid(&vm.work_dir).await {
-            if Self::pid_alive(pid) {
-                // Try QMP for detailed state
-                if let Some(ref qmp_sock) = vm.qmp_socket {
-                    if let Ok(mut qmp) = QmpClient::connect(qmp_sock, Duration::from_secs(2)).await
-                    {
-                        if let Ok(status) = qmp.query_status().await {
-                            return Ok(match status.as_str() {
-                                "running" => VmState::Running,
-                                "paused" | "suspended" => VmState::Suspended,
-                                _ => VmState::Running,
-                            });
-                        }
-                    }
-                }
-                return Ok(VmState::Running);
+    #[test]
+    fn handle_from_command_line_round_trips() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.random_seed = RngConfig::VirtioRng {
+            max_bytes: Some(1024),
+            period_ms: None,
+        };
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.vcpus, 2);
+        assert_eq!(found.memory_mb, 512);
+        assert!(matches!(found.network, NetworkConfig::User));
+        assert_eq!(found.ssh_host_port, Some(10022));
+        assert_eq!(found.mac_addr.as_deref(), Some("52:54:00:ab:cd:ef"));
+        assert_eq!(found.random_seed, vm.random_seed);
+        assert_eq!(found.machine, MachineType::Q35);
+        assert!(!found.uefi);
+
+        vm.network = NetworkConfig::Tap {
+            bridge: "br0".into(),
+        };
+        vm.random_seed = RngConfig::None;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert!(matches!(found.network, NetworkConfig::Tap { ref bridge } if bridge == "br0"));
+        assert_eq!(found.ssh_host_port, None);
+        assert_eq!(found.random_seed, RngConfig::None);
+
+        // Nothing to go on: the defaults of a deserialized handle
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &[]);
+        assert_eq!((found.vcpus, found.memory_mb), (1, 1024));
+        assert_eq!(found.random_seed, RngConfig::default());
+    }
+
+    #[test]
+    fn numa_and_hugepage_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.vcpus = 4;
+        vm.memory_mb = 4096;
+        vm.numa = vec![
+            NumaNode {
+                cpus: vec![0, 1],
+                memory_mb: 1024,
+            },
+            NumaNode {
+                cpus: vec![2, 3],
+                memory_mb: 3072,
+            },
+        ];
+        vm.hugepages = true;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-smp", "4,sockets=2,cores=2,threads=1"));
+        assert!(has_pair(
+            &args,
+            "-object",
+            "memory-backend-file,id=numa1,size=3072M,mem-path=/dev/hugepages,prealloc=on"
+        ));
+        assert!(has_pair(&args, "-numa", "node,nodeid=1,memdev=numa1"));
+        assert!(has_pair(&args, "-numa", "cpu,node-id=1,socket-id=1"));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.numa, vm.numa);
+        assert!(found.hugepages);
+
+        // Nodes that don't split into equal sockets get a socket per vCPU
+        vm.numa[0].cpus = vec![0, 2];
+        vm.numa[1].cpus = vec![1, 3];
+        vm.hugepages = false;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-smp", "4,sockets=4,cores=1,threads=1"));
+        assert!(has_pair(
+            &args,
+            "-object",
+            "memory-backend-ram,id=numa0,size=1024M"
+        ));
+        assert!(has_pair(&args, "-numa", "cpu,node-id=1,socket-id=3"));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.numa, vm.numa);
+        assert!(!found.hugepages);
+
+        // Huge pages alone back the whole memory with one object
+        vm.numa.clear();
+        vm.hugepages = true;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-smp", "4"));
+        assert!(has_pair(&args, "-machine", "memory-backend=ram0"));
+        assert!(!args.iter().any(|a| a == "-numa"));
+        assert!(handle_from_command_line("test-vm", vm.work_dir.clone(), &args).hugepages);
+
+        let mut spec = VmSpec::new("test-vm", "/images/base.qcow2");
+        spec.vcpus = 2;
+        spec.memory_mb = 2048;
+        spec.numa = vec![NumaNode {
+            cpus: vec![0, 1],
+            memory_mb: 1024,
+        }];
+        match check_numa(&spec) {
+            Err(VmError::NumaLayoutInvalid { detail, .. }) => {
+                assert!(detail.contains("2048"), "got {detail}")
             }
+            other => panic!("expected NumaLayoutInvalid, got {other:?}"),
         }
+        assert_eq!(
+            hugepages_info(
+                "HugePages_Total:      16\nHugePages_Free:       12\nHugepagesize:       2048 kB\n"
+            ),
+            Some((2048, 12))
+        );
+    }
 
-        // Check if work dir exists (prepared but not running)
-        if vm.work_dir.exists() {
-            Ok(VmState::Stopped)
-        } else {
-            Ok(VmState::Destroyed)
-        }
+    #[test]
+    fn display_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(args.iter().any(|a| a == "-daemonize"));
+        assert!(has_pair(&args, "-vnc", "127.0.0.1:0,to=99"));
+
+        vm.display = DisplayMode::Gtk;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(!args.iter().any(|a| a == "-daemonize" || a == "-vnc"));
+        assert!(has_pair(&args, "-pidfile", "/tmp/vm/qemu.pid"));
+        assert!(has_pair(&args, "-device", "virtio-gpu-pci"));
+        assert!(has_pair(&args, "-display", "gtk"));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.display, DisplayMode::Gtk);
+
+        vm.display = DisplayMode::None;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(args.iter().any(|a| a == "-daemonize"));
+        assert!(has_pair(&args, "-display", "none"));
+        assert!(!args.iter().any(|a| a == "-vnc"));
+
+        let mut vm = test_handle(MachineType::Microvm);
+        vm.display = DisplayMode::Sdl;
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
     }
 
-    async fn guest_ip(&self, vm: &VmHandle) -> Result<String> {
-        // For user-mode networking, the guest is reachable via localhost
-        // (SSH uses the forwarded host port)
-        if matches!(vm.network, NetworkConfig::User) {
-            return Ok("127.0.0.1".to_string());
+    #[test]
+    fn audio_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(!args.iter().any(|a| a == "-audiodev"));
+
+        vm.audio = AudioBackend::Pipewire;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-audiodev", "pipewire,id=audio0"));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-sound-pci,audiodev=audio0"
+        ));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.audio, AudioBackend::Pipewire);
+
+        vm.audio = AudioBackend::Pa;
+        vm.guest_os = GuestOs::Windows;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-audiodev", "pa,id=audio0"));
+        assert!(has_pair(&args, "-device", "intel-hda"));
+        assert!(has_pair(&args, "-device", "hda-duplex,audiodev=audio0"));
+
+        let mut vm = test_handle(MachineType::Microvm);
+        vm.audio = AudioBackend::Pa;
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+
+        let help = "Available audio drivers:\nnone\nalsa\npa\nwav\n";
+        assert_eq!(help_list(help), ["none", "alsa", "pa", "wav"]);
+    }
+
+    #[test]
+    fn parse_qemu_probe_output() {
+        for (output, version, shown) in [
+            (
+                "QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)\nCopyright (c) 2003-2023 Fabrice Bellard and the QEMU Project developers\n",
+                QemuVersion::new(8, 2, 2),
+                "8.2.2",
+            ),
+            (
+                "QEMU emulator version 5.0.0\n",
+                QemuVersion::new(5, 0, 0),
+                "5.0",
+            ),
+            (
+                "QEMU emulator version 9.1.0-rc1\n",
+                QemuVersion::new(9, 1, 0),
+                "9.1",
+            ),
+        ] {
+            assert_eq!(QemuVersion::parse(output), Some(version), "{output}");
+            assert_eq!(version.to_string(), shown);
         }
+        assert_eq!(QemuVersion::parse("qemu: unknown option\n"), None);
+        assert!(QemuVersion::new(4, 2, 1) < QemuVersion::new(5, 0, 0));
 
-        // For TAP networking: parse ARP table (`ip neigh`) looking for IPs on the bridge
-        let bridge_filter = match &vm.network {
-            NetworkConfig::Tap { bridge } => Some(bridge.as_str()),
-            _ => self.default_bridge.as_deref(),
+        let help = "Supported machines are:\nmicrovm              microvm (i386)\nq35                  Standard PC (Q35 + ICH9, 2009) (alias of pc-q35-8.2)\npc-q35-8.2           Standard PC (Q35 + ICH9, 2009) (default)\n";
+        assert_eq!(machine_types(help), ["microvm", "q35", "pc-q35-8.2"]);
+        let help = "Accelerators supported in QEMU binary:\ntcg\nkvm\n";
+        assert_eq!(help_list(help), ["tcg", "kvm"]);
+    }
+
+    #[tokio::test]
+    async fn probe_uses_the_cache_until_qemu_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        // Not executable, so probing fails unless the cache answers
+        let binary = dir.path().join("qemu-system-x86_64");
+        std::fs::write(&binary, "").unwrap();
+        let modified = std::fs::metadata(&binary).unwrap().modified().unwrap();
+        let info = QemuInfo {
+            version: QemuVersion::new(4, 2, 1),
+            accels: vec!["kvm".into()],
+            machine_types: vec!["q35".into()],
         };
+        let cache = HashMap::from([(
+            binary.clone(),
+            CachedQemuInfo {
+                modified,
+                info: info.clone(),
+            },
+        )]);
+        std::fs::write(
+            dir.path().join(QEMU_INFO_CACHE),
+            serde_json::to_vec(&cache).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(probe(&binary, dir.path()).await.unwrap(), info);
 
-        let output = tokio::process::Command::new("ip")
-            .args(["neigh", "show"])
-            .output()
-            .await
-            .map_err(|_| VmError::IpDiscoveryTimeout {
-                name: vm.name.clone(),
-            })?;
+        // An upgraded QEMU is run again
+        let file = std::fs::File::options().write(true).open(&binary).unwrap();
+        file.set_modified(modified + Duration::from_secs(60))
+            .unwrap();
+        assert!(probe(&binary, dir.path()).await.is_err());
+    }
 
-        let text = String::from_utf8_lossy(&output.stdout);
+    #[test]
+    fn qemu_version_gates_args() {
+        let info = |major, minor, micro| QemuInfo {
+            version: QemuVersion::new(major, minor, micro),
+            accels: vec!["tcg".into(), "kvm".into()],
+            machine_types: vec!["q35".into(), "microvm".into()],
+        };
+        let mut vm = test_handle(MachineType::Q35);
+        vm.audio = AudioBackend::Pa;
+        let args = QemuBackend::build_args(&vm, None, None, Some(&info(8, 2, 0))).unwrap();
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-sound-pci,audiodev=audio0"
+        ));
+        // QEMU before 8.2 has no virtio-sound, so the guest gets an HDA card instead
+        let args = QemuBackend::build_args(&vm, None, None, Some(&info(7, 2, 0))).unwrap();
+        assert!(has_pair(&args, "-device", "intel-hda"));
+        assert!(has_pair(&args, "-device", "hda-duplex,audiodev=audio0"));
 
-        for line in text.lines() {
-            if line.contains("REACHABLE") || line.contains("STALE") {
-                // If we have a bridge filter, only match entries on that interface
-                if let Some(br) = bridge_filter {
-                    if !line.contains(br) {
-                        continue;
-                    }
-                }
-                if let Some(ip) = line.split_whitespace().next() {
-                    // Basic IPv4 check
-                    if ip.contains('.') && !ip.starts_with("127.") {
-                        return Ok(ip.to_string());
-                    }
-                }
-            }
-        }
+        vm.audio = AudioBackend::Pipewire;
+        let msg = QemuBackend::build_args(&vm, None, None, Some(&info(7, 2, 0)))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            msg.contains("the pipewire audio backend requires QEMU ≥ 8.1, found 7.2"),
+            "got {msg}"
+        );
 
-        // Fallback: check dnsmasq leases if available
-        if bridge_filter.is_some() {
-            let leases_path = "/var/lib/misc/dnsmasq.leases";
-            if let Ok(content) = tokio::fs::read_to_string(leases_path).await {
-                // Lease format: epoch MAC IP hostname clientid
-                // Try to match by MAC address if we know it
-                if let Some(ref mac) = vm.mac_addr {
-                    for line in content.lines() {
-                        if line.contains(mac) {
-                            let parts: Vec<&str> = line.split_whitespace().collect();
-                            if parts.len() >= 3 {
-                                return Ok(parts[2].to_string());
-                            }
-                        }
-                    }
-                }
-                // Fallback to last lease
-                if let Some(line) = content.lines().last() {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    if parts.len() >= 3 {
-                        return Ok(parts[2].to_string());
-                    }
-                }
-            }
+        vm.audio = AudioBackend::None;
+        let mut old = info(4, 2, 1);
+        old.accels = vec!["tcg".into()];
+        let msg = QemuBackend::build_args(&vm, None, None, Some(&old))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            msg.contains("built without KVM (accelerators: tcg)"),
+            "got {msg}"
+        );
+        // QEMU that can't list its accelerators is taken to have KVM
+        old.accels.clear();
+        assert!(QemuBackend::build_args(&vm, None, None, Some(&old)).is_ok());
+
+        let mut vm = test_handle(MachineType::Microvm);
+        vm.kernel = Some(KernelBoot {
+            kernel: "/boot/vmlinuz".into(),
+            initrd: None,
+            cmdline: None,
+        });
+        let msg = QemuBackend::build_args(&vm, None, None, Some(&old))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            msg.contains("the microvm machine type with rtc=off requires QEMU ≥ 5.0, found 4.2.1"),
+            "got {msg}"
+        );
+        old.machine_types = vec!["q35".into()];
+        let msg = QemuBackend::build_args(&vm, None, None, Some(&old))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            msg.contains("QEMU 4.2.1 has no microvm machine type"),
+            "got {msg}"
+        );
+    }
+
+    #[test]
+    fn macvtap_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.network = NetworkConfig::Macvtap {
+            parent: "eth0".into(),
+        };
+        vm.macvtap = Some(network::macvtap_name(&vm.id));
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-netdev", "tap,id=net0,fd=3"));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-net-pci,netdev=net0,mac=52:54:00:ab:cd:ef"
+        ));
+    }
+
+    #[test]
+    fn boot_order_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(!args.iter().any(|a| a.contains("bootindex")));
+
+        vm.cdrom = Some("/tmp/installer.iso".into());
+        vm.boot_order = vec![BootDevice::Disk, BootDevice::Network];
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-blk-pci,drive=drive0,bootindex=0,id=disk0"
+        ));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "virtio-net-pci,netdev=net0,mac=52:54:00:ab:cd:ef,bootindex=1,id=nic0"
+        ));
+        assert!(has_pair(&args, "-device", "ide-cd,drive=cdrom0,bus=ide.1"));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.boot_order, vm.boot_order);
+
+        // A one-time device goes first; the rest keep their order
+        vm.boot_once = Some(BootDevice::Cdrom);
+        assert_eq!(
+            vm.start_boot_order(),
+            [BootDevice::Cdrom, BootDevice::Disk, BootDevice::Network]
+        );
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-device",
+            "ide-cd,drive=cdrom0,bus=ide.1,bootindex=0,id=cd0"
+        ));
+        vm.boot_once = Some(BootDevice::Network);
+        assert_eq!(
+            vm.start_boot_order(),
+            [BootDevice::Network, BootDevice::Disk]
+        );
+
+        // NVMe disks already have an id
+        vm.boot_once = None;
+        vm.disk_bus = DiskBus::Nvme;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-device",
+            "nvme,drive=drive0,serial=vmctl-disk0,id=disk0,bootindex=0"
+        ));
+
+        let mut vm = test_handle(MachineType::Q35);
+        for (order, expected) in [
+            (vec![BootDevice::Cdrom], "no CD-ROM ISO is attached"),
+            (
+                vec![BootDevice::Disk, BootDevice::Disk],
+                "disk is in the boot order twice",
+            ),
+        ] {
+            vm.boot_order = order;
+            let msg = QemuBackend::build_args(&vm, None, None, None)
+                .unwrap_err()
+                .to_string();
+            assert!(msg.contains(expected), "got {msg}");
         }
+        vm.boot_order = vec![BootDevice::Network];
+        vm.network = NetworkConfig::None;
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+        vm.boot_order = vec![BootDevice::Disk];
+        vm.kernel = Some(KernelBoot {
+            kernel: "/boot/vmlinuz".into(),
+            initrd: None,
+            cmdline: None,
+        });
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+    }
 
-        Err(VmError::IpDiscoveryTimeout {
-            name: vm.name.clone(),
-        })
+    #[test]
+    fn disk_bus_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        vm.disk_bus = DiskBus::Nvme;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-device",
+            "nvme,drive=drive0,serial=vmctl-disk0,id=disk0"
+        ));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.disk_bus, DiskBus::Nvme);
+
+        vm.disk_bus = DiskBus::Scsi;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-device", "virtio-scsi-pci,id=scsi0"));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "scsi-hd,drive=drive0,bus=scsi0.0,scsi-id=0,serial=vmctl-disk0,id=disk0"
+        ));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.disk_bus, DiskBus::Scsi);
+
+        let mut vm = test_handle(MachineType::Microvm);
+        vm.kernel = Some(KernelBoot {
+            kernel: "/boot/vmlinuz".into(),
+            initrd: None,
+            cmdline: None,
+        });
+        vm.disk_bus = DiskBus::Scsi;
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-device", "virtio-scsi-device,id=scsi0"));
+        vm.disk_bus = DiskBus::Nvme;
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
     }
 
-    fn console_endpoint(&self, vm: &VmHandle) -> Result<ConsoleEndpoint> {
-        match vm.console_socket {
-            Some(ref path) => Ok(ConsoleEndpoint::UnixSocket(path.clone())),
-            None => Ok(ConsoleEndpoint::None),
+    #[test]
+    fn smbios_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(!args.iter().any(|a| a == "-smbios"));
+
+        vm.smbios = SmbiosConfig {
+            manufacturer: Some("Acme, Inc.".into()),
+            serial: Some("SN-0042".into()),
+            oem_strings: vec!["tier=gold".into()],
+            identify: true,
+            ..Default::default()
+        };
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-smbios",
+            "type=1,manufacturer=Acme,, Inc.,serial=SN-0042"
+        ));
+        let oem: Vec<&str> = args
+            .windows(2)
+            .filter(|w| w[0] == "-smbios" && w[1].starts_with("type=11,"))
+            .map(|w| w[1].as_str())
+            .collect();
+        let name = format!("type=11,value=vmctl:name={}", vm.name);
+        let id = format!("type=11,value=vmctl:id={}", vm.id);
+        assert_eq!(oem, ["type=11,value=tier=gold", name.as_str(), id.as_str()]);
+
+        let mut vm = test_handle(MachineType::Microvm);
+        vm.smbios.identify = true;
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+    }
+
+    #[test]
+    fn usb_passthrough_args() {
+        let mut vm = test_handle(MachineType::Q35);
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(&args, "-device", "qemu-xhci,id=xhci"));
+
+        vm.usb_devices = vec!["1050:0407".parse().unwrap(), "3.7".parse().unwrap()];
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(has_pair(
+            &args,
+            "-device",
+            "usb-host,bus=xhci.0,vendorid=0x1050,productid=0x0407,id=usb-1050-0407"
+        ));
+        assert!(has_pair(
+            &args,
+            "-device",
+            "usb-host,bus=xhci.0,hostbus=3,hostaddr=7,id=usb-3.7"
+        ));
+        let found = handle_from_command_line("test-vm", vm.work_dir.clone(), &args);
+        assert_eq!(found.usb_devices, vm.usb_devices);
+        assert_eq!(
+            usb_device_from_id("usb-046d-c52b"),
+            "046d:c52b".parse().ok()
+        );
+        assert_eq!(usb_device_from_id("net0"), None);
+
+        let mut vm = test_handle(MachineType::Microvm);
+        let args = QemuBackend::build_args(&vm, None, None, None).unwrap();
+        assert!(!args.iter().any(|a| a.contains("xhci")));
+        vm.usb_devices = vec!["1050:0407".parse().unwrap()];
+        assert!(QemuBackend::build_args(&vm, None, None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn daemonized_spawn_reads_pidfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = QemuBackend::new(Some("sh".into()), None, None);
+        let script = format!("echo 4194304 > {}", dir.path().join("qemu.pid").display());
+        let pid = backend
+            .spawn_daemonized(dir.path(), &["-c".into(), script], None)
+            .await
+            .unwrap();
+        assert_eq!(pid, Some(4194304));
+
+        // A failed launch reports the end of QEMU's stderr and logs all of it
+        let script = "for i in $(seq 50); do echo line $i >&2; done; \
+                      echo \"qemu: could not open disk image: No such file\" >&2; exit 1";
+        let failed = backend
+            .spawn_daemonized(dir.path(), &["-c".into(), script.into()], None)
+            .await;
+        let Err(VmError::QemuSpawnFailed {
+            stderr: Some(stderr),
+            ..
+        }) = failed
+        else {
+            panic!("expected QemuSpawnFailed with stderr, got {failed:?}");
+        };
+        assert!(stderr.starts_with("[11 earlier lines in qemu-launch.log]\nline 12\n"));
+        assert!(stderr.ends_with("could not open disk image: No such file"));
+        let log = std::fs::read_to_string(dir.path().join(QEMU_LAUNCH_LOG)).unwrap();
+        assert_eq!(log.lines().count(), 51);
+
+        let failed = backend
+            .spawn_daemonized(dir.path(), &["-c".into(), "exit 1".into()], None)
+            .await;
+        assert!(matches!(
+            failed,
+            Err(VmError::QemuSpawnFailed { stderr: None, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn supervised_spawn_reaps_the_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = QemuBackend::new(Some("sh".into()), None, None);
+        let mut vm = test_handle(MachineType::Q35);
+        vm.work_dir = dir.path().to_path_buf();
+
+        // An early exit is reported with the last line QEMU logged
+        let script = "echo 'gtk initialization failed' >&2; exit 1";
+        let (_, exited) = backend
+            .spawn_supervised(&vm, &["-c".into(), script.into()], None)
+            .await
+            .unwrap();
+        let status = exited.await.unwrap();
+        assert_eq!(status.code(), Some(1));
+        let err = QemuBackend::exited_early(dir.path(), Some(status)).await;
+        assert!(
+            err.to_string().contains("gtk initialization failed"),
+            "{err}"
+        );
+
+        // A killed process is reaped, so it no longer counts as alive
+        let (pid, exited) = backend
+            .spawn_supervised(&vm, &["-c".into(), "sleep 30".into()], None)
+            .await
+            .unwrap();
+        assert!(QemuBackend::pid_alive(pid));
+        unsafe {
+            libc::kill(pid as i32, libc::SIGKILL);
         }
+        exited.await.unwrap();
+        assert!(!QemuBackend::pid_alive(pid));
     }
-}
 
-/// Search common paths for the OVMF_CODE firmware file.
-fn find_ovmf_code() -> Option<PathBuf> {
-    let candidates = [
-        "/usr/share/OVMF/OVMF_CODE.fd",
-        "/usr/share/OVMF/OVMF_CODE_4M.fd",
-        "/usr/share/ovmf/OVMF_CODE.fd",
-        "/usr/share/qemu/efi-virtio.rom",
-        "/usr/share/edk2/ovmf/OVMF_CODE.fd",
-        "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
-    ];
-    candidates.iter().map(PathBuf::from).find(|p| p.exists())
-}
+    #[tokio::test]
+    async fn list_vms_scans_work_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = QemuBackend::new(None, Some(dir.path().to_path_buf()), None);
+        assert!(backend.list_vms().await.unwrap().is_empty());
+
+        // A VM whose QEMU is gone, one that was never started, and a stray file
+        let stopped = dir.path().join("stopped");
+        std::fs::create_dir(&stopped).unwrap();
+        std::fs::write(stopped.join("qemu.pid"), "4194304").unwrap();
+        std::fs::write(stopped.join("overlay.qcow2"), "").unwrap();
+        std::fs::create_dir(dir.path().join("prepared")).unwrap();
+        std::fs::write(dir.path().join("vms.json"), "{}").unwrap();
 
-/// Search common paths for the OVMF_VARS template file.
-fn find_ovmf_vars() -> Option<PathBuf> {
-    let candidates = [
-        "/usr/share/OVMF/OVMF_VARS.fd",
-        "/usr/share/OVMF/OVMF_VARS_4M.fd",
-        "/usr/share/ovmf/OVMF_VARS.fd",
-        "/usr/share/edk2/ovmf/OVMF_VARS.fd",
-        "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd",
-    ];
-    candidates.iter().map(PathBuf::from).find(|p| p.exists())
+        let vms = backend.list_vms().await.unwrap();
+        assert_eq!(vms.len(), 1);
+        assert_eq!(vms[0].name, "stopped");
+        assert_eq!(vms[0].backend, BackendTag::Qemu);
+        assert_eq!(vms[0].pid, None);
+        assert_eq!(vms[0].overlay_path, Some(stopped.join("overlay.qcow2")));
+        assert_eq!(vms[0].seed_iso_path, None);
+
+        let missing = QemuBackend::new(None, Some(dir.path().join("missing")), None);
+        assert!(missing.list_vms().await.unwrap().is_empty());
+    }
 }
@@ -0,0 +1,730 @@
+//! Client for the QEMU guest agent (`qemu-ga`), reached through a virtio-serial channel that the
+//! QEMU backend gives every VM.
+//!
+//! The agent runs commands and writes files in the guest without any network, so it still works
+//! when the guest's networking or SSH server is broken. It has to be installed and running in the
+//! guest. The client is blocking, like [`crate::ssh`], so the provisioning engine can use it as a
+//! transport.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::Value;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use crate::error::{Result, VmError};
+use crate::types::{GuestOs, VmHandle};
+
+/// Guest agent socket in the VM's work directory.
+pub const AGENT_SOCKET: &str = "qga.sock";
+
+/// Name of the virtio-serial port the agent listens on in the guest.
+pub const AGENT_CHANNEL: &str = "org.qemu.guest_agent.0";
+
+/// How long [`GuestAgent::connect`] waits for the agent to answer. QEMU accepts the connection
+/// whether or not an agent runs in the guest, so silence is the only sign that there is none.
+pub const DEFAULT_AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often [`GuestAgent::exec`] asks whether the command has finished.
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Bytes sent per `guest-file-write`, before base64.
+const WRITE_CHUNK: usize = 48 * 1024;
+
+/// Bytes asked for per `guest-file-read`.
+const READ_CHUNK: u64 = 48 * 1024;
+
+/// Largest file [`GuestAgent::write_file`] and [`GuestAgent::read_file`] transfer. Every byte
+/// crosses the serial channel base64-encoded in a JSON message, so larger files are better
+/// copied over SSH.
+pub const MAX_TRANSFER_BYTES: u64 = 64 << 20;
+
+/// efivarfs file of the firmware's `SecureBoot` variable in Linux guests: four attribute bytes,
+/// then 1 when Secure Boot is enforced.
+const SECURE_BOOT_EFIVAR: &str =
+    "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// How a command run through the agent ended.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecOutput {
+    /// The command's exit code, or 128 plus the signal that killed it.
+    pub exit_code: i32,
+    /// Empty unless output was captured.
+    pub stdout: String,
+    pub stderr: String,
+    /// Whether the agent cut the output short (it keeps at most 16 MiB per stream).
+    pub truncated: bool,
+}
+
+/// A connection to a VM's guest agent.
+pub struct GuestAgent {
+    vm: String,
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    /// How long to wait for the reply to a command.
+    reply_timeout: Duration,
+}
+
+impl GuestAgent {
+    /// Connect to `vm`'s guest agent and check that it answers within `timeout`.
+    pub fn connect(vm: &VmHandle, timeout: Duration) -> Result<Self> {
+        let unavailable = |detail: String| VmError::GuestAgentUnavailable {
+            vm: vm.name.clone(),
+            detail,
+        };
+        let socket = vm.work_dir.join(AGENT_SOCKET);
+        let stream = UnixStream::connect(&socket).map_err(|e| {
+            unavailable(format!(
+                "cannot connect to {}: {e} (is the VM running?)",
+                socket.display()
+            ))
+        })?;
+        let writer = stream.try_clone()?;
+        let mut agent = Self {
+            vm: vm.name.clone(),
+            reader: BufReader::new(stream),
+            writer,
+            reply_timeout: timeout.max(Duration::from_secs(30)),
+        };
+
+        // Only the reply carrying our id counts: a previous client may have left replies behind.
+        agent.reader.get_ref().set_read_timeout(Some(timeout))?;
+        let id = u32::from_ne_bytes(uuid::Uuid::new_v4().as_bytes()[..4].try_into().unwrap());
+        agent.send("guest-sync", Some(serde_json::json!({ "id": id })))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let reply = agent.read_reply().map_err(|e| match e {
+                VmError::Io(ref io) if is_timeout(io) => unavailable(format!(
+                    "no answer within {timeout:?}; is qemu-guest-agent installed and running?"
+                )),
+                e => e,
+            })?;
+            if reply.get("return").and_then(Value::as_u64) == Some(u64::from(id)) {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(unavailable(format!("no answer within {timeout:?}")));
+            }
+        }
+        // Commands may take a while; `exec` polls instead of blocking on one reply
+        agent
+            .reader
+            .get_ref()
+            .set_read_timeout(Some(agent.reply_timeout))?;
+        debug!(vm = %agent.vm, "guest agent connected");
+        Ok(agent)
+    }
+
+    /// Run `cmd` with `args` in the guest and wait for it to finish. The agent starts `cmd`
+    /// directly, without a shell, so it must be a path or found in the agent's `PATH`. With
+    /// `capture_output`, its stdout and stderr are returned; otherwise they are discarded.
+    pub fn exec(&mut self, cmd: &str, args: &[String], capture_output: bool) -> Result<ExecOutput> {
+        let started = self.execute(
+            "guest-exec",
+            Some(serde_json::json!({
+                "path": cmd,
+                "arg": args,
+                "capture-output": capture_output,
+            })),
+        )?;
+        let pid = started.get("pid").and_then(Value::as_i64).ok_or_else(|| {
+            VmError::GuestAgentCommandFailed {
+                message: format!("guest-exec returned no pid: {started}"),
+            }
+        })?;
+        debug!(vm = %self.vm, cmd, pid, "guest agent started command");
+        loop {
+            let status =
+                self.execute("guest-exec-status", Some(serde_json::json!({ "pid": pid })))?;
+            if let Some(output) = exec_output(&status)? {
+                debug!(vm = %self.vm, cmd, exit_code = output.exit_code, "guest agent command finished");
+                return Ok(output);
+            }
+            std::thread::sleep(EXEC_POLL_INTERVAL);
+        }
+    }
+
+    /// Write `contents` to `path` in the guest, replacing the file if it exists.
+    pub fn write_file(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        self.write_file_with_progress(path, contents, |_, _| {})
+    }
+
+    /// [`write_file`](Self::write_file), calling `progress` with the bytes written so far and
+    /// the total after each chunk. Files over [`MAX_TRANSFER_BYTES`] are refused. A failure
+    /// part-way leaves a truncated file in the guest.
+    pub fn write_file_with_progress(
+        &mut self,
+        path: &Path,
+        contents: &[u8],
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<()> {
+        let total = contents.len() as u64;
+        check_size(path, total)?;
+        let handle = self.open_file(path, "w")?;
+        let written = (|| -> Result<()> {
+            let mut done = 0;
+            for chunk in contents.chunks(WRITE_CHUNK) {
+                // The agent may write less than it was sent; send the rest again
+                let mut rest = chunk;
+                while !rest.is_empty() {
+                    let reply = self.execute(
+                        "guest-file-write",
+                        Some(
+                            serde_json::json!({ "handle": handle, "buf-b64": BASE64.encode(rest) }),
+                        ),
+                    )?;
+                    let count = reply.get("count").and_then(Value::as_u64).unwrap_or(0) as usize;
+                    if count == 0 {
+                        return Err(VmError::GuestAgentCommandFailed {
+                            message: format!(
+                                "guest-file-write: nothing written to {} after {done} bytes",
+                                path.display()
+                            ),
+                        });
+                    }
+                    let count = count.min(rest.len());
+                    rest = &rest[count..];
+                    done += count as u64;
+                }
+                progress(done, total);
+            }
+            Ok(())
+        })();
+        let closed = self.close_file(handle);
+        written?;
+        closed?;
+        debug!(vm = %self.vm, path = %path.display(), bytes = total, "guest agent wrote file");
+        Ok(())
+    }
+
+    /// Read the file at `path` in the guest.
+    pub fn read_file(&mut self, path: &Path) -> Result<Vec<u8>> {
+        self.read_file_with_progress(path, |_, _| {})
+    }
+
+    /// [`read_file`](Self::read_file), calling `progress` with the bytes read so far and the
+    /// file's size after each chunk. Files over [`MAX_TRANSFER_BYTES`] are refused.
+    pub fn read_file_with_progress(
+        &mut self,
+        path: &Path,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<Vec<u8>> {
+        let handle = self.open_file(path, "r")?;
+        let read = (|| -> Result<Vec<u8>> {
+            let total = self.file_size(handle)?;
+            check_size(path, total)?;
+            let mut contents = Vec::with_capacity(total as usize);
+            loop {
+                let reply = self.execute(
+                    "guest-file-read",
+                    Some(serde_json::json!({ "handle": handle, "count": READ_CHUNK })),
+                )?;
+                if let Some(data) = reply.get("buf-b64").and_then(Value::as_str) {
+                    let bytes =
+                        BASE64
+                            .decode(data)
+                            .map_err(|e| VmError::GuestAgentCommandFailed {
+                                message: format!("invalid base64 from guest-file-read: {e}"),
+                            })?;
+                    contents.extend_from_slice(&bytes);
+                }
+                // The file may grow while it is read
+                let done = contents.len() as u64;
+                check_size(path, done)?;
+                progress(done, total.max(done));
+                let eof = reply.get("eof").and_then(Value::as_bool).unwrap_or(true);
+                let count = reply.get("count").and_then(Value::as_u64).unwrap_or(0);
+                if eof || count == 0 {
+                    return Ok(contents);
+                }
+            }
+        })();
+        let closed = self.close_file(handle);
+        let contents = read?;
+        closed?;
+        debug!(vm = %self.vm, path = %path.display(), bytes = contents.len(), "guest agent read file");
+        Ok(contents)
+    }
+
+    /// Step the guest's clock to `time` and, where the guest can, its hardware clock too.
+    pub fn set_time(&mut self, time: SystemTime) -> Result<()> {
+        let nanos = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        self.execute("guest-set-time", Some(serde_json::json!({ "time": nanos })))?;
+        debug!(vm = %self.vm, "guest agent set the guest clock");
+        Ok(())
+    }
+
+    /// Whether the guest booted with Secure Boot enforced. Linux guests report their `SecureBoot`
+    /// EFI variable, Windows guests `Confirm-SecureBootUEFI`. efivarfs files can't be seeked,
+    /// which `read_file` needs, so the variable is read with `od`.
+    pub fn secure_boot(&mut self, guest_os: GuestOs) -> Result<bool> {
+        let (cmd, args): (&str, &[&str]) = match guest_os {
+            GuestOs::Linux => ("od", &["-An", "-tu1", "-j4", "-N1", SECURE_BOOT_EFIVAR]),
+            GuestOs::Windows => (
+                "powershell.exe",
+                &["-NoProfile", "-Command", "Confirm-SecureBootUEFI"],
+            ),
+        };
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let output = self.exec(cmd, &args, true)?;
+        if output.exit_code != 0 {
+            // No SecureBoot variable: the guest did not boot with UEFI
+            return Ok(false);
+        }
+        let answer = output.stdout.trim();
+        Ok(answer == "1" || answer.eq_ignore_ascii_case("true"))
+    }
+
+    fn open_file(&mut self, path: &Path, mode: &str) -> Result<i64> {
+        let handle = self.execute(
+            "guest-file-open",
+            Some(serde_json::json!({ "path": path, "mode": mode })),
+        )?;
+        handle
+            .as_i64()
+            .ok_or_else(|| VmError::GuestAgentCommandFailed {
+                message: format!("guest-file-open returned no handle: {handle}"),
+            })
+    }
+
+    fn close_file(&mut self, handle: i64) -> Result<()> {
+        self.execute(
+            "guest-file-close",
+            Some(serde_json::json!({ "handle": handle })),
+        )
+        .map(drop)
+    }
+
+    /// Size of the open file `handle`, leaving its position at the start.
+    fn file_size(&mut self, handle: i64) -> Result<u64> {
+        let end = self.execute(
+            "guest-file-seek",
+            Some(serde_json::json!({ "handle": handle, "offset": 0, "whence": "end" })),
+        )?;
+        self.execute(
+            "guest-file-seek",
+            Some(serde_json::json!({ "handle": handle, "offset": 0, "whence": "set" })),
+        )?;
+        Ok(end.get("position").and_then(Value::as_u64).unwrap_or(0))
+    }
+
+    /// Run an agent command and return its `return` value.
+    fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        self.send(command, arguments)?;
+        let reply = self.read_reply().map_err(|e| match e {
+            VmError::Io(ref io) if is_timeout(io) => VmError::GuestAgentCommandFailed {
+                message: format!(
+                    "{command}: no answer from the guest agent within {:?}",
+                    self.reply_timeout
+                ),
+            },
+            e => e,
+        })?;
+        if let Some(err) = reply.get("error") {
+            let class = err.get("class").and_then(Value::as_str).unwrap_or_default();
+            let desc = err.get("desc").and_then(Value::as_str).unwrap_or_default();
+            // Commands can be disabled in the agent's configuration
+            if class == "CommandNotFound" || desc.contains("has been disabled") {
+                return Err(VmError::GuestAgentUnavailable {
+                    vm: self.vm.clone(),
+                    detail: format!("the agent does not allow {command}: {desc}"),
+                });
+            }
+            return Err(VmError::GuestAgentCommandFailed {
+                message: format!("{command}: {desc}"),
+            });
+        }
+        Ok(reply.get("return").cloned().unwrap_or(Value::Null))
+    }
+
+    fn send(&mut self, command: &str, arguments: Option<Value>) -> Result<()> {
+        let mut cmd = serde_json::json!({ "execute": command });
+        if let Some(args) = arguments {
+            cmd["arguments"] = args;
+        }
+        trace!(cmd = %cmd, "guest agent send");
+        let mut line = cmd.to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Read the next reply, skipping the 0xff sync markers and blank lines the agent may send.
+    fn read_reply(&mut self) -> Result<Value> {
+        loop {
+            let mut buf = Vec::new();
+            if self.reader.read_until(b'\n', &mut buf)? == 0 {
+                return Err(VmError::GuestAgentCommandFailed {
+                    message: "guest agent connection closed".into(),
+                });
+            }
+            buf.retain(|&b| b != 0xff);
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            trace!(reply = %line, "guest agent recv");
+            return serde_json::from_str(line).map_err(|e| VmError::GuestAgentCommandFailed {
+                message: format!("invalid reply {line:?}: {e}"),
+            });
+        }
+    }
+}
+
+/// Connect to `vm`'s guest agent, retrying until `timeout` elapses while the guest boots and
+/// starts the agent. Gives up with `Cancelled` as soon as `cancel` fires.
+pub async fn connect_with_retry(
+    vm: &VmHandle,
+    timeout: Duration,
+    cancel: &CancellationToken,
+) -> Result<GuestAgent> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut attempt: u32 = 0;
+    loop {
+        attempt += 1;
+        let handle = vm.clone();
+        let connecting = tokio::task::spawn_blocking(move || {
+            GuestAgent::connect(&handle, DEFAULT_AGENT_TIMEOUT)
+        });
+        let result = tokio::select! {
+            result = connecting => result.map_err(|e| VmError::GuestAgentCommandFailed {
+                message: format!("connect task panicked: {e}"),
+            })?,
+            _ = cancel.cancelled() => {
+                return Err(VmError::Cancelled {
+                    operation: format!("guest agent connection to VM '{}'", vm.name),
+                });
+            }
+        };
+        match result {
+            Ok(agent) => return Ok(agent),
+            Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+            Err(e) => {
+                debug!(vm = %vm.name, attempt, error = %e, "guest agent not ready; retrying");
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            _ = cancel.cancelled() => {}
+        }
+    }
+}
+
+/// Run `cmd` with `args` in `vm` through its guest agent. See [`GuestAgent::exec`].
+pub fn exec(vm: &VmHandle, cmd: &str, args: &[String], capture_output: bool) -> Result<ExecOutput> {
+    GuestAgent::connect(vm, DEFAULT_AGENT_TIMEOUT)?.exec(cmd, args, capture_output)
+}
+
+/// Whether `err` is a socket read that ran into its timeout.
+fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Refuse to transfer `path` if it is `size` bytes and that is over [`MAX_TRANSFER_BYTES`].
+fn check_size(path: &Path, size: u64) -> Result<()> {
+    if size > MAX_TRANSFER_BYTES {
+        return Err(VmError::GuestAgentCommandFailed {
+            message: format!(
+                "{} is {size} bytes, more than the guest agent transfer limit of {} MiB; copy it over SSH instead",
+                path.display(),
+                MAX_TRANSFER_BYTES >> 20
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Set `vm`'s clock to the host's through its guest agent, waiting at most `timeout` for the
+/// agent. See [`GuestAgent::set_time`].
+pub fn sync_time(vm: &VmHandle, timeout: Duration) -> Result<()> {
+    GuestAgent::connect(vm, timeout)?.set_time(SystemTime::now())
+}
+
+/// Whether `vm` booted with Secure Boot enforced, asking its guest agent and waiting at most
+/// `timeout` for it. See [`GuestAgent::secure_boot`].
+pub fn secure_boot(vm: &VmHandle, timeout: Duration) -> Result<bool> {
+    GuestAgent::connect(vm, timeout)?.secure_boot(vm.guest_os)
+}
+
+/// The outcome in a `guest-exec-status` reply, or `None` while the command runs.
+fn exec_output(status: &Value) -> Result<Option<ExecOutput>> {
+    if !status
+        .get("exited")
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+    {
+        return Ok(None);
+    }
+    let decode = |key: &str| -> Result<String> {
+        let Some(data) = status.get(key).and_then(Value::as_str) else {
+            return Ok(String::new());
+        };
+        let bytes = BASE64
+            .decode(data)
+            .map_err(|e| VmError::GuestAgentCommandFailed {
+                message: format!("invalid base64 in {key}: {e}"),
+            })?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    };
+    let exit_code = match status.get("signal").and_then(Value::as_i64) {
+        Some(signal) => 128 + signal as i32,
+        None => status.get("exitcode").and_then(Value::as_i64).unwrap_or(0) as i32,
+    };
+    let truncated = |key: &str| status.get(key).and_then(Value::as_bool).unwrap_or(false);
+    Ok(Some(ExecOutput {
+        exit_code,
+        stdout: decode("out-data")?,
+        stderr: decode("err-data")?,
+        truncated: truncated("out-truncated") || truncated("err-truncated"),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_status_decodes_output() {
+        let running = serde_json::json!({ "exited": false });
+        assert_eq!(exec_output(&running).unwrap(), None);
+
+        let done = serde_json::json!({
+            "exited": true,
+            "exitcode": 3,
+            "out-data": BASE64.encode("hello\n"),
+            "err-data": BASE64.encode("oops\n"),
+        });
+        let output = exec_output(&done).unwrap().unwrap();
+        assert_eq!(output.exit_code, 3);
+        assert_eq!(output.stdout, "hello\n");
+        assert_eq!(output.stderr, "oops\n");
+        assert!(!output.truncated);
+
+        let killed = serde_json::json!({ "exited": true, "signal": 9, "out-truncated": true });
+        let output = exec_output(&killed).unwrap().unwrap();
+        assert_eq!(output.exit_code, 137);
+        assert_eq!(output.stdout, "");
+        assert!(output.truncated);
+    }
+
+    /// A fake agent on `socket` that answers `guest-sync` and runs every command through
+    /// `respond`, or stays silent when `silent`.
+    fn fake_agent(
+        socket: &Path,
+        silent: bool,
+        respond: impl Fn(&Value) -> Value + Send + 'static,
+    ) -> std::thread::JoinHandle<()> {
+        let listener = std::os::unix::net::UnixListener::bind(socket).unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else { return };
+                let cmd: Value = serde_json::from_str(&line).unwrap();
+                if silent {
+                    continue;
+                }
+                let reply = match cmd["execute"].as_str().unwrap() {
+                    // A sync marker and a stale reply first, as a previous client may have left
+                    "guest-sync" => [
+                        &[0xff][..],
+                        format!(
+                            "{{\"return\": 1}}\n{{\"return\": {}}}\n",
+                            cmd["arguments"]["id"]
+                        )
+                        .as_bytes(),
+                    ]
+                    .concat(),
+                    _ => format!("{}\n", respond(&cmd)).into_bytes(),
+                };
+                writer.write_all(&reply).unwrap();
+            }
+        })
+    }
+
+    fn handle(dir: &Path) -> VmHandle {
+        serde_json::from_value(serde_json::json!({
+            "id": "qemu-1",
+            "name": "web",
+            "backend": "qemu",
+            "work_dir": dir,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn exec_polls_until_the_command_exits() {
+        let dir = tempfile::tempdir().unwrap();
+        let polls = std::sync::atomic::AtomicUsize::new(0);
+        let agent = fake_agent(&dir.path().join(AGENT_SOCKET), false, move |cmd| {
+            match cmd["execute"].as_str().unwrap() {
+                "guest-exec" => {
+                    assert_eq!(cmd["arguments"]["path"], "/bin/echo");
+                    assert_eq!(cmd["arguments"]["arg"], serde_json::json!(["hi"]));
+                    serde_json::json!({ "return": { "pid": 42 } })
+                }
+                "guest-exec-status"
+                    if polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 =>
+                {
+                    serde_json::json!({ "return": { "exited": false } })
+                }
+                "guest-exec-status" => serde_json::json!({ "return": {
+                    "exited": true, "exitcode": 0, "out-data": BASE64.encode("hi\n")
+                }}),
+                "guest-file-open" => serde_json::json!({ "error": {
+                    "class": "CommandNotFound", "desc": "command guest-file-open has been disabled"
+                }}),
+                other => panic!("unexpected command {other}"),
+            }
+        });
+
+        let vm = handle(dir.path());
+        let mut client = GuestAgent::connect(&vm, Duration::from_secs(2)).unwrap();
+        let output = client.exec("/bin/echo", &["hi".into()], true).unwrap();
+        assert_eq!((output.exit_code, output.stdout.as_str()), (0, "hi\n"));
+
+        match client.write_file(Path::new("/tmp/x"), b"x") {
+            Err(VmError::GuestAgentUnavailable { vm, detail }) => {
+                assert_eq!(vm, "web");
+                assert!(detail.contains("guest-file-open"), "{detail}");
+            }
+            other => panic!("expected GuestAgentUnavailable, got {other:?}"),
+        }
+        drop(client);
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn file_transfer_resends_short_writes() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempfile::tempdir().unwrap();
+        // One file in the guest, with a position; writes take at most 1000 bytes at a time.
+        let file = Arc::new(Mutex::new((Vec::<u8>::new(), 0usize)));
+        let guest = file.clone();
+        let agent = fake_agent(&dir.path().join(AGENT_SOCKET), false, move |cmd| {
+            let mut file = guest.lock().unwrap();
+            let args = &cmd["arguments"];
+            let reply = match cmd["execute"].as_str().unwrap() {
+                "guest-file-open" if args["path"] == "/missing" => {
+                    return serde_json::json!({ "error": {
+                        "class": "GenericError", "desc": "No such file or directory"
+                    }});
+                }
+                "guest-file-open" => {
+                    if args["mode"] == "w" {
+                        file.0.clear();
+                    }
+                    file.1 = 0;
+                    serde_json::json!(7)
+                }
+                "guest-file-write" => {
+                    assert_eq!(args["handle"], 7);
+                    let data = BASE64.decode(args["buf-b64"].as_str().unwrap()).unwrap();
+                    let count = data.len().min(1000);
+                    file.0.extend_from_slice(&data[..count]);
+                    serde_json::json!({ "count": count, "eof": false })
+                }
+                "guest-file-seek" => {
+                    file.1 = if args["whence"] == "end" {
+                        file.0.len()
+                    } else {
+                        0
+                    };
+                    serde_json::json!({ "position": file.1, "eof": false })
+                }
+                "guest-file-read" => {
+                    let end = (file.1 + args["count"].as_u64().unwrap() as usize).min(file.0.len());
+                    let data = file.0[file.1..end].to_vec();
+                    file.1 = end;
+                    serde_json::json!({
+                        "count": data.len(),
+                        "buf-b64": BASE64.encode(&data),
+                        "eof": end == file.0.len(),
+                    })
+                }
+                "guest-file-close" => serde_json::json!({}),
+                other => panic!("unexpected command {other}"),
+            };
+            serde_json::json!({ "return": reply })
+        });
+
+        let vm = handle(dir.path());
+        let mut client = GuestAgent::connect(&vm, Duration::from_secs(2)).unwrap();
+        let contents: Vec<u8> = (0..=255u8).cycle().take(WRITE_CHUNK * 2 + 123).collect();
+        let mut reported = Vec::new();
+        client
+            .write_file_with_progress(Path::new("/etc/app.conf"), &contents, |done, total| {
+                reported.push((done, total))
+            })
+            .unwrap();
+        assert_eq!(file.lock().unwrap().0, contents);
+        let total = contents.len() as u64;
+        assert_eq!(reported.last(), Some(&(total, total)));
+        assert_eq!(reported.len(), 3);
+
+        let read = client.read_file(Path::new("/etc/app.conf")).unwrap();
+        assert_eq!(read, contents);
+
+        let err = client.read_file(Path::new("/missing")).unwrap_err();
+        assert!(err.to_string().contains("No such file"), "{err}");
+
+        let too_big = vec![0; MAX_TRANSFER_BYTES as usize + 1];
+        let err = client.write_file(Path::new("/big"), &too_big).unwrap_err();
+        assert!(err.to_string().contains("transfer limit"), "{err}");
+
+        drop(client);
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn set_time_sends_nanoseconds() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent = fake_agent(&dir.path().join(AGENT_SOCKET), false, |cmd| {
+            assert_eq!(cmd["execute"], "guest-set-time");
+            assert_eq!(cmd["arguments"]["time"], 1_700_000_000_123_456_789u64);
+            serde_json::json!({ "return": {} })
+        });
+        let vm = handle(dir.path());
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        GuestAgent::connect(&vm, Duration::from_secs(5))
+            .unwrap()
+            .set_time(time)
+            .unwrap();
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn silent_agent_is_unavailable() {
+        let dir = tempfile::tempdir().unwrap();
+        let _agent = fake_agent(&dir.path().join(AGENT_SOCKET), true, |_| Value::Null);
+        let vm = handle(dir.path());
+        match GuestAgent::connect(&vm, Duration::from_millis(200)) {
+            Err(VmError::GuestAgentUnavailable { detail, .. }) => {
+                assert!(detail.contains("qemu-guest-agent"), "{detail}");
+            }
+            Err(e) => panic!("expected GuestAgentUnavailable, got {e:?}"),
+            Ok(_) => panic!("expected GuestAgentUnavailable, got a connection"),
+        }
+
+        // No socket at all: the VM isn't running
+        let missing = handle(&dir.path().join("missing"));
+        assert!(matches!(
+            GuestAgent::connect(&missing, Duration::from_millis(200)),
+            Err(VmError::GuestAgentUnavailable { .. })
+        ));
+    }
+}
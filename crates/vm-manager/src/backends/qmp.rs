@@ -6,9 +6,13 @@
 //! 2. Client sends `{"execute": "qmp_capabilities"}`
 //! 3. Server responds `{"return": {}}`
 //! 4. Client sends commands, server sends responses and events.
+//!
+//! [`QmpPool`] keeps sessions open between operations on the same VM within a process.
 
-use std::path::Path;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
 use serde_json::Value;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
@@ -17,10 +21,163 @@ use tracing::{debug, info, trace};
 
 use crate::error::{Result, VmError};
 
+/// I/O totals of one block device, from `query-blockstats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockStats {
+    /// Drive id, e.g. [`ROOT_DRIVE`](super::qemu::ROOT_DRIVE).
+    pub device: String,
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+}
+
+/// Guest RAM, from `query-memory-size-summary`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySizeSummary {
+    /// RAM the guest started with, in bytes.
+    pub base_memory: u64,
+    /// RAM hotplugged since, in bytes; 0 without memory hotplug.
+    pub plugged_memory: u64,
+}
+
+impl MemorySizeSummary {
+    /// All the RAM the guest has, in bytes.
+    pub fn total(&self) -> u64 {
+        self.base_memory + self.plugged_memory
+    }
+}
+
+/// SEV state of a confidential guest, from `query-sev`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SevInfo {
+    /// Launch policy the guest was started with.
+    pub policy: u32,
+    /// SEV firmware API version, `major.minor`.
+    pub api_version: String,
+    /// SEV firmware build.
+    pub build_id: u64,
+    /// Guest state in the SEV firmware, e.g. `running`.
+    pub state: String,
+}
+
+/// A key for [`QmpClient::send_key`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyValue {
+    /// A QEMU key code such as `ret`, `ctrl` or `f12` (see QEMU's `QKeyCode`).
+    QCode(String),
+    /// A raw scancode.
+    Number(u32),
+}
+
+impl std::str::FromStr for KeyValue {
+    type Err = String;
+
+    /// A QCode (`ret`, `shift_r`), or a decimal or `0x` hex scancode.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let number = match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+        if let Some(n) = number {
+            return Ok(Self::Number(n));
+        }
+        if !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Ok(Self::QCode(s.into()));
+        }
+        Err(format!(
+            "invalid key '{s}': expected a QEMU key code like `ret` or a scancode"
+        ))
+    }
+}
+
+impl KeyValue {
+    fn qcode(code: &str) -> Self {
+        Self::QCode(code.into())
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Self::QCode(code) => serde_json::json!({ "type": "qcode", "data": code }),
+            Self::Number(n) => serde_json::json!({ "type": "number", "data": n }),
+        }
+    }
+
+    /// The keys to press together to type `c` on a US keyboard layout, e.g. `shift` + `a` for
+    /// `A`. `None` for characters without a key (non-ASCII, most control characters).
+    pub fn for_char(c: char) -> Option<Vec<KeyValue>> {
+        const SHIFTED: &[(char, &str)] = &[
+            ('!', "1"),
+            ('@', "2"),
+            ('#', "3"),
+            ('$', "4"),
+            ('%', "5"),
+            ('^', "6"),
+            ('&', "7"),
+            ('*', "8"),
+            ('(', "9"),
+            (')', "0"),
+            ('_', "minus"),
+            ('+', "equal"),
+            ('{', "bracket_left"),
+            ('}', "bracket_right"),
+            ('|', "backslash"),
+            (':', "semicolon"),
+            ('"', "apostrophe"),
+            ('~', "grave_accent"),
+            ('<', "comma"),
+            ('>', "dot"),
+            ('?', "slash"),
+        ];
+        let plain = match c {
+            'a'..='z' | '0'..='9' => Some(c.to_string()),
+            'A'..='Z' => None,
+            ' ' => Some("spc".into()),
+            '\n' => Some("ret".into()),
+            '\t' => Some("tab".into()),
+            '-' => Some("minus".into()),
+            '=' => Some("equal".into()),
+            '[' => Some("bracket_left".into()),
+            ']' => Some("bracket_right".into()),
+            '\\' => Some("backslash".into()),
+            ';' => Some("semicolon".into()),
+            '\'' => Some("apostrophe".into()),
+            '`' => Some("grave_accent".into()),
+            ',' => Some("comma".into()),
+            '.' => Some("dot".into()),
+            '/' => Some("slash".into()),
+            _ => None,
+        };
+        if let Some(code) = plain {
+            return Some(vec![Self::QCode(code)]);
+        }
+        let shifted = if c.is_ascii_uppercase() {
+            c.to_ascii_lowercase().to_string()
+        } else {
+            SHIFTED.iter().find(|(ch, _)| *ch == c)?.1.to_string()
+        };
+        Some(vec![Self::qcode("shift"), Self::QCode(shifted)])
+    }
+}
+
+/// `input-send-event` arguments that press `keys` in order and release them in reverse.
+fn key_events(keys: &[KeyValue]) -> Value {
+    let event = |key: &KeyValue, down: bool| serde_json::json!({ "type": "key", "data": { "down": down, "key": key.to_json() } });
+    let events: Vec<Value> = keys
+        .iter()
+        .map(|k| event(k, true))
+        .chain(keys.iter().rev().map(|k| event(k, false)))
+        .collect();
+    serde_json::json!({ "events": events })
+}
+
 /// A connected QMP client for a single QEMU instance.
 pub struct QmpClient {
     reader: BufReader<tokio::io::ReadHalf<UnixStream>>,
     writer: tokio::io::WriteHalf<UnixStream>,
+    /// Set once the connection fails; QEMU error replies leave it usable.
+    broken: bool,
 }
 
 impl QmpClient {
@@ -52,20 +209,27 @@ impl QmpClient {
         let mut client = Self {
             reader: BufReader::new(read_half),
             writer: write_half,
+            broken: false,
         };
 
-        // Read the QMP greeting
-        let greeting = client.read_response().await?;
-        debug!(greeting = %greeting, "QMP greeting received");
+        // QEMU serves one QMP client at a time and only greets the next once the current one
+        // disconnects, so don't wait forever for the greeting.
+        let negotiate = async {
+            // Read the QMP greeting
+            let greeting = client.read_response().await?;
+            debug!(greeting = %greeting, "QMP greeting received");
 
-        // Negotiate capabilities
-        client.send_command("qmp_capabilities", None).await?;
-        let resp = client.read_response().await?;
-        if resp.get("error").is_some() {
-            return Err(VmError::QmpCommandFailed {
-                message: format!("qmp_capabilities failed: {resp}"),
-            });
-        }
+            // Negotiate capabilities
+            client.execute("qmp_capabilities", None).await
+        };
+        tokio::time::timeout(timeout, negotiate)
+            .await
+            .map_err(|_| VmError::QmpCommandFailed {
+                message: format!(
+                    "no QMP greeting from {} within {timeout:?}; another client may be connected",
+                    socket_path.display()
+                ),
+            })??;
 
         debug!(path = %socket_path.display(), "QMP connected and negotiated");
         Ok(client)
@@ -84,23 +248,58 @@ impl QmpClient {
         })?;
         line.push('\n');
         trace!(cmd = %line.trim(), "QMP send");
-        self.writer
-            .write_all(line.as_bytes())
-            .await
-            .map_err(|e| VmError::QmpCommandFailed {
+        let written = async {
+            self.writer.write_all(line.as_bytes()).await?;
+            self.writer.flush().await
+        }
+        .await;
+        written.map_err(|e| {
+            self.broken = true;
+            VmError::QmpCommandFailed {
                 message: format!("write failed: {e}"),
-            })?;
-        self.writer
-            .flush()
-            .await
-            .map_err(|e| VmError::QmpCommandFailed {
-                message: format!("flush failed: {e}"),
-            })?;
-        Ok(())
+            }
+        })
     }
 
     /// Read the next JSON response (skipping asynchronous events).
     async fn read_response(&mut self) -> Result<Value> {
+        let result = self.read_response_inner().await;
+        if result.is_err() {
+            self.broken = true;
+        }
+        result
+    }
+
+    async fn read_response_inner(&mut self) -> Result<Value> {
+        loop {
+            let val = self.read_message().await?;
+
+            // Skip async events (they have an "event" key)
+            if val.get("event").is_some() {
+                debug!(event = %val, "QMP async event (skipped)");
+                continue;
+            }
+
+            return Ok(val);
+        }
+    }
+
+    /// Wait for the next asynchronous event, such as `SHUTDOWN` or `STOP`, skipping command
+    /// responses. Fails once QEMU closes the connection, e.g. because it exited.
+    pub async fn next_event(&mut self) -> Result<Value> {
+        loop {
+            let val = self
+                .read_message()
+                .await
+                .inspect_err(|_| self.broken = true)?;
+            if val.get("event").is_some() {
+                return Ok(val);
+            }
+        }
+    }
+
+    /// Read the next JSON message, response or event.
+    async fn read_message(&mut self) -> Result<Value> {
         loop {
             let mut line = String::new();
             let n =
@@ -120,34 +319,26 @@ impl QmpClient {
                 continue;
             }
             trace!(resp = %line, "QMP recv");
-            let val: Value = serde_json::from_str(line).map_err(|e| VmError::QmpCommandFailed {
+            return serde_json::from_str(line).map_err(|e| VmError::QmpCommandFailed {
                 message: format!("JSON parse failed: {e}: {line}"),
-            })?;
-
-            // Skip async events (they have an "event" key)
-            if val.get("event").is_some() {
-                debug!(event = %val, "QMP async event (skipped)");
-                continue;
-            }
-
-            return Ok(val);
+            });
         }
     }
 
-    /// Execute a QMP command and return the response.
+    /// Execute a QMP command and return the response. An error reply from QEMU becomes
+    /// [`VmError::QmpError`].
     async fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
         self.send_command(command, arguments).await?;
-        self.read_response().await
+        let resp = self.read_response().await?;
+        match resp.get("error") {
+            Some(error) => Err(qmp_error(command, error)),
+            None => Ok(resp),
+        }
     }
 
     /// Send an ACPI system_powerdown event (graceful shutdown).
     pub async fn system_powerdown(&mut self) -> Result<()> {
-        let resp = self.execute("system_powerdown", None).await?;
-        if resp.get("error").is_some() {
-            return Err(VmError::QmpCommandFailed {
-                message: format!("system_powerdown: {resp}"),
-            });
-        }
+        self.execute("system_powerdown", None).await?;
         info!("QMP: system_powerdown sent");
         Ok(())
     }
@@ -162,36 +353,178 @@ impl QmpClient {
 
     /// Pause VM execution (freeze vCPUs).
     pub async fn stop(&mut self) -> Result<()> {
-        let resp = self.execute("stop", None).await?;
-        if resp.get("error").is_some() {
-            return Err(VmError::QmpCommandFailed {
-                message: format!("stop: {resp}"),
-            });
-        }
+        self.execute("stop", None).await?;
         info!("QMP: stop (pause) sent");
         Ok(())
     }
 
     /// Resume VM execution.
     pub async fn cont(&mut self) -> Result<()> {
-        let resp = self.execute("cont", None).await?;
-        if resp.get("error").is_some() {
-            return Err(VmError::QmpCommandFailed {
-                message: format!("cont: {resp}"),
-            });
-        }
+        self.execute("cont", None).await?;
         info!("QMP: cont (resume) sent");
         Ok(())
     }
 
+    /// Grow the block device `device` (e.g. `"drive0"`) and its image file to `new_size_bytes`.
+    ///
+    /// The guest sees the new capacity immediately if its driver supports online resize
+    /// (virtio-blk on Linux does).
+    pub async fn block_resize(&mut self, device: &str, new_size_bytes: u64) -> Result<()> {
+        let args = serde_json::json!({ "device": device, "size": new_size_bytes });
+        self.execute("block_resize", Some(args)).await?;
+        info!(device, new_size_bytes, "QMP: block_resize sent");
+        Ok(())
+    }
+
+    /// Press `keys` together (e.g. `ctrl`, `alt`, `delete`) and release them, as if typed on the
+    /// guest's keyboard.
+    pub async fn send_key(&mut self, keys: &[KeyValue]) -> Result<()> {
+        self.execute("input-send-event", Some(key_events(keys)))
+            .await?;
+        debug!(?keys, "QMP: keys sent");
+        Ok(())
+    }
+
+    /// Run a human monitor (HMP) command, for features QMP lacks, and return its output.
+    ///
+    /// HMP reports most failures as output rather than as an error reply, so callers of commands
+    /// that normally print nothing should treat any output as a failure.
+    pub async fn human_monitor_command(&mut self, command: &str) -> Result<String> {
+        let args = serde_json::json!({ "command-line": command });
+        let resp = self.execute("human-monitor-command", Some(args)).await?;
+        debug!(command, "QMP: human monitor command sent");
+        Ok(resp
+            .get("return")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    /// Hot-plug a device, e.g. `{"driver": "usb-host", "id": "usb0", ...}`.
+    pub async fn device_add(&mut self, device: Value) -> Result<()> {
+        self.execute("device_add", Some(device)).await?;
+        info!("QMP: device_add sent");
+        Ok(())
+    }
+
+    /// Unplug the device with id `id`. Fails with class `DeviceNotFound` if there is none.
+    pub async fn device_del(&mut self, id: &str) -> Result<()> {
+        let args = serde_json::json!({ "id": id });
+        self.execute("device_del", Some(args)).await?;
+        info!(id, "QMP: device_del sent");
+        Ok(())
+    }
+
+    /// Names and types of the children of QOM object `path`, e.g. `("usb0", "child<usb-host>")`
+    /// for `/machine/peripheral`.
+    pub async fn qom_list(&mut self, path: &str) -> Result<Vec<(String, String)>> {
+        let args = serde_json::json!({ "path": path });
+        let resp = self.execute("qom-list", Some(args)).await?;
+        let properties = resp.get("return").and_then(|v| v.as_array());
+        Ok(properties
+            .into_iter()
+            .flatten()
+            .filter_map(|p| {
+                let name = p.get("name")?.as_str()?;
+                let kind = p.get("type")?.as_str()?;
+                Some((name.to_string(), kind.to_string()))
+            })
+            .collect())
+    }
+
+    /// Set property `property` of QOM object `path` to `value`.
+    pub async fn qom_set(&mut self, path: &str, property: &str, value: Value) -> Result<()> {
+        let args = serde_json::json!({ "path": path, "property": property, "value": value });
+        self.execute("qom-set", Some(args)).await?;
+        debug!(path, property, "QMP: qom-set sent");
+        Ok(())
+    }
+
+    /// Bytes read from and written to each block device since QEMU started.
+    pub async fn query_blockstats(&mut self) -> Result<Vec<BlockStats>> {
+        let resp = self.execute("query-blockstats", None).await?;
+        let entries = resp.get("return").and_then(|v| v.as_array());
+        Ok(entries
+            .into_iter()
+            .flatten()
+            .map(|entry| BlockStats {
+                device: entry
+                    .get("device")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                rd_bytes: entry
+                    .pointer("/stats/rd_bytes")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(0),
+                wr_bytes: entry
+                    .pointer("/stats/wr_bytes")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(0),
+            })
+            .collect())
+    }
+
+    /// Host thread id of each vCPU, from `query-cpus-fast`.
+    pub async fn query_vcpu_threads(&mut self) -> Result<Vec<u32>> {
+        let resp = self.execute("query-cpus-fast", None).await?;
+        let cpus = resp.get("return").and_then(|v| v.as_array());
+        Ok(cpus
+            .into_iter()
+            .flatten()
+            .filter_map(|cpu| cpu.get("thread-id")?.as_u64())
+            .map(|id| id as u32)
+            .collect())
+    }
+
+    /// The guest's RAM, from `query-memory-size-summary`.
+    pub async fn query_memory_size_summary(&mut self) -> Result<MemorySizeSummary> {
+        let resp = self.execute("query-memory-size-summary", None).await?;
+        let bytes = |key: &str| {
+            resp.pointer(&format!("/return/{key}"))
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0)
+        };
+        Ok(MemorySizeSummary {
+            base_memory: bytes("base-memory"),
+            // Only reported when the machine has memory hotplug slots
+            plugged_memory: bytes("plugged-memory"),
+        })
+    }
+
+    /// The SEV launch measurement, base64-encoded, from `query-sev-launch-measure`.
+    pub async fn query_sev_launch_measure(&mut self) -> Result<String> {
+        let resp = self.execute("query-sev-launch-measure", None).await?;
+        Ok(resp
+            .pointer("/return/data")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    /// SEV state of the guest, from `query-sev`. Fails unless the guest runs with SEV.
+    pub async fn query_sev(&mut self) -> Result<SevInfo> {
+        let resp = self.execute("query-sev", None).await?;
+        let number = |key: &str| {
+            resp.pointer(&format!("/return/{key}"))
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0)
+        };
+        Ok(SevInfo {
+            policy: number("policy") as u32,
+            api_version: format!("{}.{}", number("api-major"), number("api-minor")),
+            build_id: number("build-id"),
+            state: resp
+                .pointer("/return/state")
+                .and_then(|v| v.as_str())
+                .unwrap_or("unknown")
+                .to_string(),
+        })
+    }
+
     /// Query the current VM status. Returns the "status" string (e.g. "running", "paused").
     pub async fn query_status(&mut self) -> Result<String> {
         let resp = self.execute("query-status", None).await?;
-        if let Some(err) = resp.get("error") {
-            return Err(VmError::QmpCommandFailed {
-                message: format!("query-status: {err}"),
-            });
-        }
         let status = resp
             .pointer("/return/status")
             .and_then(|v| v.as_str())
@@ -202,10 +535,11 @@ impl QmpClient {
 
     /// Query the VNC server address. Returns `"host:port"` if VNC is active.
     pub async fn query_vnc(&mut self) -> Result<Option<String>> {
-        let resp = self.execute("query-vnc", None).await?;
-        if resp.get("error").is_some() {
-            return Ok(None);
-        }
+        let resp = match self.execute("query-vnc", None).await {
+            // QEMU built without VNC
+            Err(VmError::QmpError { .. }) => return Ok(None),
+            other => other?,
+        };
         let ret = match resp.get("return") {
             Some(r) => r,
             None => return Ok(None),
@@ -225,3 +559,268 @@ impl QmpClient {
         Ok(Some(format!("{host}:{service}")))
     }
 }
+
+/// The [`VmError::QmpError`] for the `error` object of QEMU's reply to `command`.
+fn qmp_error(command: &str, error: &Value) -> VmError {
+    let field = |key: &str| error.get(key).and_then(|v| v.as_str());
+    VmError::QmpError {
+        class: field("class").unwrap_or("GenericError").to_string(),
+        desc: field("desc")
+            .map(String::from)
+            .unwrap_or_else(|| error.to_string()),
+        command: command.to_string(),
+    }
+}
+
+/// How long an unused session stays open. QEMU serves one QMP client at a time, so holding a
+/// session longer would make `vmctl` commands in other processes wait for it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The process-wide pool, shared by the QEMU backend and the metrics sampler.
+static POOL: LazyLock<QmpPool> = LazyLock::new(QmpPool::default);
+
+/// QMP sessions kept open per socket path, so operations on the same VM in quick succession
+/// (a state check, then a stop; a metrics scrape) skip the greeting and negotiation.
+///
+/// A session is used by one operation at a time. One that has failed is replaced by a fresh
+/// connection, and one left unused for [`IDLE_TIMEOUT`] is closed.
+#[derive(Default)]
+pub struct QmpPool {
+    slots: std::sync::Mutex<HashMap<PathBuf, Slot>>,
+}
+
+type Slot = Arc<tokio::sync::Mutex<Option<IdleSession>>>;
+
+struct IdleSession {
+    client: QmpClient,
+    since: Instant,
+}
+
+impl QmpPool {
+    /// The pool shared by everything in this process.
+    pub fn shared() -> &'static QmpPool {
+        &POOL
+    }
+
+    /// Run `op` on a session for `socket`, connecting (within `timeout`) if there is no open one.
+    ///
+    /// If a reused session turns out to be dead, `op` is retried once on a new connection, so it
+    /// must be safe to repeat. QEMU error replies are returned as they are.
+    pub async fn run<T>(
+        &self,
+        socket: &Path,
+        timeout: Duration,
+        mut op: impl AsyncFnMut(&mut QmpClient) -> Result<T>,
+    ) -> Result<T> {
+        let slot = self.slot(socket);
+        let mut idle = slot.lock().await;
+
+        if let Some(IdleSession { mut client, .. }) = idle.take() {
+            let result = op(&mut client).await;
+            if !client.broken {
+                *idle = Some(IdleSession::new(client));
+                drop(idle);
+                Self::expire_later(slot);
+                return result;
+            }
+            debug!(path = %socket.display(), "cached QMP session died; reconnecting");
+        }
+
+        let mut client = QmpClient::connect(socket, timeout).await?;
+        let result = op(&mut client).await;
+        if !client.broken {
+            *idle = Some(IdleSession::new(client));
+            drop(idle);
+            Self::expire_later(slot);
+        }
+        result
+    }
+
+    /// Close the session for `socket`, e.g. because its VM is shutting down.
+    pub async fn forget(&self, socket: &Path) {
+        let slot = self.slots.lock().unwrap().remove(socket);
+        if let Some(slot) = slot {
+            slot.lock().await.take();
+        }
+    }
+
+    fn slot(&self, socket: &Path) -> Slot {
+        self.slots
+            .lock()
+            .unwrap()
+            .entry(socket.to_path_buf())
+            .or_default()
+            .clone()
+    }
+
+    /// Close the session in `slot` once it has been idle for [`IDLE_TIMEOUT`].
+    fn expire_later(slot: Slot) {
+        tokio::spawn(async move {
+            tokio::time::sleep(IDLE_TIMEOUT).await;
+            let mut idle = slot.lock().await;
+            if idle
+                .as_ref()
+                .is_some_and(|s| s.since.elapsed() >= IDLE_TIMEOUT)
+            {
+                idle.take();
+            }
+        });
+    }
+}
+
+impl IdleSession {
+    fn new(client: QmpClient) -> Self {
+        Self {
+            client,
+            since: Instant::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A QMP server that answers `query-status` and rejects `block_resize`, and hangs up after
+    /// three commands on each connection. Returns the number of connections accepted so far.
+    fn fake_qemu(socket: &Path) -> Arc<AtomicUsize> {
+        let listener = tokio::net::UnixListener::bind(socket).unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                let (read, mut write) = tokio::io::split(conn);
+                let mut lines = BufReader::new(read).lines();
+                write.write_all(b"{\"QMP\": {}}\n").await.unwrap();
+                for _ in 0..3 {
+                    let Ok(Some(line)) = lines.next_line().await else {
+                        break;
+                    };
+                    let reply = if line.contains("query-status") {
+                        r#"{"return": {"status": "running"}}"#
+                    } else if line.contains("block_resize") {
+                        r#"{"error": {"class": "GenericError", "desc": "cannot shrink"}}"#
+                    } else {
+                        r#"{"return": {}}"#
+                    };
+                    write
+                        .write_all(format!("{reply}\n").as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        accepted
+    }
+
+    #[tokio::test]
+    async fn pool_reuses_and_replaces_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("qmp.sock");
+        let accepted = fake_qemu(&socket);
+        let pool = QmpPool::default();
+        let timeout = Duration::from_secs(2);
+        let status = async || {
+            pool.run(&socket, timeout, async |qmp| qmp.query_status().await)
+                .await
+        };
+
+        // Capabilities plus two commands fit in one connection.
+        assert_eq!(status().await.unwrap(), "running");
+        assert_eq!(status().await.unwrap(), "running");
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // The server has hung up: the command is retried on a new connection.
+        assert_eq!(status().await.unwrap(), "running");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // An error reply from QEMU is returned, and the session stays usable.
+        let resize = pool
+            .run(&socket, timeout, async |qmp| {
+                qmp.block_resize("drive0", 1).await
+            })
+            .await;
+        match resize {
+            Err(VmError::QmpError {
+                class,
+                desc,
+                command,
+            }) => {
+                assert_eq!(class, "GenericError");
+                assert_eq!(desc, "cannot shrink");
+                assert_eq!(command, "block_resize");
+            }
+            other => panic!("expected QmpError, got {other:?}"),
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // A forgotten session is closed; the next command connects again.
+        pool.forget(&socket).await;
+        assert_eq!(status().await.unwrap(), "running");
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn parse_keys() {
+        assert_eq!("ret".parse(), Ok(KeyValue::qcode("ret")));
+        assert_eq!("shift_r".parse(), Ok(KeyValue::qcode("shift_r")));
+        assert_eq!("28".parse(), Ok(KeyValue::Number(28)));
+        assert_eq!("0x1c".parse(), Ok(KeyValue::Number(28)));
+        assert!("Ret".parse::<KeyValue>().is_err());
+        assert!("".parse::<KeyValue>().is_err());
+    }
+
+    #[test]
+    fn keys_for_ascii() {
+        let codes = |c| {
+            KeyValue::for_char(c).map(|keys| {
+                keys.into_iter()
+                    .map(|k| match k {
+                        KeyValue::QCode(code) => code,
+                        KeyValue::Number(n) => n.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(codes('a'), Some(vec!["a".to_string()]));
+        assert_eq!(codes('Q'), Some(vec!["shift".into(), "q".into()]));
+        assert_eq!(codes('7'), Some(vec!["7".into()]));
+        assert_eq!(codes('&'), Some(vec!["shift".into(), "7".into()]));
+        assert_eq!(codes(' '), Some(vec!["spc".into()]));
+        assert_eq!(codes('\n'), Some(vec!["ret".into()]));
+        assert_eq!(codes('"'), Some(vec!["shift".into(), "apostrophe".into()]));
+        assert_eq!(codes('é'), None);
+        assert_eq!(codes('\x07'), None);
+    }
+
+    #[test]
+    fn chord_presses_in_order_and_releases_in_reverse() {
+        let args = key_events(&[KeyValue::qcode("ctrl"), KeyValue::Number(0x53)]);
+        let events: Vec<(bool, Value)> = args["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                assert_eq!(e["type"], "key");
+                (
+                    e["data"]["down"].as_bool().unwrap(),
+                    e["data"]["key"].clone(),
+                )
+            })
+            .collect();
+        let ctrl = serde_json::json!({ "type": "qcode", "data": "ctrl" });
+        let del = serde_json::json!({ "type": "number", "data": 0x53 });
+        assert_eq!(
+            events,
+            [
+                (true, ctrl.clone()),
+                (true, del.clone()),
+                (false, del),
+                (false, ctrl)
+            ]
+        );
+    }
+}
@@ -0,0 +1,646 @@
+//! Remote hypervisor host reached over SSH.
+//!
+//! Every backend operation is forwarded to a `vmctl __agent` process on the remote host. The
+//! agent speaks newline-delimited JSON on stdin/stdout: one [`AgentRequest`] per line in, one
+//! [`AgentResponse`] per line out. A fresh SSH connection is used for each call, so the agent is
+//! stateless — the handle sent with each request carries everything the remote backend needs.
+//!
+//! Handles returned by a [`RemoteBackend`] have `remote_host` set, which is how
+//! [`RouterHypervisor`](super::RouterHypervisor) routes later operations back to the same host.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::error::{Result, VmError};
+use crate::image::ImageManager;
+use crate::traits::{ConsoleEndpoint, DEFAULT_IP_TIMEOUT, Hypervisor};
+use crate::types::{SshConfig, VmHandle, VmIpInfo, VmSpec, VmState};
+
+/// An SSH destination such as `ssh://user@server:2222` or `user@server`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteHost {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl RemoteHost {
+    /// Parse `ssh://[user@]host[:port]` or `[user@]host`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = |detail: &str| VmError::RemoteFailed {
+            host: s.to_string(),
+            detail: detail.to_string(),
+        };
+        let rest = s.strip_prefix("ssh://").unwrap_or(s).trim_end_matches('/');
+        let (user, hostport) = match rest.rsplit_once('@') {
+            Some((u, h)) => (Some(u.to_string()), h),
+            None => (None, rest),
+        };
+        let (host, port) = match hostport.rsplit_once(':') {
+            Some((h, p)) if !h.contains(':') => {
+                (h, Some(p.parse().map_err(|_| invalid("invalid port"))?))
+            }
+            _ => (hostport, None),
+        };
+        if host.is_empty() || user.as_deref() == Some("") {
+            return Err(invalid("expected ssh://[user@]host[:port]"));
+        }
+        // ssh would take a destination starting with '-' as an option
+        if host.starts_with('-') || user.as_deref().is_some_and(|u| u.starts_with('-')) {
+            return Err(invalid("user and host must not start with '-'"));
+        }
+        Ok(Self {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// `user@host` (or just `host`) as passed to `ssh`.
+    pub fn destination(&self) -> String {
+        match self.user {
+            Some(ref u) => format!("{u}@{}", self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    /// `[user@]host[:port]` as passed to `ssh -J`.
+    pub fn jump_spec(&self) -> String {
+        match self.port {
+            Some(p) => format!("{}:{p}", self.destination()),
+            None => self.destination(),
+        }
+    }
+
+    /// Common `ssh` arguments to reach this host non-interactively.
+    fn ssh_command(&self) -> tokio::process::Command {
+        let mut cmd = tokio::process::Command::new("ssh");
+        cmd.args(["-o", "BatchMode=yes"]);
+        if let Some(p) = self.port {
+            cmd.arg("-p").arg(p.to_string());
+        }
+        cmd
+    }
+
+    /// Build a command that runs `program` on this host over SSH.
+    pub fn command(&self, program: &str) -> tokio::process::Command {
+        let mut cmd = self.ssh_command();
+        cmd.arg(self.destination()).arg(program);
+        cmd
+    }
+
+    /// Forward a local TCP port to `target_host:target_port` as seen from the remote host.
+    ///
+    /// The tunnel lives as long as the returned [`SshTunnel`].
+    pub async fn tunnel_tcp(&self, target_host: &str, target_port: u16) -> Result<SshTunnel> {
+        let local_port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let forward = format!(
+            "127.0.0.1:{local_port}:{}:{target_port}",
+            crate::ssh::bracket_host(target_host)
+        );
+        let child = self.spawn_forward(&forward)?;
+        let tunnel = SshTunnel {
+            _child: child,
+            local_port,
+            local_socket: None,
+        };
+
+        self.wait_ready(|| async {
+            tokio::net::TcpStream::connect(("127.0.0.1", local_port))
+                .await
+                .is_ok()
+        })
+        .await?;
+        Ok(tunnel)
+    }
+
+    /// Forward a local Unix socket at `local` to the Unix socket `remote` on the remote host.
+    pub async fn tunnel_unix(&self, remote: &Path, local: &Path) -> Result<SshTunnel> {
+        let _ = tokio::fs::remove_file(local).await;
+        let forward = format!("{}:{}", local.display(), remote.display());
+        let child = self.spawn_forward(&forward)?;
+        let tunnel = SshTunnel {
+            _child: child,
+            local_port: 0,
+            local_socket: Some(local.to_path_buf()),
+        };
+
+        self.wait_ready(|| async { tokio::net::UnixStream::connect(local).await.is_ok() })
+            .await?;
+        Ok(tunnel)
+    }
+
+    fn spawn_forward(&self, forward: &str) -> Result<tokio::process::Child> {
+        let mut cmd = self.ssh_command();
+        cmd.args(["-N", "-o", "ExitOnForwardFailure=yes", "-L", forward])
+            .arg(self.destination())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true);
+        debug!(host = %self.destination(), forward, "opening SSH tunnel");
+        cmd.spawn().map_err(|e| VmError::RemoteFailed {
+            host: self.destination(),
+            detail: format!("failed to spawn ssh: {e}"),
+        })
+    }
+
+    async fn wait_ready<F, Fut>(&self, probe: F) -> Result<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(15);
+        while tokio::time::Instant::now() < deadline {
+            if probe().await {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Err(VmError::RemoteFailed {
+            host: self.destination(),
+            detail: "SSH tunnel did not come up within 15s".into(),
+        })
+    }
+}
+
+impl std::fmt::Display for RemoteHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ssh://{}", self.jump_spec())
+    }
+}
+
+/// A running `ssh -N -L` forward. The ssh process is killed when this is dropped.
+pub struct SshTunnel {
+    _child: tokio::process::Child,
+    local_port: u16,
+    local_socket: Option<PathBuf>,
+}
+
+impl SshTunnel {
+    /// Local TCP port of a [`RemoteHost::tunnel_tcp`] forward.
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.local_socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Open an SSH session to a guest, jumping through `remote` when the VM lives on a remote host.
+///
+/// `ip`/`port` are as reported by the VM's backend, i.e. relative to the hypervisor host. The
+/// returned tunnel (if any) must be kept alive for as long as the session is used.
+pub async fn connect_guest(
+    remote: Option<&RemoteHost>,
+    ip: &str,
+    port: u16,
+    config: &SshConfig,
+    timeout: Duration,
+    cancel: &CancellationToken,
+) -> Result<(ssh2::Session, Option<SshTunnel>)> {
+    match remote {
+        Some(host) => {
+            let tunnel = host.tunnel_tcp(ip, port).await?;
+            let local_port = tunnel.local_port();
+            let sess =
+                crate::ssh::connect_with_retry("127.0.0.1", local_port, config, timeout, cancel)
+                    .await?;
+            Ok((sess, Some(tunnel)))
+        }
+        None => Ok((
+            crate::ssh::connect_with_retry(ip, port, config, timeout, cancel).await?,
+            None,
+        )),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Agent protocol
+// ---------------------------------------------------------------------------
+
+/// A single request to a `vmctl __agent` process.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum AgentRequest {
+    Prepare {
+        spec: VmSpec,
+    },
+    Start {
+        vm: VmHandle,
+    },
+    Stop {
+        vm: VmHandle,
+        timeout_secs: u64,
+    },
+    Suspend {
+        vm: VmHandle,
+    },
+    Resume {
+        vm: VmHandle,
+    },
+    Destroy {
+        vm: VmHandle,
+    },
+    State {
+        vm: VmHandle,
+    },
+    /// Preferred address only; kept for clients that predate `guest_ips`.
+    GuestIp {
+        vm: VmHandle,
+    },
+    GuestIps {
+        vm: VmHandle,
+        /// How long to wait for the guest; agents default to [`DEFAULT_IP_TIMEOUT`] for older
+        /// clients that don't send it.
+        #[serde(default)]
+        timeout_secs: Option<u64>,
+    },
+    ConsoleEndpoint {
+        vm: VmHandle,
+    },
+    /// Download an image into the remote host's image cache.
+    PullImage {
+        url: String,
+        name: Option<String>,
+    },
+}
+
+/// The agent's reply to one [`AgentRequest`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AgentResponse {
+    Ok { value: serde_json::Value },
+    Error { message: String },
+}
+
+/// Serve agent requests from `reader` until EOF, dispatching them to `hv`. Images are pulled
+/// into `images`' cache.
+pub async fn serve_agent<H, R, W>(
+    hv: &H,
+    images: &ImageManager,
+    reader: R,
+    mut writer: W,
+) -> Result<()>
+where
+    H: Hypervisor,
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = reader.lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<AgentRequest>(&line) {
+            Ok(req) => match handle_request(hv, images, req).await {
+                Ok(value) => AgentResponse::Ok { value },
+                Err(e) => AgentResponse::Error {
+                    message: e.to_string(),
+                },
+            },
+            Err(e) => AgentResponse::Error {
+                message: format!("invalid request: {e}"),
+            },
+        };
+        let mut out = serde_json::to_string(&response).map_err(std::io::Error::other)?;
+        out.push('\n');
+        writer.write_all(out.as_bytes()).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+async fn handle_request<H: Hypervisor>(
+    hv: &H,
+    images: &ImageManager,
+    req: AgentRequest,
+) -> Result<serde_json::Value> {
+    fn to_value<T: Serialize>(v: T) -> Result<serde_json::Value> {
+        serde_json::to_value(v).map_err(|e| VmError::Io(std::io::Error::other(e)))
+    }
+
+    match req {
+        AgentRequest::Prepare { spec } => to_value(hv.prepare(&spec).await?),
+        AgentRequest::Start { vm } => to_value(hv.start(&vm).await?),
+        AgentRequest::Stop { vm, timeout_secs } => {
+            to_value(hv.stop(&vm, Duration::from_secs(timeout_secs)).await?)
+        }
+        AgentRequest::Suspend { vm } => to_value(hv.suspend(&vm).await?),
+        AgentRequest::Resume { vm } => to_value(hv.resume(&vm).await?),
+        AgentRequest::Destroy { vm } => to_value(hv.destroy(vm).await?),
+        AgentRequest::State { vm } => to_value(hv.state(&vm).await?),
+        AgentRequest::GuestIp { vm } => to_value(hv.guest_ip(&vm, DEFAULT_IP_TIMEOUT).await?),
+        AgentRequest::GuestIps { vm, timeout_secs } => {
+            let timeout = timeout_secs.map_or(DEFAULT_IP_TIMEOUT, Duration::from_secs);
+            to_value(hv.guest_ips(&vm, timeout).await?)
+        }
+        AgentRequest::ConsoleEndpoint { vm } => to_value(hv.console_endpoint(&vm)?),
+        AgentRequest::PullImage { url, name } => {
+            to_value(images.pull(&url, name.as_deref()).await?)
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Backend
+// ---------------------------------------------------------------------------
+
+/// Backend that runs every operation on a remote host via `vmctl __agent` over SSH.
+#[derive(Clone)]
+pub struct RemoteBackend {
+    host: RemoteHost,
+    agent_command: String,
+}
+
+impl RemoteBackend {
+    pub fn new(host: RemoteHost) -> Self {
+        Self {
+            host,
+            agent_command: "vmctl __agent".into(),
+        }
+    }
+
+    /// Override the command used to start the agent on the remote host.
+    pub fn with_agent_command(mut self, command: impl Into<String>) -> Self {
+        self.agent_command = command.into();
+        self
+    }
+
+    pub fn host(&self) -> &RemoteHost {
+        &self.host
+    }
+
+    fn failed(&self, detail: impl Into<String>) -> VmError {
+        VmError::RemoteFailed {
+            host: self.host.destination(),
+            detail: detail.into(),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, req: &AgentRequest) -> Result<T> {
+        let mut line = serde_json::to_string(req).map_err(|e| self.failed(e.to_string()))?;
+        line.push('\n');
+
+        let mut child = self
+            .host
+            .ssh_command()
+            .arg(self.host.destination())
+            .arg(&self.agent_command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| self.failed(format!("failed to spawn ssh: {e}")))?;
+
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        stdin.write_all(line.as_bytes()).await?;
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let Some(reply) = stdout.lines().find(|l| !l.trim().is_empty()) else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(self.failed(format!(
+                "agent returned no response ({}): {}",
+                output.status,
+                stderr.trim()
+            )));
+        };
+
+        match serde_json::from_str::<AgentResponse>(reply)
+            .map_err(|e| self.failed(format!("invalid agent response: {e}")))?
+        {
+            AgentResponse::Ok { value } => serde_json::from_value(value)
+                .map_err(|e| self.failed(format!("unexpected agent response: {e}"))),
+            AgentResponse::Error { message } => Err(self.failed(message)),
+        }
+    }
+
+    /// Strip the local-only `remote_host` before handing a handle to the agent.
+    fn outgoing(vm: &VmHandle) -> VmHandle {
+        let mut vm = vm.clone();
+        vm.remote_host = None;
+        vm
+    }
+
+    /// Tag a handle from the agent with this host so later calls are routed back here.
+    fn incoming(&self, mut vm: VmHandle) -> VmHandle {
+        vm.remote_host = Some(self.host.to_string());
+        vm
+    }
+
+    /// Download an image into the remote host's cache and return its path there.
+    pub async fn pull_image(&self, url: &str, name: Option<&str>) -> Result<PathBuf> {
+        info!(host = %self.host, url, "pulling image on remote host");
+        self.call(&AgentRequest::PullImage {
+            url: url.to_string(),
+            name: name.map(String::from),
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Hypervisor for RemoteBackend {
+    async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle> {
+        let vm = self
+            .call(&AgentRequest::Prepare { spec: spec.clone() })
+            .await?;
+        info!(name = %spec.name, host = %self.host, "remote: prepared");
+        Ok(self.incoming(vm))
+    }
+
+    async fn start(&self, vm: &VmHandle) -> Result<VmHandle> {
+        let vm = self
+            .call(&AgentRequest::Start {
+                vm: Self::outgoing(vm),
+            })
+            .await?;
+        Ok(self.incoming(vm))
+    }
+
+    async fn stop(&self, vm: &VmHandle, timeout: Duration) -> Result<VmHandle> {
+        let vm = self
+            .call(&AgentRequest::Stop {
+                vm: Self::outgoing(vm),
+                timeout_secs: timeout.as_secs(),
+            })
+            .await?;
+        Ok(self.incoming(vm))
+    }
+
+    async fn suspend(&self, vm: &VmHandle) -> Result<VmHandle> {
+        let vm = self
+            .call(&AgentRequest::Suspend {
+                vm: Self::outgoing(vm),
+            })
+            .await?;
+        Ok(self.incoming(vm))
+    }
+
+    async fn resume(&self, vm: &VmHandle) -> Result<VmHandle> {
+        let vm = self
+            .call(&AgentRequest::Resume {
+                vm: Self::outgoing(vm),
+            })
+            .await?;
+        Ok(self.incoming(vm))
+    }
+
+    async fn destroy(&self, vm: VmHandle) -> Result<()> {
+        self.call(&AgentRequest::Destroy {
+            vm: Self::outgoing(&vm),
+        })
+        .await
+    }
+
+    async fn state(&self, vm: &VmHandle) -> Result<VmState> {
+        self.call(&AgentRequest::State {
+            vm: Self::outgoing(vm),
+        })
+        .await
+    }
+
+    async fn guest_ips(&self, vm: &VmHandle, timeout: Duration) -> Result<VmIpInfo> {
+        self.call(&AgentRequest::GuestIps {
+            vm: Self::outgoing(vm),
+            timeout_secs: Some(timeout.as_secs()),
+        })
+        .await
+    }
+
+    fn console_endpoint(&self, vm: &VmHandle) -> Result<ConsoleEndpoint> {
+        // The console socket lives on the remote host; it is reached through an SSH forward
+        // rather than a round-trip to the agent, so this stays synchronous.
+        match vm.console_socket {
+            Some(ref path) => Ok(ConsoleEndpoint::RemoteUnixSocket {
+                host: self.host.to_string(),
+                path: path.clone(),
+            }),
+            None => Ok(ConsoleEndpoint::None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_remote_host() {
+        let h = RemoteHost::parse("ssh://alice@server:2222").unwrap();
+        assert_eq!(h.user.as_deref(), Some("alice"));
+        assert_eq!(h.host, "server");
+        assert_eq!(h.port, Some(2222));
+        assert_eq!(h.destination(), "alice@server");
+        assert_eq!(h.to_string(), "ssh://alice@server:2222");
+
+        let h = RemoteHost::parse("server").unwrap();
+        assert_eq!(h.user, None);
+        assert_eq!(h.port, None);
+        assert_eq!(h.jump_spec(), "server");
+
+        assert!(RemoteHost::parse("ssh://").is_err());
+        assert!(RemoteHost::parse("ssh://bob@server:notaport").is_err());
+        for s in [
+            "-oProxyCommand=sh",
+            "ssh://-oProxyCommand=sh",
+            "-oProxyCommand=sh@server",
+            "ssh://bob@-server:22",
+        ] {
+            let err = RemoteHost::parse(s).unwrap_err();
+            assert!(
+                err.to_string().contains("must not start with '-'"),
+                "{s}: {err}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn agent_roundtrip_with_noop() {
+        use crate::backends::noop::NoopBackend;
+        use crate::types::{MachineType, NetworkConfig};
+
+        let spec = VmSpec {
+            name: "agent-test".into(),
+            image_path: "/tmp/test.qcow2".into(),
+            vcpus: 1,
+            memory_mb: 512,
+            disk_gb: None,
+            overlay: Default::default(),
+            network: NetworkConfig::None,
+            cloud_init: None,
+            ssh: None,
+            uefi: false,
+            mac_addr: None,
+            existing_macs: Vec::new(),
+            static_ip: None,
+            ssh_host_port: None,
+            private_networks: Vec::new(),
+            serial_ports: Vec::new(),
+            machine: MachineType::Q35,
+            kernel: None,
+            cdrom: None,
+            boot_order: Vec::new(),
+            random_seed: Default::default(),
+            qemu_args: Vec::new(),
+            restart_policy: Default::default(),
+            guest_os: Default::default(),
+            display: Default::default(),
+            audio: Default::default(),
+            smbios: Default::default(),
+            disk_bus: None,
+            drivers_iso: None,
+            secure_boot: false,
+            secure_boot_vars: None,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
+        };
+        let mut input = serde_json::to_string(&AgentRequest::Prepare { spec }).unwrap();
+        input.push_str("\n{\"method\":\"bogus\"}\n");
+
+        let mut out = Vec::new();
+        serve_agent(
+            &NoopBackend,
+            &ImageManager::new(),
+            input.as_bytes(),
+            &mut out,
+        )
+        .await
+        .unwrap();
+
+        let replies: Vec<AgentResponse> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 2);
+        let AgentResponse::Ok { ref value } = replies[0] else {
+            panic!("prepare failed: {:?}", replies[0]);
+        };
+        let vm: VmHandle = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(vm.name, "agent-test");
+        assert!(matches!(replies[1], AgentResponse::Error { .. }));
+
+        let _ = std::fs::remove_dir_all(&vm.work_dir);
+    }
+}
@@ -0,0 +1,371 @@
+//! Host capability probing.
+//!
+//! [`probe`] inspects the host for the pieces the backends rely on — hardware virtualization,
+//! hypervisor binaries, ISO tooling — plus the resources left for new VMs. The router uses it to
+//! pick a backend, `vmctl doctor` prints it, and embedders can use it to make their own choices.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use serde::Serialize;
+
+use crate::error::{Result, VmError};
+use crate::types::BackendTag;
+
+/// Memory [`check_resources`] leaves to the host itself, in MB.
+pub const HOST_MEMORY_MARGIN_MB: u64 = 512;
+
+/// What the host offers for running VMs.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostCapabilities {
+    /// Host OS (`linux`, `illumos`, ...).
+    pub os: String,
+    /// Host CPU architecture (`x86_64`, `aarch64`, ...).
+    pub arch: String,
+    /// `/dev/kvm` exists and is readable and writable by this process.
+    pub kvm: bool,
+    /// `qemu-system-<arch>` on `PATH`.
+    pub qemu: Option<BinaryInfo>,
+    /// `qemu-img` on `PATH` (needed for overlays and image conversion).
+    pub qemu_img: Option<BinaryInfo>,
+    /// `cloud-hypervisor` on `PATH`.
+    pub cloud_hypervisor: Option<BinaryInfo>,
+    /// How cloud-init seed ISOs can be built, if at all.
+    pub iso_tool: Option<IsoTool>,
+    /// Memory available for new allocations, in MB.
+    pub free_memory_mb: Option<u64>,
+    /// Free space in the VM data directory, in MB.
+    pub free_disk_mb: Option<u64>,
+}
+
+/// A binary found on `PATH` and the first line of its `--version` output.
+#[derive(Debug, Clone, Serialize)]
+pub struct BinaryInfo {
+    pub path: PathBuf,
+    pub version: Option<String>,
+}
+
+/// Tool used to build cloud-init seed ISOs.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum IsoTool {
+    Genisoimage {
+        path: PathBuf,
+    },
+    Mkisofs {
+        path: PathBuf,
+    },
+    /// Built in via the `pure-iso` feature.
+    Builtin,
+}
+
+impl std::fmt::Display for IsoTool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IsoTool::Genisoimage { path } => write!(f, "genisoimage ({})", path.display()),
+            IsoTool::Mkisofs { path } => write!(f, "mkisofs ({})", path.display()),
+            IsoTool::Builtin => write!(f, "built-in (pure-iso)"),
+        }
+    }
+}
+
+impl HostCapabilities {
+    /// The backend that can actually run VMs on this host, or [`BackendTag::Noop`] if none can.
+    pub fn preferred_backend(&self) -> BackendTag {
+        #[cfg(target_os = "linux")]
+        {
+            if self.qemu.is_some() && self.kvm {
+                BackendTag::Qemu
+            } else {
+                BackendTag::Noop
+            }
+        }
+        #[cfg(target_os = "illumos")]
+        {
+            BackendTag::Propolis
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "illumos")))]
+        {
+            BackendTag::Noop
+        }
+    }
+
+    /// Why QEMU can't be used on this host, if it can't.
+    pub fn qemu_unavailable_reason(&self) -> Option<String> {
+        match (&self.qemu, self.kvm) {
+            (None, _) => Some(format!("qemu-system-{} not found in PATH", self.arch)),
+            (Some(_), false) => Some("/dev/kvm is missing or not accessible".into()),
+            (Some(_), true) => None,
+        }
+    }
+}
+
+/// [`probe`] the host once per process and return that result from then on. The memory and disk
+/// figures are those of the first call.
+pub fn probe_cached() -> &'static HostCapabilities {
+    static CAPABILITIES: OnceLock<HostCapabilities> = OnceLock::new();
+    CAPABILITIES.get_or_init(probe)
+}
+
+/// Probe the host. Runs each discovered binary with `--version`, so this takes a few
+/// milliseconds and should not be called in a hot loop.
+pub fn probe() -> HostCapabilities {
+    let arch = std::env::consts::ARCH.to_string();
+    let qemu = find_binary(&format!("qemu-system-{arch}"));
+
+    let iso_tool = if cfg!(feature = "pure-iso") {
+        Some(IsoTool::Builtin)
+    } else if let Some(path) = which("genisoimage") {
+        Some(IsoTool::Genisoimage { path })
+    } else {
+        which("mkisofs").map(|path| IsoTool::Mkisofs { path })
+    };
+
+    let data_dir = dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("vmctl");
+
+    HostCapabilities {
+        os: std::env::consts::OS.to_string(),
+        arch,
+        kvm: kvm_available(),
+        qemu,
+        qemu_img: find_binary("qemu-img"),
+        cloud_hypervisor: find_binary("cloud-hypervisor"),
+        iso_tool,
+        free_memory_mb: free_memory_mb(),
+        free_disk_mb: free_disk_mb(&data_dir),
+    }
+}
+
+/// The host's memory and disk space, for [`check_resources`].
+#[derive(Debug, Clone, Default)]
+pub struct HostResources {
+    /// `MemAvailable`, in MB.
+    pub available_memory_mb: Option<u64>,
+    /// `MemTotal`, in MB.
+    pub total_memory_mb: Option<u64>,
+    /// Memory given to the VMs already running, in MB. They may not have touched all of it yet,
+    /// so it isn't all missing from `available_memory_mb`.
+    pub running_memory_mb: u64,
+    /// Free space in the directory VM disks are created in, in MB.
+    pub free_disk_mb: Option<u64>,
+}
+
+impl HostResources {
+    /// Read the host's memory and the free space in `data_dir`. `running_memory_mb` is the
+    /// memory of the VMs already running.
+    pub fn probe(data_dir: &Path, running_memory_mb: u64) -> Self {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").unwrap_or_default();
+        Self {
+            available_memory_mb: parse_meminfo_mb(&meminfo, "MemAvailable"),
+            total_memory_mb: parse_meminfo_mb(&meminfo, "MemTotal"),
+            running_memory_mb,
+            free_disk_mb: free_disk_mb(data_dir),
+        }
+    }
+}
+
+/// Check that `host` has room for `vms` (a VM name, or a description of several), which need
+/// `memory_mb` of memory once started and disks of up to `disk_gb` in total. Pass 0 for what
+/// isn't being allocated: VMs only take memory when they start and disk space when they are
+/// created. Whatever the host doesn't report isn't checked.
+///
+/// Fails with [`VmError::InsufficientResources`] when the memory wouldn't fit in what is
+/// available or, together with the running VMs, in the host's total, each leaving
+/// [`HOST_MEMORY_MARGIN_MB`] to the host, or when the disks could outgrow the free space.
+pub fn check_resources(
+    vms: &str,
+    memory_mb: u64,
+    disk_gb: u64,
+    host: &HostResources,
+) -> Result<()> {
+    let mut problems = Vec::new();
+    if memory_mb > 0 {
+        let needed = memory_mb + HOST_MEMORY_MARGIN_MB;
+        if let Some(available) = host.available_memory_mb.filter(|&a| needed > a) {
+            problems.push(format!(
+                "{memory_mb} MB of memory plus {HOST_MEMORY_MARGIN_MB} MB for the host is needed, \
+                 but only {available} MB is available"
+            ));
+        } else if let Some(total) = host
+            .total_memory_mb
+            .filter(|&t| host.running_memory_mb + needed > t)
+        {
+            problems.push(format!(
+                "{memory_mb} MB of memory plus {HOST_MEMORY_MARGIN_MB} MB for the host is needed, \
+                 but running VMs already have {} MB of the host's {total} MB",
+                host.running_memory_mb
+            ));
+        }
+    }
+    let disk_mb = disk_gb * 1024;
+    if let Some(free) = host.free_disk_mb.filter(|&f| disk_mb > f) {
+        problems.push(format!(
+            "disks of up to {disk_gb} GB may be written but the data directory has {free} MB free"
+        ));
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(VmError::InsufficientResources {
+        vm: vms.into(),
+        detail: problems.join("; "),
+    })
+}
+
+/// Search `PATH` for an executable named `name`.
+pub fn which(name: &str) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| {
+            p.metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+}
+
+fn find_binary(name: &str) -> Option<BinaryInfo> {
+    let path = which(name)?;
+    let version = Command::new(&path)
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .next()
+                .map(|l| l.trim().to_string())
+        });
+    Some(BinaryInfo { path, version })
+}
+
+fn kvm_available() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .is_ok()
+}
+
+fn free_memory_mb() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    parse_meminfo_mb(&meminfo, "MemAvailable")
+}
+
+/// Extract `field` (in MB) from `/proc/meminfo` contents.
+fn parse_meminfo_mb(meminfo: &str, field: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix(field)?.strip_prefix(':'))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb / 1024)
+}
+
+/// Free space on the filesystem holding `path` (or its nearest existing ancestor).
+#[cfg(target_os = "linux")]
+fn free_disk_mb(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is a valid NUL-terminated string and `st` is a valid out-pointer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut st) } != 0 {
+        return None;
+    }
+    Some(st.f_bavail as u64 * st.f_frsize as u64 / (1024 * 1024))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn free_disk_mb(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meminfo_available() {
+        let meminfo = "MemTotal:       16303580 kB\nMemFree:         1234567 kB\nMemAvailable:    8388608 kB\n";
+        assert_eq!(parse_meminfo_mb(meminfo, "MemAvailable"), Some(8192));
+        assert_eq!(parse_meminfo_mb(meminfo, "MemTotal"), Some(15921));
+        assert_eq!(parse_meminfo_mb("MemTotal: 1 kB\n", "MemAvailable"), None);
+    }
+
+    #[test]
+    fn resources_are_checked_against_the_host() {
+        let host = HostResources {
+            available_memory_mb: Some(8192),
+            total_memory_mb: Some(16384),
+            running_memory_mb: 8192,
+            free_disk_mb: Some(50 * 1024),
+        };
+        check_resources("web", 4096, 40, &host).unwrap();
+        // Nothing is allocated: nothing to check
+        check_resources("web", 0, 0, &HostResources::default()).unwrap();
+
+        for (memory_mb, disk_gb, expected) in [
+            (
+                65536,
+                0,
+                "65536 MB of memory plus 512 MB for the host is needed",
+            ),
+            (7900, 0, "but only 8192 MB is available"),
+            (
+                0,
+                500,
+                "disks of up to 500 GB may be written but the data directory has 51200 MB free",
+            ),
+        ] {
+            let err = check_resources("web", memory_mb, disk_gb, &host).unwrap_err();
+            let msg = err.to_string();
+            assert!(msg.contains(expected), "got {msg}");
+        }
+
+        // Memory the running VMs haven't touched yet is still theirs
+        let host = HostResources {
+            running_memory_mb: 14336,
+            ..host
+        };
+        let msg = check_resources("db", 2048, 0, &host)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            msg.contains("running VMs already have 14336 MB of the host's 16384 MB"),
+            "{msg}"
+        );
+    }
+
+    #[test]
+    fn preferred_backend_needs_qemu_and_kvm() {
+        let mut caps = HostCapabilities {
+            os: "linux".into(),
+            arch: "x86_64".into(),
+            kvm: false,
+            qemu: Some(BinaryInfo {
+                path: "/usr/bin/qemu-system-x86_64".into(),
+                version: None,
+            }),
+            qemu_img: None,
+            cloud_hypervisor: None,
+            iso_tool: None,
+            free_memory_mb: None,
+            free_disk_mb: None,
+        };
+        assert!(caps.qemu_unavailable_reason().unwrap().contains("/dev/kvm"));
+        #[cfg(target_os = "linux")]
+        assert_eq!(caps.preferred_backend(), BackendTag::Noop);
+
+        caps.kvm = true;
+        assert!(caps.qemu_unavailable_reason().is_none());
+        #[cfg(target_os = "linux")]
+        assert_eq!(caps.preferred_backend(), BackendTag::Qemu);
+    }
+}
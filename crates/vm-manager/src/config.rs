@@ -0,0 +1,475 @@
+//! Layered defaults for vmctl.
+//!
+//! Each setting is taken from the highest-precedence layer that sets it:
+//!
+//! 1. command-line flags (applied by the caller with [`Setting::set`])
+//! 2. `VMCTL_*` environment variables
+//! 3. `~/.config/vmctl/config.kdl` (`$XDG_CONFIG_HOME/vmctl/config.kdl`)
+//! 4. `/etc/vmctl/config.kdl`
+//! 5. built-in defaults
+//!
+//! ```kdl
+//! qemu-binary "/usr/bin/qemu-system-x86_64"
+//! firmware-dir "/usr/share/edk2/x64"
+//! data-dir "~/vms"
+//! image-cache-dir "/var/cache/vmctl/images"
+//! bridge "br0"
+//! image "https://cloud-images.ubuntu.com/noble/current/noble-server-cloudimg-amd64.img"
+//! ssh-user "ubuntu"
+//! backend "qemu"
+//! lease-sources "dnsmasq:/var/lib/misc/dnsmasq.leases" "libvirt:/var/lib/libvirt/dnsmasq"
+//! hostnames "mdns"
+//! namespace "ci"
+//! ```
+//!
+//! `lease-sources` takes several values in the file and a comma-separated list in
+//! `VMCTL_LEASE_SOURCES`; every other setting takes exactly one.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use kdl::KdlDocument;
+
+use crate::error::{Result, VmError};
+use crate::hostnames::HostnameMode;
+use crate::image::ImageManager;
+use crate::leases::LeaseSource;
+use crate::types::BackendTag;
+use crate::vmfile::expand_tilde;
+
+/// System-wide configuration file.
+pub const SYSTEM_CONFIG: &str = "/etc/vmctl/config.kdl";
+
+/// Config file keys and the environment variables that override them.
+pub const KEYS: &[(&str, &str)] = &[
+    ("qemu-binary", "VMCTL_QEMU_BINARY"),
+    ("firmware-dir", "VMCTL_FIRMWARE_DIR"),
+    ("data-dir", "VMCTL_DATA_DIR"),
+    ("image-cache-dir", "VMCTL_IMAGE_CACHE_DIR"),
+    ("bridge", "VMCTL_BRIDGE"),
+    ("image", "VMCTL_IMAGE"),
+    ("ssh-user", "VMCTL_SSH_USER"),
+    ("backend", "VMCTL_BACKEND"),
+    ("lease-sources", "VMCTL_LEASE_SOURCES"),
+    ("hostnames", "VMCTL_HOSTNAMES"),
+    ("namespace", "VMCTL_NAMESPACE"),
+];
+
+/// Namespace used when `$USER` is not set either.
+const DEFAULT_NAMESPACE: &str = "default";
+
+/// Where a setting's value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    Env(&'static str),
+    Flag,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Env(var) => write!(f, "${var}"),
+            Self::Flag => write!(f, "command line"),
+        }
+    }
+}
+
+/// A configuration value and the layer it came from.
+#[derive(Debug, Clone)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: ConfigSource,
+}
+
+impl<T> Setting<T> {
+    fn default(value: T) -> Self {
+        Self {
+            value,
+            source: ConfigSource::Default,
+        }
+    }
+
+    /// Override the value from a higher-precedence layer.
+    pub fn set(&mut self, value: T, source: ConfigSource) {
+        self.value = value;
+        self.source = source;
+    }
+}
+
+/// Effective vmctl configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// QEMU system emulator; `None` searches `PATH` for `qemu-system-<arch>`.
+    pub qemu_binary: Setting<Option<PathBuf>>,
+    /// Directory searched first for OVMF firmware, before the well-known locations.
+    pub firmware_dir: Setting<Option<PathBuf>>,
+    /// Root for VM work directories, the state file and (by default) the image cache.
+    pub data_dir: Setting<PathBuf>,
+    /// Image cache; `None` means `<data-dir>/images`.
+    pub image_cache_dir: Setting<Option<PathBuf>>,
+    /// Bridge for TAP networking when none is given explicitly.
+    pub bridge: Setting<Option<String>>,
+    /// Image path or URL used by `vmctl create` when none is given.
+    pub image: Setting<Option<String>>,
+    /// Guest user for SSH when none is given.
+    pub ssh_user: Setting<String>,
+    /// Backend for new VMs; `None` picks the best one available on the host.
+    pub backend: Setting<Option<BackendTag>>,
+    /// DHCP lease files searched, in order, to find bridged guests' IP addresses.
+    pub lease_sources: Setting<Vec<LeaseSource>>,
+    /// Whether running VMs are registered as `<name>.local` in `/etc/hosts` or via mDNS.
+    pub hostnames: Setting<HostnameMode>,
+    /// Which user's VMs commands operate on, so that users sharing a data directory don't see or
+    /// clash with each other's VMs. Defaults to `$USER`.
+    pub namespace: Setting<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            qemu_binary: Setting::default(None),
+            firmware_dir: Setting::default(None),
+            data_dir: Setting::default(
+                dirs::data_dir()
+                    .unwrap_or_else(|| PathBuf::from("/tmp"))
+                    .join("vmctl"),
+            ),
+            image_cache_dir: Setting::default(None),
+            bridge: Setting::default(None),
+            image: Setting::default(None),
+            ssh_user: Setting::default("vm".into()),
+            backend: Setting::default(None),
+            lease_sources: Setting::default(LeaseSource::defaults()),
+            hostnames: Setting::default(HostnameMode::Off),
+            namespace: Setting::default(DEFAULT_NAMESPACE.into()),
+        }
+    }
+}
+
+impl Config {
+    /// Config files read by [`load`](Self::load), in increasing precedence.
+    pub fn search_paths() -> Vec<PathBuf> {
+        let mut files = vec![PathBuf::from(SYSTEM_CONFIG)];
+        if let Some(dir) = dirs::config_dir() {
+            files.push(dir.join("vmctl").join("config.kdl"));
+        }
+        files
+    }
+
+    /// Load the system file, the user file and the environment.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::search_paths(), |var| std::env::var(var).ok())
+    }
+
+    /// Load `files` in increasing precedence (missing files are skipped), then apply environment
+    /// variables looked up with `env`.
+    pub fn load_from(files: &[PathBuf], env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(user) = env("USER").filter(|u| valid_namespace(u)) {
+            config.namespace = Setting::default(user);
+        }
+        for path in files {
+            match std::fs::read_to_string(path) {
+                Ok(content) => config.merge_file(path, &content)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(VmError::ConfigInvalid {
+                        origin: path.display().to_string(),
+                        detail: format!("could not read file: {e}"),
+                        hint: "check the file's permissions".into(),
+                    });
+                }
+            }
+        }
+        for &(key, var) in KEYS {
+            if let Some(value) = env(var).filter(|v| !v.is_empty()) {
+                let values: Vec<&str> = if key == "lease-sources" {
+                    value.split(',').map(str::trim).collect()
+                } else {
+                    vec![&value]
+                };
+                config.apply(key, &values, ConfigSource::Env(var))?;
+            }
+        }
+        Ok(config)
+    }
+
+    fn merge_file(&mut self, path: &Path, content: &str) -> Result<()> {
+        let invalid = |detail: String, hint: &str| VmError::ConfigInvalid {
+            origin: path.display().to_string(),
+            detail,
+            hint: hint.into(),
+        };
+        let doc: KdlDocument = content
+            .parse()
+            .map_err(|e: kdl::KdlError| invalid(e.to_string(), "check the KDL syntax"))?;
+
+        for node in doc.nodes() {
+            let key = node.name().value();
+            let values: Option<Vec<&str>> = node
+                .entries()
+                .iter()
+                .map(|e| e.name().is_none().then(|| e.value().as_string()).flatten())
+                .collect();
+            let values = values
+                .filter(|v| !v.is_empty() && (v.len() == 1 || key == "lease-sources"))
+                .ok_or_else(|| {
+                    invalid(
+                        format!("{key} requires a string value"),
+                        "write settings as: key \"value\"",
+                    )
+                })?;
+            self.apply(key, &values, ConfigSource::File(path.to_path_buf()))?;
+        }
+        Ok(())
+    }
+
+    /// Set `key` (a config file key) from the layer `source`. Only `lease-sources` takes more than
+    /// one value.
+    fn apply(&mut self, key: &str, values: &[&str], source: ConfigSource) -> Result<()> {
+        let value = values[0];
+        match key {
+            "qemu-binary" => self.qemu_binary.set(Some(expand_tilde(value)), source),
+            "firmware-dir" => self.firmware_dir.set(Some(expand_tilde(value)), source),
+            "data-dir" => self.data_dir.set(expand_tilde(value), source),
+            "image-cache-dir" => self.image_cache_dir.set(Some(expand_tilde(value)), source),
+            "bridge" => self.bridge.set(Some(value.into()), source),
+            "image" => self.image.set(Some(value.into()), source),
+            "ssh-user" => self.ssh_user.set(value.into(), source),
+            "backend" => self
+                .backend
+                .set(Some(BackendTag::from(value.to_string())), source),
+            "lease-sources" => {
+                let sources = values
+                    .iter()
+                    .map(|v| LeaseSource::parse(v))
+                    .collect::<Result<Vec<_>>>()
+                    .map_err(|e| with_origin(e, &source))?;
+                self.lease_sources.set(sources, source);
+            }
+            "hostnames" => {
+                let mode = HostnameMode::parse(value).map_err(|e| with_origin(e, &source))?;
+                self.hostnames.set(mode, source);
+            }
+            "namespace" => self.set_namespace(value, source)?,
+            other => {
+                let keys: Vec<&str> = KEYS.iter().map(|(k, _)| *k).collect();
+                return Err(VmError::ConfigInvalid {
+                    origin: source.to_string(),
+                    detail: format!("unknown setting: {other}"),
+                    hint: format!("valid settings are: {}", keys.join(", ")),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch to namespace `name` from the layer `source`, rejecting names that are not usable as
+    /// a directory name.
+    pub fn set_namespace(&mut self, name: &str, source: ConfigSource) -> Result<()> {
+        if !valid_namespace(name) {
+            return Err(VmError::ConfigInvalid {
+                origin: source.to_string(),
+                detail: format!("invalid namespace: {name}"),
+                hint: "use letters, digits, '-', '_' and '.', not starting with '.'".into(),
+            });
+        }
+        self.namespace.set(name.into(), source);
+        Ok(())
+    }
+
+    /// Directory holding one subdirectory per namespace.
+    pub fn namespaces_dir(&self) -> PathBuf {
+        self.data_dir.value.join("namespaces")
+    }
+
+    /// Directory for the current namespace: its VM store and work directories.
+    pub fn namespace_dir(&self) -> PathBuf {
+        self.namespaces_dir().join(&self.namespace.value)
+    }
+
+    /// Lifecycle counters of the current namespace, reported by `vmctl serve-metrics`.
+    pub fn counters_file(&self) -> PathBuf {
+        self.namespace_dir().join("counters.json")
+    }
+
+    /// Crashes and restarts recorded by `vmctl watch` in the current namespace, as JSON lines.
+    pub fn events_journal(&self) -> PathBuf {
+        self.namespace_dir().join("events.jsonl")
+    }
+
+    /// Directory holding one work directory per local VM of the current namespace.
+    pub fn vms_dir(&self) -> PathBuf {
+        self.namespace_dir().join("vms")
+    }
+
+    /// The effective image cache directory.
+    pub fn image_cache(&self) -> PathBuf {
+        self.image_cache_dir
+            .value
+            .clone()
+            .unwrap_or_else(|| self.data_dir.value.join("images"))
+    }
+
+    /// Directory holding managed network state (dnsmasq configuration and leases).
+    pub fn networks_dir(&self) -> PathBuf {
+        self.data_dir.value.join("networks")
+    }
+
+    /// An image manager that downloads into [`image_cache`](Self::image_cache).
+    pub fn image_manager(&self) -> ImageManager {
+        ImageManager::with_cache_dir(self.image_cache())
+    }
+}
+
+/// Whether `name` can be used as a namespace, which becomes a directory name.
+fn valid_namespace(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Attribute a value parser's `ConfigInvalid` error to the layer the value came from.
+fn with_origin(e: VmError, source: &ConfigSource) -> VmError {
+    match e {
+        VmError::ConfigInvalid { detail, hint, .. } => VmError::ConfigInvalid {
+            origin: source.to_string(),
+            detail,
+            hint,
+        },
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_override_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = dir.path().join("system.kdl");
+        let user = dir.path().join("user.kdl");
+        std::fs::write(
+            &system,
+            "data-dir \"/srv/vmctl\"\nbridge \"br0\"\nssh-user \"admin\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &user,
+            "bridge \"br1\"\nbackend \"noop\"\nhostnames \"mdns\"\nfirmware-dir \"/opt/edk2\"\n",
+        )
+        .unwrap();
+
+        let files = [system.clone(), user.clone(), dir.path().join("missing.kdl")];
+        let config = Config::load_from(&files, |var| {
+            (var == "VMCTL_SSH_USER").then(|| "ubuntu".to_string())
+        })
+        .unwrap();
+
+        assert_eq!(config.data_dir.value, PathBuf::from("/srv/vmctl"));
+        assert_eq!(config.data_dir.source, ConfigSource::File(system));
+        assert_eq!(config.bridge.value.as_deref(), Some("br1"));
+        assert_eq!(config.bridge.source, ConfigSource::File(user));
+        assert_eq!(config.backend.value, Some(BackendTag::Noop));
+        assert_eq!(config.hostnames.value, HostnameMode::Mdns);
+        assert_eq!(config.ssh_user.value, "ubuntu");
+        assert_eq!(config.ssh_user.source, ConfigSource::Env("VMCTL_SSH_USER"));
+        assert_eq!(config.qemu_binary.source, ConfigSource::Default);
+        assert_eq!(config.firmware_dir.value, Some(PathBuf::from("/opt/edk2")));
+        assert_eq!(config.image_cache(), PathBuf::from("/srv/vmctl/images"));
+        assert_eq!(
+            config.vms_dir(),
+            PathBuf::from("/srv/vmctl/namespaces/default/vms")
+        );
+        assert_eq!(config.networks_dir(), PathBuf::from("/srv/vmctl/networks"));
+    }
+
+    #[test]
+    fn lease_sources_take_lists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.kdl");
+        std::fs::write(
+            &path,
+            "lease-sources \"libvirt:/var/lib/libvirt/dnsmasq\" \"dnsmasq:/srv/dnsmasq.leases\"\n",
+        )
+        .unwrap();
+
+        let config = Config::load_from(std::slice::from_ref(&path), |_| None).unwrap();
+        let sources: Vec<String> = config
+            .lease_sources
+            .value
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                "libvirt:/var/lib/libvirt/dnsmasq",
+                "dnsmasq:/srv/dnsmasq.leases"
+            ]
+        );
+
+        let config = Config::load_from(&[path], |var| {
+            (var == "VMCTL_LEASE_SOURCES")
+                .then(|| "networkd:/run/leases, dnsmasq:/tmp/x.leases".to_string())
+        })
+        .unwrap();
+        assert_eq!(config.lease_sources.value.len(), 2);
+        assert_eq!(
+            config.lease_sources.value[0].to_string(),
+            "networkd:/run/leases"
+        );
+
+        assert_eq!(
+            Config::default().lease_sources.value,
+            LeaseSource::defaults()
+        );
+
+        std::fs::write(dir.path().join("bad.kdl"), "bridge \"br0\" \"br1\"\n").unwrap();
+        assert!(Config::load_from(&[dir.path().join("bad.kdl")], |_| None).is_err());
+    }
+
+    #[test]
+    fn namespace_defaults_to_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.kdl");
+        let env = |var: &str| (var == "USER").then(|| "alice".to_string());
+
+        let config = Config::load_from(std::slice::from_ref(&path), env).unwrap();
+        assert_eq!(config.namespace.value, "alice");
+        assert_eq!(config.namespace.source, ConfigSource::Default);
+
+        std::fs::write(&path, "data-dir \"/srv/vmctl\"\nnamespace \"ci\"\n").unwrap();
+        let config = Config::load_from(std::slice::from_ref(&path), env).unwrap();
+        assert_eq!(config.namespace.value, "ci");
+        assert_eq!(
+            config.vms_dir(),
+            PathBuf::from("/srv/vmctl/namespaces/ci/vms")
+        );
+
+        for bad in ["../bob", ".hidden", ""] {
+            std::fs::write(&path, format!("namespace \"{bad}\"\n")).unwrap();
+            let err = Config::load_from(std::slice::from_ref(&path), |_| None).unwrap_err();
+            assert!(
+                err.to_string().contains("invalid namespace"),
+                "{bad}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.kdl");
+        std::fs::write(&path, "bridg \"br0\"\n").unwrap();
+        let err = Config::load_from(&[path], |_| None).unwrap_err();
+        assert!(err.to_string().contains("unknown setting: bridg"), "{err}");
+    }
+}
@@ -33,6 +33,7 @@ use tokio::io::AsyncBufReadExt;
 use tokio::sync::{mpsc, watch};
 use tracing::{debug, warn};
 
+use crate::backends::remote::RemoteHost;
 use crate::error::{Result, VmError};
 use crate::traits::ConsoleEndpoint;
 
@@ -52,6 +53,13 @@ impl ConsoleTailer {
     ) -> Result<()> {
         match endpoint {
             ConsoleEndpoint::UnixSocket(path) => Self::tail_unix_socket(&path, tx, &mut stop).await,
+            ConsoleEndpoint::RemoteUnixSocket { host, path } => {
+                let host = RemoteHost::parse(&host)?;
+                let dir = tempfile::tempdir()?;
+                let local = dir.path().join("console.sock");
+                let _tunnel = host.tunnel_unix(&path, &local).await?;
+                Self::tail_unix_socket(&local, tx, &mut stop).await
+            }
             ConsoleEndpoint::WebSocket(_url) => {
                 // TODO: WebSocket console tailing for Propolis
                 warn!("WebSocket console tailing not yet implemented");
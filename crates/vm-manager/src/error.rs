@@ -9,14 +9,29 @@ use thiserror::Error;
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum VmError {
-    #[error("failed to spawn QEMU process: {source}")]
+    #[error(
+        "failed to spawn QEMU process: {source}{}",
+        .stderr.as_deref().map(|s| format!("\n{s}")).unwrap_or_default()
+    )]
     #[diagnostic(
         code(vm_manager::qemu::spawn_failed),
         help(
             "ensure qemu-system-x86_64 is installed and in PATH, and that KVM is available (/dev/kvm)"
         )
     )]
-    QemuSpawnFailed { source: std::io::Error },
+    QemuSpawnFailed {
+        source: std::io::Error,
+        stderr: Option<String>,
+    },
+
+    #[error("QEMU is not usable on this host: {reason}")]
+    #[diagnostic(
+        code(vm_manager::qemu::unavailable),
+        help(
+            "install qemu-system for this architecture and make sure /dev/kvm is accessible (`vmctl doctor` shows what is missing), or choose another backend with --backend"
+        )
+    )]
+    QemuUnavailable { reason: String },
 
     #[error("failed to connect to QMP socket at {}: {source}", path.display())]
     #[diagnostic(
@@ -34,6 +49,27 @@ pub enum VmError {
     #[diagnostic(code(vm_manager::qemu::qmp_command_failed))]
     QmpCommandFailed { message: String },
 
+    #[error("QMP command {command} failed: {class}: {desc}")]
+    #[diagnostic(code(vm_manager::qemu::qmp_error))]
+    QmpError {
+        class: String,
+        desc: String,
+        command: String,
+    },
+
+    #[error("the QEMU guest agent of VM {vm} is not available: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::guest_agent_unavailable),
+        help(
+            "install and start qemu-guest-agent in the guest, e.g. with cloud-init user-data `packages: [qemu-guest-agent]` and `runcmd: [[systemctl, enable, --now, qemu-guest-agent]]`; VMs started before vmctl added the agent channel must be restarted"
+        )
+    )]
+    GuestAgentUnavailable { vm: String, detail: String },
+
+    #[error("guest agent command failed: {message}")]
+    #[diagnostic(code(vm_manager::qemu::guest_agent_command_failed))]
+    GuestAgentCommandFailed { message: String },
+
     #[error("failed to create QCOW2 overlay from base image {}: {detail}", base.display())]
     #[diagnostic(
         code(vm_manager::image::overlay_creation_failed),
@@ -41,14 +77,158 @@ pub enum VmError {
     )]
     OverlayCreationFailed { base: PathBuf, detail: String },
 
-    #[error("timed out waiting for guest IP address for VM {name}")]
+    #[error("OVMF firmware for UEFI boot not found (searched: {searched})")]
+    #[diagnostic(
+        code(vm_manager::qemu::firmware_not_found),
+        help(
+            "install OVMF (the ovmf or edk2-ovmf package), or point `firmware-dir` in the vmctl config at the directory that holds OVMF_CODE and OVMF_VARS"
+        )
+    )]
+    FirmwareNotFound { searched: String },
+
+    #[error("OVMF firmware with Secure Boot and enrolled keys not found (searched: {searched})")]
+    #[diagnostic(
+        code(vm_manager::qemu::secure_boot_firmware_not_found),
+        help(
+            "install the edk2-ovmf (Fedora) or ovmf (Debian, Ubuntu) package, which ship OVMF_CODE.secboot.fd with a vars template holding the Microsoft keys, or point `firmware-dir` at a directory with such a pair"
+        )
+    )]
+    SecureBootFirmwareNotFound { searched: String },
+
+    #[error("AMD SEV is not available on this host: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::sev_unavailable),
+        help(
+            "SEV needs an AMD EPYC host with SEV enabled in the BIOS and kvm_amd loaded with `sev=1` (check /sys/module/kvm_amd/parameters/sev and `dmesg | grep -i sev`)"
+        )
+    )]
+    SevUnavailable { detail: String },
+
+    #[error("VM {vm} is confidential, which rules out {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::confidential_conflict),
+        help(
+            "the host cannot read an encrypted guest's memory; drop the conflicting option or create the VM without `confidential`"
+        )
+    )]
+    ConfidentialConflict { vm: String, detail: String },
+
+    #[error("cannot pin the threads of VM {vm}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::cpu_pinning_failed),
+        help(
+            "pin only to host CPUs listed in /sys/devices/system/cpu/online and allowed by vmctl's own cpuset (see `taskset -cp $$`)"
+        )
+    )]
+    CpuPinningFailed { vm: String, detail: String },
+
+    #[error("invalid NUMA layout for VM {vm}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::numa_invalid),
+        help(
+            "give every vCPU to exactly one node, and make the nodes' memory add up to the VM's memory"
+        )
+    )]
+    NumaLayoutInvalid { vm: String, detail: String },
+
+    #[error("invalid boot order for VM {vm}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::boot_order_invalid),
+        help(
+            "boot from disk, cdrom or network, each at most once; cdrom needs an ISO attached, and microvm and direct kernel boot take no boot order"
+        )
+    )]
+    BootOrderInvalid { vm: String, detail: String },
+
+    #[error("invalid SMBIOS settings for VM {vm}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::smbios_invalid),
+        help(
+            "SMBIOS strings must be 1 to 255 bytes of printable ASCII, and the uuid a hyphenated UUID"
+        )
+    )]
+    SmbiosInvalid { vm: String, detail: String },
+
+    #[error("cannot back the memory of VM {vm} with huge pages: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::hugepages_unavailable),
+        help(
+            "reserve huge pages with `echo N | sudo tee /proc/sys/vm/nr_hugepages` and mount hugetlbfs on /dev/hugepages"
+        )
+    )]
+    HugepagesUnavailable { vm: String, detail: String },
+
+    #[error("QEMU on this host can't run VM {vm}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::qemu::unsupported),
+        help(
+            "upgrade QEMU (e.g. from your distribution's backports), point `qemu-binary` in the vmctl config at a newer build, or change the VM's settings so it doesn't need the feature; `vmctl doctor` shows the QEMU version"
+        )
+    )]
+    QemuUnsupported { vm: String, detail: String },
+
+    #[error("not enough host resources for {vm}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::vm::insufficient_resources),
+        help(
+            "stop other VMs or free up disk space, give the VM less memory or a smaller disk, or pass --force to try anyway"
+        )
+    )]
+    InsufficientResources { vm: String, detail: String },
+
+    #[error("VM {vm} plays audio through {backend}, which this QEMU build does not support")]
+    #[diagnostic(
+        code(vm_manager::qemu::audio_unavailable),
+        help(
+            "this QEMU supports: {available}; set the VM's audio to one of those or \"none\", or install QEMU's {backend} audio module (qemu-audio-{backend} on Fedora)"
+        )
+    )]
+    AudioBackendUnavailable {
+        vm: String,
+        backend: String,
+        available: String,
+    },
+
+    #[error("USB device {device} for VM {vm} is not plugged into the host")]
+    #[diagnostic(
+        code(vm_manager::usb::not_found),
+        help(
+            "run `vmctl usb list` to see the host's devices; a bus.device address changes when the device is replugged, so prefer vendor:product"
+        )
+    )]
+    UsbDeviceNotFound { vm: String, device: String },
+
+    #[error("cannot open USB device {device} for VM {vm}: permission denied on {}", path.display())]
+    #[diagnostic(
+        code(vm_manager::usb::permission_denied),
+        help(
+            "add the udev rule `{rule}` to /etc/udev/rules.d/70-vmctl-usb.rules, run `sudo udevadm control --reload && sudo udevadm trigger` (or replug the device), and join the kvm group"
+        )
+    )]
+    UsbPermissionDenied {
+        vm: String,
+        device: String,
+        path: PathBuf,
+        rule: String,
+    },
+
+    #[error("extra QEMU argument {arg} conflicts with an option vmctl manages")]
+    #[diagnostic(
+        code(vm_manager::qemu::managed_arg),
+        help(
+            "vmctl sets -qmp, -pidfile and -daemonize itself; remove {arg} from the extra QEMU arguments"
+        )
+    )]
+    QemuArgManaged { arg: String },
+
+    #[error("timed out waiting for guest IP address for VM {name} (MAC {mac})")]
     #[diagnostic(
         code(vm_manager::network::ip_discovery_timeout),
         help(
             "the guest may not have obtained a DHCP lease — check bridge/network configuration and that the guest cloud-init is configured correctly"
         )
     )]
-    IpDiscoveryTimeout { name: String },
+    IpDiscoveryTimeout { name: String, mac: String },
 
     #[error("propolis server at {addr} is unreachable: {source}")]
     #[diagnostic(
@@ -71,6 +251,15 @@ pub enum VmError {
     )]
     CloudInitIsoFailed { detail: String },
 
+    #[error("invalid cloud-config: {detail}")]
+    #[diagnostic(
+        code(vm_manager::cloudinit::invalid_config),
+        help(
+            "cloud-config user-data must be a YAML mapping, optionally after a `#cloud-config` line"
+        )
+    )]
+    CloudConfigInvalid { detail: String },
+
     #[error("SSH operation failed: {detail}")]
     #[diagnostic(
         code(vm_manager::ssh::failed),
@@ -78,6 +267,13 @@ pub enum VmError {
     )]
     SshFailed { detail: String },
 
+    #[error("VM {vm} uses user-mode networking but has no forwarded SSH port")]
+    #[diagnostic(
+        code(vm_manager::ssh::no_port),
+        help("recreate the VM so a forwarded SSH port is allocated")
+    )]
+    SshPortMissing { vm: String },
+
     #[error("failed to generate SSH keypair: {detail}")]
     #[diagnostic(
         code(vm_manager::ssh::keygen_failed),
@@ -85,6 +281,19 @@ pub enum VmError {
     )]
     SshKeygenFailed { detail: String },
 
+    #[error("failed to import the SSH keys of {account} from {url}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::ssh::key_import_failed),
+        help(
+            "check the account name, that it has public SSH keys on the code host, and network connectivity"
+        )
+    )]
+    SshKeyImportFailed {
+        account: String,
+        url: String,
+        detail: String,
+    },
+
     #[error("failed to download image from {url}: {detail}")]
     #[diagnostic(
         code(vm_manager::image::download_failed),
@@ -92,6 +301,24 @@ pub enum VmError {
     )]
     ImageDownloadFailed { url: String, detail: String },
 
+    #[error("failed to upload image to {url}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::image::upload_failed),
+        help(
+            "check that the server accepts PUT at this URL and the credentials; `vmctl image list` shows the cached images"
+        )
+    )]
+    ImageUploadFailed { url: String, detail: String },
+
+    #[error("packer build of {} failed: {detail}", template.display())]
+    #[diagnostic(
+        code(vm_manager::image::build_failed),
+        help(
+            "check packer's output above; the template must write its image to the path given in the `output` variable"
+        )
+    )]
+    ImageBuildFailed { template: PathBuf, detail: String },
+
     #[error("image format detection failed for {}: {detail}", path.display())]
     #[diagnostic(
         code(vm_manager::image::format_detection_failed),
@@ -106,6 +333,37 @@ pub enum VmError {
     )]
     ImageConversionFailed { detail: String },
 
+    #[error("failed to resize disk image {}: {detail}", path.display())]
+    #[diagnostic(
+        code(vm_manager::image::resize_failed),
+        help("ensure qemu-img is installed and the VM is not using the image")
+    )]
+    DiskResizeFailed { path: PathBuf, detail: String },
+
+    #[error("snapshot '{snapshot}' not found in {} (snapshots: {available})", path.display())]
+    #[diagnostic(
+        code(vm_manager::image::snapshot_not_found),
+        help("list a disk's snapshots with `qemu-img snapshot -l <overlay>`")
+    )]
+    SnapshotNotFound {
+        snapshot: String,
+        path: PathBuf,
+        available: String,
+    },
+
+    #[error("snapshot '{snapshot}' of {} failed: {detail}", path.display())]
+    #[diagnostic(
+        code(vm_manager::image::snapshot_failed),
+        help(
+            "snapshots need every writable disk to be QCOW2; UEFI guests, whose variable store is raw, can only be snapshotted while stopped"
+        )
+    )]
+    SnapshotFailed {
+        snapshot: String,
+        path: PathBuf,
+        detail: String,
+    },
+
     #[error("VM {name} not found")]
     #[diagnostic(
         code(vm_manager::vm::not_found),
@@ -113,10 +371,40 @@ pub enum VmError {
     )]
     VmNotFound { name: String },
 
+    #[error("VM '{name}' already exists")]
+    #[diagnostic(
+        code(vm_manager::vm::name_exists),
+        help("choose a different name or destroy the existing VM with `vmctl destroy {name}`")
+    )]
+    VmAlreadyExists { name: String },
+
+    #[error("VM store {} is unreadable: {detail}", path.display())]
+    #[diagnostic(
+        code(vm_manager::store::corrupt),
+        help("fix or move the file aside; VMs missing from the store are no longer managed")
+    )]
+    StoreCorrupt { path: PathBuf, detail: String },
+
+    #[error("cannot keep the disk of VM {vm}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::vm::disk_not_kept),
+        help(
+            "nothing was changed; destroy the VM without --keep-disk, or copy what you need out of it first"
+        )
+    )]
+    DiskNotKept { vm: String, detail: String },
+
     #[error("VM {name} is in state {state} which does not allow this operation")]
     #[diagnostic(code(vm_manager::vm::invalid_state))]
     InvalidState { name: String, state: String },
 
+    #[error("the {backend} backend does not support dry runs")]
+    #[diagnostic(
+        code(vm_manager::backend::dry_run_unsupported),
+        help("run without --dry-run, or use the QEMU backend to see its command line")
+    )]
+    DryRunUnsupported { backend: String },
+
     #[error("backend not available: {backend}")]
     #[diagnostic(
         code(vm_manager::backend::not_available),
@@ -138,6 +426,23 @@ pub enum VmError {
     )]
     VmFileParseFailed { location: String, detail: String },
 
+    #[error("template '{name}' not found")]
+    #[diagnostic(
+        code(vm_manager::template::not_found),
+        help(
+            "available templates: {available}; add your own as ~/.config/vmctl/templates/<name>.kdl"
+        )
+    )]
+    TemplateNotFound { name: String, available: String },
+
+    #[error("invalid template '{name}': {detail}")]
+    #[diagnostic(code(vm_manager::template::invalid), help("{hint}"))]
+    TemplateInvalid {
+        name: String,
+        detail: String,
+        hint: String,
+    },
+
     #[error("VMFile validation error in VM '{vm}': {detail}")]
     #[diagnostic(code(vm_manager::vmfile::validation), help("{hint}"))]
     VmFileValidation {
@@ -146,6 +451,21 @@ pub enum VmError {
         hint: String,
     },
 
+    #[error("circular dependency between VMs: {cycle}")]
+    #[diagnostic(
+        code(vm_manager::vmfile::circular_dependency),
+        help("remove one of the depends-on entries in the cycle")
+    )]
+    CircularDependency { cycle: String },
+
+    #[error("invalid configuration in {origin}: {detail}")]
+    #[diagnostic(code(vm_manager::config::invalid), help("{hint}"))]
+    ConfigInvalid {
+        origin: String,
+        detail: String,
+        hint: String,
+    },
+
     #[error("provisioning failed for VM '{vm}' at step {step}: {detail}")]
     #[diagnostic(
         code(vm_manager::provision::failed),
@@ -153,7 +473,7 @@ pub enum VmError {
     )]
     ProvisionFailed {
         vm: String,
-        step: usize,
+        step: String,
         detail: String,
     },
 
@@ -166,6 +486,84 @@ pub enum VmError {
     )]
     OciPullFailed { reference: String, detail: String },
 
+    #[error("remote host {host}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::remote::failed),
+        help(
+            "check that the host is reachable with `ssh` without a password prompt and that `vmctl` is installed and on its PATH"
+        )
+    )]
+    RemoteFailed { host: String, detail: String },
+
+    #[error("{detail}")]
+    #[diagnostic(code(vm_manager::network::invalid), help("{hint}"))]
+    NetworkInvalid { detail: String, hint: String },
+
+    #[error("network {name} not found")]
+    #[diagnostic(
+        code(vm_manager::network::not_found),
+        help("run `vmctl network list` to see managed networks")
+    )]
+    NetworkNotFound { name: String },
+
+    #[error("network {name}: {detail}")]
+    #[diagnostic(
+        code(vm_manager::network::setup_failed),
+        help(
+            "run `vmctl network inspect {name}` to see which host resources are still recorded, then retry `vmctl network delete {name}`"
+        )
+    )]
+    NetworkSetupFailed { name: String, detail: String },
+
+    #[error("permission denied: root is required to {operation}")]
+    #[diagnostic(
+        code(vm_manager::network::requires_root),
+        help(
+            "managed networks create bridges, firewall rules and a dnsmasq process; run the command with sudo, using the same --data-dir as your VMs (or set data-dir in /etc/vmctl/config.kdl)"
+        )
+    )]
+    NetworkRequiresRoot { operation: String },
+
+    #[error("cannot update {}: permission denied", path.display())]
+    #[diagnostic(
+        code(vm_manager::hostnames::hosts_not_writable),
+        help(
+            "registering hostnames in /etc/hosts requires root: run the command with sudo, or set `hostnames \"mdns\"` in the vmctl config to publish <name>.local over mDNS instead"
+        )
+    )]
+    HostsFileNotWritable { path: PathBuf },
+
+    #[error("mDNS responder for {hostname} failed: {detail}")]
+    #[diagnostic(
+        code(vm_manager::hostnames::mdns_failed),
+        help(
+            "the responder needs UDP port 5353 on the host; make sure no firewall blocks multicast and that any running mDNS daemon allows port sharing"
+        )
+    )]
+    MdnsFailed { hostname: String, detail: String },
+
+    #[error("host port {port} for VM '{vm}' is already in use by {holder}")]
+    #[diagnostic(
+        code(vm_manager::qemu::host_port_in_use),
+        help(
+            "stop whatever holds the port, or give the VM a different port with ssh-port on its network node"
+        )
+    )]
+    HostPortInUse {
+        vm: String,
+        port: u16,
+        holder: String,
+    },
+
+    #[error("{operation} was cancelled")]
+    #[diagnostic(
+        code(vm_manager::cancelled),
+        help(
+            "partial files and processes from the interrupted step were cleaned up; run the command again"
+        )
+    )]
+    Cancelled { operation: String },
+
     #[error(transparent)]
     #[diagnostic(code(vm_manager::io))]
     Io(#[from] std::io::Error),
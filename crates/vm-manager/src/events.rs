@@ -0,0 +1,214 @@
+//! Structured lifecycle events for library consumers.
+//!
+//! [`RouterHypervisor`](crate::RouterHypervisor), [`ImageManager`](crate::image::ImageManager)
+//! and [`run_provisions`](crate::provision::run_provisions) publish [`VmEvent`]s into an
+//! [`EventBus`]. Give them the same bus and [`subscribe`](EventBus::subscribe) to follow a whole
+//! run without parsing log output.
+//!
+//! Publishing never blocks or fails. Events published while nobody is subscribed are dropped,
+//! and a subscriber that falls more than [`EVENT_CAPACITY`] events behind skips the oldest ones
+//! (`RecvError::Lagged`).
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before the oldest are dropped.
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened to a VM, an image download or a provision run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum VmEvent {
+    VmPrepared {
+        vm: String,
+    },
+    VmStarted {
+        vm: String,
+        pid: Option<u32>,
+    },
+    VmStopped {
+        vm: String,
+    },
+    VmDestroyed {
+        vm: String,
+    },
+    IpDiscovered {
+        vm: String,
+        ip: String,
+    },
+    /// `step` is the step's name, or its 1-based number if it has none.
+    ProvisionStepStarted {
+        vm: String,
+        step: String,
+    },
+    ProvisionStepFinished {
+        vm: String,
+        step: String,
+    },
+    /// `total` is `None` when the server didn't send a content length.
+    DownloadProgress {
+        url: String,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    /// A VM's process went away without vmctl stopping it. `exit_status` says how, e.g. QEMU's
+    /// shutdown reason `guest-panic`; `console_tail` holds the last lines of its console log.
+    VmCrashed {
+        vm: String,
+        exit_status: String,
+        console_tail: Vec<String>,
+    },
+    /// A VM was started again after it stopped on its own; `attempt` counts the restarts since
+    /// it last stayed up.
+    VmRestarted {
+        vm: String,
+        attempt: u32,
+    },
+    /// An operation failed; the same error is also returned to the caller.
+    Error {
+        vm: Option<String>,
+        message: String,
+    },
+}
+
+/// Broadcast channel of [`VmEvent`]s. Clones publish into the same channel.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<VmEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { tx }
+    }
+
+    /// Receive every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<VmEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn publish(&self, event: VmEvent) {
+        // Only fails when nobody is subscribed.
+        let _ = self.tx.send(event);
+    }
+}
+
+/// Append-only record of events, one JSON object per line with the event's `time` in unix
+/// seconds added.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+#[derive(Serialize)]
+struct JournalEntry<'a> {
+    time: u64,
+    #[serde(flatten)]
+    event: &'a VmEvent,
+}
+
+impl Journal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `event`, creating the file and its directory if needed.
+    pub async fn append(&self, event: &VmEvent) -> std::io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut line =
+            serde_json::to_string(&JournalEntry { time, event }).map_err(std::io::Error::other)?;
+        line.push('\n');
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_see_later_events() {
+        let bus = EventBus::new();
+        bus.publish(VmEvent::VmStopped { vm: "early".into() });
+
+        let mut rx = bus.subscribe();
+        bus.clone()
+            .publish(VmEvent::VmPrepared { vm: "web".into() });
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            VmEvent::VmPrepared { vm: "web".into() }
+        );
+        assert!(rx.try_recv().is_err());
+
+        let json = serde_json::to_string(&VmEvent::IpDiscovered {
+            vm: "web".into(),
+            ip: "10.0.2.15".into(),
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"event":"ip_discovered","vm":"web","ip":"10.0.2.15"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn journal_appends_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().join("ns/events.jsonl"));
+        journal
+            .append(&VmEvent::VmCrashed {
+                vm: "web".into(),
+                exit_status: "guest-panic".into(),
+                console_tail: vec!["Kernel panic".into()],
+            })
+            .await
+            .unwrap();
+        journal
+            .append(&VmEvent::VmRestarted {
+                vm: "web".into(),
+                attempt: 1,
+            })
+            .await
+            .unwrap();
+
+        let content = std::fs::read_to_string(journal.path()).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "vm_crashed");
+        assert_eq!(lines[0]["exit_status"], "guest-panic");
+        assert_eq!(lines[0]["console_tail"][0], "Kernel panic");
+        assert!(lines[0]["time"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["event"], "vm_restarted");
+        assert_eq!(lines[1]["attempt"], 1);
+    }
+}
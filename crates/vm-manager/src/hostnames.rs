@@ -0,0 +1,481 @@
+//! Host-side name resolution for guests.
+//!
+//! With [`HostnameMode::Hosts`] each running VM gets a line in a delimited block of `/etc/hosts`;
+//! with [`HostnameMode::Mdns`] a small responder answers multicast DNS queries for the VM's name
+//! (RFC 6762), so resolvers using nss-mdns, Avahi or Bonjour find it without root. Either way the
+//! VM is reachable as `<name>.local` (see [`hostname_for`]).
+
+use std::fmt;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use crate::error::{Result, VmError};
+
+/// The system hosts file.
+pub const HOSTS_FILE: &str = "/etc/hosts";
+
+const BEGIN_MARKER: &str = "# BEGIN vmctl (managed by vmctl; do not edit)";
+const END_MARKER: &str = "# END vmctl";
+
+/// Serialises hosts file read-modify-write cycles between this process's concurrent tasks, so
+/// VMs brought up together don't drop each other's entries.
+static HOSTS_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// TTL for records sent to the multicast group; RFC 6762 recommends 120s for host records.
+const MDNS_TTL: u32 = 120;
+/// TTL for answers to one-shot (legacy unicast) queries, which bypass the cache-flush rules.
+const LEGACY_TTL: u32 = 10;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// How guest hostnames are registered on the host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HostnameMode {
+    /// Don't register names.
+    #[default]
+    Off,
+    /// Maintain a managed block in `/etc/hosts` (requires root).
+    Hosts,
+    /// Answer mDNS queries from a per-VM responder process.
+    Mdns,
+}
+
+impl HostnameMode {
+    /// Parse `off`, `hosts` or `mdns`.
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "hosts" => Ok(Self::Hosts),
+            "mdns" => Ok(Self::Mdns),
+            other => Err(VmError::ConfigInvalid {
+                origin: "hostnames".into(),
+                detail: format!("unknown hostname mode: {other}"),
+                hint: "use off, hosts or mdns".into(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for HostnameMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Hosts => "hosts",
+            Self::Mdns => "mdns",
+        })
+    }
+}
+
+/// The `.local` name registered for a VM: its name lowercased, with anything that isn't valid in a
+/// DNS label replaced by `-`.
+pub fn hostname_for(vm_name: &str) -> String {
+    let label: String = vm_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("{}.local", label.trim_matches('-'))
+}
+
+/// A hosts file with a vmctl-managed block.
+#[derive(Debug, Clone)]
+pub struct HostsFile {
+    path: PathBuf,
+}
+
+impl Default for HostsFile {
+    fn default() -> Self {
+        Self::new(HOSTS_FILE)
+    }
+}
+
+impl HostsFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Fail early with [`VmError::HostsFileNotWritable`] unless the file can be updated, so a VM
+    /// isn't started only to leave its name unregistered.
+    pub async fn check_writable(&self) -> Result<()> {
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await
+            .map(drop)
+            .map_err(|e| self.map_err(e))
+    }
+
+    /// Point `hostnames` at `ip`, replacing any entry previously registered for `vm`.
+    pub async fn register(&self, vm: &str, ip: &str, hostnames: &[String]) -> Result<()> {
+        let line = format!("{ip}\t{}", hostnames.join(" "));
+        self.update(vm, Some(&line)).await
+    }
+
+    /// Remove `vm`'s entry; the block itself goes away with its last entry.
+    pub async fn unregister(&self, vm: &str) -> Result<()> {
+        self.update(vm, None).await
+    }
+
+    async fn update(&self, vm: &str, line: Option<&str>) -> Result<()> {
+        let _guard = HOSTS_LOCK.lock().await;
+        let content = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| self.map_err(e))?;
+        let updated = update_block(&content, vm, line);
+        if updated == content {
+            return Ok(());
+        }
+        // Written in place rather than renamed over: /etc/hosts is often a bind mount.
+        tokio::fs::write(&self.path, updated)
+            .await
+            .map_err(|e| self.map_err(e))
+    }
+
+    fn map_err(&self, e: std::io::Error) -> VmError {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            VmError::HostsFileNotWritable {
+                path: self.path.clone(),
+            }
+        } else {
+            VmError::Io(e)
+        }
+    }
+}
+
+/// Replace `vm`'s line in the managed block of `content` with `line` (or drop it when `None`).
+///
+/// Lines in the block are tagged with a trailing `# <vm>` comment; everything outside the block
+/// is left untouched. A missing block is appended at the end of the file.
+pub fn update_block(content: &str, vm: &str, line: Option<&str>) -> String {
+    let mut before = Vec::new();
+    let mut block = Vec::new();
+    let mut after = Vec::new();
+    let mut section = 0;
+    for l in content.lines() {
+        match (section, l.trim()) {
+            (0, BEGIN_MARKER) => section = 1,
+            (1, END_MARKER) => section = 2,
+            (0, _) => before.push(l.to_string()),
+            (1, _) => block.push(l.to_string()),
+            _ => after.push(l.to_string()),
+        }
+    }
+
+    block.retain(|l| l.rsplit_once("\t# ").map(|(_, tag)| tag) != Some(vm));
+    if let Some(line) = line {
+        block.push(format!("{line}\t# {vm}"));
+    }
+
+    let mut out = before;
+    if !block.is_empty() {
+        out.push(BEGIN_MARKER.into());
+        out.extend(block);
+        out.push(END_MARKER.into());
+    }
+    out.extend(after);
+    let mut text = out.join("\n");
+    text.push('\n');
+    text
+}
+
+/// Answer mDNS queries for `hostname` with `ip` until `shutdown` completes, then withdraw the
+/// record with a goodbye packet.
+///
+/// The record is announced on start so caches that saw an earlier address for the name (from a
+/// previous boot) are updated immediately.
+pub async fn serve_mdns(
+    hostname: &str,
+    ip: Ipv4Addr,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let failed = |e: std::io::Error| VmError::MdnsFailed {
+        hostname: hostname.into(),
+        detail: e.to_string(),
+    };
+    let socket = mdns_socket().map_err(failed)?;
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_ADDR, MDNS_PORT));
+
+    let announcement = mdns_record(0, None, hostname, ip, MDNS_TTL, CLASS_IN | CACHE_FLUSH);
+    socket.send_to(&announcement, group).await.map_err(failed)?;
+
+    tokio::pin!(shutdown);
+    let mut buf = [0u8; 9000];
+    // RFC 6762 §8.3: announce at least twice, one second apart.
+    let reannounce = tokio::time::sleep(Duration::from_secs(1));
+    tokio::pin!(reannounce);
+    let mut reannounced = false;
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = &mut reannounce, if !reannounced => {
+                reannounced = true;
+                socket.send_to(&announcement, group).await.map_err(failed)?;
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = match received {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!(error = %e, "mdns: receive failed");
+                        continue;
+                    }
+                };
+                let legacy = from.port() != MDNS_PORT;
+                if let Some(reply) = mdns_response(&buf[..len], hostname, ip, legacy) {
+                    debug!(%from, hostname, "mdns: answering query");
+                    let dest = if legacy { from } else { group };
+                    if let Err(e) = socket.send_to(&reply, dest).await {
+                        warn!(error = %e, %dest, "mdns: send failed");
+                    }
+                }
+            }
+        }
+    }
+
+    let goodbye = mdns_record(0, None, hostname, ip, 0, CLASS_IN | CACHE_FLUSH);
+    socket.send_to(&goodbye, group).await.map_err(failed)?;
+    Ok(())
+}
+
+/// A UDP socket joined to the mDNS group, sharing port 5353 with any system responder.
+fn mdns_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The reply to an mDNS `query` if it asks for `hostname`'s A record.
+///
+/// Legacy unicast queries (sent from a port other than 5353, e.g. by `dig -p 5353`) get a
+/// conventional DNS reply echoing the query ID and question.
+pub fn mdns_response(query: &[u8], hostname: &str, ip: Ipv4Addr, legacy: bool) -> Option<Vec<u8>> {
+    let id = u16::from_be_bytes([*query.first()?, *query.get(1)?]);
+    let flags = u16::from_be_bytes([*query.get(2)?, *query.get(3)?]);
+    let questions = u16::from_be_bytes([*query.get(4)?, *query.get(5)?]);
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        let start = offset;
+        let (name, end) = read_name(query, offset)?;
+        let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+        let qclass = u16::from_be_bytes([*query.get(end + 2)?, *query.get(end + 3)?]);
+        offset = end + 4;
+
+        let wanted = matches!(qtype, TYPE_A | TYPE_ANY) && qclass & !CACHE_FLUSH == CLASS_IN;
+        if wanted && name.eq_ignore_ascii_case(hostname) {
+            return Some(if legacy {
+                let question = &query[start..end + 4];
+                mdns_record(id, Some(question), hostname, ip, LEGACY_TTL, CLASS_IN)
+            } else {
+                mdns_record(0, None, hostname, ip, MDNS_TTL, CLASS_IN | CACHE_FLUSH)
+            });
+        }
+    }
+    None
+}
+
+/// An authoritative response carrying one A record, optionally echoing a question.
+fn mdns_record(
+    id: u16,
+    question: Option<&[u8]>,
+    hostname: &str,
+    ip: Ipv4Addr,
+    ttl: u32,
+    class: u16,
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(64);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&0x8400u16.to_be_bytes());
+    packet.extend_from_slice(&u16::from(question.is_some()).to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(question) = question {
+        packet.extend_from_slice(question);
+    }
+    for label in hostname.trim_end_matches('.').split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_A.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    packet.extend_from_slice(&ttl.to_be_bytes());
+    packet.extend_from_slice(&4u16.to_be_bytes());
+    packet.extend_from_slice(&ip.octets());
+    packet
+}
+
+/// Decode the (possibly compressed) name at `offset`, returning it and the offset just past it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bound pointer chasing so a malicious loop can't spin forever.
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                let pointer = ((l & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            l => {
+                let label = packet.get(offset + 1..offset + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostname_sanitizes_vm_names() {
+        assert_eq!(hostname_for("web"), "web.local");
+        assert_eq!(hostname_for("Build_Box.2"), "build-box-2.local");
+    }
+
+    #[test]
+    fn hosts_block_is_added_updated_and_removed() {
+        let original = "127.0.0.1\tlocalhost\n::1\tlocalhost\n";
+
+        let added = update_block(original, "web", Some("10.0.0.5\tweb.local"));
+        assert_eq!(
+            added,
+            format!("{original}{BEGIN_MARKER}\n10.0.0.5\tweb.local\t# web\n{END_MARKER}\n")
+        );
+
+        let both = update_block(&added, "db", Some("10.0.0.6\tdb.local"));
+        let moved = update_block(&both, "web", Some("10.0.0.9\tweb.local"));
+        assert!(moved.contains("10.0.0.9\tweb.local\t# web\n"));
+        assert!(!moved.contains("10.0.0.5"));
+        assert!(moved.contains("10.0.0.6\tdb.local\t# db\n"));
+
+        // A VM whose name is a suffix of another's keeps its neighbour's line intact.
+        let other = update_block(&moved, "b", None);
+        assert_eq!(other, moved);
+
+        let removed = update_block(&update_block(&moved, "web", None), "db", None);
+        assert_eq!(removed, original);
+    }
+
+    #[test]
+    fn hosts_block_keeps_lines_after_it() {
+        let content =
+            format!("{BEGIN_MARKER}\n1.2.3.4\ta.local\t# a\n{END_MARKER}\n10.1.1.1\tnas\n");
+        let updated = update_block(&content, "a", None);
+        assert_eq!(updated, "10.1.1.1\tnas\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_registrations_keep_every_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        std::fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let hosts = HostsFile::new(&path);
+                tokio::spawn(async move {
+                    let vm = format!("vm{i}");
+                    let ip = format!("10.0.0.{i}");
+                    hosts.register(&vm, &ip, &[hostname_for(&vm)]).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        for i in 0..16 {
+            assert!(
+                content.contains(&format!("10.0.0.{i}\tvm{i}.local\t# vm{i}\n")),
+                "{content}"
+            );
+        }
+    }
+
+    fn query(name: &str, qtype: u16, port_legacy: bool) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        let class = if port_legacy {
+            CLASS_IN
+        } else {
+            CLASS_IN | CACHE_FLUSH
+        };
+        q.extend_from_slice(&class.to_be_bytes());
+        q
+    }
+
+    #[test]
+    fn mdns_answers_a_queries_for_own_name() {
+        let ip = Ipv4Addr::new(10, 0, 0, 5);
+        let reply = mdns_response(&query("WEB.local", TYPE_A, false), "web.local", ip, false)
+            .expect("answer");
+        // Multicast answers carry ID 0, no question and the cache-flush bit.
+        assert_eq!(&reply[..8], &[0, 0, 0x84, 0, 0, 0, 0, 1]);
+        let (name, end) = read_name(&reply, 12).unwrap();
+        assert_eq!(name, "web.local");
+        assert_eq!(&reply[end..end + 4], &[0, 1, 0x80, 1]);
+        assert_eq!(&reply[reply.len() - 4..], &[10, 0, 0, 5]);
+
+        assert!(mdns_response(&query("db.local", TYPE_A, false), "web.local", ip, false).is_none());
+        assert!(mdns_response(&query("web.local", 28, false), "web.local", ip, false).is_none());
+    }
+
+    #[test]
+    fn mdns_legacy_queries_echo_id_and_question() {
+        let ip = Ipv4Addr::new(10, 0, 0, 5);
+        let q = query("web.local", TYPE_A, true);
+        let reply = mdns_response(&q, "web.local", ip, true).expect("answer");
+        assert_eq!(&reply[..2], &[0x12, 0x34]);
+        assert_eq!(&reply[4..6], &[0, 1]);
+        assert_eq!(&reply[12..q.len()], &q[12..]);
+    }
+
+    #[test]
+    fn mdns_ignores_responses_and_pointer_loops() {
+        let ip = Ipv4Addr::new(10, 0, 0, 5);
+        let mut response = query("web.local", TYPE_A, false);
+        response[2] = 0x84;
+        assert!(mdns_response(&response, "web.local", ip, false).is_none());
+
+        let looped = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1];
+        assert!(mdns_response(&looped, "web.local", ip, false).is_none());
+    }
+}
@@ -1,10 +1,18 @@
 use std::cmp::min;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use futures_util::StreamExt;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
 
 use crate::error::{Result, VmError};
+use crate::events::{EventBus, VmEvent};
+use crate::types::OverlayOptions;
 
 /// Returns the default image cache directory: `{XDG_DATA_HOME}/vmctl/images/`.
 pub fn cache_dir() -> PathBuf {
@@ -14,18 +22,32 @@ pub fn cache_dir() -> PathBuf {
         .join("images")
 }
 
+/// Bytes between `DownloadProgress` events when the server sends no content length.
+const UNSIZED_PROGRESS_STEP: u64 = 10_000_000;
+
+/// Downloaded chunks that may wait for the zstd decoder before the download pauses.
+const DECODE_QUEUE_CHUNKS: usize = 16;
+
+/// Times a download request is repeated after a transient failure, by default.
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Seconds before the first repeat of a failed download request, by default. Each further
+/// repeat waits twice as long as the one before.
+pub const DEFAULT_RETRY_DELAY_SECS: u64 = 5;
+
 /// Streaming image downloader with progress logging and zstd decompression support.
 pub struct ImageManager {
     client: reqwest::Client,
     cache: PathBuf,
+    events: EventBus,
+    cancel: CancellationToken,
+    max_retries: u32,
+    retry_delay_secs: u64,
 }
 
 impl Default for ImageManager {
     fn default() -> Self {
-        Self {
-            client: reqwest::Client::new(),
-            cache: cache_dir(),
-        }
+        Self::with_cache_dir(cache_dir())
     }
 }
 
@@ -38,39 +60,185 @@ impl ImageManager {
         Self {
             client: reqwest::Client::new(),
             cache,
+            events: EventBus::new(),
+            cancel: CancellationToken::new(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay_secs: DEFAULT_RETRY_DELAY_SECS,
         }
     }
 
+    /// Publish `DownloadProgress` events into `events`.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Abandon downloads and OCI pulls once `cancel` fires, removing the partial file.
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Repeat a download request up to `max_retries` times when the connection fails or the
+    /// server answers 429, 500, 502, 503 or 504, waiting `retry_delay_secs` before the first
+    /// repeat and doubling the wait each time.
+    pub fn with_retries(mut self, max_retries: u32, retry_delay_secs: u64) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay_secs = retry_delay_secs;
+        self
+    }
+
+    fn progress(&self, url: &str, downloaded: u64, total_size: u64) {
+        self.events.publish(VmEvent::DownloadProgress {
+            url: url.into(),
+            downloaded,
+            total: (total_size > 0).then_some(total_size),
+        });
+    }
+
     /// Download an image from `url` to `destination`.
     ///
-    /// If the file already exists at `destination`, the download is skipped.
+    /// If the file already exists at `destination`, the server is asked whether it changed with
+    /// the `ETag` and `Last-Modified` values recorded when it was downloaded, and it is only
+    /// downloaded again if so. Images with no recorded values are never downloaded again.
     /// URLs ending in `.zst` or `.zstd` are automatically decompressed.
+    ///
+    /// The image is downloaded next to `destination` and moved into place once complete, so a
+    /// failed or cancelled download leaves the previous image, or nothing, at `destination`.
     pub async fn download(&self, url: &str, destination: &Path) -> Result<()> {
-        if destination.exists() {
-            info!(url = %url, dest = %destination.display(), "image already present; skipping download");
-            return Ok(());
-        }
+        let cached = if destination.exists() {
+            let Some(validators) = read_validators(destination).await else {
+                info!(url = %url, dest = %destination.display(), "image already present; skipping download");
+                return Ok(());
+            };
+            Some(validators)
+        } else {
+            None
+        };
 
         if let Some(parent) = destination.parent() {
             tokio::fs::create_dir_all(parent).await?;
         }
 
-        let is_zstd = url.ends_with(".zst") || url.ends_with(".zstd");
+        let res = self
+            .retry_with_backoff(url, || async {
+                let mut request = self.client.get(url);
+                if let Some(ref cached) = cached {
+                    if let Some(ref etag) = cached.etag {
+                        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+                    }
+                    if let Some(ref last_modified) = cached.last_modified {
+                        request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+                    }
+                }
+                let failed = |detail: String| VmError::ImageDownloadFailed {
+                    url: url.into(),
+                    detail,
+                };
+                let res = request.send().await.map_err(|e| {
+                    let transient = e.is_connect() || e.is_timeout();
+                    (failed(e.to_string()), transient)
+                })?;
+                let status = res.status();
+                if status.is_success() || status == reqwest::StatusCode::NOT_MODIFIED {
+                    return Ok(res);
+                }
+                Err((
+                    failed(format!("server answered {status}")),
+                    is_transient(status),
+                ))
+            })
+            .await?;
+        if cached.is_some() {
+            if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+                info!(url = %url, dest = %destination.display(), "image up to date");
+                return Ok(());
+            }
+            info!(url = %url, dest = %destination.display(), "image changed on the server; downloading again");
+        }
+        let validators = CacheValidators::from_headers(res.headers());
 
-        if is_zstd {
-            self.download_zstd(url, destination).await
+        let partial = partial_path(destination);
+        let is_zstd = url.ends_with(".zst") || url.ends_with(".zstd");
+        let result = if is_zstd {
+            self.download_zstd(url, res, &partial).await
         } else {
-            self.download_raw(url, destination).await
+            self.download_raw(url, res, &partial).await
+        };
+        let hasher = match result {
+            Ok(hasher) => hasher,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(e);
+            }
+        };
+
+        tokio::fs::rename(&partial, destination).await?;
+        write_sidecar(destination, url, hasher).await?;
+        write_validators(destination, &validators).await?;
+        Ok(())
+    }
+
+    /// Run `attempt` until it succeeds, fails with an error it doesn't mark as transient, or has
+    /// been repeated `max_retries` times. The wait between attempts starts at `retry_delay_secs`
+    /// and doubles each time; `Cancelled` is returned if the manager's token fires meanwhile.
+    async fn retry_with_backoff<T, F, Fut>(&self, url: &str, mut attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::result::Result<T, (VmError, bool)>>,
+    {
+        let mut delay = Duration::from_secs(self.retry_delay_secs);
+        let mut retry = 0;
+        loop {
+            let error = match attempt().await {
+                Ok(value) => return Ok(value),
+                Err((error, true)) if retry < self.max_retries => error,
+                Err((error, _)) => return Err(error),
+            };
+            retry += 1;
+            warn!(
+                url = %url,
+                attempt = retry,
+                max_retries = self.max_retries,
+                delay_secs = delay.as_secs(),
+                error = %error,
+                "image download failed; retrying"
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.cancel.cancelled() => {
+                    return Err(VmError::Cancelled {
+                        operation: format!("download of {url}"),
+                    });
+                }
+            }
+            delay = delay.saturating_mul(2);
         }
     }
 
+    /// The next chunk of a download, or `Cancelled` once the manager's token fires.
+    async fn next_chunk<S, T>(&self, stream: &mut S, url: &str) -> Result<Option<T>>
+    where
+        S: futures_util::Stream<Item = reqwest::Result<T>> + Unpin,
+    {
+        let item = tokio::select! {
+            item = stream.next() => item,
+            _ = self.cancel.cancelled() => {
+                info!(url = %url, "download cancelled");
+                return Err(VmError::Cancelled {
+                    operation: format!("download of {url}"),
+                });
+            }
+        };
+        item.transpose().map_err(|e| VmError::ImageDownloadFailed {
+            url: url.into(),
+            detail: e.to_string(),
+        })
+    }
+
     /// Pull a QCOW2 image from an OCI registry into the cache directory.
     pub async fn pull_oci(&self, reference: &str, name: Option<&str>) -> Result<PathBuf> {
-        let file_name = name.map(|n| format!("{n}.qcow2")).unwrap_or_else(|| {
-            let sanitized = reference.replace('/', "_").replace(':', "_");
-            format!("{sanitized}.qcow2")
-        });
-        let dest = self.cache.join(&file_name);
+        let dest = self.oci_cache_path(reference, name);
         if dest.exists() {
             info!(reference, dest = %dest.display(), "OCI image already cached; skipping pull");
             return Ok(dest);
@@ -80,14 +248,107 @@ impl ImageManager {
             tokio::fs::create_dir_all(parent).await?;
         }
 
-        let data = crate::oci::pull_qcow2(reference).await?;
+        let data = tokio::select! {
+            data = crate::oci::pull_qcow2(reference) => data?,
+            _ = self.cancel.cancelled() => {
+                return Err(VmError::Cancelled {
+                    operation: format!("pull of {reference}"),
+                });
+            }
+        };
         tokio::fs::write(&dest, &data).await?;
         info!(reference, dest = %dest.display(), "OCI artifact cached");
         Ok(dest)
     }
 
-    /// Pull an image from a URL into the cache directory, returning the cached path.
+    /// Pull an image from a URL into the cache directory, returning the cached path. An
+    /// `oci://` URL is pulled with [`pull_oci`](Self::pull_oci).
     pub async fn pull(&self, url: &str, name: Option<&str>) -> Result<PathBuf> {
+        if let Some(reference) = url.strip_prefix("oci://") {
+            return self.pull_oci(reference, name).await;
+        }
+        let dest = self.cache_path(url, name);
+        self.download(url, &dest).await?;
+        Ok(dest)
+    }
+
+    /// Upload cached image `name` to `upload_url` with an HTTP `PUT`.
+    ///
+    /// The file is streamed as `application/octet-stream` with chunked transfer encoding, so
+    /// images of any size are sent without being read into memory. Any 2xx answer counts as
+    /// success.
+    pub async fn push_http(
+        &self,
+        name: &str,
+        upload_url: &str,
+        auth: Option<ImageAuth>,
+    ) -> Result<()> {
+        let path = self.cache.join(name);
+        if name.contains('/') || is_sidecar(&path) || !path.is_file() {
+            return Err(VmError::ImageUploadFailed {
+                url: upload_url.into(),
+                detail: format!("no image named '{name}' in {}", self.cache.display()),
+            });
+        }
+        let file = tokio::fs::File::open(&path).await?;
+        let size = file.metadata().await?.len();
+        let body = futures_util::stream::unfold(file, |mut file| async move {
+            let mut chunk = vec![0u8; UPLOAD_CHUNK_SIZE];
+            match tokio::io::AsyncReadExt::read(&mut file, &mut chunk).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Some((Ok::<_, std::io::Error>(chunk), file))
+                }
+                Err(e) => Some((Err(e), file)),
+            }
+        });
+
+        let mut request = self
+            .client
+            .put(upload_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(reqwest::Body::wrap_stream(body));
+        request = match auth {
+            Some(ImageAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(ImageAuth::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        };
+
+        info!(name, url = %upload_url, size_bytes = size, "uploading image");
+        let failed = |detail: String| VmError::ImageUploadFailed {
+            url: upload_url.into(),
+            detail,
+        };
+        let res = tokio::select! {
+            res = request.send() => res.map_err(|e| failed(e.to_string()))?,
+            _ = self.cancel.cancelled() => {
+                return Err(VmError::Cancelled {
+                    operation: format!("upload of {name} to {upload_url}"),
+                });
+            }
+        };
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            let body = body.trim();
+            return Err(failed(if body.is_empty() {
+                format!("HTTP {status}")
+            } else {
+                format!("HTTP {status}: {}", truncate_body(body))
+            }));
+        }
+        info!(name, url = %upload_url, "upload completed");
+        Ok(())
+    }
+
+    /// Cache location for an image pulled from `url` (optionally saved as `name`).
+    pub fn cache_path(&self, url: &str, name: Option<&str>) -> PathBuf {
+        if let Some(reference) = url.strip_prefix("oci://") {
+            return self.oci_cache_path(reference, name);
+        }
         let file_name = name.map(|n| n.to_string()).unwrap_or_else(|| {
             url.rsplit('/')
                 .next()
@@ -96,11 +357,131 @@ impl ImageManager {
                 .trim_end_matches(".zstd")
                 .to_string()
         });
-        let dest = self.cache.join(&file_name);
-        self.download(url, &dest).await?;
+        self.cache.join(file_name)
+    }
+
+    /// Cache location for the OCI artifact `reference`: `<name>.qcow2`, or the reference with
+    /// `/` and `:` replaced.
+    fn oci_cache_path(&self, reference: &str, name: Option<&str>) -> PathBuf {
+        let file_name = name.map(|n| format!("{n}.qcow2")).unwrap_or_else(|| {
+            let sanitized = reference.replace(['/', ':'], "_");
+            format!("{sanitized}.qcow2")
+        });
+        self.cache.join(file_name)
+    }
+
+    /// Build an image with `packer build` and register it in the cache as `name`.
+    ///
+    /// The template receives the destination path as `-var output=<cache>/<name>`, followed by
+    /// each `key=value` in `vars`. Packer's output goes to the caller's terminal. If the template
+    /// writes a directory (as packer's `output_directory` does), it must contain exactly one
+    /// file, which becomes the cached image.
+    pub async fn build_packer(
+        &self,
+        template: &Path,
+        name: &str,
+        vars: &[String],
+    ) -> Result<PathBuf> {
+        let failed = |detail: String| VmError::ImageBuildFailed {
+            template: template.into(),
+            detail,
+        };
+        let dest = self.cache.join(name);
+        if dest.exists() {
+            return Err(failed(format!(
+                "an image named '{name}' is already cached at {}",
+                dest.display()
+            )));
+        }
+        tokio::fs::create_dir_all(&self.cache).await?;
+
+        let args = packer_args(template, &dest, vars).map_err(failed)?;
+        info!(template = %template.display(), dest = %dest.display(), "running packer build");
+        let build = tokio::process::Command::new("packer")
+            .args(&args)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .status();
+        let status = tokio::select! {
+            status = build => status.map_err(|e| failed(format!("packer not found: {e}")))?,
+            _ = self.cancel.cancelled() => {
+                remove_path(&dest).await;
+                return Err(VmError::Cancelled {
+                    operation: format!("packer build of {}", template.display()),
+                });
+            }
+        };
+        if !status.success() {
+            remove_path(&dest).await;
+            return Err(failed(format!("packer exited with {status}")));
+        }
+
+        if dest.is_dir() {
+            let image = single_file(&dest).await.map_err(|detail| {
+                failed(format!("{detail} in output directory {}", dest.display()))
+            })?;
+            let staged = self.cache.join(format!(".{name}.build"));
+            tokio::fs::rename(&image, &staged).await?;
+            tokio::fs::remove_dir_all(&dest).await?;
+            tokio::fs::rename(&staged, &dest).await?;
+        } else if !dest.exists() {
+            return Err(failed(format!("nothing was written to {}", dest.display())));
+        }
+
+        info!(name, dest = %dest.display(), "packer image cached");
         Ok(dest)
     }
 
+    /// Compare a cached image against the published checksum without downloading the image.
+    ///
+    /// Fetches `checksum_url` (a `SHA256SUMS`-style list or a single-hash `.sha256` file) and
+    /// compares the entry for `url`'s file name with the SHA-256 recorded when the image was
+    /// pulled. Images cached before checksums were recorded report as stale.
+    pub async fn check_remote(
+        &self,
+        url: &str,
+        checksum_url: &str,
+        name: Option<&str>,
+    ) -> Result<CheckResult> {
+        let dest = self.cache_path(url, name);
+        if !dest.exists() {
+            return Ok(CheckResult::NotCached);
+        }
+
+        let fetch_failed = |detail: String| VmError::ImageDownloadFailed {
+            url: checksum_url.into(),
+            detail,
+        };
+        let sums = self
+            .client
+            .get(checksum_url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| fetch_failed(e.to_string()))?
+            .text()
+            .await
+            .map_err(|e| fetch_failed(e.to_string()))?;
+
+        let file = url.rsplit('/').next().unwrap_or(url);
+        let expected = parse_checksum(&sums, file)
+            .ok_or_else(|| fetch_failed(format!("no SHA-256 checksum listed for {file}")))?;
+
+        Ok(match read_sidecar(&dest).await {
+            Some((recorded, _)) if recorded.eq_ignore_ascii_case(expected) => {
+                CheckResult::CachedAndValid
+            }
+            _ => CheckResult::CachedButStale,
+        })
+    }
+
+    /// The URL a cached image was pulled from, if it was recorded.
+    pub async fn cached_source(&self, name: &str) -> Option<String> {
+        read_sidecar(&self.cache.join(name))
+            .await
+            .map(|(_, url)| url)
+    }
+
     /// List all cached images.
     pub async fn list(&self) -> Result<Vec<CachedImage>> {
         let mut entries = Vec::new();
@@ -111,12 +492,14 @@ impl ImageManager {
         let mut dir = tokio::fs::read_dir(cache).await?;
         while let Some(entry) = dir.next_entry().await? {
             let path = entry.path();
-            if path.is_file() {
+            if path.is_file() && !is_sidecar(&path) {
                 let metadata = entry.metadata().await?;
+                let sha256 = read_sidecar(&path).await.map(|(hash, _)| hash);
                 entries.push(CachedImage {
                     name: entry.file_name().to_string_lossy().to_string(),
                     path,
                     size_bytes: metadata.len(),
+                    sha256,
                 });
             }
         }
@@ -124,49 +507,46 @@ impl ImageManager {
         Ok(entries)
     }
 
-    async fn download_zstd(&self, url: &str, destination: &Path) -> Result<()> {
-        let res = self
-            .client
-            .get(url)
-            .send()
-            .await
-            .map_err(|e| VmError::ImageDownloadFailed {
-                url: url.into(),
-                detail: e.to_string(),
-            })?;
-
+    /// Download and decompress in one pass. Decompression and its file writes run on a blocking
+    /// task fed through a bounded channel, so neither stalls the runtime; the bound keeps a fast
+    /// network from queueing up more than a few chunks ahead of the decoder.
+    async fn download_zstd(
+        &self,
+        url: &str,
+        res: reqwest::Response,
+        destination: &Path,
+    ) -> Result<Sha256> {
         let total_size = res.content_length().unwrap_or(0);
 
-        let tmp_name = format!(
-            "{}.zst.tmp",
-            destination
-                .file_name()
-                .map(|s| s.to_string_lossy())
-                .unwrap_or_default()
-        );
-        let tmp_path = destination
-            .parent()
-            .map(|p| p.join(&tmp_name))
-            .unwrap_or_else(|| PathBuf::from(&tmp_name));
-
         info!(url = %url, dest = %destination.display(), size_bytes = total_size, "downloading image (zstd)");
 
-        // Stream to temp compressed file
-        {
-            let mut tmp_file = std::fs::File::create(&tmp_path)?;
+        let (tx, rx) = tokio::sync::mpsc::channel(DECODE_QUEUE_CHUNKS);
+        let dest = destination.to_path_buf();
+        let decoder = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            let mut out = std::io::BufWriter::new(std::fs::File::create(dest)?);
+            zstd::stream::copy_decode(ChunkReader::new(rx), &mut out)?;
+            std::io::Write::flush(&mut out)
+        });
+
+        // Hash the bytes as published, not the decompressed image
+        let mut hasher = Sha256::new();
+        let streamed = async {
             let mut downloaded: u64 = 0;
             let mut stream = res.bytes_stream();
             let mut last_logged_pct: u64 = 0;
-            while let Some(item) = stream.next().await {
-                let chunk = item.map_err(|e| VmError::ImageDownloadFailed {
-                    url: url.into(),
-                    detail: e.to_string(),
-                })?;
-                std::io::Write::write_all(&mut tmp_file, &chunk)?;
+            while let Some(chunk) = self.next_chunk(&mut stream, url).await? {
+                let len = chunk.len() as u64;
+                hasher.update(&chunk);
+                downloaded += len;
+                if tx.send(chunk).await.is_err() {
+                    // The decoder gave up; its error is reported below.
+                    break;
+                }
                 if total_size > 0 {
-                    downloaded = min(downloaded + (chunk.len() as u64), total_size);
+                    downloaded = min(downloaded, total_size);
                     let pct = downloaded.saturating_mul(100) / total_size.max(1);
                     if pct >= last_logged_pct + 5 || pct == 100 {
+                        self.progress(url, downloaded, total_size);
                         info!(
                             percent = pct,
                             downloaded_mb = (downloaded as f64) / 1_000_000.0,
@@ -174,58 +554,59 @@ impl ImageManager {
                         );
                         last_logged_pct = pct;
                     }
+                } else if downloaded / UNSIZED_PROGRESS_STEP
+                    != (downloaded - len) / UNSIZED_PROGRESS_STEP
+                {
+                    self.progress(url, downloaded, total_size);
                 }
             }
+            Ok::<_, VmError>(())
         }
+        .await;
 
-        info!(tmp = %tmp_path.display(), "download complete; decompressing zstd");
-
-        // Decompress
-        let infile = std::fs::File::open(&tmp_path)?;
-        let mut decoder =
-            zstd::stream::Decoder::new(infile).map_err(|e| VmError::ImageDownloadFailed {
-                url: url.into(),
-                detail: format!("zstd decoder init: {e}"),
-            })?;
-        let mut outfile = std::fs::File::create(destination)?;
-        std::io::copy(&mut decoder, &mut outfile)?;
-        let _ = decoder.finish();
-        let _ = std::fs::remove_file(&tmp_path);
-
-        info!(dest = %destination.display(), "decompression completed");
-        Ok(())
-    }
-
-    async fn download_raw(&self, url: &str, destination: &Path) -> Result<()> {
-        let res = self
-            .client
-            .get(url)
-            .send()
+        // Let the decoder see the end of input (or the abandoned stream) and finish writing
+        // before the caller removes a partial file.
+        drop(tx);
+        let decoded = decoder
             .await
+            .map_err(std::io::Error::other)
+            .and_then(|r| r)
             .map_err(|e| VmError::ImageDownloadFailed {
                 url: url.into(),
-                detail: e.to_string(),
-            })?;
+                detail: format!("zstd decompression: {e}"),
+            });
+        streamed?;
+        decoded?;
+
+        info!(dest = %destination.display(), "download and decompression completed");
+        Ok(hasher)
+    }
 
+    async fn download_raw(
+        &self,
+        url: &str,
+        res: reqwest::Response,
+        destination: &Path,
+    ) -> Result<Sha256> {
         let total_size = res.content_length().unwrap_or(0);
 
         info!(url = %url, dest = %destination.display(), size_bytes = total_size, "downloading image");
 
-        let mut file = std::fs::File::create(destination)?;
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(destination).await?);
+        let mut hasher = Sha256::new();
         let mut downloaded: u64 = 0;
         let mut stream = res.bytes_stream();
         let mut last_logged_pct: u64 = 0;
 
-        while let Some(item) = stream.next().await {
-            let chunk = item.map_err(|e| VmError::ImageDownloadFailed {
-                url: url.into(),
-                detail: e.to_string(),
-            })?;
-            std::io::Write::write_all(&mut file, &chunk)?;
+        while let Some(chunk) = self.next_chunk(&mut stream, url).await? {
+            file.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            downloaded += chunk.len() as u64;
             if total_size > 0 {
-                downloaded = min(downloaded + (chunk.len() as u64), total_size);
+                downloaded = min(downloaded, total_size);
                 let pct = downloaded.saturating_mul(100) / total_size.max(1);
                 if pct >= last_logged_pct + 5 || pct == 100 {
+                    self.progress(url, downloaded, total_size);
                     info!(
                         percent = pct,
                         downloaded_mb = (downloaded as f64) / 1_000_000.0,
@@ -233,20 +614,307 @@ impl ImageManager {
                     );
                     last_logged_pct = pct;
                 }
+            } else if downloaded / UNSIZED_PROGRESS_STEP
+                != (downloaded - chunk.len() as u64) / UNSIZED_PROGRESS_STEP
+            {
+                self.progress(url, downloaded, total_size);
             }
         }
+        file.flush().await?;
 
         info!(dest = %destination.display(), "download completed");
-        Ok(())
+        Ok(hasher)
     }
 }
 
+/// Credentials for an image upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageAuth {
+    /// HTTP basic authentication.
+    Basic { username: String, password: String },
+    /// An `Authorization: Bearer` token.
+    Bearer(String),
+}
+
+/// Bytes read from the image file per chunk of an upload.
+const UPLOAD_CHUNK_SIZE: usize = 256 << 10;
+
+/// The start of an error response body, short enough for an error message.
+fn truncate_body(body: &str) -> &str {
+    match body.char_indices().nth(200) {
+        Some((end, _)) => &body[..end],
+        None => body,
+    }
+}
+
+/// Whether a download that got `status` is worth repeating.
+fn is_transient(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503 | 504)
+}
+
+/// Freshness of a cached image, as reported by [`ImageManager::check_remote`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckResult {
+    NotCached,
+    CachedAndValid,
+    CachedButStale,
+}
+
+impl std::fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotCached => write!(f, "not cached"),
+            Self::CachedAndValid => write!(f, "cached and valid"),
+            Self::CachedButStale => write!(f, "cached but stale"),
+        }
+    }
+}
+
+/// Arguments for `packer build`: the `output` variable pointing at `dest`, then `vars`.
+fn packer_args(
+    template: &Path,
+    dest: &Path,
+    vars: &[String],
+) -> std::result::Result<Vec<String>, String> {
+    let mut args = vec![
+        "build".to_string(),
+        "-var".into(),
+        format!("output={}", dest.display()),
+    ];
+    for var in vars {
+        match var.split_once('=') {
+            Some(("output", _)) => {
+                return Err("the `output` variable is set by vmctl and can't be overridden".into());
+            }
+            Some((key, _)) if !key.is_empty() => {
+                args.push("-var".into());
+                args.push(var.clone());
+            }
+            _ => return Err(format!("variable '{var}' is not in key=value form")),
+        }
+    }
+    args.push(template.to_string_lossy().into_owned());
+    Ok(args)
+}
+
+/// The only regular file in `dir`.
+async fn single_file(dir: &Path) -> std::result::Result<PathBuf, String> {
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(|e| e.to_string())?;
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+        if entry.path().is_file() {
+            files.push(entry.path());
+        }
+    }
+    match files.len() {
+        1 => Ok(files.remove(0)),
+        0 => Err("no image file".into()),
+        n => Err(format!("{n} files, expected one")),
+    }
+}
+
+/// Remove a partially written file or directory, ignoring errors.
+async fn remove_path(path: &Path) {
+    if path.is_dir() {
+        let _ = tokio::fs::remove_dir_all(path).await;
+    } else {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+/// Blocking `Read` over chunks sent from async code; ends when the sender is dropped.
+struct ChunkReader<T> {
+    rx: tokio::sync::mpsc::Receiver<T>,
+    chunk: Option<T>,
+    offset: usize,
+}
+
+impl<T> ChunkReader<T> {
+    fn new(rx: tokio::sync::mpsc::Receiver<T>) -> Self {
+        Self {
+            rx,
+            chunk: None,
+            offset: 0,
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> std::io::Read for ChunkReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.chunk {
+                let rest = &chunk.as_ref()[self.offset..];
+                if !rest.is_empty() {
+                    let n = rest.len().min(buf.len());
+                    buf[..n].copy_from_slice(&rest[..n]);
+                    self.offset += n;
+                    return Ok(n);
+                }
+            }
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = Some(chunk);
+                    self.offset = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// `<image>.sha256`: the SHA-256 of the bytes downloaded for `image` and the URL they came from,
+/// in `sha256sum` format.
+fn sidecar_path(image: &Path) -> PathBuf {
+    let mut name = image.as_os_str().to_owned();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+async fn write_sidecar(image: &Path, url: &str, hasher: Sha256) -> Result<()> {
+    let line = format!("{:x}  {url}\n", hasher.finalize());
+    tokio::fs::write(sidecar_path(image), line).await?;
+    Ok(())
+}
+
+/// Read `(sha256, url)` from an image's sidecar.
+async fn read_sidecar(image: &Path) -> Option<(String, String)> {
+    let content = tokio::fs::read_to_string(sidecar_path(image)).await.ok()?;
+    let (hash, url) = content.trim().split_once(char::is_whitespace)?;
+    Some((hash.to_string(), url.trim().to_string()))
+}
+
+/// `<image>.meta.json`: the HTTP validators the server sent with `image`, so that a later pull
+/// can ask whether it changed.
+fn validators_path(image: &Path) -> PathBuf {
+    let mut name = image.as_os_str().to_owned();
+    name.push(".meta.json");
+    PathBuf::from(name)
+}
+
+/// `<image>.part`: where `image` is downloaded before it replaces the cached copy.
+fn partial_path(image: &Path) -> PathBuf {
+    let mut name = image.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Files kept in the cache directory next to the images.
+fn is_sidecar(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    [".sha256", ".meta.json", ".part"]
+        .iter()
+        .any(|suffix| name.ends_with(suffix))
+}
+
+/// `ETag` and `Last-Modified` of a downloaded image.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheValidators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        Self {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Record `validators` for `image`, or drop stale ones when the server sent none.
+async fn write_validators(image: &Path, validators: &CacheValidators) -> Result<()> {
+    let path = validators_path(image);
+    if validators.is_empty() {
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => return Ok(()),
+        }
+    }
+    let data = serde_json::to_vec_pretty(validators).map_err(std::io::Error::other)?;
+    tokio::fs::write(&path, data).await?;
+    Ok(())
+}
+
+/// The validators recorded for `image`, if the server sent any.
+async fn read_validators(image: &Path) -> Option<CacheValidators> {
+    let content = tokio::fs::read(validators_path(image)).await.ok()?;
+    serde_json::from_slice::<CacheValidators>(&content)
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+/// Find the SHA-256 for `file` in a checksum file. Accepts `sha256sum` output (`<hash>  <file>`,
+/// optionally `*<file>`), BSD style (`SHA256 (<file>) = <hash>`), or a lone hash.
+fn parse_checksum<'a>(sums: &'a str, file: &str) -> Option<&'a str> {
+    let is_sha256 = |h: &str| h.len() == 64 && h.chars().all(|c| c.is_ascii_hexdigit());
+    let mut lone = None;
+    for line in sums.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("SHA256 (") {
+            if let Some((name, hash)) = rest.split_once(") = ") {
+                if name == file && is_sha256(hash) {
+                    return Some(hash);
+                }
+            }
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(hash), Some(name)) if is_sha256(hash) => {
+                let name = name.trim_start_matches('*').trim_start_matches("./");
+                if name == file {
+                    return Some(hash);
+                }
+            }
+            (Some(hash), None) if is_sha256(hash) => lone = Some(hash),
+            _ => {}
+        }
+    }
+    lone
+}
+
 /// Information about a cached image.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CachedImage {
     pub name: String,
     pub path: PathBuf,
     pub size_bytes: u64,
+    /// SHA-256 of the bytes downloaded for the image, as recorded when it was pulled. `None` for
+    /// images that weren't downloaded, or were pulled by older versions.
+    pub sha256: Option<String>,
+}
+
+/// Why `qemu-img <subcommand>` could not be run, which is usually that it isn't installed.
+fn qemu_img_not_run(subcommand: &str, e: &std::io::Error) -> String {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        format!(
+            "cannot run qemu-img {subcommand}: qemu-img not found in PATH — install qemu-utils \
+             (Debian, Ubuntu) or qemu-img (Fedora)"
+        )
+    } else {
+        format!("cannot run qemu-img {subcommand}: {e}")
+    }
+}
+
+/// What a failed `qemu-img <subcommand>` wrote to stderr, or its exit status if it wrote nothing.
+fn qemu_img_failed(subcommand: &str, output: &std::process::Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.trim() {
+        "" => format!("qemu-img {subcommand} failed ({})", output.status),
+        stderr => stderr.to_string(),
+    }
 }
 
 /// Detect the format of a disk image using `qemu-img info`.
@@ -258,13 +926,13 @@ pub async fn detect_format(path: &Path) -> Result<String> {
         .await
         .map_err(|e| VmError::ImageFormatDetectionFailed {
             path: path.into(),
-            detail: format!("qemu-img not found: {e}"),
+            detail: qemu_img_not_run("info", &e),
         })?;
 
     if !output.status.success() {
         return Err(VmError::ImageFormatDetectionFailed {
             path: path.into(),
-            detail: String::from_utf8_lossy(&output.stderr).into_owned(),
+            detail: qemu_img_failed("info", &output),
         });
     }
 
@@ -283,36 +951,120 @@ pub async fn detect_format(path: &Path) -> Result<String> {
 }
 
 /// Convert an image from one format to another using `qemu-img convert`.
-pub async fn convert(src: &Path, dst: &Path, output_format: &str) -> Result<()> {
+///
+/// `options` apply when `output_format` is `qcow2`.
+pub async fn convert(
+    src: &Path,
+    dst: &Path,
+    output_format: &str,
+    options: &OverlayOptions,
+) -> Result<()> {
     let output = tokio::process::Command::new("qemu-img")
         .args(["convert", "-O", output_format])
+        .args(qcow2_create_args(output_format, options))
         .arg(src)
         .arg(dst)
         .output()
         .await
         .map_err(|e| VmError::ImageConversionFailed {
-            detail: format!("qemu-img convert failed to start: {e}"),
+            detail: qemu_img_not_run("convert", &e),
         })?;
 
     if !output.status.success() {
         return Err(VmError::ImageConversionFailed {
-            detail: String::from_utf8_lossy(&output.stderr).into_owned(),
+            detail: qemu_img_failed("convert", &output),
         });
     }
 
     Ok(())
 }
 
+/// `-o` option string for `qemu-img create`/`convert` producing a qcow2 image.
+///
+/// Unless `options` ask for preallocation the image stays sparse. `nocow` disables Btrfs
+/// copy-on-write, which fragments the file badly on top of QCOW2's own allocation.
+fn qcow2_options(options: &OverlayOptions, nocow: bool) -> String {
+    let preallocation = options
+        .preallocation
+        .map_or("off".to_string(), |p| p.to_string());
+    let mut opts = vec![format!("preallocation={preallocation}")];
+    if let Some(size) = options.cluster_size {
+        opts.push(format!("cluster_size={size}"));
+    }
+    if options.lazy_refcounts {
+        opts.push("lazy_refcounts=on".into());
+    }
+    if nocow {
+        opts.push("nocow=on".into());
+    }
+    opts.join(",")
+}
+
+/// `-o <options>` for writing `format`, or nothing when it isn't qcow2 or `options` are default.
+fn qcow2_create_args(format: &str, options: &OverlayOptions) -> Vec<String> {
+    if format != "qcow2" || *options == OverlayOptions::default() {
+        return Vec::new();
+    }
+    vec!["-o".into(), qcow2_options(options, false)]
+}
+
 /// Create a QCOW2 overlay backed by a base image.
 ///
 /// Automatically detects the base image format. If `size_gb` is provided, the overlay is resized.
-pub async fn create_overlay(base: &Path, overlay: &Path, size_gb: Option<u32>) -> Result<()> {
+/// `options` tune preallocation, cluster size and refcount updates. When `cancel` fires,
+/// `qemu-img` is killed and the partial overlay removed.
+pub async fn create_overlay(
+    base: &Path,
+    overlay: &Path,
+    size_gb: Option<u32>,
+    options: &OverlayOptions,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let args = overlay_args(base, overlay, size_gb, options).await?;
+    let create = tokio::process::Command::new("qemu-img")
+        .args(&args)
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::select! {
+        output = create => output.map_err(|e| VmError::OverlayCreationFailed {
+            base: base.into(),
+            detail: qemu_img_not_run("create", &e),
+        })?,
+        _ = cancel.cancelled() => {
+            let _ = tokio::fs::remove_file(overlay).await;
+            return Err(VmError::Cancelled {
+                operation: format!("creating overlay {}", overlay.display()),
+            });
+        }
+    };
+
+    if !output.status.success() {
+        return Err(VmError::OverlayCreationFailed {
+            base: base.into(),
+            detail: qemu_img_failed("create", &output),
+        });
+    }
+
+    Ok(())
+}
+
+/// The `qemu-img` arguments [`create_overlay`] runs. Detecting the base image's format fails
+/// like `create_overlay` would when the base is missing or unreadable.
+pub async fn overlay_args(
+    base: &Path,
+    overlay: &Path,
+    size_gb: Option<u32>,
+    options: &OverlayOptions,
+) -> Result<Vec<String>> {
     let base_fmt = detect_format(base).await?;
+    let options = qcow2_options(options, overlay.parent().is_some_and(is_btrfs));
 
     let mut args = vec![
         "create".to_string(),
         "-f".into(),
         "qcow2".into(),
+        "-o".into(),
+        options,
         "-F".into(),
         base_fmt,
         "-b".into(),
@@ -323,22 +1075,771 @@ pub async fn create_overlay(base: &Path, overlay: &Path, size_gb: Option<u32>) -
     if let Some(gb) = size_gb {
         args.push(format!("{gb}G"));
     }
+    Ok(args)
+}
 
+/// Parse a disk size such as `20G`, `512M` or `10737418240` into bytes.
+///
+/// Suffixes `K`, `M`, `G` and `T` (optionally followed by `iB` or `B`) are powers of 1024, like
+/// `qemu-img`'s. Returns `None` for anything else.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number: u64 = number.parse().ok()?;
+    let shift = match suffix.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return None,
+    };
+    number.checked_mul(1 << shift)
+}
+
+/// `qemu-img info` of an image as JSON. `-U` lets it read images a running VM has open.
+async fn image_info(path: &Path) -> Result<serde_json::Value> {
     let output = tokio::process::Command::new("qemu-img")
+        .args(["info", "-U", "--output=json"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageFormatDetectionFailed {
+            path: path.into(),
+            detail: qemu_img_not_run("info", &e),
+        })?;
+    if !output.status.success() {
+        return Err(VmError::ImageFormatDetectionFailed {
+            path: path.into(),
+            detail: qemu_img_failed("info", &output),
+        });
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| VmError::ImageFormatDetectionFailed {
+        path: path.into(),
+        detail: format!("failed to parse qemu-img JSON: {e}"),
+    })
+}
+
+/// Virtual (guest-visible) size of a disk image in bytes.
+pub async fn virtual_size(path: &Path) -> Result<u64> {
+    Ok(disk_usage(path).await?.virtual_bytes)
+}
+
+/// Virtual size of a disk image and the host disk space it takes, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskUsage {
+    pub virtual_bytes: u64,
+    /// `None` if `qemu-img` couldn't tell, e.g. on some network filesystems.
+    pub allocated_bytes: Option<u64>,
+}
+
+/// Sizes of a disk image from `qemu-img info`, which works on images a running VM has open.
+pub async fn disk_usage(path: &Path) -> Result<DiskUsage> {
+    let info = image_info(path).await?;
+    let virtual_bytes = info
+        .get("virtual-size")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| VmError::ImageFormatDetectionFailed {
+            path: path.into(),
+            detail: "qemu-img info reported no virtual-size".into(),
+        })?;
+    Ok(DiskUsage {
+        virtual_bytes,
+        allocated_bytes: info.get("actual-size").and_then(|v| v.as_u64()),
+    })
+}
+
+/// Grow a disk image to `new_size_bytes` with `qemu-img resize`.
+///
+/// The image must not be in use: for a running QEMU VM use `QmpClient::block_resize` instead,
+/// which resizes the file through QEMU. Shrinking is refused, since it would cut off guest data.
+pub async fn resize(path: &Path, new_size_bytes: u64) -> Result<()> {
+    let current = virtual_size(path).await?;
+    if new_size_bytes < current {
+        return Err(VmError::DiskResizeFailed {
+            path: path.into(),
+            detail: format!("shrinking from {current} to {new_size_bytes} bytes is not supported"),
+        });
+    }
+
+    let output = tokio::process::Command::new("qemu-img")
+        .arg("resize")
+        .arg(path)
+        .arg(new_size_bytes.to_string())
+        .output()
+        .await
+        .map_err(|e| VmError::DiskResizeFailed {
+            path: path.into(),
+            detail: qemu_img_not_run("resize", &e),
+        })?;
+    if !output.status.success() {
+        return Err(VmError::DiskResizeFailed {
+            path: path.into(),
+            detail: qemu_img_failed("resize", &output),
+        });
+    }
+
+    info!(path = %path.display(), new_size_bytes, "disk image resized");
+    Ok(())
+}
+
+/// Names of the internal snapshots stored in a QCOW2 image.
+pub async fn snapshots(path: &Path) -> Result<Vec<String>> {
+    let info = image_info(path).await?;
+    Ok(info
+        .get("snapshots")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|s| s.get("name")?.as_str().map(String::from))
+        .collect())
+}
+
+/// Fail with `SnapshotNotFound`, listing the snapshots there are, unless `overlay` has
+/// `snapshot`.
+async fn require_snapshot(overlay: &Path, snapshot: &str) -> Result<()> {
+    let available = snapshots(overlay).await?;
+    if available.iter().any(|s| s == snapshot) {
+        return Ok(());
+    }
+    Err(VmError::SnapshotNotFound {
+        snapshot: snapshot.into(),
+        path: overlay.into(),
+        available: if available.is_empty() {
+            "none".into()
+        } else {
+            available.join(", ")
+        },
+    })
+}
+
+/// Write internal snapshot `snapshot` of `overlay` to `output` as a standalone image.
+///
+/// The result has no backing file: the overlay's base image is merged in. `options` apply when
+/// `output_format` is `qcow2`. The VM must not be running.
+pub async fn export_snapshot(
+    overlay: &Path,
+    snapshot: &str,
+    output: &Path,
+    output_format: &str,
+    options: &OverlayOptions,
+) -> Result<()> {
+    require_snapshot(overlay, snapshot).await?;
+
+    let result = tokio::process::Command::new("qemu-img")
+        .args(["convert", "-l"])
+        .arg(format!("snapshot.name={snapshot}"))
+        .args(["-O", output_format])
+        .args(qcow2_create_args(output_format, options))
+        .arg(overlay)
+        .arg(output)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageConversionFailed {
+            detail: qemu_img_not_run("convert", &e),
+        })?;
+    if !result.status.success() {
+        let _ = tokio::fs::remove_file(output).await;
+        return Err(VmError::ImageConversionFailed {
+            detail: qemu_img_failed("convert", &result),
+        });
+    }
+
+    info!(snapshot, output = %output.display(), "snapshot exported");
+    Ok(())
+}
+
+/// Write internal snapshot `snapshot` of `overlay` to `output`, a QCOW2 image on the overlay's
+/// backing file. Unlike [`export_snapshot`] only what differs from the base image is copied,
+/// which makes it cheap for a look at the snapshot. Internal snapshots don't change, so the
+/// overlay is read with `-U` and the VM may keep running.
+pub async fn extract_snapshot(overlay: &Path, snapshot: &str, output: &Path) -> Result<()> {
+    require_snapshot(overlay, snapshot).await?;
+
+    let info = image_info(overlay).await?;
+    let mut args = vec!["convert".to_string(), "-U".into(), "-l".into()];
+    args.push(format!("snapshot.name={snapshot}"));
+    args.extend(["-O".into(), "qcow2".into()]);
+    let backing = info
+        .get("full-backing-filename")
+        .or_else(|| info.get("backing-filename"))
+        .and_then(|v| v.as_str());
+    if let Some(backing) = backing {
+        args.extend(["-B".into(), backing.to_string()]);
+        if let Some(fmt) = info.get("backing-filename-format").and_then(|v| v.as_str()) {
+            args.extend(["-F".into(), fmt.to_string()]);
+        }
+    }
+    args.push(overlay.to_string_lossy().into_owned());
+    args.push(output.to_string_lossy().into_owned());
+
+    let result = tokio::process::Command::new("qemu-img")
         .args(&args)
         .output()
         .await
-        .map_err(|e| VmError::OverlayCreationFailed {
-            base: base.into(),
-            detail: format!("qemu-img not found: {e}"),
+        .map_err(|e| VmError::ImageConversionFailed {
+            detail: qemu_img_not_run("convert", &e),
+        })?;
+    if !result.status.success() {
+        let _ = tokio::fs::remove_file(output).await;
+        return Err(VmError::ImageConversionFailed {
+            detail: qemu_img_failed("convert", &result),
+        });
+    }
+    Ok(())
+}
+
+/// Compare the guest-visible contents of two images with `qemu-img compare`. Returns `None`
+/// when they are identical, otherwise where they first differ as qemu-img reports it, e.g.
+/// `Content mismatch at offset 1048576!`.
+pub async fn compare(a: &Path, b: &Path) -> Result<Option<String>> {
+    let output = tokio::process::Command::new("qemu-img")
+        .arg("compare")
+        .arg(a)
+        .arg(b)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageConversionFailed {
+            detail: qemu_img_not_run("compare", &e),
         })?;
+    // 0: identical, 1: different, anything else: an error
+    match output.status.code() {
+        Some(0) => Ok(None),
+        Some(1) => Ok(Some(
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        )),
+        _ => Err(VmError::ImageConversionFailed {
+            detail: qemu_img_failed("compare", &output),
+        }),
+    }
+}
 
+/// Run `qemu-img snapshot <flag> <snapshot> <overlay>`.
+async fn qemu_img_snapshot(flag: &str, overlay: &Path, snapshot: &str) -> Result<()> {
+    let failed = |detail: String| VmError::SnapshotFailed {
+        snapshot: snapshot.into(),
+        path: overlay.into(),
+        detail,
+    };
+    let output = tokio::process::Command::new("qemu-img")
+        .args(["snapshot", flag, snapshot])
+        .arg(overlay)
+        .output()
+        .await
+        .map_err(|e| failed(qemu_img_not_run("snapshot", &e)))?;
     if !output.status.success() {
-        return Err(VmError::OverlayCreationFailed {
-            base: base.into(),
-            detail: String::from_utf8_lossy(&output.stderr).into_owned(),
+        return Err(failed(qemu_img_failed("snapshot", &output)));
+    }
+    Ok(())
+}
+
+/// Take internal snapshot `snapshot` of `overlay` with `qemu-img snapshot -c`. The VM must not
+/// be running.
+pub async fn create_snapshot(overlay: &Path, snapshot: &str) -> Result<()> {
+    qemu_img_snapshot("-c", overlay, snapshot).await?;
+    info!(snapshot, path = %overlay.display(), "snapshot created");
+    Ok(())
+}
+
+/// Delete internal snapshot `snapshot` of `overlay` with `qemu-img snapshot -d`. The VM must
+/// not be running.
+pub async fn delete_snapshot(overlay: &Path, snapshot: &str) -> Result<()> {
+    qemu_img_snapshot("-d", overlay, snapshot).await?;
+    info!(snapshot, path = %overlay.display(), "snapshot deleted");
+    Ok(())
+}
+
+/// Roll `overlay` back to its internal snapshot `snapshot` with `qemu-img snapshot -a`.
+///
+/// Everything written since the snapshot is lost. The VM must not be running.
+pub async fn revert_snapshot(overlay: &Path, snapshot: &str) -> Result<()> {
+    require_snapshot(overlay, snapshot).await?;
+
+    let output = tokio::process::Command::new("qemu-img")
+        .args(["snapshot", "-a", snapshot])
+        .arg(overlay)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageConversionFailed {
+            detail: qemu_img_not_run("snapshot", &e),
+        })?;
+    if !output.status.success() {
+        return Err(VmError::ImageConversionFailed {
+            detail: qemu_img_failed("snapshot", &output),
         });
     }
 
+    info!(snapshot, path = %overlay.display(), "snapshot restored");
     Ok(())
 }
+
+/// Compact a QCOW2 image in place by rewriting it compressed with `qemu-img convert -c`.
+///
+/// If the image has a backing file, the rewritten image keeps it, so only the overlay's own data
+/// is compacted. The VM must not be running.
+pub async fn compact(overlay: &Path) -> Result<()> {
+    let output = tokio::process::Command::new("qemu-img")
+        .args(["info", "--output=json"])
+        .arg(overlay)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageConversionFailed {
+            detail: qemu_img_not_run("info", &e),
+        })?;
+    if !output.status.success() {
+        return Err(VmError::ImageConversionFailed {
+            detail: qemu_img_failed("info", &output),
+        });
+    }
+    let info: serde_json::Value =
+        serde_json::from_slice(&output.stdout).map_err(|e| VmError::ImageConversionFailed {
+            detail: format!("failed to parse qemu-img JSON: {e}"),
+        })?;
+
+    let tmp = overlay.with_extension("qcow2.compact.tmp");
+    let mut args = vec![
+        "convert".to_string(),
+        "-c".into(),
+        "-f".into(),
+        "qcow2".into(),
+        "-O".into(),
+        "qcow2".into(),
+    ];
+    let backing = info
+        .get("full-backing-filename")
+        .or_else(|| info.get("backing-filename"))
+        .and_then(|v| v.as_str());
+    if let Some(backing) = backing {
+        args.extend(["-B".into(), backing.to_string()]);
+        if let Some(fmt) = info.get("backing-filename-format").and_then(|v| v.as_str()) {
+            args.extend(["-F".into(), fmt.to_string()]);
+        }
+    }
+    args.push(overlay.to_string_lossy().into_owned());
+    args.push(tmp.to_string_lossy().into_owned());
+
+    let output = tokio::process::Command::new("qemu-img")
+        .args(&args)
+        .output()
+        .await
+        .map_err(|e| VmError::ImageConversionFailed {
+            detail: qemu_img_not_run("convert", &e),
+        })?;
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(VmError::ImageConversionFailed {
+            detail: qemu_img_failed("convert", &output),
+        });
+    }
+
+    tokio::fs::rename(&tmp, overlay).await?;
+    info!(path = %overlay.display(), "image compacted");
+    Ok(())
+}
+
+/// Check whether `path` lives on a Btrfs filesystem.
+#[cfg(target_os = "linux")]
+fn is_btrfs(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    const BTRFS_SUPER_MAGIC: u32 = 0x9123_683e;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is a valid NUL-terminated string and `st` is a valid out-pointer.
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut st) } != 0 {
+        return false;
+    }
+    st.f_type as u32 == BTRFS_SUPER_MAGIC
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_btrfs(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_http::serve;
+
+    const HASH_A: &str = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+    const HASH_B: &str = "fcde2b2edba56bf408601fb721fe9b5c338d10ee429ea04fae5511b68fbf8fb9";
+
+    #[test]
+    fn qcow2_option_strings() {
+        use crate::types::Preallocation;
+
+        let default = OverlayOptions::default();
+        assert_eq!(qcow2_options(&default, false), "preallocation=off");
+        assert_eq!(qcow2_options(&default, true), "preallocation=off,nocow=on");
+        assert!(qcow2_create_args("qcow2", &default).is_empty());
+
+        let tuned = OverlayOptions {
+            preallocation: Some(Preallocation::Metadata),
+            cluster_size: Some(2 << 20),
+            lazy_refcounts: true,
+        };
+        assert_eq!(
+            qcow2_options(&tuned, false),
+            "preallocation=metadata,cluster_size=2097152,lazy_refcounts=on"
+        );
+        assert_eq!(
+            qcow2_create_args("qcow2", &tuned),
+            [
+                "-o",
+                "preallocation=metadata,cluster_size=2097152,lazy_refcounts=on"
+            ]
+        );
+        assert!(qcow2_create_args("raw", &tuned).is_empty());
+    }
+
+    #[test]
+    fn qemu_img_error_details() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let detail = qemu_img_not_run("info", &missing);
+        assert!(detail.starts_with("cannot run qemu-img info: qemu-img not found in PATH"));
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(qemu_img_not_run("resize", &denied).starts_with("cannot run qemu-img resize: "));
+
+        let mut output = std::process::Output {
+            status: std::process::ExitStatus::from_raw(1 << 8),
+            stdout: Vec::new(),
+            stderr: b"qemu-img: Could not open 'x.qcow2': No such file\n".to_vec(),
+        };
+        assert_eq!(
+            qemu_img_failed("info", &output),
+            "qemu-img: Could not open 'x.qcow2': No such file"
+        );
+        output.stderr.clear();
+        assert_eq!(
+            qemu_img_failed("convert", &output),
+            "qemu-img convert failed (exit status: 1)"
+        );
+    }
+
+    #[test]
+    fn packer_build_args() {
+        let args = packer_args(
+            Path::new("alpine.pkr.hcl"),
+            Path::new("/cache/alpine"),
+            &["version=3.20".into(), "disk=8G".into()],
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            [
+                "build",
+                "-var",
+                "output=/cache/alpine",
+                "-var",
+                "version=3.20",
+                "-var",
+                "disk=8G",
+                "alpine.pkr.hcl"
+            ]
+        );
+        assert!(packer_args(Path::new("t"), Path::new("o"), &["novalue".into()]).is_err());
+        assert!(packer_args(Path::new("t"), Path::new("o"), &["=x".into()]).is_err());
+        assert!(packer_args(Path::new("t"), Path::new("o"), &["output=/tmp/x".into()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn single_file_in_output_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(single_file(dir.path()).await.is_err());
+        std::fs::write(dir.path().join("disk.qcow2"), b"x").unwrap();
+        std::fs::create_dir(dir.path().join("logs")).unwrap();
+        assert_eq!(
+            single_file(dir.path()).await.unwrap(),
+            dir.path().join("disk.qcow2")
+        );
+        std::fs::write(dir.path().join("disk.qcow2.sha256"), b"x").unwrap();
+        assert!(single_file(dir.path()).await.is_err());
+    }
+
+    #[test]
+    fn cache_paths_of_urls_and_oci_references() {
+        let mgr = ImageManager::with_cache_dir("/cache".into());
+        let url = "https://example.com/noble.img.zst";
+        assert_eq!(mgr.cache_path(url, None), Path::new("/cache/noble.img"));
+        assert_eq!(mgr.cache_path(url, Some("web")), Path::new("/cache/web"));
+        let oci = "oci://ghcr.io/acme/noble:24.04";
+        assert_eq!(
+            mgr.cache_path(oci, None),
+            Path::new("/cache/ghcr.io_acme_noble_24.04.qcow2")
+        );
+        assert_eq!(
+            mgr.cache_path(oci, Some("web")),
+            Path::new("/cache/web.qcow2")
+        );
+    }
+
+    #[test]
+    fn parse_checksum_formats() {
+        let gnu = format!("{HASH_A}  noble.img\n{HASH_B} *noble.img.zst\n");
+        assert_eq!(parse_checksum(&gnu, "noble.img"), Some(HASH_A));
+        assert_eq!(parse_checksum(&gnu, "noble.img.zst"), Some(HASH_B));
+        assert_eq!(parse_checksum(&gnu, "other.img"), None);
+
+        let bsd = format!("SHA256 (noble.img) = {HASH_A}\n");
+        assert_eq!(parse_checksum(&bsd, "noble.img"), Some(HASH_A));
+
+        let lone = format!("{HASH_B}\n");
+        assert_eq!(parse_checksum(&lone, "anything.qcow2"), Some(HASH_B));
+
+        assert_eq!(
+            parse_checksum("not a checksum  noble.img", "noble.img"),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_disk_sizes() {
+        assert_eq!(parse_size("20G"), Some(20 << 30));
+        assert_eq!(parse_size("512MiB"), Some(512 << 20));
+        assert_eq!(parse_size("1t"), Some(1 << 40));
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("20X"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+
+    #[tokio::test]
+    async fn sidecar_records_hash_and_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().to_path_buf());
+        // Nothing cached: the checksum URL is never contacted.
+        let result = mgr
+            .check_remote(
+                "https://example.invalid/a.img",
+                "http://127.0.0.1:9/SUMS",
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, CheckResult::NotCached);
+
+        let image = dir.path().join("a.img");
+        std::fs::write(&image, b"foo").unwrap();
+        let mut hasher = Sha256::new();
+        hasher.update(b"foo");
+        write_sidecar(&image, "https://example.invalid/a.img", hasher)
+            .await
+            .unwrap();
+
+        let (hash, _) = read_sidecar(&image).await.unwrap();
+        assert_eq!(hash, HASH_A);
+        assert_eq!(
+            mgr.cached_source("a.img").await.as_deref(),
+            Some("https://example.invalid/a.img")
+        );
+        // The sidecar is not listed as an image, but its hash is.
+        let images = mgr.list().await.unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].sha256.as_deref(), Some(HASH_A));
+    }
+
+    #[tokio::test]
+    async fn push_http_streams_the_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().to_path_buf());
+        let image = vec![7u8; 600 << 10];
+        std::fs::write(dir.path().join("a.img"), &image).unwrap();
+
+        let (url, mut requests) =
+            serve(vec![("201 Created", vec![], vec![])], "upload/a.img").await;
+        mgr.push_http("a.img", &url, Some(ImageAuth::Bearer("secret".into())))
+            .await
+            .unwrap();
+        let request = requests.recv().await.unwrap();
+        let end = request.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+        assert!(head.starts_with("put /upload/a.img "), "{head}");
+        assert!(
+            head.contains("content-type: application/octet-stream"),
+            "{head}"
+        );
+        assert!(head.contains("transfer-encoding: chunked"), "{head}");
+        assert!(head.contains("authorization: bearer secret"), "{head}");
+        let body_bytes = request[end..].iter().filter(|&&b| b == 7).count();
+        assert_eq!(body_bytes, image.len());
+
+        let (url, _) = serve(vec![("403 Forbidden", vec![], vec![])], "upload/a.img").await;
+        let err = mgr.push_http("a.img", &url, None).await.unwrap_err();
+        assert!(matches!(err, VmError::ImageUploadFailed { .. }));
+        assert!(err.to_string().contains("403"), "{err}");
+
+        let err = mgr.push_http("missing.img", &url, None).await.unwrap_err();
+        assert!(
+            err.to_string().contains("no image named 'missing.img'"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn download_retries_transient_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().to_path_buf()).with_retries(2, 0);
+        let dest = dir.path().join("a.img");
+
+        let (url, _) = serve(
+            vec![
+                ("503 Service Unavailable", vec![], b"busy".to_vec()),
+                ("429 Too Many Requests", vec![], b"slow down".to_vec()),
+                ("200 OK", vec![], b"foo".to_vec()),
+            ],
+            "a.img",
+        )
+        .await;
+        mgr.download(&url, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"foo");
+
+        // Out of retries: the last error is reported and nothing is left behind.
+        let dest = dir.path().join("b.img");
+        let (url, _) = serve(vec![("502 Bad Gateway", vec![], vec![]); 3], "b.img").await;
+        let err = mgr.download(&url, &dest).await.unwrap_err();
+        assert!(err.to_string().contains("502"), "{err}");
+        assert!(!dest.exists() && !partial_path(&dest).exists());
+
+        // Not found is not retried: a second request would find no server.
+        let (url, _) = serve(vec![("404 Not Found", vec![], vec![])], "c.img").await;
+        let err = mgr
+            .download(&url, &dir.path().join("c.img"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("404"), "{err}");
+
+        // A refused connection is retried, then reported.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/d.img", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(matches!(
+            mgr.download(&url, &dir.path().join("d.img")).await,
+            Err(VmError::ImageDownloadFailed { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn pull_revalidates_with_etag() {
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().to_path_buf());
+        let dest = dir.path().join("a.img");
+
+        let (url, _) = serve(
+            vec![("200 OK", vec![("ETag", "\"v1\"")], b"foo".to_vec())],
+            "a.img",
+        )
+        .await;
+        mgr.download(&url, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"foo");
+        assert_eq!(
+            read_validators(&dest).await.unwrap().etag.as_deref(),
+            Some("\"v1\"")
+        );
+
+        // Unchanged: the server answers 304 and the image stays.
+        let (url, mut requests) = serve(vec![("304 Not Modified", vec![], vec![])], "a.img").await;
+        mgr.download(&url, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"foo");
+        let request = String::from_utf8(requests.recv().await.unwrap()).unwrap();
+        assert!(
+            request.to_lowercase().contains("if-none-match: \"v1\""),
+            "{request}"
+        );
+
+        // Changed: the image and both sidecars are replaced.
+        let (url, _) = serve(
+            vec![("200 OK", vec![("ETag", "\"v2\"")], b"bar".to_vec())],
+            "a.img",
+        )
+        .await;
+        mgr.download(&url, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"bar");
+        assert_eq!(
+            read_validators(&dest).await.unwrap().etag.as_deref(),
+            Some("\"v2\"")
+        );
+        assert_ne!(read_sidecar(&dest).await.unwrap().0, HASH_A);
+
+        let images = mgr.list().await.unwrap();
+        assert_eq!(images.len(), 1);
+        assert!(!partial_path(&dest).exists());
+    }
+
+    /// A download must leave the (single-threaded) runtime free to run other tasks: a task
+    /// running alongside it sees the image grow, rather than only once it is complete.
+    #[tokio::test]
+    async fn download_does_not_starve_runtime() {
+        const SIZE: u64 = 4 << 20;
+        // Noise does not compress, so both images arrive over many reads from the server,
+        // which runs on this same runtime.
+        let mut state = 1u64;
+        let image: Vec<u8> = (0..SIZE)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        let compressed = zstd::encode_all(&image[..], 3).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().to_path_buf());
+
+        for (path, body) in [("raw.img", image.clone()), ("packed.img.zst", compressed)] {
+            let (url, _) = serve(vec![("200 OK", vec![], body)], path).await;
+            let dest = dir.path().join(path);
+            let partial = partial_path(&dest);
+
+            let done = std::sync::atomic::AtomicBool::new(false);
+            let watcher = async {
+                let mut seen_partway = false;
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let len = std::fs::metadata(&partial).map_or(0, |m| m.len());
+                    seen_partway |= len > 0 && len < SIZE;
+                    tokio::task::yield_now().await;
+                }
+                seen_partway
+            };
+            let download = async {
+                let result = mgr.download(&url, &dest).await;
+                done.store(true, std::sync::atomic::Ordering::Relaxed);
+                result
+            };
+            let (result, seen_partway) = tokio::join!(download, watcher);
+            result.unwrap();
+
+            assert!(
+                std::fs::read(&dest).unwrap() == image,
+                "{path}: wrong content"
+            );
+            assert!(
+                seen_partway,
+                "{path}: the runtime only ran other tasks once the image was complete"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_zstd_download_leaves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mgr = ImageManager::with_cache_dir(dir.path().to_path_buf());
+        let (url, _) = serve(
+            vec![("200 OK", vec![], b"not zstd at all".repeat(1000))],
+            "bad.img.zst",
+        )
+        .await;
+        let dest = dir.path().join("bad.img");
+
+        let err = mgr.download(&url, &dest).await.unwrap_err();
+        assert!(err.to_string().contains("zstd decompression"), "{err}");
+        assert!(!dest.exists());
+    }
+}
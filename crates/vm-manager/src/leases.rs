@@ -0,0 +1,360 @@
+//! DHCP lease lookup for guest IP discovery.
+//!
+//! A [`LeaseSource`] is a lease file, or a directory of them, in one of the formats written by
+//! common DHCP servers. Sources are searched in order and a lease only matches when its MAC
+//! address equals the guest's (case-insensitively); the newest matching lease in a file wins.
+//!
+//! Sources are written as `<format>:<path>`, e.g. `libvirt:/var/lib/libvirt/dnsmasq`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, VmError};
+
+const SYNTAX_HINT: &str = "write lease sources as <format>:<path> with format dnsmasq, libvirt or networkd, e.g. dnsmasq:/var/lib/misc/dnsmasq.leases";
+
+/// Lease file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseFormat {
+    /// dnsmasq lease file: one `expiry mac ip hostname client-id` line per lease.
+    Dnsmasq,
+    /// libvirt network directory: `<net>.status` JSON arrays (and legacy dnsmasq-format
+    /// `<net>.leases` files).
+    Libvirt,
+    /// systemd-networkd DHCP server lease files: JSON with a `Leases` array.
+    Networkd,
+}
+
+impl LeaseFormat {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Dnsmasq => "dnsmasq",
+            Self::Libvirt => "libvirt",
+            Self::Networkd => "networkd",
+        }
+    }
+}
+
+/// A lease file or directory of lease files to search.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaseSource {
+    pub format: LeaseFormat,
+    pub path: PathBuf,
+}
+
+impl LeaseSource {
+    /// Parse `<format>:<path>`, where format is `dnsmasq`, `libvirt` or `networkd`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = |detail: String| VmError::ConfigInvalid {
+            origin: "lease-sources".into(),
+            detail,
+            hint: SYNTAX_HINT.into(),
+        };
+        let (format, path) = s
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("lease source {s} has no format")))?;
+        let format = match format {
+            "dnsmasq" => LeaseFormat::Dnsmasq,
+            "libvirt" => LeaseFormat::Libvirt,
+            "networkd" => LeaseFormat::Networkd,
+            other => return Err(invalid(format!("unknown lease format: {other}"))),
+        };
+        if path.is_empty() {
+            return Err(invalid(format!("lease source {s} has no path")));
+        }
+        Ok(Self {
+            format,
+            path: crate::vmfile::expand_tilde(path),
+        })
+    }
+
+    /// Where dnsmasq, libvirt and systemd-networkd keep leases by default, in search order.
+    pub fn defaults() -> Vec<Self> {
+        [
+            (LeaseFormat::Dnsmasq, "/var/lib/misc/dnsmasq.leases"),
+            (LeaseFormat::Dnsmasq, "/var/lib/dnsmasq/dnsmasq.leases"),
+            (LeaseFormat::Libvirt, "/var/lib/libvirt/dnsmasq"),
+            (
+                LeaseFormat::Networkd,
+                "/run/systemd/netif/dhcp-server-lease",
+            ),
+            (
+                LeaseFormat::Networkd,
+                "/var/lib/systemd/network/dhcp-server-lease",
+            ),
+        ]
+        .into_iter()
+        .map(|(format, path)| Self {
+            format,
+            path: path.into(),
+        })
+        .collect()
+    }
+
+    /// Whether the file or directory exists on this host.
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Find the IPv4 address leased to `mac`.
+    pub async fn find(&self, mac: &str) -> Option<String> {
+        let mut files = Vec::new();
+        match tokio::fs::read_dir(&self.path).await {
+            Ok(mut dir) => {
+                while let Ok(Some(entry)) = dir.next_entry().await {
+                    files.push(entry.path());
+                }
+                files.sort();
+            }
+            Err(_) => files.push(self.path.clone()),
+        }
+        for path in files {
+            let Ok(content) = tokio::fs::read_to_string(&path).await else {
+                continue;
+            };
+            if let Some(ip) = parse_leases(self.format, &path, &content, mac) {
+                return Some(ip);
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for LeaseSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.format.name(), self.path.display())
+    }
+}
+
+/// Search `sources` in order for the IPv4 address leased to `mac`.
+pub async fn find_ip(sources: &[LeaseSource], mac: &str) -> Option<String> {
+    for source in sources {
+        if let Some(ip) = source.find(mac).await {
+            return Some(ip);
+        }
+    }
+    None
+}
+
+/// Find the lease for `mac` in the contents of one lease file at `path`.
+pub fn parse_leases(format: LeaseFormat, path: &Path, content: &str, mac: &str) -> Option<String> {
+    match format {
+        LeaseFormat::Dnsmasq => dnsmasq_lease_ip(content, mac),
+        LeaseFormat::Libvirt => match path.extension().and_then(|e| e.to_str()) {
+            Some("status") => libvirt_status_ip(content, mac),
+            Some("leases") => dnsmasq_lease_ip(content, mac),
+            _ => None,
+        },
+        LeaseFormat::Networkd => networkd_lease_ip(content, mac),
+    }
+}
+
+/// Find the newest lease for `mac` in a dnsmasq lease file (`epoch mac ip hostname clientid`).
+fn dnsmasq_lease_ip(content: &str, mac: &str) -> Option<String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                [_, m, ip, ..] if m.eq_ignore_ascii_case(mac) => Some(ip.to_string()),
+                _ => None,
+            }
+        })
+        .next_back()
+}
+
+/// Find the lease for `mac` in a libvirt `<network>.status` file (a JSON array of leases).
+fn libvirt_status_ip(content: &str, mac: &str) -> Option<String> {
+    let leases: Vec<serde_json::Value> = serde_json::from_str(content).ok()?;
+    leases
+        .iter()
+        .filter(|l| {
+            l.get("mac-address")
+                .and_then(|m| m.as_str())
+                .is_some_and(|m| m.eq_ignore_ascii_case(mac))
+        })
+        .filter_map(|l| l.get("ip-address").and_then(|ip| ip.as_str()))
+        .next_back()
+        .map(String::from)
+}
+
+/// Find the lease for `mac` in a systemd-networkd DHCP server lease file.
+///
+/// Each entry in `Leases` carries the address and either `HardwareAddress` or a `ClientId` of
+/// type 1 (Ethernet) followed by the MAC. networkd writes addresses as byte arrays; strings are
+/// accepted too.
+fn networkd_lease_ip(content: &str, mac: &str) -> Option<String> {
+    let doc: serde_json::Value = serde_json::from_str(content).ok()?;
+    doc.get("Leases")?
+        .as_array()?
+        .iter()
+        .filter(|lease| {
+            let hw = lease
+                .get("HardwareAddress")
+                .and_then(mac_string)
+                .or_else(|| {
+                    let id = bytes(lease.get("ClientId")?)?;
+                    (id.len() == 7 && id[0] == 1).then(|| format_mac(&id[1..]))
+                });
+            hw.is_some_and(|hw| hw.eq_ignore_ascii_case(mac))
+        })
+        .filter_map(|lease| {
+            let address = lease.get("Address")?;
+            match address.as_str() {
+                Some(s) => Some(s.to_string()),
+                None => {
+                    let b = bytes(address)?;
+                    (b.len() == 4).then(|| format!("{}.{}.{}.{}", b[0], b[1], b[2], b[3]))
+                }
+            }
+        })
+        .next_back()
+}
+
+fn bytes(value: &serde_json::Value) -> Option<Vec<u8>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+        .collect()
+}
+
+fn mac_string(value: &serde_json::Value) -> Option<String> {
+    match value.as_str() {
+        Some(s) => Some(s.to_string()),
+        None => bytes(value)
+            .filter(|b| b.len() == 6)
+            .map(|b| format_mac(&b)),
+    }
+}
+
+fn format_mac(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: &str = "52:54:00:ab:cd:ef";
+
+    #[test]
+    fn dnsmasq_matches_mac_and_prefers_newest() {
+        let leases = "1700000000 52:54:00:ab:cd:ef 192.168.122.10 vm1 *\n\
+                      1700000100 52:54:00:11:22:33 192.168.122.11 vm2 *\n\
+                      1700000200 52:54:00:AB:CD:EF 192.168.122.12 vm1 *\n";
+        let path = Path::new("dnsmasq.leases");
+        assert_eq!(
+            parse_leases(LeaseFormat::Dnsmasq, path, leases, MAC).as_deref(),
+            Some("192.168.122.12")
+        );
+        assert_eq!(
+            parse_leases(LeaseFormat::Dnsmasq, path, leases, "52:54:00:00:00:99"),
+            None
+        );
+    }
+
+    #[test]
+    fn libvirt_reads_status_and_leases_files() {
+        let status = r#"[
+            {"ip-address": "192.168.122.11", "mac-address": "52:54:00:11:22:33"},
+            {"ip-address": "192.168.122.12", "mac-address": "52:54:00:ab:cd:ef"}
+        ]"#;
+        assert_eq!(
+            parse_leases(
+                LeaseFormat::Libvirt,
+                Path::new("default.status"),
+                status,
+                MAC
+            )
+            .as_deref(),
+            Some("192.168.122.12")
+        );
+        let leases = "1700000000 52:54:00:ab:cd:ef 192.168.122.10 vm1 *\n";
+        assert_eq!(
+            parse_leases(
+                LeaseFormat::Libvirt,
+                Path::new("default.leases"),
+                leases,
+                MAC
+            )
+            .as_deref(),
+            Some("192.168.122.10")
+        );
+        // Other files in the directory (e.g. virbr0.macs) are ignored.
+        assert_eq!(
+            parse_leases(LeaseFormat::Libvirt, Path::new("virbr0.macs"), status, MAC),
+            None
+        );
+    }
+
+    #[test]
+    fn networkd_matches_hardware_address_or_client_id() {
+        let leases = r#"{
+            "Leases": [
+                {"Address": [10, 0, 0, 5], "HardwareAddress": [82, 84, 0, 17, 34, 51]},
+                {"Address": [10, 0, 0, 6], "ClientId": [1, 82, 84, 0, 171, 205, 239]}
+            ]
+        }"#;
+        let path = Path::new("vm-lab");
+        assert_eq!(
+            parse_leases(LeaseFormat::Networkd, path, leases, MAC).as_deref(),
+            Some("10.0.0.6")
+        );
+        assert_eq!(
+            parse_leases(LeaseFormat::Networkd, path, leases, "52:54:00:11:22:33").as_deref(),
+            Some("10.0.0.5")
+        );
+        let strings =
+            r#"{"Leases": [{"Address": "10.0.0.7", "HardwareAddress": "52:54:00:AB:CD:EF"}]}"#;
+        assert_eq!(
+            parse_leases(LeaseFormat::Networkd, path, strings, MAC).as_deref(),
+            Some("10.0.0.7")
+        );
+    }
+
+    #[test]
+    fn parse_sources() {
+        let source = LeaseSource::parse("libvirt:/var/lib/libvirt/dnsmasq").unwrap();
+        assert_eq!(source.format, LeaseFormat::Libvirt);
+        assert_eq!(source.path, PathBuf::from("/var/lib/libvirt/dnsmasq"));
+        assert_eq!(source.to_string(), "libvirt:/var/lib/libvirt/dnsmasq");
+        assert!(LeaseSource::parse("/var/lib/misc/dnsmasq.leases").is_err());
+        assert!(LeaseSource::parse("isc:/var/lib/dhcp/dhcpd.leases").is_err());
+    }
+
+    #[tokio::test]
+    async fn find_searches_sources_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.leases");
+        let libvirt = dir.path().join("libvirt");
+        std::fs::create_dir(&libvirt).unwrap();
+        std::fs::write(&first, "1 52:54:00:11:22:33 10.0.0.2 other *\n").unwrap();
+        std::fs::write(
+            libvirt.join("default.status"),
+            r#"[{"ip-address": "10.0.0.3", "mac-address": "52:54:00:ab:cd:ef"}]"#,
+        )
+        .unwrap();
+
+        let sources = vec![
+            LeaseSource {
+                format: LeaseFormat::Dnsmasq,
+                path: dir.path().join("missing.leases"),
+            },
+            LeaseSource {
+                format: LeaseFormat::Dnsmasq,
+                path: first,
+            },
+            LeaseSource {
+                format: LeaseFormat::Libvirt,
+                path: libvirt,
+            },
+        ];
+        assert_eq!(find_ip(&sources, MAC).await.as_deref(), Some("10.0.0.3"));
+        assert_eq!(find_ip(&sources, "52:54:00:00:00:99").await, None);
+    }
+}
@@ -1,17 +1,38 @@
 pub mod backends;
+pub mod capabilities;
 pub mod cloudinit;
+pub mod config;
 pub mod console;
 pub mod error;
+pub mod events;
+pub mod hostnames;
 pub mod image;
+pub mod leases;
+pub mod manager;
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod network;
 pub mod oci;
+pub mod pinning;
 pub mod provision;
 pub mod ssh;
+pub mod ssh_import;
+pub mod store;
+pub mod template;
+#[cfg(test)]
+mod test_http;
 pub mod traits;
 pub mod types;
+pub mod usb;
 pub mod vmfile;
 
 // Re-export key types at crate root for convenience.
 pub use backends::RouterHypervisor;
 pub use error::{Result, VmError};
-pub use traits::{ConsoleEndpoint, Hypervisor};
+pub use events::{EventBus, VmEvent};
+pub use manager::{Vm, VmManager};
+pub use traits::{ConsoleEndpoint, DEFAULT_IP_TIMEOUT, Hypervisor};
 pub use types::*;
+
+/// Cooperative cancellation for long-running operations; see [`VmError::Cancelled`].
+pub use tokio_util::sync::CancellationToken;
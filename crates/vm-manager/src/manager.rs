@@ -0,0 +1,725 @@
+//! High-level VM lifecycle: a [`RouterHypervisor`] and a [`VmStore`] behind one API.
+//!
+//! Every operation records the VM's new handle in the store, and the hypervisor publishes the
+//! matching [`VmEvent`](crate::VmEvent) on its event bus. Booting an Ubuntu cloud image and
+//! running a command in it:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use vm_manager::config::Config;
+//! use vm_manager::{VmManager, VmSpec};
+//!
+//! # async fn demo() -> vm_manager::Result<()> {
+//! const UBUNTU: &str =
+//!     "https://cloud-images.ubuntu.com/noble/current/noble-server-cloudimg-amd64.img";
+//!
+//! let config = Config::load()?;
+//! let manager = VmManager::from_config(&config)?;
+//! let image = config.image_manager().pull(UBUNTU, None).await?;
+//!
+//! let spec = VmSpec::new("demo", image).with_generated_ssh("ubuntu")?;
+//! let mut vm = manager.create(spec).await?;
+//! vm.start().await?;
+//! vm.wait_ssh(Duration::from_secs(120)).await?;
+//!
+//! let (stdout, _stderr, _code) = vm.exec("uname -a").await?;
+//! println!("{} ({}): {stdout}", vm.name(), vm.ip().await?);
+//!
+//! vm.destroy().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::backends::RouterHypervisor;
+use crate::backends::remote::{self, RemoteHost, SshTunnel};
+use crate::capabilities::{self, HostResources};
+use crate::config::Config;
+use crate::error::{Result, VmError};
+use crate::events::EventBus;
+use crate::ssh::{self, Session};
+use crate::store::{self, VmStore};
+use crate::traits::{DEFAULT_IP_TIMEOUT, Hypervisor};
+use crate::types::{BackendTag, BootDevice, DryRun, SshConfig, VmHandle, VmSpec, VmState};
+
+/// Well-known filename for a generated SSH private key, stored in the VM's work directory.
+pub const GENERATED_KEY_FILE: &str = "id_ed25519_generated";
+
+/// Directory next to the VM work directories that kept disks go to by default.
+pub const PRESERVED_DIR: &str = "_preserved";
+
+/// The UEFI variable store of a VM booted with OVMF, in its work directory.
+const EFIVARS_FILE: &str = "efivars.fd";
+
+/// How long [`Vm::destroy_keeping_disks`] lets the guest shut down before it is killed.
+const KEEP_DISK_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Creates VMs and looks up existing ones.
+///
+/// Cheap to clone; clones share the hypervisor and store.
+#[derive(Clone)]
+pub struct VmManager {
+    hv: Arc<RouterHypervisor>,
+    store: VmStore,
+    cancel: CancellationToken,
+    timeout: Duration,
+    /// Directory VM disks are created in, when host resources are checked.
+    resource_checks: Option<PathBuf>,
+}
+
+impl VmManager {
+    pub fn new(hv: RouterHypervisor, store: VmStore) -> Self {
+        Self {
+            hv: Arc::new(hv),
+            store,
+            cancel: CancellationToken::new(),
+            timeout: DEFAULT_IP_TIMEOUT,
+            resource_checks: None,
+        }
+    }
+
+    /// The platform's hypervisor and the store of the configured namespace.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self::new(
+            RouterHypervisor::from_config(config)?,
+            VmStore::for_config(config),
+        ))
+    }
+
+    /// Give up waiting for SSH as soon as `cancel` fires. Pass the same token to the hypervisor
+    /// ([`RouterHypervisor::from_config_with_cancel`]) to interrupt backend operations too.
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// How long [`Vm::ip`] waits for a guest address, and [`Vm::exec`] and [`Vm::upload`] for
+    /// SSH when no connection is open yet. Default: [`DEFAULT_IP_TIMEOUT`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Check that the host has room for a VM before [`create`](Self::create) makes its disk and
+    /// [`Vm::start`] boots it, with disks created in `data_dir`; see
+    /// [`check_resources`](capabilities::check_resources). VMs on remote hosts or the noop
+    /// backend aren't checked.
+    pub fn with_resource_checks(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.resource_checks = Some(data_dir.into());
+        self
+    }
+
+    pub fn hypervisor(&self) -> &RouterHypervisor {
+        &self.hv
+    }
+
+    pub fn store(&self) -> &VmStore {
+        &self.store
+    }
+
+    pub fn events(&self) -> &EventBus {
+        self.hv.events()
+    }
+
+    /// Prepare a new VM from `spec` and record it in the store.
+    ///
+    /// A generated SSH key (`spec.ssh.private_key_pem`) is saved as [`GENERATED_KEY_FILE`] in the
+    /// VM's work directory. Fails with [`VmError::VmAlreadyExists`] if the store has a VM of
+    /// that name; its MAC addresses are never reused.
+    pub async fn create(&self, mut spec: VmSpec) -> Result<Vm> {
+        let existing = self.store.load().await?;
+        if existing.contains_key(&spec.name) {
+            return Err(VmError::VmAlreadyExists { name: spec.name });
+        }
+        spec.existing_macs.extend(store::known_macs(&existing));
+        if !self.hv.has_remote() && *self.hv.default_backend() != BackendTag::Noop {
+            let disk_gb = spec.disk_gb.map_or(0, u64::from);
+            self.check_resources(&spec.name, 0, disk_gb).await?;
+        }
+
+        let handle = self.hv.prepare(&spec).await?;
+        let saved = async {
+            save_generated_key(&spec, &handle).await?;
+            self.store.save_handle(handle.clone()).await
+        }
+        .await;
+        if let Err(e) = saved {
+            if let Err(destroy_err) = self.hv.destroy(handle).await {
+                warn!(vm = %spec.name, error = %destroy_err, "failed to destroy unrecorded VM");
+            }
+            return Err(e);
+        }
+        info!(name = %handle.name, id = %handle.id, "VM created");
+        Ok(self.attach(handle).with_ssh_config(spec.ssh))
+    }
+
+    /// Validate `spec` as [`create`](Self::create) would and describe the commands and files
+    /// creating and starting it would run and write, without creating anything or touching the
+    /// store.
+    pub async fn dry_run(&self, mut spec: VmSpec) -> Result<DryRun> {
+        let existing = self.store.load().await?;
+        if existing.contains_key(&spec.name) {
+            return Err(VmError::VmAlreadyExists { name: spec.name });
+        }
+        spec.existing_macs.extend(store::known_macs(&existing));
+        unsupported_if_none(self.hv.dry_run_create(&spec).await?)
+    }
+
+    /// With [`with_resource_checks`](Self::with_resource_checks), check that the host has
+    /// `memory_mb` and `disk_gb` to spare for VM `name`, besides the memory of the other local
+    /// VMs that are running.
+    async fn check_resources(&self, name: &str, memory_mb: u64, disk_gb: u64) -> Result<()> {
+        let Some(ref data_dir) = self.resource_checks else {
+            return Ok(());
+        };
+        let running_memory_mb = if memory_mb > 0 {
+            self.running_memory_mb(&[name]).await?
+        } else {
+            0
+        };
+        let host = HostResources::probe(data_dir, running_memory_mb);
+        capabilities::check_resources(name, memory_mb, disk_gb, &host)
+    }
+
+    /// Memory of the local VMs in the store that are running or suspended, in MB, leaving out
+    /// the VMs named in `except`.
+    pub async fn running_memory_mb(&self, except: &[&str]) -> Result<u64> {
+        let mut total = 0;
+        for handle in self.store.load().await?.values() {
+            if except.contains(&handle.name.as_str())
+                || handle.remote_host.is_some()
+                || handle.backend == BackendTag::Noop
+            {
+                continue;
+            }
+            let state = self.hv.state(handle).await;
+            if matches!(state, Ok(VmState::Running | VmState::Suspended)) {
+                total += handle.memory_mb;
+            }
+        }
+        Ok(total)
+    }
+
+    /// The VM called `name`. Call [`Vm::with_ssh`] before using SSH.
+    pub async fn get(&self, name: &str) -> Result<Vm> {
+        let handle = self
+            .store
+            .load()
+            .await?
+            .remove(name)
+            .ok_or_else(|| VmError::VmNotFound {
+                name: name.to_string(),
+            })?;
+        Ok(self.attach(handle))
+    }
+
+    /// Every VM in the store, sorted by name.
+    pub async fn list(&self) -> Result<Vec<Vm>> {
+        let mut handles: Vec<VmHandle> = self.store.load().await?.into_values().collect();
+        handles.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(handles.into_iter().map(|h| self.attach(h)).collect())
+    }
+
+    /// Manage the VM behind `handle`, without checking the store. Operations record the handle
+    /// under its name.
+    pub fn attach(&self, handle: VmHandle) -> Vm {
+        Vm {
+            manager: self.clone(),
+            handle,
+            ssh: None,
+            session: None,
+        }
+    }
+}
+
+/// The router reports backends without dry runs itself; this only guards against a
+/// hypervisor that returns `None` anyway.
+fn unsupported_if_none(dry_run: Option<DryRun>) -> Result<DryRun> {
+    dry_run.ok_or_else(|| VmError::DryRunUnsupported {
+        backend: "configured".into(),
+    })
+}
+
+/// Persist a generated SSH private key PEM to the VM's work directory (if present).
+///
+/// The file is written with 0600 permissions so that OpenSSH accepts it.
+async fn save_generated_key(spec: &VmSpec, handle: &VmHandle) -> Result<()> {
+    let Some(pem) = spec.ssh.as_ref().and_then(|s| s.private_key_pem.as_ref()) else {
+        return Ok(());
+    };
+    let key_path = handle.work_dir.join(GENERATED_KEY_FILE);
+    tokio::fs::write(&key_path, pem).await?;
+    tokio::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o600)).await?;
+    Ok(())
+}
+
+/// One managed VM.
+///
+/// The SSH connection opened by [`wait_ssh`](Self::wait_ssh), [`exec`](Self::exec) or
+/// [`upload`](Self::upload) is kept until the VM is stopped or dropped.
+pub struct Vm {
+    manager: VmManager,
+    handle: VmHandle,
+    ssh: Option<SshConfig>,
+    /// Open session, and the tunnel it runs through for VMs on a remote host.
+    session: Option<(Session, Option<SshTunnel>)>,
+}
+
+impl Vm {
+    pub fn name(&self) -> &str {
+        &self.handle.name
+    }
+
+    pub fn handle(&self) -> &VmHandle {
+        &self.handle
+    }
+
+    pub fn into_handle(self) -> VmHandle {
+        self.handle
+    }
+
+    /// Log in to the guest with `ssh`.
+    pub fn with_ssh(self, ssh: SshConfig) -> Self {
+        self.with_ssh_config(Some(ssh))
+    }
+
+    fn with_ssh_config(mut self, ssh: Option<SshConfig>) -> Self {
+        self.ssh = ssh;
+        self.session = None;
+        self
+    }
+
+    /// Boot from `device` on the next [`start`](Self::start) only, ahead of the VM's boot
+    /// order. The store never records it.
+    pub fn with_boot_once(mut self, device: BootDevice) -> Self {
+        self.handle.boot_once = Some(device);
+        self
+    }
+
+    /// Replace the VM's handle, e.g. after updating it outside the manager, and record it.
+    pub async fn record(&mut self, handle: VmHandle) -> Result<()> {
+        self.manager.store.save_handle(handle.clone()).await?;
+        self.handle = handle;
+        Ok(())
+    }
+
+    /// Boot the VM. When its SSH host port is forwarded for another VM in the store, the
+    /// [`VmError::HostPortInUse`] error names that VM.
+    pub async fn start(&mut self) -> Result<()> {
+        let local = self.handle.remote_host.is_none() && self.handle.backend != BackendTag::Noop;
+        // Huge pages come from their own pool, which the backend checks
+        if local && !self.handle.hugepages {
+            let state = self.state().await;
+            if !matches!(state, Ok(VmState::Running | VmState::Suspended)) {
+                let memory_mb = self.handle.memory_mb;
+                self.manager
+                    .check_resources(&self.handle.name, memory_mb, 0)
+                    .await?;
+            }
+        }
+        let updated = match self.manager.hv.start(&self.handle).await {
+            Err(VmError::HostPortInUse { vm, port, holder }) => {
+                let store = self.manager.store.load().await?;
+                let holder = match store::ssh_port_owner(&store, &vm, port) {
+                    Some(owner) => format!("VM '{owner}'"),
+                    None => holder,
+                };
+                return Err(VmError::HostPortInUse { vm, port, holder });
+            }
+            result => result?,
+        };
+        self.record(VmHandle {
+            boot_once: None,
+            ..updated
+        })
+        .await
+    }
+
+    /// Describe the commands [`start`](Self::start) would run, without running them.
+    pub async fn dry_run_start(&self) -> Result<DryRun> {
+        unsupported_if_none(self.manager.hv.dry_run_start(&self.handle).await?)
+    }
+
+    /// Shut the guest down, forcibly after `timeout`.
+    pub async fn stop(&mut self, timeout: Duration) -> Result<()> {
+        self.session = None;
+        let updated = self.manager.hv.stop(&self.handle, timeout).await?;
+        self.record(updated).await
+    }
+
+    pub async fn suspend(&mut self) -> Result<()> {
+        let updated = self.manager.hv.suspend(&self.handle).await?;
+        self.record(updated).await
+    }
+
+    pub async fn resume(&mut self) -> Result<()> {
+        let updated = self.manager.hv.resume(&self.handle).await?;
+        self.record(updated).await
+    }
+
+    /// Destroy the VM and its resources and remove it from the store.
+    pub async fn destroy(mut self) -> Result<()> {
+        self.session = None;
+        let name = self.handle.name.clone();
+        self.manager.hv.destroy(self.handle).await?;
+        self.manager.store.remove(&name).await?;
+        Ok(())
+    }
+
+    /// Destroy the VM like [`destroy`](Self::destroy), but first shut it down and move its disk
+    /// (the overlay, and the UEFI variables when it has them) into `dir`, which is created if
+    /// needed. Returns the moved files. Only local VMs whose disk is a file can keep it.
+    pub async fn destroy_keeping_disks(mut self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let not_kept = |detail: String| VmError::DiskNotKept {
+            vm: self.handle.name.clone(),
+            detail,
+        };
+        if let Some(ref host) = self.handle.remote_host {
+            return Err(not_kept(format!("its disk is on the remote host {host}")));
+        }
+        let Some(overlay) = self.handle.overlay_path.clone() else {
+            return Err(not_kept(format!(
+                "the {} backend keeps no disk file for it",
+                self.handle.backend
+            )));
+        };
+        let mut disks = vec![overlay];
+        let efivars = self.handle.work_dir.join(EFIVARS_FILE);
+        if efivars.exists() {
+            disks.push(efivars);
+        }
+        let mut moves = Vec::new();
+        for disk in disks {
+            let name = disk
+                .file_name()
+                .ok_or_else(|| not_kept(format!("{} is not a file", disk.display())))?;
+            let kept = dir.join(name);
+            if kept.exists() {
+                return Err(not_kept(format!("{} already exists", kept.display())));
+            }
+            moves.push((disk, kept));
+        }
+
+        self.session = None;
+        // A running guest may still be writing to the disk
+        self.manager
+            .hv
+            .stop(&self.handle, KEEP_DISK_STOP_TIMEOUT)
+            .await?;
+        tokio::fs::create_dir_all(dir).await?;
+        for (disk, kept) in &moves {
+            move_file(disk, kept).await?;
+            info!(name = %self.handle.name, disk = %kept.display(), "kept disk");
+        }
+        self.destroy().await?;
+        Ok(moves.into_iter().map(|(_, kept)| kept).collect())
+    }
+
+    pub async fn state(&self) -> Result<VmState> {
+        self.manager.hv.state(&self.handle).await
+    }
+
+    /// The guest's IP address, as seen from the hypervisor host.
+    pub async fn ip(&self) -> Result<String> {
+        self.manager
+            .hv
+            .guest_ip(&self.handle, self.manager.timeout)
+            .await
+    }
+
+    /// Wait up to `timeout` for the guest to accept SSH logins, and keep the connection for
+    /// later commands. VMs on a remote host are reached through an SSH tunnel.
+    pub async fn wait_ssh(&mut self, timeout: Duration) -> Result<()> {
+        self.connect(timeout).await.map(|_| ())
+    }
+
+    async fn connect(&mut self, timeout: Duration) -> Result<Session> {
+        if let Some((ref sess, _)) = self.session {
+            return Ok(sess.clone());
+        }
+        let config = self.ssh.as_ref().ok_or_else(|| VmError::SshFailed {
+            detail: format!("no SSH configuration for VM '{}'", self.handle.name),
+        })?;
+        let remote = self
+            .handle
+            .remote_host
+            .as_deref()
+            .map(RemoteHost::parse)
+            .transpose()?;
+        let ip = self
+            .manager
+            .hv
+            .guest_ip(&self.handle, self.manager.timeout)
+            .await?;
+        let (sess, tunnel) = remote::connect_guest(
+            remote.as_ref(),
+            &ip,
+            self.handle.ssh_port()?,
+            config,
+            timeout,
+            &self.manager.cancel,
+        )
+        .await?;
+        self.session = Some((sess.clone(), tunnel));
+        Ok(sess)
+    }
+
+    /// Run `cmd` in the guest. Returns `(stdout, stderr, exit_code)`.
+    pub async fn exec(&mut self, cmd: &str) -> Result<(String, String, i32)> {
+        let sess = self.connect(self.manager.timeout).await?;
+        let cmd = cmd.to_string();
+        blocking(move || ssh::exec(&sess, &cmd)).await
+    }
+
+    /// Copy the file or directory `local` to `remote` in the guest.
+    pub async fn upload(&mut self, local: &Path, remote: &Path) -> Result<()> {
+        let sess = self.connect(self.manager.timeout).await?;
+        let (local, remote) = (local.to_path_buf(), remote.to_path_buf());
+        blocking(move || {
+            if local.is_dir() {
+                ssh::upload_dir(&sess, &local, &remote)
+            } else {
+                ssh::upload(&sess, &local, &remote)
+            }
+        })
+        .await
+    }
+}
+
+/// Run a blocking SSH operation off the async runtime.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| VmError::SshFailed {
+            detail: format!("SSH task failed: {e}"),
+        })?
+}
+
+/// Where [`Vm::destroy_keeping_disks`] puts the disk of the VM `handle` destroyed at `at`, unless
+/// told otherwise: `_preserved/<name>-<UTC date and time>` next to its work directory.
+pub fn preserved_disks_dir(handle: &VmHandle, at: SystemTime) -> PathBuf {
+    let parent = handle.work_dir.parent().unwrap_or(&handle.work_dir);
+    parent
+        .join(PRESERVED_DIR)
+        .join(format!("{}-{}", handle.name, utc_timestamp(at)))
+}
+
+/// `at` in UTC as `YYYYMMDD-HHMMSS`.
+fn utc_timestamp(at: SystemTime) -> String {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Move `from` to `to`, copying when they are on different filesystems.
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            tokio::fs::copy(from, to).await?;
+            tokio::fs::remove_file(from).await?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(dir: &Path) -> VmManager {
+        VmManager::new(
+            RouterHypervisor::noop_only(),
+            VmStore::new(dir.join("vms.json")),
+        )
+    }
+
+    #[tokio::test]
+    async fn lifecycle_is_recorded_in_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let mut events = manager.events().subscribe();
+
+        let vm = manager
+            .create(VmSpec::new("web", "/images/base.qcow2"))
+            .await
+            .unwrap();
+        assert!(manager.store().load().await.unwrap().contains_key("web"));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            crate::VmEvent::VmPrepared { .. }
+        ));
+
+        let mut vm = vm.with_boot_once(BootDevice::Network);
+        vm.start().await.unwrap();
+        assert_eq!(vm.handle().boot_once, None);
+        assert_eq!(manager.get("web").await.unwrap().handle().boot_once, None);
+        vm.stop(Duration::from_secs(1)).await.unwrap();
+        let names: Vec<String> = manager
+            .list()
+            .await
+            .unwrap()
+            .iter()
+            .map(|vm| vm.name().to_string())
+            .collect();
+        assert_eq!(names, ["web"]);
+
+        let vm = manager.get("web").await.unwrap();
+        vm.destroy().await.unwrap();
+        assert!(manager.store().load().await.unwrap().is_empty());
+        assert!(matches!(
+            manager.get("web").await,
+            Err(VmError::VmNotFound { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn create_refuses_a_taken_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let spec = VmSpec::new("web", "/images/base.qcow2");
+        let vm = manager.create(spec.clone()).await.unwrap();
+        assert!(matches!(
+            manager.create(spec).await,
+            Err(VmError::VmAlreadyExists { .. })
+        ));
+        vm.destroy().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dry_run_leaves_the_store_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let spec = VmSpec::new("web", "/images/base.qcow2");
+        manager.dry_run(spec.clone()).await.unwrap();
+        assert!(!manager.store().path().exists());
+
+        let vm = manager.create(spec.clone()).await.unwrap();
+        assert!(matches!(
+            manager.dry_run(spec).await,
+            Err(VmError::VmAlreadyExists { .. })
+        ));
+        vm.dry_run_start().await.unwrap();
+        vm.destroy().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn noop_vms_skip_resource_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path()).with_resource_checks(dir.path());
+        let mut spec = VmSpec::new("big", "/images/base.qcow2");
+        spec.memory_mb = 1 << 30;
+        spec.disk_gb = Some(u32::MAX);
+        let mut vm = manager.create(spec).await.unwrap();
+        vm.start().await.unwrap();
+        assert_eq!(manager.running_memory_mb(&[]).await.unwrap(), 0);
+        vm.destroy().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn create_saves_a_generated_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let spec = VmSpec::new("web", "/images/base.qcow2")
+            .with_generated_ssh("ubuntu")
+            .unwrap();
+        let vm = manager.create(spec).await.unwrap();
+        let key = vm.handle().work_dir.join(GENERATED_KEY_FILE);
+        let mode = std::fs::metadata(&key).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        vm.destroy().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exec_without_ssh_config_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let mut vm = manager
+            .create(VmSpec::new("web", "/images/base.qcow2"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            vm.exec("true").await,
+            Err(VmError::SshFailed { .. })
+        ));
+        vm.destroy().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn destroy_keeping_disks_moves_the_overlay_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let mut vm = manager
+            .create(VmSpec::new("web", "/images/base.qcow2"))
+            .await
+            .unwrap();
+        let overlay = vm.handle().work_dir.join("overlay.qcow2");
+        std::fs::write(&overlay, b"disk").unwrap();
+        let handle = VmHandle {
+            overlay_path: Some(overlay.clone()),
+            ..vm.handle().clone()
+        };
+        vm.record(handle).await.unwrap();
+
+        let kept_dir = dir.path().join("kept");
+        let kept = vm.destroy_keeping_disks(&kept_dir).await.unwrap();
+        assert_eq!(kept, [kept_dir.join("overlay.qcow2")]);
+        assert_eq!(std::fs::read(&kept[0]).unwrap(), b"disk");
+        assert!(!overlay.exists());
+        assert!(manager.store().load().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn destroy_keeping_disks_needs_a_disk_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        let vm = manager
+            .create(VmSpec::new("web", "/images/base.qcow2"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            vm.destroy_keeping_disks(&dir.path().join("kept")).await,
+            Err(VmError::DiskNotKept { .. })
+        ));
+        assert!(manager.store().load().await.unwrap().contains_key("web"));
+        manager.get("web").await.unwrap().destroy().await.unwrap();
+    }
+
+    #[test]
+    fn preserved_disks_dir_is_named_after_the_vm_and_time() {
+        let handle: VmHandle = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "name": "web",
+            "backend": "qemu",
+            "work_dir": "/data/vms/web",
+        }))
+        .unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(
+            preserved_disks_dir(&handle, at),
+            Path::new("/data/vms/_preserved/web-20240229-123456")
+        );
+    }
+}
@@ -0,0 +1,808 @@
+//! Prometheus metrics for managed VMs.
+//!
+//! The names and labels below are stable: dashboards and alerts are built on them. Add new
+//! metrics rather than renaming these.
+//!
+//! Gauges, sampled by a [`Collector`] on every scrape. Labels: `vm`, `backend`, and `project`
+//! (the VM's `project` label, set with `vmctl label`; empty if unset).
+//!
+//! | Metric | Meaning |
+//! |---|---|
+//! | `vmctl_vm_state` | 1 for the VM's current state and 0 for the others; extra label `state`, one of [`STATES`] |
+//! | `vmctl_vm_cpu_percent` | Host CPU used by the VM process since the previous scrape, in percent of one core |
+//! | `vmctl_vm_memory_rss_bytes` | Resident memory of the VM process |
+//! | `vmctl_vm_memory_configured_bytes` | Memory the VM is configured with |
+//! | `vmctl_vm_memory_guest_bytes` | Memory QEMU reports the guest has, base plus hotplugged |
+//! | `vmctl_vm_disk_allocated_bytes` | Host disk space allocated to the VM's disk image |
+//! | `vmctl_vm_uptime_seconds` | Time since the VM process started |
+//!
+//! Counters with the same labels, read from QEMU on every scrape and reset when the VM restarts:
+//!
+//! | Metric | Meaning |
+//! |---|---|
+//! | `vmctl_vm_disk_read_bytes_total` | Bytes the guest read from its root disk |
+//! | `vmctl_vm_disk_written_bytes_total` | Bytes the guest wrote to its root disk |
+//!
+//! Counters recorded from [`VmEvent`]s by a [`CounterRecorder`] and kept in [`Counters`]:
+//!
+//! | Metric | Labels | Meaning |
+//! |---|---|---|
+//! | `vmctl_lifecycle_operations_total` | `vm`, `operation` (`prepare`, `start`, `stop`, `destroy`) | Successful lifecycle operations |
+//! | `vmctl_provision_failures_total` | `vm` | Provision runs that failed |
+//! | `vmctl_image_download_bytes_total` | none | Bytes downloaded into the image cache |
+//!
+//! Process, memory and disk metrics other than the configured memory are only reported for
+//! local VMs; a value that can't be read
+//! is left out rather than reported as 0.
+//!
+//! [`resource_usage`] takes the same kind of measurements of a single VM on demand, for
+//! `vmctl status`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::error::Result;
+use crate::events::VmEvent;
+use crate::traits::Hypervisor;
+use crate::types::{BackendTag, VmHandle, VmState};
+
+/// Values of the `state` label of `vmctl_vm_state`. `unknown` means the backend couldn't be
+/// asked.
+pub const STATES: [&str; 8] = [
+    "preparing",
+    "prepared",
+    "running",
+    "suspended",
+    "stopped",
+    "failed",
+    "destroyed",
+    "unknown",
+];
+
+/// How long a single VM may take to sample before it is reported as `unknown`.
+pub const SAMPLE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Everything measured about one VM in a scrape.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmSample {
+    pub vm: String,
+    pub backend: String,
+    pub project: String,
+    /// `None` if the backend couldn't be reached.
+    pub state: Option<VmState>,
+    pub cpu_percent: Option<f64>,
+    pub rss_bytes: Option<u64>,
+    pub memory_configured_bytes: Option<u64>,
+    pub guest_memory_bytes: Option<u64>,
+    pub disk_allocated_bytes: Option<u64>,
+    pub uptime_seconds: Option<f64>,
+    pub disk_read_bytes: Option<u64>,
+    pub disk_written_bytes: Option<u64>,
+}
+
+/// Samples VMs for the gauges. Keep one across scrapes: CPU usage is measured between them.
+#[derive(Debug, Default)]
+pub struct Collector {
+    /// Per VM: process id, CPU seconds used and when that was read.
+    cpu: HashMap<String, (u32, f64, Instant)>,
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sample every VM. A VM that can't be reached, or doesn't answer within
+    /// [`SAMPLE_TIMEOUT`], is reported with an unknown state instead of failing the scrape.
+    pub async fn collect(&mut self, hv: &dyn Hypervisor, vms: &[VmHandle]) -> Vec<VmSample> {
+        let mut samples = Vec::with_capacity(vms.len());
+        for vm in vms {
+            let mut sample = VmSample {
+                vm: vm.name.clone(),
+                backend: vm.backend.to_string(),
+                project: vm.labels.get("project").cloned().unwrap_or_default(),
+                memory_configured_bytes: Some(vm.memory_mb * 1024 * 1024),
+                ..VmSample::default()
+            };
+            match tokio::time::timeout(SAMPLE_TIMEOUT, hv.state(vm)).await {
+                Ok(Ok(state)) => sample.state = Some(state),
+                Ok(Err(e)) => warn!(vm = %vm.name, error = %e, "cannot read VM state for metrics"),
+                Err(_) => warn!(vm = %vm.name, "timed out reading VM state for metrics"),
+            }
+            if vm.remote_host.is_none() {
+                self.sample_local(vm, &mut sample).await;
+            }
+            samples.push(sample);
+        }
+        let names: HashSet<&str> = vms.iter().map(|vm| vm.name.as_str()).collect();
+        self.cpu.retain(|name, _| names.contains(name.as_str()));
+        samples
+    }
+
+    async fn sample_local(&mut self, vm: &VmHandle, sample: &mut VmSample) {
+        if let Some(ref overlay) = vm.overlay_path {
+            sample.disk_allocated_bytes = allocated_bytes(overlay);
+        }
+        if !matches!(sample.state, Some(VmState::Running | VmState::Suspended)) {
+            return;
+        }
+
+        if let Some(stats) = vm.pid.and_then(process_stats) {
+            let pid = vm.pid.unwrap_or_default();
+            let now = Instant::now();
+            // Without an earlier reading of the same process, average over its whole life.
+            sample.cpu_percent = Some(match self.cpu.get(&vm.name) {
+                Some(&(prev_pid, prev_cpu, at)) if prev_pid == pid => {
+                    let wall = now.duration_since(at).as_secs_f64();
+                    if wall > 0.0 {
+                        (stats.cpu_seconds - prev_cpu).max(0.0) / wall * 100.0
+                    } else {
+                        0.0
+                    }
+                }
+                _ if stats.uptime_seconds > 0.0 => stats.cpu_seconds / stats.uptime_seconds * 100.0,
+                _ => 0.0,
+            });
+            self.cpu
+                .insert(vm.name.clone(), (pid, stats.cpu_seconds, now));
+            sample.rss_bytes = Some(stats.rss_bytes);
+            sample.uptime_seconds = Some(stats.uptime_seconds);
+        }
+
+        if vm.backend == BackendTag::Qemu {
+            if let Some((read, written)) = root_disk_io(vm).await {
+                sample.disk_read_bytes = Some(read);
+                sample.disk_written_bytes = Some(written);
+            }
+            sample.guest_memory_bytes = guest_memory_bytes(vm).await;
+        }
+    }
+}
+
+/// Bytes the filesystem has allocated for `path`, which for a sparse image is less than its
+/// size.
+fn allocated_bytes(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| m.blocks() * 512)
+}
+
+#[cfg(target_os = "linux")]
+async fn root_disk_io(vm: &VmHandle) -> Option<(u64, u64)> {
+    use crate::backends::qemu::ROOT_DRIVE;
+    use crate::backends::qmp::QmpPool;
+
+    let socket = vm.qmp_socket.as_deref()?;
+    // Shares the session the state check just used.
+    let query = QmpPool::shared().run(socket, Duration::from_secs(1), async |qmp| {
+        qmp.query_blockstats().await
+    });
+    match tokio::time::timeout(SAMPLE_TIMEOUT, query).await {
+        Ok(Ok(stats)) => stats
+            .into_iter()
+            .find(|s| s.device == ROOT_DRIVE)
+            .map(|s| (s.rd_bytes, s.wr_bytes)),
+        Ok(Err(e)) => {
+            warn!(vm = %vm.name, error = %e, "cannot read block stats for metrics");
+            None
+        }
+        Err(_) => {
+            warn!(vm = %vm.name, "timed out reading block stats for metrics");
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn root_disk_io(_vm: &VmHandle) -> Option<(u64, u64)> {
+    None
+}
+
+/// How long [`resource_usage`] watches the VM process to measure its current CPU usage.
+pub const CPU_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What a VM costs its host right now. Fields that don't apply or can't be read are `None`:
+/// the process fields while the VM isn't running, all of them for remote VMs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// CPU used by the VM process over [`CPU_SAMPLE_INTERVAL`], in percent of one core.
+    pub cpu_percent: Option<f64>,
+    pub rss_bytes: Option<u64>,
+    /// Time since the VM process started.
+    pub uptime_seconds: Option<f64>,
+    /// Host threads running the guest's vCPUs (QEMU).
+    pub vcpu_threads: Option<usize>,
+    /// RAM the guest has, base plus hotplugged, which differs from the configured memory once
+    /// memory is hotplugged (QEMU).
+    pub guest_memory_bytes: Option<u64>,
+    /// Host disk space allocated to the overlay.
+    pub overlay_allocated_bytes: Option<u64>,
+    /// Size of the overlay as the guest sees it.
+    pub overlay_virtual_bytes: Option<u64>,
+}
+
+/// Measure what `vm`, in `state`, costs its host. Takes [`CPU_SAMPLE_INTERVAL`] while the VM
+/// runs.
+pub async fn resource_usage(vm: &VmHandle, state: VmState) -> ResourceUsage {
+    let mut usage = ResourceUsage::default();
+    if vm.remote_host.is_some() {
+        return usage;
+    }
+    if let Some(ref overlay) = vm.overlay_path {
+        match crate::image::disk_usage(overlay).await {
+            Ok(disk) => {
+                usage.overlay_virtual_bytes = Some(disk.virtual_bytes);
+                usage.overlay_allocated_bytes =
+                    disk.allocated_bytes.or_else(|| allocated_bytes(overlay));
+            }
+            Err(e) => {
+                warn!(vm = %vm.name, error = %e, "cannot read overlay size");
+                usage.overlay_allocated_bytes = allocated_bytes(overlay);
+            }
+        }
+    }
+    if !matches!(state, VmState::Running | VmState::Suspended) {
+        return usage;
+    }
+    let Some(pid) = vm.pid else {
+        return usage;
+    };
+
+    if let Some(before) = process_stats(pid) {
+        tokio::time::sleep(CPU_SAMPLE_INTERVAL).await;
+        if let Some(after) = process_stats(pid) {
+            let used = (after.cpu_seconds - before.cpu_seconds).max(0.0);
+            usage.cpu_percent = Some(used / CPU_SAMPLE_INTERVAL.as_secs_f64() * 100.0);
+            usage.rss_bytes = Some(after.rss_bytes);
+            usage.uptime_seconds = Some(after.uptime_seconds);
+        }
+    }
+    if vm.backend == BackendTag::Qemu {
+        usage.vcpu_threads = vcpu_threads(vm).await;
+        usage.guest_memory_bytes = guest_memory_bytes(vm).await;
+    }
+    usage
+}
+
+#[cfg(target_os = "linux")]
+async fn vcpu_threads(vm: &VmHandle) -> Option<usize> {
+    use crate::backends::qmp::QmpPool;
+
+    let socket = vm.qmp_socket.as_deref()?;
+    let query = QmpPool::shared().run(socket, Duration::from_secs(1), async |qmp| {
+        qmp.query_vcpu_threads().await
+    });
+    match tokio::time::timeout(SAMPLE_TIMEOUT, query).await {
+        Ok(Ok(threads)) => Some(threads.len()),
+        Ok(Err(e)) => {
+            warn!(vm = %vm.name, error = %e, "cannot read vCPU threads");
+            None
+        }
+        Err(_) => {
+            warn!(vm = %vm.name, "timed out reading vCPU threads");
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn vcpu_threads(_vm: &VmHandle) -> Option<usize> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+async fn guest_memory_bytes(vm: &VmHandle) -> Option<u64> {
+    use crate::backends::qmp::QmpPool;
+
+    let socket = vm.qmp_socket.as_deref()?;
+    let query = QmpPool::shared().run(socket, Duration::from_secs(1), async |qmp| {
+        qmp.query_memory_size_summary().await
+    });
+    match tokio::time::timeout(SAMPLE_TIMEOUT, query).await {
+        Ok(Ok(summary)) => Some(summary.total()),
+        Ok(Err(e)) => {
+            warn!(vm = %vm.name, error = %e, "cannot read guest memory size");
+            None
+        }
+        Err(_) => {
+            warn!(vm = %vm.name, "timed out reading guest memory size");
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn guest_memory_bytes(_vm: &VmHandle) -> Option<u64> {
+    None
+}
+
+/// CPU time, memory and age of a process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProcessStats {
+    /// User plus system CPU time.
+    pub cpu_seconds: f64,
+    pub rss_bytes: u64,
+    pub uptime_seconds: f64,
+}
+
+/// Read a process's statistics from `/proc`. `None` if it doesn't exist (anymore).
+#[cfg(target_os = "linux")]
+pub fn process_stats(pid: u32) -> Option<ProcessStats> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let (cpu_ticks, start_ticks, rss_pages) = parse_proc_stat(&stat)?;
+    let boot_uptime: f64 = std::fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+
+    // SAFETY: sysconf has no preconditions.
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    Some(ProcessStats {
+        cpu_seconds: cpu_ticks as f64 / ticks,
+        rss_bytes: rss_pages * page_size,
+        uptime_seconds: (boot_uptime - start_ticks as f64 / ticks).max(0.0),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_stats(_pid: u32) -> Option<ProcessStats> {
+    None
+}
+
+/// CPU ticks (user + system), start time in ticks after boot, and resident pages from the
+/// contents of `/proc/<pid>/stat`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_stat(stat: &str) -> Option<(u64, u64, u64)> {
+    // The command name (field 2) is in parentheses and may contain spaces, so count fields
+    // from the last `)`: field 3 (state) is the first after it.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+    Some((field(14)? + field(15)?, field(22)?, field(24)?))
+}
+
+/// Counters recorded from lifecycle events, summed across vmctl runs in a counters file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counters {
+    /// VM name -> operation -> count.
+    #[serde(default)]
+    pub lifecycle_operations: BTreeMap<String, BTreeMap<String, u64>>,
+    /// VM name -> failed provision runs.
+    #[serde(default)]
+    pub provision_failures: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub image_download_bytes: u64,
+}
+
+impl Counters {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn add(&mut self, other: &Counters) {
+        for (vm, ops) in &other.lifecycle_operations {
+            let mine = self.lifecycle_operations.entry(vm.clone()).or_default();
+            for (op, n) in ops {
+                *mine.entry(op.clone()).or_default() += n;
+            }
+        }
+        for (vm, n) in &other.provision_failures {
+            *self.provision_failures.entry(vm.clone()).or_default() += n;
+        }
+        self.image_download_bytes += other.image_download_bytes;
+    }
+
+    /// Read a counters file; a missing file reads as all zeros.
+    pub async fn load(path: &Path) -> Result<Self> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(data) => Ok(serde_json::from_str(&data).map_err(std::io::Error::other)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Add these counts to the counters file at `path`, creating it if needed.
+    ///
+    /// The file is rewritten atomically, but two processes adding at the same moment can lose
+    /// one of the updates.
+    pub async fn add_to_file(&self, path: &Path) -> Result<()> {
+        let mut total = Self::load(path).await?;
+        total.add(self);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let data = serde_json::to_string_pretty(&total).map_err(std::io::Error::other)?;
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+/// Turns a stream of [`VmEvent`]s into [`Counters`].
+#[derive(Debug, Default)]
+pub struct CounterRecorder {
+    counters: Counters,
+    /// VMs with a provision step started but not finished.
+    provisioning: HashSet<String>,
+    /// Bytes already counted per download URL.
+    downloaded: HashMap<String, u64>,
+}
+
+impl CounterRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, event: &VmEvent) {
+        let operation = match event {
+            VmEvent::VmPrepared { vm } => Some((vm, "prepare")),
+            VmEvent::VmStarted { vm, .. } => Some((vm, "start")),
+            VmEvent::VmStopped { vm } => Some((vm, "stop")),
+            VmEvent::VmDestroyed { vm } => Some((vm, "destroy")),
+            _ => None,
+        };
+        if let Some((vm, op)) = operation {
+            *self
+                .counters
+                .lifecycle_operations
+                .entry(vm.clone())
+                .or_default()
+                .entry(op.into())
+                .or_default() += 1;
+            return;
+        }
+
+        match event {
+            VmEvent::ProvisionStepStarted { vm, .. } => {
+                self.provisioning.insert(vm.clone());
+            }
+            VmEvent::ProvisionStepFinished { vm, .. } => {
+                self.provisioning.remove(vm);
+            }
+            VmEvent::Error { vm: Some(vm), .. } if self.provisioning.remove(vm) => {
+                *self
+                    .counters
+                    .provision_failures
+                    .entry(vm.clone())
+                    .or_default() += 1;
+            }
+            VmEvent::DownloadProgress {
+                url, downloaded, ..
+            } => {
+                let counted = self.downloaded.entry(url.clone()).or_default();
+                // A smaller figure means the URL is being downloaded again from the start.
+                self.counters.image_download_bytes += if *downloaded >= *counted {
+                    *downloaded - *counted
+                } else {
+                    *downloaded
+                };
+                *counted = *downloaded;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+}
+
+/// Render samples and counters in the Prometheus text exposition format (version 0.0.4).
+pub fn render(samples: &[VmSample], counters: &Counters) -> String {
+    let mut out = String::new();
+    let vm_labels = |s: &VmSample| {
+        format!(
+            "vm=\"{}\",backend=\"{}\",project=\"{}\"",
+            escape(&s.vm),
+            escape(&s.backend),
+            escape(&s.project)
+        )
+    };
+
+    family(
+        &mut out,
+        "vmctl_vm_state",
+        "gauge",
+        "Current VM state: 1 for the state the VM is in, 0 for the others.",
+    );
+    for s in samples {
+        let current = s.state.map_or("unknown".to_string(), |st| st.to_string());
+        for state in STATES {
+            let value = u8::from(state == current);
+            let _ = writeln!(
+                out,
+                "vmctl_vm_state{{{},state=\"{state}\"}} {value}",
+                vm_labels(s)
+            );
+        }
+    }
+
+    let gauges: [(&str, &str, &str, SampleValue); 8] = [
+        (
+            "vmctl_vm_cpu_percent",
+            "gauge",
+            "Host CPU used by the VM process since the previous scrape, in percent of one core.",
+            |s| s.cpu_percent,
+        ),
+        (
+            "vmctl_vm_memory_rss_bytes",
+            "gauge",
+            "Resident memory of the VM process.",
+            |s| s.rss_bytes.map(|v| v as f64),
+        ),
+        (
+            "vmctl_vm_memory_configured_bytes",
+            "gauge",
+            "Memory the VM is configured with.",
+            |s| s.memory_configured_bytes.map(|v| v as f64),
+        ),
+        (
+            "vmctl_vm_memory_guest_bytes",
+            "gauge",
+            "Memory QEMU reports the guest has, base plus hotplugged.",
+            |s| s.guest_memory_bytes.map(|v| v as f64),
+        ),
+        (
+            "vmctl_vm_disk_allocated_bytes",
+            "gauge",
+            "Host disk space allocated to the VM's disk image.",
+            |s| s.disk_allocated_bytes.map(|v| v as f64),
+        ),
+        (
+            "vmctl_vm_uptime_seconds",
+            "gauge",
+            "Time since the VM process started.",
+            |s| s.uptime_seconds,
+        ),
+        (
+            "vmctl_vm_disk_read_bytes_total",
+            "counter",
+            "Bytes the guest read from its root disk since the VM started.",
+            |s| s.disk_read_bytes.map(|v| v as f64),
+        ),
+        (
+            "vmctl_vm_disk_written_bytes_total",
+            "counter",
+            "Bytes the guest wrote to its root disk since the VM started.",
+            |s| s.disk_written_bytes.map(|v| v as f64),
+        ),
+    ];
+    for (name, kind, help, value) in gauges {
+        family(&mut out, name, kind, help);
+        for s in samples {
+            if let Some(v) = value(s) {
+                let _ = writeln!(out, "{name}{{{}}} {v}", vm_labels(s));
+            }
+        }
+    }
+
+    family(
+        &mut out,
+        "vmctl_lifecycle_operations_total",
+        "counter",
+        "Successful VM lifecycle operations.",
+    );
+    for (vm, ops) in &counters.lifecycle_operations {
+        for (op, n) in ops {
+            let _ = writeln!(
+                out,
+                "vmctl_lifecycle_operations_total{{vm=\"{}\",operation=\"{}\"}} {n}",
+                escape(vm),
+                escape(op)
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "vmctl_provision_failures_total",
+        "counter",
+        "Provision runs that failed.",
+    );
+    for (vm, n) in &counters.provision_failures {
+        let _ = writeln!(
+            out,
+            "vmctl_provision_failures_total{{vm=\"{}\"}} {n}",
+            escape(vm)
+        );
+    }
+
+    family(
+        &mut out,
+        "vmctl_image_download_bytes_total",
+        "counter",
+        "Bytes downloaded into the image cache.",
+    );
+    let _ = writeln!(
+        out,
+        "vmctl_image_download_bytes_total {}",
+        counters.image_download_bytes
+    );
+    out
+}
+
+/// Reads one metric's value out of a sample.
+type SampleValue = fn(&VmSample) -> Option<f64>;
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escape a label value: backslash, double quote and newline.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proc_stat_fields() {
+        // Field 2 contains spaces and a parenthesis.
+        let stat = "4242 (qemu (x) kvm) S 1 4242 4242 0 -1 4194560 100 0 0 0 \
+                    250 50 0 0 20 0 3 0 12345 1000000 2048 18446744073709551615";
+        assert_eq!(parse_proc_stat(stat), Some((300, 12345, 2048)));
+        assert_eq!(parse_proc_stat("4242 (qemu) S 1"), None);
+    }
+
+    #[tokio::test]
+    async fn resource_usage_of_running_and_stopped() {
+        // This test process stands in for the VM's
+        let vm: VmHandle = serde_json::from_value(serde_json::json!({
+            "id": "noop-1",
+            "name": "web",
+            "backend": "noop",
+            "work_dir": "/nonexistent",
+            "pid": std::process::id(),
+        }))
+        .unwrap();
+
+        let usage = resource_usage(&vm, VmState::Running).await;
+        if cfg!(target_os = "linux") {
+            assert!(usage.cpu_percent.is_some_and(|p| p >= 0.0));
+            assert!(usage.rss_bytes.is_some_and(|b| b > 0));
+            assert!(usage.uptime_seconds.is_some());
+        }
+        // Only asked of QEMU, and there is no overlay
+        assert_eq!(usage.vcpu_threads, None);
+        assert_eq!(usage.guest_memory_bytes, None);
+        assert_eq!(usage.overlay_virtual_bytes, None);
+
+        assert_eq!(
+            resource_usage(&vm, VmState::Stopped).await,
+            ResourceUsage::default()
+        );
+    }
+
+    #[test]
+    fn recorder_counts_events() {
+        let mut rec = CounterRecorder::new();
+        let vm = || "web".to_string();
+        rec.record(&VmEvent::VmPrepared { vm: vm() });
+        rec.record(&VmEvent::VmStarted {
+            vm: vm(),
+            pid: None,
+        });
+        rec.record(&VmEvent::VmStarted {
+            vm: vm(),
+            pid: None,
+        });
+        // A backend error outside provisioning is not a provision failure.
+        rec.record(&VmEvent::Error {
+            vm: Some(vm()),
+            message: "boom".into(),
+        });
+        rec.record(&VmEvent::ProvisionStepStarted {
+            vm: vm(),
+            step: "1".into(),
+        });
+        rec.record(&VmEvent::Error {
+            vm: Some(vm()),
+            message: "exit 1".into(),
+        });
+        for downloaded in [100, 400, 50] {
+            rec.record(&VmEvent::DownloadProgress {
+                url: "https://example.invalid/a.img".into(),
+                downloaded,
+                total: None,
+            });
+        }
+
+        let counters = rec.counters();
+        assert_eq!(counters.lifecycle_operations["web"]["prepare"], 1);
+        assert_eq!(counters.lifecycle_operations["web"]["start"], 2);
+        assert_eq!(counters.provision_failures["web"], 1);
+        assert_eq!(counters.image_download_bytes, 450);
+
+        let mut total = counters.clone();
+        total.add(counters);
+        assert_eq!(total.lifecycle_operations["web"]["start"], 4);
+        assert_eq!(total.image_download_bytes, 900);
+    }
+
+    #[tokio::test]
+    async fn collect_and_render() {
+        let hv = crate::RouterHypervisor::noop_only();
+        let dir = tempfile::tempdir().unwrap();
+        let spec = crate::types::VmSpec {
+            name: "web".into(),
+            image_path: dir.path().join("base.qcow2"),
+            vcpus: 1,
+            memory_mb: 512,
+            disk_gb: None,
+            overlay: Default::default(),
+            network: crate::types::NetworkConfig::None,
+            cloud_init: None,
+            ssh: None,
+            uefi: false,
+            mac_addr: None,
+            existing_macs: Vec::new(),
+            static_ip: None,
+            ssh_host_port: None,
+            private_networks: Vec::new(),
+            serial_ports: Vec::new(),
+            machine: crate::types::MachineType::Q35,
+            kernel: None,
+            cdrom: None,
+            boot_order: Vec::new(),
+            random_seed: Default::default(),
+            qemu_args: Vec::new(),
+            restart_policy: Default::default(),
+            guest_os: Default::default(),
+            display: Default::default(),
+            audio: Default::default(),
+            smbios: Default::default(),
+            disk_bus: None,
+            drivers_iso: None,
+            secure_boot: false,
+            secure_boot_vars: None,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
+        };
+        let mut vm = hv.prepare(&spec).await.unwrap();
+        vm.labels.insert("project".into(), "ci\"x".into());
+        // A VM on a remote host that can't be reached must not fail the whole collection.
+        let mut remote = vm.clone();
+        remote.name = "remote".into();
+        remote.remote_host = Some("ssh://nobody@127.0.0.1:9".into());
+
+        let samples = Collector::new().collect(&hv, &[vm.clone(), remote]).await;
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].state, Some(VmState::Prepared));
+        assert_eq!(samples[1].state, None);
+
+        let text = render(&samples, &Counters::default());
+        assert!(text.contains(
+            "vmctl_vm_state{vm=\"web\",backend=\"noop\",project=\"ci\\\"x\",state=\"prepared\"} 1"
+        ));
+        assert!(
+            text.contains(
+                "vm=\"remote\",backend=\"noop\",project=\"ci\\\"x\",state=\"unknown\"} 1"
+            )
+        );
+        assert!(text.contains(
+            "vmctl_vm_memory_configured_bytes{vm=\"web\",backend=\"noop\",project=\"ci\\\"x\"} 536870912"
+        ));
+        assert!(!text.contains("vmctl_vm_memory_guest_bytes{"));
+        assert!(text.contains("# TYPE vmctl_lifecycle_operations_total counter"));
+        assert!(text.contains("vmctl_image_download_bytes_total 0"));
+
+        hv.destroy(vm).await.unwrap();
+    }
+}
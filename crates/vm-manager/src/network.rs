@@ -0,0 +1,779 @@
+//! Managed NAT networks and private network bridges (Linux).
+//!
+//! A managed network is a Linux bridge with a gateway address, masquerade rules (nftables, or
+//! iptables when `nft` is not installed) and a dnsmasq instance serving DHCP on the bridge. VMs
+//! attach to it like any other bridge, with `NetworkConfig::Tap`.
+//!
+//! Each network lives in `<dir>/<name>/`: `network.json` (the [`ManagedNetwork`] record),
+//! `dnsmasq.conf`, `dnsmasq.leases` and `dnsmasq.pid`. Every host resource is recorded as soon as
+//! it exists, so [`NetworkManager::delete`] removes everything, including what is left over from
+//! a creation that failed halfway.
+//!
+//! Private networks from a VMFile use a bare bridge, `vmp-<name>`, with no address, NAT or DHCP.
+//! It is created when the first member starts and removed when the last member is destroyed.
+//!
+//! VMs with `NetworkConfig::Macvtap` get a macvtap device of their own, `mvt-<id>`, on the host
+//! NIC. QEMU is handed its `/dev/tap<ifindex>` node as an open file descriptor.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::capabilities::which;
+use crate::error::{Result, VmError};
+pub use crate::types::Subnet;
+
+/// Prefix for bridge names (`vm-<name>`); Linux limits interface names to 15 bytes.
+const BRIDGE_PREFIX: &str = "vm-";
+const MAX_NAME_LEN: usize = 15 - BRIDGE_PREFIX.len();
+
+/// Prefix for private network bridges (`vmp-<name>`).
+const PRIVATE_BRIDGE_PREFIX: &str = "vmp-";
+
+/// Prefix for the macvtap devices of VMs (`mvt-<id>`).
+const MACVTAP_PREFIX: &str = "mvt-";
+
+/// A host resource created for a managed network, recorded so it can be removed again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum HostResource {
+    /// A bridge interface (removing it also removes its address).
+    Bridge { name: String },
+    /// An nftables table holding the NAT and forwarding rules.
+    NftTable { name: String },
+    /// An iptables rule, inserted with `iptables -t <table> -I <chain> <rule...>`.
+    IptablesRule {
+        table: String,
+        chain: String,
+        rule: Vec<String>,
+    },
+    /// The dnsmasq process serving DHCP on the bridge.
+    Dnsmasq { pid: u32 },
+}
+
+impl fmt::Display for HostResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bridge { name } => write!(f, "bridge {name}"),
+            Self::NftTable { name } => write!(f, "nftables table ip {name}"),
+            Self::IptablesRule { table, chain, rule } => {
+                write!(f, "iptables -t {table} {chain} {}", rule.join(" "))
+            }
+            Self::Dnsmasq { pid } => write!(f, "dnsmasq (pid {pid})"),
+        }
+    }
+}
+
+/// A managed network as recorded in `network.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManagedNetwork {
+    pub name: String,
+    pub bridge: String,
+    pub subnet: Subnet,
+    /// Host resources in creation order.
+    pub resources: Vec<HostResource>,
+}
+
+impl ManagedNetwork {
+    /// PID of the network's dnsmasq, if it is still running.
+    pub fn dnsmasq_pid(&self) -> Option<u32> {
+        self.resources.iter().find_map(|r| match r {
+            HostResource::Dnsmasq { pid } if pid_alive(*pid) => Some(*pid),
+            _ => None,
+        })
+    }
+}
+
+/// Creates, lists and deletes managed networks stored under one directory (by default
+/// `<data-dir>/networks`).
+pub struct NetworkManager {
+    dir: PathBuf,
+}
+
+impl NetworkManager {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn network_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// The dnsmasq lease file of network `name`.
+    pub fn lease_file(&self, name: &str) -> PathBuf {
+        self.network_dir(name).join("dnsmasq.leases")
+    }
+
+    /// All managed networks, sorted by name.
+    pub async fn list(&self) -> Result<Vec<ManagedNetwork>> {
+        let mut networks = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(networks),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path().join("network.json");
+            match tokio::fs::read_to_string(&path).await {
+                Ok(data) => match serde_json::from_str(&data) {
+                    Ok(network) => networks.push(network),
+                    Err(e) => {
+                        warn!(path = %path.display(), error = %e, "skipping unreadable network record")
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        networks.sort_by(|a: &ManagedNetwork, b| a.name.cmp(&b.name));
+        Ok(networks)
+    }
+
+    /// The managed network called `name`.
+    pub async fn get(&self, name: &str) -> Result<ManagedNetwork> {
+        let path = self.network_dir(name).join("network.json");
+        match tokio::fs::read_to_string(&path).await {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| VmError::NetworkInvalid {
+                detail: format!("corrupt network record {}: {e}", path.display()),
+                hint: format!(
+                    "remove the host resources by hand, then delete {}",
+                    self.network_dir(name).display()
+                ),
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(VmError::NetworkNotFound {
+                name: name.to_string(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The managed network whose bridge is `bridge`, if any.
+    pub async fn find_by_bridge(&self, bridge: &str) -> Option<ManagedNetwork> {
+        self.list()
+            .await
+            .ok()?
+            .into_iter()
+            .find(|n| n.bridge == bridge)
+    }
+
+    /// Create network `name`: bridge `vm-<name>` with the subnet's first address, masquerading
+    /// for traffic leaving the subnet, and dnsmasq handing out the rest of the subnet.
+    ///
+    /// Requires root. If any step fails, everything created so far is removed again.
+    pub async fn create(&self, name: &str, subnet: Subnet) -> Result<ManagedNetwork> {
+        validate_name(name)?;
+        require_root("create a network")?;
+
+        if self.network_dir(name).join("network.json").exists() {
+            return Err(VmError::NetworkInvalid {
+                detail: format!("network {name} already exists"),
+                hint: format!("delete it first with `vmctl network delete {name}`"),
+            });
+        }
+        for other in self.list().await? {
+            if other.subnet.overlaps(&subnet) {
+                return Err(VmError::NetworkInvalid {
+                    detail: format!(
+                        "subnet {subnet} overlaps {} of network {}",
+                        other.subnet, other.name
+                    ),
+                    hint: "pick a subnet that no other network uses".into(),
+                });
+            }
+        }
+        let bridge = format!("{BRIDGE_PREFIX}{name}");
+        if interface_exists(&bridge) {
+            return Err(VmError::NetworkInvalid {
+                detail: format!("interface {bridge} already exists on this host"),
+                hint: format!("remove it with `ip link delete {bridge}` or choose another name"),
+            });
+        }
+        if which("dnsmasq").is_none() {
+            return Err(VmError::NetworkInvalid {
+                detail: "dnsmasq is not installed".into(),
+                hint: "install dnsmasq (it serves DHCP on managed networks)".into(),
+            });
+        }
+
+        tokio::fs::create_dir_all(self.network_dir(name)).await?;
+        let mut network = ManagedNetwork {
+            name: name.to_string(),
+            bridge,
+            subnet,
+            resources: Vec::new(),
+        };
+        match self.setup(&mut network).await {
+            Ok(()) => {
+                info!(network = %name, bridge = %network.bridge, %subnet, "network created");
+                Ok(network)
+            }
+            Err(e) => {
+                warn!(network = %name, error = %e, "network creation failed, rolling back");
+                if let Err(cleanup) = self.teardown(&mut network).await {
+                    warn!(network = %name, error = %cleanup, "rollback incomplete");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn setup(&self, network: &mut ManagedNetwork) -> Result<()> {
+        let name = network.name.clone();
+        let bridge = network.bridge.clone();
+        let subnet = network.subnet;
+
+        run(&name, "ip", &["link", "add", &bridge, "type", "bridge"]).await?;
+        self.record(
+            network,
+            HostResource::Bridge {
+                name: bridge.clone(),
+            },
+        )
+        .await?;
+        let address = format!("{}/{}", subnet.gateway(), subnet.prefix);
+        run(&name, "ip", &["addr", "add", &address, "dev", &bridge]).await?;
+        run(&name, "ip", &["link", "set", &bridge, "up"]).await?;
+
+        // Forwarding is a host-wide switch; it is left on when the network is deleted.
+        tokio::fs::write("/proc/sys/net/ipv4/ip_forward", "1")
+            .await
+            .map_err(|e| VmError::NetworkSetupFailed {
+                name: name.clone(),
+                detail: format!("cannot enable IPv4 forwarding: {e}"),
+            })?;
+
+        if which("nft").is_some() {
+            let table = format!("vmctl_{}", name.replace('-', "_"));
+            run(&name, "nft", &["add", "table", "ip", &table]).await?;
+            self.record(
+                network,
+                HostResource::NftTable {
+                    name: table.clone(),
+                },
+            )
+            .await?;
+            let script = nft_script(&table, &bridge, subnet);
+            let args: Vec<&str> = script.iter().map(String::as_str).collect();
+            run(&name, "nft", &args).await?;
+        } else if which("iptables").is_some() {
+            for (table, chain, rule) in iptables_rules(&bridge, subnet) {
+                let mut args = vec!["-t", table, "-I", chain];
+                args.extend(rule.iter().map(String::as_str));
+                run(&name, "iptables", &args).await?;
+                self.record(
+                    network,
+                    HostResource::IptablesRule {
+                        table: table.into(),
+                        chain: chain.into(),
+                        rule,
+                    },
+                )
+                .await?;
+            }
+        } else {
+            return Err(VmError::NetworkSetupFailed {
+                name,
+                detail: "neither nft nor iptables is installed".into(),
+            });
+        }
+
+        let dir = self.network_dir(&network.name);
+        let conf = dir.join("dnsmasq.conf");
+        let pid_file = dir.join("dnsmasq.pid");
+        tokio::fs::write(&conf, dnsmasq_config(network, &dir)).await?;
+        let _ = tokio::fs::remove_file(&pid_file).await;
+        run(
+            &name,
+            "dnsmasq",
+            &[&format!("--conf-file={}", conf.display())],
+        )
+        .await?;
+        let pid = read_pid(&pid_file)
+            .await
+            .ok_or_else(|| VmError::NetworkSetupFailed {
+                name: name.clone(),
+                detail: format!("dnsmasq did not write {}", pid_file.display()),
+            })?;
+        self.record(network, HostResource::Dnsmasq { pid }).await
+    }
+
+    /// Delete network `name` and every host resource recorded for it.
+    ///
+    /// Resources that are already gone are skipped. If some cannot be removed, the record keeps
+    /// them so `delete` can be retried.
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        require_root("delete a network")?;
+        let mut network = self.get(name).await?;
+        self.teardown(&mut network).await?;
+        info!(network = %name, "network deleted");
+        Ok(())
+    }
+
+    async fn teardown(&self, network: &mut ManagedNetwork) -> Result<()> {
+        let mut failed = Vec::new();
+        while let Some(resource) = network.resources.pop() {
+            if let Err(e) = remove_resource(&network.name, &resource).await {
+                warn!(network = %network.name, %resource, error = %e, "failed to remove");
+                failed.push((resource, e));
+            }
+        }
+        if failed.is_empty() {
+            tokio::fs::remove_dir_all(self.network_dir(&network.name)).await?;
+            return Ok(());
+        }
+
+        let detail = failed
+            .iter()
+            .map(|(r, e)| format!("{r}: {e}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        network.resources = failed.into_iter().rev().map(|(r, _)| r).collect();
+        self.save(network).await?;
+        Err(VmError::NetworkSetupFailed {
+            name: network.name.clone(),
+            detail: format!("could not remove {detail}"),
+        })
+    }
+
+    /// Append `resource` to the network's record and persist it immediately.
+    async fn record(&self, network: &mut ManagedNetwork, resource: HostResource) -> Result<()> {
+        network.resources.push(resource);
+        self.save(network).await
+    }
+
+    async fn save(&self, network: &ManagedNetwork) -> Result<()> {
+        let path = self.network_dir(&network.name).join("network.json");
+        let data = serde_json::to_string_pretty(network)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+}
+
+/// The host bridge of private network `network`.
+pub fn private_bridge_name(network: &str) -> String {
+    format!("{PRIVATE_BRIDGE_PREFIX}{network}")
+}
+
+/// Create a private network's bridge unless it exists. The bridge has no address and no uplink,
+/// so guests attached to it reach each other and nothing else.
+///
+/// Requires root.
+pub async fn ensure_private_bridge(network: &str) -> Result<String> {
+    let bridge = private_bridge_name(network);
+    if interface_exists(&bridge) {
+        return Ok(bridge);
+    }
+    require_root("create a private network bridge")?;
+    run(network, "ip", &["link", "add", &bridge, "type", "bridge"]).await?;
+    run(network, "ip", &["link", "set", &bridge, "up"]).await?;
+    info!(network = %network, %bridge, "private network bridge created");
+    Ok(bridge)
+}
+
+/// Remove a private network's bridge if no interface is attached to it any more, i.e. its last
+/// running member is gone. Returns whether the bridge was removed.
+pub async fn remove_private_bridge_if_unused(network: &str) -> Result<bool> {
+    let bridge = private_bridge_name(network);
+    let ports = Path::new("/sys/class/net").join(&bridge).join("brif");
+    let in_use = match std::fs::read_dir(&ports) {
+        Ok(mut entries) => entries.next().is_some(),
+        Err(_) => return Ok(false),
+    };
+    if in_use {
+        return Ok(false);
+    }
+    run(network, "ip", &["link", "delete", &bridge]).await?;
+    info!(network = %network, %bridge, "private network bridge removed");
+    Ok(true)
+}
+
+/// Shell script QEMU runs to attach a private network tap device (`$1`) to the bridge.
+pub fn private_ifup_script(bridge: &str) -> String {
+    format!("#!/bin/sh\nip link set \"$1\" master {bridge}\nip link set \"$1\" up\n")
+}
+
+/// The macvtap device of the VM with id `vm_id` (`qemu-<uuid>`): the UUID's first hex digits, to
+/// fit Linux's 15 byte limit on interface names.
+pub fn macvtap_name(vm_id: &str) -> String {
+    let uuid = vm_id.split_once('-').map_or(vm_id, |(_, uuid)| uuid);
+    let digits: String = uuid
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .take(15 - MACVTAP_PREFIX.len())
+        .collect();
+    format!("{MACVTAP_PREFIX}{digits}")
+}
+
+/// Open macvtap device `name` on host NIC `parent` for a guest NIC with address `mac`, creating
+/// the device unless it exists. The returned file is the device's `/dev/tap<ifindex>` node, for
+/// QEMU's `-netdev tap,fd=`.
+///
+/// Requires root.
+pub async fn open_macvtap(name: &str, parent: &str, mac: &str) -> Result<std::fs::File> {
+    if !interface_exists(name) {
+        if !interface_exists(parent) {
+            return Err(VmError::NetworkInvalid {
+                detail: format!("host network interface {parent} does not exist"),
+                hint: "set the macvtap parent to a host NIC listed by `ip link`".into(),
+            });
+        }
+        require_root("create a macvtap device")?;
+        // Bridge mode lets guests on the same parent reach each other
+        run(
+            name,
+            "ip",
+            &[
+                "link", "add", "link", parent, "name", name, "address", mac, "type", "macvtap",
+                "mode", "bridge",
+            ],
+        )
+        .await?;
+        info!(%name, %parent, "macvtap device created");
+    }
+    run(name, "ip", &["link", "set", name, "up"]).await?;
+
+    let ifindex = std::fs::read_to_string(Path::new("/sys/class/net").join(name).join("ifindex"))?;
+    let node = format!("/dev/tap{}", ifindex.trim());
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&node)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::PermissionDenied => VmError::NetworkRequiresRoot {
+                operation: format!("open {node}"),
+            },
+            _ => VmError::NetworkSetupFailed {
+                name: name.to_string(),
+                detail: format!("cannot open {node}: {e}"),
+            },
+        })
+}
+
+/// Delete macvtap device `name` if it exists.
+pub async fn delete_macvtap(name: &str) -> Result<()> {
+    if !interface_exists(name) {
+        return Ok(());
+    }
+    run(name, "ip", &["link", "delete", name]).await?;
+    info!(%name, "macvtap device removed");
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(VmError::NetworkInvalid {
+            detail: format!("invalid network name: {name}"),
+            hint: format!(
+                "use at most {MAX_NAME_LEN} lowercase letters, digits and dashes, starting with a letter (the bridge is named {BRIDGE_PREFIX}<name>)"
+            ),
+        })
+    }
+}
+
+fn require_root(operation: &str) -> Result<()> {
+    if unsafe { libc::geteuid() } == 0 {
+        Ok(())
+    } else {
+        Err(VmError::NetworkRequiresRoot {
+            operation: operation.to_string(),
+        })
+    }
+}
+
+fn interface_exists(name: &str) -> bool {
+    Path::new("/sys/class/net").join(name).exists()
+}
+
+fn pid_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as i32, 0) == 0 }
+}
+
+/// Run a host networking command, turning a non-zero exit into an error carrying its stderr.
+async fn run(network: &str, program: &str, args: &[&str]) -> Result<()> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| VmError::NetworkSetupFailed {
+            name: network.to_string(),
+            detail: format!("failed to run {program}: {e}"),
+        })?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("Operation not permitted") {
+        return Err(VmError::NetworkRequiresRoot {
+            operation: format!("run {program} {}", args.join(" ")),
+        });
+    }
+    Err(VmError::NetworkSetupFailed {
+        name: network.to_string(),
+        detail: format!("{program} {} failed: {}", args.join(" "), stderr.trim()),
+    })
+}
+
+async fn remove_resource(network: &str, resource: &HostResource) -> Result<()> {
+    match resource {
+        HostResource::Dnsmasq { pid } => {
+            if pid_alive(*pid) {
+                unsafe {
+                    libc::kill(*pid as i32, libc::SIGTERM);
+                }
+            }
+            Ok(())
+        }
+        HostResource::NftTable { name } => {
+            let exists = tokio::process::Command::new("nft")
+                .args(["list", "table", "ip", name])
+                .output()
+                .await
+                .is_ok_and(|o| o.status.success());
+            if !exists {
+                return Ok(());
+            }
+            run(network, "nft", &["delete", "table", "ip", name]).await
+        }
+        HostResource::IptablesRule { table, chain, rule } => {
+            let rule: Vec<&str> = rule.iter().map(String::as_str).collect();
+            let mut check = vec!["-t", table.as_str(), "-C", chain.as_str()];
+            check.extend(&rule);
+            let exists = tokio::process::Command::new("iptables")
+                .args(&check)
+                .output()
+                .await
+                .is_ok_and(|o| o.status.success());
+            if !exists {
+                return Ok(());
+            }
+            let mut delete = vec!["-t", table.as_str(), "-D", chain.as_str()];
+            delete.extend(&rule);
+            run(network, "iptables", &delete).await
+        }
+        HostResource::Bridge { name } => {
+            if !interface_exists(name) {
+                return Ok(());
+            }
+            run(network, "ip", &["link", "delete", name]).await
+        }
+    }
+}
+
+/// Wait briefly for dnsmasq to write its pidfile after daemonizing.
+async fn read_pid(path: &Path) -> Option<u32> {
+    for _ in 0..20 {
+        if let Ok(s) = tokio::fs::read_to_string(path).await {
+            if let Ok(pid) = s.trim().parse() {
+                return Some(pid);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    None
+}
+
+/// nftables chains for the network's table, as one `nft` command: masquerade traffic leaving
+/// the subnet and let the bridge's traffic through the forward hook.
+fn nft_script(table: &str, bridge: &str, subnet: Subnet) -> Vec<String> {
+    let subnet = subnet.to_string();
+    [
+        format!(
+            "add chain ip {table} postrouting {{ type nat hook postrouting priority srcnat ; }}"
+        ),
+        format!(
+            "add rule ip {table} postrouting ip saddr {subnet} ip daddr != {subnet} masquerade"
+        ),
+        format!("add chain ip {table} forward {{ type filter hook forward priority filter ; }}"),
+        format!("add rule ip {table} forward iifname {bridge} accept"),
+        format!("add rule ip {table} forward oifname {bridge} ct state related,established accept"),
+    ]
+    .into_iter()
+    .fold(Vec::new(), |mut args, cmd| {
+        if !args.is_empty() {
+            args.push(";".into());
+        }
+        args.extend(cmd.split(' ').map(String::from));
+        args
+    })
+}
+
+/// The iptables equivalent of [`nft_script`]: `(table, chain, rule)` triples.
+fn iptables_rules(bridge: &str, subnet: Subnet) -> Vec<(&'static str, &'static str, Vec<String>)> {
+    let subnet = subnet.to_string();
+    let rule = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    vec![
+        (
+            "nat",
+            "POSTROUTING",
+            rule(&["-s", &subnet, "!", "-d", &subnet, "-j", "MASQUERADE"]),
+        ),
+        ("filter", "FORWARD", rule(&["-i", bridge, "-j", "ACCEPT"])),
+        (
+            "filter",
+            "FORWARD",
+            rule(&[
+                "-o",
+                bridge,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "RELATED,ESTABLISHED",
+                "-j",
+                "ACCEPT",
+            ]),
+        ),
+    ]
+}
+
+/// dnsmasq configuration: DHCP and DNS on the bridge only, with state files in `dir`.
+fn dnsmasq_config(network: &ManagedNetwork, dir: &Path) -> String {
+    let subnet = network.subnet;
+    let (start, end) = subnet.dhcp_range();
+    format!(
+        "# Managed by vmctl; removed by `vmctl network delete {name}`.\n\
+         interface={bridge}\n\
+         bind-interfaces\n\
+         except-interface=lo\n\
+         listen-address={gateway}\n\
+         strict-order\n\
+         domain-needed\n\
+         bogus-priv\n\
+         dhcp-authoritative\n\
+         dhcp-range={start},{end},{netmask},12h\n\
+         dhcp-option=option:router,{gateway}\n\
+         dhcp-leasefile={leases}\n\
+         pid-file={pid}\n",
+        name = network.name,
+        bridge = network.bridge,
+        gateway = subnet.gateway(),
+        netmask = subnet.netmask(),
+        leases = dir.join("dnsmasq.leases").display(),
+        pid = dir.join("dnsmasq.pid").display(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn subnet_addresses() {
+        let subnet = Subnet::parse("192.168.123.0/24").unwrap();
+        assert_eq!(subnet.gateway(), Ipv4Addr::new(192, 168, 123, 1));
+        assert_eq!(subnet.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(
+            subnet.dhcp_range(),
+            (
+                Ipv4Addr::new(192, 168, 123, 2),
+                Ipv4Addr::new(192, 168, 123, 254)
+            )
+        );
+        assert_eq!(subnet.to_string(), "192.168.123.0/24");
+
+        let small = Subnet::parse("10.0.0.0/30").unwrap();
+        assert_eq!(
+            small.dhcp_range(),
+            (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 2))
+        );
+
+        assert!(subnet.overlaps(&Subnet::parse("192.168.0.0/16").unwrap()));
+        assert!(!subnet.overlaps(&Subnet::parse("192.168.124.0/24").unwrap()));
+
+        for bad in [
+            "192.168.123.0",
+            "192.168.123.5/24",
+            "10.0.0.0/31",
+            "nope/24",
+        ] {
+            assert!(Subnet::parse(bad).is_err(), "{bad}");
+        }
+        let err = Subnet::parse("192.168.123.5/24").unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("192.168.123.0/24"),
+            "hint should suggest the network address: {err:?}"
+        );
+    }
+
+    #[test]
+    fn network_names() {
+        assert!(validate_name("lab").is_ok());
+        assert!(validate_name("build-net-01").is_ok());
+        for bad in ["", "Lab", "1net", "net_1", "much-too-long-name"] {
+            assert!(validate_name(bad).is_err(), "{bad}");
+        }
+        assert_eq!(
+            macvtap_name("qemu-3f2a9c1e-77b0-4d1e-9a55-0c6a1b2c3d4e"),
+            "mvt-3f2a9c1e77b"
+        );
+    }
+
+    #[test]
+    fn host_configuration() {
+        let network = ManagedNetwork {
+            name: "lab".into(),
+            bridge: "vm-lab".into(),
+            subnet: Subnet::parse("192.168.123.0/24").unwrap(),
+            resources: Vec::new(),
+        };
+
+        let conf = dnsmasq_config(&network, Path::new("/data/networks/lab"));
+        assert!(conf.contains("interface=vm-lab\n"));
+        assert!(conf.contains("dhcp-range=192.168.123.2,192.168.123.254,255.255.255.0,12h\n"));
+        assert!(conf.contains("dhcp-option=option:router,192.168.123.1\n"));
+        assert!(conf.contains("dhcp-leasefile=/data/networks/lab/dnsmasq.leases\n"));
+
+        let script = nft_script("vmctl_lab", "vm-lab", network.subnet).join(" ");
+        assert!(script.contains(
+            "add rule ip vmctl_lab postrouting ip saddr 192.168.123.0/24 ip daddr != 192.168.123.0/24 masquerade"
+        ));
+        assert!(script.contains("add rule ip vmctl_lab forward iifname vm-lab accept"));
+
+        let rules = iptables_rules("vm-lab", network.subnet);
+        assert_eq!(rules[0].0, "nat");
+        assert_eq!(rules[0].1, "POSTROUTING");
+        assert_eq!(
+            rules[0].2.join(" "),
+            "-s 192.168.123.0/24 ! -d 192.168.123.0/24 -j MASQUERADE"
+        );
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let network = ManagedNetwork {
+            name: "lab".into(),
+            bridge: "vm-lab".into(),
+            subnet: Subnet::parse("192.168.123.0/24").unwrap(),
+            resources: vec![
+                HostResource::Bridge {
+                    name: "vm-lab".into(),
+                },
+                HostResource::NftTable {
+                    name: "vmctl_lab".into(),
+                },
+                HostResource::Dnsmasq { pid: 4242 },
+            ],
+        };
+        let json = serde_json::to_string(&network).unwrap();
+        assert!(json.contains("\"subnet\":\"192.168.123.0/24\""), "{json}");
+        assert!(json.contains("\"kind\":\"nft-table\""), "{json}");
+        let parsed: ManagedNetwork = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.resources, network.resources);
+        assert_eq!(parsed.subnet, network.subnet);
+    }
+}
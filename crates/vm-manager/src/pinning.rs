@@ -0,0 +1,202 @@
+//! Pinning a VM's threads to host CPUs.
+//!
+//! CPU lists are written the way the kernel's `cpuset` files and `taskset -c` write them,
+//! e.g. `4-7,10`. Pinning itself uses `sched_setaffinity`, so it is Linux-only; elsewhere
+//! [`available_cpus`] returns `None` and [`CpuPinning`] settings are only checked for syntax.
+
+use crate::types::CpuPinning;
+
+/// One more than the highest CPU number a CPU list may name: the size of the kernel's
+/// `cpu_set_t`.
+pub const MAX_CPUS: usize = 1024;
+
+/// Parse a CPU list such as `4-7,10` into sorted CPU numbers without duplicates.
+pub fn parse_cpuset(list: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for part in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse_cpu(first)?, parse_cpu(last)?),
+            None => {
+                let cpu = parse_cpu(part)?;
+                (cpu, cpu)
+            }
+        };
+        if first > last {
+            return Err(format!("CPU range {part} is reversed"));
+        }
+        cpus.extend(first..=last);
+    }
+    if cpus.is_empty() {
+        return Err("empty CPU list".to_string());
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+fn parse_cpu(s: &str) -> Result<usize, String> {
+    let cpu: usize = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid CPU number: {s}"))?;
+    if cpu >= MAX_CPUS {
+        return Err(format!(
+            "CPU {cpu} is out of range (at most {})",
+            MAX_CPUS - 1
+        ));
+    }
+    Ok(cpu)
+}
+
+/// The shortest CPU list naming `cpus`, e.g. `4-7,10`.
+pub fn format_cpuset(cpus: &[usize]) -> String {
+    let mut sorted = cpus.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut ranges = Vec::new();
+    let mut iter = sorted.into_iter().peekable();
+    while let Some(first) = iter.next() {
+        let mut last = first;
+        while iter.next_if_eq(&(last + 1)).is_some() {
+            last += 1;
+        }
+        ranges.push(if first == last {
+            first.to_string()
+        } else {
+            format!("{first}-{last}")
+        });
+    }
+    ranges.join(",")
+}
+
+/// Host CPUs vmctl may put threads on: the online CPUs, narrowed by the affinity and cpuset
+/// cgroup vmctl itself runs under. `None` where that can't be read.
+pub fn available_cpus() -> Option<Vec<usize>> {
+    #[cfg(target_os = "linux")]
+    {
+        thread_affinity(0).ok()
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Check that every vCPU `pinning` pins exists in a VM with `vcpus` vCPUs and that every host
+/// CPU it names is [available](available_cpus); the error says what is wrong.
+pub fn check(pinning: &CpuPinning, vcpus: u16) -> Result<(), String> {
+    if let Some(vcpu) = pinning.vcpus.keys().find(|&&v| v >= vcpus) {
+        return Err(format!(
+            "vCPU {vcpu} is pinned, but the VM has {vcpus} vCPUs (numbered from 0)"
+        ));
+    }
+    if let Some(host) = available_cpus() {
+        let missing: Vec<usize> = pinning
+            .host_cpus()
+            .into_iter()
+            .filter(|cpu| !host.contains(cpu))
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "host CPUs {} are not available (available: {})",
+                format_cpuset(&missing),
+                format_cpuset(&host)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Restrict thread `tid` to `cpus`; `tid` 0 is the calling thread.
+#[cfg(target_os = "linux")]
+pub fn set_thread_affinity(tid: u32, cpus: &[usize]) -> std::io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bit array, and CPU_SET is given CPUs below its size
+    let set = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus.iter().filter(|&&cpu| cpu < MAX_CPUS) {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        set
+    };
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_setaffinity(tid as libc::pid_t, size, &set) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Host CPUs thread `tid` may run on; `tid` 0 is the calling thread.
+#[cfg(target_os = "linux")]
+pub fn thread_affinity(tid: u32) -> std::io::Result<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_getaffinity(tid as libc::pid_t, size, &mut set) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((0..MAX_CPUS)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect())
+}
+
+/// Apply `pinning` to the threads of process `pid`: vCPU `i` runs on `vcpu_threads[i]`, and
+/// every other thread of the process counts as an emulator thread. Threads whose set is empty
+/// are left alone.
+#[cfg(target_os = "linux")]
+pub fn apply(pid: u32, vcpu_threads: &[u32], pinning: &CpuPinning) -> std::io::Result<()> {
+    for (vcpu, &tid) in (0u16..).zip(vcpu_threads) {
+        let cpus = pinning.vcpu_cpus(vcpu);
+        if !cpus.is_empty() {
+            set_thread_affinity(tid, &cpus)?;
+        }
+    }
+    if pinning.emulator.is_empty() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(format!("/proc/{pid}/task"))? {
+        let Some(tid) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        if vcpu_threads.contains(&tid) {
+            continue;
+        }
+        match set_thread_affinity(tid, &pinning.emulator) {
+            // The thread exited since the directory was read
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+            other => other?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpuset_round_trip() {
+        for (list, cpus, formatted) in [
+            ("4-7", vec![4, 5, 6, 7], "4-7"),
+            ("10, 4-5,0", vec![0, 4, 5, 10], "0,4-5,10"),
+            ("3,2,1,3", vec![1, 2, 3], "1-3"),
+        ] {
+            let parsed = parse_cpuset(list).unwrap();
+            assert_eq!(parsed, cpus, "{list}");
+            assert_eq!(format_cpuset(&parsed), formatted, "{list}");
+        }
+        for (list, expected) in [
+            ("", "empty CPU list"),
+            ("7-4", "reversed"),
+            ("a-b", "invalid CPU number"),
+            ("1024", "out of range"),
+        ] {
+            let err = parse_cpuset(list).unwrap_err();
+            assert!(err.contains(expected), "{list}: got {err}");
+        }
+    }
+
+    #[test]
+    fn check_rejects_missing_vcpus() {
+        let mut pinning = CpuPinning::default();
+        pinning.vcpus.insert(2, 0);
+        let err = check(&pinning, 2).unwrap_err();
+        assert!(err.contains("vCPU 2 is pinned"), "got {err}");
+    }
+}
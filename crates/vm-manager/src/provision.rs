@@ -1,41 +1,375 @@
+use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use ssh2::Session;
-use tracing::info;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
 
+#[cfg(target_os = "linux")]
+use crate::backends::qga::GuestAgent;
 use crate::error::{Result, VmError};
+use crate::events::{EventBus, VmEvent};
 use crate::ssh;
-use crate::vmfile::{FileProvision, ProvisionDef, ShellProvision, resolve_path};
+use crate::types::shell_quote;
+use crate::vmfile::{
+    DownloadProvision, FileProvision, LoopProvision, ProvisionDef, PuppetProvision, ShellProvision,
+    resolve_path,
+};
 
-/// Run all provision steps on an established SSH session.
+/// Selects which provision steps to run.
 ///
-/// Output from shell provisioners is streamed to stdout/stderr in real time.
+/// Steps are matched by name, or by their 1-based number for unnamed steps.
+/// An empty `only` list selects every step; `skip` is applied afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct ProvisionFilter {
+    pub only: Vec<String>,
+    pub skip: Vec<String>,
+}
+
+impl ProvisionFilter {
+    fn matches(list: &[String], step: usize, prov: &ProvisionDef) -> bool {
+        list.iter()
+            .any(|s| prov.name() == Some(s.as_str()) || *s == step.to_string())
+    }
+
+    /// Whether the 1-based `step` should run.
+    pub fn includes(&self, step: usize, prov: &ProvisionDef) -> bool {
+        (self.only.is_empty() || Self::matches(&self.only, step, prov))
+            && !Self::matches(&self.skip, step, prov)
+    }
+
+    /// Entries of `only` and `skip` that match none of `provisions`.
+    pub fn unknown<'a>(&'a self, provisions: &[ProvisionDef]) -> Vec<&'a str> {
+        self.only
+            .iter()
+            .chain(&self.skip)
+            .filter(|s| {
+                !provisions
+                    .iter()
+                    .enumerate()
+                    .any(|(i, p)| Self::matches(std::slice::from_ref(s), i + 1, p))
+            })
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Run the provision steps selected by `filter` over `transport`: an established SSH session,
+/// or the guest agent.
+///
+/// Output from shell provisioners is echoed to stdout/stderr, in real time over SSH.
 /// If `log_dir` is provided, output is also appended to `provision.log`.
+///
+/// A shell step with `capture` stores its trimmed stdout under that name. Later steps see every
+/// captured variable as `${name}` in their inline command and as an exported environment
+/// variable. Returns the captured variables.
+///
+/// Each selected step publishes `ProvisionStepStarted` into `events`, then
+/// `ProvisionStepFinished` or `Error`.
+///
+/// `cancel` is checked before each step (and each loop item); once it fires, no further step
+/// starts and `Cancelled` is returned. A step that is already running is not interrupted.
+#[allow(clippy::too_many_arguments)]
 pub fn run_provisions(
-    sess: &Session,
+    transport: &Transport,
     provisions: &[ProvisionDef],
+    filter: &ProvisionFilter,
     base_dir: &Path,
     vm_name: &str,
     log_dir: Option<&Path>,
-) -> Result<()> {
+    events: &EventBus,
+    cancel: &CancellationToken,
+) -> Result<HashMap<String, String>> {
+    let ctx = StepContext {
+        transport,
+        base_dir,
+        vm_name,
+        log_dir,
+        cancel,
+    };
+    let mut vars = HashMap::new();
     for (i, prov) in provisions.iter().enumerate() {
         let step = i + 1;
-        match prov {
-            ProvisionDef::Shell(shell) => {
-                run_shell(sess, shell, base_dir, vm_name, step, log_dir)?;
-            }
-            ProvisionDef::File(file) => {
-                run_file(sess, file, base_dir, vm_name, step, log_dir)?;
+        let label = prov.label(step);
+        if !filter.includes(step, prov) {
+            info!(vm = %vm_name, step = %label, "skipping provision step");
+            continue;
+        }
+        ctx.check_cancelled(&label)?;
+        events.publish(VmEvent::ProvisionStepStarted {
+            vm: vm_name.into(),
+            step: label.clone(),
+        });
+        if let Err(e) = run_step(&ctx, prov, step, &label, &mut vars) {
+            events.publish(VmEvent::Error {
+                vm: Some(vm_name.into()),
+                message: e.to_string(),
+            });
+            return Err(e);
+        }
+        events.publish(VmEvent::ProvisionStepFinished {
+            vm: vm_name.into(),
+            step: label,
+        });
+    }
+    Ok(vars)
+}
+
+fn run_step(
+    ctx: &StepContext,
+    prov: &ProvisionDef,
+    step: usize,
+    label: &str,
+    vars: &mut HashMap<String, String>,
+) -> Result<()> {
+    match prov {
+        ProvisionDef::Shell(shell) => {
+            let stdout = run_shell(ctx, shell, step, label, vars)?;
+            if let Some(ref var) = shell.capture {
+                info!(vm = %ctx.vm_name, step = %label, var = %var, "captured provision output");
+                vars.insert(var.clone(), stdout.trim().to_string());
             }
         }
+        ProvisionDef::File(file) => {
+            run_file(ctx, file, label)?;
+        }
+        ProvisionDef::Loop(lp) => {
+            run_loop(ctx, lp, step, label, vars)?;
+        }
+        ProvisionDef::Puppet(puppet) => {
+            run_puppet(ctx, puppet, label)?;
+        }
+        ProvisionDef::Download(download) => {
+            run_download(ctx, download, label)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the loop's step once per item, labelled `<label>[<item>]`. The item is substituted into
+/// a copy of the step and exported as `item` while it runs.
+fn run_loop(
+    ctx: &StepContext,
+    lp: &LoopProvision,
+    step: usize,
+    label: &str,
+    vars: &mut HashMap<String, String>,
+) -> Result<()> {
+    if lp.items.is_empty() {
+        warn!(vm = %ctx.vm_name, step = %label, "loop provision has no items, skipping");
+        return Ok(());
+    }
+    let outer = vars.remove("item");
+    for item in &lp.items {
+        ctx.check_cancelled(label)?;
+        vars.insert("item".into(), item.clone());
+        let prov = with_item(&lp.step, item);
+        run_step(ctx, &prov, step, &format!("{label}[{item}]"), vars)?;
+    }
+    vars.remove("item");
+    if let Some(outer) = outer {
+        vars.insert("item".into(), outer);
     }
     Ok(())
 }
 
+/// Copy of `prov` with `${item}` substituted in its inline command, file destination or
+/// download URL and destination.
+fn with_item(prov: &ProvisionDef, item: &str) -> ProvisionDef {
+    let vars = HashMap::from([("item".to_string(), item.to_string())]);
+    match prov {
+        ProvisionDef::Shell(shell) => ProvisionDef::Shell(ShellProvision {
+            inline: shell.inline.as_deref().map(|cmd| substitute(cmd, &vars)),
+            ..shell.clone()
+        }),
+        ProvisionDef::File(file) => ProvisionDef::File(FileProvision {
+            destination: substitute(&file.destination, &vars),
+            ..file.clone()
+        }),
+        ProvisionDef::Download(download) => ProvisionDef::Download(DownloadProvision {
+            url: substitute(&download.url, &vars),
+            destination: substitute(&download.destination, &vars),
+            ..download.clone()
+        }),
+        ProvisionDef::Loop(_) | ProvisionDef::Puppet(_) => prov.clone(),
+    }
+}
+
+/// How provisioning reaches the guest.
+pub enum Transport {
+    /// An SSH session. Output is streamed while commands run.
+    Ssh(Session),
+    /// The QEMU guest agent, for VMs without SSH. Commands run under `/bin/sh -c`; their output
+    /// is printed once they finish, since the agent only returns it then.
+    #[cfg(target_os = "linux")]
+    Agent(std::sync::Mutex<GuestAgent>),
+}
+
+impl Transport {
+    /// Run `cmd` in the guest's shell, echoing its output to ours, as root with `sudo`. Returns
+    /// stdout, stderr and the exit code.
+    fn exec(&self, cmd: &str, sudo: bool) -> Result<(String, String, i32)> {
+        match self {
+            Self::Ssh(sess) => {
+                let cmd = if sudo {
+                    &ssh::sudo_command(cmd, false)
+                } else {
+                    cmd
+                };
+                ssh::exec_streaming(sess, cmd, std::io::stdout(), std::io::stderr())
+            }
+            // The agent runs commands as root already
+            #[cfg(target_os = "linux")]
+            Self::Agent(agent) => {
+                let output = agent.lock().unwrap_or_else(|e| e.into_inner()).exec(
+                    "/bin/sh",
+                    &["-c".into(), cmd.into()],
+                    true,
+                )?;
+                print!("{}", output.stdout);
+                eprint!("{}", output.stderr);
+                Ok((output.stdout, output.stderr, output.exit_code))
+            }
+        }
+    }
+
+    /// Copy the local file `local` to `remote` in the guest.
+    fn upload(&self, local: &Path, remote: &Path) -> Result<()> {
+        match self {
+            Self::Ssh(sess) => ssh::upload(sess, local, remote),
+            #[cfg(target_os = "linux")]
+            Self::Agent(agent) => {
+                let contents = std::fs::read(local)?;
+                agent
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .write_file(remote, &contents)
+            }
+        }
+    }
+
+    /// Copy the local directory `local` and everything in it to `remote` in the guest.
+    fn upload_dir(&self, local: &Path, remote: &Path) -> Result<()> {
+        match self {
+            Self::Ssh(sess) => ssh::upload_dir(sess, local, remote),
+            #[cfg(target_os = "linux")]
+            Self::Agent(_) => {
+                let mkdir = format!("mkdir -p {}", shell_quote(&remote.to_string_lossy()));
+                let (_, stderr, exit_code) = self.exec(&mkdir, false)?;
+                if exit_code != 0 {
+                    return Err(std::io::Error::other(format!(
+                        "mkdir {}: {}",
+                        remote.display(),
+                        stderr.trim()
+                    ))
+                    .into());
+                }
+                for entry in std::fs::read_dir(local)? {
+                    let entry = entry?;
+                    let src = entry.path();
+                    let dst = remote.join(entry.file_name());
+                    if src.is_dir() {
+                        self.upload_dir(&src, &dst)?;
+                    } else {
+                        self.upload(&src, &dst)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What every step needs besides its own definition.
+struct StepContext<'a> {
+    transport: &'a Transport,
+    base_dir: &'a Path,
+    vm_name: &'a str,
+    log_dir: Option<&'a Path>,
+    cancel: &'a CancellationToken,
+}
+
+impl StepContext<'_> {
+    fn check_cancelled(&self, label: &str) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            info!(vm = %self.vm_name, step = %label, "provisioning cancelled");
+            return Err(VmError::Cancelled {
+                operation: format!("provisioning of VM '{}' before step {label}", self.vm_name),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Replace `${name}` with the captured value of `name`. References to anything else, such as
+/// `${HOME}`, are left for the guest's shell.
+fn substitute(cmd: &str, vars: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(cmd.len());
+    let mut rest = cmd;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after
+            .find('}')
+            .and_then(|end| Some((vars.get(&after[..end])?, end)))
+        {
+            Some((value, end)) => {
+                out.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str("${");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `export` statements for the captured variables, to prefix a remote command with.
+fn exports(vars: &HashMap<String, String>) -> String {
+    let mut names: Vec<&String> = vars.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| format!("export {name}='{}'; ", vars[name].replace('\'', "'\\''")))
+        .collect()
+}
+
+/// Prefix of the disk snapshots taken before provisioning, followed by a Unix timestamp.
+pub const PRE_PROVISION_PREFIX: &str = "pre-provision-";
+
+/// Name for a snapshot taken before provisioning at `at`: `pre-provision-<unix seconds>`.
+pub fn pre_provision_tag(at: SystemTime) -> String {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    format!("{PRE_PROVISION_PREFIX}{secs}")
+}
+
+/// The pre-provision snapshots among `snapshots` to delete so that only the newest `keep`
+/// remain, oldest first. Other snapshots are never selected.
+pub fn stale_pre_provision_snapshots(snapshots: &[String], keep: usize) -> Vec<String> {
+    let mut tagged: Vec<(u64, &String)> = snapshots
+        .iter()
+        .filter_map(|s| {
+            let secs = s.strip_prefix(PRE_PROVISION_PREFIX)?.parse().ok()?;
+            Some((secs, s))
+        })
+        .collect();
+    tagged.sort();
+    let stale = tagged.len().saturating_sub(keep);
+    tagged
+        .into_iter()
+        .take(stale)
+        .map(|(_, s)| s.clone())
+        .collect()
+}
+
 /// Append provision output to a log file in the given directory.
-pub fn append_provision_log(log_dir: &Path, step: usize, label: &str, stdout: &str, stderr: &str) {
+pub fn append_provision_log(log_dir: &Path, step: &str, label: &str, stdout: &str, stderr: &str) {
     let log_path = log_dir.join("provision.log");
     if let Ok(mut f) = OpenOptions::new().create(true).append(true).open(&log_path) {
         let _ = writeln!(f, "=== Step {step}: {label} ===");
@@ -58,112 +392,461 @@ pub fn append_provision_log(log_dir: &Path, step: usize, label: &str, stdout: &s
 }
 
 fn run_shell(
-    sess: &Session,
+    ctx: &StepContext,
     shell: &ShellProvision,
-    base_dir: &Path,
-    vm_name: &str,
     step: usize,
-    log_dir: Option<&Path>,
-) -> Result<()> {
+    label: &str,
+    vars: &HashMap<String, String>,
+) -> Result<String> {
     if let Some(ref cmd) = shell.inline {
-        info!(vm = %vm_name, step, cmd = %cmd, "running inline shell provision");
+        let cmd = substitute(cmd, vars);
+        info!(vm = %ctx.vm_name, step = %label, cmd = %cmd, "running inline shell provision");
 
+        let exec = format!("{}{cmd}", exports(vars));
         let (stdout, stderr, exit_code) =
-            ssh::exec_streaming(sess, cmd, std::io::stdout(), std::io::stderr()).map_err(|e| {
-                VmError::ProvisionFailed {
-                    vm: vm_name.into(),
-                    step,
+            ctx.transport
+                .exec(&exec, shell.sudo)
+                .map_err(|e| VmError::ProvisionFailed {
+                    vm: ctx.vm_name.into(),
+                    step: label.into(),
                     detail: format!("shell exec: {e}"),
-                }
-            })?;
+                })?;
 
-        if let Some(dir) = log_dir {
-            append_provision_log(dir, step, cmd, &stdout, &stderr);
+        if let Some(dir) = ctx.log_dir {
+            append_provision_log(dir, label, &cmd, &stdout, &stderr);
         }
 
         if exit_code != 0 {
             return Err(VmError::ProvisionFailed {
-                vm: vm_name.into(),
-                step,
+                vm: ctx.vm_name.into(),
+                step: label.into(),
                 detail: format!(
                     "inline command exited with code {exit_code}\nstdout: {stdout}\nstderr: {stderr}"
                 ),
             });
         }
-        info!(vm = %vm_name, step, "inline shell provision completed");
+        info!(vm = %ctx.vm_name, step = %label, "inline shell provision completed");
+        return Ok(stdout);
     } else if let Some(ref script_raw) = shell.script {
-        let local_path = resolve_path(script_raw, base_dir);
-        info!(vm = %vm_name, step, script = %local_path.display(), "running script provision");
+        let local_path = resolve_path(script_raw, ctx.base_dir);
+        info!(vm = %ctx.vm_name, step = %label, script = %local_path.display(), "running script provision");
 
         let remote_path_str = format!("/tmp/vmctl-provision-{step}.sh");
         let remote_path = Path::new(&remote_path_str);
 
         // Upload the script
-        ssh::upload(sess, &local_path, remote_path).map_err(|e| VmError::ProvisionFailed {
-            vm: vm_name.into(),
-            step,
-            detail: format!("upload script: {e}"),
-        })?;
+        ctx.transport
+            .upload(&local_path, remote_path)
+            .map_err(|e| VmError::ProvisionFailed {
+                vm: ctx.vm_name.into(),
+                step: label.into(),
+                detail: format!("upload script: {e}"),
+            })?;
 
         // Make executable and run
-        let run_cmd = format!("chmod +x {remote_path_str} && {remote_path_str}");
+        let run_cmd = format!(
+            "{}chmod +x {remote_path_str} && {remote_path_str}",
+            exports(vars)
+        );
         let (stdout, stderr, exit_code) =
-            ssh::exec_streaming(sess, &run_cmd, std::io::stdout(), std::io::stderr()).map_err(
-                |e| VmError::ProvisionFailed {
-                    vm: vm_name.into(),
-                    step,
+            ctx.transport
+                .exec(&run_cmd, shell.sudo)
+                .map_err(|e| VmError::ProvisionFailed {
+                    vm: ctx.vm_name.into(),
+                    step: label.into(),
                     detail: format!("script exec: {e}"),
-                },
-            )?;
+                })?;
 
-        if let Some(dir) = log_dir {
-            append_provision_log(dir, step, script_raw, &stdout, &stderr);
+        if let Some(dir) = ctx.log_dir {
+            append_provision_log(dir, label, script_raw, &stdout, &stderr);
         }
 
         if exit_code != 0 {
             return Err(VmError::ProvisionFailed {
-                vm: vm_name.into(),
-                step,
+                vm: ctx.vm_name.into(),
+                step: label.into(),
                 detail: format!(
                     "script exited with code {exit_code}\nstdout: {stdout}\nstderr: {stderr}"
                 ),
             });
         }
-        info!(vm = %vm_name, step, "script provision completed");
+        info!(vm = %ctx.vm_name, step = %label, "script provision completed");
+        return Ok(stdout);
     }
-    Ok(())
+    Ok(String::new())
 }
 
-fn run_file(
-    sess: &Session,
-    file: &FileProvision,
-    base_dir: &Path,
-    vm_name: &str,
-    step: usize,
-    log_dir: Option<&Path>,
-) -> Result<()> {
-    let local_path = resolve_path(&file.source, base_dir);
+fn run_file(ctx: &StepContext, file: &FileProvision, label: &str) -> Result<()> {
+    let local_path = resolve_path(&file.source, ctx.base_dir);
     let remote_path = Path::new(&file.destination);
 
     info!(
-        vm = %vm_name,
-        step,
+        vm = %ctx.vm_name,
+        step = %label,
         source = %local_path.display(),
         destination = %file.destination,
         "running file provision"
     );
 
-    ssh::upload(sess, &local_path, remote_path).map_err(|e| VmError::ProvisionFailed {
-        vm: vm_name.into(),
-        step,
-        detail: format!("file upload: {e}"),
-    })?;
+    ctx.transport
+        .upload(&local_path, remote_path)
+        .map_err(|e| VmError::ProvisionFailed {
+            vm: ctx.vm_name.into(),
+            step: label.into(),
+            detail: format!("file upload: {e}"),
+        })?;
 
     let msg = format!("{} -> {}", local_path.display(), file.destination);
-    if let Some(dir) = log_dir {
-        append_provision_log(dir, step, "file-upload", &msg, "");
+    if let Some(dir) = ctx.log_dir {
+        append_provision_log(dir, label, "file-upload", &msg, "");
+    }
+
+    info!(vm = %ctx.vm_name, step = %label, "file provision completed");
+    Ok(())
+}
+
+/// Have the guest fetch the file itself, so it crosses the network once instead of being
+/// downloaded to the host and uploaded again.
+fn run_download(ctx: &StepContext, download: &DownloadProvision, label: &str) -> Result<()> {
+    info!(
+        vm = %ctx.vm_name,
+        step = %label,
+        url = %download.url,
+        destination = %download.destination,
+        "running download provision"
+    );
+
+    let cmd = download_command(download);
+    let (stdout, stderr, exit_code) =
+        ctx.transport
+            .exec(&cmd, false)
+            .map_err(|e| VmError::ProvisionFailed {
+                vm: ctx.vm_name.into(),
+                step: label.into(),
+                detail: format!("download exec: {e}"),
+            })?;
+
+    if let Some(dir) = ctx.log_dir {
+        append_provision_log(dir, label, &download.url, &stdout, &stderr);
     }
 
-    info!(vm = %vm_name, step, "file provision completed");
+    if exit_code != 0 {
+        return Err(VmError::ProvisionFailed {
+            vm: ctx.vm_name.into(),
+            step: label.into(),
+            detail: format!(
+                "download of {} exited with code {exit_code}\nstdout: {stdout}\nstderr: {stderr}",
+                download.url
+            ),
+        });
+    }
+    info!(vm = %ctx.vm_name, step = %label, "download provision completed");
     Ok(())
 }
+
+/// The guest command for `download`: `curl`, or `wget` where there is no curl, then
+/// `sha256sum -c` if it has a checksum. A file that fails the check is removed.
+fn download_command(download: &DownloadProvision) -> String {
+    let url = shell_quote(&download.url);
+    let dest = shell_quote(&download.destination);
+    let mut cmd = format!(
+        "if command -v curl >/dev/null 2>&1; then curl -fsSL -o {dest} {url}; \
+         else wget -q -O {dest} {url}; fi"
+    );
+    if let Some(hash) = download
+        .checksum
+        .as_deref()
+        .and_then(|c| c.strip_prefix("sha256:"))
+    {
+        let line = shell_quote(&format!(
+            "{}  {}",
+            hash.to_lowercase(),
+            download.destination
+        ));
+        cmd.push_str(&format!(
+            " && {{ echo {line} | sha256sum -c - || {{ rm -f {dest}; exit 1; }}; }}"
+        ));
+    }
+    cmd
+}
+
+/// Guest directory a Puppet step's manifest and modules are uploaded to.
+const PUPPET_DIR: &str = "/tmp/vmctl-puppet";
+
+/// Upload the manifest and module directories to [`PUPPET_DIR`], then run `puppet apply` as root.
+fn run_puppet(ctx: &StepContext, puppet: &PuppetProvision, label: &str) -> Result<()> {
+    let failed = |detail: String| VmError::ProvisionFailed {
+        vm: ctx.vm_name.into(),
+        step: label.into(),
+        detail,
+    };
+    let manifest = resolve_path(&puppet.manifest, ctx.base_dir);
+    info!(vm = %ctx.vm_name, step = %label, manifest = %manifest.display(), "running puppet provision");
+
+    // Start from an empty directory, so modules uploaded by an earlier step don't linger
+    let (_, stderr, exit_code) = ctx
+        .transport
+        .exec(
+            &format!("rm -rf {PUPPET_DIR} && mkdir -p {PUPPET_DIR}"),
+            false,
+        )
+        .map_err(|e| failed(format!("create {PUPPET_DIR}: {e}")))?;
+    if exit_code != 0 {
+        return Err(failed(format!("create {PUPPET_DIR}: {}", stderr.trim())));
+    }
+
+    let file_name = manifest
+        .file_name()
+        .ok_or_else(|| failed(format!("invalid manifest path: {}", manifest.display())))?;
+    let remote_manifest = Path::new(PUPPET_DIR).join(file_name);
+    ctx.transport
+        .upload(&manifest, &remote_manifest)
+        .map_err(|e| failed(format!("upload manifest: {e}")))?;
+
+    let mut module_dirs = Vec::new();
+    for (i, raw) in puppet.module_paths.iter().enumerate() {
+        let local = resolve_path(raw, ctx.base_dir);
+        if !local.is_dir() {
+            return Err(failed(format!(
+                "module path {} is not a directory",
+                local.display()
+            )));
+        }
+        let remote = format!("{PUPPET_DIR}/modules-{}", i + 1);
+        ctx.transport
+            .upload_dir(&local, Path::new(&remote))
+            .map_err(|e| failed(format!("upload modules from {}: {e}", local.display())))?;
+        module_dirs.push(remote);
+    }
+
+    let cmd = puppet_command(
+        &remote_manifest.to_string_lossy(),
+        &module_dirs,
+        &puppet.facts,
+    );
+    let (stdout, stderr, exit_code) = ctx
+        .transport
+        .exec(&cmd, true)
+        .map_err(|e| failed(format!("puppet exec: {e}")))?;
+
+    if let Some(dir) = ctx.log_dir {
+        append_provision_log(dir, label, &puppet.manifest, &stdout, &stderr);
+    }
+
+    if !puppet_succeeded(exit_code) {
+        return Err(failed(format!(
+            "puppet apply exited with code {exit_code}\nstdout: {stdout}\nstderr: {stderr}"
+        )));
+    }
+    info!(vm = %ctx.vm_name, step = %label, "puppet provision completed");
+    Ok(())
+}
+
+/// Whether a `puppet apply --detailed-exitcodes` run succeeded: 0 means nothing changed and 2
+/// means changes were applied. 4 and 6 report failed resources.
+fn puppet_succeeded(exit_code: i32) -> bool {
+    matches!(exit_code, 0 | 2)
+}
+
+/// The `puppet apply` command line for `manifest` in the guest, with `facts` set in the
+/// environment as `FACTER_<name>`.
+fn puppet_command(
+    manifest: &str,
+    module_dirs: &[String],
+    facts: &HashMap<String, String>,
+) -> String {
+    let mut names: Vec<&String> = facts.keys().collect();
+    names.sort();
+    let mut cmd: String = names
+        .into_iter()
+        .map(|name| format!("FACTER_{name}={} ", shell_quote(&facts[name])))
+        .collect();
+    // puppet-agent packages install the binary outside sudo's secure_path
+    cmd.push_str("PATH=\"$PATH:/opt/puppetlabs/bin\" puppet apply --detailed-exitcodes ");
+    cmd.push_str(&shell_quote(manifest));
+    if !module_dirs.is_empty() {
+        cmd.push_str(" --modulepath ");
+        cmd.push_str(&shell_quote(&module_dirs.join(":")));
+    }
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(name: Option<&str>) -> ProvisionDef {
+        ProvisionDef::Shell(ShellProvision {
+            name: name.map(String::from),
+            inline: Some("true".into()),
+            script: None,
+            capture: None,
+            sudo: false,
+        })
+    }
+
+    fn selected(filter: &ProvisionFilter, provisions: &[ProvisionDef]) -> Vec<usize> {
+        (1..=provisions.len())
+            .filter(|&step| filter.includes(step, &provisions[step - 1]))
+            .collect()
+    }
+
+    #[test]
+    fn filter_by_name_and_number() {
+        let provisions = [shell(Some("deps")), shell(None), shell(Some("app"))];
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let all = ProvisionFilter::default();
+        assert_eq!(selected(&all, &provisions), vec![1, 2, 3]);
+
+        let only = ProvisionFilter {
+            only: strings(&["app", "2"]),
+            skip: vec![],
+        };
+        assert_eq!(selected(&only, &provisions), vec![2, 3]);
+
+        let skip = ProvisionFilter {
+            only: vec![],
+            skip: strings(&["deps"]),
+        };
+        assert_eq!(selected(&skip, &provisions), vec![2, 3]);
+
+        let unknown = ProvisionFilter {
+            only: strings(&["deps", "missing"]),
+            skip: strings(&["4"]),
+        };
+        assert_eq!(unknown.unknown(&provisions), vec!["missing", "4"]);
+    }
+
+    #[test]
+    fn captured_variables() {
+        let vars = HashMap::from([
+            ("url".to_string(), "http://10.0.0.5:8080".to_string()),
+            ("quote".to_string(), "it's".to_string()),
+        ]);
+        assert_eq!(
+            substitute("curl ${url}/health && echo ${HOME} ${url", &vars),
+            "curl http://10.0.0.5:8080/health && echo ${HOME} ${url"
+        );
+        assert_eq!(
+            exports(&vars),
+            "export quote='it'\\''s'; export url='http://10.0.0.5:8080'; "
+        );
+    }
+
+    #[test]
+    fn loop_item_substitution() {
+        let shell = ProvisionDef::Shell(ShellProvision {
+            name: None,
+            inline: Some("apt-get install -y ${item} ${version}".into()),
+            script: None,
+            capture: None,
+            sudo: false,
+        });
+        assert!(matches!(
+            with_item(&shell, "nginx"),
+            ProvisionDef::Shell(s) if s.inline.as_deref() == Some("apt-get install -y nginx ${version}")
+        ));
+
+        let file = ProvisionDef::File(FileProvision {
+            name: None,
+            source: "./${item}.conf".into(),
+            destination: "/etc/${item}/${item}.conf".into(),
+        });
+        assert!(matches!(
+            with_item(&file, "redis"),
+            ProvisionDef::File(f) if f.source == "./${item}.conf" && f.destination == "/etc/redis/redis.conf"
+        ));
+    }
+
+    #[test]
+    fn download_command_line() {
+        let mut download = DownloadProvision {
+            name: None,
+            url: "https://example.com/go.tar.gz".into(),
+            destination: "/tmp/go.tar.gz".into(),
+            checksum: None,
+        };
+        assert_eq!(
+            download_command(&download),
+            "if command -v curl >/dev/null 2>&1; then \
+             curl -fsSL -o /tmp/go.tar.gz https://example.com/go.tar.gz; \
+             else wget -q -O /tmp/go.tar.gz https://example.com/go.tar.gz; fi"
+        );
+
+        let hash = "AB".repeat(32);
+        download.checksum = Some(format!("sha256:{hash}"));
+        let cmd = download_command(&download);
+        assert!(
+            cmd.ends_with(&format!(
+                " && {{ echo '{}  /tmp/go.tar.gz' | sha256sum -c - || {{ rm -f /tmp/go.tar.gz; exit 1; }}; }}",
+                hash.to_lowercase()
+            )),
+            "{cmd}"
+        );
+
+        let looped = ProvisionDef::Download(DownloadProvision {
+            url: "https://example.com/${item}.tar.gz".into(),
+            destination: "/tmp/${item}.tar.gz".into(),
+            ..download
+        });
+        assert!(matches!(
+            with_item(&looped, "go"),
+            ProvisionDef::Download(d) if d.url == "https://example.com/go.tar.gz" && d.destination == "/tmp/go.tar.gz"
+        ));
+    }
+
+    #[test]
+    fn puppet_command_line() {
+        let facts = HashMap::from([
+            ("role".to_string(), "web".to_string()),
+            ("motd".to_string(), "it's up".to_string()),
+        ]);
+        assert_eq!(
+            puppet_command(
+                "/tmp/vmctl-puppet/site.pp",
+                &[
+                    "/tmp/vmctl-puppet/modules-1".into(),
+                    "/tmp/vmctl-puppet/modules-2".into()
+                ],
+                &facts
+            ),
+            "FACTER_motd='it'\\''s up' FACTER_role=web PATH=\"$PATH:/opt/puppetlabs/bin\" \
+             puppet apply --detailed-exitcodes /tmp/vmctl-puppet/site.pp \
+             --modulepath /tmp/vmctl-puppet/modules-1:/tmp/vmctl-puppet/modules-2"
+        );
+        assert_eq!(
+            puppet_command("/tmp/vmctl-puppet/site.pp", &[], &HashMap::new()),
+            "PATH=\"$PATH:/opt/puppetlabs/bin\" puppet apply --detailed-exitcodes \
+             /tmp/vmctl-puppet/site.pp"
+        );
+
+        assert!(puppet_succeeded(0));
+        assert!(puppet_succeeded(2));
+        for failed in [1, 4, 6] {
+            assert!(!puppet_succeeded(failed));
+        }
+    }
+
+    #[test]
+    fn pre_provision_snapshots_to_prune() {
+        let at = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        assert_eq!(pre_provision_tag(at), "pre-provision-1700000000");
+
+        let snapshots: Vec<String> = [
+            "pre-provision-1700000300",
+            "before-upgrade",
+            "pre-provision-1700000100",
+            "pre-provision-latest",
+            "pre-provision-1700000200",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(
+            stale_pre_provision_snapshots(&snapshots, 1),
+            ["pre-provision-1700000100", "pre-provision-1700000200"]
+        );
+        assert!(stale_pre_provision_snapshots(&snapshots, 3).is_empty());
+        assert_eq!(stale_pre_provision_snapshots(&snapshots, 0).len(), 3);
+    }
+}
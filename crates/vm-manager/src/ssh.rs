@@ -1,19 +1,35 @@
-use std::io::Read;
+use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use ssh2::Session;
+pub use ssh2::Session;
+use tokio_util::sync::CancellationToken;
 use tracing::warn;
 
 use crate::error::{Result, VmError};
-use crate::types::SshConfig;
+use crate::types::{SshConfig, shell_quote};
+
+/// `host` with square brackets if it is an IPv6 literal, as `host:port` strings and `ssh -L`
+/// specs require.
+pub fn bracket_host(host: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    }
+}
+
+/// `host:port`, bracketing IPv6 literals (`[fd00::5]:22`).
+pub fn host_port(host: &str, port: u16) -> String {
+    format!("{}:{port}", bracket_host(host))
+}
 
 /// Establish an SSH session to the given IP and port using the provided config.
 ///
 /// Tries in-memory key first, then key file path.
 pub fn connect(ip: &str, port: u16, config: &SshConfig) -> Result<Session> {
-    let addr = format!("{ip}:{port}");
+    let addr = host_port(ip, port);
     let tcp = TcpStream::connect(&addr).map_err(|e| VmError::SshFailed {
         detail: format!("TCP connect to {addr}: {e}"),
     })?;
@@ -60,6 +76,46 @@ pub fn connect(ip: &str, port: u16, config: &SshConfig) -> Result<Session> {
 ///
 /// Returns `(stdout, stderr, exit_code)`.
 pub fn exec(sess: &Session, cmd: &str) -> Result<(String, String, i32)> {
+    exec_with_input(sess, cmd, None)
+}
+
+/// Execute `cmd` as root through `sudo`, in `sh -c` so that pipes, redirections and `&&` run
+/// as root too.
+///
+/// With a `password`, it is fed to `sudo -S` on stdin. Without one, `sudo -n` fails instead of
+/// prompting, so the user needs `NOPASSWD` sudo rights, as cloud images usually grant.
+///
+/// Returns `(stdout, stderr, exit_code)`.
+pub fn exec_sudo(
+    sess: &Session,
+    cmd: &str,
+    password: Option<&str>,
+) -> Result<(String, String, i32)> {
+    let input = password.map(|p| format!("{p}\n"));
+    exec_with_input(
+        sess,
+        &sudo_command(cmd, password.is_some()),
+        input.as_deref().map(str::as_bytes),
+    )
+}
+
+/// `cmd` wrapped to run as root: `sudo -S -p '' sh -c <cmd>` to read a password from stdin,
+/// or `sudo -n sh -c <cmd>` to rely on `NOPASSWD`, with `<cmd>` quoted for the login shell.
+pub(crate) fn sudo_command(cmd: &str, with_password: bool) -> String {
+    let quoted = shell_quote(cmd);
+    if with_password {
+        format!("sudo -S -p '' sh -c {quoted}")
+    } else {
+        format!("sudo -n sh -c {quoted}")
+    }
+}
+
+/// Execute `cmd`, writing `input` to its stdin before closing it.
+fn exec_with_input(
+    sess: &Session,
+    cmd: &str,
+    input: Option<&[u8]>,
+) -> Result<(String, String, i32)> {
     let mut channel = sess.channel_session().map_err(|e| VmError::SshFailed {
         detail: format!("channel session: {e}"),
     })?;
@@ -68,6 +124,18 @@ pub fn exec(sess: &Session, cmd: &str) -> Result<(String, String, i32)> {
         detail: format!("exec '{cmd}': {e}"),
     })?;
 
+    if let Some(input) = input {
+        channel
+            .write_all(input)
+            .and_then(|()| channel.flush())
+            .map_err(|e| VmError::SshFailed {
+                detail: format!("write stdin: {e}"),
+            })?;
+    }
+    channel.send_eof().map_err(|e| VmError::SshFailed {
+        detail: format!("close stdin: {e}"),
+    })?;
+
     let mut stdout = String::new();
     channel
         .read_to_string(&mut stdout)
@@ -218,8 +286,8 @@ pub fn upload(sess: &Session, local: &Path, remote: &Path) -> Result<()> {
     Ok(())
 }
 
-/// Download a remote file to a local path via SFTP.
-pub fn download(sess: &Session, remote: &Path, local: &Path) -> Result<()> {
+/// Read a remote file via SFTP.
+pub fn read_file(sess: &Session, remote: &Path) -> Result<Vec<u8>> {
     let sftp = sess.sftp().map_err(|e| VmError::SshFailed {
         detail: format!("SFTP init: {e}"),
     })?;
@@ -234,6 +302,12 @@ pub fn download(sess: &Session, remote: &Path, local: &Path) -> Result<()> {
         .map_err(|e| VmError::SshFailed {
             detail: format!("SFTP read {}: {e}", remote.display()),
         })?;
+    Ok(buf)
+}
+
+/// Download a remote file to a local path via SFTP.
+pub fn download(sess: &Session, remote: &Path, local: &Path) -> Result<()> {
+    let buf = read_file(sess, remote)?;
 
     if let Some(parent) = local.parent() {
         std::fs::create_dir_all(parent).map_err(|e| VmError::SshFailed {
@@ -248,15 +322,155 @@ pub fn download(sess: &Session, remote: &Path, local: &Path) -> Result<()> {
     Ok(())
 }
 
+/// List the entries of a remote directory via SFTP.
+///
+/// Returns full remote paths with their attributes; `.` and `..` are omitted.
+pub fn list_dir(sess: &Session, remote: &Path) -> Result<Vec<(PathBuf, ssh2::FileStat)>> {
+    let sftp = sess.sftp().map_err(|e| VmError::SshFailed {
+        detail: format!("SFTP init: {e}"),
+    })?;
+
+    let entries = sftp.readdir(remote).map_err(|e| VmError::SshFailed {
+        detail: format!("SFTP readdir {}: {e}", remote.display()),
+    })?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|(p, _)| {
+            !matches!(
+                p.file_name().and_then(|n| n.to_str()),
+                Some(".") | Some("..")
+            )
+        })
+        .collect())
+}
+
+/// Return whether `remote` exists and is a directory.
+pub fn is_remote_dir(sess: &Session, remote: &Path) -> Result<bool> {
+    let sftp = sess.sftp().map_err(|e| VmError::SshFailed {
+        detail: format!("SFTP init: {e}"),
+    })?;
+    Ok(sftp.stat(remote).map(|st| st.is_dir()).unwrap_or(false))
+}
+
+/// Expand a remote path whose final component may contain `*` or `?` wildcards.
+///
+/// Paths without wildcards are returned unchanged (even if they do not exist). Matches are
+/// sorted; hidden files are only matched when the pattern itself starts with a dot.
+pub fn glob(sess: &Session, pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+    let file_pattern = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) if name.contains(['*', '?']) => name,
+        _ => return Ok(vec![path.to_path_buf()]),
+    };
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let mut matches: Vec<PathBuf> = list_dir(sess, parent)?
+        .into_iter()
+        .map(|(p, _)| p)
+        .filter(|p| {
+            p.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+                (!n.starts_with('.') || file_pattern.starts_with('.'))
+                    && glob_match(file_pattern, n)
+            })
+        })
+        .collect();
+    matches.sort();
+    Ok(matches)
+}
+
+/// Match `name` against a shell-style pattern supporting `*` and `?`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    // Position of the last `*` in the pattern and the name index it was tried at.
+    let mut star: Option<(usize, usize)> = None;
+
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Recursively upload a local directory to `remote`, creating directories as needed.
+pub fn upload_dir(sess: &Session, local: &Path, remote: &Path) -> Result<()> {
+    let sftp = sess.sftp().map_err(|e| VmError::SshFailed {
+        detail: format!("SFTP init: {e}"),
+    })?;
+    if sftp.stat(remote).is_err() {
+        sftp.mkdir(remote, 0o755).map_err(|e| VmError::SshFailed {
+            detail: format!("SFTP mkdir {}: {e}", remote.display()),
+        })?;
+    }
+
+    let entries = std::fs::read_dir(local).map_err(|e| VmError::SshFailed {
+        detail: format!("read local dir {}: {e}", local.display()),
+    })?;
+    for entry in entries {
+        let entry = entry.map_err(|e| VmError::SshFailed {
+            detail: format!("read local dir {}: {e}", local.display()),
+        })?;
+        let src = entry.path();
+        let dst = remote.join(entry.file_name());
+        if src.is_dir() {
+            upload_dir(sess, &src, &dst)?;
+        } else {
+            upload(sess, &src, &dst)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively download a remote directory to `local`.
+pub fn download_dir(sess: &Session, remote: &Path, local: &Path) -> Result<()> {
+    std::fs::create_dir_all(local).map_err(|e| VmError::SshFailed {
+        detail: format!("create local dir {}: {e}", local.display()),
+    })?;
+
+    for (src, stat) in list_dir(sess, remote)? {
+        let Some(file_name) = src.file_name() else {
+            continue;
+        };
+        let dst = local.join(file_name);
+        if stat.is_dir() {
+            download_dir(sess, &src, &dst)?;
+        } else {
+            download(sess, &src, &dst)?;
+        }
+    }
+    Ok(())
+}
+
 /// Connect with exponential backoff retry.
 ///
 /// Retries the connection until `timeout` elapses, with exponential backoff capped at 5 seconds.
+/// Gives up with `Cancelled` as soon as `cancel` fires, without waiting for an attempt in flight.
 pub async fn connect_with_retry(
     ip: &str,
     port: u16,
     config: &SshConfig,
     timeout: Duration,
+    cancel: &CancellationToken,
 ) -> Result<Session> {
+    let cancelled = || VmError::Cancelled {
+        operation: format!("SSH connection to {}", host_port(ip, port)),
+    };
     let deadline = tokio::time::Instant::now() + timeout;
     let mut backoff = Duration::from_secs(1);
     let mut attempt: u32 = 0;
@@ -267,8 +481,12 @@ pub async fn connect_with_retry(
         let config_clone = config.clone();
 
         // Run the blocking SSH connect on a blocking thread
-        let result =
-            tokio::task::spawn_blocking(move || connect(&ip_owned, port, &config_clone)).await;
+        let connecting =
+            tokio::task::spawn_blocking(move || connect(&ip_owned, port, &config_clone));
+        let result = tokio::select! {
+            result = connecting => result,
+            _ = cancel.cancelled() => return Err(cancelled()),
+        };
 
         match result {
             Ok(Ok(sess)) => return Ok(sess),
@@ -294,7 +512,100 @@ pub async fn connect_with_retry(
 
         let remaining = deadline.duration_since(tokio::time::Instant::now());
         let sleep_dur = backoff.min(remaining);
-        tokio::time::sleep(sleep_dur).await;
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_dur) => {}
+            _ = cancel.cancelled() => return Err(cancelled()),
+        }
         backoff = backoff.saturating_mul(2).min(Duration::from_secs(5));
     }
 }
+
+/// Generate an Ed25519 SSH keypair for VM `vm_name` and return
+/// `(public_key_openssh, private_key_pem)`.
+pub fn generate_keypair(vm_name: &str) -> Result<(String, String)> {
+    use ssh_key::{Algorithm, LineEnding, PrivateKey, rand_core::OsRng};
+
+    let sk = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).map_err(|e| {
+        VmError::SshKeygenFailed {
+            detail: format!("Ed25519 key generation for VM '{vm_name}': {e}"),
+        }
+    })?;
+
+    let pub_openssh = sk
+        .public_key()
+        .to_openssh()
+        .map_err(|e| VmError::SshKeygenFailed {
+            detail: format!("serialize public key: {e}"),
+        })?;
+
+    let priv_pem = sk
+        .to_openssh(LineEnding::LF)
+        .map_err(|e| VmError::SshKeygenFailed {
+            detail: format!("serialize private key: {e}"),
+        })?;
+
+    Ok((pub_openssh, priv_pem.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sudo_command_quotes_for_sh() {
+        assert_eq!(
+            sudo_command("apt-get update && echo 'done'", false),
+            r#"sudo -n sh -c 'apt-get update && echo '\''done'\'''"#
+        );
+        assert_eq!(sudo_command("id -u", true), "sudo -S -p '' sh -c 'id -u'");
+        assert_eq!(sudo_command("reboot", false), "sudo -n sh -c reboot");
+
+        // What reaches `sh -c` is the command unchanged
+        let cmd = r#"printf '%s\n' "$HOME" 'a'\''b'"#;
+        let wrapped = sudo_command(cmd, false);
+        let script = wrapped.strip_prefix("sudo -n sh -c ").unwrap();
+        let out = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf '%s' {script}"))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(out.stdout).unwrap(), cmd);
+    }
+
+    #[test]
+    fn glob_match_patterns() {
+        assert!(glob_match("*.conf", "nginx.conf"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("*.conf", "nginx.conf.bak"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn host_port_brackets_ipv6() {
+        assert_eq!(host_port("192.168.122.45", 22), "192.168.122.45:22");
+        assert_eq!(host_port("fd00::45", 22), "[fd00::45]:22");
+        assert_eq!(host_port("[fd00::45]", 22), "[fd00::45]:22");
+        assert_eq!(host_port("web.local", 2222), "web.local:2222");
+    }
+
+    #[tokio::test]
+    async fn connect_with_retry_stops_when_cancelled() {
+        let config = SshConfig {
+            user: "vm".into(),
+            public_key: None,
+            private_key_path: None,
+            private_key_pem: None,
+        };
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let started = std::time::Instant::now();
+        let result =
+            connect_with_retry("127.0.0.1", 1, &config, Duration::from_secs(60), &cancel).await;
+        assert!(matches!(result, Err(VmError::Cancelled { .. })));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,224 @@
+//! Public SSH keys published by code hosts, for `vmctl create --import-ssh-key`.
+//!
+//! GitHub and GitLab serve every account's public keys as an `authorized_keys` file at
+//! `https://github.com/<user>.keys` and `https://gitlab.com/<user>.keys`. Fetched keys are kept
+//! in `{XDG_CACHE_HOME}/vmctl/ssh-keys` for [`CACHE_TTL`], so creating several VMs for the same
+//! person asks the code host once.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::error::{Result, VmError};
+
+/// How long fetched keys are used before they are fetched again.
+pub const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A code host account whose public SSH keys to import, written `github:<user>` or
+/// `gitlab:<user>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum KeySource {
+    GitHub(String),
+    GitLab(String),
+}
+
+impl KeySource {
+    /// Where the code host publishes the account's keys.
+    pub fn url(&self) -> String {
+        match self {
+            Self::GitHub(user) => format!("https://github.com/{user}.keys"),
+            Self::GitLab(user) => format!("https://gitlab.com/{user}.keys"),
+        }
+    }
+
+    /// Name of the account's file in the cache.
+    fn cache_file(&self) -> String {
+        self.to_string().replace(':', "-") + ".keys"
+    }
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GitHub(user) => write!(f, "github:{user}"),
+            Self::GitLab(user) => write!(f, "gitlab:{user}"),
+        }
+    }
+}
+
+impl std::str::FromStr for KeySource {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (host, user) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected github:<user> or gitlab:<user>, got {s}"))?;
+        // Both hosts allow letters, digits, `-`, `_` and `.` in user names; anything else would
+        // change the URL
+        let valid = !user.is_empty()
+            && !user.starts_with(['.', '-'])
+            && user
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(format!("invalid user name: {user}"));
+        }
+        match host {
+            "github" => Ok(Self::GitHub(user.into())),
+            "gitlab" => Ok(Self::GitLab(user.into())),
+            other => Err(format!(
+                "unknown code host: {other} (expected github or gitlab)"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for KeySource {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<KeySource> for String {
+    fn from(source: KeySource) -> Self {
+        source.to_string()
+    }
+}
+
+/// Directory fetched keys are cached in: `{XDG_CACHE_HOME}/vmctl/ssh-keys`.
+pub fn cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("vmctl")
+        .join("ssh-keys")
+}
+
+/// The public keys of `source`, from the cache if they were fetched within [`CACHE_TTL`].
+/// Fails if the account doesn't exist or has no keys.
+pub async fn import(source: &KeySource) -> Result<Vec<String>> {
+    import_from(source, &source.url(), &cache_dir()).await
+}
+
+/// [`import`] from `url`, with the cache in `cache`.
+async fn import_from(source: &KeySource, url: &str, cache: &Path) -> Result<Vec<String>> {
+    let path = cache.join(source.cache_file());
+    let fresh = tokio::fs::metadata(&path)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < CACHE_TTL);
+    if fresh {
+        if let Ok(text) = tokio::fs::read_to_string(&path).await {
+            let keys = parse_keys(&text);
+            if !keys.is_empty() {
+                debug!(%source, "SSH keys: using cached keys");
+                return Ok(keys);
+            }
+        }
+    }
+
+    let failed = |detail: String| VmError::SshKeyImportFailed {
+        account: source.to_string(),
+        url: url.into(),
+        detail,
+    };
+    let res = reqwest::get(url).await.map_err(|e| failed(e.to_string()))?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(failed("no such account".into()));
+    }
+    if !res.status().is_success() {
+        return Err(failed(format!("HTTP {}", res.status())));
+    }
+    let text = res.text().await.map_err(|e| failed(e.to_string()))?;
+    let keys = parse_keys(&text);
+    if keys.is_empty() {
+        return Err(failed("the account has no public SSH keys".into()));
+    }
+    debug!(%source, keys = keys.len(), "SSH keys: fetched");
+
+    // Without the cache the keys are only fetched again next time
+    let written = async {
+        tokio::fs::create_dir_all(cache).await?;
+        tokio::fs::write(&path, keys.join("\n") + "\n").await
+    };
+    if let Err(e) = written.await {
+        debug!(path = %path.display(), error = %e, "SSH keys: cannot cache keys");
+    }
+    Ok(keys)
+}
+
+/// The keys in an `authorized_keys` file, one per line, without blank lines and comments.
+fn parse_keys(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_http::serve;
+
+    #[test]
+    fn parse_key_sources() {
+        let source: KeySource = "github:alice".parse().unwrap();
+        assert_eq!(source, KeySource::GitHub("alice".into()));
+        assert_eq!(source.url(), "https://github.com/alice.keys");
+        assert_eq!(source.cache_file(), "github-alice.keys");
+        let source: KeySource = "gitlab:bob.smith".parse().unwrap();
+        assert_eq!(source.url(), "https://gitlab.com/bob.smith.keys");
+        assert_eq!(source.to_string(), "gitlab:bob.smith");
+
+        for (text, expected) in [
+            ("alice", "expected github:<user>"),
+            ("github:", "invalid user name"),
+            ("github:../etc", "invalid user name"),
+            ("github:alice/keys", "invalid user name"),
+            ("launchpad:alice", "unknown code host: launchpad"),
+        ] {
+            let err = text.parse::<KeySource>().unwrap_err();
+            assert!(err.contains(expected), "{text}: got {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn import_fetches_and_caches_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = KeySource::GitHub("alice".into());
+        let body = b"ssh-ed25519 AAAA alice@laptop\n\nssh-rsa BBBB\n".to_vec();
+        let (url, _) = serve(vec![("200 OK", vec![], body)], "alice.keys").await;
+        let keys = import_from(&source, &url, dir.path()).await.unwrap();
+        assert_eq!(keys, ["ssh-ed25519 AAAA alice@laptop", "ssh-rsa BBBB"]);
+
+        // The server only answers once, so these come from the cache
+        assert_eq!(import_from(&source, &url, dir.path()).await.unwrap(), keys);
+        assert!(dir.path().join("github-alice.keys").is_file());
+    }
+
+    #[tokio::test]
+    async fn import_fails_without_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = KeySource::GitLab("nobody".into());
+        for (status, body, expected) in [
+            ("404 Not Found", "", "no such account"),
+            ("200 OK", "\n", "has no public SSH keys"),
+        ] {
+            let (url, _) = serve(vec![(status, vec![], body.into())], "nobody.keys").await;
+            let msg = import_from(&source, &url, dir.path())
+                .await
+                .unwrap_err()
+                .to_string();
+            assert!(msg.contains(expected), "got {msg}");
+        }
+        assert!(!dir.path().join("gitlab-nobody.keys").exists());
+    }
+}
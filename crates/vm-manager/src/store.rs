@@ -0,0 +1,242 @@
+//! Persistent VM store: maps VM name -> [`VmHandle`] in a JSON file per namespace.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use crate::config::Config;
+use crate::error::{Result, VmError};
+use crate::types::VmHandle;
+
+/// Name of the store file in each namespace directory.
+pub const STORE_FILE: &str = "vms.json";
+
+/// The VMs of one namespace, by name.
+pub type Store = HashMap<String, VmHandle>;
+
+/// Serialises the store's read-modify-write cycles between this process's concurrent tasks.
+static STORE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// A store file on disk.
+#[derive(Debug, Clone)]
+pub struct VmStore {
+    path: PathBuf,
+    /// Store from before namespaces, moved to `path` on first use.
+    legacy: Option<PathBuf>,
+}
+
+impl VmStore {
+    /// The store at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            legacy: None,
+        }
+    }
+
+    /// The store of the configured namespace: `<data-dir>/namespaces/<namespace>/vms.json`.
+    ///
+    /// A store from before namespaces (`<data-dir>/vms.json`) is moved into the namespace the
+    /// first time it is loaded, so the first user keeps their VMs. Their work directories stay
+    /// where they are.
+    pub fn for_config(config: &Config) -> Self {
+        Self {
+            path: config.namespace_dir().join(STORE_FILE),
+            legacy: Some(config.data_dir.value.join(STORE_FILE)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    async fn adopt_legacy(&self) -> Result<()> {
+        let Some(ref legacy) = self.legacy else {
+            return Ok(());
+        };
+        if self.path.exists() || !legacy.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(legacy, &self.path).await?;
+        info!(
+            from = %legacy.display(),
+            to = %self.path.display(),
+            "moved VM store into namespace"
+        );
+        Ok(())
+    }
+
+    /// Load the store from disk. Returns an empty map if the file doesn't exist.
+    pub async fn load(&self) -> Result<Store> {
+        self.adopt_legacy().await?;
+        read(&self.path).await
+    }
+
+    /// Save the store to disk atomically (write to .tmp then rename).
+    pub async fn save(&self, store: &Store) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let data = serde_json::to_string_pretty(store).map_err(|e| VmError::StoreCorrupt {
+            path: self.path.clone(),
+            detail: e.to_string(),
+        })?;
+        let tmp_path = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+
+    /// Load the store, apply `update` and save it again, holding the store lock throughout so
+    /// that VMs started concurrently don't overwrite each other's entries.
+    pub async fn update<T>(&self, update: impl FnOnce(&mut Store) -> T) -> Result<T> {
+        let _guard = STORE_LOCK.lock().await;
+        let mut store = self.load().await?;
+        let result = update(&mut store);
+        self.save(&store).await?;
+        Ok(result)
+    }
+
+    /// Record `handle` under its VM's name.
+    pub async fn save_handle(&self, handle: VmHandle) -> Result<()> {
+        self.update(|store| {
+            store.insert(handle.name.clone(), handle);
+        })
+        .await
+    }
+
+    /// Remove the VM called `name`, returning its handle if it was recorded.
+    pub async fn remove(&self, name: &str) -> Result<Option<VmHandle>> {
+        self.update(|store| store.remove(name)).await
+    }
+
+    /// Load the store of every namespace in the data directory, sorted by namespace. Namespaces
+    /// without a store are skipped.
+    pub async fn load_all(config: &Config) -> Result<Vec<(String, Store)>> {
+        Self::for_config(config).adopt_legacy().await?;
+        let mut stores = Vec::new();
+        let mut entries = match tokio::fs::read_dir(config.namespaces_dir()).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(stores),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path().join(STORE_FILE);
+            if path.exists() {
+                let namespace = entry.file_name().to_string_lossy().into_owned();
+                stores.push((namespace, read(&path).await?));
+            }
+        }
+        stores.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(stores)
+    }
+}
+
+async fn read(path: &Path) -> Result<Store> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let data = tokio::fs::read_to_string(path).await?;
+    serde_json::from_str(&data).map_err(|e| VmError::StoreCorrupt {
+        path: path.to_path_buf(),
+        detail: e.to_string(),
+    })
+}
+
+/// MAC addresses of every VM in the store, private network NICs included, for
+/// `VmSpec::existing_macs`.
+pub fn known_macs(store: &Store) -> Vec<String> {
+    store
+        .values()
+        .flat_map(|h| {
+            let private = h.private_networks.iter().filter_map(|n| n.mac.clone());
+            h.mac_addr.clone().into_iter().chain(private)
+        })
+        .collect()
+}
+
+/// Name of the VM, other than `vm`, whose SSH is forwarded from host port `port`.
+pub fn ssh_port_owner<'a>(store: &'a Store, vm: &str, port: u16) -> Option<&'a str> {
+    store
+        .values()
+        .find(|h| h.name != vm && h.remote_host.is_none() && h.ssh_host_port == Some(port))
+        .map(|h| h.name.as_str())
+}
+
+/// Look up a VM by name or by its `<name>.local` hostname.
+pub fn find<'a>(store: &'a Store, name: &str) -> Option<&'a VmHandle> {
+    store.get(name).or_else(|| {
+        store
+            .values()
+            .find(|h| crate::hostnames::hostname_for(&h.name).eq_ignore_ascii_case(name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::noop::NoopBackend;
+    use crate::traits::Hypervisor;
+    use crate::types::VmSpec;
+
+    async fn handle(name: &str) -> VmHandle {
+        let spec = VmSpec::new(name, "/images/base.qcow2");
+        NoopBackend.prepare(&spec).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn missing_store_loads_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = VmStore::new(dir.path().join("vms.json"));
+        assert!(store.load().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn save_handle_and_remove_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = VmStore::new(dir.path().join("ns/vms.json"));
+        store.save_handle(handle("web").await).await.unwrap();
+        store.save_handle(handle("db").await).await.unwrap();
+
+        let loaded = store.load().await.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded["web"].name, "web");
+
+        let removed = store.remove("web").await.unwrap();
+        assert_eq!(removed.map(|h| h.name), Some("web".to_string()));
+        assert!(store.remove("web").await.unwrap().is_none());
+        assert_eq!(store.load().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn corrupt_store_is_reported_with_its_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vms.json");
+        std::fs::write(&path, "{ not json").unwrap();
+        match VmStore::new(&path).load().await {
+            Err(VmError::StoreCorrupt { path: p, .. }) => assert_eq!(p, path),
+            other => panic!("expected StoreCorrupt, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_store_is_moved_into_the_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.data_dir.set(
+            dir.path().to_path_buf(),
+            crate::config::ConfigSource::Default,
+        );
+        let legacy = VmStore::new(dir.path().join(STORE_FILE));
+        legacy.save_handle(handle("old").await).await.unwrap();
+
+        let store = VmStore::for_config(&config);
+        assert!(store.load().await.unwrap().contains_key("old"));
+        assert!(!dir.path().join(STORE_FILE).exists());
+        assert!(store.path().exists());
+    }
+}
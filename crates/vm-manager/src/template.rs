@@ -0,0 +1,262 @@
+//! VM templates: named sets of defaults for `vmctl create --template`.
+//!
+//! A template is a KDL file in `~/.config/vmctl/templates/<name>.kdl`
+//! (`$XDG_CONFIG_HOME/vmctl/templates`) using a subset of the VMFile's `vm` nodes:
+//!
+//! ```kdl
+//! image "https://cloud-images.ubuntu.com/jammy/current/jammy-server-cloudimg-amd64.img"
+//! vcpus 2
+//! memory 2048
+//! user "ubuntu"
+//! provision "shell" {
+//!     inline "sudo apt-get update -q"
+//! }
+//! ```
+//!
+//! Every node is optional. A file in the templates directory takes precedence over a built-in
+//! template of the same name (see [`BUILTIN`]).
+
+use std::path::{Path, PathBuf};
+
+use kdl::KdlDocument;
+
+use crate::error::{Result, VmError};
+use crate::vmfile::{self, ProvisionDef};
+
+/// Templates that ship with vmctl, by name.
+pub const BUILTIN: &[(&str, &str)] = &[
+    (
+        "ubuntu-server",
+        r#"// Ubuntu 22.04 LTS server cloud image
+image "https://cloud-images.ubuntu.com/jammy/current/jammy-server-cloudimg-amd64.img"
+vcpus 2
+memory 2048
+user "ubuntu"
+provision "shell" {
+    name "apt-update"
+    inline "cloud-init status --wait >/dev/null; sudo apt-get update -q"
+}
+"#,
+    ),
+    (
+        "minimal",
+        r#"// The configured default image, as small as it boots
+vcpus 1
+memory 512
+"#,
+    ),
+];
+
+/// Defaults for a new VM, from a template.
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    /// Image path or URL, as written in the template.
+    pub image: Option<String>,
+    pub vcpus: Option<u16>,
+    pub memory_mb: Option<u64>,
+    /// User cloud-init creates for the SSH key, and provisioning logs in as.
+    pub user: Option<String>,
+    /// Steps to run once the VM has started.
+    pub provisions: Vec<ProvisionDef>,
+    /// Directory relative paths in the template are resolved against.
+    pub base_dir: PathBuf,
+}
+
+impl Template {
+    /// Whether `image` is a URL to download rather than a local path.
+    pub fn image_is_url(&self) -> bool {
+        self.image.as_ref().is_some_and(|i| i.contains("://"))
+    }
+}
+
+/// Directory user templates are read from; `None` without a home directory.
+pub fn templates_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("vmctl").join("templates"))
+}
+
+/// Load template `name`: `<templates_dir>/<name>.kdl` if it exists, otherwise the built-in
+/// template of that name.
+pub fn load(name: &str) -> Result<Template> {
+    load_from(templates_dir().as_deref(), name)
+}
+
+/// [`load`] with the user templates in `dir`.
+pub fn load_from(dir: Option<&Path>, name: &str) -> Result<Template> {
+    let valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !name.starts_with('.');
+    if valid_name {
+        if let Some(dir) = dir {
+            let path = dir.join(format!("{name}.kdl"));
+            if path.exists() {
+                let text = std::fs::read_to_string(&path)?;
+                return parse(name, &text, dir);
+            }
+        }
+        if let Some((_, text)) = BUILTIN.iter().find(|(builtin, _)| *builtin == name) {
+            return parse(name, text, dir.unwrap_or(Path::new(".")));
+        }
+    }
+    Err(VmError::TemplateNotFound {
+        name: name.into(),
+        available: available_in(dir).join(", "),
+    })
+}
+
+/// Names of the templates [`load`] finds, sorted.
+pub fn available() -> Vec<String> {
+    available_in(templates_dir().as_deref())
+}
+
+fn available_in(dir: Option<&Path>) -> Vec<String> {
+    let mut names: Vec<String> = BUILTIN.iter().map(|(name, _)| name.to_string()).collect();
+    if let Some(entries) = dir.and_then(|dir| std::fs::read_dir(dir).ok()) {
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension().is_some_and(|ext| ext == "kdl") {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(stem.to_string());
+                }
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Parse the text of template `name`.
+fn parse(name: &str, text: &str, base_dir: &Path) -> Result<Template> {
+    let invalid = |detail: String, hint: &str| VmError::TemplateInvalid {
+        name: name.into(),
+        detail,
+        hint: hint.into(),
+    };
+    let doc: KdlDocument = text.parse().map_err(|e: kdl::KdlError| {
+        invalid(
+            e.to_string(),
+            "check the template's KDL syntax — see https://kdl.dev",
+        )
+    })?;
+
+    let mut template = Template {
+        name: name.into(),
+        image: None,
+        vcpus: None,
+        memory_mb: None,
+        user: None,
+        provisions: Vec::new(),
+        base_dir: base_dir.to_path_buf(),
+    };
+    for node in doc.nodes() {
+        let key = node.name().to_string();
+        let value = node.get(0);
+        let string = || {
+            value
+                .and_then(|v| v.as_string())
+                .map(String::from)
+                .ok_or_else(|| invalid(format!("{key} requires a string"), "quote the value"))
+        };
+        match key.as_str() {
+            "image" => template.image = Some(string()?),
+            "user" => template.user = Some(string()?),
+            "vcpus" => {
+                template.vcpus = Some(
+                    value
+                        .and_then(|v| v.as_integer())
+                        .and_then(|n| u16::try_from(n).ok())
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| invalid("invalid vcpus".into(), "e.g. vcpus 2"))?,
+                );
+            }
+            "memory" => {
+                template.memory_mb = Some(
+                    value
+                        .and_then(|v| v.as_integer())
+                        .and_then(|n| u64::try_from(n).ok())
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| {
+                            invalid("invalid memory".into(), "memory in MB, e.g. memory 2048")
+                        })?,
+                );
+            }
+            "provision" => {
+                let step = vmfile::parse_provision(name, node, &template.provisions).map_err(
+                    |e| match e {
+                        VmError::VmFileValidation { detail, hint, .. } => invalid(detail, &hint),
+                        other => other,
+                    },
+                )?;
+                template.provisions.push(step);
+            }
+            other => {
+                return Err(invalid(
+                    format!("unknown node: {other}"),
+                    "templates may set image, vcpus, memory, user and provision",
+                ));
+            }
+        }
+    }
+    Ok(template)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_templates_parse() {
+        let template = load_from(None, "ubuntu-server").unwrap();
+        assert!(template.image_is_url());
+        assert_eq!(template.vcpus, Some(2));
+        assert_eq!(template.memory_mb, Some(2048));
+        assert_eq!(template.user.as_deref(), Some("ubuntu"));
+        assert_eq!(template.provisions.len(), 1);
+
+        let template = load_from(None, "minimal").unwrap();
+        assert_eq!(template.image, None);
+        assert_eq!((template.vcpus, template.memory_mb), (Some(1), Some(512)));
+        assert!(template.provisions.is_empty());
+    }
+
+    #[test]
+    fn user_templates_override_builtins() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("minimal.kdl"), "vcpus 4\n").unwrap();
+        std::fs::write(
+            dir.path().join("db.kdl"),
+            "image \"base.qcow2\"\nprovision \"file\" {\n source \"my.cnf\"\n destination \"/etc/my.cnf\"\n}\n",
+        )
+        .unwrap();
+
+        let template = load_from(Some(dir.path()), "minimal").unwrap();
+        assert_eq!((template.vcpus, template.memory_mb), (Some(4), None));
+        let template = load_from(Some(dir.path()), "db").unwrap();
+        assert!(!template.image_is_url());
+        assert_eq!(template.base_dir, dir.path());
+        assert_eq!(template.provisions.len(), 1);
+
+        match load_from(Some(dir.path()), "../db") {
+            Err(VmError::TemplateNotFound { available, .. }) => {
+                assert_eq!(available, "db, minimal, ubuntu-server");
+            }
+            other => panic!("expected TemplateNotFound, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        for (text, expected) in [
+            ("vcpus 0", "invalid vcpus"),
+            ("memory \"2G\"", "invalid memory"),
+            ("user 1", "user requires a string"),
+            ("disk 20", "unknown node: disk"),
+            ("provision \"shell\"", "provision block must have a body"),
+        ] {
+            let msg = parse("t", text, Path::new(".")).unwrap_err().to_string();
+            assert!(msg.contains(expected), "{text}: got {msg}");
+        }
+    }
+}
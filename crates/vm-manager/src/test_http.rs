@@ -0,0 +1,67 @@
+//! A canned HTTP server for tests of code that fetches from or uploads to a URL.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// One answer: the status after `HTTP/1.1`, extra headers, and the body.
+pub type Response = (&'static str, Vec<(&'static str, &'static str)>, Vec<u8>);
+
+/// Answer one request per entry of `responses`, in order and each on its own connection,
+/// returning the URL of `path` on the server. Each raw request, body included, arrives on the
+/// returned channel. Once the responses are used up the port is closed.
+pub async fn serve(
+    responses: Vec<Response>,
+    path: &str,
+) -> (String, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/{path}", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for (status, headers, body) in responses {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let _ = tx.send(read_request(&mut conn).await);
+            let mut head = format!("HTTP/1.1 {status}\r\n");
+            for (name, value) in headers {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+            head.push_str(&format!(
+                "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            ));
+            conn.write_all(head.as_bytes()).await.unwrap();
+            for chunk in body.chunks(64 << 10) {
+                conn.write_all(chunk).await.unwrap();
+            }
+        }
+    });
+    (url, rx)
+}
+
+/// Read a request up to the end of its body, given by `Content-Length` or the last chunk.
+async fn read_request(conn: &mut TcpStream) -> Vec<u8> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+            let complete = if head.contains("transfer-encoding: chunked") {
+                request[end + 2..].ends_with(b"\r\n0\r\n\r\n")
+            } else {
+                let len = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map_or(0, |len| len.trim().parse().unwrap());
+                request.len() >= end + 4 + len
+            };
+            if complete {
+                return request;
+            }
+        }
+        let n = conn.read(&mut buf).await.unwrap();
+        if n == 0 {
+            return request;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+}
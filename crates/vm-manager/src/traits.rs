@@ -1,52 +1,104 @@
 use std::time::Duration;
 
-use crate::error::Result;
-use crate::types::{VmHandle, VmSpec, VmState};
+use async_trait::async_trait;
+
+use crate::error::{Result, VmError};
+use crate::types::{DryRun, VmHandle, VmIpInfo, VmSpec, VmState};
+
+/// How long callers wait for a guest address when they have no reason to pick another timeout.
+pub const DEFAULT_IP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often backends re-check their discovery sources while waiting for a guest address.
+pub const IP_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 /// Async hypervisor trait implemented by each backend (QEMU, Propolis, Noop).
 ///
+/// The trait is object-safe, so backends can be held as `Arc<dyn Hypervisor>` and registered with
+/// [`RouterHypervisor::register_backend`](crate::RouterHypervisor::register_backend).
+/// Implementations use `#[async_trait]`.
+///
 /// The lifecycle is: `prepare` -> `start` -> (optionally `suspend`/`resume`) -> `stop` -> `destroy`.
+#[async_trait]
 pub trait Hypervisor: Send + Sync {
     /// Allocate resources (overlay disk, cloud-init ISO, zone config, etc.) and return a handle.
-    fn prepare(&self, spec: &VmSpec) -> impl Future<Output = Result<VmHandle>> + Send;
+    ///
+    /// The handle's `backend` tag must identify this backend so the router can find it again.
+    async fn prepare(&self, spec: &VmSpec) -> Result<VmHandle>;
 
     /// Boot the VM. Returns the updated handle with PID, VNC addr, etc.
-    fn start(&self, vm: &VmHandle) -> impl Future<Output = Result<VmHandle>> + Send;
+    async fn start(&self, vm: &VmHandle) -> Result<VmHandle>;
 
     /// Gracefully stop the VM. Falls back to forceful termination after `timeout`.
     /// Returns the updated handle with cleared runtime fields.
-    fn stop(
-        &self,
-        vm: &VmHandle,
-        timeout: Duration,
-    ) -> impl Future<Output = Result<VmHandle>> + Send;
+    async fn stop(&self, vm: &VmHandle, timeout: Duration) -> Result<VmHandle>;
 
     /// Pause VM execution (freeze vCPUs). Returns the updated handle.
-    fn suspend(&self, vm: &VmHandle) -> impl Future<Output = Result<VmHandle>> + Send;
+    async fn suspend(&self, vm: &VmHandle) -> Result<VmHandle>;
 
     /// Resume a suspended VM. Returns the updated handle.
-    fn resume(&self, vm: &VmHandle) -> impl Future<Output = Result<VmHandle>> + Send;
+    async fn resume(&self, vm: &VmHandle) -> Result<VmHandle>;
 
     /// Stop the VM (if running) and clean up all resources.
-    fn destroy(&self, vm: VmHandle) -> impl Future<Output = Result<()>> + Send;
+    async fn destroy(&self, vm: VmHandle) -> Result<()>;
 
     /// Query the current state of the VM.
-    fn state(&self, vm: &VmHandle) -> impl Future<Output = Result<VmState>> + Send;
+    async fn state(&self, vm: &VmHandle) -> Result<VmState>;
 
-    /// Attempt to discover the guest's IP address.
-    fn guest_ip(&self, vm: &VmHandle) -> impl Future<Output = Result<String>> + Send;
+    /// Discover the guest's IPv4 and global IPv6 addresses, polling the discovery sources every
+    /// [`IP_POLL_INTERVAL`] until `timeout` passes while the guest boots. `Duration::ZERO` makes a
+    /// single pass. Fails with `IpDiscoveryTimeout` rather than returning an empty set.
+    async fn guest_ips(&self, vm: &VmHandle, timeout: Duration) -> Result<VmIpInfo>;
+
+    /// The guest's preferred address (IPv4 when it has one), as a string, waiting up to `timeout`.
+    async fn guest_ip(&self, vm: &VmHandle, timeout: Duration) -> Result<String> {
+        self.guest_ips(vm, timeout)
+            .await?
+            .preferred(false)
+            .map(|ip| ip.to_string())
+            .ok_or_else(|| VmError::IpDiscoveryTimeout {
+                name: vm.name.clone(),
+                mac: vm.mac_addr.clone().unwrap_or_else(|| "unknown".into()),
+            })
+    }
+
+    /// The VMs this backend finds in its own inventory, for backends that keep one apart from the
+    /// caller's store. Handles are rebuilt from what the backend knows, so settings it doesn't
+    /// record take their defaults. Empty by default.
+    async fn list_vms(&self) -> Result<Vec<VmHandle>> {
+        Ok(Vec::new())
+    }
 
     /// Return a path or address for attaching to the VM's serial console.
     fn console_endpoint(&self, vm: &VmHandle) -> Result<ConsoleEndpoint>;
+
+    /// Validate `spec` as `prepare` would and describe the commands and files `prepare` followed
+    /// by `start` would run and write, without creating anything. `None` if the backend can't
+    /// tell, which is the default.
+    async fn dry_run_create(&self, spec: &VmSpec) -> Result<Option<DryRun>> {
+        let _ = spec;
+        Ok(None)
+    }
+
+    /// Describe the commands `start` would run for `vm`, without running them. `None` if the
+    /// backend can't tell, which is the default.
+    async fn dry_run_start(&self, vm: &VmHandle) -> Result<Option<DryRun>> {
+        let _ = vm;
+        Ok(None)
+    }
 }
 
 /// Describes how to connect to a VM's serial console.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum ConsoleEndpoint {
     /// Unix domain socket path (QEMU).
     UnixSocket(std::path::PathBuf),
     /// WebSocket URL (Propolis).
     WebSocket(String),
+    /// Unix domain socket on a remote hypervisor host, reached through an SSH forward.
+    RemoteUnixSocket {
+        host: String,
+        path: std::path::PathBuf,
+    },
     /// Not available (Noop).
     None,
 }
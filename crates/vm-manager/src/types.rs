@@ -1,13 +1,22 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 
+use crate::error::{Result, VmError};
+
 /// Identifies which backend manages a VM.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
+///
+/// Serialized as a plain string (`"qemu"`, `"noop"`, ...). Backends registered by other crates
+/// use [`BackendTag::Custom`] with their own name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
 pub enum BackendTag {
     Noop,
     Qemu,
     Propolis,
+    /// A backend registered with [`RouterHypervisor::register_backend`](crate::RouterHypervisor::register_backend).
+    Custom(String),
 }
 
 impl std::fmt::Display for BackendTag {
@@ -16,18 +25,39 @@ impl std::fmt::Display for BackendTag {
             Self::Noop => write!(f, "noop"),
             Self::Qemu => write!(f, "qemu"),
             Self::Propolis => write!(f, "propolis"),
+            Self::Custom(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+impl From<String> for BackendTag {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "noop" => Self::Noop,
+            "qemu" => Self::Qemu,
+            "propolis" => Self::Propolis,
+            _ => Self::Custom(s),
         }
     }
 }
 
+impl From<BackendTag> for String {
+    fn from(tag: BackendTag) -> Self {
+        tag.to_string()
+    }
+}
+
 /// Full specification for creating a VM.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VmSpec {
     pub name: String,
     pub image_path: PathBuf,
     pub vcpus: u16,
     pub memory_mb: u64,
     pub disk_gb: Option<u32>,
+    /// `qemu-img create` tuning for the overlay.
+    #[serde(default)]
+    pub overlay: OverlayOptions,
     pub network: NetworkConfig,
     pub cloud_init: Option<CloudInitConfig>,
     pub ssh: Option<SshConfig>,
@@ -35,6 +65,949 @@ pub struct VmSpec {
     /// pflash drives for OVMF_CODE and a per-VM copy of OVMF_VARS.
     /// Default: false (legacy BIOS boot).
     pub uefi: bool,
+    /// Fixed MAC address for the primary NIC. When `None`, the backend generates one.
+    pub mac_addr: Option<String>,
+    /// MAC addresses already assigned to other VMs; a generated MAC never reuses one of these.
+    #[serde(default)]
+    pub existing_macs: Vec<String>,
+    /// Static IP configuration for the primary NIC, applied via cloud-init network-config.
+    pub static_ip: Option<StaticIpConfig>,
+    /// Host port forwarded to the guest's SSH port with user-mode networking. When `None`, the
+    /// backend picks a free one.
+    #[serde(default)]
+    pub ssh_host_port: Option<u16>,
+    /// Additional NICs on private inter-VM networks.
+    #[serde(default)]
+    pub private_networks: Vec<PrivateNic>,
+    /// Additional serial devices. The console is always the first serial port (`ttyS0`); these
+    /// become `ttyS1`, `ttyS2`, ... in order.
+    #[serde(default)]
+    pub serial_ports: Vec<SerialPort>,
+    /// QEMU machine type.
+    #[serde(default)]
+    pub machine: MachineType,
+    /// Boot a kernel directly instead of the disk's bootloader. Required for `microvm`.
+    #[serde(default)]
+    pub kernel: Option<KernelBoot>,
+    /// ISO image (e.g. an installer or data disc) attached read-only as a CD-ROM, separate from
+    /// the cloud-init seed.
+    #[serde(default)]
+    pub cdrom: Option<PathBuf>,
+    /// Devices the firmware tries to boot from, first to last; empty leaves the order to the
+    /// firmware. See [`check_boot_order`].
+    #[serde(default)]
+    pub boot_order: Vec<BootDevice>,
+    /// Entropy device exposed to the guest.
+    #[serde(default)]
+    pub random_seed: RngConfig,
+    /// Extra QEMU arguments appended verbatim after the generated ones. Options vmctl manages
+    /// itself ([`MANAGED_QEMU_OPTIONS`]) are rejected by `prepare`.
+    #[serde(default)]
+    pub qemu_args: Vec<String>,
+    /// Whether `vmctl watch` restarts the VM when it crashes or stops.
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    /// Operating system family of the guest, which picks the defaults below.
+    #[serde(default)]
+    pub guest_os: GuestOs,
+    /// Where the guest's display is shown. A window on the host keeps QEMU attached to the
+    /// process that started it.
+    #[serde(default)]
+    pub display: DisplayMode,
+    /// Host audio backend the guest's sound card plays through.
+    #[serde(default)]
+    pub audio: AudioBackend,
+    /// Strings in the guest's SMBIOS (DMI) tables.
+    #[serde(default)]
+    pub smbios: SmbiosConfig,
+    /// How the root disk is attached; `None` takes the guest OS's default (see
+    /// [`VmSpec::disk_bus`]).
+    #[serde(default)]
+    pub disk_bus: Option<DiskBus>,
+    /// virtio-win drivers ISO attached as a second CD-ROM. For Windows guests the backend looks
+    /// for a well-known one when `None`.
+    #[serde(default)]
+    pub drivers_iso: Option<PathBuf>,
+    /// Boot UEFI firmware built with Secure Boot, with keys enrolled. Implies `uefi`.
+    #[serde(default)]
+    pub secure_boot: bool,
+    /// Variable-store template to start from instead of the distribution's one with the
+    /// Microsoft and distribution keys enrolled, e.g. one with your own keys.
+    #[serde(default)]
+    pub secure_boot_vars: Option<PathBuf>,
+    /// Run the guest with encrypted memory. Implies `uefi`; see [`ConfidentialMode`] for what
+    /// it rules out.
+    #[serde(default)]
+    pub confidential: Option<ConfidentialMode>,
+    /// Host CPUs the VM's threads are pinned to after each start.
+    #[serde(default)]
+    pub cpu_pinning: Option<CpuPinning>,
+    /// Guest NUMA nodes; empty for a single node. See [`check_numa_layout`].
+    #[serde(default)]
+    pub numa: Vec<NumaNode>,
+    /// Back guest memory with the host's preallocated huge pages (`/dev/hugepages`).
+    #[serde(default)]
+    pub hugepages: bool,
+    /// Host USB devices passed through to the guest on every start.
+    #[serde(default)]
+    pub usb_devices: Vec<UsbDevice>,
+}
+
+/// QEMU options the backend sets itself and that `qemu_args` may not override.
+pub const MANAGED_QEMU_OPTIONS: &[&str] = &["-qmp", "-pidfile", "-daemonize"];
+
+/// The first of `args` that is one of the [`MANAGED_QEMU_OPTIONS`], in either its `-opt` or
+/// `--opt` spelling.
+pub fn managed_qemu_option(args: &[String]) -> Option<&str> {
+    args.iter().map(String::as_str).find(|arg| {
+        let option = arg
+            .strip_prefix('-')
+            .filter(|a| a.starts_with('-'))
+            .unwrap_or(arg);
+        MANAGED_QEMU_OPTIONS.contains(&option)
+    })
+}
+
+impl VmSpec {
+    /// A spec for VM `name` booting `image_path` with 1 vCPU, 1 GiB of memory and user-mode
+    /// networking. Everything else is off; set fields directly to change it.
+    pub fn new(name: impl Into<String>, image_path: impl Into<PathBuf>) -> Self {
+        Self {
+            name: name.into(),
+            image_path: image_path.into(),
+            vcpus: default_vcpus(),
+            memory_mb: default_memory_mb(),
+            disk_gb: None,
+            overlay: OverlayOptions::default(),
+            network: NetworkConfig::User,
+            cloud_init: None,
+            ssh: None,
+            uefi: false,
+            mac_addr: None,
+            existing_macs: Vec::new(),
+            static_ip: None,
+            ssh_host_port: None,
+            private_networks: Vec::new(),
+            serial_ports: Vec::new(),
+            machine: MachineType::Q35,
+            kernel: None,
+            cdrom: None,
+            boot_order: Vec::new(),
+            random_seed: RngConfig::default(),
+            qemu_args: Vec::new(),
+            restart_policy: RestartPolicy::No,
+            guest_os: GuestOs::Linux,
+            display: DisplayMode::Vnc,
+            audio: AudioBackend::None,
+            smbios: SmbiosConfig::default(),
+            disk_bus: None,
+            drivers_iso: None,
+            secure_boot: false,
+            secure_boot_vars: None,
+            confidential: None,
+            cpu_pinning: None,
+            numa: Vec::new(),
+            hugepages: false,
+            usb_devices: Vec::new(),
+        }
+    }
+
+    /// The bus the root disk is attached to: `disk_bus`, or the guest OS's default.
+    pub fn disk_bus(&self) -> DiskBus {
+        self.disk_bus.unwrap_or(self.guest_os.default_disk_bus())
+    }
+
+    /// Generate an Ed25519 keypair, authorize it for `user` with cloud-init, and log in with it.
+    /// Replaces any cloud-init and SSH configuration already set.
+    pub fn with_generated_ssh(mut self, user: &str) -> crate::error::Result<Self> {
+        let (public_key, private_key_pem) = crate::ssh::generate_keypair(&self.name)?;
+        self.cloud_init = Some(CloudInitConfig {
+            user_data: crate::cloudinit::CloudConfig::new(user, &public_key).to_user_data(),
+            instance_id: Some(self.name.clone()),
+            hostname: Some(self.name.clone()),
+            meta_data: None,
+        });
+        self.ssh = Some(SshConfig {
+            user: user.to_string(),
+            public_key: Some(public_key),
+            private_key_path: None,
+            private_key_pem: Some(private_key_pem),
+        });
+        Ok(self)
+    }
+}
+
+/// QEMU machine type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MachineType {
+    /// Full PC with PCI devices, firmware, VNC console.
+    #[default]
+    Q35,
+    /// Minimal virtio-mmio machine without firmware; boots in well under a second but needs
+    /// [`KernelBoot`].
+    Microvm,
+}
+
+impl std::fmt::Display for MachineType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Q35 => write!(f, "q35"),
+            Self::Microvm => write!(f, "microvm"),
+        }
+    }
+}
+
+/// Operating system family of a guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuestOs {
+    /// Configured by cloud-init, with virtio devices throughout.
+    #[default]
+    Linux,
+    /// Configured by Cloudbase-Init. The disk is on SATA until the virtio drivers are installed,
+    /// the clock runs in local time and RDP is forwarded with user-mode networking.
+    Windows,
+}
+
+impl GuestOs {
+    /// The root disk's bus unless one is configured: SATA for Windows, which has no virtio
+    /// storage driver out of the box.
+    pub fn default_disk_bus(self) -> DiskBus {
+        match self {
+            Self::Linux => DiskBus::Virtio,
+            Self::Windows => DiskBus::Sata,
+        }
+    }
+}
+
+impl std::fmt::Display for GuestOs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Linux => write!(f, "linux"),
+            Self::Windows => write!(f, "windows"),
+        }
+    }
+}
+
+impl std::str::FromStr for GuestOs {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "linux" => Ok(Self::Linux),
+            "windows" => Ok(Self::Windows),
+            other => Err(format!(
+                "unknown guest OS: {other} (expected linux or windows)"
+            )),
+        }
+    }
+}
+
+/// Where the guest's display is shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayMode {
+    /// A VNC server on localhost, for `vmctl vnc`.
+    #[default]
+    Vnc,
+    /// A GTK window on the host's desktop.
+    Gtk,
+    /// An SDL window on the host's desktop.
+    Sdl,
+    /// No display; the serial console only.
+    None,
+}
+
+impl DisplayMode {
+    /// Whether the display is a window on the host, which needs QEMU to stay in the
+    /// foreground instead of daemonizing.
+    pub fn is_local(self) -> bool {
+        matches!(self, Self::Gtk | Self::Sdl)
+    }
+}
+
+impl std::fmt::Display for DisplayMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Vnc => write!(f, "vnc"),
+            Self::Gtk => write!(f, "gtk"),
+            Self::Sdl => write!(f, "sdl"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+impl std::str::FromStr for DisplayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "vnc" => Ok(Self::Vnc),
+            "gtk" => Ok(Self::Gtk),
+            "sdl" => Ok(Self::Sdl),
+            "none" => Ok(Self::None),
+            other => Err(format!(
+                "unknown display: {other} (expected vnc, gtk, sdl or none)"
+            )),
+        }
+    }
+}
+
+/// Host audio backend the guest's sound card plays through and records from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBackend {
+    /// No sound card.
+    #[default]
+    None,
+    /// The host's PipeWire server.
+    Pipewire,
+    /// The host's PulseAudio server, or PipeWire's PulseAudio emulation.
+    Pa,
+}
+
+impl AudioBackend {
+    /// The QEMU `-audiodev` driver, `None` without a sound card.
+    pub fn driver(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Pipewire => Some("pipewire"),
+            Self::Pa => Some("pa"),
+        }
+    }
+}
+
+impl std::fmt::Display for AudioBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.driver().unwrap_or("none"))
+    }
+}
+
+impl std::str::FromStr for AudioBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "pipewire" => Ok(Self::Pipewire),
+            "pa" => Ok(Self::Pa),
+            other => Err(format!(
+                "unknown audio backend: {other} (expected pipewire, pa or none)"
+            )),
+        }
+    }
+}
+
+/// Longest SMBIOS string vmctl accepts, in bytes.
+pub const SMBIOS_MAX_STRING: usize = 255;
+
+/// SMBIOS type 11 has a one-byte count of its OEM strings.
+pub const SMBIOS_MAX_OEM_STRINGS: usize = 255;
+
+/// Strings the guest reads from its SMBIOS (DMI) tables, e.g. with `dmidecode` or in
+/// `/sys/class/dmi/id`. Unset fields keep QEMU's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmbiosConfig {
+    /// System manufacturer (type 1).
+    #[serde(default)]
+    pub manufacturer: Option<String>,
+    /// System product name (type 1).
+    #[serde(default)]
+    pub product: Option<String>,
+    /// System serial number (type 1).
+    #[serde(default)]
+    pub serial: Option<String>,
+    /// System UUID (type 1), in the usual hyphenated form.
+    #[serde(default)]
+    pub uuid: Option<String>,
+    /// System family (type 1).
+    #[serde(default)]
+    pub family: Option<String>,
+    /// OEM strings (type 11), in order.
+    #[serde(default)]
+    pub oem_strings: Vec<String>,
+    /// Add `vmctl:name=<name>` and `vmctl:id=<id>` to the OEM strings, so the guest can tell
+    /// which VM it is.
+    #[serde(default)]
+    pub identify: bool,
+}
+
+impl SmbiosConfig {
+    /// Whether nothing is set, leaving the tables as QEMU makes them.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// The type 1 fields that are set, by their QEMU `-smbios` names.
+    pub fn system_fields(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("manufacturer", &self.manufacturer),
+            ("product", &self.product),
+            ("serial", &self.serial),
+            ("uuid", &self.uuid),
+            ("family", &self.family),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value.as_deref()?)))
+        .collect()
+    }
+
+    /// The OEM strings of VM `name` with id `id`: [`oem_strings`](Self::oem_strings), then the
+    /// identifying ones if [`identify`](Self::identify) is set.
+    pub fn oem_strings_for(&self, name: &str, id: &str) -> Vec<String> {
+        let mut strings = self.oem_strings.clone();
+        if self.identify {
+            strings.push(format!("vmctl:name={name}"));
+            strings.push(format!("vmctl:id={id}"));
+        }
+        strings
+    }
+
+    /// Check that every string is printable ASCII of 1 to [`SMBIOS_MAX_STRING`] bytes, that
+    /// the UUID parses and that there are at most [`SMBIOS_MAX_OEM_STRINGS`] OEM strings,
+    /// counting the two [`identify`](Self::identify) adds. The error says what is wrong.
+    pub fn check(&self) -> std::result::Result<(), String> {
+        let check_string = |what: &str, value: &str| {
+            if value.is_empty() {
+                return Err(format!("SMBIOS {what} is empty"));
+            }
+            if value.len() > SMBIOS_MAX_STRING {
+                return Err(format!(
+                    "SMBIOS {what} is {} bytes long (at most {SMBIOS_MAX_STRING})",
+                    value.len()
+                ));
+            }
+            if let Some(c) = value.chars().find(|c| !(' '..='~').contains(c)) {
+                return Err(format!(
+                    "SMBIOS {what} contains {c:?}; only printable ASCII is allowed"
+                ));
+            }
+            Ok(())
+        };
+        for (key, value) in self.system_fields() {
+            check_string(key, value)?;
+        }
+        if let Some(ref uuid) = self.uuid {
+            uuid::Uuid::parse_str(uuid).map_err(|_| format!("invalid SMBIOS uuid: {uuid}"))?;
+        }
+        for (i, value) in self.oem_strings.iter().enumerate() {
+            check_string(&format!("OEM string {}", i + 1), value)?;
+        }
+        let count = self.oem_strings.len() + if self.identify { 2 } else { 0 };
+        if count > SMBIOS_MAX_OEM_STRINGS {
+            return Err(format!(
+                "{count} SMBIOS OEM strings (at most {SMBIOS_MAX_OEM_STRINGS})"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A host USB device to pass through to the guest: `vendor:product` in hex (`1050:0407`),
+/// whichever port it is in, or `bus.device` in decimal (`3.7`), which changes when it is
+/// replugged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum UsbDevice {
+    Id { vendor: u16, product: u16 },
+    Address { bus: u8, device: u8 },
+}
+
+impl std::fmt::Display for UsbDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Id { vendor, product } => write!(f, "{vendor:04x}:{product:04x}"),
+            Self::Address { bus, device } => write!(f, "{bus}.{device}"),
+        }
+    }
+}
+
+impl std::str::FromStr for UsbDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid USB device: {s} (expected vendor:product such as 1050:0407, or bus.device such as 3.7)"
+            )
+        };
+        let hex = |part: &str| {
+            u16::from_str_radix(part, 16)
+                .ok()
+                .filter(|_| part.len() <= 4)
+        };
+        let decimal = |part: &str| part.parse::<u8>().ok().filter(|&n| n > 0);
+        if let Some((vendor, product)) = s.split_once(':') {
+            let (Some(vendor), Some(product)) = (hex(vendor), hex(product)) else {
+                return Err(invalid());
+            };
+            return Ok(Self::Id { vendor, product });
+        }
+        match s.split_once('.') {
+            Some((bus, device)) => match (decimal(bus), decimal(device)) {
+                (Some(bus), Some(device)) => Ok(Self::Address { bus, device }),
+                _ => Err(invalid()),
+            },
+            None => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for UsbDevice {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<UsbDevice> for String {
+    fn from(usb: UsbDevice) -> Self {
+        usb.to_string()
+    }
+}
+
+/// Bus (interface) the root disk is attached to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskBus {
+    /// virtio-blk: fastest, but needs a virtio driver in the guest.
+    #[default]
+    Virtio,
+    /// A SATA disk on the q35 AHCI controller, which every OS can boot from.
+    Sata,
+    /// An emulated NVMe controller with the disk as its namespace.
+    Nvme,
+    /// A SCSI disk on a virtio-scsi controller, which passes `UNMAP` through.
+    Scsi,
+}
+
+impl std::fmt::Display for DiskBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Virtio => write!(f, "virtio"),
+            Self::Sata => write!(f, "sata"),
+            Self::Nvme => write!(f, "nvme"),
+            Self::Scsi => write!(f, "scsi"),
+        }
+    }
+}
+
+impl std::str::FromStr for DiskBus {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "virtio" => Ok(Self::Virtio),
+            "sata" => Ok(Self::Sata),
+            "nvme" => Ok(Self::Nvme),
+            "scsi" => Ok(Self::Scsi),
+            other => Err(format!(
+                "unknown disk bus: {other} (expected virtio, nvme, scsi or sata)"
+            )),
+        }
+    }
+}
+
+/// A device the firmware can boot from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BootDevice {
+    /// The root disk.
+    Disk,
+    /// The ISO attached as a CD-ROM (`cdrom`), not the cloud-init seed.
+    Cdrom,
+    /// PXE on the primary NIC.
+    Network,
+}
+
+impl std::fmt::Display for BootDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disk => write!(f, "disk"),
+            Self::Cdrom => write!(f, "cdrom"),
+            Self::Network => write!(f, "network"),
+        }
+    }
+}
+
+impl std::str::FromStr for BootDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "disk" => Ok(Self::Disk),
+            "cdrom" => Ok(Self::Cdrom),
+            "network" => Ok(Self::Network),
+            other => Err(format!(
+                "unknown boot device: {other} (expected disk, cdrom or network)"
+            )),
+        }
+    }
+}
+
+/// Check that `order` names each boot device at most once and only devices the VM has: the
+/// `cdrom` needs an ISO (`cdrom`) and `network` a primary NIC (`nic`). The error says what is
+/// wrong.
+pub fn check_boot_order(
+    order: &[BootDevice],
+    cdrom: bool,
+    nic: bool,
+) -> std::result::Result<(), String> {
+    for (i, device) in order.iter().enumerate() {
+        if order[..i].contains(device) {
+            return Err(format!("{device} is in the boot order twice"));
+        }
+        match device {
+            BootDevice::Disk => {}
+            BootDevice::Cdrom if !cdrom => {
+                return Err("the boot order has cdrom, but no CD-ROM ISO is attached".into());
+            }
+            BootDevice::Network if !nic => {
+                return Err("the boot order has network, but the VM has no network".into());
+            }
+            BootDevice::Cdrom | BootDevice::Network => {}
+        }
+    }
+    Ok(())
+}
+
+/// Hardware memory encryption for a confidential guest.
+///
+/// The host cannot read or change the guest's memory, so memory ballooning and hotplug, live
+/// snapshots and Secure Boot (whose variable store needs SMM) are not available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfidentialMode {
+    /// AMD Secure Encrypted Virtualization. Needs an EPYC host with SEV enabled in the firmware
+    /// and in `kvm_amd`.
+    Sev,
+}
+
+impl std::fmt::Display for ConfidentialMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sev => write!(f, "sev"),
+        }
+    }
+}
+
+impl std::str::FromStr for ConfidentialMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sev" => Ok(Self::Sev),
+            "sev-snp" => Err("sev-snp is not supported yet (use sev)".to_string()),
+            other => Err(format!("unknown confidential mode: {other} (expected sev)")),
+        }
+    }
+}
+
+/// Host CPUs a VM's threads may run on, applied with `sched_setaffinity` once the VM has
+/// started. Empty sets leave the threads unrestricted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuPinning {
+    /// CPUs for every vCPU without an entry in `vcpus`.
+    #[serde(default)]
+    pub cpuset: Vec<usize>,
+    /// vCPU index to the one host CPU it runs on.
+    #[serde(default)]
+    pub vcpus: BTreeMap<u16, usize>,
+    /// CPUs for QEMU's other threads: the main loop, I/O threads and workers.
+    #[serde(default)]
+    pub emulator: Vec<usize>,
+}
+
+impl CpuPinning {
+    /// Host CPUs vCPU `vcpu` is pinned to; empty when it is not pinned.
+    pub fn vcpu_cpus(&self, vcpu: u16) -> Vec<usize> {
+        match self.vcpus.get(&vcpu) {
+            Some(&cpu) => vec![cpu],
+            None => self.cpuset.clone(),
+        }
+    }
+
+    /// Every host CPU the pinning names, sorted and without duplicates.
+    pub fn host_cpus(&self) -> Vec<usize> {
+        let mut cpus: Vec<usize> = self
+            .cpuset
+            .iter()
+            .chain(self.vcpus.values())
+            .chain(&self.emulator)
+            .copied()
+            .collect();
+        cpus.sort_unstable();
+        cpus.dedup();
+        cpus
+    }
+}
+
+impl std::fmt::Display for CpuPinning {
+    /// `vCPUs on 4-7, vCPU 0 on 2, emulator on 0-1`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.cpuset.is_empty() {
+            parts.push(format!(
+                "vCPUs on {}",
+                crate::pinning::format_cpuset(&self.cpuset)
+            ));
+        }
+        for (vcpu, cpu) in &self.vcpus {
+            parts.push(format!("vCPU {vcpu} on {cpu}"));
+        }
+        if !self.emulator.is_empty() {
+            parts.push(format!(
+                "emulator on {}",
+                crate::pinning::format_cpuset(&self.emulator)
+            ));
+        }
+        if parts.is_empty() {
+            parts.push("none".to_string());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// A guest NUMA node: the vCPUs and the share of the VM's memory that are local to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NumaNode {
+    /// vCPU indices, sorted.
+    pub cpus: Vec<u16>,
+    pub memory_mb: u64,
+}
+
+/// Check that `nodes` divide a VM with `vcpus` vCPUs and `memory_mb` MB between them: every
+/// node has vCPUs and memory, every vCPU is in exactly one node, and the nodes' memory adds up
+/// to the VM's. The error says what is wrong.
+pub fn check_numa_layout(
+    nodes: &[NumaNode],
+    vcpus: u16,
+    memory_mb: u64,
+) -> std::result::Result<(), String> {
+    let mut owner: Vec<Option<usize>> = vec![None; vcpus as usize];
+    for (i, node) in nodes.iter().enumerate() {
+        if node.cpus.is_empty() || node.memory_mb == 0 {
+            return Err(format!("NUMA node {i} needs vCPUs and memory"));
+        }
+        for &cpu in &node.cpus {
+            match owner.get_mut(cpu as usize) {
+                None => {
+                    return Err(format!(
+                        "NUMA node {i} has vCPU {cpu}, but the VM has {vcpus} vCPUs (numbered from 0)"
+                    ));
+                }
+                Some(Some(other)) => {
+                    return Err(format!(
+                        "vCPU {cpu} is in both NUMA node {other} and node {i}"
+                    ));
+                }
+                Some(slot) => *slot = Some(i),
+            }
+        }
+    }
+    if let Some(cpu) = owner.iter().position(Option::is_none) {
+        return Err(format!("vCPU {cpu} is in no NUMA node"));
+    }
+    let total: u64 = nodes.iter().map(|n| n.memory_mb).sum();
+    if total != memory_mb {
+        return Err(format!(
+            "the NUMA nodes have {total} MB of memory in total, but the VM has {memory_mb} MB"
+        ));
+    }
+    Ok(())
+}
+
+/// Host CPUs a vCPU thread of a running VM is allowed to run on, as the kernel reports them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VcpuAffinity {
+    /// vCPU index.
+    pub vcpu: u16,
+    /// Host thread running the vCPU.
+    pub thread_id: u32,
+    /// Host CPUs the thread may run on.
+    pub cpus: Vec<usize>,
+}
+
+/// QCOW2 creation options for a VM's overlay. The defaults give a sparse image with QEMU's
+/// default 64 KiB clusters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlayOptions {
+    /// How much of the image to allocate up front.
+    #[serde(default)]
+    pub preallocation: Option<Preallocation>,
+    /// Cluster size in bytes: a power of two from 512 bytes to 2 MiB.
+    #[serde(default)]
+    pub cluster_size: Option<u64>,
+    /// Defer refcount updates, trading crash consistency (repaired on next open) for fewer
+    /// metadata writes.
+    #[serde(default)]
+    pub lazy_refcounts: bool,
+}
+
+/// QCOW2 `preallocation` mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preallocation {
+    /// Allocate metadata (L2 tables, refcounts) only.
+    Metadata,
+    /// Allocate metadata and reserve the data blocks with `fallocate`.
+    Falloc,
+    /// Allocate metadata and write zeroes to every data block.
+    Full,
+}
+
+impl std::fmt::Display for Preallocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Metadata => write!(f, "metadata"),
+            Self::Falloc => write!(f, "falloc"),
+            Self::Full => write!(f, "full"),
+        }
+    }
+}
+
+impl std::str::FromStr for Preallocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "metadata" => Ok(Self::Metadata),
+            "falloc" => Ok(Self::Falloc),
+            "full" => Ok(Self::Full),
+            other => Err(format!("unknown preallocation mode: {other}")),
+        }
+    }
+}
+
+/// What `vmctl watch` does when a VM's QEMU process goes away without vmctl stopping it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Leave it stopped.
+    #[default]
+    No,
+    /// Restart it after a crash, but not after the guest shut itself down cleanly.
+    OnFailure,
+    /// Restart it whenever it stops.
+    Always,
+}
+
+impl std::fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::No => write!(f, "no"),
+            Self::OnFailure => write!(f, "on-failure"),
+            Self::Always => write!(f, "always"),
+        }
+    }
+}
+
+impl std::str::FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "no" => Ok(Self::No),
+            "on-failure" => Ok(Self::OnFailure),
+            "always" => Ok(Self::Always),
+            other => Err(format!(
+                "unknown restart policy: {other} (expected no, on-failure or always)"
+            )),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Whether a VM that went away with `exit` is started again under this policy.
+    pub fn restarts_after(self, exit: &VmExit) -> bool {
+        match (self, exit) {
+            (_, VmExit::Requested) | (Self::No, _) => false,
+            (Self::OnFailure, VmExit::GuestShutdown) => false,
+            (Self::OnFailure, VmExit::Crashed { .. }) | (Self::Always, _) => true,
+        }
+    }
+}
+
+/// How a running VM's process came to an end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmExit {
+    /// vmctl stopped or destroyed it, or the user closed its display window.
+    Requested,
+    /// The guest powered itself off.
+    GuestShutdown,
+    /// Anything else: a QEMU crash, a kill from outside vmctl, a guest panic. `reason` is
+    /// QEMU's shutdown reason (e.g. `guest-panic`), or says that there was none.
+    Crashed { reason: String },
+}
+
+/// Guest entropy device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RngConfig {
+    /// virtio-rng fed from the host's `/dev/urandom`, optionally rate-limited to `max_bytes`
+    /// per `period_ms` (QEMU's default period is 1000 ms).
+    VirtioRng {
+        #[serde(default)]
+        max_bytes: Option<u64>,
+        #[serde(default)]
+        period_ms: Option<u32>,
+    },
+    /// No entropy device, e.g. to test how a guest copes with entropy starvation.
+    None,
+}
+
+impl Default for RngConfig {
+    fn default() -> Self {
+        Self::VirtioRng {
+            max_bytes: None,
+            period_ms: None,
+        }
+    }
+}
+
+/// Direct kernel boot configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelBoot {
+    /// Path to the kernel image on the hypervisor host.
+    pub kernel: PathBuf,
+    /// Optional initramfs.
+    #[serde(default)]
+    pub initrd: Option<PathBuf>,
+    /// Kernel command line. Defaults to `console=ttyS0 root=/dev/vda rw` when unset.
+    #[serde(default)]
+    pub cmdline: Option<String>,
+}
+
+impl KernelBoot {
+    /// The command line to pass, falling back to a default that uses the serial console and the
+    /// first virtio disk as root.
+    pub fn cmdline(&self) -> &str {
+        self.cmdline
+            .as_deref()
+            .unwrap_or("console=ttyS0 root=/dev/vda rw")
+    }
+}
+
+/// An additional guest serial port.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SerialPort {
+    pub backend: SerialBackend,
+}
+
+/// Where the host side of a serial port is connected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "path", rename_all = "lowercase")]
+pub enum SerialBackend {
+    /// Unix domain socket that the hypervisor listens on.
+    Socket(PathBuf),
+    /// Append guest output to a file.
+    File(PathBuf),
+    /// The hypervisor process's stdin/stdout.
+    Stdio,
+    /// Discard output.
+    Null,
 }
 
 /// Network configuration for a VM.
@@ -48,12 +1021,221 @@ pub enum NetworkConfig {
     User,
     /// illumos VNIC for exclusive-IP zones.
     Vnic { name: String },
+    /// macvtap device on host NIC `parent`, so the guest sits on the NIC's own network without
+    /// a host bridge (Linux, requires root). The host itself can't reach the guest this way.
+    Macvtap { parent: String },
     /// No networking.
     None,
 }
 
+/// Static IP configuration for a guest NIC.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticIpConfig {
+    /// Address in CIDR notation (e.g. "192.168.100.10/24").
+    pub address: String,
+    /// Default gateway.
+    #[serde(default)]
+    pub gateway: Option<String>,
+    /// DNS servers.
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+}
+
+impl StaticIpConfig {
+    /// The address without its prefix length.
+    pub fn ip(&self) -> &str {
+        self.address.split('/').next().unwrap_or(&self.address)
+    }
+}
+
+/// An IPv4 subnet in CIDR notation, e.g. `192.168.123.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Subnet {
+    pub network: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Subnet {
+    /// Parse `a.b.c.d/prefix`. The prefix must leave room for a gateway and at least one guest,
+    /// and the host bits must be zero.
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = |detail: String, hint: String| VmError::NetworkInvalid { detail, hint };
+        let example = "use CIDR notation with a private range, e.g. 192.168.123.0/24".to_string();
+
+        let (addr, prefix) = s
+            .split_once('/')
+            .ok_or_else(|| invalid(format!("subnet {s} has no prefix length"), example.clone()))?;
+        let network: Ipv4Addr = addr
+            .parse()
+            .map_err(|_| invalid(format!("invalid subnet address: {addr}"), example.clone()))?;
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|p| (8..=30).contains(p))
+            .ok_or_else(|| {
+                invalid(
+                    format!("invalid prefix length in {s}"),
+                    "the prefix length must be between 8 and 30".into(),
+                )
+            })?;
+
+        let subnet = Self { network, prefix };
+        let base = Ipv4Addr::from(u32::from(network) & subnet.mask());
+        if base != network {
+            return Err(invalid(
+                format!("{s} has host bits set"),
+                format!("did you mean {base}/{prefix}?"),
+            ));
+        }
+        Ok(subnet)
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX << (32 - self.prefix)
+    }
+
+    /// The netmask in dotted form, e.g. `255.255.255.0`.
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.mask())
+    }
+
+    /// The host's address on the bridge: the first address in the subnet.
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + 1)
+    }
+
+    /// First and last address handed out by DHCP: everything after the gateway up to (not
+    /// including) the broadcast address.
+    pub fn dhcp_range(&self) -> (Ipv4Addr, Ipv4Addr) {
+        let broadcast = u32::from(self.network) | !self.mask();
+        (
+            Ipv4Addr::from(u32::from(self.network) + 2),
+            Ipv4Addr::from(broadcast - 1),
+        )
+    }
+
+    /// Every address a host on the subnet can use: all but the network and broadcast addresses.
+    /// Used for private networks, which have no gateway.
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let broadcast = u32::from(self.network) | !self.mask();
+        (u32::from(self.network) + 1..broadcast).map(Ipv4Addr::from)
+    }
+
+    /// Whether `ip` lies within the subnet.
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & self.mask() == u32::from(self.network)
+    }
+
+    /// Whether the two subnets share any address.
+    pub fn overlaps(&self, other: &Subnet) -> bool {
+        let mask = self.mask() & other.mask();
+        u32::from(self.network) & mask == u32::from(other.network) & mask
+    }
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl TryFrom<String> for Subnet {
+    type Error = VmError;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::parse(&s)
+    }
+}
+
+impl From<Subnet> for String {
+    fn from(subnet: Subnet) -> Self {
+        subnet.to_string()
+    }
+}
+
+/// A guest NIC on a private network: an isolated L2 segment shared by VMs, without host
+/// addresses, NAT or DHCP.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrivateNic {
+    /// Network name, as declared in the VMFile.
+    pub network: String,
+    /// Guest address in CIDR notation, applied via cloud-init network-config.
+    pub address: String,
+    /// MAC address of the NIC; generated by the backend when `None`.
+    #[serde(default)]
+    pub mac: Option<String>,
+    /// Host bridge the NIC is attached to. `None` means the VMs are linked with a QEMU multicast
+    /// socket instead (see [`PrivateNic::multicast_group`]), which needs no privileges.
+    #[serde(default)]
+    pub bridge: Option<String>,
+}
+
+impl PrivateNic {
+    /// The `group:port` the network's multicast socket uses, derived from the network name so
+    /// that every VM on the host joining `network` meets on the same segment.
+    pub fn multicast_group(&self) -> String {
+        // FNV-1a, so the group is stable across builds and hosts.
+        let hash = self.network.bytes().fold(0x811c_9dc5u32, |h, b| {
+            (h ^ u32::from(b)).wrapping_mul(0x0100_0193)
+        });
+        let [a, b, c, d] = hash.to_be_bytes();
+        let port = 20000 + u16::from_be_bytes([c, d]) % 40000;
+        format!("239.192.{a}.{b}:{port}")
+    }
+}
+
+/// Addresses discovered for a guest, by family, in discovery order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmIpInfo {
+    pub v4: Vec<Ipv4Addr>,
+    /// Global (non-link-local) IPv6 addresses.
+    pub v6: Vec<Ipv6Addr>,
+}
+
+impl VmIpInfo {
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    /// Add `ip` unless it is already known. Leaving out link-local IPv6 addresses is the caller's
+    /// job; see [`is_global_v6`].
+    pub fn push(&mut self, ip: IpAddr) {
+        match ip {
+            IpAddr::V4(v4) if !self.v4.contains(&v4) => self.v4.push(v4),
+            IpAddr::V6(v6) if !self.v6.contains(&v6) => self.v6.push(v6),
+            _ => {}
+        }
+    }
+
+    /// The address to connect to: the first IPv4 address, or with `prefer_ipv6` the first IPv6
+    /// one. Falls back to the other family when the preferred one has none.
+    pub fn preferred(&self, prefer_ipv6: bool) -> Option<IpAddr> {
+        let v4 = self.v4.first().copied().map(IpAddr::V4);
+        let v6 = self.v6.first().copied().map(IpAddr::V6);
+        if prefer_ipv6 { v6.or(v4) } else { v4.or(v6) }
+    }
+}
+
+impl From<IpAddr> for VmIpInfo {
+    fn from(ip: IpAddr) -> Self {
+        let mut info = Self::default();
+        info.push(ip);
+        info
+    }
+}
+
+/// Whether `ip` is usable from the host beyond the local link: not loopback, unspecified,
+/// multicast or link-local (`fe80::/10`). Unique local addresses (`fd00::/8`) count as global.
+pub fn is_global_v6(ip: &Ipv6Addr) -> bool {
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (ip.segments()[0] & 0xffc0) == 0xfe80)
+}
+
 /// Cloud-init NoCloud configuration.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CloudInitConfig {
     /// Raw user-data content (typically a cloud-config YAML).
     pub user_data: Vec<u8>,
@@ -61,10 +1243,29 @@ pub struct CloudInitConfig {
     pub instance_id: Option<String>,
     /// Hostname for the guest.
     pub hostname: Option<String>,
+    /// Raw meta-data content, used as it is instead of the one built from `instance_id` and
+    /// `hostname`.
+    #[serde(default)]
+    pub meta_data: Option<Vec<u8>>,
+}
+
+impl CloudInitConfig {
+    /// The NoCloud meta-data for VM `name`: `meta_data` if set, otherwise an instance-id and
+    /// hostname that default to `name`.
+    pub fn meta_data_for(config: Option<&Self>, name: &str) -> Vec<u8> {
+        if let Some(raw) = config.and_then(|c| c.meta_data.as_ref()) {
+            return raw.clone();
+        }
+        let instance_id = config
+            .and_then(|c| c.instance_id.as_deref())
+            .unwrap_or(name);
+        let hostname = config.and_then(|c| c.hostname.as_deref()).unwrap_or(name);
+        format!("instance-id: {instance_id}\nlocal-hostname: {hostname}\n").into_bytes()
+    }
 }
 
 /// SSH connection configuration.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SshConfig {
     /// Username to connect as.
     pub user: String,
@@ -108,18 +1309,144 @@ pub struct VmHandle {
     /// Disk size in GB (overlay resize), if specified.
     #[serde(default)]
     pub disk_gb: Option<u32>,
+    /// `qemu-img` tuning the overlay was created with; also applied when its snapshots are
+    /// exported as qcow2.
+    #[serde(default)]
+    pub overlay: OverlayOptions,
     /// Network configuration for this VM.
     #[serde(default)]
     pub network: NetworkConfig,
+    /// Host macvtap device of [`NetworkConfig::Macvtap`] networking, created on start and
+    /// deleted with the VM.
+    #[serde(default)]
+    pub macvtap: Option<String>,
     /// SSH host port for user-mode networking (forwarded to guest port 22).
     #[serde(default)]
     pub ssh_host_port: Option<u16>,
+    /// Whether `ssh_host_port` was configured rather than picked by the backend. A picked port
+    /// that has been taken by the next start is replaced; a configured one is an error.
+    #[serde(default)]
+    pub ssh_host_port_fixed: bool,
     /// MAC address assigned to this VM.
     #[serde(default)]
     pub mac_addr: Option<String>,
     /// Boot with UEFI firmware.
     #[serde(default)]
     pub uefi: bool,
+    /// Static IP configuration, if the guest was given a fixed address.
+    #[serde(default)]
+    pub static_ip: Option<StaticIpConfig>,
+    /// NICs on private inter-VM networks, with the MAC and link the backend chose.
+    #[serde(default)]
+    pub private_networks: Vec<PrivateNic>,
+    /// Additional serial devices (`ttyS1` onwards).
+    #[serde(default)]
+    pub serial_ports: Vec<SerialPort>,
+    /// QEMU machine type.
+    #[serde(default)]
+    pub machine: MachineType,
+    /// Direct kernel boot configuration.
+    #[serde(default)]
+    pub kernel: Option<KernelBoot>,
+    /// ISO image attached read-only as a CD-ROM.
+    #[serde(default)]
+    pub cdrom: Option<PathBuf>,
+    /// Devices the firmware tries to boot from, first to last.
+    #[serde(default)]
+    pub boot_order: Vec<BootDevice>,
+    /// Device to boot from on the next start only, ahead of `boot_order`. Never recorded:
+    /// [`VmManager`](crate::VmManager) clears it once the VM has started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_once: Option<BootDevice>,
+    /// Entropy device exposed to the guest.
+    #[serde(default)]
+    pub random_seed: RngConfig,
+    /// Extra QEMU arguments appended after the generated ones.
+    #[serde(default)]
+    pub qemu_args: Vec<String>,
+    /// Whether `vmctl watch` restarts the VM when it crashes or stops.
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    /// Operating system family of the guest.
+    #[serde(default)]
+    pub guest_os: GuestOs,
+    /// Where the guest's display is shown.
+    #[serde(default)]
+    pub display: DisplayMode,
+    /// Host audio backend of the guest's sound card.
+    #[serde(default)]
+    pub audio: AudioBackend,
+    /// Strings in the guest's SMBIOS (DMI) tables.
+    #[serde(default)]
+    pub smbios: SmbiosConfig,
+    /// Bus the root disk is attached to.
+    #[serde(default)]
+    pub disk_bus: DiskBus,
+    /// virtio-win drivers ISO attached as a second CD-ROM.
+    #[serde(default)]
+    pub drivers_iso: Option<PathBuf>,
+    /// Host port on 127.0.0.1 forwarded to the guest's RDP port, for Windows guests with
+    /// user-mode networking.
+    #[serde(default)]
+    pub rdp_host_port: Option<u16>,
+    /// Whether the VM boots Secure Boot firmware.
+    #[serde(default)]
+    pub secure_boot: bool,
+    /// Memory encryption the VM runs with, if any.
+    #[serde(default)]
+    pub confidential: Option<ConfidentialMode>,
+    /// Host CPUs the VM's threads are pinned to.
+    #[serde(default)]
+    pub cpu_pinning: Option<CpuPinning>,
+    /// Guest NUMA nodes; empty for a single node.
+    #[serde(default)]
+    pub numa: Vec<NumaNode>,
+    /// Whether guest memory is backed by huge pages.
+    #[serde(default)]
+    pub hugepages: bool,
+    /// Host USB devices passed through to the guest on every start; devices attached with
+    /// `QemuBackend::usb_attach` are not listed.
+    #[serde(default)]
+    pub usb_devices: Vec<UsbDevice>,
+    /// Remote hypervisor host (`ssh://user@host`) this VM runs on; `None` for local VMs.
+    #[serde(default)]
+    pub remote_host: Option<String>,
+    /// Short `key=value` tags, e.g. `team=infra` (set with `vmctl label`).
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Free-form metadata such as a CI build URL (set with `vmctl annotate`). Values may be large.
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    /// Names registered for the guest in `/etc/hosts` or via mDNS while it runs.
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+}
+
+impl VmHandle {
+    /// Port the guest's SSH server is reached on from the hypervisor host: the forwarded host
+    /// port for user-mode networking, 22 otherwise. Fails with [`VmError::SshPortMissing`] for a
+    /// user-mode VM without a forwarded port, rather than reaching the host's own port 22.
+    pub fn ssh_port(&self) -> Result<u16> {
+        match self.network {
+            NetworkConfig::User => self.ssh_host_port.ok_or_else(|| VmError::SshPortMissing {
+                vm: self.name.clone(),
+            }),
+            _ => Ok(22),
+        }
+    }
+
+    /// The boot order of the next start: `boot_once`, then the rest of `boot_order`.
+    pub fn start_boot_order(&self) -> Vec<BootDevice> {
+        self.boot_once
+            .into_iter()
+            .chain(
+                self.boot_order
+                    .iter()
+                    .copied()
+                    .filter(|&d| Some(d) != self.boot_once),
+            )
+            .collect()
+    }
 }
 
 fn default_vcpus() -> u16 {
@@ -163,3 +1490,48 @@ impl std::fmt::Display for VmState {
         }
     }
 }
+
+/// What a backend would run and write for an operation, without doing it; see
+/// [`Hypervisor::dry_run_create`](crate::Hypervisor::dry_run_create).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DryRun {
+    /// Commands in the order they would run, each as the program followed by its arguments.
+    pub commands: Vec<Vec<String>>,
+    /// Files that would be generated.
+    pub files: Vec<DryRunFile>,
+    /// MAC address the VM's primary NIC would get.
+    pub mac_addr: Option<String>,
+}
+
+/// A file a dry run would have generated.
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunFile {
+    /// Where it would be written, e.g. `seed.iso:user-data` for a file inside the seed ISO.
+    pub location: String,
+    pub contents: String,
+}
+
+impl DryRun {
+    /// Each command as one line, quoted for a POSIX shell.
+    pub fn shell_lines(&self) -> Vec<String> {
+        self.commands
+            .iter()
+            .map(|argv| {
+                argv.iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect()
+    }
+}
+
+/// `arg` single-quoted unless it only has characters a shell leaves alone.
+pub fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
@@ -0,0 +1,226 @@
+//! Host USB devices, for passing them through to guests.
+//!
+//! Devices are found in sysfs (`/sys/bus/usb/devices`), where udev learns about them too, and
+//! opened by QEMU through their usbfs nodes in `/dev/bus/usb`. Those nodes belong to root unless
+//! a udev rule says otherwise, so [`check_access`] tries to open them first and explains the
+//! rule that is missing.
+
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, VmError};
+use crate::types::UsbDevice;
+
+/// Where the kernel lists USB devices.
+pub const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// A USB device plugged into the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostUsbDevice {
+    pub bus: u8,
+    /// Device address on the bus; it changes when the device is replugged.
+    pub device: u8,
+    pub vendor: u16,
+    pub product: u16,
+    pub manufacturer: Option<String>,
+    /// Product name the device reports, e.g. `YubiKey OTP+FIDO+CCID`.
+    pub name: Option<String>,
+}
+
+impl HostUsbDevice {
+    /// Whether `usb` selects this device.
+    pub fn matches(&self, usb: &UsbDevice) -> bool {
+        match *usb {
+            UsbDevice::Id { vendor, product } => self.vendor == vendor && self.product == product,
+            UsbDevice::Address { bus, device } => self.bus == bus && self.device == device,
+        }
+    }
+
+    /// The usbfs node QEMU opens for the device.
+    pub fn dev_path(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", self.bus, self.device))
+    }
+
+    /// Manufacturer and product name, as far as the device reports them.
+    pub fn description(&self) -> String {
+        let parts: Vec<&str> = [&self.manufacturer, &self.name]
+            .into_iter()
+            .filter_map(|s| s.as_deref())
+            .collect();
+        parts.join(" ")
+    }
+
+    /// A udev rule that lets the `kvm` group and the user at the seat open this device.
+    pub fn udev_rule(&self) -> String {
+        format!(
+            "SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", ATTR{{idProduct}}==\"{:04x}\", \
+             MODE=\"0660\", GROUP=\"kvm\", TAG+=\"uaccess\"",
+            self.vendor, self.product
+        )
+    }
+}
+
+/// The USB devices plugged into the host, by bus and address. Root hubs are left out.
+pub fn list() -> std::io::Result<Vec<HostUsbDevice>> {
+    list_in(Path::new(SYSFS_USB_DEVICES))
+}
+
+/// [`list`] with sysfs's USB device directory at `dir`.
+pub fn list_in(dir: &Path) -> std::io::Result<Vec<HostUsbDevice>> {
+    let mut devices = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let name = file_name.to_string_lossy();
+        // `1-2` and `1-2.3` are devices; `1-2:1.0` are their interfaces and `usb1` a root hub
+        if name.contains(':') || name.starts_with("usb") {
+            continue;
+        }
+        let path = entry.path();
+        let read = |attr: &str| {
+            std::fs::read_to_string(path.join(attr))
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+        let number = |attr: &str| read(attr).and_then(|s| s.parse().ok());
+        let id = |attr: &str| read(attr).and_then(|s| u16::from_str_radix(&s, 16).ok());
+        let (Some(bus), Some(device), Some(vendor), Some(product)) = (
+            number("busnum"),
+            number("devnum"),
+            id("idVendor"),
+            id("idProduct"),
+        ) else {
+            continue;
+        };
+        devices.push(HostUsbDevice {
+            bus,
+            device,
+            vendor,
+            product,
+            manufacturer: read("manufacturer"),
+            name: read("product"),
+        });
+    }
+    devices.sort_by_key(|d| (d.bus, d.device));
+    Ok(devices)
+}
+
+/// Find the host device `usb` selects for VM `vm` and check that this process can open its
+/// usbfs node, as QEMU will have to.
+pub fn check_access(vm: &str, usb: &UsbDevice) -> Result<HostUsbDevice> {
+    let devices = list()?;
+    let host = devices
+        .into_iter()
+        .find(|d| d.matches(usb))
+        .ok_or_else(|| VmError::UsbDeviceNotFound {
+            vm: vm.into(),
+            device: usb.to_string(),
+        })?;
+    let path = host.dev_path();
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+    {
+        Ok(_) => Ok(host),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(VmError::UsbPermissionDenied {
+                vm: vm.into(),
+                device: usb.to_string(),
+                path,
+                rule: host.udev_rule(),
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_device(dir: &Path, name: &str, attrs: &[(&str, &str)]) {
+        let path = dir.join(name);
+        std::fs::create_dir(&path).unwrap();
+        for (attr, value) in attrs {
+            std::fs::write(path.join(attr), format!("{value}\n")).unwrap();
+        }
+    }
+
+    #[test]
+    fn list_reads_sysfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let hub = [
+            ("busnum", "1"),
+            ("devnum", "1"),
+            ("idVendor", "1d6b"),
+            ("idProduct", "0002"),
+        ];
+        write_device(dir.path(), "usb1", &hub);
+        write_device(
+            dir.path(),
+            "3-2",
+            &[
+                ("busnum", "3"),
+                ("devnum", "7"),
+                ("idVendor", "1050"),
+                ("idProduct", "0407"),
+                ("manufacturer", "Yubico"),
+                ("product", "YubiKey OTP+FIDO+CCID"),
+            ],
+        );
+        write_device(dir.path(), "3-2:1.0", &[("bInterfaceClass", "03")]);
+        write_device(
+            dir.path(),
+            "1-1.4",
+            &[
+                ("busnum", "1"),
+                ("devnum", "5"),
+                ("idVendor", "0403"),
+                ("idProduct", "6001"),
+            ],
+        );
+
+        let devices = list_in(dir.path()).unwrap();
+        assert_eq!(devices.len(), 2);
+        let serial = &devices[0];
+        assert_eq!((serial.bus, serial.device), (1, 5));
+        assert_eq!(serial.description(), "");
+        let yubikey = &devices[1];
+        assert!(yubikey.matches(&"1050:0407".parse().unwrap()));
+        assert!(yubikey.matches(&"3.7".parse().unwrap()));
+        assert!(!yubikey.matches(&"3.8".parse().unwrap()));
+        assert_eq!(yubikey.dev_path(), Path::new("/dev/bus/usb/003/007"));
+        assert_eq!(yubikey.description(), "Yubico YubiKey OTP+FIDO+CCID");
+        assert!(yubikey.udev_rule().contains("ATTR{idVendor}==\"1050\""));
+    }
+
+    #[test]
+    fn parse_usb_devices() {
+        for (text, parsed, shown) in [
+            (
+                "1050:0407",
+                UsbDevice::Id {
+                    vendor: 0x1050,
+                    product: 0x0407,
+                },
+                "1050:0407",
+            ),
+            (
+                "46d:C52B",
+                UsbDevice::Id {
+                    vendor: 0x046d,
+                    product: 0xc52b,
+                },
+                "046d:c52b",
+            ),
+            ("003.007", UsbDevice::Address { bus: 3, device: 7 }, "3.7"),
+        ] {
+            assert_eq!(text.parse::<UsbDevice>(), Ok(parsed), "{text}");
+            assert_eq!(parsed.to_string(), shown);
+        }
+        for text in ["1050", "1050:04077", "0.7", "3.x", "yubikey"] {
+            assert!(text.parse::<UsbDevice>().is_err(), "{text}");
+        }
+    }
+}
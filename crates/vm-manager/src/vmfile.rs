@@ -1,13 +1,20 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::{Path, PathBuf};
 
 use kdl::KdlDocument;
 use tracing::info;
 
-use crate::cloudinit::build_cloud_config;
+use crate::cloudinit::CloudConfig;
 use crate::error::{Result, VmError};
 use crate::image::ImageManager;
-use crate::types::{CloudInitConfig, NetworkConfig, SshConfig, VmSpec};
+use crate::pinning;
+use crate::types::{
+    AudioBackend, BootDevice, CloudInitConfig, ConfidentialMode, CpuPinning, DiskBus, DisplayMode,
+    GuestOs, KernelBoot, MachineType, NetworkConfig, NumaNode, OverlayOptions, PrivateNic,
+    RestartPolicy, RngConfig, SmbiosConfig, SshConfig, StaticIpConfig, Subnet, UsbDevice, VmSpec,
+    check_boot_order, managed_qemu_option,
+};
 
 // ---------------------------------------------------------------------------
 // Types
@@ -20,6 +27,16 @@ pub struct VmFile {
     pub base_dir: PathBuf,
     /// Ordered list of VM definitions.
     pub vms: Vec<VmDef>,
+    /// Private networks declared at the top level.
+    pub networks: Vec<PrivateNetworkDef>,
+}
+
+/// A private network: an isolated L2 segment for the VMs that join it, with no host NAT.
+#[derive(Debug, Clone)]
+pub struct PrivateNetworkDef {
+    pub name: String,
+    /// Guest addresses are assigned from this subnet.
+    pub subnet: Subnet,
 }
 
 /// A single VM definition from a VMFile.
@@ -30,10 +47,70 @@ pub struct VmDef {
     pub vcpus: u16,
     pub memory_mb: u64,
     pub disk_gb: Option<u32>,
+    /// Overlay tuning from the `disk` node's properties.
+    pub overlay: OverlayOptions,
+    pub machine: MachineType,
+    /// Direct kernel boot; required for the `microvm` machine type.
+    pub kernel: Option<KernelDef>,
+    /// ISO attached as a CD-ROM, as written in the VMFile.
+    pub cdrom: Option<String>,
+    /// From the `boot-order` node.
+    pub boot_order: Vec<BootDevice>,
+    /// Entropy device from the `rng` node.
+    pub random_seed: RngConfig,
+    /// Extra QEMU arguments from the `extra-arg` nodes, in order.
+    pub qemu_args: Vec<String>,
+    /// From the `restart-policy` node.
+    pub restart_policy: RestartPolicy,
+    /// From the `guest-os` node.
+    pub guest_os: GuestOs,
+    /// From the `display` node.
+    pub display: DisplayMode,
+    /// From the `audio` node.
+    pub audio: AudioBackend,
+    /// From the `smbios` block.
+    pub smbios: SmbiosConfig,
+    /// From the `disk-bus` node; `None` takes the guest OS's default.
+    pub disk_bus: Option<DiskBus>,
+    /// virtio-win drivers ISO from the `drivers-iso` node, as written in the VMFile.
+    pub drivers_iso: Option<String>,
+    /// From the `secure-boot` node; implies UEFI.
+    pub secure_boot: bool,
+    /// The `vars` property of the `secure-boot` node, as written in the VMFile.
+    pub secure_boot_vars: Option<String>,
+    /// From the `confidential` node; implies UEFI.
+    pub confidential: Option<ConfidentialMode>,
+    /// From the `cpu-pinning` node.
+    pub cpu_pinning: Option<CpuPinning>,
+    /// Guest NUMA nodes from the `numa` block; empty for a single node.
+    pub numa: Vec<NumaNode>,
+    /// From the `hugepages` node.
+    pub hugepages: bool,
+    /// Host USB devices from the `usb` nodes, passed through at every start.
+    pub usb_devices: Vec<UsbDevice>,
     pub network: NetworkDef,
+    /// Fixed MAC address for the primary NIC.
+    pub mac: Option<String>,
+    /// Static address for the primary NIC (tap/bridge networking only).
+    pub static_ip: Option<StaticIpConfig>,
+    /// Fixed host port forwarded to the guest's SSH port (user-mode networking only).
+    pub ssh_host_port: Option<u16>,
+    /// Extra NICs on private networks, with their addresses assigned.
+    pub private_networks: Vec<PrivateNic>,
     pub cloud_init: Option<CloudInitDef>,
     pub ssh: Option<SshDef>,
     pub provisions: Vec<ProvisionDef>,
+    /// Snapshot the disk before provisioning, from `snapshot-before-provision`.
+    pub provision_snapshot: Option<ProvisionSnapshotDef>,
+    /// Names of VMs that must be up (and reachable over SSH) before this one starts.
+    pub depends_on: Vec<String>,
+}
+
+/// Pre-provision snapshot policy from a `snapshot-before-provision` node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProvisionSnapshotDef {
+    /// How many `pre-provision-*` snapshots to keep after a successful run; all when unset.
+    pub keep: Option<usize>,
 }
 
 /// Where to source the VM image from.
@@ -55,15 +132,32 @@ pub enum NetworkDef {
     Vnic {
         name: String,
     },
+    Macvtap {
+        parent: String,
+    },
     None,
 }
 
+/// Direct kernel boot as declared in the VMFile. Paths are resolved relative to the VMFile.
+#[derive(Debug, Clone)]
+pub struct KernelDef {
+    pub kernel: String,
+    pub initrd: Option<String>,
+    pub cmdline: Option<String>,
+}
+
 /// Cloud-init configuration block.
 #[derive(Debug, Clone)]
 pub struct CloudInitDef {
     pub hostname: Option<String>,
     pub ssh_key: Option<String>,
     pub user_data: Option<String>,
+    /// IANA time zone for the guest, e.g. `Europe/Berlin`.
+    pub timezone: Option<String>,
+    /// Guest system locale, e.g. `de_DE.UTF-8`.
+    pub locale: Option<String>,
+    /// NTP servers or pools for the guest.
+    pub ntp_servers: Vec<String>,
 }
 
 /// SSH connection configuration block.
@@ -80,20 +174,80 @@ pub struct SshDef {
 pub enum ProvisionDef {
     Shell(ShellProvision),
     File(FileProvision),
+    Loop(LoopProvision),
+    Puppet(PuppetProvision),
+    Download(DownloadProvision),
+}
+
+impl ProvisionDef {
+    /// The step's name, if the VMFile gave it one.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            ProvisionDef::Shell(s) => s.name.as_deref(),
+            ProvisionDef::File(f) => f.name.as_deref(),
+            ProvisionDef::Loop(l) => l.name.as_deref(),
+            ProvisionDef::Puppet(p) => p.name.as_deref(),
+            ProvisionDef::Download(d) => d.name.as_deref(),
+        }
+    }
+
+    /// Label used in output and logs: the step's name, or its 1-based number.
+    pub fn label(&self, step: usize) -> String {
+        self.name()
+            .map(String::from)
+            .unwrap_or_else(|| step.to_string())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ShellProvision {
+    pub name: Option<String>,
     pub inline: Option<String>,
     pub script: Option<String>,
+    /// Variable that receives the step's trimmed stdout, for `${var}` in later steps.
+    pub capture: Option<String>,
+    /// Run the step as root through `sudo -n`, from `sudo #true`.
+    pub sudo: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct FileProvision {
+    pub name: Option<String>,
     pub source: String,
     pub destination: String,
 }
 
+/// Applies a local Puppet manifest with `puppet apply`, as root.
+#[derive(Debug, Clone)]
+pub struct PuppetProvision {
+    pub name: Option<String>,
+    /// Path to the `.pp` file, relative to the VMFile's directory.
+    pub manifest: String,
+    /// Local directories of Puppet modules, searched in order.
+    pub module_paths: Vec<String>,
+    /// Custom facts, set as `FACTER_<name>` environment variables.
+    pub facts: HashMap<String, String>,
+}
+
+/// Downloads a file from a URL on the guest itself, with `curl` or `wget`.
+#[derive(Debug, Clone)]
+pub struct DownloadProvision {
+    pub name: Option<String>,
+    pub url: String,
+    /// Absolute path on the guest.
+    pub destination: String,
+    /// Expected digest as `sha256:<hex>`, checked with `sha256sum -c`.
+    pub checksum: Option<String>,
+}
+
+/// Runs `step` once per item, with `${item}` set to the item.
+#[derive(Debug, Clone)]
+pub struct LoopProvision {
+    pub name: Option<String>,
+    pub items: Vec<String>,
+    pub step: Box<ProvisionDef>,
+}
+
 // ---------------------------------------------------------------------------
 // Path helpers
 // ---------------------------------------------------------------------------
@@ -121,6 +275,44 @@ pub fn resolve_path(raw: &str, base_dir: &Path) -> PathBuf {
     }
 }
 
+// ---------------------------------------------------------------------------
+// Validation helpers
+// ---------------------------------------------------------------------------
+
+/// Check that `s` is an IP address with a prefix length, e.g. `192.168.100.10/24`.
+fn is_valid_cidr(s: &str) -> bool {
+    let Some((addr, prefix)) = s.split_once('/') else {
+        return false;
+    };
+    let Ok(addr) = addr.parse::<IpAddr>() else {
+        return false;
+    };
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    matches!(prefix.parse::<u8>(), Ok(p) if p <= max)
+}
+
+/// Built-in network types, which private networks may not be named after.
+const NETWORK_TYPES: &[&str] = &["user", "tap", "bridge", "vnic", "macvtap", "none"];
+
+/// Private network names become host bridge names, `vmp-<name>`, limited to 15 bytes.
+const MAX_PRIVATE_NAME_LEN: usize = 11;
+
+/// Check that `s` can name a shell variable: letters, digits and underscores, not starting with
+/// a digit.
+fn is_valid_variable(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Check that `s` is a colon-separated 48-bit MAC address, e.g. `52:54:00:aa:bb:01`.
+fn is_valid_mac(s: &str) -> bool {
+    let parts: Vec<&str> = s.split(':').collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
 // ---------------------------------------------------------------------------
 // Discovery
 // ---------------------------------------------------------------------------
@@ -174,6 +366,29 @@ pub fn parse(path: &Path) -> Result<VmFile> {
         })
         .unwrap_or_else(|| PathBuf::from("."));
 
+    let mut networks: Vec<PrivateNetworkDef> = Vec::new();
+    for node in doc.nodes() {
+        if node.name().to_string() == "network" {
+            let network = parse_network_def(node)?;
+            if let Some(other) = networks.iter().find(|n| n.name == network.name) {
+                return Err(VmError::NetworkInvalid {
+                    detail: format!("network '{}' is declared twice", other.name),
+                    hint: "give each network a unique name".into(),
+                });
+            }
+            if let Some(other) = networks.iter().find(|n| n.subnet.overlaps(&network.subnet)) {
+                return Err(VmError::NetworkInvalid {
+                    detail: format!(
+                        "subnet {} of network '{}' overlaps {} of network '{}'",
+                        network.subnet, network.name, other.subnet, other.name
+                    ),
+                    hint: "give each private network its own subnet".into(),
+                });
+            }
+            networks.push(network);
+        }
+    }
+
     let mut vms = Vec::new();
     let mut seen_names = HashSet::new();
 
@@ -206,7 +421,7 @@ pub fn parse(path: &Path) -> Result<VmFile> {
             hint: "add configuration inside braces: vm \"name\" { ... }".into(),
         })?;
 
-        let vm_def = parse_vm_def(&name, children)?;
+        let vm_def = parse_vm_def(&name, children, &networks)?;
         vms.push(vm_def);
     }
 
@@ -217,10 +432,821 @@ pub fn parse(path: &Path) -> Result<VmFile> {
         });
     }
 
-    Ok(VmFile { base_dir, vms })
+    assign_private_addresses(&mut vms, &networks)?;
+
+    let vmfile = VmFile {
+        base_dir,
+        vms,
+        networks,
+    };
+    vmfile.start_order(None)?;
+
+    let mut static_ips: HashMap<&str, &str> = HashMap::new();
+    let mut ssh_ports: HashMap<u16, &str> = HashMap::new();
+    for vm in &vmfile.vms {
+        if let Some(port) = vm.ssh_host_port {
+            if let Some(other) = ssh_ports.insert(port, &vm.name) {
+                return Err(VmError::VmFileValidation {
+                    vm: vm.name.clone(),
+                    detail: format!("ssh-port {port} is already used by VM '{other}'"),
+                    hint: "give each vm a unique ssh-port, or leave it out to pick a free one"
+                        .into(),
+                });
+            }
+        }
+        if let Some(ref ip) = vm.static_ip {
+            if let Some(other) = static_ips.insert(ip.ip(), &vm.name) {
+                return Err(VmError::VmFileValidation {
+                    vm: vm.name.clone(),
+                    detail: format!("static address {} is already used by VM '{other}'", ip.ip()),
+                    hint: "give each vm a unique address".into(),
+                });
+            }
+        }
+    }
+
+    Ok(vmfile)
+}
+
+impl VmFile {
+    /// Group VMs into startup stages so that every VM comes after the VMs it depends on.
+    ///
+    /// VMs within a stage don't depend on each other and keep their file order. With `only`, the
+    /// result is limited to that VM and its transitive dependencies.
+    pub fn start_order(&self, only: Option<&str>) -> Result<Vec<Vec<&VmDef>>> {
+        let by_name: HashMap<&str, &VmDef> =
+            self.vms.iter().map(|vm| (vm.name.as_str(), vm)).collect();
+
+        for vm in &self.vms {
+            for dep in &vm.depends_on {
+                if dep == &vm.name {
+                    return Err(VmError::VmFileValidation {
+                        vm: vm.name.clone(),
+                        detail: "vm depends on itself".into(),
+                        hint: "remove its own name from depends-on".into(),
+                    });
+                }
+                if !by_name.contains_key(dep.as_str()) {
+                    return Err(VmError::VmFileValidation {
+                        vm: vm.name.clone(),
+                        detail: format!("depends-on references unknown VM '{dep}'"),
+                        hint: "depends-on must name another vm in the same VMFile".into(),
+                    });
+                }
+            }
+        }
+
+        // Restrict to the requested VM and everything it (transitively) needs.
+        let wanted: HashSet<&str> = match only {
+            Some(name) => {
+                let mut wanted = HashSet::new();
+                let mut stack = vec![name];
+                while let Some(n) = stack.pop() {
+                    if let Some(vm) = by_name.get(n) {
+                        if wanted.insert(n) {
+                            stack.extend(vm.depends_on.iter().map(String::as_str));
+                        }
+                    }
+                }
+                wanted
+            }
+            None => by_name.keys().copied().collect(),
+        };
+
+        let mut remaining: Vec<&VmDef> = self
+            .vms
+            .iter()
+            .filter(|vm| wanted.contains(vm.name.as_str()))
+            .collect();
+        let mut started: HashSet<&str> = HashSet::new();
+        let mut stages = Vec::new();
+
+        while !remaining.is_empty() {
+            let (ready, blocked): (Vec<&VmDef>, Vec<&VmDef>) = remaining
+                .into_iter()
+                .partition(|vm| vm.depends_on.iter().all(|d| started.contains(d.as_str())));
+            if ready.is_empty() {
+                return Err(VmError::CircularDependency {
+                    cycle: find_cycle(&blocked),
+                });
+            }
+            started.extend(ready.iter().map(|vm| vm.name.as_str()));
+            stages.push(ready);
+            remaining = blocked;
+        }
+
+        Ok(stages)
+    }
+}
+
+/// Describe one dependency cycle among VMs that could not be ordered, e.g. `a -> b -> a`.
+fn find_cycle(blocked: &[&VmDef]) -> String {
+    let by_name: HashMap<&str, &VmDef> = blocked.iter().map(|vm| (vm.name.as_str(), *vm)).collect();
+
+    // Every blocked VM has a blocked dependency, so following them must eventually revisit one.
+    let mut path: Vec<&str> = Vec::new();
+    let mut current = blocked[0].name.as_str();
+    loop {
+        if let Some(pos) = path.iter().position(|n| *n == current) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.push(current);
+            return cycle.join(" -> ");
+        }
+        path.push(current);
+        current = by_name[current]
+            .depends_on
+            .iter()
+            .map(String::as_str)
+            .find(|d| by_name.contains_key(d))
+            .expect("blocked VM has a blocked dependency");
+    }
+}
+
+/// Parse a top-level `network "name" { type "private"; subnet "10.99.0.0/24" }` node.
+fn parse_network_def(node: &kdl::KdlNode) -> Result<PrivateNetworkDef> {
+    let invalid = |detail: String, hint: &str| VmError::NetworkInvalid {
+        detail,
+        hint: hint.into(),
+    };
+    let example =
+        "declare it as: network \"cluster\" { type \"private\"; subnet \"10.99.0.0/24\" }";
+    let name = node
+        .get(0)
+        .and_then(|v| v.as_string())
+        .ok_or_else(|| invalid("top-level network node must have a name".into(), example))?
+        .to_string();
+    if NETWORK_TYPES.contains(&name.as_str()) {
+        return Err(invalid(
+            format!("network name '{name}' is reserved for a network type"),
+            "choose another name for the private network",
+        ));
+    }
+    let valid_name = name.len() <= MAX_PRIVATE_NAME_LEN
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid_name {
+        return Err(invalid(
+            format!("invalid network name: {name}"),
+            &format!(
+                "use at most {MAX_PRIVATE_NAME_LEN} lowercase letters, digits and dashes, starting with a letter (the host bridge is named vmp-<name>)"
+            ),
+        ));
+    }
+
+    let body = node
+        .children()
+        .ok_or_else(|| invalid(format!("network '{name}' must have a body"), example))?;
+    match body.get_arg("type").and_then(|v| v.as_string()) {
+        Some("private") => {}
+        Some(other) => {
+            return Err(invalid(
+                format!("network '{name}' has unknown type: {other}"),
+                "only type \"private\" networks can be declared in a VMFile",
+            ));
+        }
+        None => {
+            return Err(invalid(
+                format!("network '{name}' has no type"),
+                "add: type \"private\"",
+            ));
+        }
+    }
+    let subnet = body
+        .get_arg("subnet")
+        .and_then(|v| v.as_string())
+        .ok_or_else(|| {
+            invalid(
+                format!("network '{name}' has no subnet"),
+                "add: subnet \"10.99.0.0/24\"",
+            )
+        })?;
+    let subnet = Subnet::parse(subnet)?;
+    Ok(PrivateNetworkDef { name, subnet })
+}
+
+/// Give every private network NIC without an explicit address the next free address of its
+/// subnet, in VMFile order, so addresses stay the same from one `vmctl up` to the next.
+fn assign_private_addresses(vms: &mut [VmDef], networks: &[PrivateNetworkDef]) -> Result<()> {
+    for network in networks {
+        let mut taken: HashMap<Ipv4Addr, String> = HashMap::new();
+        for vm in vms.iter() {
+            for nic in vm
+                .private_networks
+                .iter()
+                .filter(|n| n.network == network.name)
+            {
+                if nic.address.is_empty() {
+                    continue;
+                }
+                let ip: Ipv4Addr = nic.address.parse().map_err(|_| VmError::VmFileValidation {
+                    vm: vm.name.clone(),
+                    detail: format!(
+                        "invalid address on network '{}': {}",
+                        network.name, nic.address
+                    ),
+                    hint: "use a plain IPv4 address, e.g. address=\"10.99.0.10\"".into(),
+                })?;
+                if !network.subnet.hosts().any(|h| h == ip) {
+                    return Err(VmError::VmFileValidation {
+                        vm: vm.name.clone(),
+                        detail: format!("address {ip} is not a host address in {}", network.subnet),
+                        hint: format!("pick an address inside network '{}'", network.name),
+                    });
+                }
+                if let Some(other) = taken.insert(ip, vm.name.clone()) {
+                    return Err(VmError::VmFileValidation {
+                        vm: vm.name.clone(),
+                        detail: format!("address {ip} is already used by VM '{other}'"),
+                        hint: "give each vm a unique address".into(),
+                    });
+                }
+            }
+        }
+
+        let mut free = network.subnet.hosts().filter(|ip| !taken.contains_key(ip));
+        for vm in vms.iter_mut() {
+            for nic in vm
+                .private_networks
+                .iter_mut()
+                .filter(|n| n.network == network.name)
+            {
+                let ip = if nic.address.is_empty() {
+                    free.next().ok_or_else(|| VmError::VmFileValidation {
+                        vm: vm.name.clone(),
+                        detail: format!("no free address left in network '{}'", network.name),
+                        hint: "use a larger subnet".into(),
+                    })?
+                } else {
+                    nic.address.parse().expect("validated above")
+                };
+                nic.address = format!("{ip}/{}", network.subnet.prefix);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check a provision step name: it must be unique within the VM and usable in
+/// `vmctl provision --only`, which splits on commas and accepts step numbers.
+fn validate_step_name(vm: &str, step_name: &str, earlier: &[ProvisionDef]) -> Result<()> {
+    let invalid = |detail: String| VmError::VmFileValidation {
+        vm: vm.into(),
+        detail,
+        hint: "use a short name such as name \"install-deps\"".into(),
+    };
+    if step_name.is_empty() || step_name.contains(',') || step_name.contains(char::is_whitespace) {
+        return Err(invalid(format!(
+            "invalid provision step name '{step_name}': it must be non-empty without commas or spaces"
+        )));
+    }
+    if step_name.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid(format!(
+            "invalid provision step name '{step_name}': numbers are reserved for unnamed steps"
+        )));
+    }
+    if earlier.iter().any(|p| p.name() == Some(step_name)) {
+        return Err(VmError::VmFileValidation {
+            vm: vm.into(),
+            detail: format!("duplicate provision step name '{step_name}'"),
+            hint: "give each provision step a unique name".into(),
+        });
+    }
+    Ok(())
+}
+
+/// Parse one `provision` node. `earlier` holds the VM's preceding steps, for name checks.
+pub(crate) fn parse_provision(
+    vm: &str,
+    node: &kdl::KdlNode,
+    earlier: &[ProvisionDef],
+) -> Result<ProvisionDef> {
+    let ptype = node.get(0).and_then(|v| v.as_string()).unwrap_or("shell");
+
+    let prov_doc = node.children().ok_or_else(|| VmError::VmFileValidation {
+        vm: vm.into(),
+        detail: "provision block must have a body".into(),
+        hint: "add content inside: provision \"shell\" { inline \"...\" }".into(),
+    })?;
+
+    let step_name = prov_doc
+        .get_arg("name")
+        .and_then(|v| v.as_string())
+        .map(String::from);
+    if let Some(ref step_name) = step_name {
+        validate_step_name(vm, step_name, earlier)?;
+    }
+
+    match ptype {
+        "shell" => {
+            let inline = prov_doc
+                .get_arg("inline")
+                .and_then(|v| v.as_string())
+                .map(String::from);
+            let script = prov_doc
+                .get_arg("script")
+                .and_then(|v| v.as_string())
+                .map(String::from);
+
+            if inline.is_none() && script.is_none() {
+                return Err(VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: "shell provision requires inline or script".into(),
+                    hint: "add: inline \"command\" or script \"./setup.sh\"".into(),
+                });
+            }
+            if inline.is_some() && script.is_some() {
+                return Err(VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: "shell provision cannot have both inline and script".into(),
+                    hint: "use either inline or script, not both".into(),
+                });
+            }
+
+            let capture = prov_doc
+                .get_arg("capture")
+                .and_then(|v| v.as_string())
+                .map(String::from);
+            if let Some(ref var) = capture {
+                if !is_valid_variable(var) {
+                    return Err(VmError::VmFileValidation {
+                        vm: vm.into(),
+                        detail: format!("invalid capture variable name: {var}"),
+                        hint: "use letters, digits and underscores, not starting with a digit, e.g. capture \"service_url\"".into(),
+                    });
+                }
+            }
+
+            let sudo = match prov_doc.get_arg("sudo") {
+                None => false,
+                Some(v) => v.as_bool().ok_or_else(|| VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: format!("invalid sudo: {v}"),
+                    hint: "use sudo #true or sudo #false".into(),
+                })?,
+            };
+
+            Ok(ProvisionDef::Shell(ShellProvision {
+                name: step_name,
+                inline,
+                script,
+                capture,
+                sudo,
+            }))
+        }
+        "file" => {
+            let source = prov_doc
+                .get_arg("source")
+                .and_then(|v| v.as_string())
+                .ok_or_else(|| VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: "file provision requires source".into(),
+                    hint: "add: source \"./local-file.conf\"".into(),
+                })?
+                .to_string();
+            let destination = prov_doc
+                .get_arg("destination")
+                .and_then(|v| v.as_string())
+                .ok_or_else(|| VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: "file provision requires destination".into(),
+                    hint: "add: destination \"/etc/app/config.conf\"".into(),
+                })?
+                .to_string();
+            Ok(ProvisionDef::File(FileProvision {
+                name: step_name,
+                source,
+                destination,
+            }))
+        }
+        "loop" => {
+            let items = prov_doc
+                .get("items")
+                .ok_or_else(|| VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: "loop provision requires items".into(),
+                    hint: "add: items \"nginx\" \"curl\"".into(),
+                })?
+                .entries()
+                .iter()
+                .filter(|e| e.name().is_none())
+                .filter_map(|e| e.value().as_string().map(String::from))
+                .collect();
+
+            let inner: Vec<_> = prov_doc
+                .nodes()
+                .iter()
+                .filter(|n| n.name().to_string() == "provision")
+                .collect();
+            let [inner] = inner.as_slice() else {
+                return Err(VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: "loop provision requires exactly one inner provision step".into(),
+                    hint: "add: provision \"shell\" { inline \"... ${item}\" }".into(),
+                });
+            };
+            let step = parse_provision(vm, inner, &[])?;
+            if matches!(step, ProvisionDef::Loop(_)) || step.name().is_some() {
+                return Err(VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: "the step inside a loop provision cannot be a loop or have a name"
+                        .into(),
+                    hint: "name the loop itself instead: provision \"loop\" { name \"...\" }"
+                        .into(),
+                });
+            }
+
+            Ok(ProvisionDef::Loop(LoopProvision {
+                name: step_name,
+                items,
+                step: Box::new(step),
+            }))
+        }
+        "download" => {
+            let required = |key: &str, hint: &str| {
+                prov_doc
+                    .get_arg(key)
+                    .and_then(|v| v.as_string())
+                    .map(String::from)
+                    .ok_or_else(|| VmError::VmFileValidation {
+                        vm: vm.into(),
+                        detail: format!("download provision requires {key}"),
+                        hint: hint.into(),
+                    })
+            };
+            let url = required("url", "add: url \"https://example.com/tool.tar.gz\"")?;
+            let destination = required("destination", "add: destination \"/tmp/tool.tar.gz\"")?;
+            let checksum = prov_doc
+                .get_arg("checksum")
+                .and_then(|v| v.as_string())
+                .map(String::from);
+            if let Some(ref checksum) = checksum {
+                let valid = checksum.strip_prefix("sha256:").is_some_and(|hex| {
+                    hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())
+                });
+                if !valid {
+                    return Err(VmError::VmFileValidation {
+                        vm: vm.into(),
+                        detail: format!("invalid download checksum: {checksum}"),
+                        hint: "use checksum \"sha256:<64 hex digits>\"".into(),
+                    });
+                }
+            }
+            Ok(ProvisionDef::Download(DownloadProvision {
+                name: step_name,
+                url,
+                destination,
+                checksum,
+            }))
+        }
+        "puppet" => {
+            let manifest = prov_doc
+                .get_arg("manifest")
+                .and_then(|v| v.as_string())
+                .ok_or_else(|| VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: "puppet provision requires manifest".into(),
+                    hint: "add: manifest \"./puppet/site.pp\"".into(),
+                })?
+                .to_string();
+            if !manifest.ends_with(".pp") {
+                return Err(VmError::VmFileValidation {
+                    vm: vm.into(),
+                    detail: format!("puppet manifest must be a .pp file: {manifest}"),
+                    hint: "point manifest at a single manifest file, e.g. manifest \"./puppet/site.pp\"".into(),
+                });
+            }
+
+            let module_paths = prov_doc
+                .nodes()
+                .iter()
+                .filter(|n| n.name().value() == "module-path")
+                .flat_map(|n| n.entries())
+                .filter(|e| e.name().is_none())
+                .filter_map(|e| e.value().as_string().map(String::from))
+                .collect();
+
+            let mut facts = HashMap::new();
+            if let Some(body) = prov_doc.get("facts").and_then(|n| n.children()) {
+                for fact in body.nodes() {
+                    let key = fact.name().value().to_string();
+                    if !is_valid_variable(&key) {
+                        return Err(VmError::VmFileValidation {
+                            vm: vm.into(),
+                            detail: format!("invalid fact name: {key}"),
+                            hint: "use letters, digits and underscores, not starting with a digit, e.g. role \"web\"".into(),
+                        });
+                    }
+                    let value = fact
+                        .get(0)
+                        .and_then(|v| {
+                            v.as_string()
+                                .map(String::from)
+                                .or_else(|| v.as_integer().map(|n| n.to_string()))
+                        })
+                        .ok_or_else(|| VmError::VmFileValidation {
+                            vm: vm.into(),
+                            detail: format!("fact {key} requires a value"),
+                            hint: "give each fact a string or number, e.g. role \"web\"".into(),
+                        })?;
+                    facts.insert(key, value);
+                }
+            }
+
+            Ok(ProvisionDef::Puppet(PuppetProvision {
+                name: step_name,
+                manifest,
+                module_paths,
+                facts,
+            }))
+        }
+        other => Err(VmError::VmFileValidation {
+            vm: vm.into(),
+            detail: format!("unknown provision type: {other}"),
+            hint: "use \"shell\", \"file\", \"download\", \"puppet\" or \"loop\"".into(),
+        }),
+    }
+}
+
+/// Overlay tuning from the properties of a `disk` node:
+/// `disk 20 preallocation="metadata" cluster-size="2M" lazy-refcounts=#true`.
+fn parse_overlay_options(vm: &str, node: &kdl::KdlNode) -> Result<OverlayOptions> {
+    let invalid = |detail: String, hint: &str| VmError::VmFileValidation {
+        vm: vm.into(),
+        detail,
+        hint: hint.into(),
+    };
+    let preallocation = node
+        .get("preallocation")
+        .map(|v| {
+            v.as_string()
+                .ok_or_else(|| "preallocation must be a string".to_string())
+                .and_then(str::parse)
+                .map_err(|e| invalid(e, "use \"metadata\", \"falloc\" or \"full\""))
+        })
+        .transpose()?;
+    let cluster_size = node
+        .get("cluster-size")
+        .map(|v| {
+            let size = v.as_string().and_then(crate::image::parse_size);
+            match size {
+                Some(n) if n.is_power_of_two() && (512..=2 << 20).contains(&n) => Ok(n),
+                _ => Err(invalid(
+                    format!("invalid cluster-size: {v}"),
+                    "use a power of two from \"512\" to \"2M\", e.g. \"64k\" or \"2M\"",
+                )),
+            }
+        })
+        .transpose()?;
+    let lazy_refcounts = match node.get("lazy-refcounts") {
+        None => false,
+        Some(v) => v.as_bool().ok_or_else(|| {
+            invalid(
+                format!("invalid lazy-refcounts: {v}"),
+                "use lazy-refcounts=#true or lazy-refcounts=#false",
+            )
+        })?,
+    };
+    Ok(OverlayOptions {
+        preallocation,
+        cluster_size,
+        lazy_refcounts,
+    })
+}
+
+/// Pre-provision snapshots from `snapshot-before-provision #true keep=3`.
+/// A bare node enables them; `#false` turns them off.
+fn parse_provision_snapshot(vm: &str, node: &kdl::KdlNode) -> Result<Option<ProvisionSnapshotDef>> {
+    let invalid = |detail: String| VmError::VmFileValidation {
+        vm: vm.into(),
+        detail,
+        hint: "use snapshot-before-provision #true, optionally with keep=N".into(),
+    };
+    let enabled = match node.get(0) {
+        None => true,
+        Some(v) => v
+            .as_bool()
+            .ok_or_else(|| invalid(format!("invalid snapshot-before-provision: {v}")))?,
+    };
+    let keep = match node.get("keep") {
+        None => None,
+        Some(v) => match v.as_integer() {
+            Some(n) if n >= 0 => Some(n as usize),
+            _ => {
+                return Err(invalid(format!(
+                    "invalid snapshot-before-provision keep: {v}"
+                )));
+            }
+        },
+    };
+    Ok(enabled.then_some(ProvisionSnapshotDef { keep }))
+}
+
+/// Secure Boot from `secure-boot #true vars="OVMF_VARS.custom.fd"`. A bare node enables it;
+/// `#false` turns it off.
+fn parse_secure_boot(vm: &str, node: &kdl::KdlNode) -> Result<(bool, Option<String>)> {
+    let invalid = |detail: String| VmError::VmFileValidation {
+        vm: vm.into(),
+        detail,
+        hint: "use secure-boot #true, optionally with vars=\"path/to/OVMF_VARS.fd\"".into(),
+    };
+    let enabled = match node.get(0) {
+        None => true,
+        Some(v) => v
+            .as_bool()
+            .ok_or_else(|| invalid(format!("invalid secure-boot: {v}")))?,
+    };
+    let vars = match node.get("vars") {
+        None => None,
+        Some(v) => Some(
+            v.as_string()
+                .ok_or_else(|| invalid(format!("invalid secure-boot vars: {v}")))?
+                .to_string(),
+        ),
+    };
+    Ok((enabled, vars.filter(|_| enabled)))
+}
+
+/// CPU pinning from a `cpu-pinning` node: `cpu-pinning "4-7"` for every vCPU, or a block of
+/// `cpuset "4-7"`, `vcpu 0 host=4` and `emulator "0-1"` nodes. Whether the host has the CPUs is
+/// checked by `resolve`.
+fn parse_cpu_pinning(vm: &str, node: &kdl::KdlNode, vcpus: u16) -> Result<CpuPinning> {
+    let invalid = |detail: String| VmError::VmFileValidation {
+        vm: vm.into(),
+        detail,
+        hint: "use cpu-pinning \"4-7\", or a block of cpuset, vcpu and emulator nodes".into(),
+    };
+    let cpu_list = |value: Option<&kdl::KdlValue>, what: &str| -> Result<Vec<usize>> {
+        let list = value
+            .and_then(|v| v.as_string())
+            .ok_or_else(|| invalid(format!("{what} requires a CPU list such as \"4-7\"")))?;
+        pinning::parse_cpuset(list).map_err(|e| invalid(format!("invalid {what}: {e}")))
+    };
+    let mut cpu_pinning = CpuPinning::default();
+    match (node.get(0), node.children()) {
+        (Some(list), None) => cpu_pinning.cpuset = cpu_list(Some(list), "cpu-pinning")?,
+        (None, Some(body)) => {
+            for child in body.nodes() {
+                match child.name().to_string().as_str() {
+                    "cpuset" => cpu_pinning.cpuset = cpu_list(child.get(0), "cpuset")?,
+                    "emulator" => cpu_pinning.emulator = cpu_list(child.get(0), "emulator")?,
+                    "vcpu" => {
+                        let vcpu = child
+                            .get(0)
+                            .and_then(|v| v.as_integer())
+                            .and_then(|n| u16::try_from(n).ok())
+                            .ok_or_else(|| invalid("vcpu requires a vCPU index".into()))?;
+                        let host = child
+                            .get("host")
+                            .and_then(|v| v.as_integer())
+                            .and_then(|n| usize::try_from(n).ok())
+                            .filter(|&cpu| cpu < pinning::MAX_CPUS)
+                            .ok_or_else(|| invalid(format!("vcpu {vcpu} requires host=<CPU>")))?;
+                        if cpu_pinning.vcpus.insert(vcpu, host).is_some() {
+                            return Err(invalid(format!("vCPU {vcpu} is pinned twice")));
+                        }
+                    }
+                    other => return Err(invalid(format!("unknown cpu-pinning setting: {other}"))),
+                }
+            }
+        }
+        _ => return Err(invalid("cpu-pinning takes a CPU list or a block".into())),
+    }
+    if let Some(vcpu) = cpu_pinning.vcpus.keys().find(|&&v| v >= vcpus) {
+        return Err(invalid(format!(
+            "vCPU {vcpu} is pinned, but the VM has {vcpus} vCPUs (numbered from 0)"
+        )));
+    }
+    Ok(cpu_pinning)
+}
+
+/// Guest NUMA nodes from a `numa` block of `node cpus="0-3" memory=4096` nodes, numbered in
+/// order. Together the nodes must hold every vCPU once and all of the VM's memory.
+fn parse_numa(vm: &str, node: &kdl::KdlNode, vcpus: u16, memory_mb: u64) -> Result<Vec<NumaNode>> {
+    let invalid = |detail: String| VmError::VmFileValidation {
+        vm: vm.into(),
+        detail,
+        hint: "use a numa block of node cpus=\"0-3\" memory=4096 nodes".into(),
+    };
+    let body = node
+        .children()
+        .ok_or_else(|| invalid("numa block must have a body".into()))?;
+    let mut nodes = Vec::new();
+    for child in body.nodes() {
+        if child.name().value() != "node" {
+            return Err(invalid(format!("unknown numa setting: {}", child.name())));
+        }
+        let i = nodes.len();
+        let list = child
+            .get("cpus")
+            .and_then(|v| v.as_string())
+            .ok_or_else(|| invalid(format!("NUMA node {i} requires cpus=\"<vCPUs>\"")))?;
+        let cpus = pinning::parse_cpuset(list)
+            .map_err(|e| invalid(format!("invalid cpus of NUMA node {i}: {e}")))?
+            .into_iter()
+            .map(|cpu| u16::try_from(cpu).unwrap_or(u16::MAX))
+            .collect();
+        let memory_mb = child
+            .get("memory")
+            .and_then(|v| v.as_integer())
+            .and_then(|n| u64::try_from(n).ok())
+            .ok_or_else(|| invalid(format!("NUMA node {i} requires memory=<MB>")))?;
+        nodes.push(NumaNode { cpus, memory_mb });
+    }
+    crate::types::check_numa_layout(&nodes, vcpus, memory_mb).map_err(invalid)?;
+    Ok(nodes)
+}
+
+/// Entropy device from an `rng` node: `rng "none"` or
+/// `rng "virtio" max-bytes=1024 period=1000`.
+fn parse_rng(vm: &str, node: &kdl::KdlNode) -> Result<RngConfig> {
+    let invalid = |detail: String| VmError::VmFileValidation {
+        vm: vm.into(),
+        detail,
+        hint: "use rng \"none\" or rng \"virtio\" max-bytes=1024 period=1000".into(),
+    };
+    let limit = |key: &str| -> Result<Option<i128>> {
+        match node.get(key) {
+            None => Ok(None),
+            Some(v) => match v.as_integer() {
+                Some(n) if n > 0 => Ok(Some(n)),
+                _ => Err(invalid(format!("invalid rng {key}: {v}"))),
+            },
+        }
+    };
+    match node.get(0).and_then(|v| v.as_string()) {
+        Some("none") => Ok(RngConfig::None),
+        Some("virtio") => {
+            let max_bytes = limit("max-bytes")?.map(|n| n as u64);
+            let period_ms = limit("period")?.map(|n| n as u32);
+            if period_ms.is_some() && max_bytes.is_none() {
+                return Err(invalid("rng period requires max-bytes".into()));
+            }
+            Ok(RngConfig::VirtioRng {
+                max_bytes,
+                period_ms,
+            })
+        }
+        Some(other) => Err(invalid(format!("unknown rng device: {other}"))),
+        None => Err(invalid("rng requires a device type".into())),
+    }
+}
+
+/// SMBIOS strings from an `smbios` block of `manufacturer`, `product`, `serial`, `uuid` and
+/// `family` nodes, repeatable `oem` nodes and `identify #true`.
+fn parse_smbios(vm: &str, node: &kdl::KdlNode) -> Result<SmbiosConfig> {
+    let invalid = |detail: String| {
+        VmError::VmFileValidation {
+        vm: vm.into(),
+        detail,
+        hint: "use an smbios block of manufacturer, product, serial, uuid, family, oem and identify nodes".into(),
+    }
+    };
+    let body = node
+        .children()
+        .ok_or_else(|| invalid("smbios block must have a body".into()))?;
+    let mut smbios = SmbiosConfig::default();
+    for child in body.nodes() {
+        let key = child.name().value();
+        let string = || {
+            child
+                .get(0)
+                .and_then(|v| v.as_string())
+                .map(String::from)
+                .ok_or_else(|| invalid(format!("smbios {key} requires a string")))
+        };
+        match key {
+            "manufacturer" => smbios.manufacturer = Some(string()?),
+            "product" => smbios.product = Some(string()?),
+            "serial" => smbios.serial = Some(string()?),
+            "uuid" => smbios.uuid = Some(string()?),
+            "family" => smbios.family = Some(string()?),
+            "oem" => {
+                for entry in child.entries().iter().filter(|e| e.name().is_none()) {
+                    let value = entry
+                        .value()
+                        .as_string()
+                        .ok_or_else(|| invalid("smbios oem requires strings".into()))?;
+                    smbios.oem_strings.push(value.into());
+                }
+            }
+            "identify" => {
+                smbios.identify = match child.get(0) {
+                    None => true,
+                    Some(v) => v
+                        .as_bool()
+                        .ok_or_else(|| invalid(format!("invalid smbios identify: {v}")))?,
+                };
+            }
+            other => return Err(invalid(format!("unknown smbios setting: {other}"))),
+        }
+    }
+    smbios.check().map_err(invalid)?;
+    Ok(smbios)
 }
 
-fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
+fn parse_vm_def(name: &str, doc: &KdlDocument, networks: &[PrivateNetworkDef]) -> Result<VmDef> {
     // Image: local or URL
     let local_image = doc
         .get_arg("image")
@@ -267,49 +1293,545 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
         .get_arg("disk")
         .and_then(|v| v.as_integer())
         .map(|v| v as u32);
+    let overlay = match doc.get("disk") {
+        Some(node) => parse_overlay_options(name, node)?,
+        None => OverlayOptions::default(),
+    };
 
-    // Network
-    let network = if let Some(net_node) = doc.get("network") {
-        let net_type = net_node
-            .get(0)
-            .and_then(|v| v.as_string())
-            .unwrap_or("user");
-        match net_type {
-            "user" => NetworkDef::User,
-            "tap" => {
-                let bridge = net_node
-                    .get("bridge")
-                    .and_then(|v| v.as_string())
-                    .unwrap_or("br0")
-                    .to_string();
-                NetworkDef::Tap { bridge }
-            }
-            "vnic" => {
-                let vnic_name = net_node
-                    .get("name")
-                    .and_then(|v| v.as_string())
-                    .ok_or_else(|| VmError::VmFileValidation {
-                        vm: name.into(),
-                        detail: "vnic network requires a name".into(),
-                        hint: "add a name: network \"vnic\" name=\"vnic0\"".into(),
-                    })?
-                    .to_string();
-                NetworkDef::Vnic { name: vnic_name }
-            }
-            "none" => NetworkDef::None,
-            other => {
-                return Err(VmError::VmFileValidation {
-                    vm: name.into(),
-                    detail: format!("unknown network type: {other}"),
-                    hint: "use \"user\", \"tap\", \"vnic\", or \"none\"".into(),
-                });
-            }
+    // Machine type and direct kernel boot
+    let machine = match doc.get_arg("machine").and_then(|v| v.as_string()) {
+        None | Some("q35") => MachineType::Q35,
+        Some("microvm") => MachineType::Microvm,
+        Some(other) => {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: format!("unknown machine type: {other}"),
+                hint: "use \"q35\" or \"microvm\"".into(),
+            });
         }
-    } else {
-        NetworkDef::default()
     };
 
-    // Cloud-init
+    let kernel = if let Some(node) = doc.get("kernel") {
+        let path =
+            node.get(0)
+                .and_then(|v| v.as_string())
+                .ok_or_else(|| VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: "kernel requires a path".into(),
+                    hint: "add a path: kernel \"vmlinuz\" initrd=\"initrd.img\"".into(),
+                })?;
+        let arg = |key: &str| node.get(key).and_then(|v| v.as_string()).map(String::from);
+        Some(KernelDef {
+            kernel: path.to_string(),
+            initrd: arg("initrd"),
+            cmdline: arg("cmdline"),
+        })
+    } else {
+        None
+    };
+    if machine == MachineType::Microvm && kernel.is_none() {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: "the microvm machine type requires direct kernel boot".into(),
+            hint: "add a kernel: kernel \"vmlinuz\" initrd=\"initrd.img\"".into(),
+        });
+    }
+    let cdrom = doc
+        .get_arg("cdrom")
+        .and_then(|v| v.as_string())
+        .map(String::from);
+    let random_seed = match doc.get("rng") {
+        Some(node) => parse_rng(name, node)?,
+        None => RngConfig::default(),
+    };
+
+    // Extra QEMU arguments: extra-arg "-device" "virtio-balloon-pci"
+    let mut qemu_args = Vec::new();
+    for node in doc
+        .nodes()
+        .iter()
+        .filter(|n| n.name().to_string() == "extra-arg")
+    {
+        let args: Vec<String> = node
+            .entries()
+            .iter()
+            .filter(|e| e.name().is_none())
+            .filter_map(|e| e.value().as_string().map(String::from))
+            .collect();
+        if args.is_empty() {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "extra-arg requires at least one string argument".into(),
+                hint: "pass the QEMU arguments as strings: extra-arg \"-device\" \"virtio-balloon-pci\"".into(),
+            });
+        }
+        qemu_args.extend(args);
+    }
+    if let Some(arg) = managed_qemu_option(&qemu_args) {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: format!("extra-arg {arg} conflicts with an option vmctl manages"),
+            hint: "vmctl sets -qmp, -pidfile and -daemonize itself; remove the extra-arg".into(),
+        });
+    }
+
+    // Restart policy: restart-policy "on-failure"
+    let restart_policy = match doc.get("restart-policy") {
+        None => RestartPolicy::No,
+        Some(node) => node
+            .get(0)
+            .and_then(|v| v.as_string())
+            .ok_or_else(|| "restart-policy requires a policy name".to_string())
+            .and_then(str::parse)
+            .map_err(|detail| VmError::VmFileValidation {
+                vm: name.into(),
+                detail,
+                hint: "use restart-policy \"no\", \"on-failure\" or \"always\"".into(),
+            })?,
+    };
+
+    // Guest OS and disk bus: guest-os "windows", disk-bus "virtio"
+    let guest_os = match doc.get("guest-os") {
+        None => GuestOs::Linux,
+        Some(node) => node
+            .get(0)
+            .and_then(|v| v.as_string())
+            .ok_or_else(|| "guest-os requires a name".to_string())
+            .and_then(str::parse)
+            .map_err(|detail| VmError::VmFileValidation {
+                vm: name.into(),
+                detail,
+                hint: "use guest-os \"linux\" or \"windows\"".into(),
+            })?,
+    };
+    // Where the display is shown: display "gtk"
+    let display = match doc.get("display") {
+        None => DisplayMode::Vnc,
+        Some(node) => node
+            .get(0)
+            .and_then(|v| v.as_string())
+            .ok_or_else(|| "display requires a name".to_string())
+            .and_then(str::parse)
+            .map_err(|detail| VmError::VmFileValidation {
+                vm: name.into(),
+                detail,
+                hint: "use display \"vnc\", \"gtk\", \"sdl\" or \"none\"".into(),
+            })?,
+    };
+    if machine == MachineType::Microvm && display.is_local() {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: "the microvm machine type has no display adapter for a local window".into(),
+            hint: "use display \"none\" or machine \"q35\"".into(),
+        });
+    }
+    // Sound card and host audio backend: audio "pipewire"
+    let audio = match doc.get("audio") {
+        None => AudioBackend::None,
+        Some(node) => node
+            .get(0)
+            .and_then(|v| v.as_string())
+            .ok_or_else(|| "audio requires a backend name".to_string())
+            .and_then(str::parse)
+            .map_err(|detail| VmError::VmFileValidation {
+                vm: name.into(),
+                detail,
+                hint: "use audio \"pipewire\", \"pa\" or \"none\"".into(),
+            })?,
+    };
+    if machine == MachineType::Microvm && audio != AudioBackend::None {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: "the microvm machine type has no sound card".into(),
+            hint: "use audio \"none\" or machine \"q35\"".into(),
+        });
+    }
+    let smbios = match doc.get("smbios") {
+        None => SmbiosConfig::default(),
+        Some(node) => parse_smbios(name, node)?,
+    };
+    if machine == MachineType::Microvm && !smbios.is_empty() {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: "the microvm machine type has no SMBIOS tables".into(),
+            hint: "remove the smbios block or use machine \"q35\"".into(),
+        });
+    }
+    // The root disk's bus: disk-bus "nvme", or the disk node's interface="nvme"
+    let bus_node = doc.get("disk-bus").map(|node| (node.get(0), "disk-bus"));
+    let interface = doc
+        .get("disk")
+        .and_then(|node| node.get("interface"))
+        .map(|v| (Some(v), "interface"));
+    let disk_bus = match (bus_node, interface) {
+        (Some(_), Some(_)) => {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "both disk-bus and the disk node's interface specified".into(),
+                hint: "use either disk-bus or interface, not both".into(),
+            });
+        }
+        (Some((value, key)), None) | (None, Some((value, key))) => Some(
+            value
+                .and_then(|v| v.as_string())
+                .ok_or_else(|| format!("{key} requires a bus name"))
+                .and_then(str::parse)
+                .map_err(|detail| VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail,
+                    hint: format!("use {key} \"virtio\", \"nvme\", \"scsi\" or \"sata\""),
+                })?,
+        ),
+        (None, None) => None,
+    };
+    if machine == MachineType::Microvm && matches!(disk_bus, Some(DiskBus::Sata | DiskBus::Nvme)) {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: format!(
+                "the microvm machine type has no {} controller",
+                if disk_bus == Some(DiskBus::Sata) {
+                    "SATA"
+                } else {
+                    "NVMe"
+                }
+            ),
+            hint: "use \"virtio\" or \"scsi\", or machine \"q35\"".into(),
+        });
+    }
+    let drivers_iso = doc
+        .get_arg("drivers-iso")
+        .and_then(|v| v.as_string())
+        .map(String::from);
+    let (secure_boot, secure_boot_vars) = match doc.get("secure-boot") {
+        None => (false, None),
+        Some(node) => parse_secure_boot(name, node)?,
+    };
+    if machine == MachineType::Microvm && secure_boot {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: "the microvm machine type does not support UEFI or Secure Boot".into(),
+            hint: "remove secure-boot or use machine \"q35\"".into(),
+        });
+    }
+    // Memory encryption: confidential "sev"
+    let confidential = doc
+        .get("confidential")
+        .map(|node| {
+            node.get(0)
+                .and_then(|v| v.as_string())
+                .ok_or_else(|| "confidential requires a mode".to_string())
+                .and_then(str::parse)
+                .map_err(|detail| VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail,
+                    hint: "use confidential \"sev\"".into(),
+                })
+        })
+        .transpose()?;
+    if confidential.is_some() && (machine == MachineType::Microvm || secure_boot) {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: "confidential guests need the q35 machine type and no Secure Boot".into(),
+            hint: "remove confidential, or remove secure-boot and machine \"microvm\"".into(),
+        });
+    }
+
+    // Host CPU affinity: cpu-pinning "4-7"
+    let cpu_pinning = doc
+        .get("cpu-pinning")
+        .map(|node| parse_cpu_pinning(name, node, vcpus))
+        .transpose()?;
+
+    // Guest topology: numa { node cpus="0-1" memory=2048; ... }
+    let numa = match doc.get("numa") {
+        None => Vec::new(),
+        Some(node) => parse_numa(name, node, vcpus, memory_mb)?,
+    };
+    if machine == MachineType::Microvm && !numa.is_empty() {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: "the microvm machine type has no NUMA support".into(),
+            hint: "remove numa or use machine \"q35\"".into(),
+        });
+    }
+    // Guest memory on huge pages: hugepages #true
+    let hugepages = match doc.get("hugepages") {
+        None => false,
+        Some(node) => match node.get(0) {
+            None => true,
+            Some(v) => v.as_bool().ok_or_else(|| VmError::VmFileValidation {
+                vm: name.into(),
+                detail: format!("invalid hugepages: {v}"),
+                hint: "use hugepages #true or hugepages #false".into(),
+            })?,
+        },
+    };
+
+    // Host USB devices: usb "1050:0407" "3.7"
+    let mut usb_devices: Vec<UsbDevice> = Vec::new();
+    for node in doc.nodes().iter().filter(|n| n.name().to_string() == "usb") {
+        let hint = "use usb \"vendor:product\" (hex, as lsusb shows) or usb \"bus.device\"";
+        let values: Vec<_> = node
+            .entries()
+            .iter()
+            .filter(|e| e.name().is_none())
+            .collect();
+        if values.is_empty() {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "usb requires at least one device".into(),
+                hint: hint.into(),
+            });
+        }
+        for entry in values {
+            let device: UsbDevice = entry
+                .value()
+                .as_string()
+                .ok_or_else(|| format!("invalid USB device: {}", entry.value()))
+                .and_then(str::parse)
+                .map_err(|detail| VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail,
+                    hint: hint.into(),
+                })?;
+            if !usb_devices.contains(&device) {
+                usb_devices.push(device);
+            }
+        }
+    }
+    if machine == MachineType::Microvm && !usb_devices.is_empty() {
+        return Err(VmError::VmFileValidation {
+            vm: name.into(),
+            detail: "the microvm machine type has no USB controller".into(),
+            hint: "remove usb or use machine \"q35\"".into(),
+        });
+    }
+
+    // Network
+    let mut mac = None;
+    let mut static_ip = None;
+    let mut ssh_host_port = None;
+
+    // One primary network node, plus a node for each private network the VM joins
+    let mut private_networks: Vec<PrivateNic> = Vec::new();
+    let mut primary_node = None;
+    for node in doc
+        .nodes()
+        .iter()
+        .filter(|n| n.name().to_string() == "network")
+    {
+        let private = node
+            .get(0)
+            .and_then(|v| v.as_string())
+            .filter(|_| node.get("type").is_none())
+            .filter(|n| networks.iter().any(|p| p.name == *n));
+        match private {
+            Some(network) => {
+                if private_networks.iter().any(|n| n.network == network) {
+                    return Err(VmError::VmFileValidation {
+                        vm: name.into(),
+                        detail: format!("network '{network}' is joined twice"),
+                        hint: "remove the duplicate network node".into(),
+                    });
+                }
+                private_networks.push(PrivateNic {
+                    network: network.to_string(),
+                    // Filled in from the subnet once every VM is parsed
+                    address: node
+                        .get("address")
+                        .and_then(|v| v.as_string())
+                        .unwrap_or_default()
+                        .to_string(),
+                    mac: None,
+                    bridge: None,
+                });
+            }
+            None if primary_node.is_some() => {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: "more than one network node that is not a private network".into(),
+                    hint: "keep one network node for the primary NIC; extra NICs must join a top-level private network".into(),
+                });
+            }
+            None => primary_node = Some(node),
+        }
+    }
+
+    let network = if let Some(net_node) = primary_node {
+        let net_type = net_node
+            .get("type")
+            .or_else(|| net_node.get(0))
+            .and_then(|v| v.as_string())
+            .unwrap_or("user");
+        let network = match net_type {
+            "user" => NetworkDef::User,
+            "tap" | "bridge" => {
+                let bridge = net_node
+                    .get("bridge")
+                    .and_then(|v| v.as_string())
+                    .unwrap_or("br0")
+                    .to_string();
+                NetworkDef::Tap { bridge }
+            }
+            "vnic" => {
+                let vnic_name = net_node
+                    .get("name")
+                    .and_then(|v| v.as_string())
+                    .ok_or_else(|| VmError::VmFileValidation {
+                        vm: name.into(),
+                        detail: "vnic network requires a name".into(),
+                        hint: "add a name: network \"vnic\" name=\"vnic0\"".into(),
+                    })?
+                    .to_string();
+                NetworkDef::Vnic { name: vnic_name }
+            }
+            "macvtap" => {
+                let parent = net_node
+                    .get("parent")
+                    .and_then(|v| v.as_string())
+                    .ok_or_else(|| VmError::VmFileValidation {
+                        vm: name.into(),
+                        detail: "macvtap network requires a parent".into(),
+                        hint: "name the host NIC: network \"macvtap\" parent=\"eth0\"".into(),
+                    })?
+                    .to_string();
+                NetworkDef::Macvtap { parent }
+            }
+            "none" => NetworkDef::None,
+            other => {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("unknown network type: {other}"),
+                    hint: "use \"user\", \"tap\", \"bridge\", \"vnic\", \"macvtap\", \"none\", or the name of a top-level private network".into(),
+                });
+            }
+        };
+
+        if let Some(m) = net_node.get("mac").and_then(|v| v.as_string()) {
+            if !is_valid_mac(m) {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("invalid MAC address: {m}"),
+                    hint: "use six colon-separated hex pairs, e.g. mac=\"52:54:00:aa:bb:01\""
+                        .into(),
+                });
+            }
+            mac = Some(m.to_ascii_lowercase());
+        }
+
+        if let Some(port) = net_node.get("ssh-port") {
+            if !matches!(network, NetworkDef::User) {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("ssh-port is not supported with {net_type} networking"),
+                    hint: "only user-mode networking forwards a host port; connect to the guest's own address instead".into(),
+                });
+            }
+            let port = port
+                .as_integer()
+                .and_then(|p| u16::try_from(p).ok())
+                .filter(|&p| p != 0)
+                .ok_or_else(|| VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("invalid ssh-port: {port}"),
+                    hint: "use a TCP port number, e.g. ssh-port=2222".into(),
+                })?;
+            ssh_host_port = Some(port);
+        }
+
+        if let Some(address) = net_node.get("address").and_then(|v| v.as_string()) {
+            if !matches!(
+                network,
+                NetworkDef::Tap { .. } | NetworkDef::Vnic { .. } | NetworkDef::Macvtap { .. }
+            ) {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("static address is not supported with {net_type} networking"),
+                    hint: "use network type=\"bridge\" to assign a static address".into(),
+                });
+            }
+            if !is_valid_cidr(address) {
+                return Err(VmError::VmFileValidation {
+                    vm: name.into(),
+                    detail: format!("invalid address: {address}"),
+                    hint: "use CIDR notation, e.g. address=\"192.168.100.10/24\"".into(),
+                });
+            }
+            let gateway = net_node
+                .get("gateway")
+                .and_then(|v| v.as_string())
+                .map(String::from);
+            let nameservers: Vec<String> = net_node
+                .get("nameservers")
+                .and_then(|v| v.as_string())
+                .map(|s| {
+                    s.split(',')
+                        .map(|ns| ns.trim().to_string())
+                        .filter(|ns| !ns.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default();
+            for ip in gateway.iter().chain(nameservers.iter()) {
+                if ip.parse::<IpAddr>().is_err() {
+                    return Err(VmError::VmFileValidation {
+                        vm: name.into(),
+                        detail: format!("invalid IP address: {ip}"),
+                        hint: "gateway and nameservers must be plain IP addresses".into(),
+                    });
+                }
+            }
+            static_ip = Some(StaticIpConfig {
+                address: address.to_string(),
+                gateway,
+                nameservers,
+            });
+        } else if net_node.get("gateway").is_some() || net_node.get("nameservers").is_some() {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "gateway and nameservers require a static address".into(),
+                hint: "add address=\"192.168.100.10/24\" to the network node".into(),
+            });
+        }
+
+        network
+    } else {
+        NetworkDef::default()
+    };
+
+    // Boot order: boot-order "cdrom" "disk"
+    let mut boot_order = Vec::new();
+    if let Some(node) = doc.get("boot-order") {
+        let invalid = |detail: String| VmError::VmFileValidation {
+            vm: name.into(),
+            detail,
+            hint: "list boot devices first to last, e.g. boot-order \"cdrom\" \"disk\"".into(),
+        };
+        for entry in node.entries().iter().filter(|e| e.name().is_none()) {
+            let device = entry
+                .value()
+                .as_string()
+                .ok_or_else(|| "boot-order takes device names".to_string())
+                .and_then(str::parse)
+                .map_err(invalid)?;
+            boot_order.push(device);
+        }
+        if boot_order.is_empty() {
+            return Err(invalid("boot-order names no devices".into()));
+        }
+        if machine == MachineType::Microvm || kernel.is_some() {
+            return Err(invalid(
+                "boot-order has no effect with direct kernel boot".into(),
+            ));
+        }
+        check_boot_order(
+            &boot_order,
+            cdrom.is_some(),
+            !matches!(network, NetworkDef::None),
+        )
+        .map_err(invalid)?;
+    }
+
+    // Cloud-init
     let cloud_init = if let Some(ci_node) = doc.get("cloud-init") {
         let ci_doc = ci_node.children();
         let hostname = ci_doc
@@ -324,11 +1846,64 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
             .and_then(|d| d.get_arg("user-data"))
             .and_then(|v| v.as_string())
             .map(String::from);
+        let timezone = ci_doc
+            .and_then(|d| d.get_arg("timezone"))
+            .and_then(|v| v.as_string())
+            .map(String::from);
+        let locale = ci_doc
+            .and_then(|d| d.get_arg("locale"))
+            .and_then(|v| v.as_string())
+            .map(String::from);
+        let ntp_servers: Vec<String> = ci_doc
+            .and_then(|d| d.get("ntp-servers"))
+            .map(|n| {
+                n.entries()
+                    .iter()
+                    .filter(|e| e.name().is_none())
+                    .filter_map(|e| e.value().as_string())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(bad) = ntp_servers
+            .iter()
+            .find(|s| !crate::cloudinit::is_valid_ntp_server(s))
+        {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: format!("invalid NTP server: {bad}"),
+                hint: "use hostnames or IP addresses, e.g. ntp-servers \"time.example.com\" \"pool.ntp.org\"".into(),
+            });
+        }
+        if let Some(bad) = [&timezone, &locale]
+            .into_iter()
+            .flatten()
+            .find(|v| crate::cloudinit::has_control_chars(v))
+        {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: format!("control characters in timezone or locale: {bad:?}"),
+                hint: "use a name such as timezone \"Europe/Berlin\" or locale \"de_DE.UTF-8\""
+                    .into(),
+            });
+        }
+        if user_data.is_some()
+            && (timezone.is_some() || locale.is_some() || !ntp_servers.is_empty())
+        {
+            return Err(VmError::VmFileValidation {
+                vm: name.into(),
+                detail: "timezone, locale and ntp-servers cannot be combined with user-data".into(),
+                hint: "set timezone:, locale: and ntp: in your cloud-config file instead".into(),
+            });
+        }
 
         Some(CloudInitDef {
             hostname,
             ssh_key,
             user_data,
+            timezone,
+            locale,
+            ntp_servers,
         })
     } else {
         None
@@ -363,86 +1938,63 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
         if node.name().to_string() != "provision" {
             continue;
         }
-        let ptype = node.get(0).and_then(|v| v.as_string()).unwrap_or("shell");
-
-        let prov_doc = node.children().ok_or_else(|| VmError::VmFileValidation {
-            vm: name.into(),
-            detail: "provision block must have a body".into(),
-            hint: "add content inside: provision \"shell\" { inline \"...\" }".into(),
-        })?;
-
-        match ptype {
-            "shell" => {
-                let inline = prov_doc
-                    .get_arg("inline")
-                    .and_then(|v| v.as_string())
-                    .map(String::from);
-                let script = prov_doc
-                    .get_arg("script")
-                    .and_then(|v| v.as_string())
-                    .map(String::from);
-
-                if inline.is_none() && script.is_none() {
-                    return Err(VmError::VmFileValidation {
-                        vm: name.into(),
-                        detail: "shell provision requires inline or script".into(),
-                        hint: "add: inline \"command\" or script \"./setup.sh\"".into(),
-                    });
-                }
-                if inline.is_some() && script.is_some() {
-                    return Err(VmError::VmFileValidation {
-                        vm: name.into(),
-                        detail: "shell provision cannot have both inline and script".into(),
-                        hint: "use either inline or script, not both".into(),
-                    });
-                }
-
-                provisions.push(ProvisionDef::Shell(ShellProvision { inline, script }));
-            }
-            "file" => {
-                let source = prov_doc
-                    .get_arg("source")
-                    .and_then(|v| v.as_string())
-                    .ok_or_else(|| VmError::VmFileValidation {
-                        vm: name.into(),
-                        detail: "file provision requires source".into(),
-                        hint: "add: source \"./local-file.conf\"".into(),
-                    })?
-                    .to_string();
-                let destination = prov_doc
-                    .get_arg("destination")
-                    .and_then(|v| v.as_string())
-                    .ok_or_else(|| VmError::VmFileValidation {
-                        vm: name.into(),
-                        detail: "file provision requires destination".into(),
-                        hint: "add: destination \"/etc/app/config.conf\"".into(),
-                    })?
-                    .to_string();
-                provisions.push(ProvisionDef::File(FileProvision {
-                    source,
-                    destination,
-                }));
-            }
-            other => {
-                return Err(VmError::VmFileValidation {
-                    vm: name.into(),
-                    detail: format!("unknown provision type: {other}"),
-                    hint: "use \"shell\" or \"file\"".into(),
-                });
-            }
-        }
+        let prov = parse_provision(name, node, &provisions)?;
+        provisions.push(prov);
     }
 
+    let provision_snapshot = doc
+        .get("snapshot-before-provision")
+        .map(|node| parse_provision_snapshot(name, node))
+        .transpose()?
+        .flatten();
+
+    // Dependencies: depends-on "db" "cache"
+    let depends_on = doc
+        .nodes()
+        .iter()
+        .filter(|n| n.name().to_string() == "depends-on")
+        .flat_map(|n| n.entries().iter())
+        .filter(|e| e.name().is_none())
+        .filter_map(|e| e.value().as_string().map(String::from))
+        .collect();
+
     Ok(VmDef {
         name: name.to_string(),
         image,
         vcpus,
         memory_mb,
         disk_gb,
+        overlay,
+        machine,
+        kernel,
+        cdrom,
+        boot_order,
+        random_seed,
+        qemu_args,
+        restart_policy,
+        guest_os,
+        display,
+        audio,
+        smbios,
+        disk_bus,
+        drivers_iso,
+        secure_boot,
+        secure_boot_vars,
+        confidential,
+        cpu_pinning,
+        numa,
+        hugepages,
+        usb_devices,
         network,
+        mac,
+        static_ip,
+        ssh_host_port,
+        private_networks,
         cloud_init,
         ssh,
         provisions,
+        provision_snapshot,
+        depends_on,
     })
 }
 
@@ -450,8 +2002,9 @@ fn parse_vm_def(name: &str, doc: &KdlDocument) -> Result<VmDef> {
 // Resolve: VmDef -> VmSpec
 // ---------------------------------------------------------------------------
 
-/// Resolve a `VmDef` into a ready-to-use `VmSpec` by downloading images, reading keys, etc.
-pub async fn resolve(def: &VmDef, base_dir: &Path) -> Result<VmSpec> {
+/// Resolve a `VmDef` into a ready-to-use `VmSpec` by downloading images (into `images`' cache),
+/// reading keys, etc.
+pub async fn resolve(def: &VmDef, base_dir: &Path, images: &ImageManager) -> Result<VmSpec> {
     // Resolve image
     let image_path = match &def.image {
         ImageSource::Local(raw) => {
@@ -467,13 +2020,9 @@ pub async fn resolve(def: &VmDef, base_dir: &Path) -> Result<VmSpec> {
         }
         ImageSource::Url(url) => {
             info!(vm = %def.name, url = %url, "downloading image");
-            let mgr = ImageManager::new();
-            mgr.pull(url, Some(&def.name)).await?
-        }
-        ImageSource::Oci(oci_ref) => {
-            let mgr = ImageManager::new();
-            mgr.pull_oci(oci_ref, Some(&def.name)).await?
+            images.pull(url, Some(&def.name)).await?
         }
+        ImageSource::Oci(oci_ref) => images.pull_oci(oci_ref, Some(&def.name)).await?,
     };
 
     // Network
@@ -482,52 +2031,125 @@ pub async fn resolve(def: &VmDef, base_dir: &Path) -> Result<VmSpec> {
         NetworkDef::Tap { bridge } => NetworkConfig::Tap {
             bridge: bridge.clone(),
         },
-        NetworkDef::Vnic { name } => NetworkConfig::Vnic {
-            name: name.clone(),
+        NetworkDef::Vnic { name } => NetworkConfig::Vnic { name: name.clone() },
+        NetworkDef::Macvtap { parent } => NetworkConfig::Macvtap {
+            parent: parent.clone(),
         },
         NetworkDef::None => NetworkConfig::None,
     };
 
-    // Cloud-init + SSH config (resolved together because key generation affects both)
-    let (cloud_init, ssh) = resolve_cloud_init_and_ssh(def, base_dir).await?;
+    // Direct kernel boot
+    let kernel = match &def.kernel {
+        Some(k) => {
+            let kernel = resolve_path(&k.kernel, base_dir);
+            let initrd = k.initrd.as_deref().map(|raw| resolve_path(raw, base_dir));
+            for p in std::iter::once(&kernel).chain(initrd.as_ref()) {
+                if !p.exists() {
+                    return Err(VmError::VmFileValidation {
+                        vm: def.name.clone(),
+                        detail: format!("kernel file not found: {}", p.display()),
+                        hint: "check the kernel and initrd paths are correct".into(),
+                    });
+                }
+            }
+            Some(KernelBoot {
+                kernel,
+                initrd,
+                cmdline: k.cmdline.clone(),
+            })
+        }
+        None => None,
+    };
 
-    Ok(VmSpec {
-        name: def.name.clone(),
-        image_path,
-        vcpus: def.vcpus,
-        memory_mb: def.memory_mb,
-        disk_gb: def.disk_gb,
-        network,
-        cloud_init,
-        ssh,
-        uefi: false,
-    })
-}
+    let cdrom = def.cdrom.as_deref().map(|raw| resolve_path(raw, base_dir));
+    if let Some(ref iso) = cdrom {
+        if !iso.exists() {
+            return Err(VmError::VmFileValidation {
+                vm: def.name.clone(),
+                detail: format!("cdrom image not found: {}", iso.display()),
+                hint: "check the cdrom path is correct".into(),
+            });
+        }
+    }
 
-/// Generate an Ed25519 SSH keypair and return `(public_key_openssh, private_key_pem)`.
-fn generate_ssh_keypair(vm_name: &str) -> Result<(String, String)> {
-    use ssh_key::{Algorithm, LineEnding, PrivateKey, rand_core::OsRng};
+    let drivers_iso = def
+        .drivers_iso
+        .as_deref()
+        .map(|raw| resolve_path(raw, base_dir));
+    if let Some(ref iso) = drivers_iso {
+        if !iso.exists() {
+            return Err(VmError::VmFileValidation {
+                vm: def.name.clone(),
+                detail: format!("drivers ISO not found: {}", iso.display()),
+                hint: "check the drivers-iso path is correct".into(),
+            });
+        }
+    }
 
-    let sk = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).map_err(|e| {
-        VmError::SshKeygenFailed {
-            detail: format!("Ed25519 key generation for VM '{vm_name}': {e}"),
+    let secure_boot_vars = def
+        .secure_boot_vars
+        .as_deref()
+        .map(|raw| resolve_path(raw, base_dir));
+    if let Some(ref vars) = secure_boot_vars {
+        if !vars.exists() {
+            return Err(VmError::VmFileValidation {
+                vm: def.name.clone(),
+                detail: format!("Secure Boot vars template not found: {}", vars.display()),
+                hint: "check the vars path of secure-boot is correct".into(),
+            });
         }
-    })?;
+    }
 
-    let pub_openssh = sk
-        .public_key()
-        .to_openssh()
-        .map_err(|e| VmError::SshKeygenFailed {
-            detail: format!("serialize public key: {e}"),
+    // The host the VM runs on may have fewer CPUs than the one the VMFile was written for
+    if let Some(ref cpu_pinning) = def.cpu_pinning {
+        pinning::check(cpu_pinning, def.vcpus).map_err(|detail| VmError::VmFileValidation {
+            vm: def.name.clone(),
+            detail,
+            hint: "pin to CPUs this host has, see /sys/devices/system/cpu/online".into(),
         })?;
+    }
 
-    let priv_pem = sk
-        .to_openssh(LineEnding::LF)
-        .map_err(|e| VmError::SshKeygenFailed {
-            detail: format!("serialize private key: {e}"),
-        })?;
+    // Cloud-init + SSH config (resolved together because key generation affects both)
+    let (cloud_init, ssh) = resolve_cloud_init_and_ssh(def, base_dir).await?;
 
-    Ok((pub_openssh, priv_pem.to_string()))
+    Ok(VmSpec {
+        name: def.name.clone(),
+        image_path,
+        vcpus: def.vcpus,
+        memory_mb: def.memory_mb,
+        disk_gb: def.disk_gb,
+        overlay: def.overlay.clone(),
+        network,
+        cloud_init,
+        ssh,
+        uefi: def.secure_boot || def.confidential.is_some(),
+        mac_addr: def.mac.clone(),
+        existing_macs: Vec::new(),
+        static_ip: def.static_ip.clone(),
+        ssh_host_port: def.ssh_host_port,
+        private_networks: def.private_networks.clone(),
+        serial_ports: Vec::new(),
+        machine: def.machine,
+        kernel,
+        cdrom,
+        boot_order: def.boot_order.clone(),
+        random_seed: def.random_seed,
+        qemu_args: def.qemu_args.clone(),
+        restart_policy: def.restart_policy,
+        guest_os: def.guest_os,
+        display: def.display,
+        audio: def.audio,
+        smbios: def.smbios.clone(),
+        disk_bus: def.disk_bus,
+        drivers_iso,
+        secure_boot: def.secure_boot,
+        secure_boot_vars,
+        confidential: def.confidential,
+        cpu_pinning: def.cpu_pinning.clone(),
+        numa: def.numa.clone(),
+        hugepages: def.hugepages,
+        usb_devices: def.usb_devices.clone(),
+    })
 }
 
 /// Resolve cloud-init and SSH config together.
@@ -561,6 +2183,7 @@ async fn resolve_cloud_init_and_ssh(
                 user_data: data,
                 instance_id: Some(def.name.clone()),
                 hostname: ci.hostname.clone().or_else(|| Some(def.name.clone())),
+                meta_data: None,
             });
             // SSH config from explicit key (if any)
             let ssh = resolve_ssh_config_from_def(def, base_dir);
@@ -579,12 +2202,12 @@ async fn resolve_cloud_init_and_ssh(
                     hint: "check the ssh-key path".into(),
                 }
             })?;
-            let (user_data, _meta) =
-                build_cloud_config(ssh_user, pubkey.trim(), &def.name, hostname);
+            let user_data = cloud_config(ci, def.guest_os, ssh_user, pubkey.trim());
             let cloud_init = Some(CloudInitConfig {
                 user_data,
                 instance_id: Some(def.name.clone()),
                 hostname: Some(hostname.to_string()),
+                meta_data: None,
             });
             let ssh = resolve_ssh_config_from_def(def, base_dir);
             return Ok((cloud_init, ssh));
@@ -592,15 +2215,16 @@ async fn resolve_cloud_init_and_ssh(
     }
 
     // --- Cloud-init block present but no ssh-key / no user-data → generate keypair ---
-    if def.cloud_init.is_some() {
+    if let Some(ci) = &def.cloud_init {
         info!(vm = %def.name, "generating Ed25519 SSH keypair for cloud-init");
-        let (pub_openssh, priv_pem) = generate_ssh_keypair(&def.name)?;
+        let (pub_openssh, priv_pem) = crate::ssh::generate_keypair(&def.name)?;
 
-        let (user_data, _meta) = build_cloud_config(ssh_user, &pub_openssh, &def.name, hostname);
+        let user_data = cloud_config(ci, def.guest_os, ssh_user, &pub_openssh);
         let cloud_init = Some(CloudInitConfig {
             user_data,
             instance_id: Some(def.name.clone()),
             hostname: Some(hostname.to_string()),
+            meta_data: None,
         });
         let ssh = Some(SshConfig {
             user: ssh_user.to_string(),
@@ -616,6 +2240,16 @@ async fn resolve_cloud_init_and_ssh(
     Ok((None, ssh))
 }
 
+/// Generated cloud-config user-data for `user` with `pubkey`, plus the block's timezone, locale and
+/// NTP servers, in the dialect `guest_os` understands.
+fn cloud_config(ci: &CloudInitDef, guest_os: GuestOs, user: &str, pubkey: &str) -> Vec<u8> {
+    let mut cc = CloudConfig::new(user, pubkey);
+    cc.timezone = ci.timezone.clone();
+    cc.locale = ci.locale.clone();
+    cc.ntp_servers = ci.ntp_servers.clone();
+    cc.to_user_data_for(guest_os)
+}
+
 /// Build an `SshConfig` from an explicit `private-key` path in the SSH block.
 /// Returns `None` if there is no ssh block or no private-key specified.
 fn resolve_ssh_config_from_def(def: &VmDef, base_dir: &Path) -> Option<SshConfig> {
@@ -821,6 +2455,1082 @@ vm "ci" {
         );
     }
 
+    #[test]
+    fn parse_static_network() {
+        let kdl = r#"
+vm "db" {
+    image "/tmp/test.qcow2"
+    network type="bridge" bridge="br1" address="192.168.100.10/24" gateway="192.168.100.1" nameservers="1.1.1.1, 8.8.8.8" mac="52:54:00:AA:BB:01"
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vmfile = parse(tmp.path()).unwrap();
+        let vm = &vmfile.vms[0];
+        assert!(matches!(vm.network, NetworkDef::Tap { ref bridge } if bridge == "br1"));
+        assert_eq!(vm.mac.as_deref(), Some("52:54:00:aa:bb:01"));
+        let ip = vm.static_ip.as_ref().unwrap();
+        assert_eq!(ip.address, "192.168.100.10/24");
+        assert_eq!(ip.ip(), "192.168.100.10");
+        assert_eq!(ip.gateway.as_deref(), Some("192.168.100.1"));
+        assert_eq!(ip.nameservers, vec!["1.1.1.1", "8.8.8.8"]);
+
+        let kdl = r#"
+vm "db" {
+    image "/tmp/test.qcow2"
+    network "macvtap" parent="enp3s0" address="192.168.1.50/24"
+}
+"#;
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vmfile = parse(tmp.path()).unwrap();
+        let vm = &vmfile.vms[0];
+        assert!(matches!(vm.network, NetworkDef::Macvtap { ref parent } if parent == "enp3s0"));
+        assert!(vm.static_ip.is_some());
+    }
+
+    #[test]
+    fn parse_cloud_init_timezone_locale_ntp() {
+        let kdl = r#"
+vm "tz" {
+    image "/tmp/a.qcow2"
+    cloud-init {
+        timezone "Europe/Berlin"
+        locale "de_DE.UTF-8"
+        ntp-servers "ntp1.example.com" "pool.ntp.org"
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vmfile = parse(tmp.path()).unwrap();
+        let ci = vmfile.vms[0].cloud_init.as_ref().unwrap();
+        assert_eq!(ci.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(ci.locale.as_deref(), Some("de_DE.UTF-8"));
+        assert_eq!(ci.ntp_servers, vec!["ntp1.example.com", "pool.ntp.org"]);
+
+        let ud = String::from_utf8(cloud_config(
+            ci,
+            GuestOs::Linux,
+            "vm",
+            "ssh-ed25519 AAAA test",
+        ))
+        .unwrap();
+        assert!(ud.contains("\ntimezone: Europe/Berlin\n"));
+        assert!(ud.contains("\nlocale: de_DE.UTF-8\n"));
+        assert!(ud.contains("  servers: [ntp1.example.com]\n  pools: [pool.ntp.org]\n"));
+
+        let kdl = r#"
+vm "tz" {
+    image "/tmp/a.qcow2"
+    cloud-init {
+        user-data "cloud-config.yaml"
+        timezone "Europe/Berlin"
+    }
+}
+"#;
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let msg = parse(tmp.path()).unwrap_err().to_string();
+        assert!(msg.contains("cannot be combined with user-data"), "{msg}");
+
+        let kdl = r#"
+vm "tz" {
+    image "/tmp/a.qcow2"
+    cloud-init {
+        ntp-servers "ntp one.example.com"
+    }
+}
+"#;
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let msg = parse(tmp.path()).unwrap_err().to_string();
+        assert!(msg.contains("invalid NTP server"), "{msg}");
+
+        let kdl = r#"
+vm "tz" {
+    image "/tmp/a.qcow2"
+    cloud-init {
+        locale "C\nruncmd: [reboot]"
+    }
+}
+"#;
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let msg = parse(tmp.path()).unwrap_err().to_string();
+        assert!(msg.contains("control characters"), "{msg}");
+    }
+
+    #[test]
+    fn error_invalid_static_network() {
+        let cases = [
+            (
+                r#"network type="bridge" address="192.168.100.10""#,
+                "invalid address",
+            ),
+            (
+                r#"network type="bridge" address="192.168.100.10/33""#,
+                "invalid address",
+            ),
+            (
+                r#"network type="bridge" mac="52:54:00:aa:bb""#,
+                "invalid MAC",
+            ),
+            (r#"network "user" address="10.0.2.15/24""#, "not supported"),
+            (r#"network "user" ssh-port=70000"#, "invalid ssh-port"),
+            (r#"network "macvtap""#, "macvtap network requires a parent"),
+            (
+                r#"network type="bridge" ssh-port=2222"#,
+                "ssh-port is not supported",
+            ),
+            (
+                r#"network type="bridge" gateway="192.168.100.1""#,
+                "require a static address",
+            ),
+        ];
+        for (net, expected) in cases {
+            let kdl = format!("vm \"bad\" {{\n    image \"/tmp/a.qcow2\"\n    {net}\n}}\n");
+            let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+            std::fs::write(tmp.path(), kdl).unwrap();
+
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "{net}: got: {msg}");
+        }
+    }
+
+    #[test]
+    fn error_duplicate_static_ip() {
+        let kdl = r#"
+vm "a" {
+    image "/tmp/a.qcow2"
+    network type="bridge" address="192.168.100.10/24"
+}
+vm "b" {
+    image "/tmp/b.qcow2"
+    network type="bridge" address="192.168.100.10/24"
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let msg = parse(tmp.path()).unwrap_err().to_string();
+        assert!(msg.contains("already used by VM 'a'"), "got: {msg}");
+    }
+
+    #[test]
+    fn ssh_port() {
+        let kdl = r#"
+vm "a" {
+    image "/tmp/a.qcow2"
+    network "user" ssh-port=2222
+}
+vm "b" {
+    image "/tmp/b.qcow2"
+    network "user" ssh-port=2222
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let msg = parse(tmp.path()).unwrap_err().to_string();
+        assert!(
+            msg.contains("ssh-port 2222 is already used by VM 'a'"),
+            "got: {msg}"
+        );
+
+        std::fs::write(tmp.path(), kdl.replacen("2222", "2223", 1)).unwrap();
+        let vmfile = parse(tmp.path()).unwrap();
+        assert_eq!(vmfile.vms[0].ssh_host_port, Some(2223));
+        assert_eq!(vmfile.vms[1].ssh_host_port, Some(2222));
+    }
+
+    #[test]
+    fn depends_on_start_order() {
+        let kdl = r#"
+vm "worker" {
+    image "/tmp/w.qcow2"
+    depends-on "control-plane" "etcd"
+}
+vm "etcd" {
+    image "/tmp/e.qcow2"
+}
+vm "control-plane" {
+    image "/tmp/c.qcow2"
+    depends-on "etcd"
+}
+vm "standalone" {
+    image "/tmp/s.qcow2"
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vmfile = parse(tmp.path()).unwrap();
+        assert_eq!(vmfile.vms[0].depends_on, vec!["control-plane", "etcd"]);
+
+        let names = |stages: Vec<Vec<&VmDef>>| -> Vec<Vec<String>> {
+            stages
+                .iter()
+                .map(|s| s.iter().map(|vm| vm.name.clone()).collect())
+                .collect()
+        };
+        assert_eq!(
+            names(vmfile.start_order(None).unwrap()),
+            vec![
+                vec!["etcd", "standalone"],
+                vec!["control-plane"],
+                vec!["worker"]
+            ]
+        );
+        assert_eq!(
+            names(vmfile.start_order(Some("control-plane")).unwrap()),
+            vec![vec!["etcd"], vec!["control-plane"]]
+        );
+    }
+
+    #[test]
+    fn error_depends_on() {
+        let cases = [
+            (
+                "vm \"a\" {\n image \"/tmp/a.qcow2\"\n depends-on \"missing\"\n}",
+                "unknown VM 'missing'",
+            ),
+            (
+                "vm \"a\" {\n image \"/tmp/a.qcow2\"\n depends-on \"a\"\n}",
+                "depends on itself",
+            ),
+            (
+                "vm \"a\" {\n image \"/tmp/a.qcow2\"\n depends-on \"b\"\n}\n\
+                 vm \"b\" {\n image \"/tmp/b.qcow2\"\n depends-on \"a\"\n}",
+                "circular dependency between VMs: a -> b -> a",
+            ),
+        ];
+        for (kdl, expected) in cases {
+            let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "got: {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_private_networks() {
+        let kdl = r#"
+network "cluster" {
+    type "private"
+    subnet "10.99.0.0/24"
+}
+
+vm "a" {
+    image "/img/a.qcow2"
+    network "cluster"
+}
+
+vm "b" {
+    image "/img/b.qcow2"
+    network "tap" bridge="br0"
+    network "cluster" address="10.99.0.1"
+}
+
+vm "c" {
+    image "/img/c.qcow2"
+    network "cluster"
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vmfile = parse(tmp.path()).unwrap();
+        assert_eq!(vmfile.networks.len(), 1);
+        assert_eq!(vmfile.networks[0].subnet.to_string(), "10.99.0.0/24");
+
+        // The primary network is unaffected by joining a private one
+        assert!(matches!(vmfile.vms[0].network, NetworkDef::User));
+        assert!(matches!(vmfile.vms[1].network, NetworkDef::Tap { ref bridge } if bridge == "br0"));
+
+        // Explicit addresses are reserved before the rest are handed out in file order
+        let addresses: Vec<&str> = vmfile
+            .vms
+            .iter()
+            .map(|vm| vm.private_networks[0].address.as_str())
+            .collect();
+        assert_eq!(
+            addresses,
+            vec!["10.99.0.2/24", "10.99.0.1/24", "10.99.0.3/24"]
+        );
+    }
+
+    #[test]
+    fn error_private_networks() {
+        let net = "network \"cluster\" {\n type \"private\"\n subnet \"10.99.0.0/30\"\n}\n";
+        let cases = [
+            (
+                "network \"user\" {\n type \"private\"\n subnet \"10.99.0.0/24\"\n}\n\
+                 vm \"a\" {\n image \"/a.qcow2\"\n}"
+                    .to_string(),
+                "reserved for a network type",
+            ),
+            (
+                "network \"cluster\" {\n subnet \"10.99.0.0/24\"\n}\nvm \"a\" {\n image \"/a.qcow2\"\n}"
+                    .to_string(),
+                "has no type",
+            ),
+            (
+                format!("{net}vm \"a\" {{\n image \"/a.qcow2\"\n network \"cluster\" address=\"10.99.1.1\"\n}}"),
+                "not a host address",
+            ),
+            (
+                format!(
+                    "{net}vm \"a\" {{\n image \"/a.qcow2\"\n network \"cluster\"\n}}\n\
+                     vm \"b\" {{\n image \"/b.qcow2\"\n network \"cluster\"\n}}\n\
+                     vm \"c\" {{\n image \"/c.qcow2\"\n network \"cluster\"\n}}"
+                ),
+                "no free address left",
+            ),
+            (
+                format!("{net}vm \"a\" {{\n image \"/a.qcow2\"\n network \"user\"\n network \"tap\"\n}}"),
+                "more than one network node",
+            ),
+        ];
+        for (kdl, expected) in cases {
+            let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "got: {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_named_provisions() {
+        let kdl = r#"
+vm "web" {
+    image "/images/ubuntu.qcow2"
+    provision "shell" {
+        name "deps"
+        inline "apt-get update"
+        capture "apt_output"
+        sudo #true
+    }
+    provision "file" {
+        name "nginx-conf"
+        source "./nginx.conf"
+        destination "/etc/nginx/nginx.conf"
+    }
+    provision "shell" {
+        inline "systemctl restart nginx"
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        let labels: Vec<String> = vm
+            .provisions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| p.label(i + 1))
+            .collect();
+        assert_eq!(labels, vec!["deps", "nginx-conf", "3"]);
+        assert!(
+            matches!(&vm.provisions[0], ProvisionDef::Shell(s) if s.capture.as_deref() == Some("apt_output") && s.sudo)
+        );
+        assert!(matches!(&vm.provisions[2], ProvisionDef::Shell(s) if !s.sudo));
+    }
+
+    #[test]
+    fn parse_loop_provision() {
+        let kdl = r#"
+vm "web" {
+    image "/images/ubuntu.qcow2"
+    provision "loop" {
+        name "packages"
+        items "nginx" "curl"
+        provision "shell" {
+            inline "apt-get install -y ${item}"
+        }
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        let ProvisionDef::Loop(lp) = &vm.provisions[0] else {
+            panic!("expected a loop provision");
+        };
+        assert_eq!(lp.name.as_deref(), Some("packages"));
+        assert_eq!(lp.items, vec!["nginx", "curl"]);
+        assert!(
+            matches!(lp.step.as_ref(), ProvisionDef::Shell(s) if s.inline.as_deref() == Some("apt-get install -y ${item}"))
+        );
+    }
+
+    #[test]
+    fn parse_download_provision() {
+        let kdl = r#"
+vm "web" {
+    image "/images/ubuntu.qcow2"
+    provision "download" {
+        url "https://example.com/releases/tool-1.4.2-linux-amd64.tar.gz"
+        destination "/tmp/tool.tar.gz"
+        checksum "sha256:5f70bf18a086007016e948b04aed3b82103a36bea41755b6cddfaf10ace3c6ef"
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        let ProvisionDef::Download(download) = &vm.provisions[0] else {
+            panic!("expected a download provision");
+        };
+        assert_eq!(
+            download.url,
+            "https://example.com/releases/tool-1.4.2-linux-amd64.tar.gz"
+        );
+        assert_eq!(download.destination, "/tmp/tool.tar.gz");
+        assert!(
+            download
+                .checksum
+                .as_deref()
+                .unwrap()
+                .starts_with("sha256:5f70")
+        );
+    }
+
+    #[test]
+    fn parse_puppet_provision() {
+        let kdl = r#"
+vm "web" {
+    image "/images/ubuntu.qcow2"
+    provision "puppet" {
+        name "site"
+        manifest "./puppet/site.pp"
+        module-path "./puppet/modules" "./vendor/modules"
+        facts {
+            role "web"
+            replicas 3
+        }
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        let ProvisionDef::Puppet(puppet) = &vm.provisions[0] else {
+            panic!("expected a puppet provision");
+        };
+        assert_eq!(puppet.name.as_deref(), Some("site"));
+        assert_eq!(puppet.manifest, "./puppet/site.pp");
+        assert_eq!(
+            puppet.module_paths,
+            vec!["./puppet/modules", "./vendor/modules"]
+        );
+        assert_eq!(puppet.facts["role"], "web");
+        assert_eq!(puppet.facts["replicas"], "3");
+    }
+
+    #[test]
+    fn error_provision_step() {
+        let cases = [
+            (
+                "provision \"shell\" {\n name \"a\"\n inline \"true\"\n}\n\
+                 provision \"shell\" {\n name \"a\"\n inline \"false\"\n}",
+                "duplicate provision step name 'a'",
+            ),
+            (
+                "provision \"shell\" {\n name \"2\"\n inline \"true\"\n}",
+                "numbers are reserved",
+            ),
+            (
+                "provision \"shell\" {\n name \"a,b\"\n inline \"true\"\n}",
+                "without commas or spaces",
+            ),
+            (
+                "provision \"shell\" {\n inline \"true\"\n capture \"1st\"\n}",
+                "invalid capture variable name: 1st",
+            ),
+            (
+                "provision \"loop\" {\n provision \"shell\" {\n inline \"true\"\n }\n}",
+                "loop provision requires items",
+            ),
+            (
+                "provision \"loop\" {\n items \"a\"\n}",
+                "exactly one inner provision step",
+            ),
+            (
+                "provision \"loop\" {\n items \"a\"\n provision \"shell\" {\n name \"x\"\n inline \"true\"\n }\n}",
+                "cannot be a loop or have a name",
+            ),
+            (
+                "provision \"download\" {\n url \"https://example.com/a\"\n}",
+                "download provision requires destination",
+            ),
+            (
+                "provision \"download\" {\n url \"https://example.com/a\"\n destination \"/tmp/a\"\n checksum \"md5:abc\"\n}",
+                "invalid download checksum: md5:abc",
+            ),
+            (
+                "provision \"puppet\" {\n module-path \"./modules\"\n}",
+                "puppet provision requires manifest",
+            ),
+            (
+                "provision \"puppet\" {\n manifest \"./puppet\"\n}",
+                "must be a .pp file",
+            ),
+            (
+                "provision \"puppet\" {\n manifest \"site.pp\"\n facts {\n my-role \"web\"\n }\n}",
+                "invalid fact name: my-role",
+            ),
+        ];
+        for (prov, expected) in cases {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n{prov}\n}}");
+            let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "got: {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_microvm_kernel() {
+        let kdl = r#"
+vm "tiny" {
+    image "rootfs.qcow2"
+    machine "microvm"
+    kernel "vmlinuz" initrd="/boot/initrd.img" cmdline="console=ttyS0 root=/dev/vda1"
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vmfile = parse(tmp.path()).unwrap();
+        let vm = &vmfile.vms[0];
+        assert_eq!(vm.machine, MachineType::Microvm);
+        let kernel = vm.kernel.as_ref().unwrap();
+        assert_eq!(kernel.kernel, "vmlinuz");
+        assert_eq!(kernel.initrd.as_deref(), Some("/boot/initrd.img"));
+        assert_eq!(
+            kernel.cmdline.as_deref(),
+            Some("console=ttyS0 root=/dev/vda1")
+        );
+
+        // microvm without a kernel, and unknown machine types, are rejected
+        for (kdl, expected) in [
+            (
+                "vm \"a\" {\n image \"/tmp/a.qcow2\"\n machine \"microvm\"\n}",
+                "requires direct kernel boot",
+            ),
+            (
+                "vm \"a\" {\n image \"/tmp/a.qcow2\"\n machine \"pc\"\n}",
+                "unknown machine type: pc",
+            ),
+        ] {
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "got: {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_guest_os_and_disk_bus() {
+        let kdl = r#"
+vm "win" {
+    image "/tmp/win.qcow2"
+    guest-os "windows"
+    drivers-iso "isos/virtio-win.iso"
+}
+vm "win-virtio" {
+    image "/tmp/win.qcow2"
+    guest-os "windows"
+    disk-bus "virtio"
+    display "gtk"
+    audio "pipewire"
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vmfile = parse(tmp.path()).unwrap();
+        let (win, virtio) = (&vmfile.vms[0], &vmfile.vms[1]);
+        assert_eq!(win.guest_os, GuestOs::Windows);
+        assert_eq!(win.disk_bus, None);
+        assert_eq!(win.drivers_iso.as_deref(), Some("isos/virtio-win.iso"));
+        assert_eq!(virtio.disk_bus, Some(DiskBus::Virtio));
+        assert_eq!(win.display, DisplayMode::Vnc);
+        assert_eq!(virtio.display, DisplayMode::Gtk);
+        assert_eq!(win.audio, AudioBackend::None);
+        assert_eq!(virtio.audio, AudioBackend::Pipewire);
+
+        let kdl = "vm \"a\" {\n image \"/tmp/a.qcow2\"\n disk 20 interface=\"nvme\"\n}";
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        assert_eq!((vm.disk_gb, vm.disk_bus), (Some(20), Some(DiskBus::Nvme)));
+
+        for (node, expected) in [
+            ("guest-os \"beos\"", "unknown guest OS: beos"),
+            ("disk-bus \"ide\"", "unknown disk bus: ide"),
+            (
+                "disk 20 interface=\"nvme\"\n disk-bus \"sata\"",
+                "both disk-bus and the disk node's interface",
+            ),
+            ("display \"spice\"", "unknown display: spice"),
+            ("audio \"alsa\"", "unknown audio backend: alsa"),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "got: {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_smbios_block() {
+        let kdl = r#"
+vm "licensed" {
+    image "/tmp/a.qcow2"
+    smbios {
+        manufacturer "Acme, Inc."
+        product "Appliance"
+        serial "SN-0042"
+        uuid "5b0f6a44-2f2e-4a53-9b3e-0d6a3c1f9a10"
+        oem "license=abc" "tier=gold"
+        oem "site=fra1"
+        identify #true
+    }
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        assert_eq!(vm.smbios.manufacturer.as_deref(), Some("Acme, Inc."));
+        assert_eq!(vm.smbios.family, None);
+        assert_eq!(
+            vm.smbios.oem_strings,
+            ["license=abc", "tier=gold", "site=fra1"]
+        );
+        assert!(vm.smbios.identify);
+
+        for (block, expected) in [
+            ("serial \"\"", "SMBIOS serial is empty"),
+            ("product \"caf\u{e9}\"", "only printable ASCII"),
+            ("uuid \"not-a-uuid\"", "invalid SMBIOS uuid: not-a-uuid"),
+            ("oem 42", "smbios oem requires strings"),
+            ("vendor \"x\"", "unknown smbios setting: vendor"),
+        ] {
+            let kdl =
+                format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n smbios {{\n {block}\n }}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "{block}: got {msg}");
+        }
+        let long = "x".repeat(256);
+        let kdl = format!(
+            "vm \"a\" {{\n image \"/tmp/a.qcow2\"\n smbios {{\n serial \"{long}\"\n }}\n}}"
+        );
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let msg = parse(tmp.path()).unwrap_err().to_string();
+        assert!(msg.contains("256 bytes long"), "got {msg}");
+    }
+
+    #[test]
+    fn parse_secure_boot() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        for (node, expected) in [
+            ("", (false, None)),
+            ("secure-boot", (true, None)),
+            ("secure-boot #false vars=\"v.fd\"", (false, None)),
+            ("secure-boot #true vars=\"v.fd\"", (true, Some("v.fd"))),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let vmfile = parse(tmp.path()).unwrap();
+            let def = &vmfile.vms[0];
+            assert_eq!(
+                (def.secure_boot, def.secure_boot_vars.as_deref()),
+                expected,
+                "{node}"
+            );
+        }
+
+        let kdl = "vm \"a\" {\n image \"/tmp/a.qcow2\"\n secure-boot \"yes\"\n}";
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let msg = parse(tmp.path()).unwrap_err().to_string();
+        assert!(msg.contains("invalid secure-boot"), "got: {msg}");
+    }
+
+    #[test]
+    fn parse_cpu_pinning() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        let kdl = "vm \"a\" {\n image \"/tmp/a.qcow2\"\n vcpus 2\n cpu-pinning \"4-7\"\n}";
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let pinning = parse(tmp.path()).unwrap().vms[0]
+            .cpu_pinning
+            .clone()
+            .unwrap();
+        assert_eq!(pinning.cpuset, vec![4, 5, 6, 7]);
+
+        let kdl = r#"vm "a" {
+    image "/tmp/a.qcow2"
+    vcpus 2
+    cpu-pinning {
+        cpuset "4-5"
+        vcpu 1 host=9
+        emulator "0-1"
+    }
+}"#;
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let pinning = parse(tmp.path()).unwrap().vms[0]
+            .cpu_pinning
+            .clone()
+            .unwrap();
+        assert_eq!(pinning.vcpu_cpus(0), vec![4, 5]);
+        assert_eq!(pinning.vcpu_cpus(1), vec![9]);
+        assert_eq!(pinning.emulator, vec![0, 1]);
+        assert_eq!(
+            pinning.to_string(),
+            "vCPUs on 4-5, vCPU 1 on 9, emulator on 0-1"
+        );
+
+        for (node, expected) in [
+            (
+                "cpu-pinning \"7-4\"",
+                "invalid cpu-pinning: CPU range 7-4 is reversed",
+            ),
+            (
+                "cpu-pinning { vcpu 2 host=1; }",
+                "vCPU 2 is pinned, but the VM has 2",
+            ),
+            ("cpu-pinning { vcpu 0; }", "vcpu 0 requires host=<CPU>"),
+            (
+                "cpu-pinning { vcpu 0 host=1; vcpu 0 host=2; }",
+                "pinned twice",
+            ),
+            (
+                "cpu-pinning { numa 0; }",
+                "unknown cpu-pinning setting: numa",
+            ),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n vcpus 2\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "{node}: got {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_numa() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        let kdl = r#"vm "a" {
+    image "/tmp/a.qcow2"
+    vcpus 4
+    memory 4096
+    hugepages #true
+    numa {
+        node cpus="0-1" memory=1024
+        node cpus="2,3" memory=3072
+    }
+}"#;
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        assert!(vm.hugepages);
+        assert_eq!(
+            vm.numa,
+            vec![
+                NumaNode {
+                    cpus: vec![0, 1],
+                    memory_mb: 1024
+                },
+                NumaNode {
+                    cpus: vec![2, 3],
+                    memory_mb: 3072
+                },
+            ]
+        );
+
+        for (node, expected) in [
+            (
+                "numa { node cpus=\"0-1\" memory=1024; node cpus=\"1-3\" memory=3072; }",
+                "vCPU 1 is in both NUMA node 0 and node 1",
+            ),
+            (
+                "numa { node cpus=\"0-3\" memory=2048; }",
+                "2048 MB of memory in total, but the VM has 4096 MB",
+            ),
+            (
+                "numa { node cpus=\"0-2\" memory=4096; }",
+                "vCPU 3 is in no NUMA node",
+            ),
+            ("numa { node memory=4096; }", "NUMA node 0 requires cpus="),
+            ("numa { socket 0; }", "unknown numa setting: socket"),
+            ("hugepages \"yes\"", "invalid hugepages"),
+        ] {
+            let kdl = format!(
+                "vm \"a\" {{\n image \"/tmp/a.qcow2\"\n vcpus 4\n memory 4096\n {node}\n}}"
+            );
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "{node}: got {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_usb() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        let kdl = r#"vm "a" {
+    image "/tmp/a.qcow2"
+    usb "1050:0407" "3.7"
+    usb "046D:C52B"
+}"#;
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        assert_eq!(
+            vm.usb_devices,
+            vec![
+                UsbDevice::Id {
+                    vendor: 0x1050,
+                    product: 0x0407
+                },
+                UsbDevice::Address { bus: 3, device: 7 },
+                UsbDevice::Id {
+                    vendor: 0x046d,
+                    product: 0xc52b
+                },
+            ]
+        );
+
+        for (node, expected) in [
+            ("usb", "usb requires at least one device"),
+            ("usb \"yubikey\"", "invalid USB device: yubikey"),
+            ("usb 1050", "invalid USB device: 1050"),
+            (
+                "machine \"microvm\"\n kernel \"/tmp/vmlinux\"\n usb \"3.7\"",
+                "no USB controller",
+            ),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "{node}: got {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_confidential() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        let kdl = "vm \"a\" {\n image \"/tmp/a.qcow2\"\n confidential \"sev\"\n}";
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vmfile = parse(tmp.path()).unwrap();
+        assert_eq!(vmfile.vms[0].confidential, Some(ConfidentialMode::Sev));
+
+        for (node, expected) in [
+            ("confidential \"sev-snp\"", "sev-snp is not supported yet"),
+            ("confidential \"tdx\"", "unknown confidential mode: tdx"),
+            (
+                "confidential \"sev\"\n secure-boot",
+                "need the q35 machine type and no Secure Boot",
+            ),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "{node}: got {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_restart_policy() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        for (node, expected) in [
+            ("", RestartPolicy::No),
+            ("restart-policy \"on-failure\"", RestartPolicy::OnFailure),
+            ("restart-policy \"always\"", RestartPolicy::Always),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            assert_eq!(parse(tmp.path()).unwrap().vms[0].restart_policy, expected);
+        }
+
+        let kdl = "vm \"a\" {\n image \"/tmp/a.qcow2\"\n restart-policy \"sometimes\"\n}";
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let msg = parse(tmp.path()).unwrap_err().to_string();
+        assert!(
+            msg.contains("unknown restart policy: sometimes"),
+            "got: {msg}"
+        );
+    }
+
+    #[test]
+    fn parse_boot_order() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        for (node, expected) in [
+            ("", vec![]),
+            (
+                "boot-order \"cdrom\" \"disk\" \"network\"",
+                vec![BootDevice::Cdrom, BootDevice::Disk, BootDevice::Network],
+            ),
+            ("boot-order \"network\"", vec![BootDevice::Network]),
+        ] {
+            let kdl =
+                format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n cdrom \"/tmp/a.iso\"\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let vmfile = parse(tmp.path()).unwrap();
+            assert_eq!(vmfile.vms[0].boot_order, expected, "{node}");
+        }
+
+        for (nodes, expected) in [
+            ("boot-order \"floppy\"", "unknown boot device: floppy"),
+            ("boot-order", "boot-order names no devices"),
+            (
+                "boot-order \"disk\" \"disk\"",
+                "disk is in the boot order twice",
+            ),
+            ("boot-order \"cdrom\"", "no CD-ROM ISO is attached"),
+            (
+                "network \"none\"\n boot-order \"network\"",
+                "the VM has no network",
+            ),
+            (
+                "kernel \"vmlinuz\"\n boot-order \"disk\"",
+                "no effect with direct kernel boot",
+            ),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {nodes}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "{nodes}: got {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_snapshot_before_provision() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        for (node, expected) in [
+            ("", None),
+            (
+                "snapshot-before-provision",
+                Some(ProvisionSnapshotDef { keep: None }),
+            ),
+            ("snapshot-before-provision #false keep=2", None),
+            (
+                "snapshot-before-provision #true keep=3",
+                Some(ProvisionSnapshotDef { keep: Some(3) }),
+            ),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            assert_eq!(
+                parse(tmp.path()).unwrap().vms[0].provision_snapshot,
+                expected
+            );
+        }
+
+        for node in [
+            "snapshot-before-provision \"yes\"",
+            "snapshot-before-provision #true keep=-1",
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {node}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains("snapshot-before-provision"), "got: {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_rng_device() {
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        for (rng, expected) in [
+            ("", RngConfig::default()),
+            ("rng \"none\"", RngConfig::None),
+            (
+                "rng \"virtio\" max-bytes=1024 period=500",
+                RngConfig::VirtioRng {
+                    max_bytes: Some(1024),
+                    period_ms: Some(500),
+                },
+            ),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {rng}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            assert_eq!(parse(tmp.path()).unwrap().vms[0].random_seed, expected);
+        }
+
+        for (rng, expected) in [
+            ("rng \"intel-hda\"", "unknown rng device: intel-hda"),
+            ("rng \"virtio\" period=500", "period requires max-bytes"),
+            ("rng \"virtio\" max-bytes=0", "invalid rng max-bytes"),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {rng}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "got: {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_extra_args() {
+        let kdl = r#"
+vm "a" {
+    image "/tmp/a.qcow2"
+    extra-arg "-device" "virtio-balloon-pci"
+    extra-arg "-cpu" "host,+invtsc"
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+        assert_eq!(
+            parse(tmp.path()).unwrap().vms[0].qemu_args,
+            ["-device", "virtio-balloon-pci", "-cpu", "host,+invtsc"]
+        );
+
+        for (arg, expected) in [
+            ("extra-arg", "requires at least one string argument"),
+            (
+                "extra-arg \"--qmp\" \"tcp::4444\"",
+                "extra-arg --qmp conflicts",
+            ),
+            ("extra-arg \"-daemonize\"", "extra-arg -daemonize conflicts"),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {arg}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "got: {msg}");
+        }
+    }
+
+    #[test]
+    fn parse_disk_overlay_options() {
+        let kdl = r#"
+vm "db" {
+    image "/tmp/a.qcow2"
+    disk 40 preallocation="falloc" cluster-size="2M" lazy-refcounts=#true
+}
+"#;
+        let tmp = tempfile::NamedTempFile::with_suffix(".kdl").unwrap();
+        std::fs::write(tmp.path(), kdl).unwrap();
+        let vm = &parse(tmp.path()).unwrap().vms[0];
+        assert_eq!(vm.disk_gb, Some(40));
+        assert_eq!(
+            vm.overlay,
+            OverlayOptions {
+                preallocation: Some(crate::types::Preallocation::Falloc),
+                cluster_size: Some(2 << 20),
+                lazy_refcounts: true,
+            }
+        );
+
+        for (disk, expected) in [
+            (
+                "disk 10 preallocation=\"sparse\"",
+                "unknown preallocation mode: sparse",
+            ),
+            ("disk 10 cluster-size=\"3M\"", "invalid cluster-size"),
+            ("disk 10 cluster-size=\"4M\"", "invalid cluster-size"),
+            ("disk 10 lazy-refcounts=\"yes\"", "invalid lazy-refcounts"),
+        ] {
+            let kdl = format!("vm \"a\" {{\n image \"/tmp/a.qcow2\"\n {disk}\n}}");
+            std::fs::write(tmp.path(), kdl).unwrap();
+            let msg = parse(tmp.path()).unwrap_err().to_string();
+            assert!(msg.contains(expected), "got: {msg}");
+        }
+    }
+
     #[test]
     fn expand_tilde_works() {
         let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/root"));
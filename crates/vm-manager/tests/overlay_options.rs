@@ -0,0 +1,134 @@
+//! Creates overlays with each tuning option and checks what `qemu-img info` reports.
+//!
+//! Needs `qemu-img`; run with `cargo test -p vm-manager --features qemu-img-tests -- --nocapture`
+//! to also see how long each variant takes to create.
+#![cfg(feature = "qemu-img-tests")]
+
+use std::path::Path;
+use std::time::Instant;
+
+use vm_manager::image;
+use vm_manager::{CancellationToken, OverlayOptions, Preallocation};
+
+const BASE_SIZE: u64 = 64 << 20;
+
+async fn info(path: &Path) -> serde_json::Value {
+    let output = tokio::process::Command::new("qemu-img")
+        .args(["info", "--output=json"])
+        .arg(path)
+        .output()
+        .await
+        .expect("qemu-img");
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+async fn base_image(dir: &Path) -> std::path::PathBuf {
+    let base = dir.join("base.raw");
+    let status = tokio::process::Command::new("qemu-img")
+        .args(["create", "-f", "raw"])
+        .arg(&base)
+        .arg(BASE_SIZE.to_string())
+        .status()
+        .await
+        .expect("qemu-img");
+    assert!(status.success());
+    base
+}
+
+#[tokio::test]
+async fn overlay_options_reach_qemu_img() {
+    let dir = tempfile::tempdir().unwrap();
+    let base = base_image(dir.path()).await;
+    let cancel = CancellationToken::new();
+
+    let variants = [
+        ("default", OverlayOptions::default()),
+        (
+            "metadata",
+            OverlayOptions {
+                preallocation: Some(Preallocation::Metadata),
+                ..Default::default()
+            },
+        ),
+        (
+            "falloc",
+            OverlayOptions {
+                preallocation: Some(Preallocation::Falloc),
+                ..Default::default()
+            },
+        ),
+        (
+            "full",
+            OverlayOptions {
+                preallocation: Some(Preallocation::Full),
+                ..Default::default()
+            },
+        ),
+        (
+            "cluster-2M",
+            OverlayOptions {
+                cluster_size: Some(2 << 20),
+                ..Default::default()
+            },
+        ),
+        (
+            "lazy-refcounts",
+            OverlayOptions {
+                lazy_refcounts: true,
+                ..Default::default()
+            },
+        ),
+    ];
+
+    for (label, options) in &variants {
+        let overlay = dir.path().join(format!("{label}.qcow2"));
+        let started = Instant::now();
+        image::create_overlay(&base, &overlay, None, options, &cancel)
+            .await
+            .unwrap();
+        println!("{label:<16} created in {:?}", started.elapsed());
+
+        let report = info(&overlay).await;
+        let cluster_size = options.cluster_size.unwrap_or(64 << 10);
+        assert_eq!(report["cluster-size"], cluster_size, "{label}");
+        assert_eq!(
+            report["format-specific"]["data"]["lazy-refcounts"], options.lazy_refcounts,
+            "{label}"
+        );
+        let actual = report["actual-size"].as_u64().unwrap();
+        match options.preallocation {
+            Some(Preallocation::Falloc | Preallocation::Full) => {
+                assert!(
+                    actual >= BASE_SIZE,
+                    "{label}: only {actual} bytes allocated"
+                );
+            }
+            _ => assert!(actual < BASE_SIZE, "{label}: {actual} bytes allocated"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn convert_applies_options_to_qcow2_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let base = base_image(dir.path()).await;
+    let options = OverlayOptions {
+        cluster_size: Some(2 << 20),
+        lazy_refcounts: true,
+        ..Default::default()
+    };
+
+    let qcow2 = dir.path().join("converted.qcow2");
+    image::convert(&base, &qcow2, "qcow2", &options)
+        .await
+        .unwrap();
+    let report = info(&qcow2).await;
+    assert_eq!(report["cluster-size"], 2 << 20);
+    assert_eq!(report["format-specific"]["data"]["lazy-refcounts"], true);
+
+    // qcow2 options are not passed to other formats
+    let raw = dir.path().join("converted.raw");
+    image::convert(&qcow2, &raw, "raw", &options).await.unwrap();
+    assert_eq!(info(&raw).await["format"], "raw");
+}
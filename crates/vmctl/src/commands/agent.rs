@@ -0,0 +1,19 @@
+use miette::{IntoDiagnostic, Result};
+use vm_manager::config::Config;
+
+/// Serve remote backend requests from another vmctl over stdin/stdout.
+///
+/// Handles prepared here are not recorded in this host's state store; the calling vmctl keeps
+/// them in its own.
+pub async fn run(config: &Config) -> Result<()> {
+    let hv = super::hypervisor(config)?;
+    let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+    vm_manager::backends::remote::serve_agent(
+        &hv,
+        &config.image_manager(),
+        stdin,
+        tokio::io::stdout(),
+    )
+    .await
+    .into_diagnostic()
+}
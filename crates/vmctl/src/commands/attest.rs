@@ -0,0 +1,68 @@
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::backends::qemu::QemuBackend;
+use vm_manager::config::Config;
+use vm_manager::{BackendTag, Hypervisor, VmState};
+
+use super::state;
+
+#[derive(Args)]
+pub struct AttestArgs {
+    /// VM name
+    name: String,
+
+    /// Print the measurement and SEV state as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+pub async fn run(args: AttestArgs, config: &Config) -> Result<()> {
+    let store = state::load_store(config).await?;
+    let handle = state::find(&store, &args.name)
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+    let name = handle.name.as_str();
+    if handle.backend != BackendTag::Qemu || handle.remote_host.is_some() {
+        miette::bail!("attest only supports confidential VMs on local QEMU");
+    }
+    let Some(mode) = handle.confidential else {
+        miette::bail!(
+            help = "create the VM with `--confidential sev` to run it with encrypted memory",
+            "VM '{name}' is not a confidential VM"
+        );
+    };
+
+    let hv = super::hypervisor(config)?;
+    let vm_state = hv.state(handle).await.into_diagnostic()?;
+    if vm_state != VmState::Running && vm_state != VmState::Suspended {
+        miette::bail!(
+            help = "start it with `vmctl start {name}`",
+            "VM '{name}' is {vm_state}; the launch measurement is only available while it runs"
+        );
+    }
+    let (measurement, sev) = QemuBackend::launch_measurement(handle).await?;
+
+    if args.json {
+        let value = serde_json::json!({
+            "name": name,
+            "mode": mode.to_string(),
+            "measurement": measurement,
+            "policy": sev.policy,
+            "api_version": sev.api_version,
+            "build_id": sev.build_id,
+            "state": sev.state,
+        });
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&value).into_diagnostic()?
+        );
+        return Ok(());
+    }
+
+    println!("VM:          {name}");
+    println!("Mode:        {mode}");
+    println!("Measurement: {measurement}");
+    println!("Policy:      {:#06x}", sev.policy);
+    println!("API version: {} (build {})", sev.api_version, sev.build_id);
+    println!("State:       {}", sev.state);
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! Client for the `vmctl daemon` API, used by `--remote`.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use futures_util::TryStreamExt;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper_util::rt::TokioIo;
+use miette::{IntoDiagnostic, MietteDiagnostic, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::net::UnixStream;
+use tokio_util::io::StreamReader;
+use tracing::debug;
+use vm_manager::config::Config;
+
+/// Error body of a failed API request.
+#[derive(Serialize, Deserialize)]
+pub struct ApiError {
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+}
+
+impl From<ApiError> for miette::Report {
+    fn from(e: ApiError) -> Self {
+        let mut diagnostic = MietteDiagnostic::new(e.error);
+        if let Some(code) = e.code {
+            diagnostic = diagnostic.with_code(code);
+        }
+        if let Some(help) = e.help {
+            diagnostic = diagnostic.with_help(help);
+        }
+        miette::Report::new(diagnostic)
+    }
+}
+
+/// The socket named by `unix:///path` or a plain path.
+pub fn socket_path(target: &str) -> Result<PathBuf> {
+    if let Some(path) = target.strip_prefix("unix://") {
+        return Ok(PathBuf::from(path));
+    }
+    if target.contains("://") {
+        miette::bail!(
+            help = "the daemon only listens on unix sockets, e.g. unix:///run/vmctl.sock",
+            "unsupported API address: {target}"
+        );
+    }
+    Ok(PathBuf::from(target))
+}
+
+/// Sends each command to a `vmctl daemon`, in the namespace of the local configuration.
+pub struct ApiClient {
+    socket: PathBuf,
+    namespace: String,
+}
+
+impl ApiClient {
+    pub fn new(target: &str, config: &Config) -> Result<Self> {
+        Ok(Self {
+            socket: socket_path(target)?,
+            namespace: config.namespace.value.clone(),
+        })
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<Vec<u8>>,
+    ) -> Result<(u16, Incoming)> {
+        let stream = UnixStream::connect(&self.socket).await.map_err(|e| {
+            miette::miette!(
+                help = "is `vmctl daemon` running and is the socket writable by you?",
+                "cannot connect to {}: {e}",
+                self.socket.display()
+            )
+        })?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .into_diagnostic()?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!(error = %e, "API connection failed");
+            }
+        });
+
+        let mut target = format!("{path}?namespace={}", encode(&self.namespace));
+        for (key, value) in query {
+            target.push_str(&format!("&{key}={}", encode(value)));
+        }
+        let request = hyper::Request::builder()
+            .method(method)
+            .uri(target)
+            .header(HOST, "localhost");
+        let request = match body {
+            Some(body) => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(Full::new(Bytes::from(body))),
+            None => request.body(Full::default()),
+        }
+        .into_diagnostic()?;
+        let response = sender.send_request(request).await.into_diagnostic()?;
+        Ok((response.status().as_u16(), response.into_body()))
+    }
+
+    /// Make a request and decode the JSON response. A `204 No Content` decodes as `null`.
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<&impl Serialize>,
+    ) -> Result<T> {
+        let body = body.map(serde_json::to_vec).transpose().into_diagnostic()?;
+        let (status, body) = self.send(method, path, query, body).await?;
+        let data = body.collect().await.into_diagnostic()?.to_bytes();
+        if !(200..300).contains(&status) {
+            return Err(error_from(status, &data));
+        }
+        if data.is_empty() {
+            return serde_json::from_slice(b"null").into_diagnostic();
+        }
+        serde_json::from_slice(&data).into_diagnostic()
+    }
+
+    /// Follow an event stream, calling `on_event` with each event's name and data until the
+    /// daemon ends the stream.
+    pub async fn stream(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        mut on_event: impl FnMut(&str, &str) -> Result<()>,
+    ) -> Result<()> {
+        let (status, body) = self.send("GET", path, query, None).await?;
+        if status != 200 {
+            let data = body.collect().await.into_diagnostic()?.to_bytes();
+            return Err(error_from(status, &data));
+        }
+        let mut reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+        while let Some((event, data)) = read_event(&mut reader).await.into_diagnostic()? {
+            on_event(&event, &data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the next server-sent event as `(event, data)`. The event name is `message` when the
+/// server didn't give one. Returns `None` at the end of the stream.
+async fn read_event(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Option<(String, String)>> {
+    let mut event = None;
+    let mut data: Option<String> = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(data.map(|data| (event.unwrap_or_else(|| "message".into()), data)));
+        }
+        let line = line.trim_end_matches('\n');
+        if line.is_empty() {
+            if let Some(data) = data.take() {
+                return Ok(Some((event.unwrap_or_else(|| "message".into()), data)));
+            }
+            event = None;
+            continue;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => event = Some(value.to_string()),
+            "data" => match data {
+                Some(ref mut data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_string()),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Percent-encode everything but unreserved characters, for paths and query strings.
+pub fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn error_from(status: u16, body: &[u8]) -> miette::Report {
+    match serde_json::from_slice::<ApiError>(body) {
+        Ok(e) => e.into(),
+        Err(_) => miette::miette!(
+            "daemon answered {status}: {}",
+            String::from_utf8_lossy(body).trim()
+        ),
+    }
+}
+
+/// `path` made absolute against this process's working directory, so the daemon finds the
+/// same file.
+pub fn absolute(path: &Path) -> Result<PathBuf> {
+    std::path::absolute(path).into_diagnostic()
+}
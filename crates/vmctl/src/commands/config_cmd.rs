@@ -0,0 +1,90 @@
+use clap::{Args, Subcommand};
+use miette::Result;
+use vm_manager::config::{Config, Setting};
+
+#[derive(Args)]
+pub struct ConfigCommand {
+    #[command(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the effective configuration and where each value came from
+    Show,
+}
+
+pub async fn run(args: ConfigCommand, config: &Config) -> Result<()> {
+    match args.action {
+        ConfigAction::Show => show(config),
+    }
+    Ok(())
+}
+
+fn show(config: &Config) {
+    println!("{:<16} {:<48} SOURCE", "KEY", "VALUE");
+    println!("{}", "-".repeat(80));
+
+    row(
+        "qemu-binary",
+        &config.qemu_binary,
+        |v| v.as_ref().map(|p| p.display().to_string()),
+        "(search PATH)",
+    );
+    row(
+        "firmware-dir",
+        &config.firmware_dir,
+        |v| v.as_ref().map(|p| p.display().to_string()),
+        "(well-known locations)",
+    );
+    row(
+        "data-dir",
+        &config.data_dir,
+        |v| Some(v.display().to_string()),
+        "",
+    );
+    // An unset cache dir follows data-dir; show where that resolves to.
+    let cache = config.image_cache().display().to_string();
+    row(
+        "image-cache-dir",
+        &config.image_cache_dir,
+        |v| v.as_ref().map(|p| p.display().to_string()),
+        &cache,
+    );
+    row("bridge", &config.bridge, Clone::clone, "-");
+    row("image", &config.image, Clone::clone, "-");
+    row("ssh-user", &config.ssh_user, |v| Some(v.clone()), "");
+    row(
+        "backend",
+        &config.backend,
+        |v| v.as_ref().map(ToString::to_string),
+        "(auto)",
+    );
+    row(
+        "lease-sources",
+        &config.lease_sources,
+        |v| {
+            Some(
+                v.iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+        },
+        "",
+    );
+    row("hostnames", &config.hostnames, |v| Some(v.to_string()), "");
+    row("namespace", &config.namespace, |v| Some(v.clone()), "");
+
+    println!();
+    println!("Config files (later files override earlier ones):");
+    for path in Config::search_paths() {
+        let status = if path.exists() { "" } else { " (not found)" };
+        println!("  {}{status}", path.display());
+    }
+}
+
+fn row<T>(key: &str, setting: &Setting<T>, format: impl Fn(&T) -> Option<String>, unset: &str) {
+    let value = format(&setting.value).unwrap_or_else(|| unset.to_string());
+    println!("{key:<16} {value:<48} {}", setting.source);
+}
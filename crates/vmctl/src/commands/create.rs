@@ -1,19 +1,44 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
-use tracing::info;
-use vm_manager::{CloudInitConfig, Hypervisor, NetworkConfig, RouterHypervisor, SshConfig, VmSpec};
+use serde::{Deserialize, Serialize};
+use vm_manager::backends::remote::{RemoteBackend, RemoteHost};
+use vm_manager::config::Config;
+use vm_manager::ssh_import::{self, KeySource};
+use vm_manager::template::{self, Template};
+use vm_manager::vmfile::SshDef;
+use vm_manager::{
+    AudioBackend, BootDevice, CloudInitConfig, ConfidentialMode, DiskBus, DisplayMode, GuestOs,
+    NetworkConfig, RestartPolicy, SshConfig, VmError, VmHandle, VmSpec,
+};
 
-use super::state;
+use super::client::{self, ApiClient};
+use super::ssh::{SshTarget, resolve_target};
+use super::{hostnames, state};
 
-#[derive(Args)]
+/// How long `--wait-cloud-init` waits for the guest's SSH server and then for cloud-init.
+const CLOUD_INIT_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Pause before asking again when the guest dropped the connection (cloud-init may reboot it)
+/// or cloud-init is too old to wait by itself.
+const CLOUD_INIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where cloud-init logs the output of the modules it ran; printed when it fails.
+const CLOUD_INIT_OUTPUT_LOG: &str = "/var/log/cloud-init-output.log";
+
+/// Schemes that make `--image` a URL to pull rather than a local path.
+const IMAGE_URL_SCHEMES: [&str; 4] = ["http://", "https://", "oci://", "s3://"];
+
+#[derive(Args, Serialize, Deserialize)]
 pub struct CreateArgs {
     /// VM name
     #[arg(long)]
     name: String,
 
-    /// Path to a local disk image
+    /// Path to a disk image (on the remote host when `--host` is given), or an `http://`,
+    /// `https://` or `oci://` URL to pull it from into the image cache first
     #[arg(long)]
     image: Option<PathBuf>,
 
@@ -21,42 +46,493 @@ pub struct CreateArgs {
     #[arg(long)]
     image_url: Option<String>,
 
-    /// Number of vCPUs
-    #[arg(long, default_value = "1")]
-    vcpus: u16,
+    /// Take defaults for the image, vCPUs, memory, login user and provision steps from a
+    /// template: `~/.config/vmctl/templates/<NAME>.kdl`, or the built-in `ubuntu-server` or
+    /// `minimal`. Flags override the template's values
+    #[arg(long, value_name = "NAME")]
+    #[serde(default)]
+    template: Option<String>,
+
+    /// Number of vCPUs (default: the template's, or 1)
+    #[arg(long)]
+    vcpus: Option<u16>,
 
-    /// Memory in MB
-    #[arg(long, default_value = "1024")]
-    memory: u64,
+    /// Memory in MB (default: the template's, or 1024)
+    #[arg(long)]
+    memory: Option<u64>,
 
     /// Disk size in GB (overlay resize)
     #[arg(long)]
     disk: Option<u32>,
 
-    /// Bridge name for TAP networking
+    /// Bridge name for TAP networking (default: the configured `bridge`)
     #[arg(long)]
     bridge: Option<String>,
 
-    /// Path to cloud-init user-data file
-    #[arg(long)]
-    cloud_init: Option<PathBuf>,
+    /// cloud-init user-data file to use as it is: a cloud-config (`#cloud-config`) or a script
+    /// (`#!`)
+    #[arg(long, value_name = "PATH", visible_alias = "cloud-init")]
+    #[serde(alias = "cloud_init")]
+    user_data_file: Option<PathBuf>,
+
+    /// cloud-init meta-data file to use instead of the generated instance-id and hostname
+    #[arg(long, value_name = "PATH")]
+    #[serde(default)]
+    meta_data_file: Option<PathBuf>,
 
     /// Path to SSH public key file (injected via cloud-init)
     #[arg(long)]
     ssh_key: Option<PathBuf>,
 
+    /// Also authorize the public SSH keys of a GitHub or GitLab account, `github:<user>` or
+    /// `gitlab:<user>` (repeatable). Fetched keys are cached for an hour
+    #[arg(
+        long = "import-ssh-key",
+        value_name = "ACCOUNT",
+        conflicts_with = "user_data_file"
+    )]
+    #[serde(default)]
+    import_ssh_keys: Vec<KeySource>,
+
+    /// Attach no cloud-init seed ISO, for images that already have SSH set up. `--ssh-key` then
+    /// only names the key to log in with
+    #[arg(long, conflicts_with_all = ["user_data_file", "meta_data_file", "import_ssh_keys", "wait_cloud_init"])]
+    #[serde(default)]
+    no_cloud_init: bool,
+
+    /// ISO image to attach as a read-only CD-ROM, e.g. an installer (on the remote host when
+    /// `--host` is given)
+    #[arg(long)]
+    cdrom: Option<PathBuf>,
+
+    /// Devices to boot from, first to last, e.g. `--boot-order cdrom,disk` (default: the
+    /// firmware's order)
+    #[arg(long, value_name = "DEVICES", value_delimiter = ',')]
+    #[serde(default)]
+    boot_order: Vec<BootDevice>,
+
     /// Boot with UEFI firmware (OVMF) instead of legacy BIOS
     #[arg(long)]
     uefi: bool,
 
+    /// Boot UEFI firmware with Secure Boot and the Microsoft and distribution keys enrolled
+    /// (implies `--uefi`)
+    #[arg(long)]
+    #[serde(default)]
+    secure_boot: bool,
+
+    /// Variable-store template to use with `--secure-boot` instead of the distribution's, e.g.
+    /// one with your own keys enrolled
+    #[arg(long, requires = "secure_boot")]
+    #[serde(default)]
+    secure_boot_vars: Option<PathBuf>,
+
+    /// Run the guest with encrypted memory: `sev` (AMD SEV, needs a host with SEV enabled).
+    /// Implies `--uefi`; check the guest with `vmctl attest`
+    #[arg(long, value_name = "MODE", conflicts_with = "secure_boot")]
+    #[serde(default)]
+    confidential: Option<ConfidentialMode>,
+
+    /// Guest operating system: `linux` or `windows`. Windows guests get a local-time clock, a
+    /// display adapter, RDP forwarding and a SATA root disk
+    #[arg(long, default_value_t = GuestOs::Linux)]
+    #[serde(default)]
+    guest_os: GuestOs,
+
+    /// Where the guest's display is shown: `vnc`, `gtk` or `sdl` (a window on this desktop;
+    /// QEMU then stays attached to the starting process instead of daemonizing) or `none`
+    #[arg(long, default_value_t = DisplayMode::Vnc)]
+    #[serde(default)]
+    display: DisplayMode,
+
+    /// Give the guest a sound card playing through the host's `pipewire` or `pa` (PulseAudio)
+    /// server, or `none`
+    #[arg(long, default_value_t = AudioBackend::None)]
+    #[serde(default)]
+    audio: AudioBackend,
+
+    /// Root disk bus: `virtio`, `nvme`, `scsi` or `sata` (default: `sata` for Windows guests,
+    /// otherwise `virtio`)
+    #[arg(long)]
+    #[serde(default)]
+    disk_bus: Option<DiskBus>,
+
+    /// virtio-win drivers ISO to attach for Windows installers (default: a system-wide
+    /// virtio-win.iso for Windows guests, if installed)
+    #[arg(long)]
+    #[serde(default)]
+    drivers_iso: Option<PathBuf>,
+
+    /// Extra argument appended to the QEMU command line (repeatable), e.g.
+    /// `--qemu-arg=-device --qemu-arg=virtio-balloon-pci`
+    #[arg(long = "qemu-arg", value_name = "ARG", allow_hyphen_values = true)]
+    #[serde(default)]
+    qemu_args: Vec<String>,
+
+    /// Restart the VM from `vmctl watch` when it stops: `no`, `on-failure` or `always`
+    #[arg(long, default_value_t = RestartPolicy::No)]
+    #[serde(default)]
+    restart_policy: RestartPolicy,
+
     /// Also start the VM after creation
     #[arg(long)]
     start: bool,
+
+    /// With `--start`, wait until cloud-init has finished in the guest. Fails, printing the
+    /// guest's cloud-init output log, if cloud-init reports an error
+    #[arg(long, requires = "start")]
+    #[serde(skip)]
+    wait_cloud_init: bool,
+
+    /// Print the commands and cloud-init files creating and starting the VM would run and
+    /// write, without creating anything
+    #[arg(long, conflicts_with = "start")]
+    #[serde(skip)]
+    dry_run: bool,
+
+    /// Create (and start) the VM even if the host seems short of memory or disk space for it
+    #[arg(long)]
+    #[serde(default)]
+    force: bool,
+}
+
+impl CreateArgs {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Body of `POST /v1/vms`: the `create` arguments plus `--host`.
+#[derive(Serialize, Deserialize)]
+pub struct CreateRequest {
+    #[serde(flatten)]
+    pub args: CreateArgs,
+    pub host: Option<String>,
+}
+
+pub async fn run(args: CreateArgs, host: Option<&str>, config: &Config) -> Result<()> {
+    if args.dry_run {
+        return dry_run(args, host, config).await;
+    }
+    let start = args.start;
+    let wait_cloud_init = args.wait_cloud_init;
+    let template = load_template(&args)?;
+    let user = login_user(template.as_ref(), config);
+    // The private key next to the public one given with --ssh-key, if there is one
+    let key = args
+        .ssh_key
+        .as_ref()
+        .filter(|public| public.extension().is_some_and(|ext| ext == "pub"))
+        .map(|public| public.with_extension(""))
+        .filter(|private| private.exists());
+    let handle = create_with(args, template.as_ref(), host, config).await?;
+    println!("VM '{}' created (id: {})", handle.name, handle.id);
+    if start {
+        println!("VM '{}' started", handle.name);
+    }
+    if wait_cloud_init {
+        wait_for_cloud_init(config, &handle.name, user.clone(), key.clone()).await?;
+    }
+    if let Some(template) = template.filter(|t| !t.provisions.is_empty()) {
+        if !start {
+            println!(
+                "Template '{}' has provision steps; they only run with --start",
+                template.name
+            );
+            return Ok(());
+        }
+        // Over SSH with the key given, otherwise through the guest agent
+        let ssh_def = key.map(|key| SshDef {
+            user,
+            private_key: Some(key.display().to_string()),
+        });
+        let hv = super::hypervisor(config)?;
+        super::up::run_provision_for_vm(
+            &hv,
+            config,
+            &handle.name,
+            &template.provisions,
+            ssh_def.as_ref(),
+            &template.base_dir,
+            None,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// The template named with `--template`, if any.
+fn load_template(args: &CreateArgs) -> Result<Option<Template>> {
+    Ok(args.template.as_deref().map(template::load).transpose()?)
+}
+
+/// The user cloud-init creates for the SSH key: the template's, or the configured `ssh-user`.
+fn login_user(template: Option<&Template>, config: &Config) -> String {
+    template
+        .and_then(|t| t.user.clone())
+        .unwrap_or_else(|| config.ssh_user.value.clone())
+}
+
+/// Wait until cloud-init has finished in VM `name`, with `cloud-init status --wait` over SSH.
+/// Reconnects when the guest drops the connection, as it does when cloud-init reboots it. If
+/// cloud-init reports an error, prints its output log and fails.
+async fn wait_for_cloud_init(
+    config: &Config,
+    name: &str,
+    user: String,
+    key: Option<PathBuf>,
+) -> Result<()> {
+    println!("Waiting for cloud-init in VM '{name}'...");
+    let deadline = Instant::now() + CLOUD_INIT_TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let SshTarget {
+            ip,
+            port,
+            config: ssh_config,
+            remote,
+        } = resolve_target(
+            config,
+            name,
+            Some(user.clone()),
+            key.clone(),
+            None,
+            false,
+            remaining,
+        )
+        .await?;
+        let (sess, tunnel) = vm_manager::backends::remote::connect_guest(
+            remote.as_ref(),
+            &ip,
+            port,
+            &ssh_config,
+            remaining,
+            super::cancel(),
+        )
+        .await
+        .into_diagnostic()?;
+
+        let asking = tokio::task::spawn_blocking(move || {
+            let _tunnel = tunnel;
+            let (stdout, _, exit_code) = vm_manager::ssh::exec(&sess, "cloud-init status --wait")?;
+            let status = cloud_init_status(&stdout).map(str::to_string);
+            let log = (status.as_deref() == Some("error"))
+                .then(|| vm_manager::ssh::read_file(&sess, Path::new(CLOUD_INIT_OUTPUT_LOG)));
+            Ok::<_, VmError>((status, exit_code, log))
+        });
+        let result = tokio::select! {
+            result = asking => result.into_diagnostic()?,
+            _ = super::cancel().cancelled() => {
+                miette::bail!("stopped waiting for cloud-init in VM '{name}'")
+            }
+        };
+
+        match result {
+            Ok((Some(status), _, _)) if status == "done" || status == "disabled" => {
+                println!("cloud-init finished in VM '{name}' ({status})");
+                return Ok(());
+            }
+            Ok((Some(status), _, log)) if status == "error" => {
+                match log {
+                    Some(Ok(log)) => {
+                        eprintln!("--- {CLOUD_INIT_OUTPUT_LOG} ---");
+                        eprint!("{}", String::from_utf8_lossy(&log));
+                    }
+                    Some(Err(e)) => eprintln!("could not read {CLOUD_INIT_OUTPUT_LOG}: {e}"),
+                    None => {}
+                }
+                miette::bail!(
+                    help =
+                        format!("see `cloud-init status --long` in the guest (`vmctl ssh {name}`)"),
+                    "cloud-init failed in VM '{name}'"
+                );
+            }
+            Ok((_, 127, _)) => miette::bail!(
+                help = "drop --wait-cloud-init for images without cloud-init",
+                "cloud-init is not installed in VM '{name}'"
+            ),
+            Ok((status, _, _)) if Instant::now() >= deadline => miette::bail!(
+                "cloud-init in VM '{name}' did not finish within {}s (status: {})",
+                CLOUD_INIT_TIMEOUT.as_secs(),
+                status.as_deref().unwrap_or("unknown")
+            ),
+            Err(e) if Instant::now() >= deadline => return Err(e.into()),
+            // Still running, or the connection dropped
+            _ => tokio::time::sleep(CLOUD_INIT_POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// The value of the `status:` line of `cloud-init status` output: `done`, `error`, `running`,
+/// `disabled` or `not started`.
+fn cloud_init_status(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("status:"))
+        .map(str::trim)
+}
+
+/// Read a `--user-data-file`, which cloud-init only runs if it starts with a format marker.
+async fn read_user_data(path: &Path) -> Result<Vec<u8>> {
+    let data = read_file(path, "user-data").await?;
+    if !(data.starts_with(b"#cloud-config") || data.starts_with(b"#!")) {
+        miette::bail!(
+            severity = miette::Severity::Error,
+            code = "vmctl::create::invalid_user_data",
+            help = "start the file with a `#cloud-config` line for a cloud-config, or `#!` for a script",
+            "{} does not start with #cloud-config or #!, so cloud-init would ignore it",
+            path.display()
+        );
+    }
+    Ok(data)
+}
+
+/// `--image` as a URL, when it starts with one of [`IMAGE_URL_SCHEMES`].
+fn image_as_url(image: &Path) -> Option<&str> {
+    let image = image.to_str()?;
+    IMAGE_URL_SCHEMES
+        .iter()
+        .any(|scheme| image.starts_with(scheme))
+        .then_some(image)
+}
+
+async fn read_file(path: &Path, what: &str) -> Result<Vec<u8>> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|e| miette::miette!("cannot read {what} file {}: {e}", path.display()))
+}
+
+/// Create through the daemon. Local paths are sent as absolute paths, since the daemon reads
+/// them from its own working directory.
+pub async fn run_remote(
+    mut args: CreateArgs,
+    host: Option<&str>,
+    client: &ApiClient,
+) -> Result<()> {
+    if args.dry_run {
+        miette::bail!(
+            help = "run the dry run on the daemon's host without --remote",
+            "`vmctl create --dry-run` is not supported with --remote"
+        );
+    }
+    if args.wait_cloud_init {
+        miette::bail!(
+            help = "run `vmctl exec <name> -- cloud-init status --wait` on the daemon's host",
+            "`vmctl create --wait-cloud-init` is not supported with --remote"
+        );
+    }
+    if args.template.is_some() {
+        miette::bail!(
+            help = "pass --image-url, --vcpus and --memory instead",
+            "`vmctl create --template` is not supported with --remote"
+        );
+    }
+    // An image URL is pulled by the daemon as it is
+    let image = args
+        .image
+        .as_mut()
+        .filter(|image| image_as_url(image).is_none());
+    for path in [
+        image,
+        args.user_data_file.as_mut(),
+        args.meta_data_file.as_mut(),
+        args.ssh_key.as_mut(),
+        args.cdrom.as_mut(),
+        args.drivers_iso.as_mut(),
+        args.secure_boot_vars.as_mut(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        *path = client::absolute(path)?;
+    }
+    let start = args.start;
+    let request = CreateRequest {
+        args,
+        host: host.map(str::to_string),
+    };
+    let created: super::daemon::VmReply =
+        client.call("POST", "/v1/vms", &[], Some(&request)).await?;
+    println!("VM '{}' created (id: {})", created.vm.name, created.vm.id);
+    if start {
+        println!("VM '{}' started", created.vm.name);
+    }
+    Ok(())
 }
 
-pub async fn run(args: CreateArgs) -> Result<()> {
+/// Show what creating the VM would do, without creating it or touching the store.
+async fn dry_run(args: CreateArgs, host: Option<&str>, config: &Config) -> Result<()> {
+    if let Some(host) = host {
+        return Err(VmError::DryRunUnsupported {
+            backend: host.to_string(),
+        }
+        .into());
+    }
+    let template = load_template(&args)?;
+    let spec = build_spec(&args, template.as_ref(), None, config).await?;
+    let manager = super::manager(config, super::hypervisor(config)?);
+    super::print_dry_run(&manager.dry_run(spec).await?);
+    Ok(())
+}
+
+/// Create the VM, start it too if asked, and record it in the store. Returns its latest handle.
+pub async fn create(args: CreateArgs, host: Option<&str>, config: &Config) -> Result<VmHandle> {
+    let template = load_template(&args)?;
+    create_with(args, template.as_ref(), host, config).await
+}
+
+/// [`create`] with the `--template` already loaded.
+async fn create_with(
+    args: CreateArgs,
+    template: Option<&Template>,
+    host: Option<&str>,
+    config: &Config,
+) -> Result<VmHandle> {
+    let remote = host
+        .map(|h| RemoteHost::parse(h).map(RemoteBackend::new))
+        .transpose()
+        .into_diagnostic()?;
+    let spec = build_spec(&args, template, remote.as_ref(), config).await?;
+
+    let mut hv = super::hypervisor(config)?;
+    if let Some(remote) = remote {
+        hv = hv.with_remote(remote);
+    }
+    if args.start {
+        hostnames::check(config).await?;
+    }
+    let manager = super::checked_manager(config, hv, args.force);
+    let mut vm = manager.create(spec).await?;
+
+    let result = async {
+        if !args.start {
+            return Ok(vm.handle().clone());
+        }
+        vm.start().await?;
+        let updated =
+            hostnames::register(config, manager.hypervisor(), vm.handle().clone()).await?;
+        vm.record(updated).await?;
+        Ok(vm.handle().clone())
+    }
+    .await;
+
+    // A creation interrupted at any point after `prepare` leaves nothing behind.
+    if super::cancel().is_cancelled() {
+        super::roll_back_create(config, manager.hypervisor(), vm.into_handle()).await;
+    }
+    result
+}
+
+/// Validate the arguments and build the VM's spec, pulling its image unless this is a dry run.
+/// Flags take precedence over `template`, which takes precedence over the config.
+async fn build_spec(
+    args: &CreateArgs,
+    template: Option<&Template>,
+    remote: Option<&RemoteBackend>,
+    config: &Config,
+) -> Result<VmSpec> {
     // --- Input validation ---
-    if args.vcpus == 0 {
+    if args.vcpus == Some(0) {
         miette::bail!(
             severity = miette::Severity::Error,
             code = "vmctl::create::invalid_vcpus",
@@ -64,7 +540,7 @@ pub async fn run(args: CreateArgs) -> Result<()> {
             "vCPUs must be greater than 0"
         );
     }
-    if args.memory == 0 {
+    if args.memory == Some(0) {
         miette::bail!(
             severity = miette::Severity::Error,
             code = "vmctl::create::invalid_memory",
@@ -72,22 +548,91 @@ pub async fn run(args: CreateArgs) -> Result<()> {
             "memory must be greater than 0"
         );
     }
+    // Read before pulling the image, so a bad file fails fast
+    let user_data = match args.user_data_file {
+        Some(ref path) => Some(read_user_data(path).await?),
+        None => None,
+    };
+    let meta_data = match args.meta_data_file {
+        Some(ref path) => Some(read_file(path, "meta-data").await?),
+        None => None,
+    };
+
+    if let Some(arg) = vm_manager::managed_qemu_option(&args.qemu_args) {
+        return Err(VmError::QemuArgManaged { arg: arg.into() }.into());
+    }
 
-    // Check for name collision
-    let mut store = state::load_store().await?;
-    if store.contains_key(&args.name) {
-        miette::bail!(
-            severity = miette::Severity::Error,
-            code = "vmctl::create::name_exists",
-            help = "choose a different name or destroy the existing VM with `vmctl destroy {name}`",
-            "VM '{name}' already exists",
-            name = args.name
-        );
+    // Check for name collision before downloading anything
+    if state::load_store(config).await?.contains_key(&args.name) {
+        return Err(VmError::VmAlreadyExists {
+            name: args.name.clone(),
+        }
+        .into());
+    }
+
+    // Fall back to the template's image, then the configured default image, when neither
+    // --image nor --image-url is given. Relative paths in a template are relative to its file.
+    // An --image URL is pulled like --image-url, but cached under the name the URL gives it.
+    let url_image = args.image.as_deref().and_then(image_as_url);
+    let cache_name = match url_image {
+        Some(_) => None,
+        None => Some(args.name.as_str()),
+    };
+    let (image, image_url) = match (args.image.clone(), args.image_url.clone()) {
+        _ if url_image.is_some() => (None, url_image.map(str::to_string)),
+        (None, None) => match template.and_then(|t| t.image.as_ref().map(|i| (t, i))) {
+            Some((template, url)) if template.image_is_url() => (None, Some(url.clone())),
+            Some((template, path)) => (
+                Some(vm_manager::vmfile::resolve_path(path, &template.base_dir)),
+                None,
+            ),
+            None => match config.image.value.clone() {
+                Some(default) if default.contains("://") => (None, Some(default)),
+                Some(default) => (Some(PathBuf::from(default)), None),
+                None => (None, None),
+            },
+        },
+        explicit => explicit,
+    };
+
+    if let Some(ref iso) = args.cdrom {
+        if remote.is_none() && !iso.exists() {
+            miette::bail!(
+                severity = miette::Severity::Error,
+                code = "vmctl::create::cdrom_not_found",
+                help = "check the path is correct and the file exists",
+                "cdrom image not found: {}",
+                iso.display()
+            );
+        }
+    }
+    if let Some(ref vars) = args.secure_boot_vars {
+        if remote.is_none() && !vars.exists() {
+            miette::bail!(
+                severity = miette::Severity::Error,
+                code = "vmctl::create::secure_boot_vars_not_found",
+                help = "check the path is correct and the file exists",
+                "Secure Boot vars template not found: {}",
+                vars.display()
+            );
+        }
+    }
+    if let Some(ref iso) = args.drivers_iso {
+        if remote.is_none() && !iso.exists() {
+            miette::bail!(
+                severity = miette::Severity::Error,
+                code = "vmctl::create::drivers_iso_not_found",
+                help =
+                    "download virtio-win.iso from the Fedora virtio-win project, or check the path",
+                "drivers ISO not found: {}",
+                iso.display()
+            );
+        }
     }
 
     // Resolve image
-    let image_path = if let Some(ref path) = args.image {
-        if !path.exists() {
+    let image_path = if let Some(ref path) = image {
+        if remote.is_none() && !path.exists() {
             miette::bail!(
                 severity = miette::Severity::Error,
                 code = "vmctl::create::image_not_found",
@@ -97,41 +642,84 @@ pub async fn run(args: CreateArgs) -> Result<()> {
             );
         }
         path.clone()
-    } else if let Some(ref url) = args.image_url {
-        let mgr = vm_manager::image::ImageManager::new();
-        mgr.pull(url, Some(&args.name)).await.into_diagnostic()?
+    } else if let Some(ref url) = image_url {
+        if url.starts_with("s3://") {
+            miette::bail!(
+                severity = miette::Severity::Error,
+                code = "vmctl::create::unsupported_image_url",
+                help = "give the object's https:// URL instead, e.g. a presigned URL",
+                "cannot pull {url}: s3:// URLs are not supported"
+            );
+        }
+        if args.dry_run {
+            let cached = config.image_manager().cache_path(url, cache_name);
+            if !cached.exists() {
+                let pull = match cache_name {
+                    Some(name) => format!("vmctl image pull {url} --name {name}"),
+                    None => format!("vmctl image pull {url}"),
+                };
+                miette::bail!(
+                    severity = miette::Severity::Error,
+                    code = "vmctl::create::image_not_cached",
+                    help = format!("a dry run doesn't download images; run `{pull}` first"),
+                    "image {url} is not in the cache"
+                );
+            }
+            cached
+        } else if let Some(remote) = remote {
+            println!("Pulling image from {url}...");
+            remote.pull_image(url, cache_name).await.into_diagnostic()?
+        } else {
+            println!("Pulling image from {url}...");
+            let mgr = config
+                .image_manager()
+                .with_events(super::events().clone())
+                .with_cancel(super::cancel().clone());
+            mgr.pull(url, cache_name).await.into_diagnostic()?
+        }
     } else {
         miette::bail!(
             severity = miette::Severity::Error,
             code = "vmctl::create::no_image",
-            help = "provide --image for a local file or --image-url to download one",
+            help = "provide --image for a local file or --image-url to download one, use a --template with an image, or set a default `image` in the vmctl config",
             "either --image or --image-url must be specified"
         );
     };
 
-    // Build cloud-init config if user-data or ssh key provided
-    let cloud_init = if args.cloud_init.is_some() || args.ssh_key.is_some() {
-        let user_data = if let Some(ref path) = args.cloud_init {
-            tokio::fs::read(path).await.into_diagnostic()?
-        } else if let Some(ref key_path) = args.ssh_key {
-            let pubkey = tokio::fs::read_to_string(key_path)
-                .await
-                .into_diagnostic()?;
-            let (ud, _) = vm_manager::cloudinit::build_cloud_config(
-                "vm",
-                pubkey.trim(),
-                &args.name,
-                &args.name,
-            );
-            ud
+    // Build cloud-init config if user-data, meta-data or ssh keys are provided
+    let user = login_user(template, config);
+    let cloud_init = if args.no_cloud_init {
+        None
+    } else if user_data.is_some()
+        || meta_data.is_some()
+        || args.ssh_key.is_some()
+        || !args.import_ssh_keys.is_empty()
+    {
+        let user_data = if let Some(data) = user_data {
+            data
+        } else if args.ssh_key.is_some() || !args.import_ssh_keys.is_empty() {
+            let mut keys = Vec::new();
+            if let Some(ref key_path) = args.ssh_key {
+                let pubkey = tokio::fs::read_to_string(key_path)
+                    .await
+                    .into_diagnostic()?;
+                keys.push(pubkey.trim().to_string());
+            }
+            for source in &args.import_ssh_keys {
+                keys.extend(ssh_import::import(source).await?);
+            }
+            let mut cloud_config = vm_manager::cloudinit::CloudConfig::new(&user, "");
+            cloud_config.ssh_authorized_keys = keys;
+            cloud_config.to_user_data_for(args.guest_os)
         } else {
-            Vec::new()
+            b"#cloud-config\n".to_vec()
         };
 
         Some(CloudInitConfig {
             user_data,
             instance_id: Some(args.name.clone()),
             hostname: Some(args.name.clone()),
+            meta_data,
         })
     } else {
         None
@@ -139,48 +727,43 @@ pub async fn run(args: CreateArgs) -> Result<()> {
 
     // Build SSH config if key provided
     let ssh = args.ssh_key.as_ref().map(|key_path| SshConfig {
-        user: "vm".into(),
+        user: user.clone(),
         public_key: None,
         private_key_path: Some(key_path.clone()),
         private_key_pem: None,
     });
 
     // Network config
-    let network = if let Some(bridge) = args.bridge {
+    let network = if let Some(bridge) = args.bridge.clone().or_else(|| config.bridge.value.clone())
+    {
         NetworkConfig::Tap { bridge }
     } else {
         NetworkConfig::User
     };
 
-    let spec = VmSpec {
-        name: args.name.clone(),
-        image_path,
-        vcpus: args.vcpus,
-        memory_mb: args.memory,
-        disk_gb: args.disk,
-        network,
-        cloud_init,
-        ssh,
-        uefi: args.uefi,
-    };
-
-    let hv = RouterHypervisor::new(None, None);
-    let handle = hv.prepare(&spec).await.into_diagnostic()?;
-
-    info!(name = %args.name, id = %handle.id, "VM created");
-
-    // Persist handle
-    store.insert(args.name.clone(), handle.clone());
-    state::save_store(&store).await?;
-
-    println!("VM '{}' created (id: {})", args.name, handle.id);
-
-    if args.start {
-        let updated = hv.start(&handle).await.into_diagnostic()?;
-        store.insert(args.name.clone(), updated);
-        state::save_store(&store).await?;
-        println!("VM '{}' started", args.name);
+    let mut spec = VmSpec::new(&args.name, image_path);
+    if let Some(vcpus) = args.vcpus.or(template.and_then(|t| t.vcpus)) {
+        spec.vcpus = vcpus;
     }
-
-    Ok(())
+    if let Some(memory) = args.memory.or(template.and_then(|t| t.memory_mb)) {
+        spec.memory_mb = memory;
+    }
+    spec.disk_gb = args.disk;
+    spec.network = network;
+    spec.cloud_init = cloud_init;
+    spec.ssh = ssh;
+    spec.uefi = args.uefi || args.secure_boot || args.confidential.is_some();
+    spec.secure_boot = args.secure_boot;
+    spec.secure_boot_vars = args.secure_boot_vars.clone();
+    spec.confidential = args.confidential;
+    spec.cdrom = args.cdrom.clone();
+    spec.boot_order = args.boot_order.clone();
+    spec.guest_os = args.guest_os;
+    spec.display = args.display;
+    spec.audio = args.audio;
+    spec.disk_bus = args.disk_bus;
+    spec.drivers_iso = args.drivers_iso.clone();
+    spec.qemu_args = args.qemu_args.clone();
+    spec.restart_policy = args.restart_policy;
+    Ok(spec)
 }
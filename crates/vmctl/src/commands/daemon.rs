@@ -0,0 +1,573 @@
+//! `vmctl daemon`: owns the VMs and serves their operations as a REST API on a unix socket.
+//!
+//! Anyone who can write to the socket can use the API, so access is controlled with the
+//! socket's permissions. Every mutation of a VM goes through this one process, which serialises
+//! operations on the same VM and updates the store under its lock.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::IntoFuture;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{Path as PathParam, Query, State};
+use axum::http::{StatusCode, Uri};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use clap::Args;
+use futures_util::{Stream, StreamExt, TryStreamExt, stream};
+use miette::{IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::info;
+use vm_manager::config::{Config, ConfigSource};
+use vm_manager::metrics::ResourceUsage;
+use vm_manager::{BackendTag, Hypervisor, VcpuAffinity, VmHandle, VmState};
+
+use super::client::{self, ApiError};
+use super::create::CreateRequest;
+use super::log::LogLines;
+use super::{create, destroy, hostnames, list, start, state, status, stop};
+
+/// Name of the default socket in the data directory.
+const SOCKET_FILE: &str = "vmctl.sock";
+
+#[derive(Args)]
+pub struct DaemonArgs {
+    /// Socket to serve the API on, as `unix:///path` or a path (default: `vmctl.sock` in the data
+    /// directory)
+    #[arg(long)]
+    listen: Option<String>,
+}
+
+/// Reply to creating, starting or stopping a VM.
+#[derive(Serialize, Deserialize)]
+pub struct VmReply {
+    pub vm: VmHandle,
+}
+
+/// Reply to destroying a VM with `keep_disk=true`: where its disk files went.
+#[derive(Serialize, Deserialize)]
+pub struct KeptDisksReply {
+    pub disks: Vec<PathBuf>,
+}
+
+/// Reply to `GET /v1/vms/{name}`, and `vmctl status --format json`.
+#[derive(Serialize, Deserialize)]
+pub struct StatusReply {
+    pub vm: VmHandle,
+    pub state: VmState,
+    #[serde(default)]
+    pub usage: ResourceUsage,
+    /// Whether the guest reports Secure Boot as enforced; `None` unless the VM runs with Secure
+    /// Boot and its guest agent answered.
+    #[serde(default)]
+    pub guest_secure_boot: Option<bool>,
+    /// Host CPUs each vCPU thread may run on; empty unless the VM runs with CPU pinning.
+    #[serde(default)]
+    pub vcpu_affinity: Vec<VcpuAffinity>,
+}
+
+/// One VM in the reply to `GET /v1/vms`.
+#[derive(Serialize, Deserialize)]
+pub struct ListEntry {
+    pub namespace: String,
+    pub vm: VmHandle,
+    /// `None` when the backend could not tell.
+    #[serde(default)]
+    pub state: Option<VmState>,
+}
+
+/// Body of `POST /v1/images/pull`.
+#[derive(Serialize, Deserialize)]
+pub struct PullRequest {
+    pub url: String,
+    pub name: Option<String>,
+}
+
+/// Reply to `POST /v1/images/pull`.
+#[derive(Serialize, Deserialize)]
+pub struct PullReply {
+    pub path: PathBuf,
+}
+
+pub async fn run(args: DaemonArgs, config: &Config) -> Result<()> {
+    let path = match args.listen {
+        Some(ref listen) => client::socket_path(listen)?,
+        None => config.data_dir.value.join(SOCKET_FILE),
+    };
+    let listener = bind(&path).await?;
+    info!(
+        socket = %path.display(),
+        namespace = %config.namespace.value,
+        "serving the vmctl API"
+    );
+
+    let daemon = Arc::new(Daemon {
+        config: config.clone(),
+        locks: Mutex::default(),
+    });
+    let mut terminate = signal(SignalKind::terminate()).into_diagnostic()?;
+    // Not a graceful shutdown: log and event streams would keep it waiting forever.
+    let result = tokio::select! {
+        served = axum::serve(listener, router(daemon)).into_future() => served.into_diagnostic(),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+        _ = terminate.recv() => Ok(()),
+    };
+    let _ = std::fs::remove_file(&path);
+    info!("daemon stopped");
+    result
+}
+
+fn router(daemon: Arc<Daemon>) -> Router {
+    Router::new()
+        .route("/v1/vms", get(list_vms).post(create_vm))
+        .route("/v1/vms/{name}", get(vm_status).delete(destroy_vm))
+        .route("/v1/vms/{name}/start", post(start_vm))
+        .route("/v1/vms/{name}/stop", post(stop_vm))
+        .route("/v1/vms/{name}/logs", get(vm_logs))
+        .route("/v1/images/pull", post(pull_image))
+        .route("/v1/events", get(events))
+        .method_not_allowed_fallback(|| async {
+            Failure::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method not allowed here".into(),
+            )
+        })
+        .fallback(|uri: Uri| async move {
+            Failure::new(
+                StatusCode::NOT_FOUND,
+                format!("no such endpoint: {}", uri.path()),
+            )
+        })
+        .with_state(daemon)
+}
+
+/// Listen on `path`, replacing a socket left behind by a daemon that is gone. The socket is
+/// readable and writable by the daemon's user and group.
+async fn bind(path: &Path) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => miette::bail!(
+            help = "pick another socket path with --listen",
+            "{} exists and is not a socket",
+            path.display()
+        ),
+        Ok(_) if UnixStream::connect(path).await.is_ok() => miette::bail!(
+            help = "stop the other daemon first, or pick another socket path with --listen",
+            "a daemon is already listening on {}",
+            path.display()
+        ),
+        Ok(_) => std::fs::remove_file(path).into_diagnostic()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).into_diagnostic(),
+    }
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await.into_diagnostic()?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| {
+        miette::miette!(
+            help = "pick another socket path with --listen",
+            "cannot listen on {}: {e}",
+            path.display()
+        )
+    })?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660)).into_diagnostic()?;
+    Ok(listener)
+}
+
+/// A failed request: the HTTP status and the error body.
+struct Failure {
+    status: StatusCode,
+    body: ApiError,
+}
+
+impl Failure {
+    fn new(status: StatusCode, error: String) -> Self {
+        Self {
+            status,
+            body: ApiError {
+                error,
+                code: None,
+                help: None,
+            },
+        }
+    }
+
+    fn bad_request(error: String) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, error)
+    }
+}
+
+impl From<miette::Report> for Failure {
+    fn from(e: miette::Report) -> Self {
+        let code = e.code().map(|c| c.to_string());
+        let status = match code.as_deref() {
+            Some(c) if c.ends_with("::name_exists") => StatusCode::CONFLICT,
+            Some(c) if c.starts_with("vmctl::create::") => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self {
+            status,
+            body: ApiError {
+                error: e.to_string(),
+                code,
+                help: e.help().map(|h| h.to_string()),
+            },
+        }
+    }
+}
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        (self.status, axum::Json(self.body)).into_response()
+    }
+}
+
+/// A successful request's response.
+type Reply = std::result::Result<Response, Failure>;
+
+fn json(status: StatusCode, value: &impl Serialize) -> Reply {
+    Ok((status, axum::Json(value)).into_response())
+}
+
+/// The query parameters every request takes.
+#[derive(Deserialize)]
+struct NamespaceQuery {
+    namespace: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    all_namespaces: bool,
+    backend: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StartQuery {
+    boot_once: Option<String>,
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize)]
+struct StopQuery {
+    timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct DestroyQuery {
+    #[serde(default)]
+    keep_disk: bool,
+    keep_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct LogsQuery {
+    source: Option<String>,
+    #[serde(default)]
+    tail: usize,
+    #[serde(default)]
+    follow: bool,
+}
+
+/// Query parameters of type `T`, answering a malformed query like every other failure.
+struct Params<T>(T);
+
+impl<T: serde::de::DeserializeOwned + Send, S: Send + Sync> axum::extract::FromRequestParts<S>
+    for Params<T>
+{
+    type Rejection = Failure;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> std::result::Result<Self, Failure> {
+        Query::from_request_parts(parts, state)
+            .await
+            .map(|Query(params)| Self(params))
+            .map_err(|e| Failure::bad_request(e.body_text()))
+    }
+}
+
+type DaemonState = State<Arc<Daemon>>;
+
+async fn list_vms(
+    State(daemon): DaemonState,
+    Params(ns): Params<NamespaceQuery>,
+    Params(query): Params<ListQuery>,
+) -> Reply {
+    daemon.list(&daemon.config_for(ns)?, query).await
+}
+
+async fn create_vm(
+    State(daemon): DaemonState,
+    Params(ns): Params<NamespaceQuery>,
+    body: Bytes,
+) -> Reply {
+    daemon.create(&daemon.config_for(ns)?, &body).await
+}
+
+async fn vm_status(
+    State(daemon): DaemonState,
+    PathParam(name): PathParam<String>,
+    Params(ns): Params<NamespaceQuery>,
+) -> Reply {
+    daemon.status(&daemon.config_for(ns)?, &name).await
+}
+
+async fn start_vm(
+    State(daemon): DaemonState,
+    PathParam(name): PathParam<String>,
+    Params(ns): Params<NamespaceQuery>,
+    Params(query): Params<StartQuery>,
+) -> Reply {
+    daemon.start(&daemon.config_for(ns)?, &name, query).await
+}
+
+async fn stop_vm(
+    State(daemon): DaemonState,
+    PathParam(name): PathParam<String>,
+    Params(ns): Params<NamespaceQuery>,
+    Params(query): Params<StopQuery>,
+) -> Reply {
+    daemon.stop(&daemon.config_for(ns)?, &name, query).await
+}
+
+async fn destroy_vm(
+    State(daemon): DaemonState,
+    PathParam(name): PathParam<String>,
+    Params(ns): Params<NamespaceQuery>,
+    Params(query): Params<DestroyQuery>,
+) -> Reply {
+    daemon.destroy(&daemon.config_for(ns)?, &name, query).await
+}
+
+async fn vm_logs(
+    State(daemon): DaemonState,
+    PathParam(name): PathParam<String>,
+    Params(ns): Params<NamespaceQuery>,
+    Params(query): Params<LogsQuery>,
+) -> Reply {
+    daemon.logs(&daemon.config_for(ns)?, &name, query).await
+}
+
+async fn pull_image(
+    State(daemon): DaemonState,
+    Params(ns): Params<NamespaceQuery>,
+    body: Bytes,
+) -> Reply {
+    daemon.pull(&daemon.config_for(ns)?, &body).await
+}
+
+struct Daemon {
+    config: Config,
+    /// One lock per VM, so that operations on the same VM run one after another.
+    locks: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Daemon {
+    /// The daemon's configuration, switched to the namespace the request asks for.
+    fn config_for(&self, query: NamespaceQuery) -> std::result::Result<Config, Failure> {
+        let mut config = self.config.clone();
+        if let Some(ref namespace) = query.namespace {
+            config
+                .set_namespace(namespace, ConfigSource::Flag)
+                .map_err(|e| Failure::bad_request(e.to_string()))?;
+        }
+        Ok(config)
+    }
+
+    /// Wait for the lock of VM `name`.
+    async fn lock(&self, config: &Config, name: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(config.namespace_dir().join(name))
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    async fn find(&self, config: &Config, name: &str) -> std::result::Result<VmHandle, Failure> {
+        state::load_store(config)
+            .await?
+            .remove(name)
+            .ok_or_else(|| Failure::new(StatusCode::NOT_FOUND, format!("VM '{name}' not found")))
+    }
+
+    async fn list(&self, config: &Config, query: ListQuery) -> Reply {
+        let stores = if query.all_namespaces {
+            state::load_all_stores(config).await?
+        } else {
+            vec![(
+                config.namespace.value.clone(),
+                state::load_store(config).await?,
+            )]
+        };
+        let backend = query.backend.map(BackendTag::from);
+        let vms: Vec<(String, VmHandle)> = stores
+            .into_iter()
+            .flat_map(|(namespace, store)| {
+                store.into_values().map(move |vm| (namespace.clone(), vm))
+            })
+            .filter(|(_, vm)| backend.as_ref().is_none_or(|b| vm.backend == *b))
+            .collect();
+        let hv = super::hypervisor(config)?;
+        let states = list::states(&hv, vms.iter().map(|(_, vm)| vm)).await;
+        let entries: Vec<ListEntry> = vms
+            .into_iter()
+            .zip(states)
+            .map(|((namespace, vm), state)| ListEntry {
+                namespace,
+                vm,
+                state,
+            })
+            .collect();
+        json(StatusCode::OK, &entries)
+    }
+
+    async fn create(&self, config: &Config, body: &[u8]) -> Reply {
+        let request: CreateRequest = serde_json::from_slice(body)
+            .map_err(|e| Failure::bad_request(format!("invalid request: {e}")))?;
+        let _lock = self.lock(config, request.args.name()).await;
+        let vm = create::create(request.args, request.host.as_deref(), config).await?;
+        json(StatusCode::CREATED, &VmReply { vm })
+    }
+
+    async fn status(&self, config: &Config, name: &str) -> Reply {
+        let vm = self.find(config, name).await?;
+        let hv = super::hypervisor(config)?;
+        let state = hv.state(&vm).await.into_diagnostic()?;
+        let usage = vm_manager::metrics::resource_usage(&vm, state).await;
+        let guest_secure_boot = status::guest_secure_boot(&vm, state).await;
+        let vcpu_affinity = status::vcpu_affinity(&vm, state).await;
+        json(
+            StatusCode::OK,
+            &StatusReply {
+                vm,
+                state,
+                usage,
+                guest_secure_boot,
+                vcpu_affinity,
+            },
+        )
+    }
+
+    async fn start(&self, config: &Config, name: &str, query: StartQuery) -> Reply {
+        let boot_once = query
+            .boot_once
+            .map(|device| device.parse().map_err(Failure::bad_request))
+            .transpose()?;
+        let _lock = self.lock(config, name).await;
+        self.find(config, name).await?;
+        hostnames::check(config).await?;
+        let manager = super::checked_manager(config, super::hypervisor(config)?, query.force);
+        let vm = start::start_one(config, &manager, name, boot_once).await?;
+        json(StatusCode::OK, &VmReply { vm })
+    }
+
+    async fn stop(&self, config: &Config, name: &str, query: StopQuery) -> Reply {
+        let timeout = Duration::from_secs(query.timeout.unwrap_or(30));
+        let _lock = self.lock(config, name).await;
+        self.find(config, name).await?;
+        let vm = stop::stop(config, name, timeout).await?;
+        json(StatusCode::OK, &VmReply { vm })
+    }
+
+    async fn destroy(&self, config: &Config, name: &str, query: DestroyQuery) -> Reply {
+        let _lock = self.lock(config, name).await;
+        self.find(config, name).await?;
+        if query.keep_disk {
+            let keep_dir = query.keep_dir.as_deref();
+            let disks = destroy::destroy_keeping_disks(config, name, keep_dir).await?;
+            return json(StatusCode::OK, &KeptDisksReply { disks });
+        }
+        destroy::destroy(config, name).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+
+    async fn pull(&self, config: &Config, body: &[u8]) -> Reply {
+        let request: PullRequest = serde_json::from_slice(body)
+            .map_err(|e| Failure::bad_request(format!("invalid request: {e}")))?;
+        let mgr = config
+            .image_manager()
+            .with_events(super::events().clone())
+            .with_cancel(super::cancel().clone());
+        let path = mgr
+            .pull(&request.url, request.name.as_deref())
+            .await
+            .into_diagnostic()?;
+        json(StatusCode::OK, &PullReply { path })
+    }
+
+    /// Stream a VM's console or provision log, one event per line holding it as a JSON string,
+    /// so empty lines survive. The first event is `file` with the log's path, or `missing` if
+    /// there is no log yet and the client isn't following.
+    async fn logs(&self, config: &Config, name: &str, query: LogsQuery) -> Reply {
+        let source = match query.source.as_deref().unwrap_or("console") {
+            source @ ("console" | "provision") => source,
+            other => return Err(Failure::bad_request(format!("unknown log source: {other}"))),
+        };
+        let handle = self.find(config, name).await?;
+
+        let path = handle.work_dir.join(format!("{source}.log"));
+        let shown = path.display().to_string();
+        let lines = LogLines::open(&path, query.tail, query.follow)
+            .await
+            .into_diagnostic()?;
+        let Some(lines) = lines else {
+            let missing = Event::default().event("missing").data(shown);
+            return Ok(sse(stream::iter([Ok::<_, Infallible>(missing)])));
+        };
+        let first = Event::default().event("file").data(shown);
+        let rest = stream::try_unfold(lines, |mut lines| async move {
+            Ok::<_, axum::BoxError>(lines.next().await?.map(|batch| (batch, lines)))
+        })
+        .map_ok(|batch| {
+            stream::iter(batch).map(|line| {
+                Event::default()
+                    .json_data(line)
+                    .map_err(axum::BoxError::from)
+            })
+        })
+        .try_flatten();
+        Ok(sse(stream::once(async { Ok(first) }).chain(rest)))
+    }
+}
+
+/// `events` as a server-sent event stream, with comments sent while it is idle so that clients
+/// that went away are noticed.
+fn sse<E>(events: impl Stream<Item = std::result::Result<Event, E>> + Send + 'static) -> Response
+where
+    E: Into<axum::BoxError>,
+{
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Stream every lifecycle event of the daemon's operations as JSON, one event each.
+async fn events() -> Response {
+    let rx = super::events().subscribe();
+    let events = stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(event) => Event::default().json_data(&event),
+            Err(RecvError::Lagged(n)) => {
+                Ok(Event::default().comment(format!("skipped {n} events")))
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, rx))
+    });
+    sse::<axum::Error>(events)
+}
@@ -1,25 +1,87 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
 use clap::Args;
-use miette::{IntoDiagnostic, Result};
-use vm_manager::{Hypervisor, RouterHypervisor};
+use miette::Result;
+use vm_manager::config::Config;
+use vm_manager::manager::preserved_disks_dir;
 
-use super::state;
+use super::client::{self, ApiClient};
+use super::daemon::KeptDisksReply;
+use super::hostnames;
 
 #[derive(Args)]
 pub struct DestroyArgs {
     /// VM name
     name: String,
-}
 
-pub async fn run(args: DestroyArgs) -> Result<()> {
-    let mut store = state::load_store().await?;
-    let handle = store
-        .remove(&args.name)
-        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+    /// Shut the VM down and move its disk out of the work directory before destroying it
+    #[arg(long)]
+    keep_disk: bool,
+
+    /// Directory to move the disk to (default: `_preserved/<name>-<date>` next to the VM work
+    /// directories)
+    #[arg(long, value_name = "PATH", requires = "keep_disk")]
+    keep_dir: Option<PathBuf>,
+}
 
-    let hv = RouterHypervisor::new(None, None);
-    hv.destroy(handle).await.into_diagnostic()?;
+pub async fn run(args: DestroyArgs, config: &Config) -> Result<()> {
+    if args.keep_disk {
+        let disks = destroy_keeping_disks(config, &args.name, args.keep_dir.as_deref()).await?;
+        print_kept(&args.name, &disks);
+        return Ok(());
+    }
+    destroy(config, &args.name).await?;
+    println!("VM '{}' destroyed", args.name);
+    Ok(())
+}
 
-    state::save_store(&store).await?;
+pub async fn run_remote(args: DestroyArgs, client: &ApiClient) -> Result<()> {
+    let path = format!("/v1/vms/{}", client::encode(&args.name));
+    if args.keep_disk {
+        let keep_dir = args.keep_dir.as_ref().map(|dir| dir.display().to_string());
+        let mut query = vec![("keep_disk", "true")];
+        if let Some(ref dir) = keep_dir {
+            query.push(("keep_dir", dir));
+        }
+        let reply: KeptDisksReply = client.call("DELETE", &path, &query, None::<&()>).await?;
+        print_kept(&args.name, &reply.disks);
+        return Ok(());
+    }
+    let () = client.call("DELETE", &path, &[], None::<&()>).await?;
     println!("VM '{}' destroyed", args.name);
     Ok(())
 }
+
+fn print_kept(name: &str, disks: &[PathBuf]) {
+    println!("VM '{name}' destroyed, keeping its disk:");
+    for disk in disks {
+        println!("  {}", disk.display());
+    }
+}
+
+/// Destroy a VM and remove it from the store.
+pub async fn destroy(config: &Config, name: &str) -> Result<()> {
+    let manager = super::manager(config, super::hypervisor(config)?);
+    let handle = manager.get(name).await?.into_handle();
+    let vm = manager.attach(hostnames::unregister(handle).await?);
+    vm.destroy().await?;
+    Ok(())
+}
+
+/// Destroy a VM like [`destroy`], first moving its disk to `keep_dir`, or by default to
+/// `_preserved/<name>-<date>` next to its work directory. Returns where the files went.
+pub async fn destroy_keeping_disks(
+    config: &Config,
+    name: &str,
+    keep_dir: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let manager = super::manager(config, super::hypervisor(config)?);
+    let handle = manager.get(name).await?.into_handle();
+    let dir = match keep_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => preserved_disks_dir(&handle, SystemTime::now()),
+    };
+    let vm = manager.attach(hostnames::unregister(handle).await?);
+    Ok(vm.destroy_keeping_disks(&dir).await?)
+}
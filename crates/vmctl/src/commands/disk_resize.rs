@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::backends::qemu::ROOT_DRIVE;
+use vm_manager::backends::qmp::QmpPool;
+use vm_manager::config::Config;
+use vm_manager::{BackendTag, Hypervisor, VmState};
+
+use super::state;
+
+#[derive(Args)]
+pub struct DiskResizeArgs {
+    /// VM name
+    name: String,
+
+    /// New disk size, e.g. `40G` or `512M` (powers of 1024); the disk can only grow
+    size: String,
+}
+
+pub async fn run(args: DiskResizeArgs, config: &Config) -> Result<()> {
+    let Some(new_bytes) = vm_manager::image::parse_size(&args.size) else {
+        miette::bail!(
+            help = "use a number of bytes with an optional K, M, G or T suffix, e.g. 40G",
+            "invalid disk size '{}'",
+            args.size
+        );
+    };
+
+    let mut store = state::load_store(config).await?;
+    let handle = store.get(&args.name).cloned().ok_or_else(|| {
+        miette::miette!(
+            "VM '{}' not found — run `vmctl list` to see available VMs",
+            args.name
+        )
+    })?;
+    if handle.backend != BackendTag::Qemu || handle.remote_host.is_some() {
+        miette::bail!(
+            "VM '{}' uses the {} backend{} — disk-resize only supports local QEMU VMs",
+            args.name,
+            handle.backend,
+            if handle.remote_host.is_some() {
+                " on a remote host"
+            } else {
+                ""
+            }
+        );
+    }
+    let overlay = handle
+        .overlay_path
+        .clone()
+        .ok_or_else(|| miette::miette!("VM '{}' has no disk image", args.name))?;
+
+    let current = vm_manager::image::virtual_size(&overlay).await?;
+    if new_bytes < current {
+        miette::bail!(
+            help = "disks can only grow; shrinking would cut off guest data",
+            "VM '{}' disk is already {} bytes, larger than {}",
+            args.name,
+            current,
+            args.size
+        );
+    }
+
+    let hv = super::hypervisor(config)?;
+    match hv.state(&handle).await.into_diagnostic()? {
+        // QEMU holds a write lock on the overlay, so let it resize the file itself.
+        VmState::Running | VmState::Suspended => {
+            let socket = handle
+                .qmp_socket
+                .as_deref()
+                .ok_or_else(|| miette::miette!("VM '{}' has no QMP socket", args.name))?;
+            QmpPool::shared()
+                .run(socket, Duration::from_secs(10), async |qmp| {
+                    qmp.block_resize(ROOT_DRIVE, new_bytes).await
+                })
+                .await?;
+        }
+        _ => vm_manager::image::resize(&overlay, new_bytes).await?,
+    }
+
+    let mut updated = handle;
+    updated.disk_gb = Some(new_bytes.div_ceil(1 << 30) as u32);
+    store.insert(args.name.clone(), updated);
+    state::save_store(config, &store).await?;
+
+    println!("VM '{}' disk resized to {}", args.name, args.size);
+    Ok(())
+}
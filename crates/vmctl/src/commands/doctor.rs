@@ -0,0 +1,115 @@
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::capabilities::{self, BinaryInfo};
+use vm_manager::config::Config;
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    /// Print the probe result as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+pub async fn run(args: DoctorArgs, config: &Config) -> Result<()> {
+    let caps = tokio::task::spawn_blocking(capabilities::probe)
+        .await
+        .into_diagnostic()?;
+    let leases: Vec<(String, bool)> = config
+        .lease_sources
+        .value
+        .iter()
+        .map(|s| (s.to_string(), s.exists()))
+        .collect();
+
+    if args.json {
+        let mut value = serde_json::to_value(&caps).into_diagnostic()?;
+        value["lease_sources"] = leases
+            .iter()
+            .map(|(source, found)| serde_json::json!({ "source": source, "found": found }))
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&value).into_diagnostic()?
+        );
+        return Ok(());
+    }
+
+    println!("Host:             {} {}", caps.os, caps.arch);
+    println!(
+        "KVM:              {}",
+        if caps.kvm {
+            "available"
+        } else {
+            "not available"
+        }
+    );
+    println!("QEMU:             {}", format_binary(caps.qemu.as_ref()));
+    println!(
+        "qemu-img:         {}",
+        format_binary(caps.qemu_img.as_ref())
+    );
+    println!(
+        "cloud-hypervisor: {}",
+        format_binary(caps.cloud_hypervisor.as_ref())
+    );
+    println!(
+        "ISO tooling:      {}",
+        caps.iso_tool
+            .as_ref()
+            .map(|t| t.to_string())
+            .unwrap_or_else(|| "not found".into())
+    );
+    println!("Free memory:      {}", format_mb(caps.free_memory_mb));
+    println!("Free disk:        {}", format_mb(caps.free_disk_mb));
+    println!("Backend:          {}", caps.preferred_backend());
+    for (i, (source, found)) in leases.iter().enumerate() {
+        let label = if i == 0 { "DHCP leases:" } else { "" };
+        let status = if *found { "found" } else { "not found" };
+        println!("{label:<17} {source} ({status})");
+    }
+
+    let mut problems = Vec::new();
+    if let Some(reason) = caps.qemu_unavailable_reason() {
+        problems.push(format!("QEMU backend unavailable: {reason}"));
+    }
+    if caps.qemu_img.is_none() {
+        problems.push("qemu-img not found — overlays and image conversion will fail".into());
+    }
+    if caps.iso_tool.is_none() {
+        problems
+            .push("no ISO tool found — install genisoimage or mkisofs to use cloud-init".into());
+    }
+    if !leases.iter().any(|(_, found)| *found) {
+        problems.push(
+            "no DHCP lease sources found — bridged guests' IPs can only be discovered from the neighbour table".into(),
+        );
+    }
+
+    if !problems.is_empty() {
+        println!();
+        for p in &problems {
+            println!("warning: {p}");
+        }
+    }
+
+    Ok(())
+}
+
+fn format_binary(bin: Option<&BinaryInfo>) -> String {
+    match bin {
+        Some(BinaryInfo {
+            path,
+            version: Some(v),
+        }) => format!("{} ({v})", path.display()),
+        Some(BinaryInfo {
+            path,
+            version: None,
+        }) => path.display().to_string(),
+        None => "not found".into(),
+    }
+}
+
+fn format_mb(mb: Option<u64>) -> String {
+    mb.map(|m| format!("{m} MB"))
+        .unwrap_or_else(|| "unknown".into())
+}
@@ -3,9 +3,10 @@ use std::time::Duration;
 
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
-use vm_manager::{Hypervisor, RouterHypervisor};
+use vm_manager::Hypervisor;
+use vm_manager::config::Config;
 
-use super::state;
+use super::{hostnames, state};
 
 #[derive(Args)]
 pub struct DownArgs {
@@ -22,12 +23,12 @@ pub struct DownArgs {
     destroy: bool,
 }
 
-pub async fn run(args: DownArgs) -> Result<()> {
+pub async fn run(args: DownArgs, config: &Config) -> Result<()> {
     let path = vm_manager::vmfile::discover(args.file.as_deref()).into_diagnostic()?;
     let vmfile = vm_manager::vmfile::parse(&path).into_diagnostic()?;
 
-    let mut store = state::load_store().await?;
-    let hv = RouterHypervisor::new(None, None);
+    let mut store = state::load_store(config).await?;
+    let hv = super::hypervisor(config)?;
 
     for def in &vmfile.vms {
         if let Some(ref filter) = args.name {
@@ -37,10 +38,11 @@ pub async fn run(args: DownArgs) -> Result<()> {
         }
 
         if let Some(handle) = store.get(&def.name).cloned() {
+            let handle = hostnames::unregister(handle).await?;
             if args.destroy {
                 store.remove(&def.name);
                 hv.destroy(handle).await.into_diagnostic()?;
-                state::save_store(&store).await?;
+                state::save_store(config, &store).await?;
                 println!("VM '{}' destroyed", def.name);
             } else {
                 let updated = hv
@@ -48,7 +50,7 @@ pub async fn run(args: DownArgs) -> Result<()> {
                     .await
                     .into_diagnostic()?;
                 store.insert(def.name.clone(), updated);
-                state::save_store(&store).await?;
+                state::save_store(config, &store).await?;
                 println!("VM '{}' stopped", def.name);
             }
         } else {
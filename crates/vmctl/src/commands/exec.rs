@@ -0,0 +1,153 @@
+//! `vmctl exec`: run a command in a VM and exit with its exit code.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::config::Config;
+
+use super::ssh::{SshTarget, resolve_target};
+
+#[derive(Args)]
+pub struct ExecArgs {
+    /// VM name or `<name>.local` hostname
+    name: String,
+
+    /// Command and arguments to run, after `--`
+    #[arg(required = true, last = true)]
+    command: Vec<String>,
+
+    /// Run through the QEMU guest agent instead of SSH, which works without guest networking.
+    /// The command is started directly, not by a shell
+    #[arg(long)]
+    via_agent: bool,
+
+    /// SSH user (overrides VMFile ssh block)
+    #[arg(long, conflicts_with = "via_agent")]
+    user: Option<String>,
+
+    /// Path to SSH private key
+    #[arg(long, conflicts_with = "via_agent")]
+    key: Option<PathBuf>,
+
+    /// Path to VMFile.kdl (for reading ssh user)
+    #[arg(long, conflicts_with = "via_agent")]
+    file: Option<PathBuf>,
+
+    /// Connect over IPv6 when the guest has both an IPv4 and a global IPv6 address
+    #[arg(long, conflicts_with = "via_agent")]
+    prefer_ipv6: bool,
+
+    /// Seconds to wait for the guest to report an IP address
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    wait: u64,
+}
+
+pub async fn run(args: ExecArgs, config: &Config) -> Result<()> {
+    let exit_code = if args.via_agent {
+        via_agent(&args, config).await?
+    } else {
+        via_ssh(&args, config).await?
+    };
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+/// Run the command over SSH, with its output streamed. Arguments are quoted for the guest's
+/// shell so they arrive unchanged.
+async fn via_ssh(args: &ExecArgs, config: &Config) -> Result<i32> {
+    let SshTarget {
+        ip,
+        port,
+        config: ssh_config,
+        remote,
+    } = resolve_target(
+        config,
+        &args.name,
+        args.user.clone(),
+        args.key.clone(),
+        args.file.as_deref(),
+        args.prefer_ipv6,
+        Duration::from_secs(args.wait),
+    )
+    .await?;
+
+    let (sess, tunnel) = vm_manager::backends::remote::connect_guest(
+        remote.as_ref(),
+        &ip,
+        port,
+        &ssh_config,
+        Duration::from_secs(30),
+        super::cancel(),
+    )
+    .await
+    .into_diagnostic()?;
+
+    let cmd = args
+        .command
+        .iter()
+        .map(|arg| format!("'{}'", arg.replace('\'', "'\\''")))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let (_, _, exit_code) = tokio::task::spawn_blocking(move || {
+        let result =
+            vm_manager::ssh::exec_streaming(&sess, &cmd, std::io::stdout(), std::io::stderr());
+        drop(tunnel);
+        result
+    })
+    .await
+    .into_diagnostic()?
+    .into_diagnostic()?;
+    Ok(exit_code)
+}
+
+/// Run the command through the guest agent. Its output is printed once it finishes.
+#[cfg(target_os = "linux")]
+async fn via_agent(args: &ExecArgs, config: &Config) -> Result<i32> {
+    use std::io::Write;
+
+    let handle = agent_vm(config, &args.name).await?;
+    let (cmd, cmd_args) = args.command.split_first().expect("command is required");
+    let (cmd, cmd_args) = (cmd.clone(), cmd_args.to_vec());
+    let output = tokio::task::spawn_blocking(move || {
+        vm_manager::backends::qga::exec(&handle, &cmd, &cmd_args, true)
+    })
+    .await
+    .into_diagnostic()?
+    .into_diagnostic()?;
+
+    std::io::stdout()
+        .write_all(output.stdout.as_bytes())
+        .into_diagnostic()?;
+    std::io::stderr()
+        .write_all(output.stderr.as_bytes())
+        .into_diagnostic()?;
+    if output.truncated {
+        eprintln!("warning: the guest agent truncated the command's output");
+    }
+    Ok(output.exit_code)
+}
+
+/// The VM `name`, which must be a local QEMU VM to have a guest agent channel.
+#[cfg(target_os = "linux")]
+pub(super) async fn agent_vm(config: &Config, name: &str) -> Result<vm_manager::VmHandle> {
+    let store = super::state::load_store(config).await?;
+    let handle = super::state::find(&store, name)
+        .ok_or_else(|| miette::miette!("VM '{name}' not found — run `vmctl up` first"))?;
+    if handle.backend != vm_manager::BackendTag::Qemu || handle.remote_host.is_some() {
+        miette::bail!(
+            help = "use SSH instead by dropping --via-agent",
+            "VM '{}' is not a local QEMU VM — only those have a guest agent channel",
+            handle.name
+        );
+    }
+    Ok(handle.clone())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn via_agent(_args: &ExecArgs, _config: &Config) -> Result<i32> {
+    miette::bail!("--via-agent requires the QEMU backend, which is only available on Linux")
+}
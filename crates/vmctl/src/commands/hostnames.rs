@@ -0,0 +1,161 @@
+//! Register running VMs as `<name>.local` according to the `hostnames` setting.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::config::Config;
+use vm_manager::hostnames::{HostnameMode, HostsFile};
+use vm_manager::{Hypervisor, RouterHypervisor, VmHandle};
+
+/// PID file of a VM's mDNS responder, in its work directory.
+const MDNS_PID_FILE: &str = "mdns.pid";
+
+#[derive(Args)]
+pub struct MdnsArgs {
+    /// Name to answer for, e.g. `web.local`
+    #[arg(long)]
+    hostname: String,
+
+    /// IPv4 address to answer with
+    #[arg(long)]
+    ip: Ipv4Addr,
+}
+
+/// Fail before a VM starts if its name could not be registered afterwards.
+pub async fn check(config: &Config) -> Result<()> {
+    if config.hostnames.value == HostnameMode::Hosts {
+        HostsFile::default().check_writable().await?;
+    }
+    Ok(())
+}
+
+/// Register a freshly started VM's name and record it in the handle.
+///
+/// Remote VMs and guests only reachable through a forwarded port (user-mode networking) are
+/// skipped, as is a guest whose address can't be found; those only produce a warning.
+pub async fn register(
+    config: &Config,
+    hv: &RouterHypervisor,
+    mut handle: VmHandle,
+) -> Result<VmHandle> {
+    let mode = config.hostnames.value;
+    if mode == HostnameMode::Off || handle.remote_host.is_some() {
+        return Ok(handle);
+    }
+    let hostname = vm_manager::hostnames::hostname_for(&handle.name);
+
+    let ip = match hv.guest_ip(&handle, vm_manager::DEFAULT_IP_TIMEOUT).await {
+        Ok(ip) => ip,
+        Err(e) => {
+            eprintln!("warning: not registering {hostname}: {e}");
+            return Ok(handle);
+        }
+    };
+    let ip: IpAddr = ip.parse().into_diagnostic()?;
+    if ip.is_loopback() {
+        eprintln!(
+            "warning: not registering {hostname}: VM '{}' uses user-mode networking; connect with `vmctl ssh {}`",
+            handle.name, handle.name
+        );
+        return Ok(handle);
+    }
+
+    match mode {
+        HostnameMode::Hosts => {
+            HostsFile::default()
+                .register(
+                    &handle.name,
+                    &ip.to_string(),
+                    std::slice::from_ref(&hostname),
+                )
+                .await?;
+        }
+        HostnameMode::Mdns => {
+            let IpAddr::V4(ip) = ip else {
+                eprintln!(
+                    "warning: not registering {hostname}: mDNS publishing supports IPv4 only"
+                );
+                return Ok(handle);
+            };
+            stop_responder(&handle.work_dir).await;
+            spawn_responder(&handle.work_dir, &hostname, ip).await?;
+        }
+        HostnameMode::Off => return Ok(handle),
+    }
+    println!("VM '{}' registered as {hostname} ({ip})", handle.name);
+    handle.hostnames = vec![hostname];
+    Ok(handle)
+}
+
+/// Withdraw a VM's registered names before it stops or is destroyed.
+///
+/// Works from what the handle recorded rather than the current setting, so names registered
+/// before the setting changed are still cleaned up.
+pub async fn unregister(mut handle: VmHandle) -> Result<VmHandle> {
+    if handle.hostnames.is_empty() {
+        return Ok(handle);
+    }
+    if !stop_responder(&handle.work_dir).await {
+        HostsFile::default().unregister(&handle.name).await?;
+    }
+    handle.hostnames.clear();
+    Ok(handle)
+}
+
+/// Answer mDNS queries until terminated (spawned per VM by [`register`]).
+pub async fn run_mdns(args: MdnsArgs) -> Result<()> {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .into_diagnostic()?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    };
+    vm_manager::hostnames::serve_mdns(&args.hostname, args.ip, shutdown).await?;
+    Ok(())
+}
+
+async fn spawn_responder(work_dir: &Path, hostname: &str, ip: Ipv4Addr) -> Result<()> {
+    let exe = std::env::current_exe().into_diagnostic()?;
+    let log = std::fs::File::create(work_dir.join("mdns.log")).into_diagnostic()?;
+    // Detach from our process group so the responder outlives this command and its terminal.
+    let child = std::process::Command::new(exe)
+        .args(["__mdns", "--hostname", hostname, "--ip"])
+        .arg(ip.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log)
+        .process_group(0)
+        .spawn()
+        .into_diagnostic()?;
+    tokio::fs::write(pid_file(work_dir), format!("{}\n", child.id()))
+        .await
+        .into_diagnostic()?;
+    Ok(())
+}
+
+/// Terminate the VM's mDNS responder, if it has one; returns whether a PID file was found.
+async fn stop_responder(work_dir: &Path) -> bool {
+    let path = pid_file(work_dir);
+    let Ok(pid) = tokio::fs::read_to_string(&path).await else {
+        return false;
+    };
+    // A failed kill means the responder already exited; the PID file is stale either way.
+    let _ = tokio::process::Command::new("kill")
+        .arg(pid.trim())
+        .stderr(Stdio::null())
+        .status()
+        .await;
+    let _ = tokio::fs::remove_file(&path).await;
+    true
+}
+
+/// Where the VM's mDNS responder records its PID.
+pub fn pid_file(work_dir: &Path) -> PathBuf {
+    work_dir.join(MDNS_PID_FILE)
+}
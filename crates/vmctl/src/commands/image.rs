@@ -1,7 +1,13 @@
 use std::path::PathBuf;
 
-use clap::{Args, Subcommand};
+use clap::{Args, Subcommand, ValueEnum};
 use miette::{IntoDiagnostic, Result};
+use vm_manager::config::Config;
+use vm_manager::image::{ImageAuth, ImageManager};
+
+use super::client::ApiClient;
+use super::daemon::{PullReply, PullRequest};
+use super::progress::Progress;
 
 #[derive(Args)]
 pub struct ImageCommand {
@@ -14,9 +20,15 @@ enum ImageAction {
     /// Download an image to the local cache
     Pull(PullArgs),
     /// List cached images
-    List,
+    List(ListArgs),
     /// Show image format and details
     Inspect(InspectArgs),
+    /// Check whether a cached image matches its published checksum, without downloading it
+    Check(CheckArgs),
+    /// Build an image with packer and add it to the cache
+    Build(BuildArgs),
+    /// Upload a cached image to an HTTP server with PUT
+    Push(PushArgs),
 }
 
 #[derive(Args)]
@@ -27,6 +39,87 @@ struct PullArgs {
     /// Name to save as in the cache
     #[arg(long)]
     name: Option<String>,
+
+    /// Download into this directory instead of the image cache (created if missing)
+    #[arg(long, value_name = "PATH")]
+    output_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Output format
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    format: ListFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListFormat {
+    /// Aligned columns with human-readable sizes
+    Table,
+    /// A JSON array of images, sizes in bytes
+    Json,
+    /// `name,size_bytes,path,sha256` with a header row
+    Csv,
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    /// Image URL, or the name of an image in the cache
+    image: String,
+
+    /// URL of the published checksum file (`SHA256SUMS` or `<image>.sha256`)
+    #[arg(long)]
+    checksum_url: String,
+
+    /// Name the image was saved as in the cache (when checking by URL)
+    #[arg(long)]
+    name: Option<String>,
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    /// Packer template to build
+    packer_file: PathBuf,
+
+    /// Name to save the built image as in the cache
+    #[arg(long = "name")]
+    output_name: String,
+
+    /// Extra packer variable as key=value (repeatable)
+    #[arg(long = "var")]
+    vars: Vec<String>,
+}
+
+#[derive(Args)]
+struct PushArgs {
+    /// Name of the image in the cache
+    name: String,
+
+    /// URL to PUT the image to
+    #[arg(long)]
+    url: String,
+
+    /// User name for HTTP basic authentication
+    #[arg(long, env = "VMCTL_PUSH_USER", requires = "password")]
+    user: Option<String>,
+
+    /// Password for HTTP basic authentication
+    #[arg(
+        long,
+        env = "VMCTL_PUSH_PASSWORD",
+        hide_env_values = true,
+        requires = "user"
+    )]
+    password: Option<String>,
+
+    /// Bearer token to authenticate with
+    #[arg(
+        long,
+        env = "VMCTL_PUSH_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "user"
+    )]
+    token: Option<String>,
 }
 
 #[derive(Args)]
@@ -35,20 +128,74 @@ struct InspectArgs {
     path: PathBuf,
 }
 
-pub async fn run(args: ImageCommand) -> Result<()> {
+/// Only `image pull` goes through the daemon; the image lands in the daemon's cache.
+pub async fn run_remote(args: ImageCommand, client: &ApiClient) -> Result<()> {
+    let ImageAction::Pull(pull) = args.action else {
+        miette::bail!(
+            help = "run it without --remote on the daemon's host",
+            "only `vmctl image pull` is supported with --remote"
+        );
+    };
+    if pull.output_dir.is_some() {
+        miette::bail!(
+            help = "pull without --remote, or without --output-dir to use the daemon's cache",
+            "--output-dir is not supported with --remote"
+        );
+    }
+    let request = PullRequest {
+        url: pull.url,
+        name: pull.name,
+    };
+    let reply: PullReply = client
+        .call("POST", "/v1/images/pull", &[], Some(&request))
+        .await?;
+    println!("Image cached at: {}", reply.path.display());
+    Ok(())
+}
+
+pub async fn run(args: ImageCommand, config: &Config) -> Result<()> {
     match args.action {
         ImageAction::Pull(pull) => {
-            let mgr = vm_manager::image::ImageManager::new();
-            let path = mgr
-                .pull(&pull.url, pull.name.as_deref())
-                .await
-                .into_diagnostic()?;
+            let events = super::events();
+            let mgr = match pull.output_dir {
+                Some(dir) => ImageManager::with_cache_dir(dir),
+                None => config.image_manager(),
+            };
+            let mgr = mgr
+                .with_events(events.clone())
+                .with_cancel(super::cancel().clone());
+            let progress = Progress::start(events);
+            let result = mgr.pull(&pull.url, pull.name.as_deref()).await;
+            progress.finish().await;
+            let path = result.into_diagnostic()?;
             println!("Image cached at: {}", path.display());
         }
-        ImageAction::List => {
-            let mgr = vm_manager::image::ImageManager::new();
+        ImageAction::List(list) => {
+            let mgr = config.image_manager();
             let images = mgr.list().await.into_diagnostic()?;
 
+            match list.format {
+                ListFormat::Json => {
+                    let json = serde_json::to_string_pretty(&images).into_diagnostic()?;
+                    println!("{json}");
+                    return Ok(());
+                }
+                ListFormat::Csv => {
+                    println!("name,size_bytes,path,sha256");
+                    for img in images {
+                        println!(
+                            "{},{},{},{}",
+                            csv_field(&img.name),
+                            img.size_bytes,
+                            csv_field(&img.path.to_string_lossy()),
+                            img.sha256.unwrap_or_default()
+                        );
+                    }
+                    return Ok(());
+                }
+                ListFormat::Table => {}
+            }
+
             if images.is_empty() {
                 println!("No cached images.");
                 return Ok(());
@@ -66,6 +213,48 @@ pub async fn run(args: ImageCommand) -> Result<()> {
                 println!("{:<40} {:<12} {}", img.name, size, img.path.display());
             }
         }
+        ImageAction::Check(check) => {
+            let mgr = config.image_manager();
+            let (url, name) = if check.image.contains("://") {
+                (check.image.clone(), check.name.as_deref())
+            } else {
+                let url = mgr.cached_source(&check.image).await.ok_or_else(|| {
+                    miette::miette!(
+                        help = "pass the image URL instead; images pulled by older versions of vmctl don't record their source",
+                        "no source URL recorded for cached image '{}'",
+                        check.image
+                    )
+                })?;
+                (url, Some(check.image.as_str()))
+            };
+            let result = mgr
+                .check_remote(&url, &check.checksum_url, name)
+                .await
+                .into_diagnostic()?;
+            println!("{}: {result}", name.unwrap_or(&url));
+        }
+        ImageAction::Build(build) => {
+            let mgr = config.image_manager().with_cancel(super::cancel().clone());
+            let path = mgr
+                .build_packer(&build.packer_file, &build.output_name, &build.vars)
+                .await
+                .into_diagnostic()?;
+            println!("Image cached at: {}", path.display());
+        }
+        ImageAction::Push(push) => {
+            let auth = match (push.user, push.password, push.token) {
+                (Some(username), Some(password), _) => {
+                    Some(ImageAuth::Basic { username, password })
+                }
+                (_, _, Some(token)) => Some(ImageAuth::Bearer(token)),
+                _ => None,
+            };
+            let mgr = config.image_manager().with_cancel(super::cancel().clone());
+            mgr.push_http(&push.name, &push.url, auth)
+                .await
+                .into_diagnostic()?;
+            println!("Image '{}' uploaded to {}", push.name, push.url);
+        }
         ImageAction::Inspect(inspect) => {
             let fmt = vm_manager::image::detect_format(&inspect.path)
                 .await
@@ -86,3 +275,12 @@ pub async fn run(args: ImageCommand) -> Result<()> {
 
     Ok(())
 }
+
+/// `value` as a CSV field, quoted when it contains a comma, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use miette::Result;
+use vm_manager::config::Config;
+
+use super::state;
+
+/// Longest label value; anything bigger belongs in an annotation.
+const MAX_LABEL_VALUE: usize = 63;
+
+#[derive(Args)]
+pub struct LabelArgs {
+    /// VM name
+    name: String,
+
+    /// Labels to set, as `key=value`. Without labels or `--remove`, prints the VM's labels
+    labels: Vec<String>,
+
+    /// Label keys to remove (repeatable)
+    #[arg(long, value_name = "KEY")]
+    remove: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct AnnotateArgs {
+    /// VM name
+    name: String,
+
+    /// Annotations to set, as `key=value`; the value is everything after the first `=`. Without
+    /// annotations, prints the VM's annotations
+    annotations: Vec<String>,
+}
+
+pub async fn run_label(args: LabelArgs, config: &Config) -> Result<()> {
+    let mut store = state::load_store(config).await?;
+    let handle = store
+        .get_mut(&args.name)
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+
+    if args.labels.is_empty() && args.remove.is_empty() {
+        print_sorted(&handle.labels);
+        return Ok(());
+    }
+
+    let mut updates = Vec::with_capacity(args.labels.len());
+    for pair in &args.labels {
+        let (key, value) = parse_pair(pair)?;
+        if value.len() > MAX_LABEL_VALUE || value.chars().any(char::is_whitespace) {
+            miette::bail!(
+                help = "store long or free-form values with `vmctl annotate` instead",
+                "label value for '{key}' must be at most {MAX_LABEL_VALUE} characters without whitespace"
+            );
+        }
+        updates.push((key, value));
+    }
+    for key in &args.remove {
+        if handle.labels.remove(key).is_none() {
+            miette::bail!("VM '{}' has no label '{key}'", args.name);
+        }
+    }
+    for (key, value) in updates {
+        handle.labels.insert(key, value);
+    }
+
+    state::save_store(config, &store).await?;
+    println!("VM '{}' labels updated", args.name);
+    Ok(())
+}
+
+pub async fn run_annotate(args: AnnotateArgs, config: &Config) -> Result<()> {
+    let mut store = state::load_store(config).await?;
+    let handle = store
+        .get_mut(&args.name)
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+
+    if args.annotations.is_empty() {
+        print_sorted(&handle.annotations);
+        return Ok(());
+    }
+
+    let updates = args
+        .annotations
+        .iter()
+        .map(|pair| parse_pair(pair))
+        .collect::<Result<Vec<_>>>()?;
+    handle.annotations.extend(updates);
+
+    state::save_store(config, &store).await?;
+    println!("VM '{}' annotations updated", args.name);
+    Ok(())
+}
+
+/// Split `key=value` at the first `=`, so values may themselves contain `=` (e.g. base64).
+fn parse_pair(pair: &str) -> Result<(String, String)> {
+    let (key, value) = pair.split_once('=').ok_or_else(|| {
+        miette::miette!(
+            help = "use key=value, e.g. team=infra",
+            "invalid pair '{pair}': missing '='"
+        )
+    })?;
+    let valid_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'));
+    if !valid_key {
+        miette::bail!(
+            help = "keys may contain letters, digits, '.', '_', '-' and '/'",
+            "invalid key '{key}'"
+        );
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn print_sorted(map: &HashMap<String, String>) {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort();
+    for (key, value) in entries {
+        println!("{key}={value}");
+    }
+}
@@ -1,34 +1,212 @@
-use clap::Args;
+use clap::{Args, ValueEnum};
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
 use miette::Result;
-use vm_manager::NetworkConfig;
+use tracing::warn;
+use vm_manager::config::Config;
+use vm_manager::{BackendTag, Hypervisor, NetworkConfig, RouterHypervisor, VmHandle, VmState};
 
+use super::client::ApiClient;
+use super::daemon::ListEntry;
 use super::state;
 
 #[derive(Args)]
-pub struct ListArgs;
+pub struct ListArgs {
+    /// List the VMs of every namespace in the data directory, not just the current one
+    #[arg(long)]
+    all_namespaces: bool,
 
-pub async fn run(_args: ListArgs) -> Result<()> {
-    let store = state::load_store().await?;
+    /// Only list VMs in this state
+    #[arg(long, value_enum)]
+    state: Option<StateFilter>,
 
-    if store.is_empty() {
+    /// Only list VMs of this backend: the global `--backend`, which `list` takes as a filter
+    #[arg(skip)]
+    pub backend: Option<BackendTag>,
+
+    /// First add the VMs the local backends know about but the state store doesn't
+    #[arg(long)]
+    from_backend: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StateFilter {
+    Running,
+    Stopped,
+    Suspended,
+    Prepared,
+    Destroyed,
+}
+
+impl StateFilter {
+    fn matches(self, state: Option<VmState>) -> bool {
+        let wanted = match self {
+            Self::Running => VmState::Running,
+            Self::Stopped => VmState::Stopped,
+            Self::Suspended => VmState::Suspended,
+            Self::Prepared => VmState::Prepared,
+            Self::Destroyed => VmState::Destroyed,
+        };
+        state == Some(wanted)
+    }
+}
+
+pub async fn run(args: ListArgs, config: &Config) -> Result<()> {
+    let hv = super::hypervisor(config)?;
+    if args.from_backend {
+        reconcile(config, &hv).await?;
+    }
+    let stores = if args.all_namespaces {
+        state::load_all_stores(config).await?
+    } else {
+        vec![(
+            config.namespace.value.clone(),
+            state::load_store(config).await?,
+        )]
+    };
+
+    // The backend filter only reads the store, so apply it before querying any state
+    let handles: Vec<(&str, &VmHandle)> = stores
+        .iter()
+        .flat_map(|(ns, store)| store.values().map(move |h| (ns.as_str(), h)))
+        .filter(|(_, h)| args.backend.as_ref().is_none_or(|b| h.backend == *b))
+        .collect();
+    let states = states(&hv, handles.iter().map(|(_, h)| *h)).await;
+    let entries = handles
+        .into_iter()
+        .zip(states)
+        .map(|((ns, h), state)| (ns, h, state))
+        .collect();
+    print(entries, args.state, args.all_namespaces);
+    Ok(())
+}
+
+/// Add the VMs the local backends report that the current namespace's store lacks, and correct
+/// the pid recorded for those it has. Entries the backends don't report are left alone: a
+/// stopped VM leaves nothing for them to find.
+async fn reconcile(config: &Config, hv: &RouterHypervisor) -> Result<()> {
+    let found = hv.list_vms().await?;
+    let changes = state::with_store_mut(config, |store| {
+        let mut changes = Vec::new();
+        for vm in found {
+            match store.get_mut(&vm.name) {
+                None => {
+                    changes.push(format!(
+                        "Added VM '{}' from the {} backend to the state store",
+                        vm.name, vm.backend
+                    ));
+                    store.insert(vm.name.clone(), vm);
+                }
+                Some(known) if known.work_dir == vm.work_dir && known.pid != vm.pid => {
+                    changes.push(format!(
+                        "Updated the pid of VM '{}' to {}",
+                        vm.name,
+                        vm.pid.map_or_else(|| "none".to_string(), |p| p.to_string())
+                    ));
+                    known.pid = vm.pid;
+                }
+                Some(_) => {}
+            }
+        }
+        changes
+    })
+    .await?;
+    for change in changes {
+        println!("{change}");
+    }
+    Ok(())
+}
+
+/// The state of each of `handles`, queried concurrently, in the same order. `None` where the
+/// backend could not tell.
+pub async fn states<'a>(
+    hv: &RouterHypervisor,
+    handles: impl IntoIterator<Item = &'a VmHandle>,
+) -> Vec<Option<VmState>> {
+    let mut pending: FuturesUnordered<_> = handles
+        .into_iter()
+        .enumerate()
+        .map(|(i, handle)| async move { (i, handle, hv.state(handle).await) })
+        .collect();
+    let mut states = vec![None; pending.len()];
+    while let Some((i, handle, state)) = pending.next().await {
+        match state {
+            Ok(state) => states[i] = Some(state),
+            Err(e) => warn!(vm = %handle.name, error = %e, "failed to query VM state"),
+        }
+    }
+    states
+}
+
+pub async fn run_remote(args: ListArgs, client: &ApiClient) -> Result<()> {
+    if args.from_backend {
+        miette::bail!(
+            help = "run `vmctl list --from-backend` on the daemon's host without --remote",
+            "--from-backend is not supported with --remote"
+        );
+    }
+    let all = args.all_namespaces.to_string();
+    let backend = args.backend.as_ref().map(BackendTag::to_string);
+    let mut query = vec![("all_namespaces", all.as_str())];
+    query.extend(backend.as_deref().map(|b| ("backend", b)));
+    let entries: Vec<ListEntry> = client.call("GET", "/v1/vms", &query, None::<&()>).await?;
+    print(
+        entries
+            .iter()
+            .map(|e| (e.namespace.as_str(), &e.vm, e.state))
+            .collect(),
+        args.state,
+        args.all_namespaces,
+    );
+    Ok(())
+}
+
+fn print(
+    mut entries: Vec<(&str, &VmHandle, Option<VmState>)>,
+    filter: Option<StateFilter>,
+    all_namespaces: bool,
+) {
+    if let Some(filter) = filter {
+        entries.retain(|(_, _, state)| filter.matches(*state));
+        if entries.is_empty() {
+            let state = filter.to_possible_value().expect("no skipped variants");
+            println!("No {} VMs found.", state.get_name());
+            return;
+        }
+    }
+    if entries.is_empty() {
         println!("No VMs found.");
-        return Ok(());
+        return;
     }
+    entries.sort_by(|a, b| (a.0, &a.1.name).cmp(&(b.0, &b.1.name)));
 
+    let namespace = |ns: &str| {
+        if all_namespaces {
+            format!("{ns:<16} ")
+        } else {
+            String::new()
+        }
+    };
     println!(
-        "{:<16} {:<8} {:>5} {:>6} {:<10} {:<8} SSH",
-        "NAME", "BACKEND", "VCPUS", "MEM", "NETWORK", "PID"
+        "{}{:<16} {:<10} {:<8} {:>5} {:>6} {:<10} {:<8} SSH",
+        namespace("NAMESPACE"),
+        "NAME",
+        "STATE",
+        "BACKEND",
+        "VCPUS",
+        "MEM",
+        "NETWORK",
+        "PID"
     );
-    println!("{}", "-".repeat(72));
+    println!("{}", "-".repeat(if all_namespaces { 100 } else { 83 }));
 
-    let mut entries: Vec<_> = store.iter().collect();
-    entries.sort_by_key(|(name, _)| (*name).clone());
-
-    for (name, handle) in entries {
+    for (ns, handle, state) in entries {
+        let state = state.map_or_else(|| "unknown".to_string(), |s| s.to_string());
         let net = match &handle.network {
             NetworkConfig::Tap { .. } => "tap",
             NetworkConfig::User => "user",
             NetworkConfig::Vnic { .. } => "vnic",
+            NetworkConfig::Macvtap { .. } => "macvtap",
             NetworkConfig::None => "none",
         };
         let pid = handle
@@ -41,10 +219,16 @@ pub async fn run(_args: ListArgs) -> Result<()> {
             .unwrap_or_else(|| "-".into());
 
         println!(
-            "{:<16} {:<8} {:>5} {:>4}MB {:<10} {:<8} {}",
-            name, handle.backend, handle.vcpus, handle.memory_mb, net, pid, ssh
+            "{}{:<16} {:<10} {:<8} {:>5} {:>4}MB {:<10} {:<8} {}",
+            namespace(ns),
+            handle.name,
+            state,
+            handle.backend,
+            handle.vcpus,
+            handle.memory_mb,
+            net,
+            pid,
+            ssh
         );
     }
-
-    Ok(())
 }
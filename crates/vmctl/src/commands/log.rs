@@ -1,8 +1,17 @@
+use std::path::Path;
+use std::time::Duration;
+
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
+use tokio::io::AsyncReadExt;
+use vm_manager::config::Config;
 
+use super::client::{self, ApiClient};
 use super::state;
 
+/// How often a followed log is checked for new lines.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
 #[derive(Args)]
 pub struct LogArgs {
     /// VM name
@@ -19,53 +28,163 @@ pub struct LogArgs {
     /// Show the last N lines (0 = all)
     #[arg(long, short = 'n', default_value = "0")]
     tail: usize,
+
+    /// Keep printing lines as they are written (needs --console or --provision)
+    #[arg(long, short = 'f')]
+    follow: bool,
 }
 
-pub async fn run(args: LogArgs) -> Result<()> {
-    let store = state::load_store().await?;
+/// The logs to show, in order: both unless one was picked.
+fn sources(args: &LogArgs) -> Result<Vec<&'static str>> {
+    if args.follow && args.console == args.provision {
+        miette::bail!(
+            help = "pick --console or --provision",
+            "--follow follows one log at a time"
+        );
+    }
+    let mut sources = Vec::new();
+    if args.console || !args.provision {
+        sources.push("console");
+    }
+    if args.provision || !args.console {
+        sources.push("provision");
+    }
+    Ok(sources)
+}
+
+pub async fn run(args: LogArgs, config: &Config) -> Result<()> {
+    let store = state::load_store(config).await?;
     let handle = store
         .get(&args.name)
         .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
 
-    // If neither flag is set, show both
-    let show_console = args.console || !args.provision;
-    let show_provision = args.provision || !args.console;
-
-    if show_console {
-        let path = handle.work_dir.join("console.log");
-        print_log("console", &path, args.tail).await?;
+    for label in sources(&args)? {
+        let path = handle.work_dir.join(format!("{label}.log"));
+        print_log(label, &path, args.tail, args.follow).await?;
     }
 
-    if show_provision {
-        let path = handle.work_dir.join("provision.log");
-        print_log("provision", &path, args.tail).await?;
+    Ok(())
+}
+
+pub async fn run_remote(args: LogArgs, client: &ApiClient) -> Result<()> {
+    let path = format!("/v1/vms/{}/logs", client::encode(&args.name));
+    let (tail, follow) = (args.tail.to_string(), args.follow.to_string());
+    for label in sources(&args)? {
+        let query = [("source", label), ("tail", &tail), ("follow", &follow)];
+        client
+            .stream(&path, &query, |event, data| {
+                match event {
+                    "file" => println!("=== {label} log ({data}) ==="),
+                    "missing" => not_found(label),
+                    _ => println!(
+                        "{}",
+                        serde_json::from_str::<String>(data).into_diagnostic()?
+                    ),
+                }
+                Ok(())
+            })
+            .await?;
+        println!();
     }
+    Ok(())
+}
+
+fn not_found(label: &str) {
+    println!("=== {label} log: not found (VM may not have been started yet) ===");
+}
 
+async fn print_log(label: &str, path: &Path, tail: usize, follow: bool) -> Result<()> {
+    let Some(mut lines) = LogLines::open(path, tail, follow).await.into_diagnostic()? else {
+        not_found(label);
+        println!();
+        return Ok(());
+    };
+    println!("=== {label} log ({}) ===", path.display());
+    while let Some(batch) = lines.next().await.into_diagnostic()? {
+        for line in batch {
+            println!("{line}");
+        }
+    }
+    println!();
     Ok(())
 }
 
-async fn print_log(label: &str, path: &std::path::Path, tail: usize) -> Result<()> {
-    match tokio::fs::read_to_string(path).await {
-        Ok(content) => {
-            println!("=== {label} log ({}) ===", path.display());
-            if tail > 0 {
-                let lines: Vec<&str> = content.lines().collect();
-                let start = lines.len().saturating_sub(tail);
-                for line in &lines[start..] {
-                    println!("{line}");
+/// Reads a log line by line: first its last lines, then, when following, each line as it is
+/// completed.
+pub struct LogLines {
+    file: tokio::fs::File,
+    backlog: Option<Vec<String>>,
+    /// The unfinished last line of a followed log.
+    pending: Vec<u8>,
+    follow: bool,
+}
+
+impl LogLines {
+    /// Open the log at `path`, starting `tail` lines from its end (at its start if 0). Returns
+    /// `None` if there is no such log; with `follow`, waits for it to be created instead.
+    pub async fn open(path: &Path, tail: usize, follow: bool) -> std::io::Result<Option<Self>> {
+        let mut file = loop {
+            match tokio::fs::File::open(path).await {
+                Ok(file) => break file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && follow => {
+                    tokio::time::sleep(FOLLOW_INTERVAL).await;
                 }
-            } else {
-                print!("{content}");
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
             }
-            println!();
+        };
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).await?;
+        let pending = if follow {
+            let end = content
+                .iter()
+                .rposition(|b| *b == b'\n')
+                .map_or(0, |i| i + 1);
+            content.split_off(end)
+        } else {
+            Vec::new()
+        };
+        let text = String::from_utf8_lossy(&content);
+        let lines: Vec<&str> = text.lines().collect();
+        let start = if tail > 0 {
+            lines.len().saturating_sub(tail)
+        } else {
+            0
+        };
+        Ok(Some(Self {
+            file,
+            backlog: Some(lines[start..].iter().map(|l| l.to_string()).collect()),
+            pending,
+            follow,
+        }))
+    }
+
+    /// The next lines, or `None` at the end of a log that isn't followed.
+    pub async fn next(&mut self) -> std::io::Result<Option<Vec<String>>> {
+        if let Some(backlog) = self.backlog.take() {
+            return Ok(Some(backlog));
         }
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            println!("=== {label} log: not found (VM may not have been started yet) ===");
-            println!();
+        if !self.follow {
+            return Ok(None);
         }
-        Err(e) => {
-            return Err(e).into_diagnostic();
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = self.file.read(&mut buf).await?;
+            if n == 0 {
+                tokio::time::sleep(FOLLOW_INTERVAL).await;
+                continue;
+            }
+            self.pending.extend_from_slice(&buf[..n]);
+            let Some(end) = self.pending.iter().rposition(|b| *b == b'\n') else {
+                continue;
+            };
+            let complete: Vec<u8> = self.pending.drain(..=end).collect();
+            let lines = String::from_utf8_lossy(&complete)
+                .lines()
+                .map(str::to_string)
+                .collect();
+            return Ok(Some(lines));
         }
     }
-    Ok(())
 }
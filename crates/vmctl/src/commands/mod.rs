@@ -1,26 +1,115 @@
+pub mod agent;
+#[cfg(target_os = "linux")]
+pub mod attest;
+pub mod client;
+pub mod config_cmd;
 pub mod console;
 pub mod create;
+pub mod daemon;
 pub mod destroy;
+#[cfg(target_os = "linux")]
+pub mod disk_resize;
+pub mod doctor;
 pub mod down;
+pub mod exec;
+pub mod hostnames;
 pub mod image;
+pub mod label;
 pub mod list;
 pub mod log;
+#[cfg(target_os = "linux")]
+pub mod network;
+pub mod nuke;
+pub mod ping;
+pub mod port_forward;
+pub mod progress;
 pub mod provision_cmd;
+pub mod rdp;
+pub mod reconcile;
 pub mod reload;
+pub mod scp;
+#[cfg(target_os = "linux")]
+pub mod send_keys;
+#[cfg(target_os = "linux")]
+pub mod serve_metrics;
+#[cfg(target_os = "linux")]
+pub mod snapshot;
 pub mod ssh;
 pub mod start;
 pub mod state;
 pub mod status;
 pub mod stop;
+pub mod tail_console;
+#[cfg(target_os = "linux")]
+pub mod time_sync;
 pub mod up;
+#[cfg(target_os = "linux")]
+pub mod usb;
+pub mod validate;
+#[cfg(target_os = "linux")]
+pub mod watch;
+pub mod whoami;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
-use miette::Result;
-use vm_manager::{NetworkConfig, VmHandle};
+use miette::{IntoDiagnostic, Result};
+use vm_manager::config::{Config, ConfigSource};
+
+use crate::logging::LogFormat;
+use client::ApiClient;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::warn;
+use vm_manager::manager::GENERATED_KEY_FILE;
+use vm_manager::metrics::CounterRecorder;
+use vm_manager::store::VmStore;
+use vm_manager::{
+    CancellationToken, DryRun, EventBus, Hypervisor, RouterHypervisor, VmError, VmHandle, VmManager,
+};
 
 #[derive(Parser)]
 #[command(name = "vmctl", about = "Manage virtual machines", version)]
 pub struct Cli {
+    /// Create VMs on a remote hypervisor host, e.g. `ssh://user@server`
+    #[arg(long, global = true, env = "VMCTL_HOST")]
+    host: Option<String>,
+
+    /// Send commands to a `vmctl daemon` at this socket (`unix:///path` or a path)
+    #[arg(long, global = true, env = "VMCTL_REMOTE")]
+    remote: Option<String>,
+
+    /// Directory for VM state, work directories and the image cache
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+
+    /// Backend for new VMs (`qemu`, `propolis`, `noop`); with `list`, only list VMs of this
+    /// backend
+    #[arg(long, global = true)]
+    backend: Option<String>,
+
+    /// Namespace whose VMs to operate on (default: `$USER`)
+    #[arg(long, global = true)]
+    namespace: Option<String>,
+
+    /// Console log format
+    #[arg(
+        long,
+        global = true,
+        env = "VMCTL_LOG_FORMAT",
+        value_enum,
+        default_value_t
+    )]
+    pub log_format: LogFormat,
+
+    /// Also append logs to this file, with timestamps, whatever the console format
+    #[arg(long, global = true)]
+    pub log_file: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -35,20 +124,70 @@ enum Command {
     Stop(stop::StopArgs),
     /// Destroy a VM and clean up all resources
     Destroy(destroy::DestroyArgs),
+    /// Kill a VM and delete its files and state by force, for when `destroy` fails
+    Nuke(nuke::NukeArgs),
     /// List all VMs
     List(list::ListArgs),
+    /// Bring the state store back in line with the VM work directories on disk
+    Reconcile(reconcile::ReconcileArgs),
+    /// Print the current namespace
+    Whoami,
     /// Show VM status
     Status(status::StatusArgs),
     /// Attach to a VM's serial console
     Console(console::ConsoleArgs),
+    /// Stream a VM's serial console output without attaching
+    TailConsole(tail_console::TailConsoleArgs),
     /// SSH into a VM
     Ssh(ssh::SshArgs),
+    /// Run a command in a VM, over SSH or through the guest agent
+    Exec(exec::ExecArgs),
+    /// Check that a VM's network is reachable
+    Ping(ping::PingArgs),
+    /// Open a Remote Desktop session to a Windows VM
+    Rdp(rdp::RdpArgs),
+    /// Copy files to or from a VM over SFTP, or through the guest agent
+    #[command(visible_alias = "cp")]
+    Scp(scp::ScpArgs),
+    /// Forward local ports to a VM through an SSH tunnel
+    PortForward(port_forward::PortForwardArgs),
     /// Suspend a running VM (pause vCPUs)
     Suspend(start::SuspendArgs),
     /// Resume a suspended VM
     Resume(start::ResumeArgs),
+    /// Set a VM's clock to the host's through the guest agent
+    #[cfg(target_os = "linux")]
+    TimeSync(time_sync::TimeSyncArgs),
+    /// Serve Prometheus metrics for the namespace's VMs over HTTP
+    #[cfg(target_os = "linux")]
+    ServeMetrics(serve_metrics::ServeMetricsArgs),
+    /// Restart VMs that crash or stop on their own, according to their restart policy
+    #[cfg(target_os = "linux")]
+    Watch(watch::WatchArgs),
+    /// Grow a VM's disk, online if the VM is running
+    #[cfg(target_os = "linux")]
+    DiskResize(disk_resize::DiskResizeArgs),
+    /// Print a confidential VM's SEV launch measurement for attestation
+    #[cfg(target_os = "linux")]
+    Attest(attest::AttestArgs),
+    /// Type keys on a VM's keyboard, e.g. to drive a boot menu before SSH is up
+    #[cfg(target_os = "linux")]
+    SendKeys(send_keys::SendKeysArgs),
+    /// Export, restore or compare a VM's disk snapshots
+    #[cfg(target_os = "linux")]
+    Snapshot(snapshot::SnapshotCommand),
+    /// Pass host USB devices through to VMs
+    #[cfg(target_os = "linux")]
+    Usb(usb::UsbCommand),
     /// Manage VM images
     Image(image::ImageCommand),
+    /// Set, remove or show a VM's labels
+    Label(label::LabelArgs),
+    /// Set or show a VM's annotations
+    Annotate(label::AnnotateArgs),
+    /// Manage bridges with NAT and DHCP for VMs
+    #[cfg(target_os = "linux")]
+    Network(network::NetworkCommand),
     /// Bring up VMs defined in VMFile.kdl
     Up(up::UpArgs),
     /// Bring down VMs defined in VMFile.kdl
@@ -57,68 +196,337 @@ enum Command {
     Reload(reload::ReloadArgs),
     /// Re-run provisioners on running VMs from VMFile.kdl
     Provision(provision_cmd::ProvisionArgs),
+    /// Check VMFile.kdl, and that the host has room for its VMs
+    Validate(validate::ValidateArgs),
     /// Show VM console and provision logs
     Log(log::LogArgs),
+    /// Own the VMs and serve their operations as a REST API on a unix socket
+    Daemon(daemon::DaemonArgs),
+    /// Check the host for hypervisor support and required tools
+    Doctor(doctor::DoctorArgs),
+    /// Inspect vmctl configuration
+    Config(config_cmd::ConfigCommand),
+    /// Serve backend requests on stdin/stdout (used by `--host` over SSH)
+    #[command(name = "__agent", hide = true)]
+    Agent,
+    /// Answer mDNS queries for one VM (spawned when `hostnames` is `mdns`)
+    #[command(name = "__mdns", hide = true)]
+    Mdns(hostnames::MdnsArgs),
 }
 
 impl Cli {
-    pub async fn run(self) -> Result<()> {
-        match self.command {
-            Command::Create(args) => create::run(args).await,
-            Command::Start(args) => start::run_start(args).await,
-            Command::Stop(args) => stop::run(args).await,
-            Command::Destroy(args) => destroy::run(args).await,
-            Command::List(args) => list::run(args).await,
-            Command::Status(args) => status::run(args).await,
-            Command::Console(args) => console::run(args).await,
-            Command::Ssh(args) => ssh::run(args).await,
-            Command::Suspend(args) => start::run_suspend(args).await,
-            Command::Resume(args) => start::run_resume(args).await,
-            Command::Image(args) => image::run(args).await,
-            Command::Up(args) => up::run(args).await,
-            Command::Down(args) => down::run(args).await,
-            Command::Reload(args) => reload::run(args).await,
-            Command::Provision(args) => provision_cmd::run(args).await,
-            Command::Log(args) => log::run(args).await,
+    pub async fn run(mut self) -> Result<()> {
+        let mut config = Config::load().into_diagnostic()?;
+        if let Some(dir) = self.data_dir {
+            config.data_dir.set(dir, ConfigSource::Flag);
         }
+        // `list` creates nothing, so it filters by the backend rather than switching to it
+        if let Command::List(ref mut args) = self.command {
+            args.backend = self.backend.take().map(Into::into);
+        }
+        if let Some(backend) = self.backend {
+            config.backend.set(Some(backend.into()), ConfigSource::Flag);
+        }
+        if let Some(namespace) = self.namespace {
+            config.set_namespace(&namespace, ConfigSource::Flag)?;
+        }
+
+        // Count this run's lifecycle events towards `vmctl serve-metrics`.
+        let recorder = progress::Listener::start(events(), CounterRecorder::new());
+        // The daemon itself always acts locally, even with `VMCTL_REMOTE` set.
+        let remote = self
+            .remote
+            .filter(|_| !matches!(self.command, Command::Daemon(_)));
+        let result = match remote {
+            Some(ref remote) => {
+                let client = ApiClient::new(remote, &config)?;
+                dispatch_remote(self.command, self.host.as_deref(), &client).await
+            }
+            None => dispatch(self.command, self.host.as_deref(), &config).await,
+        };
+        if let Some(recorder) = recorder.finish().await {
+            let counters = recorder.counters();
+            if !counters.is_empty() {
+                if let Err(e) = counters.add_to_file(&config.counters_file()).await {
+                    warn!(error = %e, "failed to save metrics counters");
+                }
+            }
+        }
+        result
     }
 }
 
-/// Determine the SSH port for a VM handle: use the forwarded host port for user-mode networking,
-/// or 22 for all other network types.
-fn ssh_port_for_handle(handle: &VmHandle) -> u16 {
-    match handle.network {
-        NetworkConfig::User => handle.ssh_host_port.unwrap_or(22),
-        _ => 22,
+async fn dispatch(command: Command, host: Option<&str>, config: &Config) -> Result<()> {
+    match command {
+        Command::Create(args) => interruptible(create::run(args, host, config)).await,
+        Command::Start(args) => interruptible(start::run_start(args, config)).await,
+        Command::Stop(args) => stop::run(args, config).await,
+        Command::Destroy(args) => destroy::run(args, config).await,
+        Command::Nuke(args) => nuke::run(args, config).await,
+        Command::List(args) => list::run(args, config).await,
+        Command::Reconcile(args) => reconcile::run(args, config).await,
+        Command::Whoami => whoami::run(config),
+        Command::Status(args) => status::run(args, config).await,
+        Command::Console(args) => console::run(args, config).await,
+        Command::TailConsole(args) => tail_console::run(args, config).await,
+        Command::Ssh(args) => ssh::run(args, config).await,
+        Command::Exec(args) => exec::run(args, config).await,
+        Command::Ping(args) => ping::run(args, config).await,
+        Command::Rdp(args) => rdp::run(args, config).await,
+        Command::Scp(args) => scp::run(args, config).await,
+        Command::PortForward(args) => port_forward::run(args, config).await,
+        Command::Suspend(args) => start::run_suspend(args, config).await,
+        Command::Resume(args) => start::run_resume(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::TimeSync(args) => time_sync::run(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::ServeMetrics(args) => serve_metrics::run(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::Watch(args) => watch::run(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::DiskResize(args) => disk_resize::run(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::Attest(args) => attest::run(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::SendKeys(args) => send_keys::run(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::Snapshot(args) => snapshot::run(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::Usb(args) => usb::run(args, config).await,
+        Command::Image(args) => interruptible(image::run(args, config)).await,
+        Command::Label(args) => label::run_label(args, config).await,
+        Command::Annotate(args) => label::run_annotate(args, config).await,
+        #[cfg(target_os = "linux")]
+        Command::Network(args) => network::run(args, config).await,
+        Command::Up(args) => interruptible(up::run(args, config)).await,
+        Command::Down(args) => down::run(args, config).await,
+        Command::Reload(args) => interruptible(reload::run(args, config)).await,
+        Command::Provision(args) => interruptible(provision_cmd::run(args, config)).await,
+        Command::Validate(args) => validate::run(args, config).await,
+        Command::Log(args) => log::run(args, config).await,
+        Command::Daemon(args) => daemon::run(args, config).await,
+        Command::Doctor(args) => doctor::run(args, config).await,
+        Command::Config(args) => config_cmd::run(args, config).await,
+        Command::Agent => agent::run(config).await,
+        Command::Mdns(args) => hostnames::run_mdns(args).await,
     }
 }
 
-/// Well-known filename for a generated SSH private key, stored in the VM's work directory.
-const GENERATED_KEY_FILE: &str = "id_ed25519_generated";
+/// Send a command to the daemon, for the commands that it serves.
+async fn dispatch_remote(command: Command, host: Option<&str>, client: &ApiClient) -> Result<()> {
+    match command {
+        Command::Create(args) => create::run_remote(args, host, client).await,
+        Command::Start(args) => start::run_start_remote(args, client).await,
+        Command::Stop(args) => stop::run_remote(args, client).await,
+        Command::Destroy(args) => destroy::run_remote(args, client).await,
+        Command::List(args) => list::run_remote(args, client).await,
+        Command::Status(args) => status::run_remote(args, client).await,
+        Command::Log(args) => log::run_remote(args, client).await,
+        Command::Image(args) => image::run_remote(args, client).await,
+        _ => miette::bail!(
+            help = "the daemon serves create, start, stop, destroy, list, status, log and image pull; run other commands without --remote",
+            "this command is not supported with --remote"
+        ),
+    }
+}
 
-/// Persist a generated SSH private key PEM to the VM's work directory (if present).
-///
-/// The file is written with 0600 permissions so that OpenSSH accepts it.
-async fn save_generated_ssh_key(
-    spec: &vm_manager::VmSpec,
-    handle: &VmHandle,
-) -> miette::Result<()> {
-    use std::os::unix::fs::PermissionsExt;
-
-    if let Some(ref ssh) = spec.ssh {
-        if let Some(ref pem) = ssh.private_key_pem {
-            let key_path = handle.work_dir.join(GENERATED_KEY_FILE);
-            tokio::fs::write(&key_path, pem)
-                .await
-                .map_err(|e| miette::miette!("failed to save generated SSH key: {e}"))?;
-            tokio::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o600))
-                .await
-                .map_err(|e| miette::miette!("failed to set SSH key permissions: {e}"))?;
+/// Ends vmctl with this exit status and no message, for commands whose status is their answer,
+/// like `snapshot diff`. Returned as an error so the command's cleanup still runs.
+#[derive(Debug)]
+pub struct Exit(pub u8);
+
+impl std::fmt::Display for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "exit status {}", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+impl miette::Diagnostic for Exit {}
+
+/// Bus that every hypervisor, image manager and provision run of this process publishes into.
+fn events() -> &'static EventBus {
+    static EVENTS: OnceLock<EventBus> = OnceLock::new();
+    EVENTS.get_or_init(EventBus::new)
+}
+
+/// Token that the first Ctrl-C cancels while an [`interruptible`] command runs. Hypervisors,
+/// image managers, SSH retries and provision runs of this process all watch it.
+fn cancel() -> &'static CancellationToken {
+    static CANCEL: OnceLock<CancellationToken> = OnceLock::new();
+    CANCEL.get_or_init(CancellationToken::new)
+}
+
+/// Run a command that cleans up after itself when [`cancel`] fires. The first Ctrl-C cancels it
+/// and waits for the cleanup; a second one exits immediately. A cancelled command always fails,
+/// even if it got to finish its last step.
+async fn interruptible(command: impl Future<Output = Result<()>>) -> Result<()> {
+    let watcher = tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        eprintln!("cleaning up…");
+        cancel().cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("interrupted again, exiting without cleanup");
+            std::process::exit(130);
         }
+    });
+    let result = command.await;
+    watcher.abort();
+    match result {
+        Ok(()) if cancel().is_cancelled() => Err(VmError::Cancelled {
+            operation: "command".into(),
+        }
+        .into()),
+        result => result,
     }
-    Ok(())
 }
 
+/// Wait for blocking work such as a provision run, giving up once [`cancel`] fires. The work
+/// itself only stops at its next check, but the CLI exits as soon as cleanup is done, which
+/// closes the SSH session and hangs up the guest command in flight.
+async fn until_cancelled<T: Send + 'static>(
+    task: tokio::task::JoinHandle<vm_manager::Result<T>>,
+) -> Result<T> {
+    tokio::select! {
+        result = task => Ok(result.into_diagnostic()?.into_diagnostic()?),
+        _ = cancel().cancelled() => Err(VmError::Cancelled {
+            operation: "provisioning".into(),
+        }
+        .into()),
+    }
+}
+
+/// Undo a VM creation that was cancelled: destroy whatever the backend prepared or started and
+/// drop the VM's store entry. `handle` is used if the VM did not make it into the store.
+async fn roll_back_create(config: &Config, hv: &RouterHypervisor, handle: VmHandle) {
+    let name = handle.name.clone();
+    warn!(vm = %name, "cancelled, rolling back VM creation");
+    let handle = match state::load_store(config).await {
+        Ok(store) => store.get(&name).cloned().unwrap_or(handle),
+        Err(_) => handle,
+    };
+    let handle = match hostnames::unregister(handle.clone()).await {
+        Ok(handle) => handle,
+        Err(e) => {
+            warn!(vm = %name, error = %e, "failed to unregister hostnames of cancelled VM");
+            handle
+        }
+    };
+    if let Err(e) = hv.destroy(handle).await {
+        warn!(vm = %name, error = %e, "failed to destroy cancelled VM");
+    }
+    if let Err(e) = state::with_store_mut(config, |store| store.remove(&name)).await {
+        warn!(vm = %name, error = %e, "failed to remove cancelled VM from the store");
+    }
+}
+
+/// How many VMs multi-VM commands bring up at the same time unless `--parallel` says otherwise.
+const DEFAULT_PARALLEL: usize = 4;
+
+/// Run one task per VM, at most `parallel` at a time. Every task runs to completion; the results
+/// of those that succeeded and the errors of those that failed are returned in completion order.
+async fn run_parallel<T, Fut>(
+    tasks: Vec<(String, Fut)>,
+    parallel: usize,
+) -> (Vec<(String, T)>, Vec<(String, miette::Report)>)
+where
+    T: Send + 'static,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+{
+    let limit = Arc::new(Semaphore::new(parallel.max(1)));
+    let mut set = JoinSet::new();
+    let mut names = HashMap::new();
+    for (name, task) in tasks {
+        let limit = limit.clone();
+        let id = set
+            .spawn(async move {
+                let _permit = limit.acquire_owned().await;
+                task.await
+            })
+            .id();
+        names.insert(id, name);
+    }
+
+    let (mut done, mut failed) = (Vec::new(), Vec::new());
+    while let Some(joined) = set.join_next_with_id().await {
+        match joined {
+            Ok((id, Ok(value))) => done.push((names.remove(&id).unwrap_or_default(), value)),
+            Ok((id, Err(e))) => failed.push((names.remove(&id).unwrap_or_default(), e)),
+            Err(e) => failed.push((
+                names.remove(&e.id()).unwrap_or_default(),
+                miette::miette!("task failed: {e}"),
+            )),
+        }
+    }
+    (done, failed)
+}
+
+/// Print every VM's error, then fail with a summary; succeed if there were none.
+fn report_failures(failures: Vec<(String, miette::Report)>, total: usize) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    for (name, e) in &failures {
+        eprintln!("VM '{name}' failed: {e:?}");
+    }
+    let mut names: Vec<&str> = failures.iter().map(|(name, _)| name.as_str()).collect();
+    names.sort_unstable();
+    miette::bail!(
+        "{} of {total} VMs failed: {}",
+        failures.len(),
+        names.join(", ")
+    )
+}
+
+/// The hypervisor router for `config`, publishing into [`events`] and watching [`cancel`].
+fn hypervisor(config: &Config) -> Result<RouterHypervisor> {
+    Ok(
+        RouterHypervisor::from_config_with_cancel(config, cancel().clone())
+            .into_diagnostic()?
+            .with_events(events().clone()),
+    )
+}
+
+/// A [`VmManager`] over `hv` and the configured namespace's store, watching [`cancel`].
+fn manager(config: &Config, hv: RouterHypervisor) -> VmManager {
+    VmManager::new(hv, VmStore::for_config(config)).with_cancel(cancel().clone())
+}
+
+/// [`manager`], checking that the host has the memory and disk space for VMs before they are
+/// created or started, unless `force`.
+fn checked_manager(config: &Config, hv: RouterHypervisor, force: bool) -> VmManager {
+    let manager = manager(config, hv);
+    if force {
+        manager
+    } else {
+        manager.with_resource_checks(config.data_dir.value.clone())
+    }
+}
+
+/// Print a dry run: each command on one line, shell-quoted, the MAC address as a comment, then
+/// each generated file under a `# <location>` header.
+fn print_dry_run(dry_run: &DryRun) {
+    for line in dry_run.shell_lines() {
+        println!("{line}");
+    }
+    if let Some(ref mac) = dry_run.mac_addr {
+        println!("\n# MAC address: {mac}");
+    }
+    for file in &dry_run.files {
+        println!("\n# {}", file.location);
+        print!("{}", file.contents);
+        if !file.contents.ends_with('\n') {
+            println!();
+        }
+    }
+}
+
+/// How long provisioning waits for a freshly booted guest to report an IP address.
+const PROVISION_IP_TIMEOUT: Duration = Duration::from_secs(120);
+
 /// Build an `SshConfig` from a VMFile ssh block and (optionally) a persisted generated key.
 ///
 /// If the ssh block specifies `private-key`, use that file. Otherwise, look for a previously
@@ -0,0 +1,107 @@
+use clap::{Args, Subcommand};
+use miette::Result;
+use vm_manager::config::Config;
+use vm_manager::network::{ManagedNetwork, NetworkManager, Subnet};
+
+#[derive(Args)]
+pub struct NetworkCommand {
+    #[command(subcommand)]
+    action: NetworkAction,
+}
+
+#[derive(Subcommand)]
+enum NetworkAction {
+    /// Create a bridge with NAT and DHCP (requires root)
+    Create(CreateArgs),
+    /// List managed networks
+    List,
+    /// Show a managed network and the host resources it owns
+    Inspect(NameArgs),
+    /// Delete a managed network and all of its host resources (requires root)
+    Delete(NameArgs),
+}
+
+#[derive(Args)]
+struct CreateArgs {
+    /// Network name; the bridge is called `vm-<name>`
+    name: String,
+
+    /// IPv4 subnet in CIDR notation; the first address becomes the host's gateway
+    #[arg(long)]
+    subnet: String,
+}
+
+#[derive(Args)]
+struct NameArgs {
+    /// Network name
+    name: String,
+}
+
+pub async fn run(args: NetworkCommand, config: &Config) -> Result<()> {
+    let mgr = NetworkManager::new(config.networks_dir());
+    match args.action {
+        NetworkAction::Create(create) => {
+            let subnet = Subnet::parse(&create.subnet)?;
+            let network = mgr.create(&create.name, subnet).await?;
+            println!(
+                "Network '{}' created: bridge {}, gateway {}",
+                network.name,
+                network.bridge,
+                network.subnet.gateway()
+            );
+            println!(
+                "Attach VMs with `vmctl create --bridge {}` or `network \"tap\" bridge=\"{}\"` in a VMFile",
+                network.bridge, network.bridge
+            );
+        }
+        NetworkAction::List => {
+            let networks = mgr.list().await?;
+            if networks.is_empty() {
+                println!("No managed networks.");
+                return Ok(());
+            }
+
+            println!(
+                "{:<14} {:<16} {:<20} {:<16} DHCP",
+                "NAME", "BRIDGE", "SUBNET", "GATEWAY"
+            );
+            println!("{}", "-".repeat(76));
+            for network in networks {
+                println!(
+                    "{:<14} {:<16} {:<20} {:<16} {}",
+                    network.name,
+                    network.bridge,
+                    network.subnet.to_string(),
+                    network.subnet.gateway().to_string(),
+                    dhcp_status(&network)
+                );
+            }
+        }
+        NetworkAction::Inspect(inspect) => {
+            let network = mgr.get(&inspect.name).await?;
+            let (start, end) = network.subnet.dhcp_range();
+            println!("Name:    {}", network.name);
+            println!("Bridge:  {}", network.bridge);
+            println!("Subnet:  {}", network.subnet);
+            println!("Gateway: {}", network.subnet.gateway());
+            println!("DHCP:    {start} - {end} ({})", dhcp_status(&network));
+            println!("Leases:  {}", mgr.lease_file(&network.name).display());
+            println!("Host resources:");
+            for resource in &network.resources {
+                println!("  {resource}");
+            }
+        }
+        NetworkAction::Delete(delete) => {
+            mgr.delete(&delete.name).await?;
+            println!("Network '{}' deleted", delete.name);
+        }
+    }
+    Ok(())
+}
+
+fn dhcp_status(network: &ManagedNetwork) -> String {
+    match network.dnsmasq_pid() {
+        Some(pid) => format!("running (pid {pid})"),
+        None => "stopped".into(),
+    }
+}
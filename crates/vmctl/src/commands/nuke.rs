@@ -0,0 +1,219 @@
+use std::path::Path;
+use std::process::Stdio;
+
+use clap::Args;
+use miette::Result;
+use tracing::{info, warn};
+use vm_manager::VmHandle;
+use vm_manager::backends::remote::RemoteHost;
+use vm_manager::config::Config;
+use vm_manager::hostnames::HostsFile;
+use vm_manager::types::shell_quote;
+
+use super::{hostnames, state};
+
+#[derive(Args)]
+pub struct NukeArgs {
+    /// VM name
+    name: String,
+}
+
+/// Tear a VM down by force, for when `vmctl destroy` fails. Every step is tried whatever
+/// happened in the ones before; problems are logged and the command always succeeds.
+pub async fn run(args: NukeArgs, config: &Config) -> Result<()> {
+    let handle = match state::load_store(config).await {
+        Ok(store) => store.get(&args.name).cloned(),
+        Err(e) => {
+            warn!(error = %e, "cannot read the VM store");
+            None
+        }
+    };
+    let Some(handle) = handle else {
+        warn!(vm = %args.name, "VM not found in the store, nothing to nuke");
+        return Ok(());
+    };
+
+    let responder = match handle.remote_host {
+        Some(ref host) => {
+            nuke_remote(&handle, host).await;
+            false
+        }
+        None => nuke_local(&handle).await,
+    };
+    if !handle.hostnames.is_empty() && !responder {
+        if let Err(e) = HostsFile::default().unregister(&handle.name).await {
+            warn!(vm = %handle.name, error = %e, "failed to remove hosts file entries");
+        }
+    }
+    if let Err(e) = state::with_store_mut(config, |store| store.remove(&args.name)).await {
+        warn!(vm = %args.name, error = %e, "failed to remove the VM from the store");
+    }
+
+    println!("VM '{}' nuked", args.name);
+    Ok(())
+}
+
+/// Kill the VM's process and mDNS responder, remove its host network devices and delete its
+/// work directory. Returns whether the VM had an mDNS responder.
+async fn nuke_local(handle: &VmHandle) -> bool {
+    // The PID in the store may be stale, so also take the ones the processes wrote themselves.
+    let mut pids: Vec<u32> = handle.pid.into_iter().collect();
+    let responder_file = hostnames::pid_file(&handle.work_dir);
+    let responder = responder_file.exists();
+    for file in [handle.work_dir.join("qemu.pid"), responder_file] {
+        if let Ok(pid) = tokio::fs::read_to_string(&file).await {
+            pids.extend(pid.trim().parse::<u32>().ok());
+        }
+    }
+    pids.sort_unstable();
+    pids.dedup();
+    for pid in pids {
+        let cmdline = tokio::fs::read(format!("/proc/{pid}/cmdline")).await.ok();
+        if cmdline.is_some_and(|cmdline| belongs_to(&cmdline, handle)) {
+            let mut cmd = tokio::process::Command::new("kill");
+            cmd.args(kill_args(pid));
+            kill(&mut cmd, pid, &handle.name).await;
+        } else {
+            warn!(vm = %handle.name, pid, "not killing PID, it can't be verified to be the VM's");
+        }
+    }
+    remove_network_devices(handle).await;
+
+    let dir = &handle.work_dir;
+    if suspicious(dir) {
+        warn!(vm = %handle.name, dir = %dir.display(), "not removing suspicious work directory");
+        return responder;
+    }
+    match tokio::fs::remove_dir_all(dir).await {
+        Ok(()) => info!(vm = %handle.name, dir = %dir.display(), "removed work directory"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            warn!(vm = %handle.name, dir = %dir.display(), error = %e, "failed to remove work directory")
+        }
+    }
+    responder
+}
+
+/// Remove the VM's macvtap device and the bridges of private networks it was the last member
+/// of, as `destroy` does.
+#[cfg(target_os = "linux")]
+async fn remove_network_devices(handle: &VmHandle) {
+    use vm_manager::network;
+
+    if let Some(ref device) = handle.macvtap {
+        match network::delete_macvtap(device).await {
+            Ok(()) => info!(vm = %handle.name, %device, "removed macvtap device"),
+            Err(e) => {
+                warn!(vm = %handle.name, %device, error = %e, "failed to remove macvtap device")
+            }
+        }
+    }
+    for nic in handle
+        .private_networks
+        .iter()
+        .filter(|n| n.bridge.is_some())
+    {
+        match network::remove_private_bridge_if_unused(&nic.network).await {
+            Ok(true) => {
+                info!(vm = %handle.name, network = %nic.network, "removed private network bridge")
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!(vm = %handle.name, network = %nic.network, error = %e, "failed to remove private network bridge")
+            }
+        }
+    }
+}
+
+/// Host network devices are only created on Linux.
+#[cfg(not(target_os = "linux"))]
+async fn remove_network_devices(_handle: &VmHandle) {}
+
+/// Whether the process with the command line `cmdline` (as in `/proc/<pid>/cmdline`) is still
+/// one of the VM's: its QEMU, started with the pidfile in its work directory, or its mDNS
+/// responder, answering for one of its hostnames. The PID may have been reused since.
+#[cfg(target_os = "linux")]
+fn belongs_to(cmdline: &[u8], handle: &VmHandle) -> bool {
+    use vm_manager::backends::qemu;
+
+    let args = qemu::split_cmdline(cmdline);
+    let responder = args.first().is_some_and(|arg| arg == "__mdns")
+        && args
+            .windows(2)
+            .any(|w| w[0] == "--hostname" && handle.hostnames.contains(&w[1]));
+    qemu::is_qemu_of(&args, &handle.work_dir) || responder
+}
+
+/// Processes can only be verified on Linux; elsewhere none are killed.
+#[cfg(not(target_os = "linux"))]
+fn belongs_to(_cmdline: &[u8], _handle: &VmHandle) -> bool {
+    false
+}
+
+/// Whether `dir` is `/`, a top-level directory or relative: never let a damaged store entry
+/// point `rm -rf` at one of those.
+fn suspicious(dir: &Path) -> bool {
+    !dir.is_absolute() || dir.parent().and_then(Path::parent).is_none()
+}
+
+/// Arguments of `kill` to send `SIGKILL` to `pid`.
+fn kill_args(pid: u32) -> [String; 2] {
+    ["-KILL".into(), pid.to_string()]
+}
+
+/// Run `cmd`, a `kill` with [`kill_args`] for `pid`, logging how it went.
+async fn kill(cmd: &mut tokio::process::Command, pid: u32, vm: &str) {
+    let status = cmd
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => info!(vm, pid, "killed"),
+        Ok(_) => warn!(vm, pid, "could not kill, the process may be gone already"),
+        Err(e) => warn!(vm, pid, error = %e, "could not run kill"),
+    }
+}
+
+/// Kill the VM's process and delete its work directory on its remote host. ssh hands the remote
+/// shell one string, so every argument is quoted for it.
+async fn nuke_remote(handle: &VmHandle, host: &str) {
+    let host = match RemoteHost::parse(host) {
+        Ok(host) => host,
+        Err(e) => {
+            warn!(vm = %handle.name, error = %e, "cannot reach the VM's host, leaving it running there");
+            return;
+        }
+    };
+    if let Some(pid) = handle.pid {
+        let cmdline = host
+            .command("cat")
+            .arg(shell_quote(&format!("/proc/{pid}/cmdline")))
+            .stderr(Stdio::null())
+            .output()
+            .await
+            .ok()
+            .filter(|output| output.status.success());
+        if cmdline.is_some_and(|output| belongs_to(&output.stdout, handle)) {
+            let mut cmd = host.command("kill");
+            cmd.args(kill_args(pid).map(|arg| shell_quote(&arg)));
+            kill(&mut cmd, pid, &handle.name).await;
+        } else {
+            warn!(vm = %handle.name, pid, "not killing remote PID, it can't be verified to be the VM's");
+        }
+    }
+    if suspicious(&handle.work_dir) {
+        warn!(vm = %handle.name, dir = %handle.work_dir.display(), "not removing suspicious remote work directory");
+        return;
+    }
+    let removed = host
+        .command("rm")
+        .args(["-rf", "--"])
+        .arg(shell_quote(&handle.work_dir.to_string_lossy()))
+        .stdout(Stdio::null())
+        .status()
+        .await;
+    if !matches!(removed, Ok(status) if status.success()) {
+        warn!(vm = %handle.name, host = %host.destination(), "failed to remove the remote work directory");
+    }
+}
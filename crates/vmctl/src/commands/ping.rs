@@ -0,0 +1,167 @@
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use vm_manager::backends::remote::RemoteHost;
+use vm_manager::config::Config;
+use vm_manager::{Hypervisor, NetworkConfig};
+
+use super::state;
+
+#[derive(Args)]
+pub struct PingArgs {
+    /// VM name
+    name: String,
+
+    /// Number of echo requests to send
+    #[arg(short, long, default_value = "4")]
+    count: u32,
+
+    /// Ping the guest's IPv6 address when it has both an IPv4 and a global IPv6 address
+    #[arg(long)]
+    prefer_ipv6: bool,
+}
+
+pub async fn run(args: PingArgs, config: &Config) -> Result<()> {
+    if args.count == 0 {
+        miette::bail!(
+            help = "use --count 1 or more",
+            "count must be greater than 0"
+        );
+    }
+
+    let store = state::load_store(config).await?;
+    let handle = store
+        .get(&args.name)
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+
+    let hv = super::hypervisor(config)?;
+    let ip = hv
+        .guest_ips(handle, vm_manager::DEFAULT_IP_TIMEOUT)
+        .await?
+        .preferred(args.prefer_ipv6)
+        .ok_or_else(|| miette::miette!("no address found for VM '{}'", args.name))?
+        .to_string();
+    let remote = handle
+        .remote_host
+        .as_deref()
+        .map(RemoteHost::parse)
+        .transpose()
+        .into_diagnostic()?;
+
+    let (sent, received) = match handle.network {
+        // SLIRP doesn't forward ICMP to the guest; time the forwarded SSH port instead.
+        NetworkConfig::User => {
+            if remote.is_some() {
+                miette::bail!(
+                    help = "use `vmctl ssh` to check reachability",
+                    "ping is not supported for user-mode networking VMs on a remote host"
+                );
+            }
+            let port = handle.ssh_host_port.ok_or_else(|| {
+                miette::miette!(
+                    "VM '{}' uses user-mode networking but has no forwarded SSH port",
+                    args.name
+                )
+            })?;
+            tcp_ping(&args.name, port, args.count).await
+        }
+        _ => icmp_ping(remote.as_ref(), &ip, args.count).await?,
+    };
+
+    if received < sent {
+        miette::bail!(
+            "{} of {} probes to VM '{}' failed",
+            sent - received,
+            sent,
+            args.name
+        );
+    }
+    Ok(())
+}
+
+/// Run the system `ping` (on the VM's host) and return `(transmitted, received)`.
+async fn icmp_ping(remote: Option<&RemoteHost>, ip: &str, count: u32) -> Result<(u32, u32)> {
+    let mut cmd = match remote {
+        Some(host) => host.command("ping"),
+        None => tokio::process::Command::new("ping"),
+    };
+    cmd.args(["-c", &count.to_string(), "-W", "1", ip])
+        .stdout(Stdio::piped());
+
+    let mut child = cmd.spawn().map_err(|e| {
+        miette::miette!(
+            help = "install iputils-ping or equivalent",
+            "failed to run ping: {e}"
+        )
+    })?;
+
+    // Echo ping's own output (per-reply RTTs and the min/avg/max summary) while watching for
+    // the "N packets transmitted, M received" line.
+    let mut counts = None;
+    let mut lines = BufReader::new(child.stdout.take().expect("piped stdout")).lines();
+    while let Some(line) = lines.next_line().await.into_diagnostic()? {
+        println!("{line}");
+        counts = counts.or_else(|| parse_summary(&line));
+    }
+    child.wait().await.into_diagnostic()?;
+
+    Ok(counts.unwrap_or((count, 0)))
+}
+
+/// Parse `4 packets transmitted, 3 received, ...` (iputils, BSD and illumos ping).
+fn parse_summary(line: &str) -> Option<(u32, u32)> {
+    let (sent, rest) = line.split_once(" packets transmitted, ")?;
+    let received = rest.split_whitespace().next()?;
+    Some((sent.trim().parse().ok()?, received.parse().ok()?))
+}
+
+/// Time how long the guest's SSH server takes to send its banner through the forwarded port.
+async fn tcp_ping(name: &str, port: u16, count: u32) -> (u32, u32) {
+    println!("PING {name} via 127.0.0.1:{port} (SSH banner)");
+
+    let mut rtts = Vec::new();
+    for seq in 1..=count {
+        if seq > 1 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let start = Instant::now();
+        match tokio::time::timeout(Duration::from_secs(1), ssh_banner(port)).await {
+            Ok(Ok(())) => {
+                let ms = start.elapsed().as_secs_f64() * 1000.0;
+                println!("banner from 127.0.0.1:{port}: seq={seq} time={ms:.3} ms");
+                rtts.push(ms);
+            }
+            Ok(Err(e)) => println!("no banner from 127.0.0.1:{port}: seq={seq} {e}"),
+            Err(_) => println!("no banner from 127.0.0.1:{port}: seq={seq} timed out"),
+        }
+    }
+
+    let received = rtts.len() as u32;
+    println!();
+    println!(
+        "{count} probes sent, {received} answered, {:.0}% loss",
+        (count - received) as f64 * 100.0 / count as f64
+    );
+    if !rtts.is_empty() {
+        let min = rtts.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = rtts.iter().copied().fold(0.0, f64::max);
+        let avg = rtts.iter().sum::<f64>() / rtts.len() as f64;
+        println!("rtt min/avg/max = {min:.3}/{avg:.3}/{max:.3} ms");
+    }
+    (count, received)
+}
+
+/// Connect and wait for the `SSH-` identification string. SLIRP accepts connections on the
+/// forwarded port even when the guest is down, so a bare connect proves nothing.
+async fn ssh_banner(port: u16) -> std::io::Result<()> {
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    if &buf != b"SSH-" {
+        return Err(std::io::Error::other("unexpected response"));
+    }
+    Ok(())
+}
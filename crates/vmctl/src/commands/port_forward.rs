@@ -0,0 +1,337 @@
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::config::Config;
+use vm_manager::ssh::{bracket_host, host_port};
+
+use super::ssh::{SshTarget, resolve_target};
+use super::state;
+
+/// How long a background tunnel must stay up before it counts as started. With
+/// `ExitOnForwardFailure`, ssh exits within this time when it can't connect or bind a port.
+const TUNNEL_STARTUP_WAIT: Duration = Duration::from_secs(3);
+
+#[derive(Args)]
+pub struct PortForwardArgs {
+    /// VM name
+    name: String,
+
+    /// Forwards as `[bind_address:]local_port:remote_host:remote_port` (remote host is resolved on
+    /// the VM). Write IPv6 addresses in brackets, e.g. `[::1]:8080:[fd00::10]:80`
+    #[arg(required_unless_present = "stop")]
+    forwards: Vec<String>,
+
+    /// Run the tunnel in the background and record its PID in the VM's work directory
+    #[arg(long, short = 'b')]
+    background: bool,
+
+    /// Stop a background tunnel by its local port
+    #[arg(long, value_name = "LOCAL_PORT", conflicts_with_all = ["forwards", "background"])]
+    stop: Option<u16>,
+
+    /// SSH user (overrides VMFile ssh block)
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Path to SSH private key
+    #[arg(long)]
+    key: Option<PathBuf>,
+
+    /// Path to VMFile.kdl (for reading ssh user)
+    #[arg(long)]
+    file: Option<PathBuf>,
+
+    /// Connect over IPv6 when the guest has both an IPv4 and a global IPv6 address
+    #[arg(long)]
+    prefer_ipv6: bool,
+}
+
+/// A single `-L` forward.
+struct Forward {
+    /// Local listen address; `None` leaves it to ssh (loopback, IPv4 and IPv6).
+    bind: Option<String>,
+    local_port: u16,
+    remote_host: String,
+    remote_port: u16,
+}
+
+impl Forward {
+    /// The `-L` argument, with IPv6 addresses bracketed.
+    fn ssh_spec(&self) -> String {
+        let remote = format!(
+            "{}:{}:{}",
+            self.local_port,
+            bracket_host(&self.remote_host),
+            self.remote_port
+        );
+        match self.bind {
+            Some(ref bind) => format!("{}:{remote}", bracket_host(bind)),
+            None => remote,
+        }
+    }
+
+    fn local(&self) -> String {
+        host_port(self.bind.as_deref().unwrap_or("localhost"), self.local_port)
+    }
+}
+
+fn parse_forward(spec: &str) -> Result<Forward> {
+    let invalid = || {
+        miette::miette!(
+            help = "use [bind_address:]local_port:remote_host:remote_port, e.g. 8080:localhost:80 or [::1]:8080:localhost:80",
+            "invalid forward '{spec}'"
+        )
+    };
+    let parts = split_fields(spec).ok_or_else(invalid)?;
+    let (bind, local, host, remote) = match parts.as_slice() {
+        [local, host, remote] => (None, local, host, remote),
+        [bind, local, host, remote] if !bind.is_empty() => {
+            (Some(bind.clone()), local, host, remote)
+        }
+        _ => return Err(invalid()),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Forward {
+        bind,
+        local_port: local.parse().map_err(|_| invalid())?,
+        remote_host: host.clone(),
+        remote_port: remote.parse().map_err(|_| invalid())?,
+    })
+}
+
+/// Split `spec` at colons outside square brackets, dropping the brackets (`[::1]:80` gives `::1`
+/// and `80`). Returns `None` for an unclosed bracket.
+fn split_fields(spec: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut rest = spec;
+    loop {
+        let (field, after) = match rest.strip_prefix('[') {
+            Some(inner) => {
+                let (addr, after) = inner.split_once(']')?;
+                if !(after.is_empty() || after.starts_with(':')) {
+                    return None;
+                }
+                (addr, after)
+            }
+            None => match rest.find(':') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, ""),
+            },
+        };
+        fields.push(field.to_string());
+        match after.strip_prefix(':') {
+            Some(next) => rest = next,
+            None => return Some(fields),
+        }
+    }
+}
+
+fn pid_file(work_dir: &Path, local_port: u16) -> PathBuf {
+    work_dir.join(format!("tunnel-{local_port}.pid"))
+}
+
+/// Where a background tunnel's ssh writes its errors, named after its first forward.
+fn log_file(work_dir: &Path, local_port: u16) -> PathBuf {
+    work_dir.join(format!("tunnel-{local_port}.log"))
+}
+
+/// Whether `pid` is a tunnel ssh serving `local_port`, judged by its command line
+/// (`ssh -N ... -L [bind:]<local_port>:...`): the PID may have been reused since it was recorded.
+fn is_tunnel(pid: u32, local_port: u16) -> bool {
+    let Ok(raw) = std::fs::read(format!("/proc/{pid}/cmdline")) else {
+        return false;
+    };
+    let args: Vec<String> = raw
+        .split(|&b| b == 0)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    let is_ssh = args
+        .first()
+        .is_some_and(|program| Path::new(program).ends_with("ssh"));
+    is_ssh
+        && args.iter().any(|arg| arg == "-N")
+        && args.windows(2).any(|w| {
+            w[0] == "-L" && parse_forward(&w[1]).is_ok_and(|fwd| fwd.local_port == local_port)
+        })
+}
+
+pub async fn run(args: PortForwardArgs, config: &Config) -> Result<()> {
+    let store = state::load_store(config).await?;
+    let work_dir = store
+        .get(&args.name)
+        .map(|h| h.work_dir.clone())
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+
+    if let Some(port) = args.stop {
+        return stop_tunnel(&args.name, &work_dir, port).await;
+    }
+
+    let forwards = args
+        .forwards
+        .iter()
+        .map(|f| parse_forward(f))
+        .collect::<Result<Vec<_>>>()?;
+
+    for fwd in &forwards {
+        if pid_file(&work_dir, fwd.local_port).exists() {
+            miette::bail!(
+                help = format!(
+                    "stop it with `vmctl port-forward {} --stop {}`",
+                    args.name, fwd.local_port
+                ),
+                "a background tunnel for local port {} already exists",
+                fwd.local_port
+            );
+        }
+    }
+
+    let SshTarget {
+        ip,
+        port,
+        config,
+        remote,
+    } = resolve_target(
+        config,
+        &args.name,
+        args.user,
+        args.key,
+        args.file.as_deref(),
+        args.prefer_ipv6,
+        vm_manager::DEFAULT_IP_TIMEOUT,
+    )
+    .await?;
+
+    if args.background && remote.is_some() {
+        miette::bail!(
+            help = "run the tunnel in the foreground, or with your shell's job control",
+            "background tunnels are not supported for VMs on a remote host"
+        );
+    }
+
+    let mut cmd = std::process::Command::new("ssh");
+    cmd.args(["-N", "-o", "StrictHostKeyChecking=no"])
+        .args(["-o", "UserKnownHostsFile=/dev/null"])
+        .args(["-o", "ServerAliveInterval=30"])
+        .args(["-o", "ServerAliveCountMax=3"])
+        .args(["-o", "ExitOnForwardFailure=yes"])
+        .arg("-p")
+        .arg(port.to_string());
+    if let Some(ref key) = config.private_key_path {
+        cmd.arg("-i").arg(key);
+    }
+    if let Some(ref host) = remote {
+        cmd.arg("-J").arg(host.jump_spec());
+    }
+    for fwd in &forwards {
+        cmd.arg("-L").arg(fwd.ssh_spec());
+    }
+    cmd.arg(format!("{}@{ip}", config.user));
+
+    for fwd in &forwards {
+        println!(
+            "Forwarding {} -> {} on VM '{}'",
+            fwd.local(),
+            host_port(&fwd.remote_host, fwd.remote_port),
+            args.name
+        );
+    }
+
+    if args.background {
+        let log_path = log_file(&work_dir, forwards[0].local_port);
+        let log = std::fs::File::create(&log_path).into_diagnostic()?;
+        // Detach from our process group so the tunnel survives the terminal's Ctrl+C.
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(log)
+            .process_group(0)
+            .spawn()
+            .into_diagnostic()?;
+        let pid = child.id();
+        let started = tokio::time::Instant::now();
+        while started.elapsed() < TUNNEL_STARTUP_WAIT {
+            if let Some(status) = child.try_wait().into_diagnostic()? {
+                let log = tokio::fs::read_to_string(&log_path)
+                    .await
+                    .unwrap_or_default();
+                miette::bail!(
+                    help = "check that the local ports are free and the VM accepts SSH logins",
+                    "SSH tunnel exited right away ({status}){}",
+                    match log.trim() {
+                        "" => String::new(),
+                        log => format!(":\n{log}"),
+                    }
+                );
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        for fwd in &forwards {
+            tokio::fs::write(pid_file(&work_dir, fwd.local_port), format!("{pid}\n"))
+                .await
+                .into_diagnostic()?;
+        }
+        println!("Tunnel running in background (pid {pid})");
+        return Ok(());
+    }
+
+    println!("Press Ctrl+C to stop.");
+    let status = tokio::process::Command::from(cmd)
+        .status()
+        .await
+        .into_diagnostic()?;
+    if !status.success() {
+        miette::bail!("SSH tunnel exited with status {}", status);
+    }
+    Ok(())
+}
+
+async fn stop_tunnel(name: &str, work_dir: &Path, local_port: u16) -> Result<()> {
+    let path = pid_file(work_dir, local_port);
+    let pid = tokio::fs::read_to_string(&path)
+        .await
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        .ok_or_else(|| {
+            miette::miette!("no background tunnel for local port {local_port} on VM '{name}'")
+        })?;
+
+    let running = is_tunnel(pid, local_port);
+    if running {
+        let _ = tokio::process::Command::new("kill")
+            .arg(pid.to_string())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+    }
+
+    // One ssh process may serve several forwards — drop every PID file that points at it.
+    let mut dir = tokio::fs::read_dir(work_dir).await.into_diagnostic()?;
+    while let Some(entry) = dir.next_entry().await.into_diagnostic()? {
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if !(file_name.starts_with("tunnel-") && file_name.ends_with(".pid")) {
+            continue;
+        }
+        let same = tokio::fs::read_to_string(entry.path())
+            .await
+            .is_ok_and(|s| s.trim() == pid.to_string());
+        if same {
+            let _ = tokio::fs::remove_file(entry.path()).await;
+        }
+    }
+
+    if running {
+        println!("Tunnel on local port {local_port} stopped (pid {pid})");
+    } else {
+        println!(
+            "Tunnel on local port {local_port} was not running (pid {pid} is not its ssh); removed its PID file"
+        );
+    }
+    Ok(())
+}
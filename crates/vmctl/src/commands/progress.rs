@@ -0,0 +1,142 @@
+//! Progress output rendered from the library's lifecycle events.
+
+use std::collections::HashSet;
+
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use vm_manager::metrics::CounterRecorder;
+use vm_manager::{EventBus, VmEvent};
+
+/// Something that consumes the events of a bus, one at a time.
+pub trait Handler: Send + 'static {
+    fn handle(&mut self, event: VmEvent);
+}
+
+impl Handler for CounterRecorder {
+    fn handle(&mut self, event: VmEvent) {
+        self.record(&event);
+    }
+}
+
+/// Feeds the events published into a bus to a [`Handler`] until [`finish`](Self::finish) is
+/// called.
+pub struct Listener<H> {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<H>,
+}
+
+impl<H: Handler> Listener<H> {
+    pub fn start(events: &EventBus, mut handler: H) -> Self {
+        let mut rx = events.subscribe();
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = rx.recv() => match event {
+                        Ok(event) => handler.handle(event),
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return handler,
+                    },
+                    _ = &mut stopped => break,
+                }
+            }
+            loop {
+                match rx.try_recv() {
+                    Ok(event) => handler.handle(event),
+                    Err(TryRecvError::Lagged(_)) => {}
+                    Err(_) => return handler,
+                }
+            }
+        });
+        Self { stop, task }
+    }
+
+    /// Handle the events published so far, then stop and hand the handler back.
+    pub async fn finish(self) -> Option<H> {
+        let _ = self.stop.send(());
+        self.task.await.ok()
+    }
+}
+
+/// Prints the events published into a bus until [`finish`](Self::finish) is called.
+pub struct Progress(Listener<Printer>);
+
+impl Progress {
+    pub fn start(events: &EventBus) -> Self {
+        Self(Listener::start(events, Printer::default()))
+    }
+
+    /// Like [`start`](Self::start), starting each VM's lines with `[<vm>]` so that the output of
+    /// VMs brought up concurrently can be told apart.
+    pub fn start_prefixed(events: &EventBus) -> Self {
+        let printer = Printer {
+            prefixed: true,
+            ..Printer::default()
+        };
+        Self(Listener::start(events, printer))
+    }
+
+    /// Print the events published so far, then stop.
+    pub async fn finish(self) {
+        self.0.finish().await;
+    }
+}
+
+#[derive(Default)]
+struct Printer {
+    /// Addresses already reported, since every SSH connection looks the address up again.
+    addresses: HashSet<(String, String)>,
+    prefixed: bool,
+}
+
+impl Printer {
+    /// Print `message` about `vm`: `VM '<vm>'<sep> <message>`, or `[<vm>] <message>` when
+    /// prefixed.
+    fn vm_line(&self, vm: &str, sep: &str, message: &str) {
+        if self.prefixed {
+            println!("[{vm}] {message}");
+        } else {
+            println!("VM '{vm}'{sep} {message}");
+        }
+    }
+}
+
+impl Handler for Printer {
+    fn handle(&mut self, event: VmEvent) {
+        match event {
+            VmEvent::VmPrepared { vm } => self.vm_line(&vm, "", "created"),
+            VmEvent::VmStarted { vm, .. } => self.vm_line(&vm, "", "started"),
+            VmEvent::VmStopped { vm } => self.vm_line(&vm, "", "stopped"),
+            VmEvent::VmDestroyed { vm } => self.vm_line(&vm, "", "destroyed"),
+            // User-mode guests report loopback; they are reached through the forwarded port.
+            VmEvent::IpDiscovered { vm, ip } => {
+                if ip != "127.0.0.1" && self.addresses.insert((vm.clone(), ip.clone())) {
+                    self.vm_line(&vm, "", &format!("has address {ip}"));
+                }
+            }
+            VmEvent::ProvisionStepStarted { vm, step } => {
+                self.vm_line(&vm, ":", &format!("running provision step {step}"))
+            }
+            VmEvent::ProvisionStepFinished { vm, step } => {
+                self.vm_line(&vm, ":", &format!("provision step {step} done"))
+            }
+            VmEvent::DownloadProgress {
+                url,
+                downloaded,
+                total,
+            } => match total {
+                Some(total) => println!("Downloading {url}: {}%", downloaded * 100 / total.max(1)),
+                None => println!("Downloading {url}: {} MB", downloaded / 1_000_000),
+            },
+            VmEvent::VmCrashed {
+                vm, exit_status, ..
+            } => self.vm_line(&vm, "", &format!("crashed ({exit_status})")),
+            VmEvent::VmRestarted { vm, attempt } => {
+                self.vm_line(&vm, "", &format!("restarted (attempt {attempt})"))
+            }
+            // The command reports the error itself when it fails.
+            VmEvent::Error { .. } => {}
+        }
+    }
+}
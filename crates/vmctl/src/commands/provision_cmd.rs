@@ -1,10 +1,15 @@
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
-use vm_manager::{Hypervisor, RouterHypervisor, VmState};
+use tracing::warn;
+use vm_manager::config::Config;
+use vm_manager::provision::{ProvisionFilter, Transport};
+use vm_manager::vmfile::{SshDef, VmDef};
+use vm_manager::{BackendTag, Hypervisor, RouterHypervisor, VmHandle, VmState};
 
+use super::progress::Progress;
 use super::state;
 
 #[derive(Args)]
@@ -16,26 +21,112 @@ pub struct ProvisionArgs {
     /// Only provision a specific VM by name
     #[arg(long)]
     name: Option<String>,
+
+    /// Only run these provision steps (comma-separated names, or numbers for unnamed steps)
+    #[arg(long, value_delimiter = ',')]
+    only: Vec<String>,
+
+    /// Skip these provision steps (comma-separated names, or numbers for unnamed steps)
+    #[arg(long, value_delimiter = ',')]
+    skip: Vec<String>,
+
+    #[command(flatten)]
+    snapshot: SnapshotArgs,
+}
+
+/// Pre-provision snapshot flags, shared with `vmctl up`.
+#[derive(Args, Clone, Default)]
+pub struct SnapshotArgs {
+    /// Snapshot each VM's disk before provisioning, as `snapshot-before-provision` does in
+    /// VMFile.kdl
+    #[arg(long)]
+    snapshot: bool,
+
+    /// Restore the pre-provision snapshot when provisioning fails (implies --snapshot)
+    #[arg(long)]
+    rollback_on_failure: bool,
+
+    /// After a successful run, delete all but the newest N pre-provision snapshots (implies
+    /// --snapshot)
+    #[arg(long, value_name = "N")]
+    keep_snapshots: Option<usize>,
+}
+
+/// What to do around one VM's provisioning: the flags combined with its VMFile definition.
+pub(super) struct SnapshotPolicy {
+    keep: Option<usize>,
+    rollback: bool,
+}
+
+impl SnapshotArgs {
+    pub(super) fn policy(&self, def: &VmDef) -> Option<SnapshotPolicy> {
+        let enabled = self.snapshot
+            || self.rollback_on_failure
+            || self.keep_snapshots.is_some()
+            || def.provision_snapshot.is_some();
+        enabled.then(|| SnapshotPolicy {
+            keep: self
+                .keep_snapshots
+                .or(def.provision_snapshot.as_ref().and_then(|s| s.keep)),
+            rollback: self.rollback_on_failure,
+        })
+    }
 }
 
-pub async fn run(args: ProvisionArgs) -> Result<()> {
+pub async fn run(args: ProvisionArgs, config: &Config) -> Result<()> {
     let path = vm_manager::vmfile::discover(args.file.as_deref()).into_diagnostic()?;
     let vmfile = vm_manager::vmfile::parse(&path).into_diagnostic()?;
 
-    let store = state::load_store().await?;
-    let hv = RouterHypervisor::new(None, None);
+    let store = state::load_store(config).await?;
+    let hv = super::hypervisor(config)?;
 
-    for def in &vmfile.vms {
-        if let Some(ref filter) = args.name {
-            if &def.name != filter {
-                continue;
-            }
-        }
+    let filter = ProvisionFilter {
+        only: args.only,
+        skip: args.skip,
+    };
+    let targets: Vec<_> = vmfile
+        .vms
+        .iter()
+        .filter(|def| args.name.as_ref().is_none_or(|n| &def.name == n))
+        .collect();
+
+    // A step name only has to exist in one of the targeted VMs
+    let unknown: Vec<&str> = filter
+        .only
+        .iter()
+        .chain(&filter.skip)
+        .map(String::as_str)
+        .filter(|s| {
+            targets
+                .iter()
+                .all(|def| filter.unknown(&def.provisions).contains(s))
+        })
+        .collect();
+    if !unknown.is_empty() {
+        miette::bail!(
+            help = "name steps with `name \"...\"` inside a provision block, or refer to them by number",
+            "no provision step matches: {}",
+            unknown.join(", ")
+        );
+    }
 
+    for def in targets {
         if def.provisions.is_empty() {
             println!("VM '{}' has no provisioners — skipping", def.name);
             continue;
         }
+        if !def
+            .provisions
+            .iter()
+            .enumerate()
+            .any(|(i, p)| filter.includes(i + 1, p))
+        {
+            println!(
+                "VM '{}' has no matching provision steps — skipping",
+                def.name
+            );
+            continue;
+        }
 
         let handle = store.get(&def.name).ok_or_else(|| {
             miette::miette!(
@@ -52,43 +143,206 @@ pub async fn run(args: ProvisionArgs) -> Result<()> {
             );
         }
 
-        let ssh_def = def.ssh.as_ref().ok_or_else(|| {
-            miette::miette!(
-                "VM '{}' has provisioners but no ssh block — add an ssh {{ }} section to VMFile.kdl",
-                def.name
-            )
-        })?;
-
-        let ip = hv.guest_ip(handle).await.into_diagnostic()?;
-        let port = super::ssh_port_for_handle(handle);
-
-        let config = super::build_ssh_config(ssh_def, &vmfile.base_dir, handle)?;
-
         println!("Provisioning VM '{}'...", def.name);
-        let sess =
-            vm_manager::ssh::connect_with_retry(&ip, port, &config, Duration::from_secs(120))
-                .await
-                .into_diagnostic()?;
+        let transport = connect(&hv, handle, def.ssh.as_ref(), &vmfile.base_dir).await?;
 
         let provisions = def.provisions.clone();
+        let filter = filter.clone();
         let base_dir = vmfile.base_dir.clone();
         let name = def.name.clone();
         let log_dir = handle.work_dir.clone();
-        tokio::task::spawn_blocking(move || {
-            vm_manager::provision::run_provisions(
-                &sess,
-                &provisions,
-                &base_dir,
-                &name,
-                Some(&log_dir),
-            )
+        let events = hv.events().clone();
+        let progress = Progress::start(&events);
+        let result = with_snapshot(handle, args.snapshot.policy(def), async {
+            super::until_cancelled(tokio::task::spawn_blocking(move || {
+                vm_manager::provision::run_provisions(
+                    &transport,
+                    &provisions,
+                    &filter,
+                    &base_dir,
+                    &name,
+                    Some(&log_dir),
+                    &events,
+                    super::cancel(),
+                )
+            }))
+            .await
         })
-        .await
-        .into_diagnostic()?
-        .into_diagnostic()?;
+        .await;
+        progress.finish().await;
+        result?;
 
         println!("VM '{}' provisioned", def.name);
     }
 
     Ok(())
 }
+
+/// Connect to `vm` for provisioning: over SSH when the VMFile has an `ssh` block, otherwise
+/// through the guest agent of a local QEMU VM.
+pub(super) async fn connect(
+    hv: &RouterHypervisor,
+    vm: &VmHandle,
+    ssh_def: Option<&SshDef>,
+    base_dir: &Path,
+) -> Result<Transport> {
+    let Some(ssh_def) = ssh_def else {
+        return connect_agent(vm).await;
+    };
+    let ip = hv
+        .guest_ip(vm, super::PROVISION_IP_TIMEOUT)
+        .await
+        .into_diagnostic()?;
+    let config = super::build_ssh_config(ssh_def, base_dir, vm)?;
+    let sess = vm_manager::ssh::connect_with_retry(
+        &ip,
+        vm.ssh_port()?,
+        &config,
+        Duration::from_secs(120),
+        super::cancel(),
+    )
+    .await
+    .into_diagnostic()?;
+    Ok(Transport::Ssh(sess))
+}
+
+#[cfg(target_os = "linux")]
+async fn connect_agent(vm: &VmHandle) -> Result<Transport> {
+    if vm.backend != BackendTag::Qemu || vm.remote_host.is_some() {
+        return Err(no_ssh_block(vm));
+    }
+    println!(
+        "VM '{}' has no ssh block — provisioning through the guest agent",
+        vm.name
+    );
+    let agent = vm_manager::backends::qga::connect_with_retry(
+        vm,
+        Duration::from_secs(120),
+        super::cancel(),
+    )
+    .await?;
+    Ok(Transport::Agent(std::sync::Mutex::new(agent)))
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn connect_agent(vm: &VmHandle) -> Result<Transport> {
+    Err(no_ssh_block(vm))
+}
+
+fn no_ssh_block(vm: &VmHandle) -> miette::Report {
+    miette::miette!(
+        help = "only local QEMU VMs can be provisioned through the guest agent instead",
+        "VM '{}' has provisioners but no ssh block — add an ssh {{ }} section to VMFile.kdl",
+        vm.name
+    )
+}
+
+/// Run `provision` for `vm`. With a `policy`, a `pre-provision-<timestamp>` snapshot is taken
+/// first; a failure then restores it or tells the user how to, and a success prunes the older
+/// pre-provision snapshots down to `keep`.
+pub(super) async fn with_snapshot<T>(
+    vm: &VmHandle,
+    policy: Option<SnapshotPolicy>,
+    provision: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(policy) = policy else {
+        return provision.await;
+    };
+    if vm.backend != BackendTag::Qemu || vm.remote_host.is_some() {
+        miette::bail!(
+            help = "drop --snapshot and snapshot-before-provision for this VM",
+            "VM '{}' uses the {} backend{} — pre-provision snapshots only support local QEMU VMs",
+            vm.name,
+            vm.backend,
+            if vm.remote_host.is_some() {
+                " on a remote host"
+            } else {
+                ""
+            }
+        );
+    }
+
+    let tag = vm_manager::provision::pre_provision_tag(SystemTime::now());
+    snapshots::save(vm, &tag).await?;
+    println!("Snapshot '{tag}' of VM '{}' taken", vm.name);
+
+    let output = match provision.await {
+        Ok(output) => output,
+        Err(e) => {
+            roll_back(vm, &tag, policy.rollback).await;
+            return Err(e);
+        }
+    };
+
+    if let Some(keep) = policy.keep {
+        // Pruning is housekeeping: a failure leaves extra snapshots, not a broken VM.
+        if let Err(e) = snapshots::prune(vm, keep).await {
+            warn!(vm = %vm.name, error = %e, "failed to prune pre-provision snapshots");
+        }
+    }
+    Ok(output)
+}
+
+/// After failed provisioning, restore snapshot `tag` if `restore` is set, or say how to.
+async fn roll_back(vm: &VmHandle, tag: &str, restore: bool) {
+    if !restore {
+        eprintln!(
+            "Provisioning VM '{}' failed — roll it back with `vmctl snapshot restore {} {tag}`",
+            vm.name, vm.name
+        );
+    } else if let Err(e) = snapshots::load(vm, tag).await {
+        eprintln!(
+            "Rolling VM '{}' back to snapshot '{tag}' failed: {e}",
+            vm.name
+        );
+    } else {
+        eprintln!("VM '{}' rolled back to snapshot '{tag}'", vm.name);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod snapshots {
+    use vm_manager::VmHandle;
+    use vm_manager::backends::qemu::QemuBackend;
+
+    pub async fn save(vm: &VmHandle, tag: &str) -> miette::Result<()> {
+        Ok(QemuBackend::save_snapshot(vm, tag).await?)
+    }
+
+    pub async fn load(vm: &VmHandle, tag: &str) -> miette::Result<()> {
+        Ok(QemuBackend::load_snapshot(vm, tag).await?)
+    }
+
+    pub async fn prune(vm: &VmHandle, keep: usize) -> miette::Result<()> {
+        let Some(ref overlay) = vm.overlay_path else {
+            return Ok(());
+        };
+        let existing = vm_manager::image::snapshots(overlay).await?;
+        for tag in vm_manager::provision::stale_pre_provision_snapshots(&existing, keep) {
+            QemuBackend::delete_snapshot(vm, &tag).await?;
+            println!("Snapshot '{tag}' of VM '{}' pruned", vm.name);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod snapshots {
+    use vm_manager::VmHandle;
+
+    fn unsupported() -> miette::Report {
+        miette::miette!("pre-provision snapshots need the QEMU backend, which only runs on Linux")
+    }
+
+    pub async fn save(_vm: &VmHandle, _tag: &str) -> miette::Result<()> {
+        Err(unsupported())
+    }
+
+    pub async fn load(_vm: &VmHandle, _tag: &str) -> miette::Result<()> {
+        Err(unsupported())
+    }
+
+    pub async fn prune(_vm: &VmHandle, _keep: usize) -> miette::Result<()> {
+        Err(unsupported())
+    }
+}
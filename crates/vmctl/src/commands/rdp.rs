@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use tracing::debug;
+use vm_manager::capabilities::which;
+use vm_manager::config::Config;
+use vm_manager::{GuestOs, Hypervisor, NetworkConfig};
+
+use super::state;
+
+/// The guest's RDP port.
+const RDP_PORT: u16 = 3389;
+
+/// RDP clients to launch on Linux, in order of preference.
+const RDP_CLIENTS: &[&str] = &["xfreerdp3", "xfreerdp", "wlfreerdp", "remmina"];
+
+#[derive(Args)]
+pub struct RdpArgs {
+    /// VM name
+    name: String,
+
+    /// User to log in as (default: the configured `ssh-user`)
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Print the address to connect to instead of launching a client
+    #[arg(long)]
+    print: bool,
+
+    /// Seconds to wait for the guest to accept RDP connections
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    wait: u64,
+}
+
+pub async fn run(args: RdpArgs, config: &Config) -> Result<()> {
+    let store = state::load_store(config).await?;
+    let handle = state::find(&store, &args.name)
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+    let name = handle.name.as_str();
+
+    if handle.remote_host.is_some() {
+        miette::bail!(
+            help = "forward the port with `ssh -L` through the hypervisor host",
+            "rdp is not supported for VMs on a remote host"
+        );
+    }
+    if handle.guest_os != GuestOs::Windows {
+        debug!(vm = %name, "not a Windows guest, trying RDP anyway");
+    }
+
+    let (host, port) = match handle.network {
+        NetworkConfig::User => {
+            let port = handle.rdp_host_port.ok_or_else(|| {
+                miette::miette!(
+                    help = "recreate the VM with `--guest-os windows` so a port is forwarded",
+                    "VM '{name}' uses user-mode networking but has no forwarded RDP port"
+                )
+            })?;
+            ("127.0.0.1".to_string(), port)
+        }
+        _ => {
+            let hv = super::hypervisor(config)?;
+            let ip = hv
+                .guest_ips(handle, vm_manager::DEFAULT_IP_TIMEOUT)
+                .await?
+                .preferred(false)
+                .ok_or_else(|| miette::miette!("no address found for VM '{name}'"))?;
+            (ip.to_string(), RDP_PORT)
+        }
+    };
+    let addr = vm_manager::ssh::host_port(&host, port);
+
+    if args.print {
+        println!("{addr}");
+        return Ok(());
+    }
+
+    println!("Waiting for RDP on {addr}...");
+    wait_for_port(&addr, Duration::from_secs(args.wait))
+        .await
+        .map_err(|_| {
+            miette::miette!(
+                help = "check that Remote Desktop is enabled in the guest (Settings > System > Remote Desktop)",
+                "VM '{name}' did not accept RDP connections on {addr} within {}s",
+                args.wait
+            )
+        })?;
+
+    let user = args.user.unwrap_or_else(|| config.ssh_user.value.clone());
+    let mut cmd = client_command(&host, port, &user)?;
+    println!("Connecting to {user}@{addr}...");
+    let status = cmd.status().await.into_diagnostic()?;
+    if !status.success() {
+        miette::bail!("RDP client exited with status {}", status);
+    }
+    Ok(())
+}
+
+/// Wait until a TCP connection to `addr` succeeds.
+async fn wait_for_port(addr: &str, timeout: Duration) -> std::io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let attempt =
+            tokio::time::timeout(Duration::from_secs(2), tokio::net::TcpStream::connect(addr))
+                .await;
+        match attempt {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) if Instant::now() >= deadline => return Err(e),
+            Err(_) if Instant::now() >= deadline => {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            _ => tokio::time::sleep(Duration::from_secs(2)).await,
+        }
+    }
+}
+
+/// The command that opens an RDP session to `host:port`: the first RDP client found on Linux,
+/// the registered `rdp://` handler on macOS.
+fn client_command(host: &str, port: u16, user: &str) -> Result<tokio::process::Command> {
+    if cfg!(target_os = "macos") {
+        let mut cmd = tokio::process::Command::new("open");
+        cmd.arg(format!(
+            "rdp://full%20address=s:{host}:{port}&username=s:{user}"
+        ));
+        return Ok(cmd);
+    }
+    let (client, path) = RDP_CLIENTS
+        .iter()
+        .find_map(|c| which(c).map(|p| (*c, p)))
+        .ok_or_else(|| {
+            miette::miette!(
+                help = "install FreeRDP or Remmina, or connect another client to the address from `vmctl rdp --print`",
+                "no RDP client found (looked for {})",
+                RDP_CLIENTS.join(", ")
+            )
+        })?;
+    let mut cmd = tokio::process::Command::new(path);
+    if client == "remmina" {
+        cmd.arg("-c").arg(format!("rdp://{user}@{host}:{port}"));
+    } else {
+        cmd.arg(format!("/v:{host}:{port}"))
+            .arg(format!("/u:{user}"))
+            .arg("/cert:ignore")
+            .arg("/dynamic-resolution");
+    }
+    Ok(cmd)
+}
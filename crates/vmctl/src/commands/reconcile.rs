@@ -0,0 +1,201 @@
+//! `vmctl reconcile`: bring the namespace's state store back in line with the work directories
+//! on disk, after VMs were deleted by hand or the store was damaged.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::config::Config;
+use vm_manager::{BackendTag, VmHandle};
+
+use super::state::{self, Store};
+
+#[derive(Args)]
+pub struct ReconcileArgs {
+    /// Show the changes without saving them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Remove store entries whose work directory is gone
+    #[arg(long)]
+    prune_missing: bool,
+
+    /// Add VM work directories in the data directory that no store entry refers to
+    #[arg(long)]
+    import_orphans: bool,
+}
+
+/// One change to the store.
+enum Change {
+    /// Remove the entry of a VM whose work directory is gone.
+    Prune { name: String, work_dir: PathBuf },
+    /// Add a VM found on disk.
+    Import(Box<VmHandle>),
+    /// Forget the pid of a VM whose QEMU is no longer running.
+    ClearPid { name: String, pid: u32 },
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Prune { name, work_dir } => {
+                write!(
+                    f,
+                    "- {name:<16} work directory {} is gone",
+                    work_dir.display()
+                )
+            }
+            Self::Import(vm) => write!(
+                f,
+                "+ {:<16} imported from {}{}",
+                vm.name,
+                vm.work_dir.display(),
+                vm.pid
+                    .map(|p| format!(" (running, pid {p})"))
+                    .unwrap_or_default()
+            ),
+            Self::ClearPid { name, pid } => {
+                write!(f, "~ {name:<16} pid {pid} cleared, QEMU is not running")
+            }
+        }
+    }
+}
+
+pub async fn run(args: ReconcileArgs, config: &Config) -> Result<()> {
+    let store = state::load_store(config).await?;
+    let mut changes = Vec::new();
+    let mut notes = Vec::new();
+
+    let mut names: Vec<&String> = store.keys().collect();
+    names.sort();
+    for name in names {
+        let vm = &store[name];
+        // Remote VMs live on another host, so there is nothing to compare here
+        if vm.remote_host.is_some() {
+            continue;
+        }
+        if !vm.work_dir.exists() {
+            if args.prune_missing {
+                changes.push(Change::Prune {
+                    name: name.clone(),
+                    work_dir: vm.work_dir.clone(),
+                });
+            } else {
+                notes.push(format!(
+                    "VM '{name}' has no work directory at {}; --prune-missing removes it",
+                    vm.work_dir.display()
+                ));
+            }
+            continue;
+        }
+        if let Some(pid) = vm.pid {
+            if vm.backend == BackendTag::Qemu && !vm.work_dir.join("qemu.pid").exists() {
+                changes.push(Change::ClearPid {
+                    name: name.clone(),
+                    pid,
+                });
+            }
+        }
+    }
+
+    for dir in orphans(&config.vms_dir(), &store).await? {
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        if store.contains_key(name.as_ref()) {
+            notes.push(format!(
+                "{} is not VM '{name}''s work directory, which is {}; not importing it",
+                dir.display(),
+                store[name.as_ref()].work_dir.display()
+            ));
+        } else if !args.import_orphans {
+            notes.push(format!(
+                "{} belongs to no VM; --import-orphans adds it",
+                dir.display()
+            ));
+        } else {
+            match inspect(dir.clone()).await {
+                Some(vm) => changes.push(Change::Import(Box::new(vm))),
+                None => notes.push(format!(
+                    "{} can only be imported where the QEMU backend is available",
+                    dir.display()
+                )),
+            }
+        }
+    }
+
+    for change in &changes {
+        println!("{change}");
+    }
+    for note in &notes {
+        println!("note: {note}");
+    }
+    if changes.is_empty() {
+        println!("No changes to make.");
+        return Ok(());
+    }
+    if args.dry_run {
+        println!("Dry run: the state store was not changed.");
+        return Ok(());
+    }
+
+    let count = changes.len();
+    state::with_store_mut(config, |store| {
+        for change in changes {
+            match change {
+                Change::Prune { name, work_dir } => {
+                    if store.get(&name).is_some_and(|vm| vm.work_dir == work_dir) {
+                        store.remove(&name);
+                    }
+                }
+                Change::Import(vm) => {
+                    store.entry(vm.name.clone()).or_insert(*vm);
+                }
+                Change::ClearPid { name, pid } => {
+                    if let Some(vm) = store.get_mut(&name).filter(|vm| vm.pid == Some(pid)) {
+                        vm.pid = None;
+                    }
+                }
+            }
+        }
+    })
+    .await?;
+    println!(
+        "Applied {count} change{} to the state store.",
+        if count == 1 { "" } else { "s" }
+    );
+    Ok(())
+}
+
+/// VM work directories in `vms_dir` that no entry of `store` refers to, by name. Only
+/// directories with a QEMU overlay or pidfile count: anything else there isn't a VM.
+async fn orphans(vms_dir: &Path, store: &Store) -> Result<Vec<PathBuf>> {
+    let mut entries = match tokio::fs::read_dir(vms_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).into_diagnostic(),
+    };
+    let known: HashSet<&Path> = store.values().map(|vm| vm.work_dir.as_path()).collect();
+    let mut orphans = Vec::new();
+    while let Some(entry) = entries.next_entry().await.into_diagnostic()? {
+        let dir = entry.path();
+        let is_vm = ["overlay.qcow2", "qemu.pid"]
+            .iter()
+            .any(|file| dir.join(file).is_file());
+        if is_vm && !known.contains(dir.as_path()) {
+            orphans.push(dir);
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+/// The handle of the QEMU VM in `work_dir`, rebuilt by the backend.
+#[cfg(target_os = "linux")]
+async fn inspect(work_dir: PathBuf) -> Option<VmHandle> {
+    Some(vm_manager::backends::qemu::QemuBackend::inspect(work_dir).await)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn inspect(_work_dir: PathBuf) -> Option<VmHandle> {
+    None
+}
@@ -1,13 +1,15 @@
 use std::path::PathBuf;
-use std::time::Duration;
 
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
 use tracing::info;
+use vm_manager::RouterHypervisor;
+use vm_manager::config::Config;
+use vm_manager::provision::ProvisionFilter;
 use vm_manager::vmfile::{ProvisionDef, SshDef};
-use vm_manager::{Hypervisor, RouterHypervisor};
 
-use super::state;
+use super::progress::Progress;
+use super::{hostnames, provision_cmd, state};
 
 #[derive(Args)]
 pub struct ReloadArgs {
@@ -22,60 +24,74 @@ pub struct ReloadArgs {
     /// Skip provisioning after reload
     #[arg(long)]
     no_provision: bool,
+
+    /// Recreate the VMs even if the host seems short of memory or disk space for them
+    #[arg(long)]
+    force: bool,
 }
 
-pub async fn run(args: ReloadArgs) -> Result<()> {
+pub async fn run(args: ReloadArgs, config: &Config) -> Result<()> {
     let path = vm_manager::vmfile::discover(args.file.as_deref()).into_diagnostic()?;
     let vmfile = vm_manager::vmfile::parse(&path).into_diagnostic()?;
 
-    let mut store = state::load_store().await?;
-    let hv = RouterHypervisor::new(None, None);
+    let mut store = state::load_store(config).await?;
+    let manager = super::checked_manager(config, super::hypervisor(config)?, args.force);
+    hostnames::check(config).await?;
+    let images = config
+        .image_manager()
+        .with_events(manager.events().clone())
+        .with_cancel(super::cancel().clone());
+
+    let progress = Progress::start(manager.events());
+    let result = async {
+        for def in &vmfile.vms {
+            if let Some(ref filter) = args.name {
+                if &def.name != filter {
+                    continue;
+                }
+            }
 
-    for def in &vmfile.vms {
-        if let Some(ref filter) = args.name {
-            if &def.name != filter {
-                continue;
+            // Destroy existing if present
+            if let Some(handle) = store.remove(&def.name) {
+                info!(vm = %def.name, "destroying existing VM for reload");
+                let handle = hostnames::unregister(handle).await?;
+                manager.attach(handle).destroy().await?;
             }
-        }
 
-        // Destroy existing if present
-        if let Some(handle) = store.remove(&def.name) {
-            info!(vm = %def.name, "destroying existing VM for reload");
-            hv.destroy(handle).await.into_diagnostic()?;
-            state::save_store(&store).await?;
+            // Resolve, prepare, start
+            info!(vm = %def.name, "creating and starting VM");
+            let spec = vm_manager::vmfile::resolve(def, &vmfile.base_dir, &images)
+                .await
+                .into_diagnostic()?;
+
+            let mut vm = manager.create(spec).await?;
+            vm.start().await?;
+            println!("VM '{}' reloaded", def.name);
+
+            let updated =
+                hostnames::register(config, manager.hypervisor(), vm.handle().clone()).await?;
+            vm.record(updated).await?;
+            store.insert(def.name.clone(), vm.into_handle());
+
+            // Provision
+            if !args.no_provision && !def.provisions.is_empty() {
+                run_provision_for_vm(
+                    manager.hypervisor(),
+                    &store,
+                    &def.name,
+                    &def.provisions,
+                    def.ssh.as_ref(),
+                    &vmfile.base_dir,
+                )
+                .await?;
+            }
         }
 
-        // Resolve, prepare, start
-        info!(vm = %def.name, "creating and starting VM");
-        let spec = vm_manager::vmfile::resolve(def, &vmfile.base_dir)
-            .await
-            .into_diagnostic()?;
-
-        let handle = hv.prepare(&spec).await.into_diagnostic()?;
-        super::save_generated_ssh_key(&spec, &handle).await?;
-        store.insert(def.name.clone(), handle.clone());
-        state::save_store(&store).await?;
-
-        let updated = hv.start(&handle).await.into_diagnostic()?;
-        store.insert(def.name.clone(), updated);
-        state::save_store(&store).await?;
-        println!("VM '{}' reloaded", def.name);
-
-        // Provision
-        if !args.no_provision && !def.provisions.is_empty() {
-            run_provision_for_vm(
-                &hv,
-                &store,
-                &def.name,
-                &def.provisions,
-                def.ssh.as_ref(),
-                &vmfile.base_dir,
-            )
-            .await?;
-        }
+        Ok(())
     }
-
-    Ok(())
+    .await;
+    progress.finish().await;
+    result
 }
 
 async fn run_provision_for_vm(
@@ -86,42 +102,31 @@ async fn run_provision_for_vm(
     ssh_def: Option<&SshDef>,
     base_dir: &std::path::Path,
 ) -> Result<()> {
-    let ssh_def = ssh_def.ok_or_else(|| {
-        miette::miette!(
-            "VM '{vm_name}' has provisioners but no ssh block — add an ssh {{ }} section to VMFile.kdl"
-        )
-    })?;
-
     let handle = store
         .get(vm_name)
         .ok_or_else(|| miette::miette!("VM '{vm_name}' not found in store"))?;
 
-    let ip = hv.guest_ip(handle).await.into_diagnostic()?;
-    let port = super::ssh_port_for_handle(handle);
-
-    let config = super::build_ssh_config(ssh_def, base_dir, handle)?;
-
     println!("Provisioning VM '{vm_name}'...");
-    let sess = vm_manager::ssh::connect_with_retry(&ip, port, &config, Duration::from_secs(120))
-        .await
-        .into_diagnostic()?;
+    let transport = provision_cmd::connect(hv, handle, ssh_def, base_dir).await?;
 
     let provisions = provisions.to_vec();
     let base_dir = base_dir.to_path_buf();
     let name = vm_name.to_string();
     let log_dir = handle.work_dir.clone();
-    tokio::task::spawn_blocking(move || {
+    let events = hv.events().clone();
+    super::until_cancelled(tokio::task::spawn_blocking(move || {
         vm_manager::provision::run_provisions(
-            &sess,
+            &transport,
             &provisions,
+            &ProvisionFilter::default(),
             &base_dir,
             &name,
             Some(&log_dir),
+            &events,
+            super::cancel(),
         )
-    })
-    .await
-    .into_diagnostic()?
-    .into_diagnostic()?;
+    }))
+    .await?;
 
     println!("VM '{vm_name}' provisioned");
     Ok(())
@@ -0,0 +1,336 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::config::Config;
+use vm_manager::ssh::Session;
+
+use super::ssh::{SshTarget, resolve_target};
+
+#[derive(Args)]
+pub struct ScpArgs {
+    /// Files to copy. Prefix with `<vm>:` to copy from the VM; the final path component of a
+    /// remote source may contain `*` and `?` wildcards
+    #[arg(required = true)]
+    source: Vec<String>,
+
+    /// Destination. Prefix with `<vm>:` to copy to the VM
+    dest: String,
+
+    /// Copy directories recursively
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Copy single files through the QEMU guest agent instead of SFTP, which works without
+    /// guest networking
+    #[arg(long, conflicts_with = "recursive")]
+    via_agent: bool,
+
+    /// SSH user (overrides VMFile ssh block)
+    #[arg(long, conflicts_with = "via_agent")]
+    user: Option<String>,
+
+    /// Path to SSH private key
+    #[arg(long, conflicts_with = "via_agent")]
+    key: Option<PathBuf>,
+
+    /// Path to VMFile.kdl (for reading ssh user)
+    #[arg(long, conflicts_with = "via_agent")]
+    file: Option<PathBuf>,
+
+    /// Connect over IPv6 when the guest has both an IPv4 and a global IPv6 address
+    #[arg(long, conflicts_with = "via_agent")]
+    prefer_ipv6: bool,
+
+    /// Seconds to wait for the guest to report an IP address
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    wait: u64,
+}
+
+/// Split `vm:path` into `(vm, path)`. Local paths (including ones with a `/` before any colon)
+/// return `None`.
+fn split_remote(s: &str) -> Option<(&str, &str)> {
+    let (vm, path) = s.split_once(':')?;
+    if vm.is_empty() || vm.contains('/') {
+        return None;
+    }
+    Some((vm, path))
+}
+
+/// Direction and endpoints of a copy, after parsing `vm:` prefixes.
+enum Transfer {
+    Upload {
+        vm: String,
+        sources: Vec<PathBuf>,
+        dest: String,
+    },
+    Download {
+        vm: String,
+        sources: Vec<String>,
+        dest: PathBuf,
+    },
+}
+
+fn plan(source: &[String], dest: &str) -> Result<Transfer> {
+    let remote_sources: Vec<_> = source.iter().filter_map(|s| split_remote(s)).collect();
+
+    match split_remote(dest) {
+        Some((vm, path)) => {
+            if !remote_sources.is_empty() {
+                miette::bail!("copying between two VMs is not supported");
+            }
+            Ok(Transfer::Upload {
+                vm: vm.to_string(),
+                sources: source.iter().map(PathBuf::from).collect(),
+                dest: if path.is_empty() {
+                    ".".into()
+                } else {
+                    path.into()
+                },
+            })
+        }
+        None => {
+            if remote_sources.len() != source.len() {
+                miette::bail!(
+                    "either the sources or the destination must be on a VM — prefix it with `<vm>:`"
+                );
+            }
+            let vm = remote_sources[0].0;
+            if remote_sources.iter().any(|(v, _)| *v != vm) {
+                miette::bail!("all remote sources must be on the same VM");
+            }
+            Ok(Transfer::Download {
+                vm: vm.to_string(),
+                sources: remote_sources.iter().map(|(_, p)| p.to_string()).collect(),
+                dest: PathBuf::from(dest),
+            })
+        }
+    }
+}
+
+pub async fn run(args: ScpArgs, config: &Config) -> Result<()> {
+    let transfer = plan(&args.source, &args.dest)?;
+    let vm = match &transfer {
+        Transfer::Upload { vm, .. } | Transfer::Download { vm, .. } => vm.clone(),
+    };
+    if args.via_agent {
+        return via_agent(transfer, &vm, config).await;
+    }
+
+    let SshTarget {
+        ip,
+        port,
+        config,
+        remote,
+    } = resolve_target(
+        config,
+        &vm,
+        args.user,
+        args.key,
+        args.file.as_deref(),
+        args.prefer_ipv6,
+        Duration::from_secs(args.wait),
+    )
+    .await?;
+    // Keep the tunnel (for VMs on a remote host) alive until the copy finishes.
+    let (sess, _tunnel) = vm_manager::backends::remote::connect_guest(
+        remote.as_ref(),
+        &ip,
+        port,
+        &config,
+        Duration::from_secs(30),
+        super::cancel(),
+    )
+    .await
+    .into_diagnostic()?;
+
+    let recursive = args.recursive;
+    tokio::task::spawn_blocking(move || match transfer {
+        Transfer::Upload { sources, dest, .. } => upload(&sess, &sources, &dest, recursive),
+        Transfer::Download { sources, dest, .. } => download(&sess, &sources, &dest, recursive),
+    })
+    .await
+    .into_diagnostic()??;
+
+    Ok(())
+}
+
+fn upload(sess: &Session, sources: &[PathBuf], dest: &str, recursive: bool) -> Result<()> {
+    let dest = Path::new(dest);
+    let dest_is_dir = vm_manager::ssh::is_remote_dir(sess, dest).into_diagnostic()?;
+    if sources.len() > 1 && !dest_is_dir {
+        miette::bail!(
+            "destination {} is not a directory on the VM",
+            dest.display()
+        );
+    }
+
+    for src in sources {
+        let target = match (dest_is_dir, src.file_name()) {
+            (true, Some(name)) => dest.join(name),
+            _ => dest.to_path_buf(),
+        };
+        if src.is_dir() {
+            if !recursive {
+                miette::bail!("{} is a directory — use --recursive", src.display());
+            }
+            vm_manager::ssh::upload_dir(sess, src, &target).into_diagnostic()?;
+        } else {
+            vm_manager::ssh::upload(sess, src, &target).into_diagnostic()?;
+        }
+        println!("{} -> {}", src.display(), target.display());
+    }
+    Ok(())
+}
+
+fn download(sess: &Session, sources: &[String], dest: &Path, recursive: bool) -> Result<()> {
+    let mut matches = Vec::new();
+    for pattern in sources {
+        let found = vm_manager::ssh::glob(sess, pattern).into_diagnostic()?;
+        if found.is_empty() {
+            miette::bail!("no files on the VM match {pattern}");
+        }
+        matches.extend(found);
+    }
+
+    let dest_is_dir = dest.is_dir();
+    if matches.len() > 1 && !dest_is_dir {
+        miette::bail!("destination {} is not a directory", dest.display());
+    }
+
+    for src in &matches {
+        let target = match (dest_is_dir, src.file_name()) {
+            (true, Some(name)) => dest.join(name),
+            _ => dest.to_path_buf(),
+        };
+        if vm_manager::ssh::is_remote_dir(sess, src).into_diagnostic()? {
+            if !recursive {
+                miette::bail!("{} is a directory — use --recursive", src.display());
+            }
+            vm_manager::ssh::download_dir(sess, src, &target).into_diagnostic()?;
+        } else {
+            vm_manager::ssh::download(sess, src, &target).into_diagnostic()?;
+        }
+        println!("{} -> {}", src.display(), target.display());
+    }
+    Ok(())
+}
+
+/// Files at least this large report progress while they cross the guest agent channel.
+#[cfg(target_os = "linux")]
+const AGENT_PROGRESS_MIN_BYTES: u64 = 1 << 20;
+
+/// Copy files through the guest agent, one at a time and whole. Remote paths are taken
+/// literally: the agent can't expand wildcards or list directories.
+#[cfg(target_os = "linux")]
+async fn via_agent(transfer: Transfer, vm: &str, config: &Config) -> Result<()> {
+    use vm_manager::backends::qga::{self, GuestAgent};
+
+    let handle = super::exec::agent_vm(config, vm).await?;
+    let literal = |path: &str| {
+        if path.contains(['*', '?']) {
+            miette::bail!(
+                help = "name the file, or copy over SSH by dropping --via-agent",
+                "{path}: wildcards are not supported with --via-agent"
+            );
+        }
+        Ok(())
+    };
+    // Check everything before connecting, so a bad argument doesn't wait on the agent
+    match &transfer {
+        Transfer::Upload { sources, dest, .. } => {
+            literal(dest)?;
+            for src in sources {
+                let size = std::fs::metadata(src)
+                    .into_diagnostic()
+                    .map_err(|e| e.wrap_err(format!("{}", src.display())))?;
+                if size.is_dir() {
+                    miette::bail!(
+                        "{} is a directory — --via-agent copies single files",
+                        src.display()
+                    );
+                }
+                if size.len() > qga::MAX_TRANSFER_BYTES {
+                    miette::bail!(
+                        help = "copy it over SSH by dropping --via-agent",
+                        "{} is larger than the guest agent transfer limit of {} MiB",
+                        src.display(),
+                        qga::MAX_TRANSFER_BYTES >> 20
+                    );
+                }
+            }
+        }
+        Transfer::Download { sources, dest, .. } => {
+            for src in sources {
+                literal(src)?;
+            }
+            if sources.len() > 1 && !dest.is_dir() {
+                miette::bail!("destination {} is not a directory", dest.display());
+            }
+        }
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let mut agent =
+            GuestAgent::connect(&handle, qga::DEFAULT_AGENT_TIMEOUT).into_diagnostic()?;
+        match transfer {
+            Transfer::Upload { sources, dest, .. } => {
+                // Without a way to ask the guest, a trailing slash marks a directory
+                let dest_is_dir = dest.ends_with('/') || sources.len() > 1;
+                let dest = Path::new(&dest);
+                for src in &sources {
+                    let target = match (dest_is_dir, src.file_name()) {
+                        (true, Some(name)) => dest.join(name),
+                        _ => dest.to_path_buf(),
+                    };
+                    let contents = std::fs::read(src).into_diagnostic()?;
+                    agent
+                        .write_file_with_progress(&target, &contents, progress(&target))
+                        .into_diagnostic()?;
+                    println!("{} -> {}", src.display(), target.display());
+                }
+            }
+            Transfer::Download { sources, dest, .. } => {
+                for src in &sources {
+                    let src = Path::new(src);
+                    let target = match (dest.is_dir(), src.file_name()) {
+                        (true, Some(name)) => dest.join(name),
+                        _ => dest.clone(),
+                    };
+                    let contents = agent
+                        .read_file_with_progress(src, progress(src))
+                        .into_diagnostic()?;
+                    std::fs::write(&target, contents).into_diagnostic()?;
+                    println!("{} -> {}", src.display(), target.display());
+                }
+            }
+        }
+        Ok(())
+    })
+    .await
+    .into_diagnostic()?
+}
+
+/// Progress callback for a file crossing the guest agent channel: a line every 10% for files
+/// of at least [`AGENT_PROGRESS_MIN_BYTES`].
+#[cfg(target_os = "linux")]
+fn progress(path: &Path) -> impl FnMut(u64, u64) + use<> {
+    let path = path.display().to_string();
+    let mut reported = 0;
+    move |done, total| {
+        if total < AGENT_PROGRESS_MIN_BYTES {
+            return;
+        }
+        let pct = done * 100 / total;
+        if pct >= reported + 10 || (done == total && reported < 100) {
+            eprintln!("{path}: {pct}% of {:.1} MB", total as f64 / 1_000_000.0);
+            reported = pct;
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn via_agent(_transfer: Transfer, _vm: &str, _config: &Config) -> Result<()> {
+    miette::bail!("--via-agent requires the QEMU backend, which is only available on Linux")
+}
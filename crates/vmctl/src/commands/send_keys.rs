@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::backends::qmp::{KeyValue, QmpPool};
+use vm_manager::config::Config;
+use vm_manager::{BackendTag, Hypervisor, VmState};
+
+use super::state;
+
+#[derive(Args)]
+pub struct SendKeysArgs {
+    /// VM name
+    name: String,
+
+    /// Keys to press, one chord per argument: a QEMU key code (`ret`, `esc`, `f12`), a scancode
+    /// (`0x1c`), or several joined with `-` to press together (`ctrl-alt-delete`)
+    #[arg(value_parser = parse_chord)]
+    keys: Vec<Vec<KeyValue>>,
+
+    /// Type this ASCII text (US keyboard layout) before the keys
+    #[arg(long, value_name = "TEXT")]
+    string: Option<String>,
+}
+
+fn parse_chord(s: &str) -> std::result::Result<Vec<KeyValue>, String> {
+    s.split('-').map(str::parse).collect()
+}
+
+pub async fn run(args: SendKeysArgs, config: &Config) -> Result<()> {
+    let mut chords = Vec::new();
+    for c in args.string.iter().flat_map(|s| s.chars()) {
+        let keys = KeyValue::for_char(c).ok_or_else(|| {
+            miette::miette!(
+                help = "--string takes printable ASCII, tabs and newlines; send other keys by key code",
+                "no key types {c:?}"
+            )
+        })?;
+        chords.push(keys);
+    }
+    chords.extend(args.keys);
+    if chords.is_empty() {
+        miette::bail!(
+            help = "pass key codes such as `ret`, or text with --string",
+            "no keys to send"
+        );
+    }
+
+    let store = state::load_store(config).await?;
+    let handle = store.get(&args.name).ok_or_else(|| {
+        miette::miette!(
+            "VM '{}' not found — run `vmctl list` to see available VMs",
+            args.name
+        )
+    })?;
+    if handle.backend != BackendTag::Qemu || handle.remote_host.is_some() {
+        miette::bail!(
+            "VM '{}' uses the {} backend{} — send-keys only supports local QEMU VMs",
+            args.name,
+            handle.backend,
+            if handle.remote_host.is_some() {
+                " on a remote host"
+            } else {
+                ""
+            }
+        );
+    }
+
+    let hv = super::hypervisor(config)?;
+    let state = hv.state(handle).await.into_diagnostic()?;
+    if state != VmState::Running {
+        miette::bail!(
+            help = "start it with `vmctl start {name}`",
+            "VM '{name}' is {state}, not running",
+            name = args.name
+        );
+    }
+    let socket = handle
+        .qmp_socket
+        .as_deref()
+        .ok_or_else(|| miette::miette!("VM '{}' has no QMP socket", args.name))?;
+
+    QmpPool::shared()
+        .run(socket, Duration::from_secs(5), async |qmp| {
+            for keys in &chords {
+                qmp.send_key(keys).await?;
+            }
+            Ok(())
+        })
+        .await?;
+    println!("Sent {} key presses to VM '{}'", chords.len(), args.name);
+    Ok(())
+}
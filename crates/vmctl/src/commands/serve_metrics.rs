@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+use vm_manager::RouterHypervisor;
+use vm_manager::config::Config;
+use vm_manager::metrics::{Collector, Counters};
+
+use super::state;
+
+/// How long a client gets to send its request and read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest request head read; scrapes are a few hundred bytes.
+const MAX_REQUEST: usize = 8192;
+
+#[derive(Args)]
+pub struct ServeMetricsArgs {
+    /// Address to serve `/metrics` on
+    #[arg(long, default_value = "127.0.0.1:9640")]
+    listen: SocketAddr,
+}
+
+pub async fn run(args: ServeMetricsArgs, config: &Config) -> Result<()> {
+    let listener = TcpListener::bind(args.listen).await.map_err(|e| {
+        miette::miette!(
+            help = "pick another address with --listen",
+            "cannot listen on {}: {e}",
+            args.listen
+        )
+    })?;
+    info!(
+        addr = %args.listen,
+        namespace = %config.namespace.value,
+        "serving metrics on /metrics"
+    );
+
+    let hv = super::hypervisor(config)?;
+    let mut collector = Collector::new();
+    // Scrapes are served one at a time, so CPU usage is always measured between two of them.
+    loop {
+        let (stream, peer) = listener.accept().await.into_diagnostic()?;
+        let served = serve(stream, config, &hv, &mut collector);
+        match tokio::time::timeout(REQUEST_TIMEOUT, served).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!(%peer, error = %e, "metrics request failed"),
+            Err(_) => debug!(%peer, "metrics request timed out"),
+        }
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    config: &Config,
+    hv: &RouterHypervisor,
+    collector: &mut Collector,
+) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            scrape(config, hv, collector).await,
+        ),
+        ("GET", "/") => (
+            "200 OK",
+            "text/plain; charset=utf-8",
+            "vmctl metrics are served on /metrics\n".to_string(),
+        ),
+        ("GET", _) => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "not found\n".to_string(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain; charset=utf-8",
+            "only GET is supported\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Sample every VM in the store. A store or counters file that can't be read leaves its metrics
+/// out instead of failing the scrape.
+async fn scrape(config: &Config, hv: &RouterHypervisor, collector: &mut Collector) -> String {
+    let mut vms: Vec<_> = match state::load_store(config).await {
+        Ok(store) => store.into_values().collect(),
+        Err(e) => {
+            warn!(error = %e, "cannot read VM store for metrics");
+            Vec::new()
+        }
+    };
+    vms.sort_by(|a, b| a.name.cmp(&b.name));
+    let counters = Counters::load(&config.counters_file())
+        .await
+        .unwrap_or_else(|e| {
+            warn!(error = %e, "cannot read metrics counters");
+            Counters::default()
+        });
+
+    let samples = collector.collect(hv, &vms).await;
+    vm_manager::metrics::render(&samples, &counters)
+}
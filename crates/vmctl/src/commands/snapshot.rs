@@ -0,0 +1,335 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Args, Subcommand};
+use miette::{IntoDiagnostic, Result};
+use tokio::io::AsyncBufReadExt;
+use vm_manager::capabilities::which;
+use vm_manager::config::Config;
+use vm_manager::{BackendTag, Hypervisor, RouterHypervisor, VmHandle, VmState};
+
+use super::{hostnames, state};
+
+#[derive(Args)]
+pub struct SnapshotCommand {
+    #[command(subcommand)]
+    action: SnapshotAction,
+}
+
+#[derive(Subcommand)]
+enum SnapshotAction {
+    /// Write one of a VM's disk snapshots to a standalone image
+    Export(ExportArgs),
+    /// Roll a VM's disk back to a snapshot, or branch the snapshot into a new VM
+    Restore(RestoreArgs),
+    /// Show whether two of a VM's disk snapshots differ, and which files changed
+    Diff(DiffArgs),
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// VM name
+    name: String,
+
+    /// Name of the internal snapshot in the VM's disk
+    snapshot: String,
+
+    /// Image file to write
+    output: PathBuf,
+
+    /// Output image format, e.g. `qcow2` or `raw`
+    #[arg(long, default_value = "qcow2")]
+    format: String,
+}
+
+#[derive(Args)]
+struct RestoreArgs {
+    /// VM name
+    name: String,
+
+    /// Name of the internal snapshot in the VM's disk
+    snapshot: String,
+
+    /// Create a new VM with this name from the snapshot instead of rolling back NAME
+    #[arg(long, value_name = "NAME")]
+    new_vm: Option<String>,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// VM name
+    name: String,
+
+    /// Snapshot to compare from
+    snap_a: String,
+
+    /// Snapshot to compare with
+    snap_b: String,
+
+    /// Directory in the guest to list changed files under
+    #[arg(long, default_value = "/")]
+    path: String,
+
+    /// Only tell whether the disks differ, without mounting them to list changed files
+    #[arg(long)]
+    quick: bool,
+}
+
+pub async fn run(args: SnapshotCommand, config: &Config) -> Result<()> {
+    match args.action {
+        SnapshotAction::Export(args) => export(args, config).await,
+        SnapshotAction::Restore(args) => restore(args, config).await,
+        SnapshotAction::Diff(args) => diff(args, config).await,
+    }
+}
+
+async fn export(args: ExportArgs, config: &Config) -> Result<()> {
+    let mut store = state::load_store(config).await?;
+    let (handle, overlay) = local_disk(&store, &args.name, "snapshot export")?;
+    if args.output.exists() {
+        miette::bail!(
+            help = "remove it first or choose another path",
+            "{} already exists",
+            args.output.display()
+        );
+    }
+
+    let options = handle.overlay.clone();
+    let hv = super::hypervisor(config)?;
+    stop_for_disk_access(config, &hv, &mut store, handle).await?;
+    vm_manager::image::export_snapshot(
+        &overlay,
+        &args.snapshot,
+        &args.output,
+        &args.format,
+        &options,
+    )
+    .await?;
+    println!(
+        "Snapshot '{}' of VM '{}' exported to {}",
+        args.snapshot,
+        args.name,
+        args.output.display()
+    );
+    Ok(())
+}
+
+async fn restore(args: RestoreArgs, config: &Config) -> Result<()> {
+    let mut store = state::load_store(config).await?;
+    let (handle, overlay) = local_disk(&store, &args.name, "snapshot restore")?;
+
+    let Some(new_name) = args.new_vm else {
+        let hv = super::hypervisor(config)?;
+        stop_for_disk_access(config, &hv, &mut store, handle).await?;
+        vm_manager::image::revert_snapshot(&overlay, &args.snapshot).await?;
+        println!(
+            "VM '{}' restored to snapshot '{}'",
+            args.name, args.snapshot
+        );
+        return Ok(());
+    };
+
+    if store.contains_key(&new_name) {
+        miette::bail!(
+            help = "choose a different name or destroy the existing VM with `vmctl destroy {new_name}`",
+            "VM '{new_name}' already exists"
+        );
+    }
+    let work_dir = config.vms_dir().join(&new_name);
+    if work_dir.exists() {
+        miette::bail!(
+            help = "remove the leftover directory or choose a different name",
+            "work directory {} already exists",
+            work_dir.display()
+        );
+    }
+
+    // qemu-img needs the original's disk to itself, so a running original is stopped as for an
+    // export. Otherwise it is left untouched: only its snapshot is read.
+    let hv = super::hypervisor(config)?;
+    let handle = stop_for_disk_access(config, &hv, &mut store, handle).await?;
+    let branched = vm_manager::backends::qemu::QemuBackend::branch(
+        &handle,
+        &args.snapshot,
+        &new_name,
+        work_dir,
+        &state::known_macs(&store),
+    )
+    .await?;
+
+    // Keep using the original's generated SSH key: it is the one authorized in the guest.
+    let key = handle.work_dir.join(super::GENERATED_KEY_FILE);
+    if key.exists() {
+        let copy = branched.work_dir.join(super::GENERATED_KEY_FILE);
+        if let Err(e) = tokio::fs::copy(&key, &copy).await {
+            let _ = tokio::fs::remove_dir_all(&branched.work_dir).await;
+            return Err(e).into_diagnostic();
+        }
+    }
+
+    store.insert(new_name.clone(), branched);
+    state::save_store(config, &store).await?;
+    println!(
+        "VM '{new_name}' created from snapshot '{}' of VM '{}' — start it with `vmctl start {new_name}`",
+        args.snapshot, args.name
+    );
+    Ok(())
+}
+
+/// Compare two snapshots of a VM's disk, which may be running: only its snapshots are read.
+/// Exits with status 1 when they differ, like `diff`.
+async fn diff(args: DiffArgs, config: &Config) -> Result<()> {
+    let store = state::load_store(config).await?;
+    let (handle, overlay) = local_disk(&store, &args.name, "snapshot diff")?;
+
+    // Next to the disk rather than in /tmp, which is often a small tmpfs
+    let tmp = tempfile::Builder::new()
+        .prefix("snapshot-diff")
+        .tempdir_in(&handle.work_dir)
+        .into_diagnostic()?;
+    let (image_a, image_b) = (tmp.path().join("a.qcow2"), tmp.path().join("b.qcow2"));
+    vm_manager::image::extract_snapshot(&overlay, &args.snap_a, &image_a).await?;
+    vm_manager::image::extract_snapshot(&overlay, &args.snap_b, &image_b).await?;
+
+    let (a, b, name) = (&args.snap_a, &args.snap_b, &args.name);
+    let Some(mismatch) = vm_manager::image::compare(&image_a, &image_b).await? else {
+        println!("Snapshots '{a}' and '{b}' of VM '{name}' are identical");
+        return Ok(());
+    };
+    println!("Snapshots '{a}' and '{b}' of VM '{name}' differ: {mismatch}");
+    if !args.quick {
+        changed_files(&image_a, &image_b, &args.path, tmp.path()).await?;
+    }
+    Err(super::Exit(1).into())
+}
+
+/// Mount images `a` and `b` read-only under `dir` with guestmount and print the files that differ
+/// under guest directory `path`, found with `diff -rq`. Without guestmount, says how to get it.
+async fn changed_files(a: &Path, b: &Path, path: &str, dir: &Path) -> Result<()> {
+    let Some(guestmount) = which("guestmount") else {
+        println!("Install libguestfs (guestmount) to list the files that changed");
+        return Ok(());
+    };
+    let (mnt_a, mnt_b) = (dir.join("mnt-a"), dir.join("mnt-b"));
+    let mut mounted = Vec::new();
+    let result = async {
+        for (image, mnt) in [(a, &mnt_a), (b, &mnt_b)] {
+            tokio::fs::create_dir(mnt).await.into_diagnostic()?;
+            // -i mounts the guest's filesystems where its OS mounts them
+            let status = tokio::process::Command::new(&guestmount)
+                .args(["--ro", "-i", "-a"])
+                .arg(image)
+                .arg(mnt)
+                .status()
+                .await
+                .into_diagnostic()?;
+            if !status.success() {
+                miette::bail!(
+                    help = "guestmount needs FUSE and, on some distributions, a readable kernel in /boot; `libguestfs-test-tool` tells what is missing, and `--quick` skips the file listing",
+                    "guestmount could not mount {}",
+                    image.display()
+                );
+            }
+            mounted.push(mnt.clone());
+        }
+
+        let under = path.trim_start_matches('/');
+        let mut child = tokio::process::Command::new("diff")
+            .args(["-rq", "--no-dereference"])
+            .arg(mnt_a.join(under))
+            .arg(mnt_b.join(under))
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .into_diagnostic()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut lines = tokio::io::BufReader::new(stdout).lines();
+        let (root_a, root_b) = (mnt_a.to_string_lossy(), mnt_b.to_string_lossy());
+        while let Some(line) = lines.next_line().await.into_diagnostic()? {
+            println!("{}", describe_change(&line, &root_a, &root_b));
+        }
+        // 0: same, 1: some files differ, 2: trouble, which diff reported on stderr
+        if child.wait().await.into_diagnostic()?.code() == Some(2) {
+            miette::bail!("diff could not compare all files under {path}");
+        }
+        Ok(())
+    }
+    .await;
+
+    for mnt in mounted {
+        let _ = tokio::process::Command::new("guestunmount")
+            .arg(&mnt)
+            .status()
+            .await;
+    }
+    result
+}
+
+/// A `diff -rq` line about the trees mounted at `a` and `b` as a change to a guest path:
+/// `+ /etc/added`, `- /etc/removed` or `~ /etc/changed`. Other lines lose the mount points.
+fn describe_change(line: &str, a: &str, b: &str) -> String {
+    if let Some((dir, file)) = line
+        .strip_prefix("Only in ")
+        .and_then(|rest| rest.split_once(": "))
+    {
+        let (sign, dir) = match dir.strip_prefix(b) {
+            Some(dir) => ('+', dir),
+            None => ('-', dir.strip_prefix(a).unwrap_or(dir)),
+        };
+        return format!("{sign} {}/{file}", dir.trim_end_matches('/'));
+    }
+    if let Some((file, _)) = line
+        .strip_prefix("Files ")
+        .and_then(|rest| rest.split_once(" and "))
+    {
+        return format!("~ {}", file.strip_prefix(a).unwrap_or(file));
+    }
+    line.replace(a, "").replace(b, "")
+}
+
+/// The handle and disk image of local QEMU VM `name`, the only kind whose snapshots vmctl can
+/// work with.
+fn local_disk(store: &state::Store, name: &str, command: &str) -> Result<(VmHandle, PathBuf)> {
+    let handle = store.get(name).cloned().ok_or_else(|| {
+        miette::miette!("VM '{name}' not found — run `vmctl list` to see available VMs")
+    })?;
+    if handle.backend != BackendTag::Qemu || handle.remote_host.is_some() {
+        miette::bail!(
+            "VM '{name}' uses the {} backend{} — {command} only supports local QEMU VMs",
+            handle.backend,
+            if handle.remote_host.is_some() {
+                " on a remote host"
+            } else {
+                ""
+            }
+        );
+    }
+    let overlay = handle
+        .overlay_path
+        .clone()
+        .ok_or_else(|| miette::miette!("VM '{name}' has no disk image"))?;
+    Ok((handle, overlay))
+}
+
+/// Stop the VM if it is running: qemu-img cannot work on a disk QEMU has open. Returns the
+/// (possibly updated) handle.
+async fn stop_for_disk_access(
+    config: &Config,
+    hv: &RouterHypervisor,
+    store: &mut state::Store,
+    handle: VmHandle,
+) -> Result<VmHandle> {
+    let vm_state = hv.state(&handle).await.into_diagnostic()?;
+    if !matches!(vm_state, VmState::Running | VmState::Suspended) {
+        return Ok(handle);
+    }
+    let name = handle.name.clone();
+    let handle = hostnames::unregister(handle).await?;
+    let updated = hv
+        .stop(&handle, Duration::from_secs(30))
+        .await
+        .into_diagnostic()?;
+    store.insert(name.clone(), updated.clone());
+    state::save_store(config, store).await?;
+    println!("VM '{name}' stopped to access its disk");
+    Ok(updated)
+}
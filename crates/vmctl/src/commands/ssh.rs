@@ -3,16 +3,22 @@ use std::time::Duration;
 
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
-use vm_manager::{Hypervisor, NetworkConfig, RouterHypervisor, SshConfig};
+use vm_manager::backends::remote::RemoteHost;
+use vm_manager::config::Config;
+use vm_manager::{GuestOs, Hypervisor, SshConfig};
 
 use super::state;
 
 /// SSH key filenames to try, in order of preference.
 const SSH_KEY_NAMES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
 
+/// How long to wait for the SSH banner of a Windows guest before suggesting RDP instead.
+const WINDOWS_SSH_PROBE: Duration = Duration::from_secs(3);
+
 #[derive(Args)]
 pub struct SshArgs {
-    /// VM name (inferred from VMFile.kdl if omitted and only one VM is defined)
+    /// VM name or `<name>.local` hostname (inferred from VMFile.kdl if omitted and only one VM is
+    /// defined)
     name: Option<String>,
 
     /// SSH user (overrides VMFile ssh block)
@@ -26,6 +32,14 @@ pub struct SshArgs {
     /// Path to VMFile.kdl (for reading ssh user)
     #[arg(long)]
     file: Option<PathBuf>,
+
+    /// Connect over IPv6 when the guest has both an IPv4 and a global IPv6 address
+    #[arg(long)]
+    prefer_ipv6: bool,
+
+    /// Seconds to wait for the guest to report an IP address
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    wait: u64,
 }
 
 /// Find the first existing SSH key in the user's .ssh directory.
@@ -47,10 +61,7 @@ struct VmFileInfo {
     user: Option<String>,
 }
 
-fn lookup_vmfile(
-    vm_name: &str,
-    explicit_file: Option<&std::path::Path>,
-) -> Option<VmFileInfo> {
+fn lookup_vmfile(vm_name: &str, explicit_file: Option<&std::path::Path>) -> Option<VmFileInfo> {
     let path = vm_manager::vmfile::discover(explicit_file).ok()?;
     let vmfile = vm_manager::vmfile::parse(&path).ok()?;
     let def = vmfile.vms.iter().find(|d| d.name == vm_name)?;
@@ -70,42 +81,70 @@ fn default_vm_name(explicit_file: Option<&std::path::Path>) -> Option<String> {
     }
 }
 
-pub async fn run(args: SshArgs) -> Result<()> {
-    // Resolve VM name: CLI arg → infer from VMFile
-    let name = args
-        .name
-        .or_else(|| default_vm_name(args.file.as_deref()))
-        .ok_or_else(|| {
-            miette::miette!(
-                "no VM name provided and VMFile.kdl defines multiple VMs — specify one explicitly"
-            )
-        })?;
+/// Resolved SSH connection details for a VM.
+pub(super) struct SshTarget {
+    pub ip: String,
+    pub port: u16,
+    pub config: SshConfig,
+    /// Hypervisor host to jump through when the VM is not local.
+    pub remote: Option<RemoteHost>,
+}
 
-    let store = state::load_store().await?;
-    let handle = store
-        .get(&name)
+/// Look up a VM in the store and resolve its address, SSH port, user and key.
+///
+/// The address is the guest's IPv4 one unless `prefer_ipv6` is set and it has a global IPv6
+/// address. Address discovery polls for up to `wait` while the guest is still booting.
+///
+/// The user comes from the CLI flag, then the VMFile ssh block, then the configured `ssh-user`. The key comes from
+/// the CLI flag, then the VM's generated key, then the user's default keys in `~/.ssh`.
+pub(super) async fn resolve_target(
+    config: &Config,
+    name: &str,
+    user: Option<String>,
+    key: Option<PathBuf>,
+    file: Option<&std::path::Path>,
+    prefer_ipv6: bool,
+    wait: Duration,
+) -> Result<SshTarget> {
+    let store = state::load_store(config).await?;
+    let handle = state::find(&store, name)
         .ok_or_else(|| miette::miette!("VM '{name}' not found — run `vmctl up` first"))?;
+    let name = handle.name.as_str();
 
-    let hv = RouterHypervisor::new(None, None);
-    let ip = hv.guest_ip(handle).await.into_diagnostic()?;
+    let hv = super::hypervisor(config)?;
+    let ip = hv
+        .guest_ips(handle, wait)
+        .await?
+        .preferred(prefer_ipv6)
+        .ok_or_else(|| miette::miette!("no address found for VM '{name}'"))?
+        .to_string();
 
     // Determine SSH port: use the forwarded host port for user-mode networking
-    let port = match handle.network {
-        NetworkConfig::User => handle.ssh_host_port.unwrap_or(22),
-        _ => 22,
-    };
+    let port = handle.ssh_port()?;
 
-    // Resolve user: CLI flag → VMFile → default "vm"
-    let vmfile_info = lookup_vmfile(&name, args.file.as_deref());
-    let user = args
-        .user
+    // Windows only has an SSH server once OpenSSH Server is installed, and without one every
+    // retry below would just time out
+    if handle.guest_os == GuestOs::Windows
+        && handle.remote_host.is_none()
+        && !ssh_banner(&vm_manager::ssh::host_port(&ip, port), WINDOWS_SSH_PROBE).await
+    {
+        miette::bail!(
+            help = format!(
+                "connect with `vmctl rdp {name}`, or install and start OpenSSH Server in the guest (Settings > Optional features)"
+            ),
+            "Windows VM '{name}' does not answer on SSH"
+        );
+    }
+
+    // Resolve user: CLI flag → VMFile → configured ssh-user
+    let vmfile_info = lookup_vmfile(name, file);
+    let user = user
         .or_else(|| vmfile_info.and_then(|i| i.user))
-        .unwrap_or_else(|| "vm".to_string());
+        .unwrap_or_else(|| config.ssh_user.value.clone());
 
     // Check for a generated key in the VM's work directory first, then user keys
     let generated_key = handle.work_dir.join(super::GENERATED_KEY_FILE);
-    let key_path = args
-        .key
+    let key_path = key
         .or_else(|| generated_key.exists().then_some(generated_key))
         .or_else(find_ssh_key)
         .ok_or_else(|| {
@@ -116,21 +155,91 @@ pub async fn run(args: SshArgs) -> Result<()> {
         })?;
 
     let config = SshConfig {
-        user: user.clone(),
+        user,
         public_key: None,
         private_key_path: Some(key_path),
         private_key_pem: None,
     };
 
-    println!("Connecting to {user}@{ip}:{port}...");
-
-    let sess = vm_manager::ssh::connect_with_retry(&ip, port, &config, Duration::from_secs(30))
-        .await
+    let remote = handle
+        .remote_host
+        .as_deref()
+        .map(RemoteHost::parse)
+        .transpose()
         .into_diagnostic()?;
 
+    Ok(SshTarget {
+        ip,
+        port,
+        config,
+        remote,
+    })
+}
+
+/// Whether an SSH server greets us on `addr` within `timeout`. A forwarded port of user-mode
+/// networking accepts connections even when nothing listens in the guest, so this waits for the
+/// banner rather than the connection.
+async fn ssh_banner(addr: &str, timeout: Duration) -> bool {
+    use tokio::io::AsyncReadExt;
+
+    let probe = async {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
+        let mut banner = [0u8; 4];
+        stream.read_exact(&mut banner).await.ok()?;
+        Some(&banner == b"SSH-")
+    };
+    matches!(tokio::time::timeout(timeout, probe).await, Ok(Some(true)))
+}
+
+pub async fn run(args: SshArgs, config: &Config) -> Result<()> {
+    // Resolve VM name: CLI arg → infer from VMFile
+    let name = args
+        .name
+        .or_else(|| default_vm_name(args.file.as_deref()))
+        .ok_or_else(|| {
+            miette::miette!(
+                "no VM name provided and VMFile.kdl defines multiple VMs — specify one explicitly"
+            )
+        })?;
+
+    let SshTarget {
+        ip,
+        port,
+        config,
+        remote,
+    } = resolve_target(
+        config,
+        &name,
+        args.user,
+        args.key,
+        args.file.as_deref(),
+        args.prefer_ipv6,
+        Duration::from_secs(args.wait),
+    )
+    .await?;
+    let user = config.user.clone();
+    let addr = vm_manager::ssh::host_port(&ip, port);
+
+    match remote {
+        Some(ref host) => println!("Connecting to {user}@{addr} via {host}..."),
+        None => println!("Connecting to {user}@{addr}..."),
+    }
+
+    let (sess, tunnel) = vm_manager::backends::remote::connect_guest(
+        remote.as_ref(),
+        &ip,
+        port,
+        &config,
+        Duration::from_secs(30),
+        super::cancel(),
+    )
+    .await
+    .into_diagnostic()?;
+
     // Drop the libssh2 session (just used to verify connectivity) and exec system ssh.
     // We use the system ssh binary for interactive terminal support.
     drop(sess);
+    drop(tunnel);
 
     let mut cmd = tokio::process::Command::new("ssh");
     cmd.arg("-o")
@@ -148,6 +257,11 @@ pub async fn run(args: SshArgs) -> Result<()> {
         cmd.arg("-i").arg(key);
     }
 
+    // Reach VMs on a remote hypervisor host through it
+    if let Some(ref host) = remote {
+        cmd.arg("-J").arg(host.jump_spec());
+    }
+
     cmd.arg(format!("{user}@{ip}"));
 
     let status = cmd.status().await.into_diagnostic()?;
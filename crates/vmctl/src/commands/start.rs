@@ -1,51 +1,161 @@
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
-use vm_manager::{Hypervisor, RouterHypervisor};
+use vm_manager::config::Config;
+use vm_manager::{BootDevice, Hypervisor, VmHandle, VmManager, VmState};
 
-use super::state;
+use super::client::{self, ApiClient};
+use super::daemon::VmReply;
+use super::{hostnames, state};
 
 #[derive(Args)]
 pub struct StartArgs {
     /// VM name
-    name: String,
-}
+    #[arg(required_unless_present = "all", conflicts_with = "all")]
+    name: Option<String>,
 
-pub async fn run_start(args: StartArgs) -> Result<()> {
-    let mut store = state::load_store().await?;
-    let handle = store.get(&args.name).ok_or_else(|| {
-        miette::miette!(
-            "VM '{}' not found — run `vmctl list` to see available VMs",
-            args.name
-        )
-    })?;
+    /// Start every VM in the namespace that is not running
+    #[arg(long)]
+    all: bool,
 
-    let hv = RouterHypervisor::new(None, None);
-    let updated = hv.start(handle).await.into_diagnostic()?;
+    /// With --all, how many VMs to start at the same time
+    #[arg(long, default_value_t = super::DEFAULT_PARALLEL, requires = "all")]
+    parallel: usize,
 
-    store.insert(args.name.clone(), updated);
-    state::save_store(&store).await?;
+    /// Print the command starting the VM would run, without starting it
+    #[arg(long, conflicts_with = "all")]
+    dry_run: bool,
+
+    /// Boot from this device (disk, cdrom or network) this time only, ahead of the VM's boot
+    /// order
+    #[arg(long, value_name = "DEVICE", conflicts_with = "all")]
+    boot_once: Option<BootDevice>,
+
+    /// Start even if the host seems short of memory for the VM
+    #[arg(long)]
+    force: bool,
+}
 
-    println!("VM '{}' started", args.name);
+pub async fn run_start(args: StartArgs, config: &Config) -> Result<()> {
+    if let Some(ref name) = args.name {
+        if !state::load_store(config).await?.contains_key(name) {
+            miette::bail!("VM '{name}' not found — run `vmctl list` to see available VMs");
+        }
+    }
+
+    let manager = super::checked_manager(config, super::hypervisor(config)?, args.force);
+    if let (true, Some(name)) = (args.dry_run, &args.name) {
+        let mut vm = manager.get(name).await?;
+        if let Some(device) = args.boot_once {
+            vm = vm.with_boot_once(device);
+        }
+        super::print_dry_run(&vm.dry_run_start().await?);
+        return Ok(());
+    }
+
+    hostnames::check(config).await?;
+    match args.name {
+        Some(name) => {
+            start_one(config, &manager, &name, args.boot_once).await?;
+            println!("VM '{name}' started");
+            Ok(())
+        }
+        None => start_all(config, manager, args.parallel).await,
+    }
+}
+
+pub async fn run_start_remote(args: StartArgs, client: &ApiClient) -> Result<()> {
+    if args.dry_run {
+        miette::bail!(
+            help = "run the dry run on the daemon's host without --remote",
+            "`vmctl start --dry-run` is not supported with --remote"
+        );
+    }
+    let Some(name) = args.name else {
+        miette::bail!(
+            help = "start the VMs one at a time by name",
+            "`vmctl start --all` is not supported with --remote"
+        );
+    };
+    let path = format!("/v1/vms/{}/start", client::encode(&name));
+    let boot_once = args.boot_once.map(|device| device.to_string());
+    let mut query: Vec<(&str, &str)> = boot_once
+        .iter()
+        .map(|device| ("boot_once", device.as_str()))
+        .collect();
+    if args.force {
+        query.push(("force", "true"));
+    }
+    let _: VmReply = client.call("POST", &path, &query, None::<&()>).await?;
+    println!("VM '{name}' started");
     Ok(())
 }
 
+/// Start every VM that is not running, `parallel` at a time, and report the failures together.
+async fn start_all(config: &Config, manager: VmManager, parallel: usize) -> Result<()> {
+    let mut names = Vec::new();
+    for vm in manager.list().await? {
+        if vm.state().await? != VmState::Running {
+            names.push(vm.name().to_string());
+        }
+    }
+    if names.is_empty() {
+        println!("All VMs are running");
+        return Ok(());
+    }
+    names.sort();
+
+    let total = names.len();
+    let tasks = names
+        .into_iter()
+        .map(|name| {
+            let (config, manager) = (config.clone(), manager.clone());
+            let task_name = name.clone();
+            (name, async move {
+                start_one(&config, &manager, &task_name, None).await?;
+                println!("VM '{task_name}' started");
+                Ok(())
+            })
+        })
+        .collect();
+    let (_, failed) = super::run_parallel(tasks, parallel).await;
+    super::report_failures(failed, total)
+}
+
+/// Start one VM, booting from `boot_once` this time if given, and record its new handle, which
+/// is returned.
+pub async fn start_one(
+    config: &Config,
+    manager: &VmManager,
+    name: &str,
+    boot_once: Option<BootDevice>,
+) -> Result<VmHandle> {
+    let mut vm = manager.get(name).await?;
+    if let Some(device) = boot_once {
+        vm = vm.with_boot_once(device);
+    }
+    vm.start().await?;
+    let updated = hostnames::register(config, manager.hypervisor(), vm.handle().clone()).await?;
+    vm.record(updated).await?;
+    Ok(vm.into_handle())
+}
+
 #[derive(Args)]
 pub struct SuspendArgs {
     /// VM name
     name: String,
 }
 
-pub async fn run_suspend(args: SuspendArgs) -> Result<()> {
-    let mut store = state::load_store().await?;
+pub async fn run_suspend(args: SuspendArgs, config: &Config) -> Result<()> {
+    let mut store = state::load_store(config).await?;
     let handle = store
         .get(&args.name)
         .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
 
-    let hv = RouterHypervisor::new(None, None);
+    let hv = super::hypervisor(config)?;
     let updated = hv.suspend(handle).await.into_diagnostic()?;
 
     store.insert(args.name.clone(), updated);
-    state::save_store(&store).await?;
+    state::save_store(config, &store).await?;
 
     println!("VM '{}' suspended", args.name);
     Ok(())
@@ -57,17 +167,17 @@ pub struct ResumeArgs {
     name: String,
 }
 
-pub async fn run_resume(args: ResumeArgs) -> Result<()> {
-    let mut store = state::load_store().await?;
+pub async fn run_resume(args: ResumeArgs, config: &Config) -> Result<()> {
+    let mut store = state::load_store(config).await?;
     let handle = store
         .get(&args.name)
         .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
 
-    let hv = RouterHypervisor::new(None, None);
+    let hv = super::hypervisor(config)?;
     let updated = hv.resume(handle).await.into_diagnostic()?;
 
     store.insert(args.name.clone(), updated);
-    state::save_store(&store).await?;
+    state::save_store(config, &store).await?;
 
     println!("VM '{}' resumed", args.name);
     Ok(())
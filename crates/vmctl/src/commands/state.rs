@@ -1,43 +1,30 @@
-//! Persistent state for vmctl: maps VM name -> VmHandle in a JSON file.
+//! Persistent state for vmctl: the library's [`VmStore`] of the configured namespace.
+//!
+//! State file location: `<data-dir>/namespaces/<namespace>/vms.json`
+//! (`{XDG_DATA_HOME}/vmctl/namespaces/$USER/vms.json` by default)
 
-use std::collections::HashMap;
-use std::path::PathBuf;
+use miette::Result;
+use vm_manager::config::Config;
+use vm_manager::store::VmStore;
 
-use miette::{IntoDiagnostic, Result};
-use vm_manager::VmHandle;
+pub use vm_manager::store::{Store, find, known_macs};
 
-/// State file location: `{XDG_DATA_HOME}/vmctl/vms.json`
-fn state_path() -> PathBuf {
-    dirs::data_dir()
-        .unwrap_or_else(|| PathBuf::from("/tmp"))
-        .join("vmctl")
-        .join("vms.json")
+/// Load the VM store from disk. Returns an empty map if the file doesn't exist.
+pub async fn load_store(config: &Config) -> Result<Store> {
+    Ok(VmStore::for_config(config).load().await?)
 }
 
-pub type Store = HashMap<String, VmHandle>;
+/// Load the store of every namespace in the data directory, sorted by namespace.
+pub async fn load_all_stores(config: &Config) -> Result<Vec<(String, Store)>> {
+    Ok(VmStore::load_all(config).await?)
+}
 
-/// Load the VM store from disk. Returns an empty map if the file doesn't exist.
-pub async fn load_store() -> Result<Store> {
-    let path = state_path();
-    if !path.exists() {
-        return Ok(HashMap::new());
-    }
-    let data = tokio::fs::read_to_string(&path).await.into_diagnostic()?;
-    let store: Store = serde_json::from_str(&data).into_diagnostic()?;
-    Ok(store)
+/// Save the VM store to disk atomically.
+pub async fn save_store(config: &Config, store: &Store) -> Result<()> {
+    Ok(VmStore::for_config(config).save(store).await?)
 }
 
-/// Save the VM store to disk atomically (write to .tmp then rename).
-pub async fn save_store(store: &Store) -> Result<()> {
-    let path = state_path();
-    if let Some(parent) = path.parent() {
-        tokio::fs::create_dir_all(parent).await.into_diagnostic()?;
-    }
-    let data = serde_json::to_string_pretty(store).into_diagnostic()?;
-    let tmp_path = path.with_extension("json.tmp");
-    tokio::fs::write(&tmp_path, data).await.into_diagnostic()?;
-    tokio::fs::rename(&tmp_path, &path)
-        .await
-        .into_diagnostic()?;
-    Ok(())
+/// Load the store, apply `update` and save it again under the store lock.
+pub async fn with_store_mut<T>(config: &Config, update: impl FnOnce(&mut Store) -> T) -> Result<T> {
+    Ok(VmStore::for_config(config).update(update).await?)
 }
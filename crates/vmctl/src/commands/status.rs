@@ -1,63 +1,407 @@
-use clap::Args;
+use std::future::Future;
+use std::time::Duration;
+
+use clap::{Args, ValueEnum};
 use miette::{IntoDiagnostic, Result};
-use vm_manager::{Hypervisor, NetworkConfig, RouterHypervisor};
+use vm_manager::config::Config;
+use vm_manager::metrics;
+use vm_manager::pinning::format_cpuset;
+use vm_manager::{
+    AudioBackend, DisplayMode, GuestOs, Hypervisor, NetworkConfig, RestartPolicy, VcpuAffinity,
+    VmHandle, VmState,
+};
 
+use super::client::{self, ApiClient};
+use super::daemon::StatusReply;
 use super::state;
 
+/// How long `status` waits for the guest agent when asking about Secure Boot.
+#[cfg(target_os = "linux")]
+const SECURE_BOOT_AGENT_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Args)]
 pub struct StatusArgs {
     /// VM name
     name: String,
+
+    /// Output format: `table` is one `Key: value` line per setting; `json` and `yaml` are the
+    /// VM's handle, state and resource usage as one object, sizes in bytes
+    #[arg(
+        short = 'o',
+        long,
+        visible_alias = "output",
+        value_enum,
+        default_value_t = StatusFormat::Table
+    )]
+    format: StatusFormat,
+
+    /// Redraw the status every SECS seconds, highlighting what changed, until Ctrl+C
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    watch: Option<u64>,
+
+    /// Also show details such as the host CPUs each vCPU thread runs on
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StatusFormat {
+    /// Aligned, human-readable lines
+    #[value(alias = "text")]
+    Table,
+    /// A pretty-printed JSON object
+    Json,
+    /// The same object as YAML
+    Yaml,
 }
 
-pub async fn run(args: StatusArgs) -> Result<()> {
-    let store = state::load_store().await?;
-    let handle = store
-        .get(&args.name)
-        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+pub async fn run(args: StatusArgs, config: &Config) -> Result<()> {
+    let hv = &super::hypervisor(config)?;
+    let name = &args.name;
+    let poll = move || async move {
+        // Reloaded each time, so a watch sees the VM started or stopped elsewhere
+        let mut store = state::load_store(config).await?;
+        let vm = store
+            .remove(name)
+            .ok_or_else(|| miette::miette!("VM '{name}' not found"))?;
+        let state = hv.state(&vm).await.into_diagnostic()?;
+        let usage = metrics::resource_usage(&vm, state).await;
+        let guest_secure_boot = guest_secure_boot(&vm, state).await;
+        let vcpu_affinity = vcpu_affinity(&vm, state).await;
+        Ok(StatusReply {
+            vm,
+            state,
+            usage,
+            guest_secure_boot,
+            vcpu_affinity,
+        })
+    };
+    show(poll, &args).await
+}
+
+pub async fn run_remote(args: StatusArgs, client: &ApiClient) -> Result<()> {
+    let path = format!("/v1/vms/{}", client::encode(&args.name));
+    let poll = || client.call("GET", &path, &[], None::<&()>);
+    show(poll, &args).await
+}
 
-    let hv = RouterHypervisor::new(None, None);
-    let state = hv.state(handle).await.into_diagnostic()?;
+/// Print the status `poll` returns, or with `--watch`, redraw it every few seconds until
+/// Ctrl+C, with the lines that changed since the last redraw in yellow.
+async fn show<F, Fut>(mut poll: F, args: &StatusArgs) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<StatusReply>>,
+{
+    let Some(secs) = args.watch else {
+        for line in render(&poll().await?, args)? {
+            println!("{line}");
+        }
+        return Ok(());
+    };
+    let mut previous: Option<Vec<String>> = None;
+    loop {
+        let lines = render(&poll().await?, args)?;
+        // Clear the screen and move the cursor home
+        print!("\x1b[2J\x1b[H");
+        for line in &lines {
+            if previous.as_ref().is_some_and(|prev| !prev.contains(line)) {
+                println!("\x1b[33m{line}\x1b[0m");
+            } else {
+                println!("{line}");
+            }
+        }
+        previous = Some(lines);
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            _ = tokio::time::sleep(Duration::from_secs(secs)) => {}
+        }
+    }
+}
+
+/// Ask the guest agent of a running Secure Boot VM whether the guest sees Secure Boot enforced.
+pub(super) async fn guest_secure_boot(vm: &VmHandle, state: VmState) -> Option<bool> {
+    if !vm.secure_boot || state != VmState::Running || vm.remote_host.is_some() {
+        return None;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use vm_manager::backends::qga;
 
-    println!("Name:    {}", handle.name);
-    println!("ID:      {}", handle.id);
-    println!("Backend: {}", handle.backend);
-    println!("State:   {}", state);
-    println!("vCPUs:   {}", handle.vcpus);
-    println!("Memory:  {} MB", handle.memory_mb);
+        let vm = vm.clone();
+        tokio::task::spawn_blocking(move || qga::secure_boot(&vm, SECURE_BOOT_AGENT_TIMEOUT))
+            .await
+            .ok()?
+            .ok()
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Read the CPU affinity of a running, CPU-pinned VM's vCPU threads.
+pub(super) async fn vcpu_affinity(vm: &VmHandle, state: VmState) -> Vec<VcpuAffinity> {
+    if vm.cpu_pinning.is_none() || state != VmState::Running || vm.remote_host.is_some() {
+        return Vec::new();
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use vm_manager::backends::qemu::QemuBackend;
+
+        QemuBackend::vcpu_affinity(vm).await.unwrap_or_else(|e| {
+            tracing::warn!(vm = %vm.name, error = %e, "cannot read vCPU affinity");
+            Vec::new()
+        })
+    }
+    #[cfg(not(target_os = "linux"))]
+    Vec::new()
+}
+
+fn render(reply: &StatusReply, args: &StatusArgs) -> Result<Vec<String>> {
+    let text = match args.format {
+        StatusFormat::Table => return Ok(lines(reply, args.verbose)),
+        StatusFormat::Json => serde_json::to_string_pretty(reply).into_diagnostic()?,
+        StatusFormat::Yaml => serde_yaml::to_string(reply).into_diagnostic()?,
+    };
+    Ok(text.lines().map(str::to_string).collect())
+}
+
+/// The table format: one `Key: value` line per setting, and with `verbose` indented details
+/// under some.
+fn lines(reply: &StatusReply, verbose: bool) -> Vec<String> {
+    let StatusReply {
+        vm: handle,
+        state,
+        usage,
+        guest_secure_boot,
+        vcpu_affinity,
+    } = reply;
+    let mut lines = vec![
+        format!("Name:    {}", handle.name),
+        format!("ID:      {}", handle.id),
+        format!("Backend: {}", handle.backend),
+    ];
+    if let Some(ref host) = handle.remote_host {
+        lines.push(format!("Host:    {}", host));
+    }
+    lines.push(format!("State:   {}", state));
+    lines.push(format!("vCPUs:   {}", handle.vcpus));
+    lines.push(format!("Memory:  {} MB", handle.memory_mb));
+    if verbose {
+        if let Some(bytes) = usage.guest_memory_bytes {
+            lines.push(format!("  in the guest: {}", format_bytes(bytes)));
+        }
+    }
     if let Some(disk) = handle.disk_gb {
-        println!("Disk:    {} GB", disk);
+        lines.push(format!("Disk:    {} GB", disk));
+    }
+    lines.push(format!("Network: {}", format_network(handle)));
+    for nic in &handle.private_networks {
+        let link = match nic.bridge {
+            Some(ref bridge) => format!("bridge {bridge}"),
+            None => format!("multicast {}", nic.multicast_group()),
+        };
+        lines.push(format!("Private: {} {} ({link})", nic.network, nic.address));
     }
-    println!("Network: {}", format_network(&handle.network));
-    println!("WorkDir: {}", handle.work_dir.display());
+    if handle.guest_os != GuestOs::Linux {
+        lines.push(format!("Guest OS: {}", handle.guest_os));
+    }
+    lines.push(format!("Disk bus: {}", handle.disk_bus));
+    if handle.display != DisplayMode::Vnc {
+        lines.push(format!("Display: {}", handle.display));
+    }
+    if handle.audio != AudioBackend::None {
+        lines.push(format!("Audio:   {}", handle.audio));
+    }
+    if !handle.usb_devices.is_empty() {
+        let devices: Vec<String> = handle.usb_devices.iter().map(|d| d.to_string()).collect();
+        lines.push(format!("USB:     {}", devices.join(", ")));
+    }
+    lines.push(format!("WorkDir: {}", handle.work_dir.display()));
 
     if let Some(ref overlay) = handle.overlay_path {
-        println!("Overlay: {}", overlay.display());
+        lines.push(format!("Overlay: {}", overlay.display()));
     }
     if let Some(ref seed) = handle.seed_iso_path {
-        println!("Seed:    {}", seed.display());
+        lines.push(format!("Seed:    {}", seed.display()));
+    }
+    if let Some(ref iso) = handle.cdrom {
+        lines.push(format!("CD-ROM:  {}", iso.display()));
+    }
+    if !handle.boot_order.is_empty() {
+        let order: Vec<String> = handle.boot_order.iter().map(|d| d.to_string()).collect();
+        lines.push(format!("Boot order: {}", order.join(", ")));
+    }
+    if let Some(ref iso) = handle.drivers_iso {
+        lines.push(format!("Drivers: {}", iso.display()));
+    }
+    if !handle.qemu_args.is_empty() {
+        lines.push(format!("QEMU:    {}", handle.qemu_args.join(" ")));
+    }
+    if handle.secure_boot {
+        lines.push(match guest_secure_boot {
+            Some(true) => "Secure Boot: on, enforced in the guest".into(),
+            Some(false) => "Secure Boot: on, but the guest reports it off".into(),
+            None => "Secure Boot: on".into(),
+        });
+    }
+    if let Some(mode) = handle.confidential {
+        lines.push(format!("Confidential: {mode} (see `vmctl attest`)"));
+    }
+    if let Some(ref pinning) = handle.cpu_pinning {
+        lines.push(format!("CPU pinning: {pinning}"));
+        if verbose {
+            for affinity in vcpu_affinity {
+                lines.push(format!(
+                    "  vCPU {}: thread {} on {}",
+                    affinity.vcpu,
+                    affinity.thread_id,
+                    format_cpuset(&affinity.cpus)
+                ));
+            }
+        }
+    }
+    if handle.restart_policy != RestartPolicy::No {
+        lines.push(format!("Restart: {}", handle.restart_policy));
     }
     if let Some(pid) = handle.pid {
-        println!("PID:     {}", pid);
+        lines.push(format!("PID:     {}", pid));
+    }
+    // Measured rather than configured; `-` while the VM isn't running
+    let dash = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+    lines.push(format!(
+        "Uptime:  {}",
+        dash(usage.uptime_seconds.map(format_uptime))
+    ));
+    lines.push(format!(
+        "CPU:     {}",
+        dash(usage.cpu_percent.map(|p| format!("{p:.1}%")))
+    ));
+    lines.push(format!(
+        "RSS:     {}",
+        dash(usage.rss_bytes.map(format_bytes))
+    ));
+    lines.push(format!(
+        "vCPU threads: {}",
+        dash(usage.vcpu_threads.map(|n| n.to_string()))
+    ));
+    if handle.overlay_path.is_some() {
+        let allocated = dash(usage.overlay_allocated_bytes.map(format_bytes));
+        let size = dash(usage.overlay_virtual_bytes.map(format_bytes));
+        lines.push(format!("Overlay size: {allocated} allocated of {size}"));
     }
     if let Some(ref vnc) = handle.vnc_addr {
-        println!("VNC:     {}", vnc);
+        lines.push(format!("VNC:     {}", vnc));
     }
     if let Some(port) = handle.ssh_host_port {
-        println!("SSH:     127.0.0.1:{}", port);
+        lines.push(format!("SSH:     127.0.0.1:{}", port));
+    }
+    if let Some(port) = handle.rdp_host_port {
+        lines.push(format!("RDP:     127.0.0.1:{}", port));
     }
     if let Some(ref mac) = handle.mac_addr {
-        println!("MAC:     {}", mac);
+        lines.push(format!("MAC:     {}", mac));
+    }
+    if !handle.hostnames.is_empty() {
+        lines.push(format!("Hostnames: {}", handle.hostnames.join(", ")));
+    }
+    if !handle.labels.is_empty() {
+        let mut labels: Vec<_> = handle
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect();
+        labels.sort();
+        lines.push(format!("Labels:  {}", labels.join(", ")));
+    }
+    if !handle.annotations.is_empty() {
+        let mut keys: Vec<_> = handle.annotations.keys().map(String::as_str).collect();
+        keys.sort();
+        lines.push(format!(
+            "Annotations: {} (see `vmctl annotate {}`)",
+            keys.join(", "),
+            handle.name
+        ));
     }
+    lines
+}
 
-    Ok(())
+/// Sizes the way `vmctl image list` shows them.
+fn format_bytes(bytes: u64) -> String {
+    if bytes >= 1_073_741_824 {
+        format!("{:.1} GB", bytes as f64 / 1_073_741_824.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / 1_048_576.0)
+    }
 }
 
-fn format_network(net: &NetworkConfig) -> String {
-    match net {
+/// `3d 4h 05m`, `4h 05m 06s` or `5m 06s`.
+fn format_uptime(seconds: f64) -> String {
+    let s = seconds as u64;
+    let (d, h, m, s) = (s / 86_400, s / 3600 % 24, s / 60 % 60, s % 60);
+    if d > 0 {
+        format!("{d}d {h}h {m:02}m")
+    } else if h > 0 {
+        format!("{h}h {m:02}m {s:02}s")
+    } else {
+        format!("{m}m {s:02}s")
+    }
+}
+
+fn format_network(handle: &VmHandle) -> String {
+    match &handle.network {
         NetworkConfig::Tap { bridge } => format!("tap (bridge: {bridge})"),
         NetworkConfig::User => "user (SLIRP)".into(),
         NetworkConfig::Vnic { name } => format!("vnic ({name})"),
+        NetworkConfig::Macvtap { parent } => match handle.macvtap {
+            Some(ref device) => format!("macvtap ({device} on {parent})"),
+            None => format!("macvtap (on {parent})"),
+        },
         NetworkConfig::None => "none".into(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_and_yaml_include_labels_and_annotations() {
+        let vm: VmHandle = serde_json::from_value(serde_json::json!({
+            "id": "qemu-web",
+            "name": "web",
+            "backend": "qemu",
+            "work_dir": "/tmp/web",
+            "labels": { "env": "prod" },
+            "annotations": { "owner": "ops" },
+        }))
+        .unwrap();
+        let reply = StatusReply {
+            vm,
+            state: VmState::Running,
+            usage: Default::default(),
+            guest_secure_boot: None,
+            vcpu_affinity: Vec::new(),
+        };
+        for format in [StatusFormat::Json, StatusFormat::Yaml] {
+            let args = StatusArgs {
+                name: "web".into(),
+                format,
+                watch: None,
+                verbose: false,
+            };
+            let text = render(&reply, &args).unwrap().join("\n");
+            // JSON is also YAML, so one parser reads both
+            let value: serde_yaml::Value = serde_yaml::from_str(&text).unwrap();
+            assert_eq!(
+                value["vm"]["labels"]["env"].as_str(),
+                Some("prod"),
+                "{text}"
+            );
+            assert_eq!(
+                value["vm"]["annotations"]["owner"].as_str(),
+                Some("ops"),
+                "{text}"
+            );
+            assert_eq!(value["state"].as_str(), Some("running"), "{text}");
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use std::time::Duration;
 
 use clap::Args;
-use miette::{IntoDiagnostic, Result};
-use vm_manager::{Hypervisor, RouterHypervisor};
+use miette::Result;
+use vm_manager::VmHandle;
+use vm_manager::config::Config;
 
-use super::state;
+use super::client::{self, ApiClient};
+use super::daemon::VmReply;
+use super::hostnames;
 
 #[derive(Args)]
 pub struct StopArgs {
@@ -16,21 +19,27 @@ pub struct StopArgs {
     timeout: u64,
 }
 
-pub async fn run(args: StopArgs) -> Result<()> {
-    let mut store = state::load_store().await?;
-    let handle = store
-        .get(&args.name)
-        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
-
-    let hv = RouterHypervisor::new(None, None);
-    let updated = hv
-        .stop(handle, Duration::from_secs(args.timeout))
-        .await
-        .into_diagnostic()?;
-
-    store.insert(args.name.clone(), updated);
-    state::save_store(&store).await?;
+pub async fn run(args: StopArgs, config: &Config) -> Result<()> {
+    stop(config, &args.name, Duration::from_secs(args.timeout)).await?;
+    println!("VM '{}' stopped", args.name);
+    Ok(())
+}
 
+pub async fn run_remote(args: StopArgs, client: &ApiClient) -> Result<()> {
+    let path = format!("/v1/vms/{}/stop", client::encode(&args.name));
+    let timeout = args.timeout.to_string();
+    let _: VmReply = client
+        .call("POST", &path, &[("timeout", &timeout)], None::<&()>)
+        .await?;
     println!("VM '{}' stopped", args.name);
     Ok(())
 }
+
+/// Stop a VM and record its new handle, which is returned.
+pub async fn stop(config: &Config, name: &str, timeout: Duration) -> Result<VmHandle> {
+    let manager = super::manager(config, super::hypervisor(config)?);
+    let handle = manager.get(name).await?.into_handle();
+    let mut vm = manager.attach(hostnames::unregister(handle).await?);
+    vm.stop(timeout).await?;
+    Ok(vm.into_handle())
+}
@@ -0,0 +1,116 @@
+use std::path::Path;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use vm_manager::backends::remote::RemoteHost;
+use vm_manager::config::Config;
+use vm_manager::{ConsoleEndpoint, Hypervisor};
+
+use super::state;
+
+#[derive(Args)]
+pub struct TailConsoleArgs {
+    /// VM name
+    name: String,
+
+    /// Number of lines of earlier console output to show first (0 = none)
+    #[arg(long, short = 'n', default_value = "20")]
+    lines: usize,
+}
+
+pub async fn run(args: TailConsoleArgs, config: &Config) -> Result<()> {
+    let store = state::load_store(config).await?;
+    let handle = store
+        .get(&args.name)
+        .ok_or_else(|| miette::miette!("VM '{}' not found", args.name))?;
+
+    let hv = super::hypervisor(config)?;
+    let endpoint = hv.console_endpoint(handle).into_diagnostic()?;
+    let log_path = handle.work_dir.join("console.log");
+
+    match endpoint {
+        ConsoleEndpoint::UnixSocket(path) => {
+            // Connect before printing the backlog so nothing is lost in between.
+            let sock = connect(&path).await?;
+            let backlog = match tokio::fs::read(&log_path).await {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e).into_diagnostic(),
+            };
+            stream(sock, &backlog, args.lines).await?;
+        }
+        ConsoleEndpoint::RemoteUnixSocket { host, path } => {
+            let host = RemoteHost::parse(&host).into_diagnostic()?;
+            let dir = tempfile::tempdir().into_diagnostic()?;
+            let local = dir.path().join("console.sock");
+            let _tunnel = host.tunnel_unix(&path, &local).await.into_diagnostic()?;
+            let sock = connect(&local).await?;
+            // The log lives on the remote host; a missing file just means no backlog.
+            let backlog = host
+                .command("cat")
+                .arg(&log_path)
+                .output()
+                .await
+                .into_diagnostic()?
+                .stdout;
+            stream(sock, &backlog, args.lines).await?;
+        }
+        ConsoleEndpoint::WebSocket(url) => {
+            miette::bail!(
+                help = "use a WebSocket client to read the console",
+                "console for VM '{}' is only available at {url}",
+                args.name
+            );
+        }
+        ConsoleEndpoint::None => {
+            miette::bail!("no console available for VM '{}'", args.name);
+        }
+    }
+
+    Ok(())
+}
+
+async fn connect(path: &Path) -> Result<UnixStream> {
+    UnixStream::connect(path).await.map_err(|e| {
+        miette::miette!(
+            help = "is the VM running? start it with `vmctl start`",
+            "cannot connect to console at {}: {e}",
+            path.display()
+        )
+    })
+}
+
+/// Print the last `lines` lines of `backlog`, then copy console output to stdout until the VM
+/// closes the socket. Nothing is ever written to the socket.
+async fn stream(mut sock: UnixStream, backlog: &[u8], lines: usize) -> Result<()> {
+    let mut stdout = tokio::io::stdout();
+    stdout
+        .write_all(last_lines(backlog, lines))
+        .await
+        .into_diagnostic()?;
+    stdout.flush().await.into_diagnostic()?;
+
+    tokio::io::copy(&mut sock, &mut stdout)
+        .await
+        .into_diagnostic()?;
+    stdout.flush().await.into_diagnostic()?;
+    Ok(())
+}
+
+/// The trailing `n` lines of `buf` (a final line without a newline counts as a line).
+fn last_lines(buf: &[u8], n: usize) -> &[u8] {
+    if n == 0 {
+        return &[];
+    }
+    let body = buf.strip_suffix(b"\n").unwrap_or(buf);
+    let start = body
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, b)| **b == b'\n')
+        .nth(n - 1)
+        .map_or(0, |(i, _)| i + 1);
+    &buf[start..]
+}
@@ -0,0 +1,23 @@
+//! `vmctl time-sync`: set a VM's clock to the host's through the guest agent.
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::backends::qga;
+use vm_manager::config::Config;
+
+#[derive(Args)]
+pub struct TimeSyncArgs {
+    /// VM name or `<name>.local` hostname
+    name: String,
+}
+
+pub async fn run(args: TimeSyncArgs, config: &Config) -> Result<()> {
+    let handle = super::exec::agent_vm(config, &args.name).await?;
+    let name = handle.name.clone();
+    tokio::task::spawn_blocking(move || qga::sync_time(&handle, qga::DEFAULT_AGENT_TIMEOUT))
+        .await
+        .into_diagnostic()?
+        .into_diagnostic()?;
+    println!("Set the clock of VM '{name}' to the host's");
+    Ok(())
+}
@@ -1,13 +1,17 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use clap::Args;
 use miette::{IntoDiagnostic, Result};
 use tracing::info;
-use vm_manager::vmfile::{ProvisionDef, SshDef};
-use vm_manager::{Hypervisor, RouterHypervisor, VmState};
+use vm_manager::config::Config;
+use vm_manager::provision::ProvisionFilter;
+use vm_manager::vmfile::{ProvisionDef, SshDef, VmDef};
+use vm_manager::{RouterHypervisor, VmManager, VmState};
 
-use super::state;
+use super::progress::Progress;
+use super::provision_cmd::{self, SnapshotArgs, SnapshotPolicy};
+use super::{hostnames, state, validate};
 
 #[derive(Args)]
 pub struct UpArgs {
@@ -22,127 +26,259 @@ pub struct UpArgs {
     /// Skip provisioning
     #[arg(long)]
     no_provision: bool,
+
+    /// How many independent VMs to create and start at the same time
+    #[arg(long, default_value_t = super::DEFAULT_PARALLEL)]
+    parallel: usize,
+
+    /// Bring the VMs up even if the host seems short of memory or disk space for them
+    #[arg(long)]
+    force: bool,
+
+    #[command(flatten)]
+    snapshot: SnapshotArgs,
+}
+
+/// What `up_vm` did to bring a VM up.
+enum Launch {
+    AlreadyRunning,
+    Started,
+    Created,
 }
 
-pub async fn run(args: UpArgs) -> Result<()> {
+pub async fn run(args: UpArgs, config: &Config) -> Result<()> {
     let path = vm_manager::vmfile::discover(args.file.as_deref()).into_diagnostic()?;
     let vmfile = vm_manager::vmfile::parse(&path).into_diagnostic()?;
+    let stages = vmfile.start_order(args.name.as_deref()).into_diagnostic()?;
 
-    let mut store = state::load_store().await?;
-    let hv = RouterHypervisor::new(None, None);
+    state::load_store(config).await?;
+    let manager = super::checked_manager(config, super::hypervisor(config)?, args.force)
+        .with_timeout(super::PROVISION_IP_TIMEOUT);
+    hostnames::check(config).await?;
+    // VMs in a stage start together, so they are checked together too
+    if !args.force {
+        let defs: Vec<&VmDef> = stages.iter().flatten().copied().collect();
+        validate::check_resources(config, &manager, &defs).await?;
+    }
+
+    let progress = if args.parallel > 1 {
+        Progress::start_prefixed(manager.events())
+    } else {
+        Progress::start(manager.events())
+    };
+    let result = up_stages(&args, config, &manager, &stages, &vmfile.base_dir).await;
+    progress.finish().await;
+    result
+}
 
-    for def in &vmfile.vms {
-        if let Some(ref filter) = args.name {
-            if &def.name != filter {
+/// Bring up the VMs stage by stage. Within a stage the VMs are independent, so they are created
+/// and started concurrently, then provisioned one after the other. A failed VM does not stop the
+/// rest of its stage, but later stages, which may depend on it, are skipped.
+async fn up_stages(
+    args: &UpArgs,
+    config: &Config,
+    manager: &VmManager,
+    stages: &[Vec<&VmDef>],
+    base_dir: &Path,
+) -> Result<()> {
+    let total = stages.iter().map(Vec::len).sum();
+    let mut failures = Vec::new();
+    let mut reached = 0;
+    for (i, stage) in stages.iter().enumerate() {
+        reached = i + 1;
+        let tasks = stage
+            .iter()
+            .map(|def| {
+                let (config, manager, def) = (config.clone(), manager.clone(), (*def).clone());
+                let base_dir = base_dir.to_path_buf();
+                let name = def.name.clone();
+                (name, async move {
+                    up_vm(&config, &manager, &def, &base_dir).await
+                })
+            })
+            .collect();
+        let (launched, failed) = super::run_parallel(tasks, args.parallel).await;
+        failures.extend(failed);
+
+        // Provision in VMFile order, so that guest output is not interleaved.
+        for def in stage {
+            let Some((_, launch)) = launched.iter().find(|(name, _)| *name == def.name) else {
+                continue;
+            };
+            if args.no_provision
+                || def.provisions.is_empty()
+                || matches!(launch, Launch::AlreadyRunning)
+            {
                 continue;
             }
+            let result = run_provision_for_vm(
+                manager.hypervisor(),
+                config,
+                &def.name,
+                &def.provisions,
+                def.ssh.as_ref(),
+                base_dir,
+                args.snapshot.policy(def),
+            )
+            .await;
+            if let Err(e) = result {
+                // A VM created by this run is removed again, so that the next `vmctl up`
+                // creates and provisions it from scratch.
+                if super::cancel().is_cancelled() && matches!(launch, Launch::Created) {
+                    if let Some(handle) = state::load_store(config).await?.remove(&def.name) {
+                        super::roll_back_create(config, manager.hypervisor(), handle).await;
+                    }
+                }
+                failures.push((def.name.clone(), e));
+            }
         }
 
-        // Check if already in store
-        if let Some(handle) = store.get(&def.name) {
-            let state = hv.state(handle).await.into_diagnostic()?;
-            if state == VmState::Running {
-                println!("VM '{}' is already running — skipping", def.name);
-                continue;
-            }
+        if !failures.is_empty() || super::cancel().is_cancelled() {
+            break;
+        }
 
-            // Stopped → start + re-provision
-            info!(vm = %def.name, "starting existing VM");
-            let updated = hv.start(handle).await.into_diagnostic()?;
-            store.insert(def.name.clone(), updated);
-            state::save_store(&store).await?;
-            println!("VM '{}' started", def.name);
-
-            if !args.no_provision && !def.provisions.is_empty() {
-                run_provision_for_vm(
-                    &hv,
-                    &store,
-                    def.name.as_str(),
-                    &def.provisions,
-                    def.ssh.as_ref(),
-                    &vmfile.base_dir,
-                )
-                .await?;
+        // VMs in later stages depend on this one — wait until it accepts SSH connections.
+        if i + 1 < stages.len() {
+            let tasks = stage
+                .iter()
+                .map(|def| {
+                    let (manager, def) = (manager.clone(), (*def).clone());
+                    let base_dir = base_dir.to_path_buf();
+                    let name = def.name.clone();
+                    (name, async move {
+                        wait_for_ssh(&manager, &def, &base_dir).await
+                    })
+                })
+                .collect();
+            let (_, failed) = super::run_parallel(tasks, stage.len()).await;
+            failures.extend(failed);
+            if !failures.is_empty() {
+                break;
             }
-            continue;
         }
+    }
 
-        // Not in store → resolve, prepare, start, provision
-        info!(vm = %def.name, "creating and starting VM");
-        let spec = vm_manager::vmfile::resolve(def, &vmfile.base_dir)
-            .await
-            .into_diagnostic()?;
-
-        let handle = hv.prepare(&spec).await.into_diagnostic()?;
-        super::save_generated_ssh_key(&spec, &handle).await?;
-        store.insert(def.name.clone(), handle.clone());
-        state::save_store(&store).await?;
-
-        let updated = hv.start(&handle).await.into_diagnostic()?;
-        store.insert(def.name.clone(), updated);
-        state::save_store(&store).await?;
-        println!("VM '{}' created and started", def.name);
-
-        if !args.no_provision && !def.provisions.is_empty() {
-            run_provision_for_vm(
-                &hv,
-                &store,
-                &def.name,
-                &def.provisions,
-                def.ssh.as_ref(),
-                &vmfile.base_dir,
-            )
-            .await?;
+    // Interrupted VMs were cleaned up already; `interruptible` reports the cancellation.
+    if super::cancel().is_cancelled() {
+        return Ok(());
+    }
+    let skipped: Vec<&str> = stages
+        .iter()
+        .skip(reached)
+        .flatten()
+        .map(|def| def.name.as_str())
+        .collect();
+    if !skipped.is_empty() && !failures.is_empty() {
+        eprintln!(
+            "Not brought up because an earlier stage failed: {}",
+            skipped.join(", ")
+        );
+    }
+    super::report_failures(failures, total)
+}
+
+async fn up_vm(
+    config: &Config,
+    manager: &VmManager,
+    def: &VmDef,
+    base_dir: &Path,
+) -> Result<Launch> {
+    // Check if already in store
+    if let Some(handle) = state::load_store(config).await?.remove(&def.name) {
+        let mut vm = manager.attach(handle);
+        if vm.state().await? == VmState::Running {
+            println!("VM '{}' is already running — skipping", def.name);
+            return Ok(Launch::AlreadyRunning);
         }
+
+        // Stopped → start + re-provision
+        info!(vm = %def.name, "starting existing VM");
+        vm.start().await?;
+        let updated =
+            hostnames::register(config, manager.hypervisor(), vm.handle().clone()).await?;
+        vm.record(updated).await?;
+        return Ok(Launch::Started);
+    }
+
+    // Not in store → resolve, prepare, start
+    info!(vm = %def.name, "creating and starting VM");
+    let images = config
+        .image_manager()
+        .with_events(manager.events().clone())
+        .with_cancel(super::cancel().clone());
+    let spec = vm_manager::vmfile::resolve(def, base_dir, &images)
+        .await
+        .into_diagnostic()?;
+
+    let mut vm = manager.create(spec).await?;
+    let result = async {
+        vm.start().await?;
+        let updated =
+            hostnames::register(config, manager.hypervisor(), vm.handle().clone()).await?;
+        vm.record(updated).await?;
+        Ok(Launch::Created)
     }
+    .await;
 
+    // A new VM that was interrupted before it was up is removed again.
+    if super::cancel().is_cancelled() {
+        super::roll_back_create(config, manager.hypervisor(), vm.into_handle()).await;
+    }
+    result
+}
+
+/// Block until a VM with an ssh block accepts SSH connections.
+async fn wait_for_ssh(manager: &VmManager, def: &VmDef, base_dir: &Path) -> Result<()> {
+    let Some(ref ssh_def) = def.ssh else {
+        info!(vm = %def.name, "no ssh block — not waiting for SSH before starting dependents");
+        return Ok(());
+    };
+    let vm = manager.get(&def.name).await?;
+    let ssh = super::build_ssh_config(ssh_def, base_dir, vm.handle())?;
+
+    println!("Waiting for VM '{}' to accept SSH...", def.name);
+    vm.with_ssh(ssh).wait_ssh(Duration::from_secs(120)).await?;
     Ok(())
 }
 
-async fn run_provision_for_vm(
+pub(super) async fn run_provision_for_vm(
     hv: &RouterHypervisor,
-    store: &state::Store,
+    config: &Config,
     vm_name: &str,
     provisions: &[ProvisionDef],
     ssh_def: Option<&SshDef>,
-    base_dir: &std::path::Path,
+    base_dir: &Path,
+    snapshot: Option<SnapshotPolicy>,
 ) -> Result<()> {
-    let ssh_def = ssh_def.ok_or_else(|| {
-        miette::miette!(
-            "VM '{vm_name}' has provisioners but no ssh block — add an ssh {{ }} section to VMFile.kdl"
-        )
-    })?;
-
+    let store = state::load_store(config).await?;
     let handle = store
         .get(vm_name)
         .ok_or_else(|| miette::miette!("VM '{vm_name}' not found in store"))?;
 
-    let ip = hv.guest_ip(handle).await.into_diagnostic()?;
-    let port = super::ssh_port_for_handle(handle);
-
-    let config = super::build_ssh_config(ssh_def, base_dir, handle)?;
-
     println!("Provisioning VM '{vm_name}'...");
-    let sess = vm_manager::ssh::connect_with_retry(&ip, port, &config, Duration::from_secs(120))
-        .await
-        .into_diagnostic()?;
+    let transport = provision_cmd::connect(hv, handle, ssh_def, base_dir).await?;
 
     let provisions = provisions.to_vec();
     let base_dir = base_dir.to_path_buf();
     let name = vm_name.to_string();
     let log_dir = handle.work_dir.clone();
-    tokio::task::spawn_blocking(move || {
-        vm_manager::provision::run_provisions(
-            &sess,
-            &provisions,
-            &base_dir,
-            &name,
-            Some(&log_dir),
-        )
+    let events = hv.events().clone();
+    provision_cmd::with_snapshot(handle, snapshot, async {
+        super::until_cancelled(tokio::task::spawn_blocking(move || {
+            vm_manager::provision::run_provisions(
+                &transport,
+                &provisions,
+                &ProvisionFilter::default(),
+                &base_dir,
+                &name,
+                Some(&log_dir),
+                &events,
+                super::cancel(),
+            )
+        }))
+        .await
     })
-    .await
-    .into_diagnostic()?
-    .into_diagnostic()?;
+    .await?;
 
     println!("VM '{vm_name}' provisioned");
     Ok(())
@@ -0,0 +1,127 @@
+use clap::{Args, Subcommand};
+use miette::{IntoDiagnostic, Result};
+use vm_manager::backends::qemu::QemuBackend;
+use vm_manager::config::Config;
+use vm_manager::{BackendTag, Hypervisor, UsbDevice, VmHandle, VmState};
+
+use super::state;
+
+#[derive(Args)]
+pub struct UsbCommand {
+    #[command(subcommand)]
+    action: UsbAction,
+}
+
+#[derive(Subcommand)]
+enum UsbAction {
+    /// List the host's USB devices and the VMs using them
+    List,
+    /// Pass a host USB device through to a running VM
+    Attach(DeviceArgs),
+    /// Give a USB device passed through to a running VM back to the host
+    Detach(DeviceArgs),
+}
+
+#[derive(Args)]
+struct DeviceArgs {
+    /// VM name
+    name: String,
+
+    /// Device as `vendor:product` (hex, as `lsusb` shows it) or `bus.device`
+    device: UsbDevice,
+}
+
+pub async fn run(args: UsbCommand, config: &Config) -> Result<()> {
+    match args.action {
+        UsbAction::List => list(config).await,
+        UsbAction::Attach(args) => {
+            let handle = running_qemu_vm(&args.name, config).await?;
+            QemuBackend::usb_attach(&handle, &args.device).await?;
+            println!("Attached USB device {} to VM '{}'", args.device, args.name);
+            Ok(())
+        }
+        UsbAction::Detach(args) => {
+            let handle = running_qemu_vm(&args.name, config).await?;
+            QemuBackend::usb_detach(&handle, &args.device).await?;
+            println!(
+                "Detached USB device {} from VM '{}'",
+                args.device, args.name
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn list(config: &Config) -> Result<()> {
+    let devices = vm_manager::usb::list().into_diagnostic()?;
+    if devices.is_empty() {
+        println!("No USB devices found.");
+        return Ok(());
+    }
+
+    // Devices the running local QEMU VMs have attached, whether from their spec or at runtime
+    let store = state::load_store(config).await?;
+    let hv = super::hypervisor(config)?;
+    let mut in_use: Vec<(String, UsbDevice)> = Vec::new();
+    for handle in store.values() {
+        if handle.backend != BackendTag::Qemu || handle.remote_host.is_some() {
+            continue;
+        }
+        if !matches!(hv.state(handle).await, Ok(VmState::Running)) {
+            continue;
+        }
+        if let Ok(attached) = QemuBackend::usb_devices(handle).await {
+            in_use.extend(attached.into_iter().map(|d| (handle.name.clone(), d)));
+        }
+    }
+
+    println!("{:<8} {:<10} {:<40} VM", "BUS.DEV", "ID", "NAME");
+    for device in &devices {
+        let vm = in_use
+            .iter()
+            .find(|(_, usb)| device.matches(usb))
+            .map_or("-", |(name, _)| name.as_str());
+        let description = device.description();
+        println!(
+            "{:<8} {:<10} {:<40} {}",
+            format!("{}.{}", device.bus, device.device),
+            format!("{:04x}:{:04x}", device.vendor, device.product),
+            if description.is_empty() {
+                "-"
+            } else {
+                &description
+            },
+            vm
+        );
+    }
+    Ok(())
+}
+
+/// The handle of local QEMU VM `name`, which must be running.
+async fn running_qemu_vm(name: &str, config: &Config) -> Result<VmHandle> {
+    let store = state::load_store(config).await?;
+    let handle = store.get(name).ok_or_else(|| {
+        miette::miette!("VM '{name}' not found — run `vmctl list` to see available VMs")
+    })?;
+    if handle.backend != BackendTag::Qemu || handle.remote_host.is_some() {
+        miette::bail!(
+            "VM '{name}' uses the {} backend{} — USB passthrough only supports local QEMU VMs",
+            handle.backend,
+            if handle.remote_host.is_some() {
+                " on a remote host"
+            } else {
+                ""
+            }
+        );
+    }
+
+    let hv = super::hypervisor(config)?;
+    let state = hv.state(handle).await.into_diagnostic()?;
+    if state != VmState::Running {
+        miette::bail!(
+            help = "add the device to the VM's `usb` node to attach it when the VM starts",
+            "VM '{name}' is {state}, not running"
+        );
+    }
+    Ok(handle.clone())
+}
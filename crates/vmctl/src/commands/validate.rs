@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use vm_manager::capabilities::{self, HostResources};
+use vm_manager::config::Config;
+use vm_manager::vmfile::VmDef;
+use vm_manager::{BackendTag, Hypervisor, VmManager, VmState};
+
+#[derive(Args)]
+pub struct ValidateArgs {
+    /// Path to VMFile.kdl
+    #[arg(long)]
+    file: Option<PathBuf>,
+
+    /// Only check a specific VM and the VMs it depends on
+    #[arg(long)]
+    name: Option<String>,
+}
+
+pub async fn run(args: ValidateArgs, config: &Config) -> Result<()> {
+    let path = vm_manager::vmfile::discover(args.file.as_deref()).into_diagnostic()?;
+    let vmfile = vm_manager::vmfile::parse(&path).into_diagnostic()?;
+    let defs: Vec<&VmDef> = vmfile
+        .start_order(args.name.as_deref())
+        .into_diagnostic()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let manager = super::manager(config, super::hypervisor(config)?);
+    if *manager.hypervisor().default_backend() == BackendTag::Noop {
+        println!(
+            "{}: {} VMs valid (host resources not checked: noop backend)",
+            path.display(),
+            defs.len()
+        );
+        return Ok(());
+    }
+    check_resources(config, &manager, &defs).await?;
+    println!(
+        "{}: {} VMs valid, and the host has room for them",
+        path.display(),
+        defs.len()
+    );
+    Ok(())
+}
+
+/// Check that the host has room for the VMs in `defs` that aren't up yet: memory for those that
+/// aren't running, besides the VMs that are, and disk space for those that don't exist yet.
+/// Nothing is checked for the noop backend.
+pub(super) async fn check_resources(
+    config: &Config,
+    manager: &VmManager,
+    defs: &[&VmDef],
+) -> Result<()> {
+    if *manager.hypervisor().default_backend() == BackendTag::Noop {
+        return Ok(());
+    }
+    let store = manager.store().load().await?;
+    let (mut memory_mb, mut disk_gb) = (0, 0);
+    for def in defs {
+        // Huge pages come from their own pool, which the backend checks
+        let memory = if def.hugepages { 0 } else { def.memory_mb };
+        match store.get(&def.name) {
+            Some(handle) => {
+                let state = manager.hypervisor().state(handle).await;
+                if !matches!(state, Ok(VmState::Running | VmState::Suspended)) {
+                    memory_mb += memory;
+                }
+            }
+            None => {
+                memory_mb += memory;
+                disk_gb += def.disk_gb.map_or(0, u64::from);
+            }
+        }
+    }
+
+    let host = HostResources::probe(
+        &config.data_dir.value,
+        manager.running_memory_mb(&[]).await?,
+    );
+    let what = match defs {
+        [def] => def.name.clone(),
+        _ => format!("the {} VMs in VMFile.kdl", defs.len()),
+    };
+    capabilities::check_resources(&what, memory_mb, disk_gb, &host)?;
+    Ok(())
+}
@@ -0,0 +1,257 @@
+//! `vmctl watch`: notice VMs whose QEMU process went away without vmctl stopping it, record the
+//! crash in the namespace's events journal and restart them according to their restart policy.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use vm_manager::backends::qemu::QemuBackend;
+use vm_manager::config::Config;
+use vm_manager::events::Journal;
+use vm_manager::{
+    BackendTag, Hypervisor, RestartPolicy, VmEvent, VmExit, VmHandle, VmManager, VmState,
+};
+
+use super::progress::Progress;
+use super::{start, state};
+
+/// How often the store is checked for newly started VMs to watch.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay before the first restart; it doubles with each further attempt up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A VM that stays up this long has recovered: its next crash starts counting retries afresh.
+const STABLE_AFTER: Duration = Duration::from_secs(600);
+
+/// Console log lines recorded with a crash.
+const CONSOLE_TAIL_LINES: usize = 20;
+
+/// Name of the file in the namespace directory holding the pid of its running watcher.
+const PID_FILE: &str = "watch.pid";
+
+#[derive(Args)]
+pub struct WatchArgs {
+    /// Give up on a VM after this many restarts without it staying up for 10 minutes
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+}
+
+pub async fn run(args: WatchArgs, config: &Config) -> Result<()> {
+    let _pid_file = PidFile::claim(config)?;
+    let manager = super::manager(config, super::hypervisor(config)?);
+    let journal = Journal::new(config.events_journal());
+    info!(
+        namespace = %config.namespace.value,
+        journal = %journal.path().display(),
+        "watching VMs with a restart policy"
+    );
+
+    let progress = Progress::start(manager.events());
+    let mut watched = HashSet::new();
+    let mut tasks = JoinSet::new();
+    let mut rescan = tokio::time::interval(RESCAN_INTERVAL);
+    let mut terminate = signal(SignalKind::terminate()).into_diagnostic()?;
+    loop {
+        tokio::select! {
+            _ = rescan.tick() => {
+                for handle in state::load_store(config).await?.into_values() {
+                    if watched.contains(&handle.name) || !watchable(&manager, &handle).await {
+                        continue;
+                    }
+                    info!(vm = %handle.name, policy = %handle.restart_policy, "watching VM");
+                    watched.insert(handle.name.clone());
+                    let watcher = Watcher {
+                        config: config.clone(),
+                        manager: manager.clone(),
+                        journal: journal.clone(),
+                        max_retries: args.max_retries,
+                    };
+                    tasks.spawn(watcher.watch(handle.name));
+                }
+            }
+            Some(Ok(name)) = tasks.join_next() => {
+                watched.remove(&name);
+            }
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+        }
+    }
+    tasks.shutdown().await;
+    progress.finish().await;
+    Ok(())
+}
+
+/// Whether `vm` is a running local QEMU VM with a restart policy.
+async fn watchable(manager: &VmManager, vm: &VmHandle) -> bool {
+    vm.backend == BackendTag::Qemu
+        && vm.remote_host.is_none()
+        && vm.restart_policy != RestartPolicy::No
+        && manager
+            .hypervisor()
+            .state(vm)
+            .await
+            .is_ok_and(|s| s == VmState::Running)
+}
+
+struct Watcher {
+    config: Config,
+    manager: VmManager,
+    journal: Journal,
+    max_retries: u32,
+}
+
+impl Watcher {
+    /// Follow VM `name` through exits and restarts until it stops for good. Returns its name.
+    async fn watch(self, name: String) -> String {
+        let mut attempt = 0;
+        loop {
+            let Some(handle) = self.handle(&name).await else {
+                return name;
+            };
+            let up_since = Instant::now();
+            let exit = match QemuBackend::wait_for_exit(&handle).await {
+                Ok(exit) => exit,
+                Err(e) => {
+                    warn!(vm = %name, error = %e, "cannot watch VM");
+                    return name;
+                }
+            };
+
+            // The VM may have been destroyed, or its policy changed, while it ran
+            let Some(handle) = self.handle(&name).await else {
+                return name;
+            };
+            if let VmExit::Crashed { ref reason } = exit {
+                self.crashed(&handle, reason).await;
+            }
+            if !handle.restart_policy.restarts_after(&exit) {
+                info!(vm = %name, exit = ?exit, "VM stopped, not restarting it");
+                return name;
+            }
+            if up_since.elapsed() >= STABLE_AFTER {
+                attempt = 0;
+            }
+            if !self.restart(&name, &mut attempt).await {
+                return name;
+            }
+        }
+    }
+
+    async fn handle(&self, name: &str) -> Option<VmHandle> {
+        match state::load_store(&self.config).await {
+            Ok(mut store) => store.remove(name),
+            Err(e) => {
+                warn!(vm = %name, error = %e, "cannot read the VM store");
+                None
+            }
+        }
+    }
+
+    async fn crashed(&self, vm: &VmHandle, reason: &str) {
+        let console = vm_manager::console::read_console_log(&vm.work_dir)
+            .await
+            .unwrap_or_default();
+        let start = console.len().saturating_sub(CONSOLE_TAIL_LINES);
+        self.record(VmEvent::VmCrashed {
+            vm: vm.name.clone(),
+            exit_status: reason.to_string(),
+            console_tail: console[start..].to_vec(),
+        })
+        .await;
+    }
+
+    /// Start VM `name` again after a backoff, retrying until it starts or `max_retries` is
+    /// reached. Returns whether it is running.
+    async fn restart(&self, name: &str, attempt: &mut u32) -> bool {
+        loop {
+            if *attempt >= self.max_retries {
+                warn!(vm = %name, retries = self.max_retries, "VM keeps stopping, giving up");
+                eprintln!(
+                    "VM '{name}' was restarted {} times in a row — not restarting it again",
+                    self.max_retries
+                );
+                return false;
+            }
+            let delay = backoff(*attempt);
+            *attempt += 1;
+            info!(vm = %name, attempt = *attempt, ?delay, "restarting VM");
+            tokio::time::sleep(delay).await;
+
+            // Someone else may have started or destroyed it in the meantime
+            let Some(handle) = self.handle(name).await else {
+                return false;
+            };
+            let hv = self.manager.hypervisor();
+            if hv.state(&handle).await.is_ok_and(|s| s == VmState::Running) {
+                return true;
+            }
+            match start::start_one(&self.config, &self.manager, name, None).await {
+                Ok(_) => {
+                    self.record(VmEvent::VmRestarted {
+                        vm: name.to_string(),
+                        attempt: *attempt,
+                    })
+                    .await;
+                    return true;
+                }
+                Err(e) => warn!(vm = %name, error = ?e, "restarting VM failed"),
+            }
+        }
+    }
+
+    /// Append `event` to the journal and show it.
+    async fn record(&self, event: VmEvent) {
+        if let Err(e) = self.journal.append(&event).await {
+            warn!(error = %e, journal = %self.journal.path().display(), "cannot write to the events journal");
+        }
+        self.manager.events().publish(event);
+    }
+}
+
+/// Delay before restart number `attempt + 1`.
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+/// The namespace's `watch.pid`, removed again when dropped.
+struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Record this process as the namespace's watcher, unless another one is running: two
+    /// watchers would both restart a crashed VM.
+    fn claim(config: &Config) -> Result<Self> {
+        let path = config.namespace_dir().join(PID_FILE);
+        if let Some(pid) = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| s.trim().parse::<u32>().ok())
+        {
+            if pid != std::process::id() && PathBuf::from(format!("/proc/{pid}")).exists() {
+                miette::bail!(
+                    help = format!("stop it first, or remove {} if it is stale", path.display()),
+                    "`vmctl watch` is already running for namespace '{}' (pid {pid})",
+                    config.namespace.value
+                );
+            }
+        }
+        std::fs::create_dir_all(config.namespace_dir()).into_diagnostic()?;
+        std::fs::write(&path, std::process::id().to_string()).into_diagnostic()?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,8 @@
+use miette::Result;
+use vm_manager::config::Config;
+
+/// Print the namespace that commands operate on.
+pub fn run(config: &Config) -> Result<()> {
+    println!("{}", config.namespace.value);
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Log output for the CLI: the console format and an optional log file.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use miette::Result;
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One line per event, without timestamps or targets
+    #[default]
+    Compact,
+    /// Multi-line, human-oriented output with timestamps and source locations
+    Pretty,
+    /// One JSON object per line with timestamp, level, target and all fields
+    Json,
+}
+
+/// Install the global subscriber. Logs go to stderr so that stdout stays clean for `__agent`
+/// responses; with `file`, every event is also appended there, with timestamps and no colours.
+pub fn init(format: LogFormat, file: Option<&Path>) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let mut layers = vec![layer(format, std::io::stderr, true)];
+    if let Some(path) = file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                miette::miette!(
+                    help = "check that the directory exists and is writable",
+                    "cannot open log file {}: {e}",
+                    path.display()
+                )
+            })?;
+        // The compact console format drops timestamps, which a log file needs.
+        let file_format = match format {
+            LogFormat::Json => LogFormat::Json,
+            _ => LogFormat::Pretty,
+        };
+        layers.push(layer(file_format, Mutex::new(file), false));
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .init();
+    Ok(())
+}
+
+fn layer<W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match (format, ansi) {
+        (LogFormat::Compact, _) => layer.compact().without_time().with_target(false).boxed(),
+        // Pretty's multi-line layout is hard to grep in a file; use one line per event there.
+        (LogFormat::Pretty, true) => layer.pretty().boxed(),
+        (LogFormat::Pretty, false) => layer.boxed(),
+        (LogFormat::Json, _) => layer
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .boxed(),
+    }
+}
+
+/// Writes one JSON object per event:
+/// `{"timestamp":..,"level":..,"target":..,"fields":{..},"spans":[{"name":..,..}]}`.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let meta = event.metadata();
+
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let mut obj = Map::new();
+                obj.insert("name".into(), span.name().into());
+                if let Some(recorded) = span.extensions().get::<FormattedFields<N>>() {
+                    if let Ok(Value::Object(map)) = serde_json::from_str(recorded) {
+                        obj.extend(map);
+                    }
+                }
+                spans.push(Value::Object(obj));
+            }
+        }
+
+        let mut line = Map::new();
+        line.insert("timestamp".into(), timestamp.into());
+        line.insert("level".into(), meta.level().as_str().into());
+        line.insert("target".into(), meta.target().into());
+        line.insert("fields".into(), Value::Object(fields.0));
+        if !spans.is_empty() {
+            line.insert("spans".into(), spans.into());
+        }
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+/// Records span fields as JSON object text, which [`JsonFormat`] nests under the span.
+struct JsonFields;
+
+impl<'w> FormatFields<'w> for JsonFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'w>, fields: R) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'w mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut visitor = match serde_json::from_str(current) {
+            Ok(Value::Object(map)) => JsonVisitor(map),
+            _ => JsonVisitor::default(),
+        };
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.0.insert(field.name().into(), value.to_string().into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
@@ -1,21 +1,21 @@
+use std::process::ExitCode;
+
 use clap::Parser;
 use miette::Result;
-use tracing_subscriber::EnvFilter;
 
 mod commands;
-use commands::Cli;
+mod logging;
+use commands::{Cli, Exit};
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize tracing: compact format, no timestamps, no targets
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
-        .without_time()
-        .with_target(false)
-        .init();
-
+async fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
-    cli.run().await
+    logging::init(cli.log_format, cli.log_file.as_deref())?;
+    match cli.run().await {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        Err(e) => match e.downcast_ref::<Exit>() {
+            Some(Exit(code)) => Ok(ExitCode::from(*code)),
+            None => Err(e),
+        },
+    }
 }